@@ -0,0 +1,37 @@
+//! Captures build metadata the agent reports in answer to `Request::Version`
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Run a command in the crate directory, returning its trimmed output if it succeeded
+fn output_of(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !stdout.trim().is_empty()).then(|| stdout.trim().to_string())
+}
+
+fn main() {
+    // A build from a source archive has no repository; the hash can be given instead
+    println!("cargo:rerun-if-env-changed=MITOXIDE_GIT_HASH");
+    let git_hash = std::env::var("MITOXIDE_GIT_HASH").ok()
+        .or_else(|| output_of("git", &["rev-parse", "HEAD"]));
+    if let Some(git_hash) = git_hash {
+        println!("cargo:rustc-env=MITOXIDE_GIT_HASH={}", git_hash);
+    }
+    if let Some(git_dir) = output_of("git", &["rev-parse", "--absolute-git-dir"]) {
+        let git_dir = PathBuf::from(git_dir);
+        println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+        println!("cargo:rerun-if-changed={}", git_dir.join("refs").display());
+    }
+    
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = output_of(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=MITOXIDE_RUSTC_VERSION={}", version);
+    }
+    println!("cargo:rustc-env=MITOXIDE_TARGET={}", std::env::var("TARGET").unwrap());
+    println!("cargo:rustc-env=MITOXIDE_PROFILE={}", std::env::var("PROFILE").unwrap());
+}
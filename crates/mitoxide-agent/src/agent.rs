@@ -1,20 +1,631 @@
 //! Agent main loop and frame processing
 
+use crate::audit;
+use crate::idempotency::{Claim, IdempotencyCache, Waiter};
+use crate::resume::ResumeStore;
 use anyhow::{Context, Result};
 use bytes::Bytes;
-use mitoxide_proto::{Frame, FrameCodec, Message, Request, Response};
-use mitoxide_proto::message::{ErrorCode, ErrorDetails};
-use std::collections::HashMap;
+use mitoxide_proto::{CompressedReader, CompressedWriter, CompressionDictionary, Event, FlowControlMessage, Frame, FrameCodec, Message, ProtocolError, RateLimit, RateLimiter, Request, Response};
+use mitoxide_proto::message::{ErrorCode, ErrorDetails, OperationInfo, QosClass, StreamCompression, TempKind, DEFAULT_ATTACHMENT_THRESHOLD, MAX_STREAM_OUTPUT_WINDOW, MIN_STREAM_OUTPUT_WINDOW, STREAM_INPUT_WINDOW, STREAM_OUTPUT_WINDOW};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use tokio::io::{stdin, stdout, AsyncRead, AsyncWrite};
-use tokio::sync::{oneshot, RwLock};
-use tracing::{debug, error, info, warn};
+use std::time::{Duration, Instant};
+use tokio::io::{stdin, stdout, AsyncRead, AsyncWrite, BufReader};
+use tokio::sync::{mpsc, oneshot, RwLock, Semaphore};
+use tokio::task::AbortHandle;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+/// Data chunks sent by the client on a request's stream after the request itself.
+/// The channel closes when the client ends the stream.
+pub type StreamInput = mpsc::Receiver<Bytes>;
+
+/// Capacity of the per-stream input channel
+///
+/// Credit for a chunk goes back to the client once the chunk is in this
+/// channel, so it holds little beyond what the handler is working on.
+const STREAM_INPUT_CAPACITY: usize = 1;
+
+/// First stream ID the requests of a batch run on
+///
+/// Each request of a batch gets a stream of its own, so it is tracked, capped
+/// and cancelled like any other. Clients number their streams up from 1 and
+/// don't get this far on one connection.
+const BATCH_STREAM_BASE: u32 = 0x8000_0000;
+
+/// Keepalive interval the agent binary uses, well inside the client's default request timeout
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a `SessionClose` waits for running requests before ending them
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why the agent loop stopped, returned by [`AgentLoop::run`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// Stopped through the sender from [`AgentLoop::shutdown_sender`]
+    CleanShutdown,
+    /// The client closed its end of the connection between frames
+    TransportClosed,
+    /// The connection failed, or was closed part way through a frame
+    TransportLost(String),
+    /// The input could not be decoded and the next frame cannot be found
+    FatalProtocolError(String),
+    /// Stopped by the signal with this number
+    Signal(i32),
+}
+
+impl ShutdownReason {
+    /// Process exit code for the agent binary
+    ///
+    /// A client hanging up is how a session normally ends, so it exits with
+    /// 0 as a clean shutdown does. Abnormal ends each have their own code so
+    /// supervisors can tell them apart; a signal gives the shell's
+    /// conventional 128 plus the signal number.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::CleanShutdown | Self::TransportClosed => 0,
+            Self::TransportLost(_) => 3,
+            Self::FatalProtocolError(_) => 4,
+            Self::Signal(signal) => 128 + signal,
+        }
+    }
+    
+    /// Reason to stop reading after `error`, or `None` if the next frame can still be read
+    fn from_read_error(error: &ProtocolError) -> Option<Self> {
+        match error {
+            ProtocolError::Transport(_) | ProtocolError::UnexpectedEof { .. } => Some(Self::TransportLost(error.to_string())),
+            // The oversized frame is never consumed, so its end can't be found
+            ProtocolError::FrameTooLarge { .. } => Some(Self::FatalProtocolError(error.to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CleanShutdown => write!(f, "clean shutdown"),
+            Self::TransportClosed => write!(f, "transport closed"),
+            Self::TransportLost(reason) => write!(f, "transport lost: {}", reason),
+            Self::FatalProtocolError(reason) => write!(f, "fatal protocol error: {}", reason),
+            Self::Signal(signal) => write!(f, "signal {}", signal),
+        }
+    }
+}
+
+/// Handler registration key for a request
+pub(crate) fn request_type(request: &Request) -> &'static str {
+    match request {
+        Request::ProcessExec { .. } => "process_exec",
+        Request::FileGet { .. } => "file_get",
+        Request::FilePut { .. } => "file_put",
+        Request::FilePatchText { .. } => "file_patch_text",
+        Request::DirList { .. } => "dir_list",
+        Request::WasmExec { .. } => "wasm_exec",
+        Request::WasmUpload { .. } => "wasm_upload",
+        Request::WasmInspect { .. } => "wasm_inspect",
+        Request::WasmPipeline { .. } => "wasm_pipeline",
+        Request::JsonCall { .. } => "json_call",
+        Request::Ping { .. } => "ping",
+        Request::PingBatch { .. } => "ping_batch",
+        Request::PtyExec { .. } => "pty_exec",
+        Request::ProcessSignal { .. } => "process_signal",
+        Request::SessionOpen { .. } => "session_open",
+        Request::SessionClose { .. } => "session_close",
+        Request::Batch { .. } => "batch",
+        Request::FileTail { .. } => "file_tail",
+        Request::FileHash { .. } => "file_hash",
+        Request::FileXattrGet { .. } => "file_xattr_get",
+        Request::FileXattrSet { .. } => "file_xattr_set",
+        Request::FileChown { .. } => "file_chown",
+        Request::FileEnsure { .. } => "file_ensure",
+        Request::FileSignature { .. } => "file_signature",
+        Request::FileDeltaPut { .. } => "file_delta_put",
+        Request::FileSetAttributes { .. } => "file_set_attributes",
+        Request::FileRemove { .. } => "file_remove",
+        Request::DiskSpace { .. } => "disk_space",
+        Request::Chdir { .. } => "chdir",
+        Request::Getcwd { .. } => "getcwd",
+        Request::MkTemp { .. } => "mk_temp",
+        Request::ListOperations { .. } => "list_operations",
+        Request::CancelOperation { .. } => "cancel_operation",
+        Request::Version { .. } => "version",
+        Request::TcpConnect { .. } => "tcp_connect",
+        Request::WithQos { request, .. }
+        | Request::WithIdempotencyKey { request, .. }
+        | Request::WithLabels { request, .. } => request_type(request),
+    }
+}
+
+/// A request waiting for a free slot
+struct QueuedRequest {
+    /// Stream the request arrived on
+    stream_id: u32,
+    /// Frame sequence number of the request
+    sequence: u32,
+    /// The request, with paths already resolved
+    request: Request,
+    /// QoS class the request is scheduled under
+    qos: QosClass,
+    /// Idempotency key the request was claimed under
+    idempotency_key: Option<String>,
+    /// Labels the request was tagged with
+    labels: HashMap<String, String>,
+}
+
+/// A temporary file or directory removed when its connection ends
+enum TempEntry {
+    /// File created with `MkTemp`
+    File(tempfile::TempPath),
+    /// Directory created with `MkTemp`, removed with its contents
+    Dir(tempfile::TempDir),
+}
+
+impl TempEntry {
+    /// Path of the entry
+    fn path(&self) -> &Path {
+        match self {
+            Self::File(path) => path,
+            Self::Dir(dir) => dir.path(),
+        }
+    }
+    
+    /// Remove the entry now, reporting failures instead of ignoring them on drop
+    fn close(self) -> std::io::Result<()> {
+        match self {
+            Self::File(path) => path.close(),
+            Self::Dir(dir) => dir.close(),
+        }
+    }
+    
+    /// Stop tracking the entry so it outlives the connection
+    fn keep(self) -> std::io::Result<PathBuf> {
+        match self {
+            Self::File(path) => path.keep().map_err(|e| e.error),
+            Self::Dir(dir) => Ok(dir.keep()),
+        }
+    }
+}
+
+/// Requests waiting for a free slot, highest QoS class first
+#[derive(Default)]
+struct RequestQueue {
+    /// Queued requests of each class, oldest first
+    classes: BTreeMap<QosClass, VecDeque<QueuedRequest>>,
+}
+
+impl RequestQueue {
+    /// Number of queued requests
+    fn len(&self) -> usize {
+        self.classes.values().map(VecDeque::len).sum()
+    }
+    
+    /// Queue a request behind the others of its class
+    fn push(&mut self, queued: QueuedRequest) {
+        self.classes.entry(queued.qos).or_default().push_back(queued);
+    }
+    
+    /// Take the oldest request of the highest class
+    fn pop(&mut self) -> Option<QueuedRequest> {
+        let mut class = self.classes.last_entry()?;
+        let queued = class.get_mut().pop_front();
+        if class.get().is_empty() {
+            class.remove();
+        }
+        queued
+    }
+    
+    /// Take the request queued for a stream, if there is one
+    fn remove(&mut self, stream_id: u32) -> Option<QueuedRequest> {
+        let (&qos, queue) = self.classes.iter_mut().find(|(_, queue)| queue.iter().any(|queued| queued.stream_id == stream_id))?;
+        let position = queue.iter().position(|queued| queued.stream_id == stream_id)?;
+        let queued = queue.remove(position);
+        if queue.is_empty() {
+            self.classes.remove(&qos);
+        }
+        queued
+    }
+}
+
+/// Handler output waiting to be written, highest QoS class first, one message
+/// per stream in turn within a class
+///
+/// A stream whose handler produces output faster than it can be written
+/// would otherwise hold the writer until it is drained, while the other
+/// streams' output waits behind it. Interactive output goes ahead of bulk
+/// transfers so a ping isn't stuck behind a large file.
+#[derive(Default)]
+struct OutputQueue {
+    /// Queued output of each stream, oldest first
+    streams: HashMap<u32, VecDeque<HandlerOutput>>,
+    /// Streams with queued output of each class, in the order they are next served
+    ready: BTreeMap<QosClass, VecDeque<u32>>,
+    /// Class of each stream with queued output
+    classes: HashMap<u32, QosClass>,
+}
+
+impl OutputQueue {
+    /// Whether no output is queued
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+    
+    /// Number of streams with queued output
+    fn streams(&self) -> usize {
+        self.streams.len()
+    }
+    
+    /// Queue output of a request of class `qos` behind the rest of its stream's
+    fn push(&mut self, output: HandlerOutput, qos: QosClass) {
+        let queue = self.streams.entry(output.stream_id).or_default();
+        if queue.is_empty() {
+            self.ready.entry(qos).or_default().push_back(output.stream_id);
+            self.classes.insert(output.stream_id, qos);
+        }
+        queue.push_back(output);
+    }
+    
+    /// Drop everything queued for a stream
+    fn discard(&mut self, stream_id: u32) {
+        self.streams.remove(&stream_id);
+        let Some(qos) = self.classes.remove(&stream_id) else {
+            return;
+        };
+        if let Some(ready) = self.ready.get_mut(&qos) {
+            ready.retain(|id| *id != stream_id);
+            if ready.is_empty() {
+                self.ready.remove(&qos);
+            }
+        }
+    }
+    
+    /// Take the oldest output of the stream whose turn it is in the highest class
+    fn pop(&mut self) -> Option<HandlerOutput> {
+        let mut class = self.ready.last_entry()?;
+        let stream_id = class.get_mut().pop_front()?;
+        let queue = self.streams.get_mut(&stream_id)?;
+        let output = queue.pop_front();
+        if queue.is_empty() {
+            self.streams.remove(&stream_id);
+            self.classes.remove(&stream_id);
+        } else {
+            class.get_mut().push_back(stream_id);
+        }
+        if class.get().is_empty() {
+            class.remove();
+        }
+        output
+    }
+}
+
+/// A request whose handler task has not had its final response written
+struct RunningRequest {
+    /// ID of the request
+    request_id: Uuid,
+    /// Handler registration key of the request
+    request_type: &'static str,
+    /// When the handler task was started
+    started: Instant,
+    /// Sequence the final response is written with
+    sequence: u32,
+    /// Key the request was claimed under, if sent with one
+    idempotency_key: Option<String>,
+    /// Handler task, aborted when the request is cancelled or its responses outgrow the limit
+    task: AbortHandle,
+    /// Encoded size of the responses written for it so far
+    bytes_written: u64,
+    /// Whether it takes a slot under the concurrency limit
+    counted: bool,
+    /// QoS class its output is written under
+    qos: QosClass,
+}
+
+/// Client input for a running request, and how much more of it the client may send
+struct InputWindow {
+    /// Chunks on their way to the request's handler
+    tx: mpsc::UnboundedSender<Bytes>,
+    /// Bytes the client may send before it is given more credit
+    credit: u64,
+}
+
+/// Credit for the partial responses of a request with an output window
+#[derive(Debug)]
+pub(crate) struct OutputCredit {
+    /// Bytes of data the client has room for
+    permits: Semaphore,
+    /// Bytes the client lets be in flight, as it last asked
+    size: AtomicU32,
+}
+
+impl OutputCredit {
+    /// Credit for a window of `size` bytes, all of it available
+    fn new(size: u32) -> Self {
+        Self { permits: Semaphore::new(size as usize), size: AtomicU32::new(size) }
+    }
+}
+
+/// Output window of a running request, as the agent loop tracks it
+struct OutputWindow {
+    /// Shared with the request's [`ResponseSink`]
+    credit: Arc<OutputCredit>,
+    /// Credit the client still counts as in flight after it shrank the window,
+    /// kept back from its next window updates
+    owed: u32,
+}
+
+impl OutputWindow {
+    /// Take back credit the client returned with a window update
+    fn grant(&mut self, delta: u32) {
+        let repaid = delta.min(self.owed);
+        self.owed -= repaid;
+        self.credit.permits.add_permits((delta - repaid) as usize);
+    }
+    
+    /// Let the request keep `size` bytes in flight from now on
+    ///
+    /// Growing the window is credit straight away. Shrinking it takes back
+    /// what credit is available and the rest from later window updates.
+    fn resize(&mut self, size: u32) {
+        let size = size.clamp(MIN_STREAM_OUTPUT_WINDOW, MAX_STREAM_OUTPUT_WINDOW);
+        let current = self.credit.size.swap(size, Ordering::SeqCst);
+        if size >= current {
+            self.grant(size - current);
+            return;
+        }
+        let shrink = current - size;
+        let taken = (self.credit.permits.available_permits() as u32).min(shrink);
+        if let Ok(permit) = self.credit.permits.try_acquire_many(taken) {
+            permit.forget();
+        }
+        self.owed += shrink - taken;
+    }
+}
+
+/// Answers for a handler task that panics, so neither its request nor its
+/// idempotency key is left running
+struct PanicGuard {
+    /// ID of the request
+    request_id: Uuid,
+    /// Stream the request arrived on
+    stream_id: u32,
+    /// Sequence the final response is written with
+    sequence: u32,
+    /// Queue drained by the agent loop
+    response_tx: mpsc::UnboundedSender<HandlerOutput>,
+    /// Cache and key the request was claimed under, if sent with one
+    idempotency: Option<(Arc<IdempotencyCache>, String)>,
+}
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        // An aborted task is ended by whoever aborted it
+        if !std::thread::panicking() {
+            return;
+        }
+        error!("Handler for request {} panicked", self.request_id);
+        let response = Response::error(self.request_id, ErrorDetails::new(ErrorCode::InternalError, "Handler panicked"));
+        // Forgotten rather than remembered, so a retry with the key runs again
+        if let Some((cache, key)) = &self.idempotency {
+            cache.release(key, &response);
+        }
+        let _ = self.response_tx.send(HandlerOutput {
+            stream_id: self.stream_id,
+            sequence: self.sequence,
+            message: Message::response(response),
+            last: true,
+        });
+    }
+}
+
+/// A `SessionClose` answered once the connection has drained
+struct PendingClose {
+    /// Stream the request arrived on
+    stream_id: u32,
+    /// Frame sequence number of the request
+    sequence: u32,
+    /// ID of the request
+    request_id: Uuid,
+}
+
+/// A batch whose requests the loop is running one after another
+struct PendingBatch {
+    /// ID of the batch
+    request_id: Uuid,
+    /// Frame sequence number of the batch
+    sequence: u32,
+    /// When the batch arrived
+    started: Instant,
+    /// Requests not started yet, in order
+    remaining: VecDeque<Request>,
+    /// Final responses of the requests that ran
+    responses: Vec<Response>,
+    /// Skip the remaining requests after the first failed one
+    stop_on_error: bool,
+    /// Labels of the batch, put on each of its requests
+    labels: HashMap<String, String>,
+    /// Key the batch was claimed under, if sent with one
+    idempotency_key: Option<String>,
+    /// Stream the current request runs on
+    current: Option<u32>,
+}
+
+/// Labels as `key=value` pairs in key order, for a span field
+fn format_labels(labels: &HashMap<String, String>) -> String {
+    let sorted: BTreeMap<_, _> = labels.iter().collect();
+    sorted.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(",")
+}
+
+/// Shared registry of handlers by request type
+pub(crate) type HandlerMap = RwLock<HashMap<String, Arc<dyn Handler>>>;
+
+/// Run a batch's requests in order and collect their responses, for the standalone router
+///
+/// Nested batches are rejected. With `stop_on_error`, the first failed request
+/// ends the batch and the ones after it are not run.
+pub(crate) async fn run_batch(handlers: &HandlerMap, id: Uuid, requests: Vec<Request>, stop_on_error: bool) -> Response {
+    let total = requests.len();
+    let mut responses = Vec::with_capacity(total);
+    
+    for request in requests {
+        let request = request.into_inner();
+        let request_id = request.id();
+        let response = if matches!(request, Request::Batch { .. }) {
+            Response::error(request_id, ErrorDetails::new(ErrorCode::InvalidRequest, "Batches cannot be nested"))
+        } else {
+            let request_type = request_type(&request);
+            let handler = handlers.read().await.get(request_type).cloned();
+            match handler {
+                Some(handler) => handler.handle(request).await.unwrap_or_else(|e| {
+                    error!("Handler error for batched request {}: {}", request_id, e);
+                    Response::error(
+                        request_id,
+                        ErrorDetails::new(ErrorCode::InternalError, format!("Handler error: {}", e))
+                    )
+                }),
+                None => Response::error(
+                    request_id,
+                    ErrorDetails::new(ErrorCode::Unsupported, format!("Unsupported request type: {}", request_type))
+                ),
+            }
+        };
+        
+        let failed = response.is_failure();
+        responses.push(response);
+        if failed && stop_on_error {
+            debug!("Batch {} stopped at request {} of {}", id, responses.len(), total);
+            break;
+        }
+    }
+    
+    Response::BatchResult { request_id: id, responses }
+}
+
+/// Wait for `handling`, sending `Event::Keepalive` for the request every `interval` meanwhile
+async fn with_keepalive<F>(handling: F, request_id: Uuid, interval: Duration, sink: ResponseSink) -> Response
+where
+    F: Future<Output = Response>,
+{
+    tokio::pin!(handling);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    // A stalled loop should send one late keepalive, not a burst of them
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            response = &mut handling => return response,
+            _ = ticker.tick() => {
+                sink.send_event(Event::Keepalive { request_id });
+            }
+        }
+    }
+}
+
+/// A message produced by a handler task, queued for writing
+#[derive(Debug)]
+pub(crate) struct HandlerOutput {
+    /// Stream the response belongs to
+    pub(crate) stream_id: u32,
+    /// Frame sequence number
+    pub(crate) sequence: u32,
+    /// Response or event to send
+    pub(crate) message: Message,
+    /// Whether this is the request's final response
+    pub(crate) last: bool,
+}
+
+/// Sends partial responses and events on a request's stream ahead of the final response
+#[derive(Debug, Clone)]
+pub struct ResponseSink {
+    /// Stream the responses belong to
+    stream_id: u32,
+    /// Queue drained by the agent loop
+    tx: mpsc::UnboundedSender<HandlerOutput>,
+    /// Bytes of data the client has room for, if the request has an output window
+    window: Option<Arc<OutputCredit>>,
+    /// Send rate limits data is paced to, the stream's before the connection's
+    rate_limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl ResponseSink {
+    /// Create a sink for the given stream
+    pub(crate) fn new(stream_id: u32, tx: mpsc::UnboundedSender<HandlerOutput>) -> Self {
+        Self { stream_id, tx, window: None, rate_limiters: Vec::new() }
+    }
+    
+    /// Hold data sent with [`ResponseSink::send_data`] to the credit in `window`
+    pub(crate) fn with_window(mut self, window: Arc<OutputCredit>) -> Self {
+        self.window = Some(window);
+        self
+    }
+    
+    /// Pace data sent with [`ResponseSink::send_data`] to `limiter` as well
+    pub(crate) fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiters.push(limiter);
+        self
+    }
+    
+    /// Queue a partial response carrying `len` bytes of data once the client has room for them
+    ///
+    /// Without an output window the response is queued straight away, unless
+    /// the agent has a send rate limit: the response then waits for its share
+    /// of the stream's and the connection's rate.
+    /// Returns false once the connection or the request is gone.
+    pub async fn send_data(&self, response: Response, len: usize) -> bool {
+        if let Some(window) = &self.window {
+            let size = window.size.load(Ordering::SeqCst) as usize;
+            match window.permits.acquire_many(len.min(size) as u32).await {
+                Ok(permit) => permit.forget(),
+                Err(_) => return false,
+            }
+        }
+        for limiter in &self.rate_limiters {
+            limiter.acquire(len).await;
+        }
+        self.send(response)
+    }
+    
+    /// Queue a partial response; returns false once the connection is gone
+    pub fn send(&self, response: Response) -> bool {
+        self.queue(Message::response(response))
+    }
+    
+    /// Queue an event for the client's subscribers; returns false once the connection is gone
+    pub fn send_event(&self, event: Event) -> bool {
+        self.queue(Message::event(event))
+    }
+    
+    /// Queue a message that doesn't finish the request
+    fn queue(&self, message: Message) -> bool {
+        self.tx.send(HandlerOutput {
+            stream_id: self.stream_id,
+            sequence: 0,
+            message,
+            last: false,
+        }).is_ok()
+    }
+}
+
+/// Per-request stream resources handed to streaming-capable handlers
+pub struct RequestStream {
+    /// Client data sent after the request, if the request declared any
+    pub input: Option<StreamInput>,
+    /// Sink for partial responses
+    pub output: ResponseSink,
+}
 
 /// Handler trait for processing requests
 #[async_trait::async_trait]
 pub trait Handler: Send + Sync {
     /// Handle a request and return a response
     async fn handle(&self, request: Request) -> Result<Response>;
+    
+    /// Handle a request with access to its stream, for client input and partial responses
+    async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+        drop(stream);
+        self.handle(request).await
+    }
 }
 
 /// Main agent loop for processing frames
@@ -23,31 +634,129 @@ where
     R: AsyncRead + Unpin + Send,
     W: AsyncWrite + Unpin + Send,
 {
-    /// Input stream (typically stdin)
-    reader: R,
-    /// Output stream (typically stdout)
-    writer: W,
+    /// Input stream (typically stdin), decompressed once stream compression is negotiated
+    reader: CompressedReader<BufReader<R>>,
+    /// Output stream (typically stdout), compressed once stream compression is negotiated
+    writer: CompressedWriter<W>,
     /// Frame codec for encoding/decoding
     codec: FrameCodec,
     /// Registered handlers by request type
-    handlers: Arc<RwLock<HashMap<String, Arc<dyn Handler>>>>,
+    handlers: Arc<HandlerMap>,
     /// Shutdown signal receiver
-    shutdown_rx: Option<oneshot::Receiver<()>>,
+    shutdown_rx: Option<oneshot::Receiver<ShutdownReason>>,
     /// Shutdown signal sender (kept for graceful shutdown)
-    shutdown_tx: Option<oneshot::Sender<()>>,
+    shutdown_tx: Option<oneshot::Sender<ShutdownReason>>,
+    /// Input channels for streams that carry client data after the request
+    stream_inputs: HashMap<u32, InputWindow>,
+    /// Credit for the partial responses of requests with an output window, by stream
+    output_windows: HashMap<u32, OutputWindow>,
+    /// Input bytes handed to handlers, by stream, to return to the client as credit
+    credit_tx: mpsc::UnboundedSender<(u32, u32)>,
+    /// Receiver side of the credit channel
+    credit_rx: mpsc::UnboundedReceiver<(u32, u32)>,
+    /// Completed responses from spawned handler tasks
+    response_tx: mpsc::UnboundedSender<HandlerOutput>,
+    /// Receiver side of the completed responses channel
+    response_rx: mpsc::UnboundedReceiver<HandlerOutput>,
+    /// Handler output taken from `response_rx` and not yet written
+    outputs: OutputQueue,
+    /// Number of requests currently being handled
+    in_flight: usize,
+    /// Requests in flight that don't take a slot under `max_concurrent_requests`
+    uncounted: usize,
+    /// Completed responses kept for clients that reconnect
+    resume: Arc<ResumeStore>,
+    /// Session opened on this connection, if any
+    session_token: Option<Uuid>,
+    /// Most requests handled at once, advertised when a session opens
+    max_concurrent_requests: Option<usize>,
+    /// Requests waiting for a slot under the concurrency limit
+    queued: RequestQueue,
+    /// Most requests that may wait in `queued`
+    queue_capacity: usize,
+    /// Working directory set with `Chdir`, applied to later requests
+    cwd: Option<PathBuf>,
+    /// Temporary files and directories to remove when the connection ends
+    temp_paths: Vec<TempEntry>,
+    /// Responses of requests sent with an idempotency key
+    idempotency: Arc<IdempotencyCache>,
+    /// How often requests still being handled send `Event::Keepalive`
+    keepalive_interval: Option<Duration>,
+    /// Size from which file content is sent as a frame attachment
+    attachment_threshold: Option<usize>,
+    /// Dictionary stream compression is primed with when the client has it too
+    compression_dictionary: Option<CompressionDictionary>,
+    /// ID of the dictionary in use, once stream compression is enabled with one
+    stream_dictionary_id: Option<u32>,
+    /// Most bytes of responses written for one request
+    max_response_bytes: Option<u64>,
+    /// Paces the data of all requests on the connection, if it has a send rate limit
+    send_limiter: Option<Arc<RateLimiter>>,
+    /// Send rate limit for the data of each request
+    stream_send_rate_limit: Option<RateLimit>,
+    /// Requests whose final response is not yet written, by stream
+    running: HashMap<u32, RunningRequest>,
+    /// Handler tasks of aborted requests, by stream, so their late output is dropped
+    aborted_streams: HashMap<u32, AbortHandle>,
+    /// Clean close the client asked for, answered when the loop stops
+    closing: Option<PendingClose>,
+    /// How long a close waits for running requests before ending them
+    drain_timeout: Duration,
+    /// Batches being run, by the stream they arrived on
+    batches: HashMap<u32, PendingBatch>,
+    /// Stream of the batch each batched request belongs to, by the request's stream
+    batch_streams: HashMap<u32, u32>,
+    /// Batches whose next request is ready to start
+    batch_ready: VecDeque<u32>,
+    /// Stream the next batched request runs on
+    next_batch_stream: u32,
 }
 
 impl AgentLoop<tokio::io::Stdin, tokio::io::Stdout> {
     /// Create a new agent loop with stdin/stdout
     pub fn new() -> Self {
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        let (credit_tx, credit_rx) = mpsc::unbounded_channel();
         Self {
-            reader: stdin(),
-            writer: stdout(),
+            reader: CompressedReader::new(BufReader::new(stdin())),
+            writer: CompressedWriter::new(stdout()),
             codec: FrameCodec::new(),
             handlers: Arc::new(RwLock::new(HashMap::new())),
             shutdown_rx: Some(shutdown_rx),
             shutdown_tx: Some(shutdown_tx),
+            stream_inputs: HashMap::new(),
+            output_windows: HashMap::new(),
+            credit_tx,
+            credit_rx,
+            response_tx,
+            response_rx,
+            outputs: OutputQueue::default(),
+            in_flight: 0,
+            uncounted: 0,
+            resume: Arc::new(ResumeStore::new()),
+            session_token: None,
+            max_concurrent_requests: None,
+            queued: RequestQueue::default(),
+            queue_capacity: 0,
+            cwd: None,
+            temp_paths: Vec::new(),
+            idempotency: Arc::new(IdempotencyCache::new()),
+            keepalive_interval: None,
+            attachment_threshold: Some(DEFAULT_ATTACHMENT_THRESHOLD),
+            compression_dictionary: None,
+            stream_dictionary_id: None,
+            max_response_bytes: None,
+            send_limiter: None,
+            stream_send_rate_limit: None,
+            running: HashMap::new(),
+            aborted_streams: HashMap::new(),
+            closing: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            batches: HashMap::new(),
+            batch_streams: HashMap::new(),
+            batch_ready: VecDeque::new(),
+            next_batch_stream: BATCH_STREAM_BASE,
         }
     }
 }
@@ -60,16 +769,147 @@ where
     /// Create a new agent loop with custom reader/writer
     pub fn with_io(reader: R, writer: W) -> Self {
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        let (credit_tx, credit_rx) = mpsc::unbounded_channel();
         Self {
-            reader,
-            writer,
+            reader: CompressedReader::new(BufReader::new(reader)),
+            writer: CompressedWriter::new(writer),
             codec: FrameCodec::new(),
             handlers: Arc::new(RwLock::new(HashMap::new())),
             shutdown_rx: Some(shutdown_rx),
             shutdown_tx: Some(shutdown_tx),
+            stream_inputs: HashMap::new(),
+            output_windows: HashMap::new(),
+            credit_tx,
+            credit_rx,
+            response_tx,
+            response_rx,
+            outputs: OutputQueue::default(),
+            in_flight: 0,
+            uncounted: 0,
+            resume: Arc::new(ResumeStore::new()),
+            session_token: None,
+            max_concurrent_requests: None,
+            queued: RequestQueue::default(),
+            queue_capacity: 0,
+            cwd: None,
+            temp_paths: Vec::new(),
+            idempotency: Arc::new(IdempotencyCache::new()),
+            keepalive_interval: None,
+            attachment_threshold: Some(DEFAULT_ATTACHMENT_THRESHOLD),
+            compression_dictionary: None,
+            stream_dictionary_id: None,
+            max_response_bytes: None,
+            send_limiter: None,
+            stream_send_rate_limit: None,
+            running: HashMap::new(),
+            aborted_streams: HashMap::new(),
+            closing: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            batches: HashMap::new(),
+            batch_streams: HashMap::new(),
+            batch_ready: VecDeque::new(),
+            next_batch_stream: BATCH_STREAM_BASE,
         }
     }
     
+    /// Share a resume store with other connections so sessions can move between them
+    pub fn with_resume_store(mut self, resume: Arc<ResumeStore>) -> Self {
+        self.resume = resume;
+        self
+    }
+    
+    /// Share an idempotency cache with other connections so a request resent
+    /// over a new connection still runs only once
+    pub fn with_idempotency_cache(mut self, cache: Arc<IdempotencyCache>) -> Self {
+        self.idempotency = cache;
+        self
+    }
+    
+    /// Limit how many requests are handled at once
+    ///
+    /// The limit is advertised to clients when they open a session; requests
+    /// beyond it are rejected with `ErrorCode::Overloaded`.
+    pub fn with_max_concurrent_requests(mut self, max: usize) -> Self {
+        self.max_concurrent_requests = Some(max);
+        self
+    }
+    
+    /// Send `Event::Keepalive` every `interval` for each request still being handled
+    ///
+    /// Clients restart their request timeout on each one, so a long-running
+    /// handler isn't mistaken for a hung agent.
+    pub fn with_keepalive_interval(mut self, interval: Duration) -> Self {
+        self.keepalive_interval = Some(interval);
+        self
+    }
+    
+    /// Send file content of at least `threshold` bytes as frame attachments, or none with None
+    ///
+    /// Attachments are written from the handler's buffer instead of being
+    /// copied into the message encoding, which halves the memory a large file
+    /// transfer takes. Defaults to [`DEFAULT_ATTACHMENT_THRESHOLD`]. A client's
+    /// `SessionOpen` raises it for the connection to its own threshold if
+    /// larger, or turns attachments off if it takes none.
+    pub fn with_attachment_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.attachment_threshold = threshold;
+        self
+    }
+    
+    /// Prime stream compression with `dictionary` for clients that present the same one
+    ///
+    /// Clients asking for a different dictionary get stream compression without one.
+    pub fn with_compression_dictionary(mut self, dictionary: CompressionDictionary) -> Self {
+        self.compression_dictionary = Some(dictionary);
+        self
+    }
+    
+    /// Limit the encoded size of all responses written for one request to `bytes`
+    ///
+    /// A request whose next response would go past the limit has its handler
+    /// aborted and ends with `ErrorCode::ResponseTooLarge` instead, so one
+    /// runaway request can't monopolize the connection.
+    pub fn with_max_response_bytes(mut self, bytes: u64) -> Self {
+        self.max_response_bytes = Some(bytes);
+        self
+    }
+    
+    /// Pace the data responses of all requests on a connection to `limit`
+    ///
+    /// Bulk transfers such as file reads and tunnels wait for their share of
+    /// the rate in their handler, so they can't saturate the link. Each
+    /// connection served with [`run_with`](Self::run_with) gets a limit of its own.
+    pub fn with_send_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.send_limiter = Some(Arc::new(RateLimiter::new(limit)));
+        self
+    }
+    
+    /// Pace the data responses of each request to `limit`, under the connection's limit if any
+    pub fn with_stream_send_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.stream_send_rate_limit = Some(limit);
+        self
+    }
+    
+    /// Let up to `capacity` requests wait for a slot instead of being rejected
+    /// when the concurrency limit is reached
+    ///
+    /// Queued requests start by QoS class as slots free, oldest first within a
+    /// class. Requests that carry stream input are never queued.
+    pub fn with_request_queue(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+    
+    /// Give a `SessionClose` at most `timeout` to drain running requests
+    ///
+    /// Requests still running or queued when it passes end with
+    /// `ErrorCode::Cancelled`, so a hung handler can't hold the connection
+    /// open. Defaults to [`DEFAULT_DRAIN_TIMEOUT`].
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+    
     /// Register a handler for a specific request type
     pub async fn register_handler(&self, request_type: String, handler: Arc<dyn Handler>) {
         let mut handlers = self.handlers.write().await;
@@ -78,27 +918,91 @@ where
     }
     
     /// Get shutdown sender for graceful shutdown
-    pub fn shutdown_sender(&mut self) -> Option<oneshot::Sender<()>> {
+    ///
+    /// The reason sent is the one [`run`](Self::run) returns; dropping the
+    /// sender stops the loop as [`ShutdownReason::CleanShutdown`].
+    pub fn shutdown_sender(&mut self) -> Option<oneshot::Sender<ShutdownReason>> {
         self.shutdown_tx.take()
     }
     
-    /// Run the agent loop
-    pub async fn run(&mut self) -> Result<()> {
+    /// Run the agent loop, returning why it stopped
+    ///
+    /// Fails only if the loop was already run.
+    pub async fn run(&mut self) -> Result<ShutdownReason> {
         info!("Starting agent loop");
         
         let mut shutdown_rx = self.shutdown_rx.take()
             .context("Shutdown receiver already taken")?;
         
-        loop {
+        #[cfg(feature = "jsonrpc")]
+        {
+            let json_rpc = tokio::select! {
+                reason = &mut shutdown_rx => {
+                    info!("Received shutdown signal, stopping agent loop");
+                    return Ok(reason.unwrap_or(ShutdownReason::CleanShutdown));
+                }
+                json_rpc = self.detect_json_rpc() => json_rpc,
+            };
+            match json_rpc {
+                Ok(true) => {
+                    info!("Client opened with JSON, switching to JSON-RPC framing");
+                    return self.run_json_rpc(shutdown_rx).await;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Error reading input: {:#}", e);
+                    return Ok(ShutdownReason::TransportLost(format!("{:#}", e)));
+                }
+            }
+        }
+        
+        // Why input stopped being read, once it has
+        let mut input_end = None;
+        let mut stopped = None;
+        // When a close stops waiting for the requests it is draining
+        let mut drain_deadline = None;
+        
+        while input_end.is_none() || self.in_flight > 0 || !self.outputs.is_empty() {
             tokio::select! {
                 // Handle shutdown signal
-                _ = &mut shutdown_rx => {
+                reason = &mut shutdown_rx => {
                     info!("Received shutdown signal, stopping agent loop");
+                    stopped = Some(reason.unwrap_or(ShutdownReason::CleanShutdown));
                     break;
                 }
                 
+                // End whatever the close is still waiting for once its deadline passes
+                _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(tokio::time::Instant::now)), if drain_deadline.is_some() => {
+                    warn!("Close timed out after {:?}, ending {} requests", self.drain_timeout, self.in_flight);
+                    drain_deadline = None;
+                    self.abort_remaining().await;
+                }
+                
+                // Queue output from handlers, taking everything already produced
+                Some(output) = self.response_rx.recv() => {
+                    let stopped = self.stopped_aborts();
+                    self.queue_output(output);
+                    while let Ok(output) = self.response_rx.try_recv() {
+                        self.queue_output(output);
+                    }
+                    // Everything their tasks produced has been taken and dropped
+                    for stream_id in stopped {
+                        self.aborted_streams.remove(&stream_id);
+                    }
+                }
+                
+                // Write queued output, one message from each waiting stream
+                _ = std::future::ready(()), if !self.outputs.is_empty() => {
+                    self.write_output_round().await;
+                }
+                
+                // Return credit for input handlers have taken
+                Some((stream_id, bytes)) = self.credit_rx.recv() => {
+                    self.return_credit(stream_id, bytes).await;
+                }
+                
                 // Process incoming frames
-                frame_result = self.codec.read_frame(&mut self.reader) => {
+                frame_result = self.codec.read_frame(&mut self.reader), if input_end.is_none() => {
                     match frame_result {
                         Ok(Some(frame)) => {
                             if let Err(e) = self.process_frame(frame).await {
@@ -108,20 +1012,72 @@ where
                         }
                         Ok(None) => {
                             info!("Input stream closed, stopping agent loop");
-                            break;
+                            // Client input is gone, so any streams still waiting on it see EOF
+                            self.stream_inputs.clear();
+                            input_end = Some(ShutdownReason::TransportClosed);
                         }
                         Err(e) => {
                             error!("Error reading frame: {}", e);
-                            // Try to continue on protocol errors
-                            continue;
+                            // Try to continue on protocol errors the next frame can be read after
+                            if let Some(reason) = ShutdownReason::from_read_error(&e) {
+                                info!("Input unreadable, stopping agent loop");
+                                self.stream_inputs.clear();
+                                input_end = Some(reason);
+                            }
                         }
                     }
                 }
             }
+            
+            self.advance_batches().await;
+            // Also asked for by the last request of a batch
+            if self.closing.is_some() && input_end.is_none() {
+                info!("Client is closing the connection, draining {} requests", self.in_flight);
+                self.stream_inputs.clear();
+                input_end = Some(ShutdownReason::CleanShutdown);
+                drain_deadline = Some(tokio::time::Instant::now() + self.drain_timeout);
+            }
         }
         
-        info!("Agent loop stopped");
-        Ok(())
+        let temp_paths_removed = self.remove_temp_paths();
+        if let Some(close) = self.closing.take() {
+            let response = Response::SessionClosed { request_id: close.request_id, temp_paths_removed };
+            if let Err(e) = self.send_response(close.stream_id, close.sequence, response).await {
+                error!("Failed to acknowledge close: {}", e);
+            }
+            // A batch ending in the close is answered after it
+            self.advance_batches().await;
+        }
+        let reason = stopped.or(input_end).unwrap_or(ShutdownReason::TransportClosed);
+        info!(reason = %reason, exit_code = reason.exit_code(), "Agent loop stopped");
+        Ok(reason)
+    }
+    
+    /// Run the agent loop over `reader` and `writer` instead of this loop's own streams
+    ///
+    /// The connection shares this loop's handlers, resume store, idempotency
+    /// cache and limits but has its own session and working directory, so one configured loop
+    /// can serve any number of connections, such as those accepted from a
+    /// socket. Returns once the connection's input closes and its in-flight
+    /// requests have been answered.
+    pub async fn run_with<R2, W2>(&self, reader: R2, writer: W2) -> Result<ShutdownReason>
+    where
+        R2: AsyncRead + Unpin + Send,
+        W2: AsyncWrite + Unpin + Send,
+    {
+        let mut connection = AgentLoop::with_io(reader, writer)
+            .with_resume_store(Arc::clone(&self.resume))
+            .with_idempotency_cache(Arc::clone(&self.idempotency));
+        connection.handlers = Arc::clone(&self.handlers);
+        connection.max_concurrent_requests = self.max_concurrent_requests;
+        connection.queue_capacity = self.queue_capacity;
+        connection.attachment_threshold = self.attachment_threshold;
+        connection.compression_dictionary = self.compression_dictionary.clone();
+        connection.max_response_bytes = self.max_response_bytes;
+        connection.send_limiter = self.send_limiter.as_ref().map(|limiter| Arc::new(RateLimiter::new(limiter.limit())));
+        connection.stream_send_rate_limit = self.stream_send_rate_limit;
+        connection.drain_timeout = self.drain_timeout;
+        connection.run().await
     }
     
     /// Process a single frame
@@ -129,24 +1085,73 @@ where
         debug!("Processing frame: stream_id={}, sequence={}, flags={:?}, payload_size={}", 
                frame.stream_id, frame.sequence, frame.flags, frame.payload.len());
         
-        // Handle control frames
+        // Handle control frames; an error frame resets the stream, ending its request
         if frame.is_error() {
             warn!("Received error frame: stream_id={}, payload={:?}", 
                   frame.stream_id, frame.payload);
+            let error = ErrorDetails::new(ErrorCode::Cancelled, "Client reset the request's stream");
+            if let Some(running) = self.running.remove(&frame.stream_id) {
+                info!("Client reset the stream of request {}", running.request_id);
+                self.abort_running(frame.stream_id, running, error).await;
+            } else if self.abort_batch(frame.stream_id, error).await {
+                info!("Client reset the stream of a batch");
+            }
+            return Ok(());
+        }
+        
+        // The client returns credit for partial responses it has taken and resizes their windows
+        if frame.is_flow_control() {
+            match FlowControlMessage::from_frame(&frame) {
+                Ok(FlowControlMessage::WindowUpdate { delta }) => {
+                    if let Some(window) = self.output_windows.get_mut(&frame.stream_id) {
+                        window.grant(delta);
+                    }
+                }
+                Ok(FlowControlMessage::ResizeWindow { window_size }) => {
+                    if let Some(window) = self.output_windows.get_mut(&frame.stream_id) {
+                        debug!("Resizing the output window of stream {} to {} bytes", frame.stream_id, window_size);
+                        window.resize(window_size);
+                    }
+                }
+                Err(e) => warn!("Failed to read flow control frame on stream {}: {}", frame.stream_id, e),
+            }
             return Ok(());
         }
         
         if frame.is_end_stream() {
             debug!("Received end-of-stream frame: stream_id={}", frame.stream_id);
+            // Dropping the sender signals EOF to the handler reading this stream
+            self.stream_inputs.remove(&frame.stream_id);
+            return Ok(());
+        }
+        
+        // Forward data for streams that are already running a request
+        if let Some(input) = self.stream_inputs.get_mut(&frame.stream_id) {
+            let len = frame.payload.len() as u64;
+            if len > input.credit {
+                warn!("Stream {} sent {} bytes of input with {} bytes of credit", frame.stream_id, len, input.credit);
+                if let Some(running) = self.running.remove(&frame.stream_id) {
+                    let error = ErrorDetails::new(ErrorCode::InvalidRequest, "Stream input went past its flow control window");
+                    self.abort_running(frame.stream_id, running, error).await;
+                }
+                self.stream_inputs.remove(&frame.stream_id);
+                return Ok(());
+            }
+            input.credit -= len;
+            if input.tx.send(frame.payload).is_err() {
+                debug!("Stream input receiver dropped: stream_id={}", frame.stream_id);
+                self.stream_inputs.remove(&frame.stream_id);
+            }
             return Ok(());
         }
         
         // Deserialize message from frame payload
-        let message = match rmp_serde::from_slice::<Message>(&frame.payload) {
+        let (stream_id, sequence) = (frame.stream_id, frame.sequence);
+        let message = match Message::from_frame(frame) {
             Ok(msg) => msg,
             Err(e) => {
                 error!("Failed to deserialize message: {}", e);
-                self.send_error_frame(frame.stream_id, frame.sequence, 
+                self.send_error_frame(stream_id, sequence, 
                                     ErrorCode::InvalidRequest, 
                                     format!("Invalid message format: {}", e)).await?;
                 return Ok(());
@@ -156,12 +1161,15 @@ where
         // Dispatch message
         match message {
             Message::Request(request) => {
-                self.handle_request(frame.stream_id, frame.sequence, request).await?;
+                self.handle_request(stream_id, sequence, request).await?;
             }
             Message::Response(_) => {
                 warn!("Received unexpected response message on agent");
                 // Agents typically don't handle responses, only requests
             }
+            Message::Event(_) => {
+                warn!("Received unexpected event message on agent");
+            }
         }
         
         Ok(())
@@ -169,68 +1177,848 @@ where
     
     /// Handle a request message
     async fn handle_request(&mut self, stream_id: u32, sequence: u32, request: Request) -> Result<()> {
+        let qos = request.qos();
+        let idempotency_key = request.idempotency_key().map(str::to_owned);
+        let labels = request.labels().cloned().unwrap_or_default();
+        let request = request.into_inner();
         let request_id = request.id();
         debug!("Handling request: id={}, type={:?}", request_id, std::mem::discriminant(&request));
         
-        // Determine request type for handler lookup
-        let request_type = match &request {
-            Request::ProcessExec { .. } => "process_exec",
-            Request::FileGet { .. } => "file_get",
-            Request::FilePut { .. } => "file_put",
-            Request::DirList { .. } => "dir_list",
-            Request::WasmExec { .. } => "wasm_exec",
-            Request::JsonCall { .. } => "json_call",
-            Request::Ping { .. } => "ping",
-            Request::PtyExec { .. } => "pty_exec",
-        };
-        
-        // Look up handler
-        let handler = {
-            let handlers = self.handlers.read().await;
-            handlers.get(request_type).cloned()
-        };
+        if let Request::SessionOpen { id, resume_token, compression, dictionary_id, attachment_threshold } = request {
+            let (token, resumed, responses) = self.resume.open(resume_token);
+            info!("Opened session {} (resumed: {}, retained responses: {})", token, resumed, responses.len());
+            self.session_token = Some(token);
+            // A resumed session keeps its working directory; a new one starts at the agent's
+            self.cwd = self.resume.cwd(token);
+            let dictionary_id = self.session_dictionary_id(compression, dictionary_id);
+            // Content is attached only if both ends would attach it
+            self.attachment_threshold = self.attachment_threshold
+                .zip(attachment_threshold)
+                .map(|(ours, theirs)| ours.max(theirs as usize));
+            let response = Response::SessionOpened {
+                request_id: id,
+                token,
+                resumed,
+                responses,
+                max_concurrent_requests: self.max_concurrent_requests.map(|max| max as u32),
+                in_flight: self.limited_in_flight() as u32,
+                compression: compression.or(self.reader.is_compressed().then_some(StreamCompression::Zstd)),
+                dictionary_id,
+                attachment_threshold: self.attachment_threshold.map(|threshold| u32::try_from(threshold).unwrap_or(u32::MAX)),
+            };
+            self.send_response(stream_id, sequence, response).await?;
+            if compression.is_some() {
+                self.enable_stream_compression(dictionary_id)?;
+            }
+            return Ok(());
+        }
         
-        let response = match handler {
-            Some(handler) => {
-                // Execute handler
-                match handler.handle(request).await {
-                    Ok(response) => response,
-                    Err(e) => {
-                        error!("Handler error for request {}: {}", request_id, e);
-                        Response::error(
-                            request_id,
-                            ErrorDetails::new(ErrorCode::InternalError, format!("Handler error: {}", e))
-                        )
-                    }
-                }
+        let mut request = request;
+        // A batch's requests are expanded and resolved as each starts, after any `Chdir` before it
+        if !matches!(request, Request::Batch { .. }) {
+            // Expand before resolving, so a `${VAR}` working directory is expanded as written
+            if let Err(error) = request.expand_env() {
+                let response = Response::error(request.id(), error);
+                return self.send_response(stream_id, sequence, response).await;
+            }
+            if let Some(cwd) = &self.cwd {
+                request.resolve_paths(cwd);
+            }
+        }
+        match request {
+            Request::Chdir { id, path } => {
+                let response = self.change_dir(id, path).await;
+                return self.send_response(stream_id, sequence, response).await;
+            }
+            Request::Getcwd { id } => {
+                let response = match self.cwd.clone().map(Ok).unwrap_or_else(std::env::current_dir) {
+                    Ok(path) => Response::Cwd { request_id: id, path },
+                    Err(e) => Response::error(
+                        id,
+                        ErrorDetails::new(ErrorCode::InternalError, format!("Failed to get working directory: {}", e))
+                    ),
+                };
+                return self.send_response(stream_id, sequence, response).await;
+            }
+            Request::MkTemp { id, dir, prefix, suffix, kind, cleanup } => {
+                let response = self.make_temp(id, dir, prefix, suffix, kind, cleanup).await;
+                return self.send_response(stream_id, sequence, response).await;
             }
-            None => {
-                warn!("No handler registered for request type: {}", request_type);
-                Response::error(
+            Request::SessionClose { id } => {
+                self.closing = Some(PendingClose { stream_id, sequence, request_id: id });
+                return Ok(());
+            }
+            Request::ListOperations { id } => {
+                let response = self.list_operations(id);
+                return self.send_response(stream_id, sequence, response).await;
+            }
+            Request::CancelOperation { id, target } => {
+                let response = self.cancel_operation(id, target).await;
+                return self.send_response(stream_id, sequence, response).await;
+            }
+            _ => {}
+        }
+        
+        if let Some(key) = &idempotency_key {
+            // A repeat would take over the stream input of the first request
+            if request.has_stream_input() {
+                let response = Response::error(
                     request_id,
-                    ErrorDetails::new(ErrorCode::Unsupported, format!("Unsupported request type: {}", request_type))
-                )
+                    ErrorDetails::new(ErrorCode::InvalidRequest, "Requests with stream input cannot have an idempotency key")
+                );
+                return self.send_response(stream_id, sequence, response).await;
             }
-        };
+            let waiter = Waiter { stream_id, sequence, tx: self.response_tx.clone() };
+            match self.idempotency.claim(key, waiter) {
+                Claim::Run => {}
+                Claim::Done(response) => return self.send_response(stream_id, sequence, response).await,
+                Claim::Waiting => return Ok(()),
+            }
+        }
+        
+        if let Request::Batch { id, requests, stop_on_error } = request {
+            debug!("Running batch {} of {} requests", id, requests.len());
+            self.batches.insert(stream_id, PendingBatch {
+                request_id: id,
+                sequence,
+                started: Instant::now(),
+                remaining: requests.into(),
+                responses: Vec::new(),
+                stop_on_error,
+                labels,
+                idempotency_key,
+                current: None,
+            });
+            self.batch_ready.push_back(stream_id);
+            return Ok(());
+        }
         
-        // Send response
-        self.send_response(stream_id, sequence, response).await?;
+        if let Some(max) = self.max_concurrent_requests.filter(|_| request.counts_toward_limit()) {
+            if self.limited_in_flight() >= max {
+                if !request.has_stream_input() && self.queued.len() < self.queue_capacity {
+                    debug!("Queueing {:?} request {} behind {} in flight", qos, request_id, self.in_flight);
+                    self.queued.push(QueuedRequest { stream_id, sequence, request, qos, idempotency_key, labels });
+                    return Ok(());
+                }
+                warn!("Rejecting request {}: {} requests already in flight", request_id, self.in_flight);
+                let response = Response::error(
+                    request_id,
+                    ErrorDetails::new(ErrorCode::Overloaded, format!("Agent is handling its maximum of {} requests", max))
+                );
+                // The request never ran, so a retry with the same key should run it
+                if let Some(key) = &idempotency_key {
+                    self.idempotency.release(key, &response);
+                }
+                return self.send_response(stream_id, sequence, response).await;
+            }
+        }
         
+        self.start_request(stream_id, sequence, request, qos, idempotency_key, labels).await;
         Ok(())
     }
     
-    /// Send a response message
-    async fn send_response(&mut self, stream_id: u32, sequence: u32, response: Response) -> Result<()> {
-        let message = Message::response(response);
-        let payload = rmp_serde::to_vec(&message)
-            .context("Failed to serialize response message")?;
-        
-        let frame = Frame::data(stream_id, sequence, Bytes::from(payload));
-        self.codec.write_frame(&mut self.writer, &frame).await
-            .context("Failed to write response frame")?;
-        
-        debug!("Sent response: stream_id={}, sequence={}", stream_id, sequence);
-        Ok(())
+    /// Requests in flight that count toward the concurrency limit
+    fn limited_in_flight(&self) -> usize {
+        self.in_flight - self.uncounted
+    }
+    
+    /// Stop counting a request whose final response is settled as in flight
+    fn release_slot(&mut self, running: &RunningRequest) {
+        self.in_flight -= 1;
+        if !running.counted {
+            self.uncounted -= 1;
+        }
+    }
+    
+    /// Start queued requests while there are free slots
+    async fn start_queued(&mut self) {
+        while self.max_concurrent_requests.map_or(true, |max| self.limited_in_flight() < max) {
+            let Some(queued) = self.queued.pop() else {
+                break;
+            };
+            debug!("Starting queued request {}", queued.request.id());
+            self.start_request(queued.stream_id, queued.sequence, queued.request, queued.qos, queued.idempotency_key, queued.labels).await;
+        }
+    }
+    
+    /// Queue handler output for writing under the QoS class of its request
+    fn queue_output(&mut self, output: HandlerOutput) {
+        if self.aborted_streams.contains_key(&output.stream_id) {
+            // Produced before the handler task saw its abort
+            return;
+        }
+        // Answers for requests waiting on an idempotency key have no running request
+        let qos = self.running.get(&output.stream_id).map_or(QosClass::default(), |running| running.qos);
+        self.outputs.push(output, qos);
+    }
+    
+    /// Streams of aborted requests whose handler task has stopped
+    ///
+    /// A stopped task's output is all in `response_rx` already, so the stream
+    /// can be forgotten once the channel has been drained after this check.
+    fn stopped_aborts(&self) -> Vec<u32> {
+        self.aborted_streams.iter()
+            .filter(|(_, task)| task.is_finished())
+            .map(|(stream_id, _)| *stream_id)
+            .collect()
+    }
+    
+    /// Write as many queued messages as there are streams with output waiting,
+    /// taking the streams of the highest QoS class in turn
+    ///
+    /// Streams with more output go around again on a later round, after the
+    /// loop has had a chance to read input and take newly produced output.
+    async fn write_output_round(&mut self) {
+        for _ in 0..self.outputs.streams() {
+            let Some(output) = self.outputs.pop() else {
+                break;
+            };
+            let final_response = match &output.message {
+                Message::Response(response) if output.last => Some(response.clone()),
+                _ => None,
+            };
+            // Encoded all the same, so it counts toward the request's response limit
+            let batched = self.batch_streams.contains_key(&output.stream_id).then(|| output.message.clone());
+            let frame = match output.message.to_frame(output.stream_id, output.sequence, self.attachment_threshold) {
+                Ok(frame) => Some(frame),
+                Err(e) => {
+                    error!("Failed to serialize response: {}", e);
+                    None
+                }
+            };
+            // Requests with an output window are held to the client's pace instead
+            let capped = self.max_response_bytes.filter(|_| !self.output_windows.contains_key(&output.stream_id));
+            if let (Some(limit), Some(frame)) = (capped, &frame) {
+                if let Some(running) = self.running.get_mut(&output.stream_id) {
+                    running.bytes_written += (frame.payload.len() + frame.attachment.len()) as u64;
+                    if running.bytes_written > limit {
+                        self.abort_oversized(output.stream_id, limit).await;
+                        continue;
+                    }
+                }
+            }
+            if output.last {
+                if let Some(running) = self.running.remove(&output.stream_id) {
+                    self.release_slot(&running);
+                }
+                self.close_output_window(output.stream_id);
+                // Retain before writing so a response lost with the connection can be resumed
+                if let (Some(token), Some(response), false) = (self.session_token, final_response, batched.is_some()) {
+                    self.resume.retain(token, response);
+                }
+            }
+            if let Some(message) = batched {
+                self.forward_batched(output.stream_id, message).await;
+            } else if let Some(frame) = frame {
+                if let Err(e) = self.send_frame(&frame).await {
+                    error!("Error sending response: {}", e);
+                }
+            }
+            if output.last {
+                self.start_queued().await;
+            }
+        }
+    }
+    
+    /// End a request whose responses went past `limit` bytes with `ErrorCode::ResponseTooLarge`
+    ///
+    /// The handler task is aborted and whatever it already produced is dropped.
+    async fn abort_oversized(&mut self, stream_id: u32, limit: u64) {
+        let Some(running) = self.running.remove(&stream_id) else {
+            return;
+        };
+        warn!("Request {} went past the {} byte response limit", running.request_id, limit);
+        let error = ErrorDetails::new(ErrorCode::ResponseTooLarge, format!("Responses exceeded the {} byte limit", limit));
+        self.abort_running(stream_id, running, error).await;
+    }
+    
+    /// Stop a finished request's handler waiting for output credit it won't get
+    fn close_output_window(&mut self, stream_id: u32) {
+        if let Some(window) = self.output_windows.remove(&stream_id) {
+            window.credit.permits.close();
+        }
+    }
+    
+    /// Requests and batches running on this connection, oldest first, for `ListOperations`
+    fn list_operations(&self, id: Uuid) -> Response {
+        let running = self.running.iter()
+            .map(|(&stream_id, running)| (running.started, running.request_id, running.request_type, stream_id));
+        let batches = self.batches.iter()
+            .map(|(&stream_id, batch)| (batch.started, batch.request_id, "batch", stream_id));
+        let mut operations: Vec<_> = running.chain(batches).collect();
+        operations.sort_by_key(|(started, ..)| *started);
+        let operations = operations.into_iter()
+            .map(|(started, request_id, request_type, stream_id)| OperationInfo {
+                id: request_id,
+                request_type: request_type.to_string(),
+                age_ms: started.elapsed().as_millis() as u64,
+                stream_id,
+            })
+            .collect();
+        Response::Operations { request_id: id, operations }
+    }
+    
+    /// Cancel the running request `target` for `CancelOperation`
+    async fn cancel_operation(&mut self, id: Uuid, target: Uuid) -> Response {
+        let stream_id = self.running.iter()
+            .find(|(_, running)| running.request_id == target)
+            .map(|(&stream_id, _)| stream_id);
+        let Some((stream_id, running)) = stream_id.and_then(|stream_id| self.running.remove_entry(&stream_id)) else {
+            let batch = self.batches.iter()
+                .find(|(_, batch)| batch.request_id == target)
+                .map(|(&stream_id, _)| stream_id);
+            if let Some(stream_id) = batch {
+                info!("Cancelling batch {}", target);
+                self.abort_batch(stream_id, ErrorDetails::new(ErrorCode::Cancelled, "Batch was cancelled")).await;
+                return Response::OperationCancelled { request_id: id, target };
+            }
+            return Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::NotFound, format!("No request {} is running", target))
+            );
+        };
+        info!("Cancelling request {}", target);
+        let error = ErrorDetails::new(ErrorCode::Cancelled, "Request was cancelled");
+        self.abort_running(stream_id, running, error).await;
+        Response::OperationCancelled { request_id: id, target }
+    }
+    
+    /// Abort a request's handler task and end the request with `error`
+    ///
+    /// Whatever the handler already produced is dropped.
+    async fn abort_running(&mut self, stream_id: u32, running: RunningRequest, error: ErrorDetails) {
+        running.task.abort();
+        self.stream_inputs.remove(&stream_id);
+        self.close_output_window(stream_id);
+        self.outputs.discard(stream_id);
+        self.aborted_streams.insert(stream_id, running.task.clone());
+        self.release_slot(&running);
+        
+        let response = Response::error(running.request_id, error);
+        if let Some(key) = &running.idempotency_key {
+            self.idempotency.release(key, &response);
+        }
+        if let Some(token) = self.session_token.filter(|_| !self.batch_streams.contains_key(&stream_id)) {
+            self.resume.retain(token, response.clone());
+        }
+        if let Err(e) = self.send_message(stream_id, running.sequence, Message::response(response)).await {
+            error!("Error sending response: {}", e);
+        }
+        self.start_queued().await;
+    }
+    
+    /// End every request and batch still running or queued with `ErrorCode::Cancelled`
+    ///
+    /// The batch the close itself arrived in is left to answer after it.
+    async fn abort_remaining(&mut self) {
+        let error = ErrorDetails::new(ErrorCode::Cancelled, "Session closed before the request finished");
+        let closing_batch = self.closing.as_ref()
+            .and_then(|close| self.batch_streams.get(&close.stream_id))
+            .copied();
+        
+        // Emptied first so aborting running requests doesn't start queued ones
+        while let Some(queued) = self.queued.pop() {
+            // A batched request is answered by its batch
+            if self.batch_streams.remove(&queued.stream_id).is_some() {
+                continue;
+            }
+            let response = Response::error(queued.request.id(), error.clone());
+            if let Some(key) = &queued.idempotency_key {
+                self.idempotency.release(key, &response);
+            }
+            if let Err(e) = self.send_response(queued.stream_id, queued.sequence, response).await {
+                error!("Error sending response: {}", e);
+            }
+        }
+        
+        let batches: Vec<u32> = self.batches.keys().copied().filter(|&stream_id| Some(stream_id) != closing_batch).collect();
+        for stream_id in batches {
+            self.abort_batch(stream_id, error.clone()).await;
+        }
+        let running: Vec<u32> = self.running.keys().copied().collect();
+        for stream_id in running {
+            if let Some(running) = self.running.remove(&stream_id) {
+                self.abort_running(stream_id, running, error.clone()).await;
+            }
+        }
+    }
+    
+    /// Start the next request of each batch that is ready for it
+    async fn advance_batches(&mut self) {
+        while let Some(batch_stream) = self.batch_ready.pop_front() {
+            self.next_in_batch(batch_stream).await;
+        }
+    }
+    
+    /// Start the next request of a batch, or answer the batch once none are left
+    ///
+    /// The request is handled like one the client sent on a stream of its
+    /// own, under the batch's labels; its final response comes back to the
+    /// batch instead of going to the client.
+    async fn next_in_batch(&mut self, batch_stream: u32) {
+        let Some(batch) = self.batches.get_mut(&batch_stream) else {
+            return;
+        };
+        let stopped = batch.stop_on_error && batch.responses.last().is_some_and(Response::is_failure);
+        let next = if stopped { None } else { batch.remaining.pop_front() };
+        let Some(request) = next else {
+            if stopped {
+                debug!("Batch {} stopped after {} requests, skipping {}", batch.request_id, batch.responses.len(), batch.remaining.len());
+            }
+            if let Some(batch) = self.batches.remove(&batch_stream) {
+                self.finish_batch(batch_stream, batch).await;
+            }
+            return;
+        };
+        
+        let stream_id = self.next_batch_stream;
+        self.next_batch_stream = stream_id.checked_add(1).unwrap_or(BATCH_STREAM_BASE);
+        batch.current = Some(stream_id);
+        let sequence = batch.sequence;
+        let refusal = match request.inner() {
+            Request::Batch { .. } => Some("Batches cannot be nested"),
+            Request::SessionOpen { .. } => Some("Sessions cannot be opened in a batch"),
+            Request::SessionClose { .. } if !batch.remaining.is_empty() => Some("A batch can only close the session with its last request"),
+            _ if request.has_stream_input() => Some("Requests with stream input cannot be batched"),
+            _ => None,
+        };
+        if let Some(reason) = refusal {
+            batch.responses.push(Response::error(request.id(), ErrorDetails::new(ErrorCode::InvalidRequest, reason)));
+            self.batch_ready.push_back(batch_stream);
+            return;
+        }
+        let request = if batch.labels.is_empty() {
+            request
+        } else {
+            // The request's own labels win over the batch's
+            let mut labels = batch.labels.clone();
+            labels.extend(request.labels().cloned().unwrap_or_default());
+            Request::WithLabels { labels, request: Box::new(request) }
+        };
+        
+        self.batch_streams.insert(stream_id, batch_stream);
+        if let Err(e) = self.handle_request(stream_id, sequence, request).await {
+            error!("Error handling batched request: {}", e);
+        }
+    }
+    
+    /// Send a batch its `BatchResult`
+    async fn finish_batch(&mut self, stream_id: u32, batch: PendingBatch) {
+        debug!("Batch {} finished after {} requests", batch.request_id, batch.responses.len());
+        let response = Response::BatchResult { request_id: batch.request_id, responses: batch.responses };
+        if let Some(key) = &batch.idempotency_key {
+            self.idempotency.complete(key, &response);
+        }
+        if let Some(token) = self.session_token {
+            self.resume.retain(token, response.clone());
+        }
+        if let Err(e) = self.write_message(stream_id, batch.sequence, Message::response(response)).await {
+            error!("Error sending batch result: {}", e);
+        }
+    }
+    
+    /// End the batch that arrived on `stream_id` with `error`, returning whether there was one
+    ///
+    /// Its current request is aborted and the ones after it are not run.
+    async fn abort_batch(&mut self, stream_id: u32, error: ErrorDetails) -> bool {
+        let Some(batch) = self.batches.remove(&stream_id) else {
+            return false;
+        };
+        self.batch_ready.retain(|ready| *ready != stream_id);
+        if let Some(current) = batch.current {
+            if let Some(running) = self.running.remove(&current) {
+                self.abort_running(current, running, error.clone()).await;
+            } else if self.queued.remove(current).is_some() {
+                self.batch_streams.remove(&current);
+            }
+        }
+        
+        let response = Response::error(batch.request_id, error);
+        if let Some(key) = &batch.idempotency_key {
+            self.idempotency.release(key, &response);
+        }
+        if let Some(token) = self.session_token {
+            self.resume.retain(token, response.clone());
+        }
+        if let Err(e) = self.write_message(stream_id, batch.sequence, Message::response(response)).await {
+            error!("Error sending response: {}", e);
+        }
+        true
+    }
+    
+    /// Take a message for a batched request's stream
+    ///
+    /// Its final response goes to its batch, which starts the next request;
+    /// partial responses are dropped. Keepalives and progress become
+    /// keepalives of the batch, so the client doesn't time it out.
+    async fn forward_batched(&mut self, stream_id: u32, message: Message) {
+        let Some(&batch_stream) = self.batch_streams.get(&stream_id) else {
+            return;
+        };
+        match message {
+            Message::Response(response) if response.is_partial() => {
+                debug!("Dropping partial response of batched request {}", response.request_id());
+            }
+            Message::Response(response) => {
+                self.batch_streams.remove(&stream_id);
+                // The batch is gone if it was aborted
+                if let Some(batch) = self.batches.get_mut(&batch_stream) {
+                    batch.current = None;
+                    batch.responses.push(response);
+                    self.batch_ready.push_back(batch_stream);
+                }
+            }
+            Message::Event(event) => {
+                let Some(batch) = self.batches.get(&batch_stream) else {
+                    return;
+                };
+                let event = match event {
+                    Event::Keepalive { .. } | Event::Progress { .. } => Event::Keepalive { request_id: batch.request_id },
+                    event => event,
+                };
+                let sequence = batch.sequence;
+                if let Err(e) = self.write_message(batch_stream, sequence, Message::Event(event)).await {
+                    error!("Error sending event: {}", e);
+                }
+            }
+            Message::Request(_) => {}
+        }
+    }
+    
+    /// Run a request's handler in the background, counting it as in flight
+    ///
+    /// A request claimed under an idempotency key stores its response for the key.
+    /// The handler runs in a span carrying the request's labels, which also go
+    /// into any audit records it makes.
+    async fn start_request(
+        &mut self,
+        stream_id: u32,
+        sequence: u32,
+        request: Request,
+        qos: QosClass,
+        idempotency_key: Option<String>,
+        labels: HashMap<String, String>,
+    ) {
+        let request_id = request.id();
+        let request_type = request_type(&request);
+        let counted = request.counts_toward_limit();
+        
+        // Look up handler
+        let handler = {
+            let handlers = self.handlers.read().await;
+            handlers.get(request_type).cloned()
+        };
+        
+        let input = if request.has_stream_input() {
+            Some(self.open_input(stream_id))
+        } else {
+            None
+        };
+        
+        // Run the handler in its own task so long-running requests don't block the loop
+        let response_tx = self.response_tx.clone();
+        let mut output = ResponseSink::new(stream_id, response_tx.clone());
+        // A batch drops partial responses, so its requests aren't held to a window nobody grants
+        if request.has_output_window() && !self.batch_streams.contains_key(&stream_id) {
+            let credit = Arc::new(OutputCredit::new(STREAM_OUTPUT_WINDOW));
+            self.output_windows.insert(stream_id, OutputWindow { credit: Arc::clone(&credit), owed: 0 });
+            output = output.with_window(credit);
+        }
+        if let Some(limit) = self.stream_send_rate_limit {
+            output = output.with_rate_limiter(Arc::new(RateLimiter::new(limit)));
+        }
+        if let Some(limiter) = &self.send_limiter {
+            output = output.with_rate_limiter(Arc::clone(limiter));
+        }
+        let stream = RequestStream { input, output };
+        let idempotency = self.idempotency.clone();
+        let keepalive = self.keepalive_interval.map(|interval| (interval, stream.output.clone()));
+        let key = idempotency_key.clone();
+        let span = info_span!("request", id = %request_id, request_type, labels = %format_labels(&labels));
+        self.in_flight += 1;
+        if !counted {
+            self.uncounted += 1;
+        }
+        let guard = PanicGuard {
+            request_id,
+            stream_id,
+            sequence,
+            response_tx: response_tx.clone(),
+            idempotency: idempotency_key.clone().map(|key| (Arc::clone(&idempotency), key)),
+        };
+        let task = tokio::spawn(audit::with_labels(labels, async move {
+            let _guard = guard;
+            let handling = async move {
+                match (request, handler) {
+                    (request, Some(handler)) => {
+                        let result = handler.handle_stream(request, stream).await;
+                        match result {
+                            Ok(response) => response,
+                            Err(e) => {
+                                error!("Handler error for request {}: {}", request_id, e);
+                                Response::error(
+                                    request_id,
+                                    ErrorDetails::new(ErrorCode::InternalError, format!("Handler error: {}", e))
+                                )
+                            }
+                        }
+                    }
+                    (_, None) => {
+                        warn!("No handler registered for request type: {}", request_type);
+                        Response::error(
+                            request_id,
+                            ErrorDetails::new(ErrorCode::Unsupported, format!("Unsupported request type: {}", request_type))
+                        )
+                    }
+                }
+            };
+            let response = match keepalive {
+                Some((interval, sink)) => with_keepalive(handling, request_id, interval, sink).await,
+                None => handling.await,
+            };
+            
+            if let Some(key) = &idempotency_key {
+                idempotency.complete(key, &response);
+            }
+            let _ = response_tx.send(HandlerOutput {
+                stream_id,
+                sequence,
+                message: Message::response(response),
+                last: true,
+            });
+        }).instrument(span));
+        self.aborted_streams.remove(&stream_id);
+        self.running.insert(stream_id, RunningRequest {
+            request_id,
+            request_type,
+            started: Instant::now(),
+            sequence,
+            idempotency_key: key,
+            task: task.abort_handle(),
+            bytes_written: 0,
+            counted,
+            qos,
+        });
+    }
+    
+    /// Open the input of a request's stream, with a full window of credit
+    ///
+    /// Frames are taken off the connection as they arrive and queued for the
+    /// handler, which takes them at its own pace. Credit for a chunk goes
+    /// back to the client once the handler has room for it, so no more than
+    /// the window is ever queued.
+    fn open_input(&mut self, stream_id: u32) -> StreamInput {
+        let (chunks_tx, mut chunks_rx) = mpsc::unbounded_channel::<Bytes>();
+        let (input_tx, input_rx) = mpsc::channel(STREAM_INPUT_CAPACITY);
+        let credit_tx = self.credit_tx.clone();
+        tokio::spawn(async move {
+            while let Some(chunk) = chunks_rx.recv().await {
+                let len = chunk.len() as u32;
+                if input_tx.send(chunk).await.is_err() {
+                    break;
+                }
+                let _ = credit_tx.send((stream_id, len));
+            }
+        });
+        self.stream_inputs.insert(stream_id, InputWindow { tx: chunks_tx, credit: STREAM_INPUT_WINDOW as u64 });
+        input_rx
+    }
+    
+    /// Give a stream's client credit for `bytes` more input, with whatever other credit is due
+    async fn return_credit(&mut self, stream_id: u32, bytes: u32) {
+        let mut due: HashMap<u32, u32> = HashMap::from([(stream_id, bytes)]);
+        while let Ok((stream_id, bytes)) = self.credit_rx.try_recv() {
+            let total = due.entry(stream_id).or_default();
+            *total = total.saturating_add(bytes);
+        }
+        for (stream_id, delta) in due {
+            // Streams that ended since don't need any more
+            let Some(input) = self.stream_inputs.get_mut(&stream_id) else {
+                continue;
+            };
+            input.credit += delta as u64;
+            let frame = match (FlowControlMessage::WindowUpdate { delta }).to_frame(stream_id, 0) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    error!("Failed to encode window update: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.send_frame(&frame).await {
+                error!("Failed to send window update: {}", e);
+            }
+        }
+    }
+    
+    /// Change the working directory later requests of this session resolve against
+    ///
+    /// `path` is already resolved against the current working directory.
+    async fn change_dir(&mut self, id: Uuid, path: PathBuf) -> Response {
+        let path = match tokio::fs::canonicalize(&path).await {
+            Ok(path) => path,
+            Err(e) => {
+                let code = if e.kind() == std::io::ErrorKind::NotFound {
+                    ErrorCode::FileNotFound
+                } else {
+                    ErrorCode::InternalError
+                };
+                return Response::error(id, ErrorDetails::new(code, format!("Cannot change to {:?}: {}", path, e)));
+            }
+        };
+        if !tokio::fs::metadata(&path).await.map(|m| m.is_dir()).unwrap_or(false) {
+            return Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::InvalidRequest, format!("Not a directory: {:?}", path))
+            );
+        }
+        
+        debug!("Changed working directory to {:?}", path);
+        if let Some(token) = self.session_token {
+            self.resume.set_cwd(token, path.clone());
+        }
+        self.cwd = Some(path.clone());
+        Response::Cwd { request_id: id, path }
+    }
+    
+    /// Create a uniquely named temporary file or directory for `MkTemp`
+    ///
+    /// With `cleanup`, it is removed when this connection's loop stops.
+    async fn make_temp(
+        &mut self,
+        id: Uuid,
+        dir: Option<PathBuf>,
+        prefix: String,
+        suffix: String,
+        kind: TempKind,
+        cleanup: bool,
+    ) -> Response {
+        let dir = match std::path::absolute(dir.unwrap_or_else(std::env::temp_dir)) {
+            Ok(dir) => dir,
+            Err(e) => {
+                return Response::error(
+                    id,
+                    ErrorDetails::new(ErrorCode::InvalidRequest, format!("Invalid temporary directory: {}", e))
+                );
+            }
+        };
+        let parent = dir.clone();
+        let created = tokio::task::spawn_blocking(move || {
+            let mut builder = tempfile::Builder::new();
+            builder.prefix(&prefix).suffix(&suffix);
+            match kind {
+                TempKind::File => builder.tempfile_in(&parent).map(|file| TempEntry::File(file.into_temp_path())),
+                TempKind::Dir => builder.tempdir_in(&parent).map(TempEntry::Dir),
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+        
+        let entry = match created {
+            Ok(entry) => entry,
+            Err(e) => {
+                let code = match e.kind() {
+                    std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+                    std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                    _ => ErrorCode::InternalError,
+                };
+                return Response::error(id, ErrorDetails::new(code, format!("Cannot create temporary {:?} in {:?}: {}", kind, dir, e)));
+            }
+        };
+        
+        let path = entry.path().to_path_buf();
+        debug!("Created temporary {:?} {:?}", kind, path);
+        if cleanup {
+            self.temp_paths.push(entry);
+        } else if let Err(e) = entry.keep() {
+            return Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::InternalError, format!("Cannot keep temporary {:?}: {}", path, e))
+            );
+        }
+        Response::TempCreated { request_id: id, path }
+    }
+    
+    /// Remove the temporary files and directories created for this connection,
+    /// returning how many were removed
+    fn remove_temp_paths(&mut self) -> u32 {
+        let mut removed = 0;
+        for entry in self.temp_paths.drain(..) {
+            let path = entry.path().to_path_buf();
+            match entry.close() {
+                Ok(()) => {
+                    debug!("Removed temporary {:?}", path);
+                    removed += 1;
+                }
+                Err(e) => warn!("Failed to remove temporary {:?}: {}", path, e),
+            }
+        }
+        removed
+    }
+    
+    /// ID of the dictionary stream compression uses for a `SessionOpen`
+    ///
+    /// Once compression is enabled it keeps its dictionary; before, the agent's
+    /// dictionary is used if the client asked for compression with the same one.
+    fn session_dictionary_id(&self, compression: Option<StreamCompression>, requested: Option<u32>) -> Option<u32> {
+        if self.reader.is_compressed() {
+            return self.stream_dictionary_id;
+        }
+        let available = self.compression_dictionary.as_ref().map(CompressionDictionary::id);
+        if compression.is_some() && requested.is_some() && requested != available {
+            warn!("Client asked for compression dictionary {:?}, agent has {:?}; compressing without one", requested, available);
+        }
+        compression.and(requested).filter(|id| Some(*id) == available)
+    }
+    
+    /// Switch both directions of the connection to stream compression
+    ///
+    /// Called right after `SessionOpened` went out uncompressed; bytes the codec
+    /// already read past the `SessionOpen` frame belong to the compressed stream.
+    fn enable_stream_compression(&mut self, dictionary_id: Option<u32>) -> Result<()> {
+        if self.reader.is_compressed() {
+            return Ok(());
+        }
+        info!("Enabling connection stream compression (dictionary: {:?})", dictionary_id);
+        let dictionary = self.compression_dictionary.as_ref().filter(|_| dictionary_id.is_some());
+        self.writer.enable_compression_with(dictionary)
+            .context("Failed to start stream compressor")?;
+        let buffered = self.codec.take_buffered();
+        self.reader.enable_compression_with(&buffered, dictionary)
+            .context("Failed to start stream decompressor")?;
+        self.stream_dictionary_id = dictionary_id;
+        Ok(())
+    }
+    
+    /// Send a response message
+    async fn send_response(&mut self, stream_id: u32, sequence: u32, response: Response) -> Result<()> {
+        self.send_message(stream_id, sequence, Message::response(response)).await
+    }
+    
+    /// Send a response or event message
+    ///
+    /// Messages of a batched request go to its batch instead.
+    async fn send_message(&mut self, stream_id: u32, sequence: u32, message: Message) -> Result<()> {
+        if self.batch_streams.contains_key(&stream_id) {
+            self.forward_batched(stream_id, message).await;
+            return Ok(());
+        }
+        self.write_message(stream_id, sequence, message).await
+    }
+    
+    /// Write a response or event message to the client
+    async fn write_message(&mut self, stream_id: u32, sequence: u32, message: Message) -> Result<()> {
+        let frame = message.to_frame(stream_id, sequence, self.attachment_threshold)
+            .context("Failed to serialize message")?;
+        self.send_frame(&frame).await
+    }
+    
+    /// Write an encoded message frame
+    async fn send_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.codec.write_frame(&mut self.writer, frame).await
+            .context("Failed to write message frame")?;
+        
+        debug!("Sent message: stream_id={}, sequence={}", frame.stream_id, frame.sequence);
+        Ok(())
     }
     
     /// Send an error frame
@@ -248,6 +2036,129 @@ where
     }
 }
 
+#[cfg(feature = "jsonrpc")]
+impl<R, W> AgentLoop<R, W>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    /// Peek at the first input byte to see whether the client speaks JSON-RPC
+    async fn detect_json_rpc(&mut self) -> Result<bool> {
+        use mitoxide_proto::jsonrpc::JSONRPC_HANDSHAKE_BYTE;
+        use tokio::io::AsyncBufReadExt;
+        
+        let buffered = self.reader.get_mut().fill_buf().await
+            .context("Failed to read from input")?;
+        Ok(buffered.first() == Some(&JSONRPC_HANDSHAKE_BYTE))
+    }
+    
+    /// Serve newline-delimited JSON-RPC 2.0 requests until input closes
+    async fn run_json_rpc(&mut self, mut shutdown_rx: oneshot::Receiver<ShutdownReason>) -> Result<ShutdownReason> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+        
+        let mut line = Vec::new();
+        let reason = loop {
+            line.clear();
+            let read = tokio::select! {
+                reason = &mut shutdown_rx => {
+                    info!("Received shutdown signal, stopping agent loop");
+                    break reason.unwrap_or(ShutdownReason::CleanShutdown);
+                }
+                read = self.reader.get_mut().read_until(b'\n', &mut line) => read,
+            };
+            match read {
+                Ok(0) => {
+                    info!("Input stream closed, stopping agent loop");
+                    break ShutdownReason::TransportClosed;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed to read JSON-RPC request: {}", e);
+                    break ShutdownReason::TransportLost(e.to_string());
+                }
+            }
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            
+            if let Some(response) = self.handle_json_rpc(&line).await {
+                let mut payload = serde_json::to_vec(&response)
+                    .context("Failed to serialize JSON-RPC response")?;
+                payload.push(b'\n');
+                let writer = self.writer.get_mut();
+                if let Err(e) = async { writer.write_all(&payload).await?; writer.flush().await }.await {
+                    error!("Failed to write JSON-RPC response: {}", e);
+                    break ShutdownReason::TransportLost(e.to_string());
+                }
+            }
+        };
+        
+        info!(reason = %reason, exit_code = reason.exit_code(), "Agent loop stopped");
+        Ok(reason)
+    }
+    
+    /// Handle a single JSON-RPC request line, returning `None` for notifications
+    ///
+    /// Takes `&mut self` so the loop's future is `Send` without the writer being `Sync`.
+    async fn handle_json_rpc(&mut self, line: &[u8]) -> Option<mitoxide_proto::jsonrpc::JsonRpcResponse> {
+        use mitoxide_proto::jsonrpc::{
+            JsonRpcError, JsonRpcRequest, JsonRpcResponse, INVALID_REQUEST, PARSE_ERROR,
+        };
+        use serde_json::Value;
+        
+        let value: Value = match serde_json::from_slice(line) {
+            Ok(value) => value,
+            Err(e) => {
+                return Some(JsonRpcResponse::failure(
+                    Value::Null,
+                    JsonRpcError::new(PARSE_ERROR, format!("Parse error: {}", e)),
+                ));
+            }
+        };
+        let id = value.get("id").cloned().unwrap_or(Value::Null);
+        let request: JsonRpcRequest = match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => {
+                return Some(JsonRpcResponse::failure(
+                    id,
+                    JsonRpcError::new(INVALID_REQUEST, format!("Invalid request: {}", e)),
+                ));
+            }
+        };
+        
+        let response = match request.to_request() {
+            Ok(call) => {
+                let request_id = call.id();
+                let handler = {
+                    let handlers = self.handlers.read().await;
+                    handlers.get(request_type(&call)).cloned()
+                };
+                match handler {
+                    Some(handler) => handler.handle(call).await.unwrap_or_else(|e| {
+                        error!("Handler error for request {}: {}", request_id, e);
+                        Response::error(
+                            request_id,
+                            ErrorDetails::new(ErrorCode::InternalError, format!("Handler error: {}", e))
+                        )
+                    }),
+                    None => Response::error(
+                        request_id,
+                        ErrorDetails::new(ErrorCode::Unsupported, format!("Method not found: {}", request.method))
+                    ),
+                }
+            }
+            Err(_) if request.is_notification() => return None,
+            Err(error) => return Some(JsonRpcResponse::failure(id, error)),
+        };
+        
+        if request.is_notification() {
+            None
+        } else {
+            Some(JsonRpcResponse::from_response(id, response))
+        }
+    }
+}
+
 impl<R, W> Default for AgentLoop<R, W>
 where
     R: AsyncRead + Unpin + Send + Default,
@@ -306,24 +2217,58 @@ mod tests {
     
     #[tokio::test]
     async fn test_graceful_shutdown() {
-        let input = Cursor::new(Vec::<u8>::new());
-        let output = Cursor::new(Vec::<u8>::new());
-        let mut agent = AgentLoop::with_io(input, output);
-        
-        let shutdown_tx = agent.shutdown_sender().unwrap();
+        for reason in [ShutdownReason::CleanShutdown, ShutdownReason::Signal(15)] {
+            // The client stays connected, so only the shutdown signal stops the loop
+            let (_client, agent_io) = tokio::io::duplex(1024);
+            let (input, output) = tokio::io::split(agent_io);
+            let mut agent = AgentLoop::with_io(input, output);
+            
+            let shutdown_tx = agent.shutdown_sender().unwrap();
+            
+            // Start the agent loop in a task
+            let agent_task = tokio::spawn(async move {
+                agent.run().await
+            });
+            
+            // Send shutdown signal
+            shutdown_tx.send(reason.clone()).unwrap();
+            
+            // Agent should stop gracefully, reporting the reason it was given
+            let result = timeout(Duration::from_secs(1), agent_task).await;
+            assert!(result.is_ok());
+            assert_eq!(result.unwrap().unwrap().unwrap(), reason);
+        }
+        assert_eq!(ShutdownReason::Signal(15).exit_code(), 143);
+    }
+    
+    #[tokio::test]
+    async fn test_shutdown_reason_from_input() {
+        async fn run(input: Vec<u8>) -> ShutdownReason {
+            let mut agent = AgentLoop::with_io(Cursor::new(input), Cursor::new(Vec::<u8>::new()));
+            timeout(Duration::from_secs(1), agent.run()).await.unwrap().unwrap()
+        }
+        let codec = FrameCodec::new();
+        let ping = Frame::data(1, 1, Bytes::from(rmp_serde::to_vec(&Message::request(Request::ping())).unwrap()));
+        let ping = codec.encode_frame(&ping).unwrap().to_vec();
         
-        // Start the agent loop in a task
-        let agent_task = tokio::spawn(async move {
-            agent.run().await
-        });
+        // The client closing its input is a normal end, but not part way through a frame
+        assert_eq!(run(ping.clone()).await, ShutdownReason::TransportClosed);
+        assert_eq!(ShutdownReason::TransportClosed.exit_code(), 0);
+        let reason = run(ping[..ping.len() - 1].to_vec()).await;
+        assert!(matches!(reason, ShutdownReason::TransportLost(_)), "{:?}", reason);
+        assert_eq!(reason.exit_code(), 3);
         
-        // Send shutdown signal
-        shutdown_tx.send(()).unwrap();
+        // A frame that can't be decoded is skipped
+        let mut garbled = vec![0, 0, 0, 4, 0xFF, 0xFF, 0xFF, 0xFF];
+        garbled.extend_from_slice(&ping);
+        assert_eq!(run(garbled).await, ShutdownReason::TransportClosed);
         
-        // Agent should stop gracefully
-        let result = timeout(Duration::from_secs(1), agent_task).await;
-        assert!(result.is_ok());
-        assert!(result.unwrap().unwrap().is_ok());
+        // An oversized length prefix leaves no way to find the next frame
+        let mut oversized = u32::MAX.to_be_bytes().to_vec();
+        oversized.extend_from_slice(&ping);
+        let reason = run(oversized).await;
+        assert!(matches!(&reason, ShutdownReason::FatalProtocolError(error) if error.contains("too large")), "{:?}", reason);
+        assert_eq!(reason.exit_code(), 4);
     }
     
     #[tokio::test]
@@ -359,24 +2304,1528 @@ mod tests {
     }
     
     #[tokio::test]
-    async fn test_invalid_message_handling() {
-        // Create frame with invalid payload
-        let frame = Frame::data(1, 1, Bytes::from(vec![0xFF, 0xFF, 0xFF, 0xFF]));
+    async fn test_session_resume_after_disconnect() {
+        let store = Arc::new(ResumeStore::new());
+        let mut codec = FrameCodec::new();
+        let frame = |stream_id: u32, request: Request| {
+            let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+            Frame::data(stream_id, 1, Bytes::from(payload))
+        };
+        let response = |frame: Frame| match rmp_serde::from_slice::<Message>(&frame.payload).unwrap() {
+            Message::Response(response) => response,
+            other => panic!("Expected response, got {:?}", other),
+        };
         
-        let input = Cursor::new(Vec::<u8>::new());
-        let output = Cursor::new(Vec::<u8>::new());
-        let mut agent = AgentLoop::with_io(input, output);
+        // First connection opens a session, then goes away before reading the ping's response
+        let (mut client, agent_io) = tokio::io::duplex(64 * 1024);
+        let (agent_reader, agent_writer) = tokio::io::split(agent_io);
+        let mut agent = AgentLoop::with_io(agent_reader, agent_writer).with_resume_store(store.clone());
+        agent.register_handler("ping".to_string(), Arc::new(MockHandler {
+            response: Response::pong(Uuid::new_v4(), 0),
+        })).await;
+        let agent_task = tokio::spawn(async move { agent.run().await });
         
-        // Should handle invalid message gracefully
-        let result = agent.process_frame(frame).await;
-        assert!(result.is_ok());
+        codec.write_frame(&mut client, &frame(1, Request::session_open(None))).await.unwrap();
+        let token = match response(codec.read_frame(&mut client).await.unwrap().unwrap()) {
+            Response::SessionOpened { token, resumed, responses, .. } => {
+                assert!(!resumed);
+                assert!(responses.is_empty());
+                token
+            }
+            other => panic!("Expected SessionOpened, got {:?}", other),
+        };
+        
+        let ping = Request::ping();
+        let ping_id = ping.id();
+        codec.write_frame(&mut client, &frame(3, ping)).await.unwrap();
+        drop(client);
+        timeout(Duration::from_secs(5), agent_task).await.unwrap().unwrap().unwrap();
+        
+        // Second connection presents the token and collects the missed response
+        let input = codec.encode_frame(&frame(1, Request::session_open(Some(token)))).unwrap();
+        let mut agent = AgentLoop::with_io(Cursor::new(input.to_vec()), Cursor::new(Vec::<u8>::new()))
+            .with_resume_store(store.clone());
+        timeout(Duration::from_secs(5), agent.run()).await.unwrap().unwrap();
+        
+        let mut output = Cursor::new(agent.writer.get_ref().get_ref().clone());
+        match response(FrameCodec::new().read_frame(&mut output).await.unwrap().unwrap()) {
+            Response::SessionOpened { token: resumed_token, resumed, responses, .. } => {
+                assert_eq!(resumed_token, token);
+                assert!(resumed);
+                assert_eq!(responses.len(), 1);
+                assert!(matches!(responses[0], Response::Pong { request_id, .. } if request_id == ping_id));
+            }
+            other => panic!("Expected SessionOpened, got {:?}", other),
+        }
     }
     
     #[tokio::test]
-    async fn test_unsupported_request_handling() {
-        // Create a request without a registered handler
-        let request = Request::process_exec(
-            vec!["echo".to_string()],
+    async fn test_session_agrees_on_attachment_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        std::fs::write(&path, vec![7u8; 100 * 1024]).unwrap();
+        
+        // The larger threshold wins, and a client taking no attachments gets none
+        for (client_threshold, agreed) in [
+            (Some(1024), Some(DEFAULT_ATTACHMENT_THRESHOLD as u32)),
+            (Some(1024 * 1024), Some(1024 * 1024)),
+            (None, None),
+        ] {
+            let (mut client, agent_io) = tokio::io::duplex(1024 * 1024);
+            let (agent_reader, agent_writer) = tokio::io::split(agent_io);
+            let mut agent = AgentLoop::with_io(agent_reader, agent_writer);
+            agent.register_handler("file_get".to_string(), Arc::new(crate::handlers::FileHandler::new())).await;
+            tokio::spawn(async move { agent.run().await });
+            
+            let mut codec = FrameCodec::new();
+            let request = Request::SessionOpen {
+                id: Uuid::new_v4(),
+                resume_token: None,
+                compression: None,
+                dictionary_id: None,
+                attachment_threshold: client_threshold,
+            };
+            let frame = Message::request(request).to_frame(1, 0, None).unwrap();
+            codec.write_frame(&mut client, &frame).await.unwrap();
+            match Message::from_frame(codec.read_frame(&mut client).await.unwrap().unwrap()).unwrap() {
+                Message::Response(Response::SessionOpened { attachment_threshold, .. }) => assert_eq!(attachment_threshold, agreed),
+                other => panic!("Expected SessionOpened, got {:?}", other),
+            }
+            
+            let frame = Message::request(Request::file_get(path.clone(), None)).to_frame(3, 0, None).unwrap();
+            codec.write_frame(&mut client, &frame).await.unwrap();
+            let frame = codec.read_frame(&mut client).await.unwrap().unwrap();
+            assert_eq!(frame.has_attachment(), agreed.is_some_and(|agreed| agreed <= 100 * 1024));
+            match Message::from_frame(frame).unwrap() {
+                Message::Response(Response::FileContent { content, .. }) => assert_eq!(content.len(), 100 * 1024),
+                other => panic!("Expected FileContent, got {:?}", other),
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_run_with_serves_connections_over_duplex() {
+        let agent = AgentLoop::with_io(tokio::io::empty(), tokio::io::sink()).with_max_concurrent_requests(4);
+        agent.register_handler("ping".to_string(), Arc::new(MockHandler {
+            response: Response::pong(Uuid::new_v4(), 0),
+        })).await;
+        
+        let round_trip = |agent_io: tokio::io::DuplexStream, mut client: tokio::io::DuplexStream| {
+            let agent = &agent;
+            async move {
+                let (agent_reader, agent_writer) = tokio::io::split(agent_io);
+                let client_side = async move {
+                    let mut codec = FrameCodec::new();
+                    let mut responses = Vec::new();
+                    for (stream_id, request) in [(1, Request::session_open(None)), (3, Request::ping())] {
+                        let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+                        codec.write_frame(&mut client, &Frame::data(stream_id, 1, Bytes::from(payload))).await.unwrap();
+                        let frame = codec.read_frame(&mut client).await.unwrap().unwrap();
+                        match rmp_serde::from_slice::<Message>(&frame.payload).unwrap() {
+                            Message::Response(response) => responses.push(response),
+                            other => panic!("Expected response, got {:?}", other),
+                        }
+                    }
+                    // Closing the client ends the agent's side of the connection
+                    drop(client);
+                    responses
+                };
+                let (served, responses) = tokio::join!(agent.run_with(agent_reader, agent_writer), client_side);
+                served.unwrap();
+                responses
+            }
+        };
+        
+        // Two connections served at once by the same configured loop
+        let (first_agent, first_client) = tokio::io::duplex(64 * 1024);
+        let (second_agent, second_client) = tokio::io::duplex(64 * 1024);
+        let (first, second) = timeout(Duration::from_secs(5), async {
+            tokio::join!(round_trip(first_agent, first_client), round_trip(second_agent, second_client))
+        }).await.unwrap();
+        
+        for responses in [first, second] {
+            match &responses[0] {
+                Response::SessionOpened { max_concurrent_requests, .. } => assert_eq!(*max_concurrent_requests, Some(4)),
+                other => panic!("Expected SessionOpened, got {:?}", other),
+            }
+            assert!(matches!(responses[1], Response::Pong { .. }), "{:?}", responses[1]);
+        }
+    }
+    
+    /// Send `requests` over one connection served by `agent`, returning the created paths in order
+    async fn exchange(agent: &AgentLoop<tokio::io::Empty, tokio::io::Sink>, requests: Vec<Request>) -> Vec<Response> {
+        let (agent_io, mut client) = tokio::io::duplex(64 * 1024);
+        let (agent_reader, agent_writer) = tokio::io::split(agent_io);
+        let client_side = async move {
+            let mut codec = FrameCodec::new();
+            let count = requests.len();
+            for (index, request) in requests.into_iter().enumerate() {
+                let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+                codec.write_frame(&mut client, &Frame::data(index as u32 * 2 + 1, 1, Bytes::from(payload))).await.unwrap();
+            }
+            let mut responses = Vec::new();
+            for _ in 0..count {
+                let frame = codec.read_frame(&mut client).await.unwrap().unwrap();
+                match rmp_serde::from_slice::<Message>(&frame.payload).unwrap() {
+                    Message::Response(response) => responses.push(response),
+                    other => panic!("Expected a response, got {:?}", other),
+                }
+            }
+            drop(client);
+            responses
+        };
+        let (served, responses) = tokio::join!(agent.run_with(agent_reader, agent_writer), client_side);
+        served.unwrap();
+        responses
+    }
+    
+    async fn make_temps(agent: &AgentLoop<tokio::io::Empty, tokio::io::Sink>, requests: Vec<Request>) -> Vec<PathBuf> {
+        exchange(agent, requests).await
+            .into_iter()
+            .map(|response| match response {
+                Response::TempCreated { path, .. } => path,
+                other => panic!("Expected TempCreated, got {:?}", other),
+            })
+            .collect()
+    }
+    
+    /// A `MkTemp` request for a uniquely named entry in `dir`
+    fn mk_temp_in(dir: &Path, kind: TempKind, cleanup: bool) -> Request {
+        let mut request = Request::mk_temp(kind);
+        if let Request::MkTemp { dir: temp_dir, prefix, suffix, cleanup: remove, .. } = &mut request {
+            *temp_dir = Some(dir.to_path_buf());
+            *prefix = "mitoxide-".to_string();
+            *suffix = ".tmp".to_string();
+            *remove = cleanup;
+        }
+        request
+    }
+    
+    #[tokio::test]
+    async fn test_mk_temp_names_never_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let agent = AgentLoop::with_io(tokio::io::empty(), tokio::io::sink());
+        let requests = || (0..16)
+            .map(|i| mk_temp_in(dir.path(), if i % 2 == 0 { TempKind::File } else { TempKind::Dir }, false))
+            .collect::<Vec<_>>();
+        
+        // Two connections creating entries in the same directory at once
+        let (first, second) = timeout(Duration::from_secs(5), async {
+            tokio::join!(make_temps(&agent, requests()), make_temps(&agent, requests()))
+        }).await.unwrap();
+        
+        let unique: std::collections::HashSet<_> = first.iter().chain(&second).collect();
+        assert_eq!(unique.len(), 32);
+        for (i, path) in first.iter().enumerate() {
+            assert_eq!(path.parent().unwrap(), dir.path());
+            let name = path.file_name().unwrap().to_str().unwrap();
+            assert!(name.starts_with("mitoxide-") && name.ends_with(".tmp"), "{}", name);
+            assert_eq!(path.is_dir(), i % 2 == 1);
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 32);
+    }
+    
+    #[tokio::test]
+    async fn test_mk_temp_cleanup_when_connection_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        let agent = AgentLoop::with_io(tokio::io::empty(), tokio::io::sink());
+        let requests = vec![
+            mk_temp_in(dir.path(), TempKind::File, true),
+            mk_temp_in(dir.path(), TempKind::Dir, true),
+            mk_temp_in(dir.path(), TempKind::File, false),
+        ];
+        
+        let paths = timeout(Duration::from_secs(5), make_temps(&agent, requests)).await.unwrap();
+        assert!(!paths[0].exists());
+        assert!(!paths[1].exists());
+        assert!(paths[2].is_file());
+    }
+    
+    #[tokio::test]
+    async fn test_plugin_called_by_module_name() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("my_module.wasm"), mitoxide_wasm::test_utils::test_modules::wasi_echo_wasm()).unwrap();
+        std::fs::write(dir.path().join("README"), "not a plugin").unwrap();
+        
+        let plugins = crate::handlers::PluginHandler::load_dir(dir.path()).unwrap();
+        assert_eq!(plugins.names(), ["my_module"]);
+        let agent = AgentLoop::with_io(tokio::io::empty(), tokio::io::sink());
+        agent.register_handler("json_call".to_string(), Arc::new(plugins)).await;
+        
+        let call = |method: &str| Request::JsonCall {
+            id: Uuid::new_v4(),
+            method: method.to_string(),
+            params: Bytes::from(r#"{"greeting":"hello"}"#),
+        };
+        let (found, missing) = (call("my_module"), call("missing"));
+        let (found_id, missing_id) = (found.id(), missing.id());
+        let responses = timeout(Duration::from_secs(10), exchange(&agent, vec![found, missing])).await.unwrap();
+        let response = |id: Uuid| responses.iter().find(|response| response.request_id() == id).unwrap();
+        
+        // The module echoes its input back as the result
+        assert!(matches!(response(found_id), Response::JsonResult { result, .. } if result == r#"{"greeting":"hello"}"#));
+        match response(missing_id) {
+            Response::Error { error, .. } => {
+                assert_eq!(error.code, ErrorCode::NotFound);
+                assert!(error.message.contains("missing"));
+            }
+            other => panic!("Expected error, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_saturating_streams_written_in_turn() {
+        /// Sends a burst of partial responses once every request has started
+        struct BurstHandler {
+            started: Arc<tokio::sync::Barrier>,
+        }
+        
+        #[async_trait::async_trait]
+        impl Handler for BurstHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                Ok(Response::pong(request.id(), 0))
+            }
+            
+            async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+                self.started.wait().await;
+                for i in 0..32 {
+                    stream.output.send(Response::pong(request.id(), i));
+                }
+                self.handle(request).await
+            }
+        }
+        
+        let (agent_io, mut client) = tokio::io::duplex(256 * 1024);
+        let (agent_reader, agent_writer) = tokio::io::split(agent_io);
+        let mut agent = AgentLoop::with_io(agent_reader, agent_writer);
+        agent.register_handler("ping".to_string(), Arc::new(BurstHandler {
+            started: Arc::new(tokio::sync::Barrier::new(2)),
+        })).await;
+        let served = tokio::spawn(async move { agent.run().await });
+        
+        let mut codec = FrameCodec::new();
+        for stream_id in [1, 3] {
+            let payload = rmp_serde::to_vec(&Message::request(Request::ping())).unwrap();
+            codec.write_frame(&mut client, &Frame::data(stream_id, 1, Bytes::from(payload))).await.unwrap();
+        }
+        let mut order = Vec::new();
+        for _ in 0..66 {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client)).await.unwrap().unwrap().unwrap();
+            order.push(frame.stream_id);
+        }
+        drop(client);
+        served.await.unwrap().unwrap();
+        
+        // Neither stream's burst is written all at once ahead of the other's
+        let longest_run = order.chunk_by(|a, b| a == b).map(<[u32]>::len).max().unwrap();
+        assert!(longest_run <= 4, "frames written by stream: {:?}", order);
+        assert_eq!(order.iter().filter(|&&stream_id| stream_id == 1).count(), 33);
+    }
+    
+    #[test]
+    fn test_output_written_by_qos_class() {
+        let output = |stream_id| HandlerOutput {
+            stream_id,
+            sequence: 0,
+            message: Message::response(Response::pong(Uuid::new_v4(), 0)),
+            last: false,
+        };
+        
+        // A file transfer's backlog, then a ping and a listing arrive behind it
+        let mut queue = OutputQueue::default();
+        for _ in 0..3 {
+            queue.push(output(1), QosClass::Background);
+        }
+        queue.push(output(3), QosClass::Interactive);
+        queue.push(output(5), QosClass::Batch);
+        queue.push(output(7), QosClass::Batch);
+        queue.push(output(5), QosClass::Batch);
+        assert_eq!(queue.streams(), 4);
+        
+        queue.discard(7);
+        let mut order = Vec::new();
+        while let Some(output) = queue.pop() {
+            order.push(output.stream_id);
+        }
+        assert_eq!(order, vec![3, 5, 5, 1, 1, 1]);
+        assert!(queue.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_response_stream_ends_at_size_limit() {
+        /// Sends output chunks until the connection is gone
+        struct EndlessHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for EndlessHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                Ok(Response::pong(request.id(), 0))
+            }
+            
+            async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+                while stream.output.send(chunk(request.id())) {
+                    tokio::task::yield_now().await;
+                }
+                self.handle(request).await
+            }
+        }
+        
+        fn chunk(request_id: Uuid) -> Response {
+            Response::ProcessOutput {
+                request_id,
+                stream: mitoxide_proto::message::OutputStream::Stdout,
+                data: Bytes::from(vec![b'x'; 1024]),
+            }
+        }
+        
+        // Room for ten chunks and half of the eleventh
+        let chunk_size = {
+            let frame = Message::response(chunk(Uuid::new_v4())).to_frame(1, 0, Some(DEFAULT_ATTACHMENT_THRESHOLD)).unwrap();
+            (frame.payload.len() + frame.attachment.len()) as u64
+        };
+        let (agent_io, mut client) = tokio::io::duplex(256 * 1024);
+        let (agent_reader, agent_writer) = tokio::io::split(agent_io);
+        let mut agent = AgentLoop::with_io(agent_reader, agent_writer)
+            .with_max_response_bytes(chunk_size * 10 + chunk_size / 2);
+        agent.register_handler("ping".to_string(), Arc::new(EndlessHandler)).await;
+        let served = tokio::spawn(async move {
+            let reason = agent.run().await;
+            (reason, agent)
+        });
+        
+        let mut codec = FrameCodec::new();
+        for stream_id in [1, 3] {
+            let request = Request::ping();
+            let request_id = request.id();
+            let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+            codec.write_frame(&mut client, &Frame::data(stream_id, 1, Bytes::from(payload))).await.unwrap();
+            
+            let mut chunks = 0;
+            let error = loop {
+                let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client)).await.unwrap().unwrap().unwrap();
+                match Message::from_frame(frame).unwrap() {
+                    Message::Response(Response::ProcessOutput { .. }) => chunks += 1,
+                    Message::Response(Response::Error { request_id: id, error }) => {
+                        assert_eq!(id, request_id);
+                        break error;
+                    }
+                    other => panic!("Unexpected message: {:?}", other),
+                }
+            };
+            assert_eq!(chunks, 10);
+            assert_eq!(error.code, ErrorCode::ResponseTooLarge);
+        }
+        
+        // The aborted handlers no longer count as in flight, so the loop stops
+        drop(client);
+        let (reason, agent) = timeout(Duration::from_secs(5), served).await.unwrap().unwrap();
+        reason.unwrap();
+        
+        // The first stream was forgotten once its stopped handler's output was drained
+        assert_eq!(agent.aborted_streams.keys().collect::<Vec<_>>(), vec![&3]);
+    }
+    
+    #[tokio::test]
+    async fn test_handler_events_written_before_response() {
+        /// Reports progress before answering
+        struct ProgressHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for ProgressHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                Ok(Response::pong(request.id(), 0))
+            }
+            
+            async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+                stream.output.send_event(Event::Progress {
+                    request_id: request.id(),
+                    completed: 1,
+                    total: None,
+                    message: None,
+                });
+                self.handle(request).await
+            }
+        }
+        
+        let request = Request::ping();
+        let request_id = request.id();
+        let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+        let mut codec = FrameCodec::new();
+        let input = codec.encode_frame(&Frame::data(1, 0, Bytes::from(payload))).unwrap();
+        
+        let mut agent = AgentLoop::with_io(Cursor::new(input.to_vec()), Cursor::new(Vec::<u8>::new()));
+        agent.register_handler("ping".to_string(), Arc::new(ProgressHandler)).await;
+        timeout(Duration::from_secs(5), agent.run()).await.unwrap().unwrap();
+        
+        let mut output = Cursor::new(agent.writer.get_ref().get_ref().clone());
+        let mut messages = Vec::new();
+        while let Some(frame) = codec.read_frame(&mut output).await.unwrap() {
+            assert_eq!(frame.stream_id, 1);
+            messages.push(rmp_serde::from_slice::<Message>(&frame.payload).unwrap());
+        }
+        
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[0], Message::Event(Event::Progress { request_id: id, .. }) if *id == request_id));
+        assert!(matches!(&messages[1], Message::Response(Response::Pong { .. })));
+    }
+    
+    #[tokio::test]
+    async fn test_keepalives_sent_while_request_runs() {
+        /// Answers after a long delay
+        struct SlowPing;
+        
+        #[async_trait::async_trait]
+        impl Handler for SlowPing {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                tokio::time::sleep(Duration::from_millis(260)).await;
+                Ok(Response::pong(request.id(), 0))
+            }
+        }
+        
+        let (agent_io, client_io) = tokio::io::duplex(64 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        let mut agent = AgentLoop::with_io(agent_read, agent_write)
+            .with_keepalive_interval(Duration::from_millis(50));
+        agent.register_handler("ping".to_string(), Arc::new(SlowPing)).await;
+        tokio::spawn(async move { agent.run().await });
+        
+        let request = Request::ping();
+        let request_id = request.id();
+        let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+        let mut codec = FrameCodec::new();
+        codec.write_frame(&mut client_write, &Frame::data(1, 0, Bytes::from(payload))).await.unwrap();
+        let start = tokio::time::Instant::now();
+        
+        let mut keepalives = Vec::new();
+        loop {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+            assert_eq!(frame.stream_id, 1);
+            match rmp_serde::from_slice::<Message>(&frame.payload).unwrap() {
+                Message::Event(Event::Keepalive { request_id: id }) => {
+                    assert_eq!(id, request_id);
+                    keepalives.push(start.elapsed());
+                }
+                Message::Response(Response::Pong { .. }) => break,
+                other => panic!("Expected keepalive or pong, got {:?}", other),
+            }
+        }
+        
+        // One keepalive per interval until the response, none straight away
+        assert!((4..=6).contains(&keepalives.len()), "{:?}", keepalives);
+        assert!(keepalives[0] >= Duration::from_millis(40), "{:?}", keepalives);
+        for pair in keepalives.windows(2) {
+            let gap = pair[1] - pair[0];
+            assert!(gap >= Duration::from_millis(30) && gap <= Duration::from_millis(150), "{:?}", keepalives);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_stream_input_is_flow_controlled() {
+        /// Takes one input chunk per permit added to `gate`
+        struct GatedInput {
+            gate: Arc<tokio::sync::Semaphore>,
+        }
+        
+        #[async_trait::async_trait]
+        impl Handler for GatedInput {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                Ok(Response::error(request.id(), ErrorDetails::new(ErrorCode::InvalidRequest, "needs a stream")))
+            }
+            
+            async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+                let mut input = stream.input.unwrap();
+                let mut bytes_written = 0;
+                loop {
+                    self.gate.acquire().await.unwrap().forget();
+                    match input.recv().await {
+                        Some(chunk) => bytes_written += chunk.len() as u64,
+                        None => break,
+                    }
+                }
+                Ok(Response::FilePutResult { request_id: request.id(), bytes_written, skipped: false, backup_path: None })
+            }
+        }
+        
+        let (agent_io, client_io) = tokio::io::duplex(1024 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        let mut agent = AgentLoop::with_io(agent_read, agent_write);
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        agent.register_handler("file_put".to_string(), Arc::new(GatedInput { gate: gate.clone() })).await;
+        tokio::spawn(async move { agent.run().await });
+        
+        let mut request = Request::file_put(PathBuf::from("/tmp/streamed"), Bytes::new(), None, false);
+        if let Request::FilePut { stream_content, .. } = &mut request {
+            *stream_content = true;
+        }
+        let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+        let mut codec = FrameCodec::new();
+        codec.write_frame(&mut client_write, &Frame::data(1, 0, Bytes::from(payload))).await.unwrap();
+        
+        // A whole window goes out before the handler takes anything
+        let chunk = 64 * 1024;
+        for sequence in 1..=STREAM_INPUT_WINDOW / chunk {
+            codec.write_frame(&mut client_write, &Frame::data(1, sequence, Bytes::from(vec![0; chunk as usize]))).await.unwrap();
+        }
+        
+        // Credit comes back as the handler makes room: one chunk waits for it, and two are taken
+        gate.add_permits(2);
+        let mut credit = 0;
+        while credit < 3 * chunk {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+            match FlowControlMessage::from_frame(&frame).unwrap() {
+                FlowControlMessage::WindowUpdate { delta } => credit += delta,
+                other => panic!("Expected a window update, got {:?}", other),
+            }
+        }
+        assert_eq!(credit, 3 * chunk);
+        
+        // Sending past the credit given ends the request
+        let overrun = Bytes::from(vec![0; (credit + 1) as usize]);
+        codec.write_frame(&mut client_write, &Frame::data(1, 5, overrun)).await.unwrap();
+        let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+        match rmp_serde::from_slice::<Message>(&frame.payload).unwrap() {
+            Message::Response(Response::Error { error, .. }) => {
+                assert_eq!(error.code, ErrorCode::InvalidRequest);
+                assert!(error.message.contains("flow control window"), "{}", error.message);
+            }
+            other => panic!("Expected an error response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_output_window_holds_back_partial_responses() {
+        /// Sends `chunks` chunks of data as the client has room for them, counting those sent
+        struct Sender {
+            chunks: usize,
+            sent: Arc<std::sync::atomic::AtomicUsize>,
+        }
+        
+        #[async_trait::async_trait]
+        impl Handler for Sender {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                Ok(Response::error(request.id(), ErrorDetails::new(ErrorCode::InvalidRequest, "needs a stream")))
+            }
+            
+            async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+                let request_id = request.id();
+                for _ in 0..self.chunks {
+                    let data = Bytes::from(vec![0; 64 * 1024]);
+                    if !stream.output.send_data(Response::TunnelData { request_id, data }, 64 * 1024).await {
+                        break;
+                    }
+                    self.sent.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+                Ok(Response::TunnelClosed { request_id, bytes_sent: 0, bytes_received: 0 })
+            }
+        }
+        
+        let (agent_io, client_io) = tokio::io::duplex(4 * 1024 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        // Far less than the request sends; a flow controlled request isn't held to it
+        let mut agent = AgentLoop::with_io(agent_read, agent_write).with_max_response_bytes(128 * 1024);
+        let sent = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        agent.register_handler("tcp_connect".to_string(), Arc::new(Sender { chunks: 8, sent: sent.clone() })).await;
+        tokio::spawn(async move { agent.run().await });
+        
+        let mut codec = FrameCodec::new();
+        let payload = rmp_serde::to_vec(&Message::request(Request::tcp_connect("db.internal", 5432))).unwrap();
+        codec.write_frame(&mut client_write, &Frame::data(1, 0, Bytes::from(payload))).await.unwrap();
+        
+        // One window of data goes out, then the handler waits for credit
+        let window_chunks = STREAM_OUTPUT_WINDOW as usize / (64 * 1024);
+        for _ in 0..window_chunks {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+            assert!(matches!(Message::from_frame(frame).unwrap(), Message::Response(Response::TunnelData { .. })));
+        }
+        assert!(timeout(Duration::from_millis(200), codec.read_frame(&mut client_read)).await.is_err());
+        assert_eq!(sent.load(std::sync::atomic::Ordering::SeqCst), window_chunks);
+        
+        // Credit lets the rest through, and the request finishes rather than going past the limit
+        let update = FlowControlMessage::WindowUpdate { delta: STREAM_OUTPUT_WINDOW }.to_frame(1, 0).unwrap();
+        codec.write_frame(&mut client_write, &update).await.unwrap();
+        for _ in 0..window_chunks {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+            assert!(matches!(Message::from_frame(frame).unwrap(), Message::Response(Response::TunnelData { .. })));
+        }
+        let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+        assert!(matches!(Message::from_frame(frame).unwrap(), Message::Response(Response::TunnelClosed { .. })));
+        
+        // A client that resets the stream ends the request, even while it waits for credit
+        let payload = rmp_serde::to_vec(&Message::request(Request::tcp_connect("db.internal", 5432))).unwrap();
+        codec.write_frame(&mut client_write, &Frame::data(3, 0, Bytes::from(payload))).await.unwrap();
+        for _ in 0..window_chunks {
+            timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+        }
+        codec.write_frame(&mut client_write, &Frame::error(3, 0, Bytes::from_static(b"reset"))).await.unwrap();
+        let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+        match Message::from_frame(frame).unwrap() {
+            Message::Response(Response::Error { error, .. }) => assert_eq!(error.code, ErrorCode::Cancelled),
+            other => panic!("Expected the request to be cancelled, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_output_window_resize_changes_data_in_flight() {
+        /// Sends 64 KiB chunks of data as the client has room for them
+        struct Sender;
+        
+        #[async_trait::async_trait]
+        impl Handler for Sender {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                Ok(Response::error(request.id(), ErrorDetails::new(ErrorCode::InvalidRequest, "needs a stream")))
+            }
+            
+            async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+                let request_id = request.id();
+                loop {
+                    let data = Bytes::from(vec![0; 64 * 1024]);
+                    if !stream.output.send_data(Response::TunnelData { request_id, data }, 64 * 1024).await {
+                        return Ok(Response::TunnelClosed { request_id, bytes_sent: 0, bytes_received: 0 });
+                    }
+                }
+            }
+        }
+        
+        let (agent_io, client_io) = tokio::io::duplex(16 * 1024 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        let mut agent = AgentLoop::with_io(agent_read, agent_write);
+        agent.register_handler("tcp_connect".to_string(), Arc::new(Sender)).await;
+        tokio::spawn(async move { agent.run().await });
+        
+        let mut codec = FrameCodec::new();
+        let payload = rmp_serde::to_vec(&Message::request(Request::tcp_connect("db.internal", 5432))).unwrap();
+        codec.write_frame(&mut client_write, &Frame::data(1, 0, Bytes::from(payload))).await.unwrap();
+        
+        // Chunks that arrive before the agent waits for credit
+        async fn chunks_in_flight<R: AsyncRead + Unpin>(codec: &mut FrameCodec, reader: &mut R) -> usize {
+            let mut chunks = 0;
+            while let Ok(frame) = timeout(Duration::from_millis(200), codec.read_frame(reader)).await {
+                assert!(matches!(Message::from_frame(frame.unwrap().unwrap()).unwrap(), Message::Response(Response::TunnelData { .. })));
+                chunks += 1;
+            }
+            chunks
+        }
+        
+        assert_eq!(chunks_in_flight(&mut codec, &mut client_read).await, 4);
+        
+        // Raising the window lets four more chunks out without any of them being read
+        let resize = FlowControlMessage::ResizeWindow { window_size: 512 * 1024 }.to_frame(1, 0).unwrap();
+        codec.write_frame(&mut client_write, &resize).await.unwrap();
+        assert_eq!(chunks_in_flight(&mut codec, &mut client_read).await, 4);
+        
+        // After shrinking it, returning everything read only lets the new window's worth out
+        let resize = FlowControlMessage::ResizeWindow { window_size: 128 * 1024 }.to_frame(1, 0).unwrap();
+        codec.write_frame(&mut client_write, &resize).await.unwrap();
+        let update = FlowControlMessage::WindowUpdate { delta: 512 * 1024 }.to_frame(1, 0).unwrap();
+        codec.write_frame(&mut client_write, &update).await.unwrap();
+        assert_eq!(chunks_in_flight(&mut codec, &mut client_read).await, 2);
+        
+        // Sizes past the maximum are lowered to it
+        let resize = FlowControlMessage::ResizeWindow { window_size: u32::MAX }.to_frame(1, 0).unwrap();
+        codec.write_frame(&mut client_write, &resize).await.unwrap();
+        let expected = (MAX_STREAM_OUTPUT_WINDOW - 128 * 1024) as usize / (64 * 1024);
+        assert_eq!(chunks_in_flight(&mut codec, &mut client_read).await, expected);
+    }
+    
+    #[tokio::test]
+    async fn test_send_rate_limit_paces_chunked_file_gets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bulk.bin");
+        std::fs::write(&path, vec![7u8; 300 * 1024]).unwrap();
+        
+        let (agent_io, client_io) = tokio::io::duplex(4 * 1024 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        // The connection's rate is the tighter one here, with no burst to speak of
+        let mut agent = AgentLoop::with_io(agent_read, agent_write)
+            .with_send_rate_limit(RateLimit::new(1024 * 1024).with_burst(1))
+            .with_stream_send_rate_limit(RateLimit::new(4 * 1024 * 1024).with_burst(1));
+        agent.register_handler("file_get".to_string(), Arc::new(crate::handlers::FileHandler::new())).await;
+        tokio::spawn(async move { agent.run().await });
+        
+        let mut codec = FrameCodec::new();
+        let request = Request::file_get_chunked(path, 32 * 1024);
+        let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+        let start = Instant::now();
+        codec.write_frame(&mut client_write, &Frame::data(1, 0, Bytes::from(payload))).await.unwrap();
+        
+        let mut received = 0;
+        loop {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+            match Message::from_frame(frame).unwrap() {
+                Message::Response(Response::FileChunk { data, .. }) => {
+                    received += data.len();
+                    // Taken straight away, so the output window never holds the transfer back
+                    let update = FlowControlMessage::WindowUpdate { delta: data.len() as u32 }.to_frame(1, 0).unwrap();
+                    codec.write_frame(&mut client_write, &update).await.unwrap();
+                }
+                Message::Response(Response::FileContent { .. }) => break,
+                other => panic!("Expected file content, got {:?}", other),
+            }
+        }
+        
+        // 300 KiB at 1 MiB/s takes about 0.3s
+        let elapsed = start.elapsed();
+        assert_eq!(received, 300 * 1024);
+        assert!(elapsed >= Duration::from_millis(250), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_requests_explain_unavailable_runtime() {
+        use crate::handlers::{WasmUnavailableHandler, WASM_REQUEST_TYPES};
+        
+        let request = Request::WasmExec {
+            id: Uuid::new_v4(),
+            module: Bytes::from_static(b"\0asm"),
+            input: Bytes::from_static(b"{}"),
+            timeout: None,
+            module_hash: None,
+            network_allow: Vec::new(),
+        };
+        let request_id = request.id();
+        let mut codec = FrameCodec::new();
+        let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+        let input = codec.encode_frame(&Frame::data(1, 0, Bytes::from(payload))).unwrap();
+        
+        // As the agent binary does when the runtime fails to start
+        let mut agent = AgentLoop::with_io(Cursor::new(input.to_vec()), Cursor::new(Vec::<u8>::new()));
+        let unavailable = Arc::new(WasmUnavailableHandler::new("engine creation failed"));
+        for request_type in WASM_REQUEST_TYPES {
+            agent.register_handler(request_type.to_string(), unavailable.clone()).await;
+        }
+        timeout(Duration::from_secs(5), agent.run()).await.unwrap().unwrap();
+        
+        let mut output = Cursor::new(agent.writer.get_ref().get_ref().clone());
+        let frame = codec.read_frame(&mut output).await.unwrap().unwrap();
+        match Message::from_frame(frame).unwrap() {
+            Message::Response(Response::Error { request_id: id, error }) => {
+                assert_eq!(id, request_id);
+                assert_eq!(error.code, ErrorCode::WasmFailed);
+                assert_eq!(error.message, "WASM runtime unavailable: engine creation failed");
+            }
+            other => panic!("Expected error response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_list_and_cancel_operations() {
+        /// Never answers on its own
+        struct StuckHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for StuckHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                std::future::pending::<()>().await;
+                Ok(Response::pong(request.id(), 0))
+            }
+        }
+        
+        async fn request<W: AsyncWrite + Unpin>(codec: &mut FrameCodec, writer: &mut W, stream_id: u32, request: Request) {
+            let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+            codec.write_frame(writer, &Frame::data(stream_id, 0, Bytes::from(payload))).await.unwrap();
+        }
+        
+        async fn response<R: AsyncRead + Unpin>(codec: &mut FrameCodec, reader: &mut R) -> (u32, Response) {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(reader)).await.unwrap().unwrap().unwrap();
+            match Message::from_frame(frame.clone()).unwrap() {
+                Message::Response(response) => (frame.stream_id, response),
+                other => panic!("Expected response, got {:?}", other),
+            }
+        }
+        
+        let (agent_io, client_io) = tokio::io::duplex(64 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        let mut agent = AgentLoop::with_io(agent_read, agent_write);
+        agent.register_handler("ping".to_string(), Arc::new(StuckHandler)).await;
+        tokio::spawn(async move { agent.run().await });
+        
+        let mut codec = FrameCodec::new();
+        let mut ids = Vec::new();
+        for stream_id in [1, 3, 5] {
+            let ping = Request::ping();
+            ids.push(ping.id());
+            request(&mut codec, &mut client_write, stream_id, ping).await;
+        }
+        
+        request(&mut codec, &mut client_write, 7, Request::list_operations()).await;
+        let operations = match response(&mut codec, &mut client_read).await {
+            (7, Response::Operations { operations, .. }) => operations,
+            other => panic!("Expected operations, got {:?}", other),
+        };
+        let listed: Vec<(Uuid, u32)> = operations.iter().map(|op| (op.id, op.stream_id)).collect();
+        assert_eq!(listed, vec![(ids[0], 1), (ids[1], 3), (ids[2], 5)]);
+        assert!(operations.iter().all(|op| op.request_type == "ping"));
+        
+        // The cancelled request ends with an error on its own stream
+        request(&mut codec, &mut client_write, 9, Request::cancel_operation(ids[1])).await;
+        match response(&mut codec, &mut client_read).await {
+            (3, Response::Error { request_id, error }) => {
+                assert_eq!(request_id, ids[1]);
+                assert_eq!(error.code, ErrorCode::Cancelled);
+            }
+            other => panic!("Expected cancellation error, got {:?}", other),
+        }
+        match response(&mut codec, &mut client_read).await {
+            (9, Response::OperationCancelled { target, .. }) => assert_eq!(target, ids[1]),
+            other => panic!("Expected cancellation, got {:?}", other),
+        }
+        
+        request(&mut codec, &mut client_write, 11, Request::list_operations()).await;
+        match response(&mut codec, &mut client_read).await {
+            (11, Response::Operations { operations, .. }) => {
+                let listed: Vec<Uuid> = operations.iter().map(|op| op.id).collect();
+                assert_eq!(listed, vec![ids[0], ids[2]]);
+            }
+            other => panic!("Expected operations, got {:?}", other),
+        }
+        
+        request(&mut codec, &mut client_write, 13, Request::cancel_operation(ids[1])).await;
+        match response(&mut codec, &mut client_read).await {
+            (13, Response::Error { error, .. }) => assert_eq!(error.code, ErrorCode::NotFound),
+            other => panic!("Expected not found, got {:?}", other),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cancel_kills_running_process() {
+        use crate::handlers::ProcessHandler;
+        use nix::errno::Errno;
+        use nix::sys::signal::kill;
+        use nix::unistd::Pid;
+        
+        async fn request<W: AsyncWrite + Unpin>(codec: &mut FrameCodec, writer: &mut W, stream_id: u32, request: Request) {
+            let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+            codec.write_frame(writer, &Frame::data(stream_id, 0, Bytes::from(payload))).await.unwrap();
+        }
+        
+        let (agent_io, client_io) = tokio::io::duplex(64 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        let mut agent = AgentLoop::with_io(agent_read, agent_write);
+        agent.register_handler("process_exec".to_string(), Arc::new(ProcessHandler::new())).await;
+        tokio::spawn(async move { agent.run().await });
+        
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let script = format!("echo $$ > {}; exec sleep 1000", pid_file.display());
+        let exec = Request::process_exec(vec!["sh".to_string(), "-c".to_string(), script], HashMap::new(), None, None, None);
+        let exec_id = exec.id();
+        let mut codec = FrameCodec::new();
+        request(&mut codec, &mut client_write, 1, exec).await;
+        
+        let pid = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(pid) = std::fs::read_to_string(&pid_file).ok().and_then(|pid| pid.trim().parse().ok()) {
+                    break Pid::from_raw(pid);
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        
+        request(&mut codec, &mut client_write, 3, Request::cancel_operation(exec_id)).await;
+        for _ in 0..2 {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(&mut client_read)).await.unwrap().unwrap().unwrap();
+            match Message::from_frame(frame).unwrap() {
+                Message::Response(Response::Error { error, .. }) => assert_eq!(error.code, ErrorCode::Cancelled),
+                Message::Response(Response::OperationCancelled { target, .. }) => assert_eq!(target, exec_id),
+                other => panic!("Expected cancellation, got {:?}", other),
+            }
+        }
+        
+        // The process is killed and reaped rather than left running
+        timeout(Duration::from_secs(5), async {
+            while kill(pid, None) != Err(Errno::ESRCH) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("cancelled process is still running");
+    }
+    
+    #[tokio::test]
+    async fn test_session_close_drains_and_cleans_up() {
+        /// Answers after a delay
+        struct SlowHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for SlowHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Ok(Response::pong(request.id(), 0))
+            }
+        }
+        
+        async fn request<W: AsyncWrite + Unpin>(codec: &mut FrameCodec, writer: &mut W, stream_id: u32, request: Request) {
+            let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+            codec.write_frame(writer, &Frame::data(stream_id, 0, Bytes::from(payload))).await.unwrap();
+        }
+        
+        async fn response<R: AsyncRead + Unpin>(codec: &mut FrameCodec, reader: &mut R) -> (u32, Response) {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(reader)).await.unwrap().unwrap().unwrap();
+            match Message::from_frame(frame.clone()).unwrap() {
+                Message::Response(response) => (frame.stream_id, response),
+                other => panic!("Expected response, got {:?}", other),
+            }
+        }
+        
+        let dir = tempfile::tempdir().unwrap();
+        let (agent_io, client_io) = tokio::io::duplex(64 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        let mut agent = AgentLoop::with_io(agent_read, agent_write);
+        agent.register_handler("ping".to_string(), Arc::new(SlowHandler)).await;
+        let agent = tokio::spawn(async move { agent.run().await });
+        
+        let mut codec = FrameCodec::new();
+        request(&mut codec, &mut client_write, 1, mk_temp_in(dir.path(), TempKind::File, true)).await;
+        let temp = match response(&mut codec, &mut client_read).await {
+            (1, Response::TempCreated { path, .. }) => path,
+            other => panic!("Expected TempCreated, got {:?}", other),
+        };
+        
+        // The running ping is answered before the close, and nothing after the close is read
+        request(&mut codec, &mut client_write, 3, Request::ping()).await;
+        request(&mut codec, &mut client_write, 5, Request::session_close()).await;
+        request(&mut codec, &mut client_write, 7, Request::ping()).await;
+        assert!(matches!(response(&mut codec, &mut client_read).await, (3, Response::Pong { .. })));
+        match response(&mut codec, &mut client_read).await {
+            (5, Response::SessionClosed { temp_paths_removed, .. }) => assert_eq!(temp_paths_removed, 1),
+            other => panic!("Expected SessionClosed, got {:?}", other),
+        }
+        assert!(!temp.exists());
+        
+        let reason = timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+        assert_eq!(reason, ShutdownReason::CleanShutdown);
+        assert!(timeout(Duration::from_secs(1), codec.read_frame(&mut client_read)).await.unwrap().unwrap().is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_session_close_cancels_requests_after_drain_timeout() {
+        /// Never answers
+        struct HungHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for HungHandler {
+            async fn handle(&self, _request: Request) -> Result<Response> {
+                std::future::pending().await
+            }
+        }
+        
+        async fn request<W: AsyncWrite + Unpin>(codec: &mut FrameCodec, writer: &mut W, stream_id: u32, request: Request) {
+            let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+            codec.write_frame(writer, &Frame::data(stream_id, 0, Bytes::from(payload))).await.unwrap();
+        }
+        
+        async fn response<R: AsyncRead + Unpin>(codec: &mut FrameCodec, reader: &mut R) -> (u32, Response) {
+            let frame = timeout(Duration::from_secs(5), codec.read_frame(reader)).await.unwrap().unwrap().unwrap();
+            match Message::from_frame(frame.clone()).unwrap() {
+                Message::Response(response) => (frame.stream_id, response),
+                other => panic!("Expected response, got {:?}", other),
+            }
+        }
+        
+        let (agent_io, client_io) = tokio::io::duplex(64 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        let mut agent = AgentLoop::with_io(agent_read, agent_write)
+            .with_max_concurrent_requests(1)
+            .with_request_queue(1)
+            .with_drain_timeout(Duration::from_millis(100));
+        agent.register_handler("ping".to_string(), Arc::new(HungHandler)).await;
+        let agent = tokio::spawn(async move { agent.run().await });
+        
+        // One request runs and one waits for its slot when the close arrives
+        let mut codec = FrameCodec::new();
+        request(&mut codec, &mut client_write, 1, Request::ping()).await;
+        request(&mut codec, &mut client_write, 3, Request::ping()).await;
+        request(&mut codec, &mut client_write, 5, Request::session_close()).await;
+        
+        let mut cancelled = Vec::new();
+        for _ in 0..2 {
+            match response(&mut codec, &mut client_read).await {
+                (stream_id, Response::Error { error, .. }) => {
+                    assert_eq!(error.code, ErrorCode::Cancelled);
+                    cancelled.push(stream_id);
+                }
+                other => panic!("Expected a cancelled request, got {:?}", other),
+            }
+        }
+        cancelled.sort();
+        assert_eq!(cancelled, vec![1, 3]);
+        assert!(matches!(response(&mut codec, &mut client_read).await, (5, Response::SessionClosed { .. })));
+        
+        let reason = timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+        assert_eq!(reason, ShutdownReason::CleanShutdown);
+    }
+    
+    #[tokio::test]
+    async fn test_batched_requests_handled_like_sent_alone() {
+        /// Answers after a delay, noting the labels the request ran with
+        struct LabelHandler(Arc<std::sync::Mutex<Vec<HashMap<String, String>>>>);
+        
+        #[async_trait::async_trait]
+        impl Handler for LabelHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                let record = crate::audit::AuditRecord::new(
+                    "test",
+                    crate::audit::AuditOperation::FileWrite { path: PathBuf::from("/") },
+                    crate::audit::AuditResult::Succeeded,
+                );
+                self.0.lock().unwrap().push(record.labels);
+                Ok(Response::pong(request.id(), 0))
+            }
+        }
+        
+        /// Answers with more than the response limit
+        struct OversizedHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for OversizedHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                Ok(Response::error(request.id(), ErrorDetails::new(ErrorCode::InternalError, "x".repeat(4096))))
+            }
+        }
+        
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().canonicalize().unwrap();
+        let labels = HashMap::from([("job".to_string(), "nightly".to_string())]);
+        let batch = Request::batch(vec![
+            Request::chdir(dir_path.clone()),
+            Request::getcwd(),
+            Request::list_operations(),
+            Request::ping().with_labels(HashMap::from([("step".to_string(), "ping".to_string())])),
+            Request::version(),
+            Request::session_close(),
+        ], false).with_labels(labels);
+        let batch_id = batch.id();
+        
+        // The client keeps its end open, so only the batch's close stops the agent
+        let (agent_io, client_io) = tokio::io::duplex(64 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (mut client_read, mut client_write) = tokio::io::split(client_io);
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut agent = AgentLoop::with_io(agent_read, agent_write)
+            .with_keepalive_interval(Duration::from_millis(50))
+            .with_max_response_bytes(1024);
+        agent.register_handler("ping".to_string(), Arc::new(LabelHandler(seen.clone()))).await;
+        agent.register_handler("version".to_string(), Arc::new(OversizedHandler)).await;
+        let agent = tokio::spawn(async move { agent.run().await });
+        
+        let mut codec = FrameCodec::new();
+        let payload = rmp_serde::to_vec(&Message::request(batch)).unwrap();
+        codec.write_frame(&mut client_write, &Frame::data(1, 0, Bytes::from(payload))).await.unwrap();
+        let reason = timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+        assert_eq!(reason, ShutdownReason::CleanShutdown);
+        
+        let mut messages = Vec::new();
+        while let Some(frame) = codec.read_frame(&mut client_read).await.unwrap() {
+            assert_eq!(frame.stream_id, 1);
+            messages.push(Message::from_frame(frame).unwrap());
+        }
+        
+        // The slow ping keeps the batch alive
+        let (last, events) = messages.split_last().unwrap();
+        assert!(!events.is_empty());
+        for event in events {
+            match event {
+                Message::Event(Event::Keepalive { request_id }) => assert_eq!(*request_id, batch_id),
+                other => panic!("Expected keepalive, got {:?}", other),
+            }
+        }
+        
+        let responses = match last {
+            Message::Response(Response::BatchResult { request_id, responses }) if *request_id == batch_id => responses,
+            other => panic!("Expected batch result, got {:?}", other),
+        };
+        assert_eq!(responses.len(), 6);
+        assert!(matches!(&responses[0], Response::Cwd { path, .. } if *path == dir_path));
+        assert!(matches!(&responses[1], Response::Cwd { path, .. } if *path == dir_path));
+        match &responses[2] {
+            Response::Operations { operations, .. } => {
+                assert_eq!(operations.len(), 1);
+                assert_eq!((operations[0].id, operations[0].request_type.as_str()), (batch_id, "batch"));
+            }
+            other => panic!("Expected operations, got {:?}", other),
+        }
+        assert!(matches!(&responses[3], Response::Pong { .. }));
+        assert!(matches!(&responses[4], Response::Error { error, .. } if error.code == ErrorCode::ResponseTooLarge));
+        assert!(matches!(&responses[5], Response::SessionClosed { .. }));
+        
+        // The batch's labels go on each request, under its own
+        let expected = HashMap::from([
+            ("job".to_string(), "nightly".to_string()),
+            ("step".to_string(), "ping".to_string()),
+        ]);
+        assert_eq!(*seen.lock().unwrap(), vec![expected]);
+    }
+    
+    #[tokio::test]
+    async fn test_batch_refuses_requests_it_cannot_run() {
+        let batch = Request::batch(vec![
+            Request::session_open(None),
+            Request::batch(vec![Request::ping()], false),
+            Request::session_close(),
+            Request::ping(),
+        ], false);
+        let mut codec = FrameCodec::new();
+        let payload = rmp_serde::to_vec(&Message::request(batch)).unwrap();
+        let input = codec.encode_frame(&Frame::data(1, 0, Bytes::from(payload))).unwrap().to_vec();
+        
+        let mut agent = AgentLoop::with_io(Cursor::new(input), Cursor::new(Vec::<u8>::new()));
+        agent.register_handler("ping".to_string(), Arc::new(MockHandler { response: Response::pong(Uuid::new_v4(), 0) })).await;
+        timeout(Duration::from_secs(5), agent.run()).await.unwrap().unwrap();
+        
+        let mut output = Cursor::new(agent.writer.get_ref().get_ref().clone());
+        let frame = codec.read_frame(&mut output).await.unwrap().unwrap();
+        let responses = match Message::from_frame(frame).unwrap() {
+            Message::Response(Response::BatchResult { responses, .. }) => responses,
+            other => panic!("Expected batch result, got {:?}", other),
+        };
+        let codes: Vec<_> = responses.iter().map(|response| match response {
+            Response::Error { error, .. } => Some(error.code),
+            _ => None,
+        }).collect();
+        assert_eq!(codes, vec![Some(ErrorCode::InvalidRequest), Some(ErrorCode::InvalidRequest), Some(ErrorCode::InvalidRequest), None]);
+    }
+    
+    #[tokio::test]
+    async fn test_queued_requests_start_by_qos_class() {
+        /// Answers after a delay
+        struct SlowHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for SlowHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Response::DirListing { request_id: request.id(), entries: Vec::new() })
+            }
+        }
+        
+        // Saturate the agent with background listings, then ask for an interactive one
+        let mut requests: Vec<Request> = (0..4)
+            .map(|_| Request::dir_list(PathBuf::from("/"), false, false).with_qos(QosClass::Background))
+            .collect();
+        requests.push(Request::dir_list(PathBuf::from("/"), false, false).with_qos(QosClass::Interactive));
+        let ids: Vec<Uuid> = requests.iter().map(Request::id).collect();
+        
+        let mut codec = FrameCodec::new();
+        let mut input = Vec::new();
+        for (i, request) in requests.into_iter().enumerate() {
+            let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+            input.extend_from_slice(&codec.encode_frame(&Frame::data(i as u32 * 2 + 1, 0, Bytes::from(payload))).unwrap());
+        }
+        
+        let mut agent = AgentLoop::with_io(Cursor::new(input), Cursor::new(Vec::<u8>::new()))
+            .with_max_concurrent_requests(1)
+            .with_request_queue(8);
+        agent.register_handler("dir_list".to_string(), Arc::new(SlowHandler)).await;
+        timeout(Duration::from_secs(5), agent.run()).await.unwrap().unwrap();
+        
+        let mut output = Cursor::new(agent.writer.get_ref().get_ref().clone());
+        let mut answered = Vec::new();
+        while let Some(frame) = codec.read_frame(&mut output).await.unwrap() {
+            match rmp_serde::from_slice::<Message>(&frame.payload).unwrap() {
+                Message::Response(response) => answered.push(response.request_id()),
+                other => panic!("Expected response, got {:?}", other),
+            }
+        }
+        
+        // The interactive listing waited only for the listing already running
+        assert_eq!(answered, vec![ids[0], ids[4], ids[1], ids[2], ids[3]]);
+    }
+    
+    #[tokio::test]
+    async fn test_control_requests_skip_concurrency_limit() {
+        /// Answers after a delay
+        struct SlowHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for SlowHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Ok(Response::DirListing { request_id: request.id(), entries: Vec::new() })
+            }
+        }
+        
+        // One listing takes the only slot; the second is turned away, the ping and signal aren't
+        let requests = vec![
+            Request::dir_list(PathBuf::from("/"), false, false),
+            Request::dir_list(PathBuf::from("/"), false, false),
+            Request::ping(),
+            Request::process_signal(Uuid::new_v4(), "TERM"),
+        ];
+        let ids: Vec<Uuid> = requests.iter().map(Request::id).collect();
+        
+        let mut codec = FrameCodec::new();
+        let mut input = Vec::new();
+        for (i, request) in requests.into_iter().enumerate() {
+            let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+            input.extend_from_slice(&codec.encode_frame(&Frame::data(i as u32 * 2 + 1, 0, Bytes::from(payload))).unwrap());
+        }
+        
+        let mut agent = AgentLoop::with_io(Cursor::new(input), Cursor::new(Vec::<u8>::new()))
+            .with_max_concurrent_requests(1);
+        agent.register_handler("dir_list".to_string(), Arc::new(SlowHandler)).await;
+        agent.register_handler("ping".to_string(), Arc::new(MockHandler {
+            response: Response::pong(Uuid::new_v4(), 0),
+        })).await;
+        agent.register_handler("process_signal".to_string(), Arc::new(MockHandler {
+            response: Response::SignalSent { request_id: Uuid::new_v4(), pid: 1 },
+        })).await;
+        timeout(Duration::from_secs(5), agent.run()).await.unwrap().unwrap();
+        
+        let mut output = Cursor::new(agent.writer.get_ref().get_ref().clone());
+        let mut answered = HashMap::new();
+        while let Some(frame) = codec.read_frame(&mut output).await.unwrap() {
+            match rmp_serde::from_slice::<Message>(&frame.payload).unwrap() {
+                Message::Response(response) => {
+                    let overloaded = matches!(&response, Response::Error { error, .. } if error.code == ErrorCode::Overloaded);
+                    answered.insert(frame.stream_id, overloaded);
+                }
+                other => panic!("Expected response, got {:?}", other),
+            }
+        }
+        
+        assert_eq!(answered.len(), ids.len());
+        assert!(!answered[&1]);
+        assert!(answered[&3]);
+        assert!(!answered[&5] && !answered[&7]);
+    }
+    
+    #[tokio::test]
+    async fn test_keyed_file_put_runs_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("motd");
+        let (mut client, agent_io) = tokio::io::duplex(64 * 1024);
+        let (agent_reader, agent_writer) = tokio::io::split(agent_io);
+        let mut agent = AgentLoop::with_io(agent_reader, agent_writer);
+        agent.register_handler("file_put".to_string(), Arc::new(crate::handlers::FileHandler::new())).await;
+        let agent_task = tokio::spawn(async move { agent.run().await });
+        
+        let mut codec = FrameCodec::new();
+        let request = Request::file_put(path.clone(), Bytes::from("welcome"), None, false).with_idempotency_key("put-motd");
+        let frame = |stream_id: u32, request: Request| {
+            let payload = rmp_serde::to_vec(&Message::request(request)).unwrap();
+            Frame::data(stream_id, 1, Bytes::from(payload))
+        };
+        
+        codec.write_frame(&mut client, &frame(1, request.clone())).await.unwrap();
+        let first = codec.read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "welcome");
+        
+        // The resend is answered from the cache, so the file is not written again
+        std::fs::remove_file(&path).unwrap();
+        codec.write_frame(&mut client, &frame(3, request)).await.unwrap();
+        let second = codec.read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(second.stream_id, 3);
+        assert_eq!(first.payload, second.payload);
+        assert!(matches!(
+            rmp_serde::from_slice::<Message>(&second.payload).unwrap(),
+            Message::Response(Response::FilePutResult { bytes_written: 7, .. })
+        ));
+        assert!(!path.exists());
+        
+        // A different key is a different operation
+        let request = Request::file_put(path.clone(), Bytes::from("again"), None, false).with_idempotency_key("put-motd-2");
+        codec.write_frame(&mut client, &frame(5, request)).await.unwrap();
+        codec.read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "again");
+        
+        drop(client);
+        timeout(Duration::from_secs(5), agent_task).await.unwrap().unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_request_labels_reach_span_and_audit_records() {
+        use crate::audit::{AuditRecord, AuditSink};
+        use tracing_subscriber::layer::SubscriberExt;
+        
+        /// Keeps the fields of every `request` span
+        #[derive(Clone, Default)]
+        struct SpanRecorder(Arc<std::sync::Mutex<Vec<HashMap<String, String>>>>);
+        
+        impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanRecorder {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                _id: &tracing::span::Id,
+                _ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                if attrs.metadata().name() == "request" {
+                    let mut fields = SpanFields::default();
+                    attrs.record(&mut fields);
+                    self.0.lock().unwrap().push(fields.0);
+                }
+            }
+        }
+        
+        /// Span fields formatted with their `Debug` output
+        #[derive(Default)]
+        struct SpanFields(HashMap<String, String>);
+        
+        impl tracing::field::Visit for SpanFields {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                self.0.insert(field.name().to_string(), format!("{:?}", value));
+            }
+        }
+        
+        /// Sink that keeps records for inspection
+        #[derive(Default)]
+        struct RecordingSink(std::sync::Mutex<Vec<AuditRecord>>);
+        
+        impl AuditSink for RecordingSink {
+            fn record(&self, record: &AuditRecord) {
+                self.0.lock().unwrap().push(record.clone());
+            }
+        }
+        
+        let spans = SpanRecorder::default();
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry().with(spans.clone()));
+        let sink = Arc::new(RecordingSink::default());
+        let agent = AgentLoop::with_io(tokio::io::empty(), tokio::io::sink());
+        agent.register_handler(
+            "file_put".to_string(),
+            Arc::new(crate::handlers::FileHandler::new().with_audit_sink(sink.clone())),
+        ).await;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let labels = HashMap::from([
+            ("job".to_string(), "nightly".to_string()),
+            ("deploy".to_string(), "42".to_string()),
+        ]);
+        let labeled = Request::file_put(dir.path().join("motd"), Bytes::from("welcome"), None, false)
+            .with_labels(labels.clone());
+        let labeled_id = labeled.id();
+        let plain = Request::file_put(dir.path().join("issue"), Bytes::from("hello"), None, false);
+        let responses = timeout(Duration::from_secs(5), exchange(&agent, vec![labeled, plain])).await.unwrap();
+        assert!(responses.iter().all(|response| matches!(response, Response::FilePutResult { .. })));
+        
+        // The labels are on the handler's span, sorted by key
+        let spans = spans.0.lock().unwrap();
+        let span = spans.iter().find(|fields| fields["id"] == labeled_id.to_string()).unwrap();
+        assert_eq!(span["labels"], "deploy=42,job=nightly");
+        assert_eq!(spans.len(), 2);
+        
+        // Only the labeled request's write is audited with them
+        let records = sink.0.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        let labeled_record = records.iter().find(|record| {
+            record.operation == crate::audit::AuditOperation::FileWrite { path: dir.path().join("motd") }
+        }).unwrap();
+        assert_eq!(labeled_record.labels, labels);
+        assert_eq!(records.iter().filter(|record| record.labels.is_empty()).count(), 1);
+        
+        let line = serde_json::to_value(labeled_record).unwrap();
+        assert_eq!(line["labels"]["deploy"], "42");
+    }
+    
+    #[tokio::test]
+    async fn test_repeat_of_running_keyed_request_waits_for_it() {
+        /// Counts calls and answers after a delay
+        struct CountingHandler(Arc<std::sync::atomic::AtomicUsize>);
+        
+        #[async_trait::async_trait]
+        impl Handler for CountingHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                let calls = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Response::pong(request.id(), calls as u64))
+            }
+        }
+        
+        let request = Request::process_exec(vec!["deploy".to_string()], HashMap::new(), None, None, None)
+            .with_idempotency_key("deploy-42");
+        let mut codec = FrameCodec::new();
+        let mut input = Vec::new();
+        for stream_id in [1, 3] {
+            let payload = rmp_serde::to_vec(&Message::request(request.clone())).unwrap();
+            input.extend_from_slice(&codec.encode_frame(&Frame::data(stream_id, 0, Bytes::from(payload))).unwrap());
+        }
+        
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut agent = AgentLoop::with_io(Cursor::new(input), Cursor::new(Vec::<u8>::new()));
+        agent.register_handler("process_exec".to_string(), Arc::new(CountingHandler(calls.clone()))).await;
+        timeout(Duration::from_secs(5), agent.run()).await.unwrap().unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        
+        let mut output = Cursor::new(agent.writer.get_ref().get_ref().clone());
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.read_frame(&mut output).await.unwrap() {
+            frames.push(frame);
+        }
+        let mut streams: Vec<u32> = frames.iter().map(|frame| frame.stream_id).collect();
+        streams.sort();
+        assert_eq!(streams, vec![1, 3]);
+        assert_eq!(frames[0].payload, frames[1].payload);
+    }
+    
+    #[tokio::test]
+    async fn test_panicking_handler_releases_its_idempotency_key() {
+        /// Panics on every call
+        struct PanickingHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for PanickingHandler {
+            async fn handle(&self, _request: Request) -> Result<Response> {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                panic!("handler bug");
+            }
+        }
+        
+        // The repeat arrives while the first is running, so it waits for it
+        let request = Request::process_exec(vec!["deploy".to_string()], HashMap::new(), None, None, None)
+            .with_idempotency_key("deploy-43");
+        let mut codec = FrameCodec::new();
+        let mut input = Vec::new();
+        for stream_id in [1, 3] {
+            let payload = rmp_serde::to_vec(&Message::request(request.clone())).unwrap();
+            input.extend_from_slice(&codec.encode_frame(&Frame::data(stream_id, 0, Bytes::from(payload))).unwrap());
+        }
+        
+        let mut agent = AgentLoop::with_io(Cursor::new(input), Cursor::new(Vec::<u8>::new()));
+        agent.register_handler("process_exec".to_string(), Arc::new(PanickingHandler)).await;
+        timeout(Duration::from_secs(5), agent.run()).await.unwrap().unwrap();
+        
+        // Both are answered, and a retry with the key would run again
+        let mut output = Cursor::new(agent.writer.get_ref().get_ref().clone());
+        let mut streams = Vec::new();
+        while let Some(frame) = codec.read_frame(&mut output).await.unwrap() {
+            streams.push(frame.stream_id);
+            match Message::from_frame(frame).unwrap() {
+                Message::Response(Response::Error { error, .. }) => assert_eq!(error.code, ErrorCode::InternalError),
+                other => panic!("Expected an error response, got {:?}", other),
+            }
+        }
+        streams.sort();
+        assert_eq!(streams, vec![1, 3]);
+        assert!(agent.idempotency.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_invalid_message_handling() {
+        // Create frame with invalid payload
+        let frame = Frame::data(1, 1, Bytes::from(vec![0xFF, 0xFF, 0xFF, 0xFF]));
+        
+        let input = Cursor::new(Vec::<u8>::new());
+        let output = Cursor::new(Vec::<u8>::new());
+        let mut agent = AgentLoop::with_io(input, output);
+        
+        // Should handle invalid message gracefully
+        let result = agent.process_frame(frame).await;
+        assert!(result.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_unsupported_request_handling() {
+        // Create a request without a registered handler
+        let request = Request::process_exec(
+            vec!["echo".to_string()],
             std::collections::HashMap::new(),
             None,
             None,
@@ -420,4 +3869,54 @@ mod tests {
         let result = agent.process_frame(end_frame).await;
         assert!(result.is_ok());
     }
-}
\ No newline at end of file
+    
+    #[cfg(feature = "jsonrpc")]
+    #[tokio::test]
+    async fn test_json_rpc_framing() {
+        /// Echoes call params back as the result
+        struct EchoJsonHandler;
+        
+        #[async_trait::async_trait]
+        impl Handler for EchoJsonHandler {
+            async fn handle(&self, request: Request) -> Result<Response> {
+                match request {
+                    Request::JsonCall { id, method, params } if method == "echo" => {
+                        Ok(Response::JsonResult { request_id: id, result: params })
+                    }
+                    Request::JsonCall { id, method, .. } => Ok(Response::error(
+                        id,
+                        ErrorDetails::new(ErrorCode::Unsupported, format!("Unknown method: {}", method)),
+                    )),
+                    _ => unreachable!(),
+                }
+            }
+        }
+        
+        let input = concat!(
+            r#"{"jsonrpc":"2.0","method":"echo","params":{"a":[1,2]},"id":1}"#, "\n",
+            r#"{"jsonrpc":"2.0","method":"echo","params":[true]}"#, "\n",
+            r#"{"jsonrpc":"2.0","method":"nope","params":[],"id":"two"}"#, "\n",
+            "not json\n",
+        );
+        let mut agent = AgentLoop::with_io(Cursor::new(input.as_bytes().to_vec()), Cursor::new(Vec::<u8>::new()));
+        agent.register_handler("json_call".to_string(), Arc::new(EchoJsonHandler)).await;
+        
+        timeout(Duration::from_secs(5), agent.run()).await.unwrap().unwrap();
+        
+        let output = String::from_utf8(agent.writer.get_ref().get_ref().clone()).unwrap();
+        let responses: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        
+        // The notification gets no response
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0], serde_json::json!({"jsonrpc": "2.0", "result": {"a": [1, 2]}, "id": 1}));
+        assert_eq!(responses[1]["jsonrpc"], "2.0");
+        assert_eq!(responses[1]["id"], "two");
+        assert_eq!(responses[1]["error"]["code"], mitoxide_proto::jsonrpc::METHOD_NOT_FOUND);
+        assert!(responses[1].get("result").is_none());
+        assert_eq!(responses[2]["id"], serde_json::Value::Null);
+        assert_eq!(responses[2]["error"]["code"], mitoxide_proto::jsonrpc::PARSE_ERROR);
+    }
+}
@@ -0,0 +1,238 @@
+//! Audit records of privileged operations
+//!
+//! Handlers report privilege-escalated commands and changes to files (writes,
+//! extended attributes and ownership) to an [`AuditSink`], whether they
+//! succeed or fail. The default sink drops them; [`JsonLinesAuditSink`]
+//! appends one JSON object per record to a file. Records made while a request is
+//! handled carry the request's labels.
+
+use mitoxide_proto::message::FileOwner;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Operation an audit record describes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum AuditOperation {
+    /// A command run through sudo, su, doas or a custom escalation command
+    PrivilegedExec {
+        /// Command as it was executed, including the escalation prefix
+        command: Vec<String>,
+    },
+    /// A file written on behalf of the client
+    FileWrite {
+        /// Path of the file
+        path: PathBuf,
+    },
+    /// Extended attributes set on a file
+    XattrSet {
+        /// Path of the file
+        path: PathBuf,
+        /// Names of the attributes set, in order
+        names: Vec<String>,
+    },
+    /// A file or directory tree given to another owner
+    Chown {
+        /// Path of the file or directory
+        path: PathBuf,
+        /// Owning user asked for
+        uid: Option<FileOwner>,
+        /// Owning group asked for
+        gid: Option<FileOwner>,
+        /// Whether everything below a directory changed too
+        recursive: bool,
+    },
+    /// Permission bits or modification time set on a file
+    SetAttributes {
+        /// Path of the file or directory
+        path: PathBuf,
+        /// Permission bits asked for
+        mode: Option<u32>,
+        /// Modification time asked for, in seconds since the Unix epoch
+        modified: Option<u64>,
+    },
+    /// A file or directory removed on behalf of the client
+    Remove {
+        /// Path removed
+        path: PathBuf,
+        /// Whether everything below a directory was removed too
+        recursive: bool,
+    },
+}
+
+/// How an audited operation ended
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AuditResult {
+    /// The operation completed
+    Succeeded,
+    /// The command ran and exited
+    Exited {
+        /// Exit code, or -1 if it was killed by a signal
+        exit_code: i32,
+    },
+    /// The operation could not be carried out
+    Failed {
+        /// Why it failed
+        error: String,
+    },
+}
+
+/// One audited operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// When the operation finished, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// User the operation ran as
+    pub principal: String,
+    /// What was done
+    #[serde(flatten)]
+    pub operation: AuditOperation,
+    /// How it ended
+    pub result: AuditResult,
+    /// Labels of the request the operation was done for
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+}
+
+tokio::task_local! {
+    /// Labels of the request whose handler runs on this task
+    static REQUEST_LABELS: HashMap<String, String>;
+}
+
+/// Run `future` with `labels` attached to the records it creates
+pub async fn with_labels<F: Future>(labels: HashMap<String, String>, future: F) -> F::Output {
+    REQUEST_LABELS.scope(labels, future).await
+}
+
+impl AuditRecord {
+    /// Create a record stamped with the current time and the current request's labels
+    pub fn new(principal: impl Into<String>, operation: AuditOperation, result: AuditResult) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        Self {
+            timestamp_ms,
+            principal: principal.into(),
+            operation,
+            result,
+            labels: REQUEST_LABELS.try_with(Clone::clone).unwrap_or_default(),
+        }
+    }
+}
+
+/// Destination for audit records
+///
+/// Recording must not fail the audited operation, so sinks deal with their
+/// own errors.
+pub trait AuditSink: Send + Sync {
+    /// Record an audited operation
+    fn record(&self, record: &AuditRecord);
+}
+
+impl fmt::Debug for dyn AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuditSink")
+    }
+}
+
+/// Sink that drops every record
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopAuditSink;
+
+impl AuditSink for NoopAuditSink {
+    fn record(&self, _record: &AuditRecord) {}
+}
+
+/// Sink that appends records to a file as JSON lines
+#[derive(Debug)]
+pub struct JsonLinesAuditSink {
+    /// Log file, opened for appending
+    file: Mutex<File>,
+}
+
+impl JsonLinesAuditSink {
+    /// Open `path` for appending, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        
+        // One write per record keeps lines whole when several agents share the file
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(&line) {
+            warn!("Failed to write audit record: {}", e);
+        }
+    }
+}
+
+/// Sink the handlers use unless given another
+pub fn default_sink() -> Arc<dyn AuditSink> {
+    Arc::new(NoopAuditSink)
+}
+
+/// User the agent itself runs as
+pub fn agent_principal() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_json_lines_sink_appends_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let sink = JsonLinesAuditSink::open(&path).unwrap();
+        
+        sink.record(&AuditRecord::new(
+            "root",
+            AuditOperation::PrivilegedExec { command: vec!["sudo".to_string(), "id".to_string()] },
+            AuditResult::Exited { exit_code: 0 },
+        ));
+        sink.record(&AuditRecord::new(
+            "deploy",
+            AuditOperation::FileWrite { path: PathBuf::from("/etc/motd") },
+            AuditResult::Failed { error: "Permission denied".to_string() },
+        ));
+        
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["operation"], "privileged_exec");
+        assert_eq!(lines[0]["principal"], "root");
+        assert_eq!(lines[0]["command"][1], "id");
+        assert_eq!(lines[0]["result"]["status"], "exited");
+        assert!(lines[0]["timestamp_ms"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["operation"], "file_write");
+        assert_eq!(lines[1]["path"], "/etc/motd");
+        assert_eq!(lines[1]["result"]["error"], "Permission denied");
+        
+        let record: AuditRecord = serde_json::from_str(log.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(record.operation, AuditOperation::FileWrite { path: PathBuf::from("/etc/motd") });
+    }
+}
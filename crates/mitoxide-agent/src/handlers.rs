@@ -1,28 +1,178 @@
 //! Request handlers for different operation types
 
-use crate::agent::Handler;
+use crate::agent::{Handler, RequestStream, ResponseSink, StreamInput};
+use crate::audit::{self, AuditOperation, AuditRecord, AuditResult, AuditSink};
+use crate::memory::{MemoryBudget, MemoryReservation, OverBudget};
+use crate::process_limit::{ProcessLimit, ProcessSlot, TooManyProcesses};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
 use mitoxide_proto::{Request, Response};
-use mitoxide_proto::message::{ErrorCode, ErrorDetails, FileMetadata, DirEntry, PrivilegeMethod};
-use std::collections::HashMap;
-use std::path::Path;
+use mitoxide_proto::message::{Compression, ContentTransform, EnvFile, ErrorCode, ErrorDetails, FileChange, FileMetadata, FilesystemSpace, DirEntry, FileOwner, HashAlgorithm, OnExists, OutputFile, OutputStream, OutputTruncation, PingProbe, PipelineStage, PrivilegeMethod, ProcessLimits, PtyInput, PtySize, Termination};
+use mitoxide_proto::delta::{self, DeltaOp, MAX_BLOCK_SIZE};
+use mitoxide_proto::envfile::parse_env_file;
+use mitoxide_proto::transform::{transform_content, ContentTransformer, TransformError};
+use mitoxide_wasm::HostPattern;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::process::Command;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+/// Default limit on the output captured per stream of a process
+pub const DEFAULT_MAX_OUTPUT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// How long output of a timed out process is still read after killing it
+const TIMEOUT_DRAIN_GRACE: std::time::Duration = std::time::Duration::from_millis(200);
 
 /// Handler for process execution requests
-pub struct ProcessHandler;
+pub struct ProcessHandler {
+    /// Running processes by the ID of the request that started them
+    processes: Arc<Mutex<HashMap<Uuid, u32>>>,
+    /// Capture limit per output stream for requests that don't set one
+    max_output_bytes: u64,
+    /// Budget captured output is reserved from
+    memory: Option<Arc<MemoryBudget>>,
+    /// Limit each process takes a slot of while it runs
+    process_limit: Option<Arc<ProcessLimit>>,
+}
+
+impl Default for ProcessHandler {
+    fn default() -> Self {
+        Self {
+            processes: Arc::default(),
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            memory: None,
+            process_limit: None,
+        }
+    }
+}
+
+/// Reserve `bytes` from `budget`, if there is one
+async fn reserve_memory(budget: Option<&MemoryBudget>, bytes: u64) -> std::result::Result<Option<MemoryReservation>, OverBudget> {
+    match budget {
+        Some(budget) => budget.reserve(bytes).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Take a slot of `limit` for a process about to start, if there is a limit
+fn take_process_slot(limit: Option<&ProcessLimit>) -> std::result::Result<Option<ProcessSlot>, TooManyProcesses> {
+    limit.map(ProcessLimit::try_acquire).transpose()
+}
+
+/// Removes a process from the registry when its request finishes
+struct RegisteredProcess {
+    /// Registry the process was added to
+    processes: Arc<Mutex<HashMap<Uuid, u32>>>,
+    /// Request ID the process is registered under
+    request_id: Uuid,
+}
+
+impl Drop for RegisteredProcess {
+    fn drop(&mut self) {
+        if let Ok(mut processes) = self.processes.lock() {
+            processes.remove(&self.request_id);
+        }
+    }
+}
 
 #[async_trait]
 impl Handler for ProcessHandler {
     async fn handle(&self, request: Request) -> Result<Response> {
         match request {
-            Request::ProcessExec { id, command, env, cwd, stdin, timeout } => {
+            Request::ProcessSignal { id, target, signal } => Ok(self.send_signal(id, target, &signal)),
+            request => self.execute(request, None, None).await,
+        }
+    }
+    
+    async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+        match request {
+            Request::ProcessSignal { id, target, signal } => Ok(self.send_signal(id, target, &signal)),
+            request => self.execute(request, stream.input, Some(stream.output)).await,
+        }
+    }
+}
+
+impl ProcessHandler {
+    /// Create a new process handler
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Set the capture limit per output stream used when a request doesn't set one
+    pub fn with_max_output_bytes(mut self, max_output_bytes: u64) -> Self {
+        self.max_output_bytes = max_output_bytes;
+        self
+    }
+    
+    /// Reserve captured output from `budget` as it grows
+    ///
+    /// Once the budget is used up, output that doesn't fit in what a capture
+    /// already holds is dropped and the result is marked truncated.
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.memory = Some(budget);
+        self
+    }
+    
+    /// Take a slot of `limit` for each process, refusing to start one when none is free
+    ///
+    /// Handlers given the same limit share its slots.
+    pub fn with_process_limit(mut self, limit: Arc<ProcessLimit>) -> Self {
+        self.process_limit = Some(limit);
+        self
+    }
+    
+    /// Number of processes currently running
+    pub fn running_count(&self) -> usize {
+        self.processes.lock().map(|p| p.len()).unwrap_or(0)
+    }
+    
+    /// Send a signal to the process started by the `target` request
+    fn send_signal(&self, id: Uuid, target: Uuid, signal: &str) -> Response {
+        let pid = self.processes.lock().ok().and_then(|p| p.get(&target).copied());
+        let Some(pid) = pid else {
+            return Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::NotFound, format!("No running process for request {}", target))
+            );
+        };
+        
+        match deliver_signal(pid, signal) {
+            Ok(()) => {
+                debug!("Sent {} to process {} (request {})", signal, pid, target);
+                Response::SignalSent { request_id: id, pid }
+            }
+            Err(error) => Response::error(id, error.with_context("pid", pid.to_string())),
+        }
+    }
+    
+    /// Run a process, feeding stdin from the request or the stream input
+    async fn execute(
+        &self,
+        request: Request,
+        input: Option<StreamInput>,
+        output: Option<ResponseSink>,
+    ) -> Result<Response> {
+        let mut request = request;
+        if let Err(error) = request.expand_env() {
+            return Ok(Response::error(request.id(), error));
+        }
+        
+        match request {
+            Request::ProcessExec {
+                id, command, env, cwd, stdin, timeout, limits,
+                stream_output, line_buffered, max_line_length, merge_stderr,
+                max_output_bytes, output_truncation, kill_on_output_limit, output_fds, detach,
+                stdout_file, stderr_file, env_file, ..
+            } => {
                 debug!("Executing process: {:?}", command);
                 
                 if command.is_empty() {
@@ -31,6 +181,26 @@ impl Handler for ProcessHandler {
                         ErrorDetails::new(ErrorCode::InvalidRequest, "Empty command")
                     ));
                 }
+                if detach && (stdin.is_some() || input.is_some() || stream_output || merge_stderr || !output_fds.is_empty()) {
+                    return Ok(Response::error(
+                        id,
+                        ErrorDetails::new(ErrorCode::InvalidRequest, "Detached processes have no stdin or captured output")
+                    ));
+                }
+                if merge_stderr && stderr_file.is_some() {
+                    return Ok(Response::error(
+                        id,
+                        ErrorDetails::new(ErrorCode::InvalidRequest, "Merged stderr goes to the stdout file, it can't have a file of its own")
+                    ));
+                }
+                
+                // The process holds a slot of the limit until it exits, taken before
+                // anything is touched so a refused request changes nothing. A detached
+                // process keeps its slot after the request, until it is reaped.
+                let slot = match take_process_slot(self.process_limit.as_deref()) {
+                    Ok(slot) => slot,
+                    Err(e) => return Ok(Response::error(id, e.into())),
+                };
                 
                 let start_time = std::time::Instant::now();
                 
@@ -40,24 +210,100 @@ impl Handler for ProcessHandler {
                     cmd.args(&command[1..]);
                 }
                 
-                // Set environment variables
+                // Set environment variables, the request's own over the env file's
+                if let Some(env_file) = env_file {
+                    match read_env_file(cwd.as_deref(), env_file).await {
+                        Ok(vars) => {
+                            cmd.envs(vars);
+                        }
+                        Err(e) => return Ok(Response::error(id, e)),
+                    }
+                }
                 for (key, value) in env {
                     cmd.env(key, value);
                 }
                 
                 // Set working directory
-                if let Some(cwd) = cwd {
+                if let Some(cwd) = &cwd {
                     cmd.current_dir(cwd);
                 }
                 
                 // Configure stdio
-                cmd.stdin(Stdio::piped())
-                   .stdout(Stdio::piped())
-                   .stderr(Stdio::piped());
+                if detach {
+                    cmd.stdin(Stdio::null())
+                       .stdout(Stdio::null())
+                       .stderr(Stdio::null());
+                } else {
+                    cmd.stdin(Stdio::piped())
+                       .stdout(Stdio::piped())
+                       .stderr(Stdio::piped());
+                }
+                
+                // Redirected output goes straight into its file and never through the agent
+                let open = |file: Option<OutputFile>| file.map(|file| OutputRedirect::open(cwd.as_deref(), file)).transpose();
+                let (stdout_redirect, stderr_redirect) = match (open(stdout_file), open(stderr_file)) {
+                    (Ok(stdout), Ok(stderr)) => (stdout, stderr),
+                    (Err(e), _) | (_, Err(e)) => return Ok(Response::error(id, e)),
+                };
+                // Merged stderr shares the stdout file, interleaved as the process writes
+                let targets = [
+                    (&stdout_redirect, false),
+                    (if merge_stderr { &stdout_redirect } else { &stderr_redirect }, true),
+                ];
+                for (redirect, is_stderr) in targets {
+                    let Some(redirect) = redirect else { continue };
+                    let stdio = match redirect.stdio() {
+                        Ok(stdio) => stdio,
+                        Err(e) => return Ok(Response::error(id, e)),
+                    };
+                    if is_stderr {
+                        cmd.stderr(stdio);
+                    } else {
+                        cmd.stdout(stdio);
+                    }
+                }
+                
+                // Send stderr into the stdout pipe so the interleaving is kept
+                let merged_output = if merge_stderr && stdout_redirect.is_none() {
+                    match merge_output_pipes(&mut cmd) {
+                        Ok(pipe) => Some(pipe),
+                        Err(e) => return Ok(Response::error(id, e)),
+                    }
+                } else {
+                    None
+                };
+                
+                // Apply resource limits
+                if let Some(limits) = limits {
+                    if let Err(e) = apply_limits(&mut cmd, limits) {
+                        return Ok(Response::error(id, e));
+                    }
+                }
+                
+                if detach {
+                    return spawn_detached(id, cmd, start_time, slot);
+                }
+                
+                // Wire the extra output descriptors to pipes of their own
+                let fd_pipes = match output_fd_pipes(&mut cmd, &output_fds) {
+                    Ok(pipes) => pipes,
+                    Err(e) => return Ok(Response::error(id, e)),
+                };
                 
-                // Spawn the process
+                // Spawn the process, killed if the request is cancelled or aborted before it exits
+                cmd.kill_on_drop(true);
                 let mut child = cmd.spawn()
                     .context("Failed to spawn process")?;
+                // Release our copies of the child's pipe ends so EOF is seen when it exits
+                drop(cmd);
+                
+                // Track the process so it can be signalled while it runs
+                let _registered = child.id().map(|pid| {
+                    if let Ok(mut processes) = self.processes.lock() {
+                        processes.insert(id, pid);
+                    }
+                    RegisteredProcess { processes: Arc::clone(&self.processes), request_id: id }
+                });
                 
                 // Write stdin if provided
                 if let Some(stdin_data) = stdin {
@@ -67,14 +313,60 @@ impl Handler for ProcessHandler {
                         }
                         drop(child_stdin); // Close stdin
                     }
+                } else if let Some(mut input) = input {
+                    if let Some(mut child_stdin) = child.stdin.take() {
+                        // Copy streamed chunks concurrently so stdout can drain while we write
+                        tokio::spawn(async move {
+                            while let Some(chunk) = input.recv().await {
+                                if let Err(e) = child_stdin.write_all(&chunk).await {
+                                    warn!("Failed to write to process stdin: {}", e);
+                                    break;
+                                }
+                            }
+                            // Dropping child_stdin closes the pipe once the stream ends
+                        });
+                    }
                 }
                 
+                // Start collecting output before waiting so full pipes can't stall the child
+                let sink = if stream_output { output } else { None };
+                let line_limit = line_buffered.then(|| max_line_length.unwrap_or(DEFAULT_MAX_LINE_LENGTH));
+                let limit_hit = kill_on_output_limit.then(|| Arc::new(Notify::new()));
+                let capture = OutputCapture::new(
+                    max_output_bytes.unwrap_or(self.max_output_bytes),
+                    output_truncation,
+                    limit_hit.clone(),
+                    self.memory.as_ref().map(|budget| budget.reservation()),
+                );
+                let (stop_capture, capture_stopped) = watch::channel(false);
+                let fd_tasks: Vec<_> = fd_pipes.into_iter()
+                    .map(|(fd, pipe)| {
+                        (fd, tokio::spawn(capture_output(
+                            pipe, id, OutputStream::Fd(fd), sink.clone(), line_limit, capture.clone(), capture_stopped.clone(),
+                        )))
+                    })
+                    .collect();
+                let stdout_task = match merged_output {
+                    Some(pipe) => Some(tokio::spawn(capture_output(
+                        pipe, id, OutputStream::Stdout, sink.clone(), line_limit, capture.clone(), capture_stopped.clone(),
+                    ))),
+                    None => child.stdout.take().map(|pipe| {
+                        tokio::spawn(capture_output(
+                            pipe, id, OutputStream::Stdout, sink.clone(), line_limit, capture.clone(), capture_stopped.clone(),
+                        ))
+                    }),
+                };
+                let stderr_task = child.stderr.take().map(|pipe| {
+                    tokio::spawn(capture_output(pipe, id, OutputStream::Stderr, sink, line_limit, capture, capture_stopped))
+                });
+                
                 // Wait for process with optional timeout
-                let output = if let Some(timeout_secs) = timeout {
+                let mut timed_out = false;
+                let status = if let Some(timeout_secs) = timeout {
                     let timeout_duration = std::time::Duration::from_secs(timeout_secs);
                     
-                    match tokio::time::timeout(timeout_duration, child.wait_with_output()).await {
-                        Ok(Ok(output)) => output,
+                    match tokio::time::timeout(timeout_duration, wait_child(&mut child, limit_hit.as_deref())).await {
+                        Ok(Ok(status)) => status,
                         Ok(Err(e)) => {
                             return Ok(Response::error(
                                 id,
@@ -82,17 +374,33 @@ impl Handler for ProcessHandler {
                             ));
                         }
                         Err(_) => {
-                            // Timeout occurred - the child process is already consumed by wait_with_output
-                            // so we can't kill it here. The timeout will have interrupted the wait.
-                            return Ok(Response::error(
-                                id,
-                                ErrorDetails::new(ErrorCode::Timeout, "Process execution timed out")
-                            ));
+                            debug!("Killing process that ran past its {}s timeout", timeout_secs);
+                            timed_out = true;
+                            if let Err(e) = child.start_kill() {
+                                warn!("Failed to kill timed out process: {}", e);
+                            }
+                            
+                            // Children of the process may hold its pipes open, so only
+                            // read what is already buffered before returning
+                            tokio::spawn(async move {
+                                tokio::time::sleep(TIMEOUT_DRAIN_GRACE).await;
+                                let _ = stop_capture.send(true);
+                            });
+                            
+                            match child.wait().await {
+                                Ok(status) => status,
+                                Err(e) => {
+                                    return Ok(Response::error(
+                                        id,
+                                        ErrorDetails::new(ErrorCode::ProcessFailed, format!("Process error: {}", e))
+                                    ));
+                                }
+                            }
                         }
                     }
                 } else {
-                    match child.wait_with_output().await {
-                        Ok(output) => output,
+                    match wait_child(&mut child, limit_hit.as_deref()).await {
+                        Ok(status) => status,
                         Err(e) => {
                             return Ok(Response::error(
                                 id,
@@ -102,1283 +410,6400 @@ impl Handler for ProcessHandler {
                     }
                 };
                 
+                let (stdout, stdout_truncated) = join_capture(stdout_task).await;
+                let (stderr, stderr_truncated) = join_capture(stderr_task).await;
+                let mut fd_output = HashMap::new();
+                let mut fd_truncated = false;
+                for (fd, task) in fd_tasks {
+                    let (data, truncated) = join_capture(Some(task)).await;
+                    fd_truncated |= truncated;
+                    fd_output.insert(fd, data);
+                }
+                
                 let duration = start_time.elapsed();
                 
                 Ok(Response::ProcessResult {
                     request_id: id,
-                    exit_code: output.status.code().unwrap_or(-1),
-                    stdout: Bytes::from(output.stdout),
-                    stderr: Bytes::from(output.stderr),
+                    exit_code: status.code().unwrap_or(-1),
+                    stdout,
+                    stderr,
                     duration_ms: duration.as_millis() as u64,
+                    truncated: stdout_truncated || stderr_truncated || fd_truncated,
+                    timed_out,
+                    termination: Some(termination(&status)),
+                    fd_output,
+                    pid: None,
+                    stdout_written: stdout_redirect.as_ref().map(OutputRedirect::written),
+                    stderr_written: stderr_redirect.as_ref().map(OutputRedirect::written),
                 })
             }
             _ => Ok(Response::error(
                 request.id(),
-                ErrorDetails::new(ErrorCode::Unsupported, "ProcessHandler only handles ProcessExec requests")
+                ErrorDetails::new(ErrorCode::Unsupported, "ProcessHandler only handles ProcessExec and ProcessSignal requests")
             ))
         }
     }
 }
 
-/// Handler for file operations (get/put)
-pub struct FileHandler;
+/// A file on the agent that a process writes its output into directly
+struct OutputRedirect {
+    /// The open file, whose length tells how much was written
+    file: std::fs::File,
+    /// Length of the file before the process ran
+    initial_len: u64,
+}
 
-#[async_trait]
-impl Handler for FileHandler {
-    async fn handle(&self, request: Request) -> Result<Response> {
-        match request {
-            Request::FileGet { id, path, range } => {
-                debug!("Getting file: {:?}", path);
-                
-                match self.handle_file_get(&path, range).await {
-                    Ok((content, metadata)) => {
-                        Ok(Response::FileContent {
-                            request_id: id,
-                            content,
-                            metadata,
-                        })
-                    }
-                    Err(e) => {
-                        error!("File get error: {}", e);
-                        let error_string = e.to_string().to_lowercase();
-                        let error_code = if error_string.contains("no such file") || 
-                                           error_string.contains("not found") ||
-                                           error_string.contains("cannot find") {
-                            ErrorCode::FileNotFound
-                        } else if error_string.contains("permission denied") || 
-                                  error_string.contains("access denied") {
-                            ErrorCode::PermissionDenied
-                        } else {
-                            ErrorCode::InternalError
-                        };
-                        
-                        Ok(Response::error(
-                            id,
-                            ErrorDetails::new(error_code, format!("File get failed: {}", e))
-                        ))
-                    }
-                }
-            }
-            
-            Request::FilePut { id, path, content, mode, create_dirs } => {
-                debug!("Putting file: {:?}", path);
-                
-                match self.handle_file_put(&path, &content, mode, create_dirs).await {
-                    Ok(bytes_written) => {
-                        Ok(Response::FilePutResult {
-                            request_id: id,
-                            bytes_written,
-                        })
-                    }
-                    Err(e) => {
-                        error!("File put error: {}", e);
-                        let error_code = if e.to_string().contains("Permission denied") {
-                            ErrorCode::PermissionDenied
-                        } else {
-                            ErrorCode::InternalError
-                        };
-                        
-                        Ok(Response::error(
-                            id,
-                            ErrorDetails::new(error_code, format!("File put failed: {}", e))
-                        ))
-                    }
-                }
-            }
-            
-            Request::DirList { id, path, include_hidden, recursive } => {
-                debug!("Listing directory: {:?}", path);
-                
-                match self.handle_dir_list(&path, include_hidden, recursive).await {
-                    Ok(entries) => {
-                        Ok(Response::DirListing {
-                            request_id: id,
-                            entries,
-                        })
-                    }
-                    Err(e) => {
-                        error!("Directory list error: {}", e);
-                        let error_code = if e.to_string().contains("No such file") {
-                            ErrorCode::FileNotFound
-                        } else if e.to_string().contains("Permission denied") {
-                            ErrorCode::PermissionDenied
-                        } else {
-                            ErrorCode::InternalError
-                        };
-                        
-                        Ok(Response::error(
-                            id,
-                            ErrorDetails::new(error_code, format!("Directory list failed: {}", e))
-                        ))
-                    }
-                }
-            }
-            
-            _ => Ok(Response::error(
-                request.id(),
-                ErrorDetails::new(ErrorCode::Unsupported, "FileHandler only handles file/directory requests")
-            ))
+impl OutputRedirect {
+    /// Open `target`, relative to the process's working directory, for writing
+    fn open(cwd: Option<&Path>, target: OutputFile) -> std::result::Result<Self, ErrorDetails> {
+        let path = match cwd {
+            Some(cwd) => cwd.join(&target.path),
+            None => target.path,
+        };
+        let mut options = std::fs::OpenOptions::new();
+        if target.append {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
         }
+        
+        let opened = options.create(true).open(&path)
+            .and_then(|file| Ok(Self { initial_len: file.metadata()?.len(), file }));
+        opened.map_err(|e| {
+            let code = match e.kind() {
+                std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+                std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                _ => ErrorCode::InternalError,
+            };
+            ErrorDetails::new(code, format!("Failed to open output file {:?}: {}", path, e))
+                .with_context("path", path.display().to_string())
+        })
+    }
+    
+    /// A handle on the file for the child's stdio
+    fn stdio(&self) -> std::result::Result<Stdio, ErrorDetails> {
+        self.file.try_clone()
+            .map(Stdio::from)
+            .map_err(|e| ErrorDetails::new(ErrorCode::InternalError, format!("Failed to share output file: {}", e)))
+    }
+    
+    /// Bytes the process added to the file
+    fn written(&self) -> u64 {
+        self.file.metadata().map_or(0, |metadata| metadata.len().saturating_sub(self.initial_len))
     }
 }
 
-impl FileHandler {
-    /// Handle file get operation
-    async fn handle_file_get(&self, path: &Path, range: Option<(u64, u64)>) -> Result<(Bytes, FileMetadata)> {
-        let metadata = fs::metadata(path).await
-            .context("Failed to get file metadata")?;
-        
-        if metadata.is_dir() {
-            return Err(anyhow::anyhow!("Path is a directory, not a file"));
+/// Line length at which a line-buffered stream flushes without a newline
+const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Read the variables of `file`, relative to the process's working directory
+///
+/// A malformed line fails the request unless the file skips them, in which
+/// case each is logged.
+async fn read_env_file(cwd: Option<&Path>, file: EnvFile) -> std::result::Result<Vec<(String, String)>, ErrorDetails> {
+    let path = match cwd {
+        Some(cwd) => cwd.join(&file.path),
+        None => file.path,
+    };
+    let content = fs::read_to_string(&path).await.map_err(|e| {
+        let code = match e.kind() {
+            std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+            std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            _ => ErrorCode::InternalError,
+        };
+        ErrorDetails::new(code, format!("Failed to read env file {:?}: {}", path, e))
+            .with_context("path", path.display().to_string())
+    })?;
+    
+    let contents = parse_env_file(&content);
+    for malformed in &contents.malformed {
+        if !file.skip_malformed {
+            return Err(ErrorDetails::new(
+                ErrorCode::InvalidRequest,
+                format!("Malformed line {} in env file {:?}: {}", malformed.line, path, malformed.reason)
+            ).with_context("path", path.display().to_string()));
         }
-        
-        let file_metadata = FileMetadata {
-            size: metadata.len(),
-            mode: 0o644, // Default mode, platform-specific implementation would get actual mode
-            modified: metadata.modified()
-                .unwrap_or(std::time::UNIX_EPOCH)
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-            is_dir: false,
-            is_symlink: metadata.file_type().is_symlink(),
+        warn!("Skipping malformed line {} in env file {:?}: {}", malformed.line, path, malformed.reason);
+    }
+    Ok(contents.vars)
+}
+
+/// Wait for a child to exit, killing it first if `limit_hit` is notified
+async fn wait_child(child: &mut Child, limit_hit: Option<&Notify>) -> std::io::Result<std::process::ExitStatus> {
+    let Some(limit_hit) = limit_hit else {
+        return child.wait().await;
+    };
+    
+    tokio::select! {
+        status = child.wait() => return status,
+        _ = limit_hit.notified() => {}
+    }
+    debug!("Killing process that exceeded its output limit");
+    if let Err(e) = child.start_kill() {
+        warn!("Failed to kill process over its output limit: {}", e);
+    }
+    child.wait().await
+}
+
+/// Read a child's output pipe to EOF, or until `stopped` turns true
+///
+/// With a sink the data is sent as `ProcessOutput` responses and nothing is
+/// returned; otherwise what `capture` keeps is returned at EOF, along with
+/// whether it had to drop any. A line limit makes streamed chunks end on
+/// newlines, flushing over-long lines at the limit.
+async fn capture_output<R>(
+    mut pipe: R,
+    request_id: Uuid,
+    stream: OutputStream,
+    sink: Option<ResponseSink>,
+    line_limit: Option<usize>,
+    mut captured: OutputCapture,
+    mut stopped: watch::Receiver<bool>,
+) -> (Bytes, bool)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = line_limit.map(LineBuffer::new);
+    let mut buf = [0u8; 8192];
+    
+    let emit = |data: Bytes| {
+        if let Some(sink) = &sink {
+            sink.send(Response::ProcessOutput { request_id, stream, data });
+        }
+    };
+    
+    loop {
+        let read = tokio::select! {
+            read = pipe.read(&mut buf) => read,
+            _ = stop_requested(&mut stopped) => {
+                debug!("Stopped reading process {:?} before EOF", stream);
+                break;
+            }
         };
-        
-        let content = if let Some((start, end)) = range {
-            // Read specific range
-            let mut file = fs::File::open(path).await
-                .context("Failed to open file")?;
-            
-            let file_size = metadata.len();
-            let actual_start = start.min(file_size);
-            let actual_end = end.min(file_size);
-            
-            if actual_start >= actual_end {
-                Bytes::new()
-            } else {
-                use tokio::io::{AsyncSeekExt, SeekFrom};
-                file.seek(SeekFrom::Start(actual_start)).await
-                    .context("Failed to seek in file")?;
-                
-                let read_size = (actual_end - actual_start) as usize;
-                let mut buffer = vec![0u8; read_size];
-                let bytes_read = file.read_exact(&mut buffer).await
-                    .context("Failed to read file range")?;
-                
-                buffer.truncate(bytes_read);
-                Bytes::from(buffer)
+        let n = match read {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                warn!("Failed to read process {:?}: {}", stream, e);
+                break;
             }
-        } else {
-            // Read entire file
-            let content = fs::read(path).await
-                .context("Failed to read file")?;
-            Bytes::from(content)
         };
         
-        Ok((content, file_metadata))
-    }
-    
-    /// Handle file put operation
-    async fn handle_file_put(&self, path: &Path, content: &Bytes, _mode: Option<u32>, create_dirs: bool) -> Result<u64> {
-        // Create parent directories if requested
-        if create_dirs {
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent).await
-                    .context("Failed to create parent directories")?;
+        if sink.is_none() {
+            captured.push(&buf[..n]);
+        } else if let Some(lines) = lines.as_mut() {
+            for line in lines.push(&buf[..n]) {
+                emit(line);
             }
+        } else {
+            emit(Bytes::copy_from_slice(&buf[..n]));
         }
-        
-        // Write file content
-        fs::write(path, content).await
-            .context("Failed to write file")?;
-        
-        // Set file permissions if specified (Unix-like systems)
-        #[cfg(unix)]
-        if let Some(mode) = _mode {
-            use std::os::unix::fs::PermissionsExt;
-            let permissions = std::fs::Permissions::from_mode(mode);
-            fs::set_permissions(path, permissions).await
-                .context("Failed to set file permissions")?;
-        }
-        
-        Ok(content.len() as u64)
     }
     
-    /// Handle directory listing operation
-    async fn handle_dir_list(&self, path: &Path, include_hidden: bool, recursive: bool) -> Result<Vec<DirEntry>> {
-        let mut entries = Vec::new();
-        
-        if recursive {
-            self.collect_entries_recursive(path, include_hidden, &mut entries).await?;
-        } else {
-            self.collect_entries_single(path, include_hidden, &mut entries).await?;
-        }
-        
-        Ok(entries)
+    // Deliver a trailing line that had no newline
+    if let Some(rest) = lines.and_then(LineBuffer::finish) {
+        emit(rest);
     }
     
-    /// Collect directory entries from a single directory
-    async fn collect_entries_single(&self, path: &Path, include_hidden: bool, entries: &mut Vec<DirEntry>) -> Result<()> {
-        let mut dir = fs::read_dir(path).await
-            .context("Failed to read directory")?;
-        
-        while let Some(entry) = dir.next_entry().await
-            .context("Failed to read directory entry")? {
-            
-            let entry_path = entry.path();
-            let name = entry.file_name().to_string_lossy().to_string();
-            
-            // Skip hidden files if not requested
-            if !include_hidden && name.starts_with('.') {
-                continue;
-            }
-            
-            let metadata = entry.metadata().await
-                .context("Failed to get entry metadata")?;
-            
-            let file_metadata = FileMetadata {
-                size: metadata.len(),
-                mode: 0o644, // Default mode
-                modified: metadata.modified()
-                    .unwrap_or(std::time::UNIX_EPOCH)
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs(),
-                is_dir: metadata.is_dir(),
-                is_symlink: metadata.file_type().is_symlink(),
-            };
-            
-            entries.push(DirEntry {
-                name,
-                path: entry_path,
-                metadata: file_metadata,
-            });
+    captured.finish()
+}
+
+/// Wait until `stopped` turns true; never returns if its sender is dropped first
+async fn stop_requested(stopped: &mut watch::Receiver<bool>) {
+    if stopped.wait_for(|stopped| *stopped).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Wait for an output capture task, treating a missing pipe or panic as empty output
+async fn join_capture(task: Option<tokio::task::JoinHandle<(Bytes, bool)>>) -> (Bytes, bool) {
+    match task {
+        Some(task) => task.await.unwrap_or_default(),
+        None => (Bytes::new(), false),
+    }
+}
+
+/// Output captured from one stream, bounded by a byte limit
+///
+/// The head fills first; past it, a ring buffer keeps the most recent bytes.
+/// Clones share one memory reservation, so it covers every stream of a request.
+#[derive(Clone)]
+struct OutputCapture {
+    /// Bytes kept from the start of the output
+    head: Vec<u8>,
+    /// Most recent bytes after the head
+    tail: VecDeque<u8>,
+    /// Capacity of `head`
+    head_limit: usize,
+    /// Capacity of `tail`
+    tail_limit: usize,
+    /// Whether any output was dropped
+    truncated: bool,
+    /// Notified the first time output is dropped
+    limit_hit: Option<Arc<Notify>>,
+    /// Memory reserved for the request's captured output
+    memory: Option<Arc<Mutex<MemoryReservation>>>,
+}
+
+impl OutputCapture {
+    /// Create a capture keeping at most `limit` bytes as chosen by `truncation`
+    fn new(
+        limit: u64,
+        truncation: OutputTruncation,
+        limit_hit: Option<Arc<Notify>>,
+        memory: Option<MemoryReservation>,
+    ) -> Self {
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        let head_limit = match truncation {
+            OutputTruncation::Head => limit,
+            OutputTruncation::Tail => 0,
+            OutputTruncation::HeadAndTail => limit / 2,
+        };
+        Self {
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            head_limit,
+            tail_limit: limit - head_limit,
+            truncated: false,
+            limit_hit,
+            memory: memory.map(|reservation| Arc::new(Mutex::new(reservation))),
+        }
+    }
+    
+    /// Add output, dropping what doesn't fit
+    fn push(&mut self, data: &[u8]) {
+        let head_growth = (self.head_limit - self.head.len()).min(data.len());
+        let growth = head_growth + (self.tail_limit - self.tail.len()).min(data.len() - head_growth);
+        if !self.reserve(growth as u64) {
+            // Without memory to grow into, the capture keeps at most what it already holds
+            debug!("Memory budget exhausted, capping captured output at {} bytes", self.head.len() + self.tail.len());
+            self.head_limit = self.head.len();
+            self.tail_limit = self.tail.len();
         }
         
-        Ok(())
+        let to_head = (self.head_limit - self.head.len()).min(data.len());
+        self.head.extend_from_slice(&data[..to_head]);
+        let rest = &data[to_head..];
+        if rest.is_empty() {
+            return;
+        }
+        
+        let overflow = (self.tail.len() + rest.len()).saturating_sub(self.tail_limit);
+        if rest.len() >= self.tail_limit {
+            self.tail.clear();
+            self.tail.extend(&rest[rest.len() - self.tail_limit..]);
+        } else {
+            self.tail.drain(..overflow);
+            self.tail.extend(rest);
+        }
+        
+        if overflow > 0 && !self.truncated {
+            self.truncated = true;
+            if let Some(limit_hit) = &self.limit_hit {
+                limit_hit.notify_one();
+            }
+        }
+    }
+    
+    /// Reserve memory for `bytes` more of captured output
+    fn reserve(&self, bytes: u64) -> bool {
+        match &self.memory {
+            Some(memory) if bytes > 0 => memory.lock().unwrap_or_else(|e| e.into_inner()).try_grow(bytes),
+            _ => true,
+        }
     }
     
-    /// Collect directory entries recursively
-    fn collect_entries_recursive<'a>(&'a self, path: &'a Path, include_hidden: bool, entries: &'a mut Vec<DirEntry>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send + 'a>> {
-        Box::pin(async move {
-            self.collect_entries_single(path, include_hidden, entries).await?;
+    /// Take the kept output and whether any was dropped
+    fn finish(self) -> (Bytes, bool) {
+        let mut data = self.head;
+        data.extend(self.tail);
+        (Bytes::from(data), self.truncated)
+    }
+}
+
+/// Splits a byte stream into newline-terminated chunks
+struct LineBuffer {
+    /// Bytes of the current incomplete line
+    pending: Vec<u8>,
+    /// Longest line held before flushing
+    max_line_length: usize,
+}
+
+impl LineBuffer {
+    /// Create a line buffer with the given maximum line length
+    fn new(max_line_length: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            max_line_length: max_line_length.max(1),
+        }
+    }
+    
+    /// Add data, returning every chunk that is now complete
+    fn push(&mut self, mut data: &[u8]) -> Vec<Bytes> {
+        let mut chunks = Vec::new();
+        
+        while !data.is_empty() {
+            let room = self.max_line_length - self.pending.len();
+            let window = &data[..data.len().min(room)];
             
-            // Collect subdirectories to process
-            let mut subdirs = Vec::new();
-            for entry in entries.iter() {
-                if entry.metadata.is_dir && entry.path != path {
-                    subdirs.push(entry.path.clone());
+            match window.iter().position(|&b| b == b'\n') {
+                Some(pos) => {
+                    self.pending.extend_from_slice(&window[..=pos]);
+                    data = &data[pos + 1..];
                 }
-            }
-            
-            // Process subdirectories recursively
-            for subdir in subdirs {
-                if let Err(e) = self.collect_entries_recursive(&subdir, include_hidden, entries).await {
-                    warn!("Failed to read subdirectory {:?}: {}", subdir, e);
-                    // Continue with other directories
+                None => {
+                    self.pending.extend_from_slice(window);
+                    data = &data[window.len()..];
+                    if self.pending.len() < self.max_line_length {
+                        continue;
+                    }
                 }
             }
-            
+            chunks.push(Bytes::from(std::mem::take(&mut self.pending)));
+        }
+        
+        chunks
+    }
+    
+    /// Take the trailing partial line, if any
+    fn finish(self) -> Option<Bytes> {
+        (!self.pending.is_empty()).then(|| Bytes::from(self.pending))
+    }
+}
+
+/// Send a named or numbered signal to a process
+#[cfg(unix)]
+fn deliver_signal(pid: u32, signal: &str) -> std::result::Result<(), ErrorDetails> {
+    use nix::errno::Errno;
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+    use std::str::FromStr;
+    
+    let parsed = match signal.parse::<i32>() {
+        Ok(number) => Signal::try_from(number),
+        Err(_) => {
+            let name = signal.to_ascii_uppercase();
+            if name.starts_with("SIG") {
+                Signal::from_str(&name)
+            } else {
+                Signal::from_str(&format!("SIG{}", name))
+            }
+        }
+    };
+    let signal = parsed.map_err(|_| {
+        ErrorDetails::new(ErrorCode::InvalidRequest, format!("Unknown signal: {}", signal))
+    })?;
+    
+    kill(Pid::from_raw(pid as i32), signal).map_err(|e| match e {
+        Errno::ESRCH => ErrorDetails::new(ErrorCode::NotFound, "Process already exited"),
+        Errno::EPERM => ErrorDetails::new(ErrorCode::PermissionDenied, "Not permitted to signal process"),
+        e => ErrorDetails::new(ErrorCode::ProcessFailed, format!("Failed to send signal: {}", e)),
+    })
+}
+
+/// How a process ended, by exit code or signal number
+#[cfg(unix)]
+fn termination(status: &std::process::ExitStatus) -> Termination {
+    use std::os::unix::process::ExitStatusExt;
+    
+    match (status.code(), status.signal()) {
+        (Some(code), _) => Termination::Exited(code),
+        (None, Some(signal)) => Termination::Signaled(signal),
+        (None, None) => Termination::Exited(-1),
+    }
+}
+
+/// Processes always exit with a code off Unix
+#[cfg(not(unix))]
+fn termination(status: &std::process::ExitStatus) -> Termination {
+    Termination::Exited(status.code().unwrap_or(-1))
+}
+
+/// Signals are only supported on Unix
+#[cfg(not(unix))]
+fn deliver_signal(_pid: u32, _signal: &str) -> std::result::Result<(), ErrorDetails> {
+    Err(ErrorDetails::new(ErrorCode::Unsupported, "Signals are not supported on this platform"))
+}
+
+/// Create a pipe whose ends are close-on-exec from the start
+///
+/// Setting the flag after `pipe()` leaves a window in which a process spawned
+/// by another task inherits the ends and holds the pipe open.
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+fn cloexec_pipe() -> nix::Result<(std::os::fd::OwnedFd, std::os::fd::OwnedFd)> {
+    nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)
+}
+
+/// Create a pipe whose ends are close-on-exec, as close to the start as this platform allows
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn cloexec_pipe() -> nix::Result<(std::os::fd::OwnedFd, std::os::fd::OwnedFd)> {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    use std::os::fd::AsRawFd;
+    
+    // There's no pipe2 here, so the flags go on straight after
+    let (reader, writer) = nix::unistd::pipe()?;
+    fcntl(reader.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    fcntl(writer.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    Ok((reader, writer))
+}
+
+/// Point the child's stdout and stderr at one shared pipe, returning its read end
+#[cfg(unix)]
+fn merge_output_pipes(cmd: &mut Command) -> std::result::Result<tokio::net::unix::pipe::Receiver, ErrorDetails> {
+    let pipe_error = |e: std::io::Error| {
+        ErrorDetails::new(ErrorCode::InternalError, format!("Failed to create output pipe: {}", e))
+    };
+    
+    let (reader, writer) = cloexec_pipe().map_err(|e| pipe_error(e.into()))?;
+    let stderr_writer = writer.try_clone().map_err(pipe_error)?;
+    cmd.stdout(Stdio::from(writer)).stderr(Stdio::from(stderr_writer));
+    
+    tokio::net::unix::pipe::Receiver::from_owned_fd(reader).map_err(pipe_error)
+}
+
+/// Merged output is only supported on Unix
+#[cfg(not(unix))]
+fn merge_output_pipes(_cmd: &mut Command) -> std::result::Result<tokio::io::Empty, ErrorDetails> {
+    Err(ErrorDetails::new(ErrorCode::Unsupported, "Merged stderr is not supported on this platform"))
+}
+
+/// Give the child the extra output descriptors `fds`, returning the read end of each one's pipe
+///
+/// The write ends are first moved above every requested number, so putting
+/// one in place in the child can't clobber another.
+#[cfg(unix)]
+fn output_fd_pipes(
+    cmd: &mut Command,
+    fds: &[u32],
+) -> std::result::Result<Vec<(u32, tokio::net::unix::pipe::Receiver)>, ErrorDetails> {
+    use nix::fcntl::{fcntl, FcntlArg};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    
+    if fds.is_empty() {
+        return Ok(Vec::new());
+    }
+    if let Some(fd) = fds.iter().find(|fd| **fd < 3 || **fd > i32::MAX as u32) {
+        return Err(ErrorDetails::new(ErrorCode::InvalidRequest, format!("Descriptor {} can't be an extra output", fd)));
+    }
+    let unique: std::collections::HashSet<_> = fds.iter().collect();
+    if unique.len() != fds.len() {
+        return Err(ErrorDetails::new(ErrorCode::InvalidRequest, "Extra output descriptors are listed more than once"));
+    }
+    let pipe_error = |e: std::io::Error| {
+        ErrorDetails::new(ErrorCode::InternalError, format!("Failed to create output pipe: {}", e))
+    };
+    
+    let above = fds.iter().max().map_or(3, |fd| *fd as RawFd + 1);
+    let mut readers = Vec::with_capacity(fds.len());
+    let mut writers = Vec::with_capacity(fds.len());
+    for &fd in fds {
+        let (reader, writer) = cloexec_pipe().map_err(|e| pipe_error(e.into()))?;
+        let moved = fcntl(writer.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(above)).map_err(|e| pipe_error(e.into()))?;
+        // SAFETY: fcntl just returned this descriptor and nothing else owns it
+        let moved = unsafe { OwnedFd::from_raw_fd(moved) };
+        readers.push((fd, tokio::net::unix::pipe::Receiver::from_owned_fd(reader).map_err(pipe_error)?));
+        writers.push((moved, fd as RawFd));
+    }
+    
+    // SAFETY: the hook only calls dup2, which is async-signal-safe and does
+    // not allocate between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            // The write ends live in the hook, so they close when the command is dropped
+            for (writer, fd) in &writers {
+                nix::unistd::dup2(writer.as_raw_fd(), *fd)?;
+            }
             Ok(())
-        })
+        });
     }
+    Ok(readers)
 }
 
-/// Handler for PTY process execution with privilege escalation
-pub struct PtyHandler;
+/// Extra output descriptors are only supported on Unix
+#[cfg(not(unix))]
+fn output_fd_pipes(_cmd: &mut Command, fds: &[u32]) -> std::result::Result<Vec<(u32, tokio::io::Empty)>, ErrorDetails> {
+    if fds.is_empty() {
+        return Ok(Vec::new());
+    }
+    Err(ErrorDetails::new(ErrorCode::Unsupported, "Extra output descriptors are not supported on this platform"))
+}
+
+/// Start `cmd` in a new session and leave it running, answering with its PID
+///
+/// Without a controlling terminal the process doesn't get the hangup sent
+/// when the client's session ends, and its stdio doesn't tie it to the agent.
+#[cfg(unix)]
+fn spawn_detached(id: Uuid, mut cmd: Command, start_time: std::time::Instant, slot: Option<ProcessSlot>) -> Result<Response> {
+    // SAFETY: the hook only calls setsid, which is async-signal-safe and does
+    // not allocate between fork and exec
+    unsafe {
+        cmd.pre_exec(|| {
+            nix::unistd::setsid()?;
+            Ok(())
+        });
+    }
+    
+    let mut child = cmd.spawn()
+        .context("Failed to spawn detached process")?;
+    let pid = child.id();
+    debug!("Detached process {:?} for request {}", pid, id);
+    // Reap the process if it exits while the agent is still running, freeing its slot
+    tokio::spawn(async move {
+        let _ = child.wait().await;
+        drop(slot);
+    });
+    
+    Ok(Response::ProcessResult {
+        request_id: id,
+        exit_code: 0,
+        stdout: Bytes::new(),
+        stderr: Bytes::new(),
+        duration_ms: start_time.elapsed().as_millis() as u64,
+        truncated: false,
+        timed_out: false,
+        termination: None,
+        fd_output: HashMap::new(),
+        pid,
+        stdout_written: None,
+        stderr_written: None,
+    })
+}
+
+/// Detached processes need Unix sessions
+#[cfg(not(unix))]
+fn spawn_detached(id: Uuid, _cmd: Command, _start_time: std::time::Instant, _slot: Option<ProcessSlot>) -> Result<Response> {
+    Ok(Response::error(id, ErrorDetails::new(ErrorCode::Unsupported, "Detached processes are not supported on this platform")))
+}
+
+/// Install a pre-exec hook that applies resource limits to the child
+#[cfg(unix)]
+fn apply_limits(cmd: &mut Command, limits: ProcessLimits) -> std::result::Result<(), ErrorDetails> {
+    use nix::sys::resource::{setrlimit, Resource};
+    
+    // SAFETY: the hook only calls setrlimit, which is async-signal-safe and
+    // does not allocate between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            // A hard limit one second above the soft one makes the kernel send
+            // SIGXCPU first, keeping SIGKILL for a child that ignores it
+            if let Some(cpu) = limits.cpu_seconds {
+                setrlimit(Resource::RLIMIT_CPU, cpu, cpu.saturating_add(1))?;
+            }
+            if let Some(bytes) = limits.address_space {
+                setrlimit(Resource::RLIMIT_AS, bytes, bytes)?;
+            }
+            if let Some(files) = limits.open_files {
+                setrlimit(Resource::RLIMIT_NOFILE, files, files)?;
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Resource limits are only supported on Unix
+#[cfg(not(unix))]
+fn apply_limits(_cmd: &mut Command, _limits: ProcessLimits) -> std::result::Result<(), ErrorDetails> {
+    Err(ErrorDetails::new(ErrorCode::Unsupported, "Process limits are not supported on this platform"))
+}
+
+/// How often a followed file is checked for appended data
+const TAIL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Most bytes read from a tailed file at a time
+const TAIL_READ_SIZE: u64 = 256 * 1024;
+
+/// Bytes before a tailed file's offset compared on each read, to notice it
+/// was truncated and rewritten past the offset between two reads
+const TAIL_FINGERPRINT_SIZE: u64 = 64;
+
+/// Directory listing batches sent ahead of the client's acknowledgements
+const DIR_LIST_WINDOW: usize = 4;
+
+/// Entries a directory walk reads ahead of whoever consumes them
+const DIR_WALK_BUFFER: usize = 256;
+
+/// Handler for file operations (get/put)
+///
+/// Create one with [`FileHandler::new`] and configure it with the `with_*` methods.
+#[derive(Debug, Clone)]
+pub struct FileHandler {
+    /// Permission bits masked out of created files and directories
+    umask: Option<u32>,
+    /// Where file writes and changes to their attributes and owners are recorded
+    audit: Arc<dyn AuditSink>,
+    /// Budget file content is reserved from before it is read
+    memory: Option<Arc<MemoryBudget>>,
+}
+
+impl Default for FileHandler {
+    fn default() -> Self {
+        Self {
+            umask: None,
+            audit: audit::default_sink(),
+            memory: None,
+        }
+    }
+}
 
 #[async_trait]
-impl Handler for PtyHandler {
+impl Handler for FileHandler {
     async fn handle(&self, request: Request) -> Result<Response> {
         match request {
-            Request::PtyExec { id, command, env, cwd, privilege, timeout } => {
-                debug!("Executing PTY process: {:?}", command);
+            Request::FileGet { id, path, range, decompress, include_xattrs, transforms, .. } => {
+                debug!("Getting file: {:?} (decompress: {:?})", path, decompress);
                 
-                if command.is_empty() {
-                    return Ok(Response::error(
-                        id,
-                        ErrorDetails::new(ErrorCode::InvalidRequest, "Empty command")
-                    ));
+                let result = self.handle_file_get(&path, range, decompress).await.and_then(|(content, metadata)| {
+                    let content = transform_content(&transforms, &content)?;
+                    Ok((Bytes::from(content), metadata))
+                });
+                let result = match result {
+                    Ok((content, metadata)) if include_xattrs => {
+                        with_xattrs(&path, metadata).await.map(|metadata| (content, metadata))
+                    }
+                    result => result,
+                };
+                match result {
+                    Ok((content, metadata)) => {
+                        Ok(Response::FileContent {
+                            request_id: id,
+                            content,
+                            metadata,
+                        })
+                    }
+                    Err(e) => Ok(file_get_error(id, e)),
                 }
+            }
+            
+            Request::FilePut { id, stream_content: true, .. } => Ok(streamed_put_without_input(id)),
+            Request::FilePut { id, path, content, mode, create_dirs, umask, modified, atomic, transforms, on_exists, .. } => {
+                debug!("Putting file: {:?} (atomic: {}, on exists: {:?})", path, atomic, on_exists);
                 
-                let start_time = std::time::Instant::now();
+                let options = PutOptions { mode, create_dirs, umask, modified, atomic, transforms, on_exists };
+                Ok(self.put_file(id, &path, PutContent::Whole(&content), options).await)
+            }
+            
+            Request::FilePatchText { id, path, patch, create_backup } => {
+                debug!("Patching file: {:?} (backup: {})", path, create_backup);
                 
-                // Build the command with privilege escalation if needed
-                let final_command = if let Some(priv_config) = privilege {
-                    self.build_privileged_command(&command, &priv_config)?
-                } else {
-                    command
+                let result = self.handle_file_patch(&path, &patch, create_backup).await;
+                let outcome = match &result {
+                    Ok(_) => AuditResult::Succeeded,
+                    Err(e) => AuditResult::Failed { error: e.message.clone() },
                 };
+                self.audit.record(&AuditRecord::new(
+                    audit::agent_principal(),
+                    AuditOperation::FileWrite { path: path.clone() },
+                    outcome,
+                ));
                 
-                // For now, we'll use regular process execution as PTY requires platform-specific code
-                // In a full implementation, this would use pty crates like `portable-pty`
-                let mut cmd = Command::new(&final_command[0]);
-                if final_command.len() > 1 {
-                    cmd.args(&final_command[1..]);
+                match result {
+                    Ok((bytes_written, backup_path)) => Ok(Response::FilePatchResult {
+                        request_id: id,
+                        bytes_written,
+                        backup_path,
+                    }),
+                    Err(error) => {
+                        error!("File patch error: {}", error.message);
+                        Ok(Response::error(id, error))
+                    }
                 }
+            }
+            
+            // Without a response stream the whole listing is returned at once
+            Request::DirList { id, path, include_hidden, recursive, max_depth, .. } => {
+                debug!("Listing directory: {:?}", path);
                 
-                // Set environment variables
-                for (key, value) in env {
-                    cmd.env(key, value);
+                match self.handle_dir_list(&path, include_hidden, listing_depth(recursive, max_depth)).await {
+                    Ok(entries) => {
+                        Ok(Response::DirListing {
+                            request_id: id,
+                            entries,
+                        })
+                    }
+                    Err(e) => Ok(dir_list_error(id, e)),
                 }
+            }
+            
+            Request::FileTail { id, .. } => Ok(Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::Unsupported, "File tail needs a response stream")
+            )),
+            
+            Request::FileHash { id, path, algorithm } => {
+                debug!("Hashing file: {:?} ({:?})", path, algorithm);
                 
-                // Set working directory
-                if let Some(cwd) = cwd {
-                    cmd.current_dir(cwd);
+                let hashed = tokio::task::spawn_blocking(move || hash_file(&path, algorithm)).await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                match hashed {
+                    Ok((hash, size)) => Ok(Response::FileHash { request_id: id, hash, size }),
+                    Err(e) => {
+                        error!("File hash error: {}", e);
+                        let error_code = match e.kind() {
+                            std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+                            std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                            _ => ErrorCode::InternalError,
+                        };
+                        Ok(Response::error(id, ErrorDetails::new(error_code, format!("File hash failed: {}", e))))
+                    }
                 }
+            }
+            
+            Request::FileXattrGet { id, path } => {
+                debug!("Reading extended attributes of {:?}", path);
                 
-                // Configure stdio - for PTY we would typically use pty, but for now use pipes
-                cmd.stdin(Stdio::piped())
-                   .stdout(Stdio::piped())
-                   .stderr(Stdio::piped());
+                let xattrs = tokio::task::spawn_blocking(move || read_xattrs(&path)).await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                Ok(xattrs_response(id, xattrs))
+            }
+            
+            Request::FileXattrSet { id, path, xattrs } => {
+                debug!("Setting {} extended attributes on {:?}", xattrs.len(), path);
                 
-                // Execute the process
-                let output = if let Some(timeout_secs) = timeout {
-                    let timeout_duration = std::time::Duration::from_secs(timeout_secs);
-                    
-                    match tokio::time::timeout(timeout_duration, cmd.output()).await {
-                        Ok(Ok(output)) => output,
-                        Ok(Err(e)) => {
-                            return Ok(Response::error(
-                                id,
-                                ErrorDetails::new(ErrorCode::ProcessFailed, format!("Process error: {}", e))
-                            ));
-                        }
-                        Err(_) => {
-                            return Ok(Response::error(
-                                id,
-                                ErrorDetails::new(ErrorCode::Timeout, "Process execution timed out")
-                            ));
-                        }
+                let mut names: Vec<String> = xattrs.keys().cloned().collect();
+                names.sort();
+                let target = path.clone();
+                let written = tokio::task::spawn_blocking(move || write_xattrs(&target, &xattrs))
+                    .await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                let outcome = match &written {
+                    Ok(()) => AuditResult::Succeeded,
+                    Err(e) => AuditResult::Failed { error: e.to_string() },
+                };
+                self.audit.record(&AuditRecord::new(
+                    audit::agent_principal(),
+                    AuditOperation::XattrSet { path: path.clone(), names },
+                    outcome,
+                ));
+                
+                let xattrs = match written {
+                    Ok(()) => tokio::task::spawn_blocking(move || read_xattrs(&path))
+                        .await
+                        .unwrap_or_else(|e| Err(std::io::Error::other(e))),
+                    Err(e) => Err(e),
+                };
+                Ok(xattrs_response(id, xattrs))
+            }
+            
+            Request::FileChown { id, path, uid, gid, recursive } => {
+                debug!("Changing owner of {:?} to {:?}:{:?} (recursive: {})", path, uid, gid, recursive);
+                
+                let operation = AuditOperation::Chown { path: path.clone(), uid: uid.clone(), gid: gid.clone(), recursive };
+                let chowned = tokio::task::spawn_blocking(move || chown_path(&path, uid.as_ref(), gid.as_ref(), recursive))
+                    .await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                let outcome = match &chowned {
+                    Ok(_) => AuditResult::Succeeded,
+                    Err(e) => AuditResult::Failed { error: e.to_string() },
+                };
+                self.audit.record(&AuditRecord::new(audit::agent_principal(), operation, outcome));
+                match chowned {
+                    Ok((uid, gid, changed)) => Ok(Response::FileOwnership { request_id: id, uid, gid, changed }),
+                    Err(e) => {
+                        error!("File chown error: {}", e);
+                        let error_code = match e.kind() {
+                            std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+                            std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                            std::io::ErrorKind::InvalidInput => ErrorCode::InvalidRequest,
+                            std::io::ErrorKind::Unsupported => ErrorCode::Unsupported,
+                            _ => ErrorCode::InternalError,
+                        };
+                        Ok(Response::error(id, ErrorDetails::new(error_code, format!("File chown failed: {}", e))))
                     }
-                } else {
-                    match cmd.output().await {
-                        Ok(output) => output,
-                        Err(e) => {
-                            return Ok(Response::error(
-                                id,
-                                ErrorDetails::new(ErrorCode::ProcessFailed, format!("Process error: {}", e))
-                            ));
-                        }
+                }
+            }
+            
+            Request::FileEnsure { id, path, content, mode, owner } => {
+                debug!("Ensuring content of {:?} (mode: {:?}, owner: {:?})", path, mode, owner);
+                
+                let result = self.handle_file_ensure(&path, &content, mode, owner).await;
+                // Only a file that had to change was written
+                let outcome = match &result {
+                    Ok(changes) if changes.is_empty() => None,
+                    Ok(_) => Some(AuditResult::Succeeded),
+                    Err(e) => Some(AuditResult::Failed { error: e.to_string() }),
+                };
+                if let Some(outcome) = outcome {
+                    self.audit.record(&AuditRecord::new(
+                        audit::agent_principal(),
+                        AuditOperation::FileWrite { path: path.clone() },
+                        outcome,
+                    ));
+                }
+                
+                match result {
+                    Ok(what_changed) => Ok(Response::FileEnsure {
+                        request_id: id,
+                        changed: !what_changed.is_empty(),
+                        what_changed,
+                    }),
+                    Err(e) => {
+                        error!("File ensure error: {:#}", e);
+                        Ok(Response::error(
+                            id,
+                            ErrorDetails::new(file_write_error_code(&e), format!("File ensure failed: {:#}", e))
+                        ))
+                    }
+                }
+            }
+            
+            Request::FileSignature { id, path, block_size } => {
+                debug!("Computing signature of {:?} in blocks of {} bytes", path, block_size);
+                
+                let signature = tokio::task::spawn_blocking(move || {
+                    if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Block size must be between 1 and {} bytes", MAX_BLOCK_SIZE),
+                        ));
                     }
+                    let file = std::fs::File::open(&path)?;
+                    delta::signature(std::io::BufReader::new(file), block_size)
+                }).await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                match signature {
+                    Ok(signature) => Ok(Response::FileSignature { request_id: id, signature }),
+                    Err(e) => {
+                        error!("File signature error: {}", e);
+                        Ok(Response::error(id, ErrorDetails::new(file_io_error_code(&e), format!("File signature failed: {}", e))))
+                    }
+                }
+            }
+            
+            Request::FileDeltaPut { id, path, block_size, base_size, ops, mode, modified } => {
+                debug!("Putting {} delta ops to {:?}", ops.len(), path);
+                
+                let result = self.handle_file_delta_put(&path, block_size, base_size, ops, mode, modified).await;
+                let outcome = match &result {
+                    Ok(_) => AuditResult::Succeeded,
+                    Err(e) => AuditResult::Failed { error: e.to_string() },
                 };
+                self.audit.record(&AuditRecord::new(
+                    audit::agent_principal(),
+                    AuditOperation::FileWrite { path: path.clone() },
+                    outcome,
+                ));
                 
-                let duration = start_time.elapsed();
+                match result {
+                    Ok(bytes_written) => Ok(Response::FilePutResult { request_id: id, bytes_written, skipped: false, backup_path: None }),
+                    Err(e) => {
+                        error!("File delta put error: {}", e);
+                        Ok(Response::error(id, ErrorDetails::new(file_io_error_code(&e), format!("File delta put failed: {}", e))))
+                    }
+                }
+            }
+            
+            Request::FileSetAttributes { id, path, mode, modified } => {
+                debug!("Setting attributes of {:?} (mode: {:?}, modified: {:?})", path, mode, modified);
                 
-                // Combine stdout and stderr for PTY-like behavior
-                let mut combined_output = output.stdout;
-                combined_output.extend_from_slice(&output.stderr);
+                let operation = AuditOperation::SetAttributes { path: path.clone(), mode, modified };
+                let attributes = tokio::task::spawn_blocking(move || set_attributes(&path, mode, modified)).await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                // Only a request that changes something is audited
+                if mode.is_some() || modified.is_some() {
+                    let outcome = match &attributes {
+                        Ok(_) => AuditResult::Succeeded,
+                        Err(e) => AuditResult::Failed { error: e.to_string() },
+                    };
+                    self.audit.record(&AuditRecord::new(audit::agent_principal(), operation, outcome));
+                }
+                match attributes {
+                    Ok((mode, modified)) => Ok(Response::FileAttributes { request_id: id, mode, modified }),
+                    Err(e) => {
+                        error!("File attributes error: {}", e);
+                        Ok(Response::error(id, ErrorDetails::new(file_io_error_code(&e), format!("Setting file attributes failed: {}", e))))
+                    }
+                }
+            }
+            
+            Request::FileRemove { id, path, recursive } => {
+                debug!("Removing {:?} (recursive: {})", path, recursive);
                 
-                Ok(Response::PtyResult {
-                    request_id: id,
-                    exit_code: output.status.code().unwrap_or(-1),
-                    output: Bytes::from(combined_output),
-                    duration_ms: duration.as_millis() as u64,
-                })
+                let operation = AuditOperation::Remove { path: path.clone(), recursive };
+                let removed = tokio::task::spawn_blocking(move || remove_path(&path, recursive)).await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                let outcome = match &removed {
+                    Ok(_) => AuditResult::Succeeded,
+                    Err(e) => AuditResult::Failed { error: e.to_string() },
+                };
+                self.audit.record(&AuditRecord::new(audit::agent_principal(), operation, outcome));
+                match removed {
+                    Ok(existed) => Ok(Response::FileRemoved { request_id: id, existed }),
+                    Err(e) => {
+                        error!("File remove error: {}", e);
+                        Ok(Response::error(id, ErrorDetails::new(file_io_error_code(&e), format!("File remove failed: {}", e))))
+                    }
+                }
+            }
+            
+            Request::DiskSpace { id, path } => {
+                debug!("Measuring filesystem of {:?}", path);
+                
+                let space = tokio::task::spawn_blocking(move || filesystem_space(&path)).await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                match space {
+                    Ok(space) => Ok(Response::DiskSpace { request_id: id, space }),
+                    Err(e) => {
+                        error!("Disk space error: {}", e);
+                        let error_code = match e.kind() {
+                            std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+                            std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                            std::io::ErrorKind::Unsupported => ErrorCode::Unsupported,
+                            _ => ErrorCode::InternalError,
+                        };
+                        Ok(Response::error(id, ErrorDetails::new(error_code, format!("Disk space check failed: {}", e))))
+                    }
+                }
             }
+            
             _ => Ok(Response::error(
                 request.id(),
-                ErrorDetails::new(ErrorCode::Unsupported, "PtyHandler only handles PtyExec requests")
+                ErrorDetails::new(ErrorCode::Unsupported, "FileHandler only handles file/directory requests")
             ))
         }
     }
-}
-
-impl PtyHandler {
-    /// Build a command with privilege escalation
-    fn build_privileged_command(
-        &self,
-        command: &[String],
-        privilege: &mitoxide_proto::message::PrivilegeEscalation,
-    ) -> Result<Vec<String>> {
-        let mut privileged_command = Vec::new();
-        
-        match &privilege.method {
-            PrivilegeMethod::Sudo => {
-                privileged_command.push("sudo".to_string());
-                privileged_command.push("-S".to_string()); // Read password from stdin
-                if let Some(ref creds) = privilege.credentials {
-                    if let Some(ref username) = creds.username {
-                        privileged_command.push("-u".to_string());
-                        privileged_command.push(username.clone());
-                    }
+    
+    async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+        match request {
+            Request::FileGet { id, path, range, decompress, chunk_size: Some(chunk_size), include_xattrs, transforms } => {
+                debug!("Getting file in chunks of {} bytes: {:?}", chunk_size, path);
+                
+                let chunks = ChunkedGet { chunk_size: chunk_size.max(1) as usize, transforms: &transforms };
+                let result = match self.stream_file_get(id, &path, range, decompress, chunks, &stream.output).await {
+                    Ok(metadata) if include_xattrs => with_xattrs(&path, metadata).await,
+                    result => result,
+                };
+                match result {
+                    Ok(metadata) => Ok(Response::FileContent { request_id: id, content: Bytes::new(), metadata }),
+                    Err(e) => Ok(file_get_error(id, e)),
                 }
-                privileged_command.extend_from_slice(command);
             }
-            PrivilegeMethod::Su => {
-                privileged_command.push("su".to_string());
-                if let Some(ref creds) = privilege.credentials {
-                    if let Some(ref username) = creds.username {
-                        privileged_command.push(username.clone());
+            Request::FileTail { id, path, from_end_lines, follow } => {
+                debug!("Tailing file: {:?} (lines: {}, follow: {})", path, from_end_lines, follow);
+                
+                match self.handle_file_tail(id, &path, from_end_lines, follow, stream).await {
+                    Ok(offset) => Ok(Response::FileTailEnded { request_id: id, offset }),
+                    Err(e) => {
+                        error!("File tail error: {}", e);
+                        let error_string = e.to_string().to_lowercase();
+                        let error_code = if error_string.contains("no such file") || error_string.contains("not found") {
+                            ErrorCode::FileNotFound
+                        } else if error_string.contains("permission denied") {
+                            ErrorCode::PermissionDenied
+                        } else {
+                            ErrorCode::InternalError
+                        };
+                        
+                        Ok(Response::error(
+                            id,
+                            ErrorDetails::new(error_code, format!("File tail failed: {}", e))
+                        ))
                     }
                 }
-                privileged_command.push("-c".to_string());
-                privileged_command.push(command.join(" "));
             }
-            PrivilegeMethod::Doas => {
-                privileged_command.push("doas".to_string());
-                if let Some(ref creds) = privilege.credentials {
-                    if let Some(ref username) = creds.username {
-                        privileged_command.push("-u".to_string());
-                        privileged_command.push(username.clone());
-                    }
-                }
-                privileged_command.extend_from_slice(command);
+            Request::FilePut { id, path, mode, create_dirs, umask, modified, atomic, transforms, on_exists, stream_content: true, .. } => {
+                debug!("Putting streamed file: {:?} (atomic: {}, on exists: {:?})", path, atomic, on_exists);
+                
+                let Some(input) = stream.input else {
+                    return Ok(streamed_put_without_input(id));
+                };
+                let options = PutOptions { mode, create_dirs, umask, modified, atomic, transforms, on_exists };
+                Ok(self.put_file(id, &path, PutContent::Streamed(input), options).await)
             }
-            PrivilegeMethod::Custom(cmd) => {
-                privileged_command.push(cmd.clone());
-                privileged_command.extend_from_slice(command);
+            Request::DirList { id, path, include_hidden, recursive, batch_size: Some(batch_size), max_depth } => {
+                debug!("Streaming directory listing: {:?} (batch size: {})", path, batch_size);
+                
+                let depth = listing_depth(recursive, max_depth);
+                match self.stream_dir_list(id, &path, include_hidden, depth, batch_size.max(1) as usize, stream).await {
+                    Ok(entries) => Ok(Response::DirListing { request_id: id, entries }),
+                    Err(e) => Ok(dir_list_error(id, e)),
+                }
             }
+            request => self.handle(request).await,
         }
-        
-        Ok(privileged_command)
     }
+}
+
+/// Metadata of a file about to be read, refusing directories
+async fn file_get_metadata(path: &Path) -> Result<(std::fs::Metadata, FileMetadata)> {
+    let metadata = fs::metadata(path).await
+        .context("Failed to get file metadata")?;
     
-    /// Detect privilege escalation prompts in output
-    fn detect_privilege_prompt(&self, output: &str, patterns: &[String]) -> bool {
-        let default_patterns = [
-            "password:",
-            "Password:",
-            "[sudo] password",
-            "su:",
-            "doas:",
-        ];
-        
-        // If custom patterns are provided, only check those
-        if !patterns.is_empty() {
-            for pattern in patterns {
-                if output.to_lowercase().contains(&pattern.to_lowercase()) {
-                    return true;
-                }
-            }
-            return false;
+    if metadata.is_dir() {
+        return Err(anyhow::anyhow!("Path is a directory, not a file"));
+    }
+    
+    let file_metadata = FileMetadata {
+        size: metadata.len(),
+        mode: 0o644, // Default mode, platform-specific implementation would get actual mode
+        modified: metadata.modified()
+            .unwrap_or(std::time::UNIX_EPOCH)
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        is_dir: false,
+        is_symlink: metadata.file_type().is_symlink(),
+        decompressed_size: None,
+        xattrs: None,
+    };
+    Ok((metadata, file_metadata))
+}
+
+/// Lowercase hex digest of the file at `path` and the number of bytes hashed
+///
+/// Reads the whole file with blocking I/O, so callers run it on a blocking thread.
+fn hash_file(path: &Path, algorithm: HashAlgorithm) -> std::io::Result<(String, u64)> {
+    use sha2::{Digest, Sha256};
+    
+    let mut file = std::fs::File::open(path)?;
+    match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            let size = std::io::copy(&mut file, &mut hasher)?;
+            Ok((format!("{:x}", hasher.finalize()), size))
         }
-        
-        // Check default patterns when no custom patterns provided
-        for pattern in &default_patterns {
-            if output.to_lowercase().contains(&pattern.to_lowercase()) {
-                return true;
-            }
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            let size = std::io::copy(&mut file, &mut hasher)?;
+            Ok((hasher.finalize().to_hex().to_string(), size))
         }
-        
-        false
     }
 }
 
-/// Handler for ping requests
-pub struct PingHandler;
+/// Extended attributes of the file at `path`, by name
+///
+/// Uses blocking system calls, so callers run it on a blocking thread.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn read_xattrs(path: &Path) -> std::io::Result<HashMap<String, Bytes>> {
+    let mut names = vec![0; rustix::fs::listxattr(path, &mut [])?];
+    let len = rustix::fs::listxattr(path, &mut names)?;
+    names.truncate(len);
+    
+    let mut xattrs = HashMap::new();
+    for name in names.split(|&c| c == 0).filter(|name| !name.is_empty()) {
+        let name: Vec<u8> = name.iter().map(|c| c.to_ne_bytes()[0]).collect();
+        let mut value = vec![0; rustix::fs::getxattr(path, &name, &mut [])?];
+        let len = rustix::fs::getxattr(path, &name, &mut value)?;
+        value.truncate(len);
+        xattrs.insert(String::from_utf8_lossy(&name).into_owned(), Bytes::from(value));
+    }
+    Ok(xattrs)
+}
 
-#[async_trait]
-impl Handler for PingHandler {
-    async fn handle(&self, request: Request) -> Result<Response> {
-        match request {
-            Request::Ping { id, timestamp } => {
-                debug!("Handling ping request: id={}, timestamp={}", id, timestamp);
-                Ok(Response::pong(id, timestamp))
+/// Set each of `xattrs` on the file at `path`, creating or replacing it
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn write_xattrs(path: &Path, xattrs: &HashMap<String, Bytes>) -> std::io::Result<()> {
+    for (name, value) in xattrs {
+        rustix::fs::setxattr(path, name.as_str(), value, rustix::fs::XattrFlags::empty())?;
+    }
+    Ok(())
+}
+
+/// Extended attributes are only implemented on Linux and macOS
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn read_xattrs(_path: &Path) -> std::io::Result<HashMap<String, Bytes>> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Extended attributes are not supported on this platform"))
+}
+
+/// Extended attributes are only implemented on Linux and macOS
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn write_xattrs(_path: &Path, _xattrs: &HashMap<String, Bytes>) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Extended attributes are not supported on this platform"))
+}
+
+/// Set the owners of `path`, and with `recursive` of everything below it, returning the owners and entries changed
+#[cfg(unix)]
+fn chown_path(path: &Path, uid: Option<&FileOwner>, gid: Option<&FileOwner>, recursive: bool) -> std::io::Result<(u32, u32, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    
+    let uid = uid.map(resolve_user).transpose()?;
+    let gid = gid.map(resolve_group).transpose()?;
+    let mut changed = 0;
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(path, uid, gid)?;
+        changed += 1;
+        
+        let mut pending = Vec::new();
+        if recursive && std::fs::metadata(path)?.is_dir() {
+            pending.push(path.to_path_buf());
+        }
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                // Symlinks are changed themselves rather than what they point to
+                std::os::unix::fs::lchown(entry.path(), uid, gid)?;
+                changed += 1;
+                if entry.file_type()?.is_dir() {
+                    pending.push(entry.path());
+                }
             }
-            _ => Ok(Response::error(
-                request.id(),
-                ErrorDetails::new(ErrorCode::Unsupported, "PingHandler only handles Ping requests")
-            ))
         }
     }
+    
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.uid(), metadata.gid(), changed))
 }
 
-/// Handler for WASM module execution
-pub struct WasmHandler {
-    /// WASM runtime for executing modules
-    runtime: Arc<mitoxide_wasm::WasmRuntime>,
-    /// Module cache for hash-based caching
-    module_cache: Arc<tokio::sync::RwLock<HashMap<String, mitoxide_wasm::WasmModule>>>,
+/// Give `path` the permissions and owners in `metadata`, as a copy standing in for that file
+#[cfg(unix)]
+fn copy_mode_and_owner(path: &Path, metadata: &std::fs::Metadata) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    
+    // Changing owners can clear set-ID bits, so the mode goes on after
+    let current = std::fs::metadata(path)?;
+    if (current.uid(), current.gid()) != (metadata.uid(), metadata.gid()) {
+        std::os::unix::fs::chown(path, Some(metadata.uid()), Some(metadata.gid()))?;
+    }
+    std::fs::set_permissions(path, metadata.permissions())
 }
 
-impl WasmHandler {
-    /// Create a new WASM handler
-    pub fn new() -> Result<Self> {
-        let runtime = Arc::new(mitoxide_wasm::WasmRuntime::new()
-            .map_err(|e| anyhow::anyhow!("Failed to create WASM runtime: {}", e))?);
-        
-        let module_cache = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
-        
-        Ok(WasmHandler {
-            runtime,
-            module_cache,
-        })
+/// Set the modification time and then the mode of `path`, returning both as they are afterwards
+fn set_attributes(path: &Path, mode: Option<u32>, modified: Option<u64>) -> std::io::Result<(u32, u64)> {
+    if let Some(modified) = modified {
+        // Setting the time takes owning the file, not writing it, so read-only files can be dated
+        let file = std::fs::File::open(path)?;
+        file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(modified))?;
     }
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
     
-    /// Create a new WASM handler with custom configuration
-    pub fn with_config(config: mitoxide_wasm::WasmConfig) -> Result<Self> {
-        let runtime = Arc::new(mitoxide_wasm::WasmRuntime::with_config(config)
-            .map_err(|e| anyhow::anyhow!("Failed to create WASM runtime: {}", e))?);
-        
-        let module_cache = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
-        
-        Ok(WasmHandler {
-            runtime,
-            module_cache,
-        })
+    let metadata = std::fs::metadata(path)?;
+    Ok((permission_bits(&metadata), modified_secs(&metadata)))
+}
+
+/// Remove whatever is at `path` without following symlinks, returning whether there was anything
+fn remove_path(path: &Path, recursive: bool) -> std::io::Result<bool> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_dir() {
+        std::fs::remove_file(path)?;
+    } else if recursive {
+        std::fs::remove_dir_all(path)?;
+    } else if std::fs::read_dir(path)?.next().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{:?} is a directory that isn't empty", path),
+        ));
+    } else {
+        std::fs::remove_dir(path)?;
     }
+    Ok(true)
+}
+
+/// Permission bits of a file
+#[cfg(unix)]
+fn permission_bits(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+/// Permission bits of a file, of which only write access is known
+#[cfg(not(unix))]
+fn permission_bits(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+/// Modification time of a file in seconds since the Unix epoch
+fn modified_secs(metadata: &std::fs::Metadata) -> u64 {
+    metadata.modified()
+        .unwrap_or(std::time::UNIX_EPOCH)
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Give `path` the permissions in `metadata`, as a copy standing in for that file
+#[cfg(not(unix))]
+fn copy_mode_and_owner(path: &Path, metadata: &std::fs::Metadata) -> std::io::Result<()> {
+    std::fs::set_permissions(path, metadata.permissions())
+}
+
+/// Set the mode and owning user of `path` where they differ, returning which were set
+#[cfg(unix)]
+fn ensure_mode_and_owner(path: &Path, mode: Option<u32>, owner: Option<&FileOwner>) -> std::io::Result<Vec<FileChange>> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
     
-    /// Get or load a WASM module from cache
-    async fn get_or_load_module(&self, module_bytes: &[u8]) -> Result<mitoxide_wasm::WasmModule> {
-        // Create module to get hash
-        let module = mitoxide_wasm::WasmModule::from_bytes(module_bytes.to_vec())
-            .map_err(|e| anyhow::anyhow!("Failed to load WASM module: {}", e))?;
-        
-        let module_hash = module.hash().to_string();
-        
-        // Check cache first
-        {
-            let cache = self.module_cache.read().await;
-            if let Some(cached_module) = cache.get(&module_hash) {
-                debug!("Using cached WASM module: {}", module_hash);
-                return Ok(cached_module.clone());
-            }
+    let metadata = std::fs::metadata(path)?;
+    let mut changes = Vec::new();
+    if let Some(mode) = mode {
+        if metadata.mode() & 0o7777 != mode & 0o7777 {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+            changes.push(FileChange::Mode);
         }
-        
-        // Module not in cache, add it
-        {
-            let mut cache = self.module_cache.write().await;
-            debug!("Caching WASM module: {}", module_hash);
-            cache.insert(module_hash, module.clone());
+    }
+    if let Some(uid) = owner.map(resolve_user).transpose()? {
+        if metadata.uid() != uid {
+            std::os::unix::fs::chown(path, Some(uid), None)?;
+            changes.push(FileChange::Owner);
         }
-        
-        Ok(module)
     }
+    Ok(changes)
+}
+
+/// File modes and ownership are only implemented on Unix
+#[cfg(not(unix))]
+fn ensure_mode_and_owner(_path: &Path, mode: Option<u32>, owner: Option<&FileOwner>) -> std::io::Result<Vec<FileChange>> {
+    if mode.is_none() && owner.is_none() {
+        return Ok(Vec::new());
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "File modes and ownership are not supported on this platform"))
+}
+
+/// Size of the filesystem holding `path`, or its nearest existing ancestor
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // statvfs field widths vary by platform
+fn filesystem_space(path: &Path) -> std::io::Result<FilesystemSpace> {
+    use nix::errno::Errno;
     
-    /// Verify module hash if provided
-    fn verify_module_hash(&self, module: &mitoxide_wasm::WasmModule, expected_hash: Option<&str>) -> Result<()> {
-        if let Some(expected) = expected_hash {
-            let actual = module.hash();
-            if actual != expected {
-                return Err(anyhow::anyhow!(
-                    "Module hash mismatch: expected {}, got {}",
-                    expected,
-                    actual
-                ));
+    let mut not_found = None;
+    for candidate in path.ancestors().filter(|candidate| !candidate.as_os_str().is_empty()) {
+        match nix::sys::statvfs::statvfs(candidate) {
+            Ok(stat) => {
+                let block_size = stat.fragment_size() as u64;
+                let total = stat.blocks() as u64 * block_size;
+                return Ok(FilesystemSpace {
+                    total,
+                    available: stat.blocks_available() as u64 * block_size,
+                    used: total.saturating_sub(stat.blocks_free() as u64 * block_size),
+                });
+            }
+            Err(errno @ (Errno::ENOENT | Errno::ENOTDIR)) => {
+                not_found.get_or_insert(errno);
             }
+            Err(errno) => return Err(errno.into()),
         }
-        Ok(())
     }
+    Err(not_found.unwrap_or(Errno::ENOENT).into())
 }
 
-#[async_trait]
-impl Handler for WasmHandler {
-    async fn handle(&self, request: Request) -> Result<Response> {
-        match request {
-            Request::WasmExec { id, module, input, timeout } => {
-                debug!("Executing WASM module: {} bytes", module.len());
-                
-                let start_time = std::time::Instant::now();
-                
-                // Load and cache the module
-                let mut wasm_module = match self.get_or_load_module(&module).await {
-                    Ok(module) => module,
-                    Err(e) => {
-                        error!("Failed to load WASM module: {}", e);
-                        return Ok(Response::error(
-                            id,
-                            ErrorDetails::new(ErrorCode::WasmFailed, format!("Module loading failed: {}", e))
-                        ));
-                    }
-                };
-                
-                // Create WASM execution context
-                let context = mitoxide_wasm::WasmContext::new();
-                
-                // Execute the module with JSON input/output
-                let execution_result = if wasm_module.is_wasi() {
-                    // For WASI modules, convert input to string and execute
-                    let input_str = String::from_utf8(input.to_vec())
-                        .unwrap_or_else(|_| {
-                            // If input is not valid UTF-8, convert to JSON string
-                            serde_json::to_string(&input.to_vec()).unwrap_or_default()
-                        });
-                    
-                    self.runtime.execute_with_stdio(&mut wasm_module, &input_str, context).await
-                } else {
-                    // For non-WASI modules, try to parse input as JSON and execute
-                    match serde_json::from_slice::<serde_json::Value>(&input) {
-                        Ok(json_input) => {
-                            match self.runtime.execute_json::<serde_json::Value, serde_json::Value>(
-                                &mut wasm_module,
-                                &json_input,
-                                context,
-                            ).await {
-                                Ok(output) => {
-                                    serde_json::to_string(&output)
-                                        .map_err(|e| mitoxide_wasm::WasmError::Execution(format!("JSON serialization failed: {}", e)))
-                                }
-                                Err(e) => Err(e),
-                            }
-                        }
-                        Err(_) => {
-                            // Input is not valid JSON, treat as raw bytes for WASI
-                            let input_str = String::from_utf8_lossy(&input);
-                            self.runtime.execute_with_stdio(&mut wasm_module, &input_str, context).await
-                        }
-                    }
+/// Filesystem sizes are only implemented on Unix
+#[cfg(not(unix))]
+fn filesystem_space(_path: &Path) -> std::io::Result<FilesystemSpace> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Filesystem sizes are not supported on this platform"))
+}
+
+/// File ownership is only implemented on Unix
+#[cfg(not(unix))]
+fn chown_path(_path: &Path, _uid: Option<&FileOwner>, _gid: Option<&FileOwner>, _recursive: bool) -> std::io::Result<(u32, u32, u64)> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "File ownership is not supported on this platform"))
+}
+
+/// User ID of `owner`, looking names up in the user database
+#[cfg(unix)]
+fn resolve_user(owner: &FileOwner) -> std::io::Result<u32> {
+    match owner {
+        FileOwner::Id(uid) => Ok(*uid),
+        FileOwner::Name(name) => nix::unistd::User::from_name(name)?
+            .map(|user| user.uid.as_raw())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Unknown user {:?}", name))),
+    }
+}
+
+/// Group ID of `owner`, looking names up in the group database
+#[cfg(unix)]
+fn resolve_group(owner: &FileOwner) -> std::io::Result<u32> {
+    match owner {
+        FileOwner::Id(gid) => Ok(*gid),
+        FileOwner::Name(name) => nix::unistd::Group::from_name(name)?
+            .map(|group| group.gid.as_raw())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Unknown group {:?}", name))),
+    }
+}
+
+/// Whether an extended attribute call failed because the platform or filesystem lacks them
+fn xattrs_unsupported(e: &std::io::Error) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        let errno = e.raw_os_error();
+        if errno == Some(rustix::io::Errno::NOTSUP.raw_os_error()) || errno == Some(rustix::io::Errno::OPNOTSUPP.raw_os_error()) {
+            return true;
+        }
+    }
+    e.kind() == std::io::ErrorKind::Unsupported
+}
+
+/// Response to a request reading or setting extended attributes
+fn xattrs_response(id: Uuid, xattrs: std::io::Result<HashMap<String, Bytes>>) -> Response {
+    match xattrs {
+        Ok(xattrs) => Response::FileXattrs { request_id: id, xattrs },
+        Err(e) => {
+            error!("Extended attribute error: {}", e);
+            let (error_code, message) = if xattrs_unsupported(&e) {
+                (ErrorCode::Unsupported, "Extended attributes are not supported by the filesystem".to_string())
+            } else {
+                let error_code = match e.kind() {
+                    std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+                    std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                    _ => ErrorCode::InternalError,
                 };
-                
-                let duration = start_time.elapsed();
-                
-                match execution_result {
-                    Ok(output) => {
-                        debug!("WASM execution completed in {:?}", duration);
-                        Ok(Response::WasmResult {
-                            request_id: id,
-                            output: Bytes::from(output),
-                            duration_ms: duration.as_millis() as u64,
-                        })
-                    }
-                    Err(e) => {
-                        error!("WASM execution failed: {}", e);
-                        Ok(Response::error(
-                            id,
-                            ErrorDetails::new(ErrorCode::WasmFailed, format!("Execution failed: {}", e))
-                        ))
-                    }
-                }
+                (error_code, format!("Extended attribute operation failed: {}", e))
+            };
+            Response::error(id, ErrorDetails::new(error_code, message))
+        }
+    }
+}
+
+/// Add the extended attributes of `path` to its metadata
+///
+/// Where the filesystem doesn't support them the metadata is left without any.
+async fn with_xattrs(path: &Path, metadata: FileMetadata) -> Result<FileMetadata> {
+    let path = path.to_path_buf();
+    match tokio::task::spawn_blocking(move || read_xattrs(&path)).await? {
+        Ok(xattrs) => Ok(FileMetadata { xattrs: Some(xattrs), ..metadata }),
+        Err(e) if xattrs_unsupported(&e) => Ok(metadata),
+        Err(e) => Err(e).context("Failed to read extended attributes"),
+    }
+}
+
+/// Error response for a failed file get
+fn file_get_error(id: Uuid, e: anyhow::Error) -> Response {
+    if let Some(over_budget) = e.downcast_ref::<OverBudget>() {
+        warn!("File get rejected: {}", over_budget);
+        return Response::error(id, (*over_budget).into());
+    }
+    if let Some(too_large) = e.downcast_ref::<DecompressedTooLarge>() {
+        warn!("File get rejected: {}", too_large);
+        return Response::error(id, ErrorDetails::new(ErrorCode::ResponseTooLarge, too_large.to_string()));
+    }
+    if let Some(transform_error) = e.downcast_ref::<TransformError>() {
+        warn!("File get rejected: {}", transform_error);
+        return Response::error(id, transform_error.clone().into());
+    }
+    error!("File get error: {}", e);
+    let error_string = e.to_string().to_lowercase();
+    let error_code = if error_string.contains("no such file") || 
+                       error_string.contains("not found") ||
+                       error_string.contains("cannot find") {
+        ErrorCode::FileNotFound
+    } else if error_string.contains("permission denied") || 
+              error_string.contains("access denied") {
+        ErrorCode::PermissionDenied
+    } else {
+        ErrorCode::InternalError
+    };
+    
+    Response::error(id, ErrorDetails::new(error_code, format!("File get failed: {}", e)))
+}
+
+/// Send `content` as `FileChunk` responses of at most `chunk_size` bytes,
+/// returning false once the client has gone
+async fn send_chunks(id: Uuid, content: &[u8], chunk_size: usize, output: &ResponseSink) -> bool {
+    for data in content.chunks(chunk_size) {
+        if !output.send_data(Response::FileChunk { request_id: id, data: Bytes::copy_from_slice(data) }, data.len()).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Create a directory and its missing parents, masking `umask` out of their permissions
+async fn create_dir_all_masked(path: &Path, umask: Option<u32>) -> std::io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(umask) = umask {
+        builder.mode(0o777 & !umask);
+    }
+    #[cfg(not(unix))]
+    let _ = umask;
+    builder.create(path).await
+}
+
+/// How a `FilePut` writes its file
+struct PutOptions {
+    /// Permissions to give the file
+    mode: Option<u32>,
+    /// Create missing parent directories
+    create_dirs: bool,
+    /// Permission bits masked out of created files and directories
+    umask: Option<u32>,
+    /// Modification time to give the file, in seconds since the Unix epoch
+    modified: Option<u64>,
+    /// Rename a complete temporary copy over the file instead of writing it in place
+    atomic: bool,
+    /// Rewriting applied to the content before it is written
+    transforms: Vec<ContentTransform>,
+    /// What to do if the file already exists
+    on_exists: OnExists,
+}
+
+/// Content a `FilePut` writes
+enum PutContent<'a> {
+    /// All of it, sent with the request
+    Whole(&'a Bytes),
+    /// Chunks arriving on the request's stream
+    Streamed(StreamInput),
+}
+
+/// How a put's content takes the place of a file that may already be there
+enum Placement {
+    /// Replace the file
+    Replace,
+    /// Fail with `AlreadyExists` rather than replace the file
+    NoReplace,
+    /// Rename the file to the given path, then put the content in its place
+    Backup(PathBuf),
+}
+
+/// What a `FilePut` did
+#[derive(Debug, Default)]
+struct PutOutcome {
+    /// Bytes written
+    bytes_written: u64,
+    /// The file existed and was left alone
+    skipped: bool,
+    /// Where the existing file was moved first
+    backup_path: Option<PathBuf>,
+}
+
+/// How a chunked `FileGet` sends its content
+struct ChunkedGet<'a> {
+    /// Largest chunk sent
+    chunk_size: usize,
+    /// Rewriting applied to the content before it is split
+    transforms: &'a [ContentTransform],
+}
+
+/// Write a file, masking `umask` out of its permissions if it is created
+///
+/// A write that fails or comes up short removes the file rather than leave
+/// part of `content` in it. With `sync`, the content is on disk before this
+/// returns. With `exclusive`, an existing file fails the write with
+/// `AlreadyExists` instead of being truncated.
+async fn write_masked(path: &Path, content: &[u8], umask: Option<u32>, sync: bool, exclusive: bool) -> std::io::Result<()> {
+    let mut file = open_masked(path, umask, exclusive).await?;
+    let written = async {
+        file.write_all(content).await?;
+        file.flush().await?;
+        if sync {
+            file.sync_all().await?;
+        }
+        let len = file.metadata().await?.len();
+        if len != content.len() as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                format!("Short write: {} of {} bytes", len, content.len()),
+            ));
+        }
+        Ok(())
+    }.await;
+    if written.is_err() {
+        drop(file);
+        let _ = fs::remove_file(path).await;
+    }
+    written
+}
+
+/// Write a file from the chunks arriving on `input`, like [`write_masked`], returning the bytes written
+///
+/// Each chunk is transformed and written as it arrives, so only the chunk at
+/// hand is held rather than the whole content.
+async fn write_streamed(
+    path: &Path,
+    mut input: StreamInput,
+    transforms: &[ContentTransform],
+    umask: Option<u32>,
+    sync: bool,
+    exclusive: bool,
+) -> std::io::Result<u64> {
+    let invalid = |e: TransformError| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let mut file = open_masked(path, umask, exclusive).await?;
+    let written = async {
+        let mut transformer = ContentTransformer::new(transforms);
+        let mut written = 0;
+        while let Some(chunk) = input.recv().await {
+            let chunk = transformer.push(&chunk).map_err(invalid)?;
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        let rest = transformer.finish().map_err(invalid)?;
+        file.write_all(&rest).await?;
+        written += rest.len() as u64;
+        file.flush().await?;
+        if sync {
+            file.sync_all().await?;
+        }
+        let len = file.metadata().await?.len();
+        if len != written {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                format!("Short write: {} of {} bytes", len, written),
+            ));
+        }
+        Ok(written)
+    }.await;
+    if written.is_err() {
+        drop(file);
+        let _ = fs::remove_file(path).await;
+    }
+    written
+}
+
+/// Create or truncate a file to write, masking `umask` out of its permissions if it is created
+///
+/// With `exclusive`, the file is only created, in the same step that checks
+/// it isn't there yet.
+async fn open_masked(path: &Path, umask: Option<u32>, exclusive: bool) -> std::io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    if exclusive {
+        options.write(true).create_new(true);
+    } else {
+        options.write(true).create(true).truncate(true);
+    }
+    #[cfg(unix)]
+    if let Some(umask) = umask {
+        options.mode(0o666 & !umask);
+    }
+    #[cfg(not(unix))]
+    let _ = umask;
+    
+    options.open(path).await
+}
+
+/// Write a file by renaming a complete temporary copy beside it over it
+///
+/// If anything fails, the copy is removed and an existing file is left as it
+/// was. The copy takes the existing file's permissions, and takes the file's
+/// place as `placement` says.
+async fn write_atomic(path: &Path, content: &[u8], umask: Option<u32>, placement: &Placement) -> std::io::Result<()> {
+    let write = |temp_path: PathBuf| async move { write_masked(&temp_path, content, umask, true, false).await };
+    let ((), ()) = write_atomic_with(path, write, keep_permissions, placement).await?;
+    Ok(())
+}
+
+/// Write a file from the chunks arriving on `input` like [`write_atomic`], returning the bytes written
+async fn write_atomic_streamed(
+    path: &Path,
+    input: StreamInput,
+    transforms: &[ContentTransform],
+    umask: Option<u32>,
+    placement: &Placement,
+) -> std::io::Result<u64> {
+    let write = |temp_path: PathBuf| async move { write_streamed(&temp_path, input, transforms, umask, true, false).await };
+    let (written, ()) = write_atomic_with(path, write, keep_permissions, placement).await?;
+    Ok(written)
+}
+
+/// Give the copy [`write_atomic`] renames over a file the file's permissions
+fn keep_permissions(temp_path: &Path, existing: Option<std::fs::Metadata>) -> std::io::Result<()> {
+    if let Some(metadata) = existing {
+        std::fs::set_permissions(temp_path, metadata.permissions())?;
+    }
+    Ok(())
+}
+
+/// Write a file like [`write_atomic`], with `prepare` setting up the copy before it is renamed
+///
+/// `prepare` is given the copy's path and the existing file's metadata, if
+/// there is one, so the file never appears without the attributes it sets.
+async fn write_atomic_prepared<T, F>(path: &Path, content: &[u8], umask: Option<u32>, prepare: F) -> std::io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Path, Option<std::fs::Metadata>) -> std::io::Result<T> + Send + 'static,
+{
+    let write = |temp_path: PathBuf| async move { write_masked(&temp_path, content, umask, true, false).await };
+    let ((), prepared) = write_atomic_with(path, write, prepare, &Placement::Replace).await?;
+    Ok(prepared)
+}
+
+/// Write a file like [`write_atomic_prepared`], with `write` filling the copy at the path it is given
+async fn write_atomic_with<T, U, W, Fut, F>(path: &Path, write: W, prepare: F, placement: &Placement) -> std::io::Result<(U, T)>
+where
+    T: Send + 'static,
+    W: FnOnce(PathBuf) -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<U>>,
+    F: FnOnce(&Path, Option<std::fs::Metadata>) -> std::io::Result<T> + Send + 'static,
+{
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.{}.put", file_name, Uuid::new_v4()));
+    let written = async {
+        let written = write(temp_path.clone()).await?;
+        let existing = fs::metadata(path).await.ok();
+        let prepare_path = temp_path.clone();
+        let prepared = tokio::task::spawn_blocking(move || prepare(&prepare_path, existing)).await
+            .map_err(std::io::Error::other)??;
+        place_copy(&temp_path, path, placement).await?;
+        Ok((written, prepared))
+    }.await;
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+    }
+    written
+}
+
+/// Put the complete copy at `temp_path` in the place of `path` as `placement` says
+async fn place_copy(temp_path: &Path, path: &Path, placement: &Placement) -> std::io::Result<()> {
+    match placement {
+        Placement::Replace => fs::rename(temp_path, path).await,
+        Placement::NoReplace => {
+            // Linking fails if the file is there, where renaming would replace it
+            fs::hard_link(temp_path, path).await?;
+            if let Err(e) = fs::remove_file(temp_path).await {
+                warn!("Failed to remove {:?} once it was linked into place: {}", temp_path, e);
+            }
+            Ok(())
+        }
+        Placement::Backup(backup) => {
+            move_to_backup(path, backup).await?;
+            fs::rename(temp_path, path).await
+        }
+    }
+}
+
+/// Rename the file at `path` to `backup`, if it is still there
+async fn move_to_backup(path: &Path, backup: &Path) -> std::io::Result<()> {
+    match fs::rename(path, backup).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Error code for a failed file write, telling a full disk apart from other I/O errors
+fn file_write_error_code(e: &anyhow::Error) -> ErrorCode {
+    if e.downcast_ref::<TransformError>().is_some() {
+        return ErrorCode::InvalidRequest;
+    }
+    let Some(io_error) = e.chain().find_map(|cause| cause.downcast_ref::<std::io::Error>()) else {
+        return ErrorCode::InternalError;
+    };
+    // Streamed content is transformed as it is written
+    if io_error.get_ref().is_some_and(|inner| inner.is::<TransformError>()) {
+        return ErrorCode::InvalidRequest;
+    }
+    #[cfg(unix)]
+    if let Some(errno) = io_error.raw_os_error().map(nix::errno::Errno::from_raw) {
+        if errno == nix::errno::Errno::ENOSPC || errno == nix::errno::Errno::EDQUOT {
+            return ErrorCode::DiskFull;
+        }
+    }
+    match io_error.kind() {
+        std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+        std::io::ErrorKind::InvalidInput => ErrorCode::InvalidRequest,
+        std::io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+        _ => ErrorCode::InternalError,
+    }
+}
+
+/// Error code for a failed file request that works on a file in place
+fn file_io_error_code(e: &std::io::Error) -> ErrorCode {
+    #[cfg(unix)]
+    if let Some(errno) = e.raw_os_error().map(nix::errno::Errno::from_raw) {
+        if errno == nix::errno::Errno::ENOSPC || errno == nix::errno::Errno::EDQUOT {
+            return ErrorCode::DiskFull;
+        }
+    }
+    match e.kind() {
+        std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+        std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+        std::io::ErrorKind::InvalidInput => ErrorCode::InvalidRequest,
+        // The file changed since the signature the delta was made against
+        std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => ErrorCode::PatchFailed,
+        _ => ErrorCode::InternalError,
+    }
+}
+
+/// Error for a `FilePut` whose content should follow on a stream it wasn't given
+fn streamed_put_without_input(id: Uuid) -> Response {
+    Response::error(
+        id,
+        ErrorDetails::new(ErrorCode::InvalidRequest, "File put with streamed content needs the request's stream")
+    )
+}
+
+/// Levels of subdirectories a `DirList` descends into, `None` for no limit
+fn listing_depth(recursive: bool, max_depth: Option<usize>) -> Option<usize> {
+    if recursive {
+        max_depth
+    } else {
+        Some(0)
+    }
+}
+
+/// Turn a directory listing failure into an error response
+fn dir_list_error(id: Uuid, e: anyhow::Error) -> Response {
+    // The I/O error is the cause under the context message
+    let message = format!("{:#}", e);
+    error!("Directory list error: {}", message);
+    let error_code = if message.contains("No such file") {
+        ErrorCode::FileNotFound
+    } else if message.contains("Permission denied") {
+        ErrorCode::PermissionDenied
+    } else {
+        ErrorCode::InternalError
+    };
+    
+    Response::error(
+        id,
+        ErrorDetails::new(error_code, format!("Directory list failed: {}", message))
+    )
+}
+
+/// Describe an entry read from a directory
+async fn to_dir_entry(entry: &fs::DirEntry) -> Result<DirEntry> {
+    let metadata = entry.metadata().await
+        .context("Failed to get entry metadata")?;
+    
+    let file_metadata = FileMetadata {
+        size: metadata.len(),
+        mode: permission_bits(&metadata),
+        modified: modified_secs(&metadata),
+        is_dir: metadata.is_dir(),
+        is_symlink: metadata.file_type().is_symlink(),
+        decompressed_size: None,
+        xattrs: None,
+    };
+    
+    Ok(DirEntry {
+        name: entry.file_name().to_string_lossy().to_string(),
+        path: entry.path(),
+        metadata: file_metadata,
+    })
+}
+
+/// Describe the next entry of `dir`, skipping hidden ones unless `include_hidden`
+async fn next_dir_entry(dir: &mut fs::ReadDir, include_hidden: bool) -> Result<Option<DirEntry>> {
+    while let Some(entry) = dir.next_entry().await
+        .context("Failed to read directory entry")? {
+        if include_hidden || !entry.file_name().to_string_lossy().starts_with('.') {
+            return to_dir_entry(&entry).await.map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Walk the tree under `root` breadth-first, sending each entry to `entries`
+///
+/// Descends at most `max_depth` levels of subdirectories, or without limit if
+/// `None`. Sending waits while [`DIR_WALK_BUFFER`] entries are unconsumed, so
+/// the walk holds no more than those and the directories left to read. It
+/// stops early, without error, once `cancel` fires or `entries` is closed. A
+/// subdirectory that can't be read is skipped; only failing on `root` is an error.
+async fn walk_dir(
+    root: PathBuf,
+    include_hidden: bool,
+    max_depth: Option<usize>,
+    entries: mpsc::Sender<DirEntry>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let mut dirs = VecDeque::from([(root.clone(), 0)]);
+    
+    while let Some((dir_path, depth)) = dirs.pop_front() {
+        if cancel.is_cancelled() {
+            return Ok(());
+        }
+        let mut dir = match fs::read_dir(&dir_path).await {
+            Ok(dir) => dir,
+            Err(e) if dir_path != root => {
+                warn!("Failed to read subdirectory {:?}: {}", dir_path, e);
+                continue;
+            }
+            Err(e) => return Err(e).context("Failed to read directory"),
+        };
+        
+        loop {
+            let entry = match next_dir_entry(&mut dir, include_hidden).await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) if dir_path != root => {
+                    warn!("Failed to read subdirectory {:?}: {:#}", dir_path, e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            
+            if entry.metadata.is_dir && max_depth.map_or(true, |max_depth| depth < max_depth) {
+                dirs.push_back((entry.path.clone(), depth + 1));
+            }
+            tokio::select! {
+                biased;
+                _ = cancel.cancelled() => return Ok(()),
+                sent = entries.send(entry) => if sent.is_err() {
+                    return Ok(());
+                },
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+/// Reads complete lines from a file as it grows
+struct LineTail {
+    /// Open file
+    file: fs::File,
+    /// Offset of the next byte to read
+    offset: u64,
+    /// Bytes of a line whose newline hasn't been read yet
+    pending: Vec<u8>,
+    /// The last bytes read, up to [`TAIL_FINGERPRINT_SIZE`] of them, ending at `offset`
+    fingerprint: Vec<u8>,
+}
+
+impl LineTail {
+    /// Open a file for tailing, starting `lines` lines before its end
+    async fn open(path: &Path, lines: u64) -> Result<Self> {
+        let mut file = fs::File::open(path).await
+            .context("Failed to open file")?;
+        let metadata = file.metadata().await
+            .context("Failed to get file metadata")?;
+        if metadata.is_dir() {
+            return Err(anyhow::anyhow!("Path is a directory, not a file"));
+        }
+        
+        let offset = last_lines_offset(&mut file, metadata.len(), lines).await?;
+        let mut tail = Self { file, offset, pending: Vec::new(), fingerprint: Vec::new() };
+        let start = offset.saturating_sub(TAIL_FINGERPRINT_SIZE);
+        tail.fingerprint = tail.read_at(start, offset - start).await?;
+        Ok(tail)
+    }
+    
+    /// Start over from the beginning of the file
+    fn rewind(&mut self) {
+        self.offset = 0;
+        self.pending.clear();
+        self.fingerprint.clear();
+    }
+    
+    /// Read up to `len` bytes from `start`
+    async fn read_at(&mut self, start: u64, len: u64) -> Result<Vec<u8>> {
+        use tokio::io::{AsyncSeekExt, SeekFrom};
+        
+        self.file.seek(SeekFrom::Start(start)).await
+            .context("Failed to seek in file")?;
+        let mut data = Vec::new();
+        (&mut self.file).take(len).read_to_end(&mut data).await
+            .context("Failed to read file")?;
+        Ok(data)
+    }
+    
+    /// Whether the bytes before the offset are still those that were read there
+    async fn is_rewritten(&mut self) -> Result<bool> {
+        if self.fingerprint.is_empty() {
+            return Ok(false);
+        }
+        let start = self.offset - self.fingerprint.len() as u64;
+        let current = self.read_at(start, self.fingerprint.len() as u64).await?;
+        Ok(current != self.fingerprint)
+    }
+    
+    /// Read the next block, returning the complete lines in it or None at end of file
+    ///
+    /// A file that shrank below the current offset, or whose bytes before it
+    /// changed, was truncated, so reading restarts from its beginning and any
+    /// partial line is dropped. The second catches a file truncated and
+    /// written past the offset again between two reads.
+    async fn read_lines(&mut self) -> Result<Option<Bytes>> {
+        let len = self.file.metadata().await
+            .context("Failed to get file metadata")?
+            .len();
+        if len < self.offset {
+            debug!("File truncated from {} to {} bytes, reading from the start", self.offset, len);
+            self.rewind();
+        } else if self.is_rewritten().await? {
+            debug!("File rewritten before offset {}, reading from the start", self.offset);
+            self.rewind();
+        }
+        if len == self.offset {
+            return Ok(None);
+        }
+        
+        let data = self.read_at(self.offset, (len - self.offset).min(TAIL_READ_SIZE)).await?;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        self.offset += data.len() as u64;
+        let fingerprint_size = TAIL_FINGERPRINT_SIZE as usize;
+        self.fingerprint.extend_from_slice(&data[data.len().saturating_sub(fingerprint_size)..]);
+        self.fingerprint.drain(..self.fingerprint.len().saturating_sub(fingerprint_size));
+        self.pending.extend_from_slice(&data);
+        
+        let lines = match self.pending.iter().rposition(|&b| b == b'\n') {
+            Some(end) => {
+                let rest = self.pending.split_off(end + 1);
+                Bytes::from(std::mem::replace(&mut self.pending, rest))
+            }
+            None => Bytes::new(),
+        };
+        Ok(Some(lines))
+    }
+    
+    /// Take the trailing line that has no newline yet
+    fn take_pending(&mut self) -> Option<Bytes> {
+        (!self.pending.is_empty()).then(|| Bytes::from(std::mem::take(&mut self.pending)))
+    }
+    
+    /// Whether `path` now names a different file than the one being read (log rotation)
+    async fn is_replaced(&self, path: &Path) -> bool {
+        let (Ok(current), Ok(open)) = (fs::metadata(path).await, self.file.metadata().await) else {
+            // Keep reading the open file until a replacement appears
+            return false;
+        };
+        file_identity(&current) != file_identity(&open)
+    }
+}
+
+/// Identity of a file that survives renames, where the platform has one
+#[cfg(unix)]
+fn file_identity(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Identity of a file that survives renames, where the platform has one
+#[cfg(not(unix))]
+fn file_identity(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Find the offset where the last `lines` lines of a file start
+///
+/// A newline at the very end terminates the last line rather than starting a new one.
+async fn last_lines_offset(file: &mut fs::File, len: u64, lines: u64) -> Result<u64> {
+    use tokio::io::{AsyncSeekExt, SeekFrom};
+    
+    if lines == 0 {
+        return Ok(len);
+    }
+    
+    let mut newlines = 0;
+    let mut end = len;
+    let mut buffer = vec![0u8; 8 * 1024];
+    while end > 0 {
+        let start = end.saturating_sub(buffer.len() as u64);
+        let block = &mut buffer[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start)).await
+            .context("Failed to seek in file")?;
+        file.read_exact(block).await
+            .context("Failed to read file")?;
+        
+        for (i, &byte) in block.iter().enumerate().rev() {
+            let position = start + i as u64;
+            if byte == b'\n' && position != len - 1 {
+                newlines += 1;
+                if newlines == lines {
+                    return Ok(position + 1);
+                }
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Most plaintext a decompressing file get keeps, whatever the memory budget
+const MAX_DECOMPRESSED_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Plaintext read from a decompressor at a time
+const DECOMPRESS_READ_SIZE: usize = 64 * 1024;
+
+/// Decompressed file content larger than [`MAX_DECOMPRESSED_SIZE`]
+#[derive(Debug, Clone, Copy)]
+struct DecompressedTooLarge;
+
+impl std::fmt::Display for DecompressedTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Decompressed content exceeds the {} byte limit", MAX_DECOMPRESSED_SIZE)
+    }
+}
+
+impl std::error::Error for DecompressedTooLarge {}
+
+/// Read a file through a decompressor, keeping only `range` of the plaintext
+///
+/// The plaintext kept is reserved from `budget` as it grows, since a small
+/// file can decompress to far more than its own size.
+fn read_decompressed(
+    path: &Path,
+    compression: Compression,
+    range: Option<(u64, u64)>,
+    budget: Option<&MemoryBudget>,
+) -> Result<Bytes> {
+    use std::io::Read;
+    
+    let file = std::io::BufReader::new(std::fs::File::open(path).context("Failed to open file")?);
+    let mut decoder: Box<dyn Read> = match compression {
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(file)
+            .context("Failed to start zstd decoder")?),
+    };
+    
+    let kept = match range {
+        Some((start, end)) => {
+            std::io::copy(&mut (&mut decoder).take(start), &mut std::io::sink())
+                .with_context(|| format!("Failed to decompress {:?} file", compression))?;
+            end.saturating_sub(start)
+        }
+        None => u64::MAX,
+    };
+    let mut decoder = decoder.take(kept);
+    
+    let mut reservation = budget.map(MemoryBudget::reservation);
+    let mut content = Vec::new();
+    let mut chunk = vec![0u8; DECOMPRESS_READ_SIZE];
+    loop {
+        let read = match decoder.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to decompress {:?} file", compression)),
+        };
+        let size = content.len() as u64 + read as u64;
+        if size > MAX_DECOMPRESSED_SIZE {
+            return Err(DecompressedTooLarge.into());
+        }
+        if let (Some(budget), Some(reservation)) = (budget, &mut reservation) {
+            if !reservation.try_grow(read as u64) {
+                return Err(OverBudget { requested: size, total: budget.total() }.into());
+            }
+        }
+        content.extend_from_slice(&chunk[..read]);
+    }
+    
+    Ok(Bytes::from(content))
+}
+
+impl FileHandler {
+    /// Create a file handler that leaves created files to the agent's umask
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Mask these permission bits out of files and directories created
+    /// without an explicit mode (Unix only)
+    pub fn with_umask(mut self, umask: u32) -> Self {
+        self.umask = Some(umask);
+        self
+    }
+    
+    /// Record file writes and changes to extended attributes and ownership to `sink`
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = sink;
+        self
+    }
+    
+    /// Reserve file content from `budget` before reading it
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.memory = Some(budget);
+        self
+    }
+    
+    /// Handle file get operation
+    async fn handle_file_get(
+        &self,
+        path: &Path,
+        range: Option<(u64, u64)>,
+        decompress: Option<Compression>,
+    ) -> Result<(Bytes, FileMetadata)> {
+        let (metadata, file_metadata) = file_get_metadata(path).await?;
+        
+        // Decompressed content isn't sized up front, so it is reserved as it is read
+        if let Some(compression) = decompress {
+            let path = path.to_path_buf();
+            let budget = self.memory.clone();
+            let content = tokio::task::spawn_blocking(move || read_decompressed(&path, compression, range, budget.as_deref()))
+                .await
+                .context("Decompression task failed")??;
+            let decompressed_size = range.is_none().then_some(content.len() as u64);
+            return Ok((content, FileMetadata { decompressed_size, ..file_metadata }));
+        }
+        
+        let buffered = match range {
+            Some((start, end)) => end.min(metadata.len()).saturating_sub(start),
+            None => metadata.len(),
+        };
+        let _reservation = reserve_memory(self.memory.as_deref(), buffered).await?;
+        
+        let content = if let Some((start, end)) = range {
+            // Read specific range
+            let mut file = fs::File::open(path).await
+                .context("Failed to open file")?;
+            
+            let file_size = metadata.len();
+            let actual_start = start.min(file_size);
+            let actual_end = end.min(file_size);
+            
+            if actual_start >= actual_end {
+                Bytes::new()
+            } else {
+                use tokio::io::{AsyncSeekExt, SeekFrom};
+                file.seek(SeekFrom::Start(actual_start)).await
+                    .context("Failed to seek in file")?;
+                
+                let read_size = (actual_end - actual_start) as usize;
+                let mut buffer = vec![0u8; read_size];
+                let bytes_read = file.read_exact(&mut buffer).await
+                    .context("Failed to read file range")?;
+                
+                buffer.truncate(bytes_read);
+                Bytes::from(buffer)
+            }
+        } else {
+            // Read entire file
+            let content = fs::read(path).await
+                .context("Failed to read file")?;
+            Bytes::from(content)
+        };
+        
+        Ok((content, file_metadata))
+    }
+    
+    /// Stream a file's content as `FileChunk` responses, returning its metadata
+    ///
+    /// A whole file is read a chunk at a time and transformed as it is read.
+    /// Ranges and decompression are applied first, as for an unchunked get,
+    /// and the result is then transformed and split.
+    async fn stream_file_get(
+        &self,
+        id: Uuid,
+        path: &Path,
+        range: Option<(u64, u64)>,
+        decompress: Option<Compression>,
+        chunks: ChunkedGet<'_>,
+        output: &ResponseSink,
+    ) -> Result<FileMetadata> {
+        let ChunkedGet { chunk_size, transforms } = chunks;
+        if range.is_some() || decompress.is_some() {
+            let (content, metadata) = self.handle_file_get(path, range, decompress).await?;
+            let content = Bytes::from(transform_content(transforms, &content)?);
+            send_chunks(id, &content, chunk_size, output).await;
+            return Ok(metadata);
+        }
+        
+        let (_, file_metadata) = file_get_metadata(path).await?;
+        let _reservation = reserve_memory(self.memory.as_deref(), chunk_size as u64).await?;
+        let mut file = fs::File::open(path).await
+            .context("Failed to open file")?;
+        if !transforms.is_empty() {
+            let mut transformer = ContentTransformer::new(transforms);
+            let mut buffer = vec![0u8; chunk_size];
+            loop {
+                let read = file.read(&mut buffer).await.context("Failed to read file")?;
+                if read == 0 {
+                    break;
+                }
+                if !send_chunks(id, &transformer.push(&buffer[..read])?, chunk_size, output).await {
+                    return Ok(file_metadata);
+                }
+            }
+            send_chunks(id, &transformer.finish()?, chunk_size, output).await;
+            return Ok(file_metadata);
+        }
+        loop {
+            let mut buffer = vec![0u8; chunk_size];
+            let mut filled = 0;
+            while filled < chunk_size {
+                match file.read(&mut buffer[filled..]).await.context("Failed to read file")? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if filled == 0 {
+                break;
+            }
+            buffer.truncate(filled);
+            if !output.send_data(Response::FileChunk { request_id: id, data: Bytes::from(buffer) }, filled).await || filled < chunk_size {
+                break;
+            }
+        }
+        Ok(file_metadata)
+    }
+    
+    /// Stream the end of a file as `FileChunk` responses, returning the offset reached
+    ///
+    /// With `follow`, the file is polled for appended lines until the client ends
+    /// the stream. A replaced file (rotation) is read from its start once the old
+    /// one is exhausted.
+    async fn handle_file_tail(
+        &self,
+        id: Uuid,
+        path: &Path,
+        from_end_lines: u64,
+        follow: bool,
+        stream: RequestStream,
+    ) -> Result<u64> {
+        let RequestStream { mut input, output } = stream;
+        let mut tail = LineTail::open(path, from_end_lines).await?;
+        
+        loop {
+            while let Some(data) = tail.read_lines().await? {
+                if !data.is_empty() && !output.send(Response::FileChunk { request_id: id, data }) {
+                    return Ok(tail.offset);
+                }
+            }
+            if !follow {
+                break;
+            }
+            
+            if tail.is_replaced(path).await {
+                debug!("File {:?} was replaced, following the new one", path);
+                if let Some(data) = tail.take_pending() {
+                    output.send(Response::FileChunk { request_id: id, data });
+                }
+                tail = LineTail::open(path, 0).await?;
+                tail.rewind();
+                continue;
+            }
+            
+            let ended = async {
+                match input.as_mut() {
+                    // Data from the client is ignored; only the end of its stream matters
+                    Some(input) => while input.recv().await.is_some() {},
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = ended => {
+                    debug!("Client ended tail of {:?}", path);
+                    break;
+                }
+                _ = tokio::time::sleep(TAIL_POLL_INTERVAL) => {}
+            }
+        }
+        
+        if let Some(data) = tail.take_pending() {
+            output.send(Response::FileChunk { request_id: id, data });
+        }
+        Ok(tail.offset)
+    }
+    
+    /// Bring a file to the given content, mode and owner, returning what had to change
+    async fn handle_file_ensure(
+        &self,
+        path: &Path,
+        content: &Bytes,
+        mode: Option<u32>,
+        owner: Option<FileOwner>,
+    ) -> Result<Vec<FileChange>> {
+        use sha2::{Digest, Sha256};
+        
+        let existing_path = path.to_path_buf();
+        let existing = tokio::task::spawn_blocking(move || hash_file(&existing_path, HashAlgorithm::Sha256)).await
+            .context("Failed to hash existing file")?;
+        let up_to_date = match existing {
+            Ok((hash, size)) => size == content.len() as u64 && hash == format!("{:x}", Sha256::digest(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e).context("Failed to read existing file"),
+        };
+        
+        if up_to_date {
+            let path = path.to_path_buf();
+            return tokio::task::spawn_blocking(move || ensure_mode_and_owner(&path, mode, owner.as_ref())).await
+                .context("Failed to set file attributes")?
+                .context("Failed to set file attributes");
+        }
+        
+        // The new content takes over the existing file's attributes, then the
+        // ones asked for, before it replaces the file
+        write_atomic_prepared(path, content, self.umask, move |temp_path, existing| {
+            if let Some(existing) = &existing {
+                copy_mode_and_owner(temp_path, existing)?;
+            }
+            let mut changes = vec![FileChange::Content];
+            changes.extend(ensure_mode_and_owner(temp_path, mode, owner.as_ref())?);
+            Ok(changes)
+        }).await
+            .context("Failed to write file")
+    }
+    
+    /// Replace `path` with the file `ops` rebuild from it, returning the new size
+    ///
+    /// Like [`handle_file_ensure`](Self::handle_file_ensure), the new file
+    /// takes over the existing one's attributes, then the ones asked for,
+    /// before it replaces the file.
+    async fn handle_file_delta_put(
+        &self,
+        path: &Path,
+        block_size: u32,
+        base_size: u64,
+        ops: Vec<DeltaOp>,
+        mode: Option<u32>,
+        modified: Option<u64>,
+    ) -> std::io::Result<u64> {
+        let base_path = path.to_path_buf();
+        let write = |temp_path: PathBuf| async move {
+            tokio::task::spawn_blocking(move || {
+                let base = std::io::BufReader::new(std::fs::File::open(&base_path)?);
+                let file = std::fs::OpenOptions::new().write(true).create_new(true).open(&temp_path)?;
+                let mut output = std::io::BufWriter::new(file);
+                let written = delta::apply(base, base_size, block_size, &ops, &mut output)?;
+                output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+                Ok(written)
+            }).await
+                .map_err(std::io::Error::other)?
+        };
+        let prepare = move |temp_path: &Path, existing: Option<std::fs::Metadata>| {
+            if let Some(existing) = &existing {
+                copy_mode_and_owner(temp_path, existing)?;
+            }
+            set_attributes(temp_path, mode, modified).map(|_| ())
+        };
+        let (written, ()) = write_atomic_with(path, write, prepare, &Placement::Replace).await?;
+        Ok(written)
+    }
+    
+    /// Put a file and audit the write, answering with its result
+    async fn put_file(&self, id: Uuid, path: &Path, content: PutContent<'_>, options: PutOptions) -> Response {
+        let result = self.handle_file_put(path, content, options).await;
+        let outcome = match &result {
+            Ok(_) => AuditResult::Succeeded,
+            Err(e) => AuditResult::Failed { error: e.to_string() },
+        };
+        self.audit.record(&AuditRecord::new(
+            audit::agent_principal(),
+            AuditOperation::FileWrite { path: path.to_path_buf() },
+            outcome,
+        ));
+        
+        match result {
+            Ok(put) => Response::FilePutResult {
+                request_id: id,
+                bytes_written: put.bytes_written,
+                skipped: put.skipped,
+                backup_path: put.backup_path,
+            },
+            Err(e) => {
+                error!("File put error: {:#}", e);
+                Response::error(
+                    id,
+                    ErrorDetails::new(file_write_error_code(&e), format!("File put failed: {:#}", e))
+                )
+            }
+        }
+    }
+    
+    
+    /// Handle file put operation
+    ///
+    /// The file ends up with all of `content` or, if the put fails, not at all;
+    /// an atomic put leaves an existing file unchanged instead.
+    async fn handle_file_put(&self, path: &Path, content: PutContent<'_>, options: PutOptions) -> Result<PutOutcome> {
+        let PutOptions { mode: _mode, create_dirs, umask, modified, atomic, transforms, on_exists } = options;
+        let umask = umask.or(self.umask);
+        
+        // Checked up front so content isn't written for nothing; the write
+        // itself then fails rather than replace a file that appears meanwhile
+        let exists = fs::symlink_metadata(path).await.is_ok();
+        let already_exists = || std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{:?} already exists", path));
+        let skipped = || {
+            debug!("Leaving existing {:?} as it is", path);
+            Ok(PutOutcome { skipped: true, ..PutOutcome::default() })
+        };
+        let placement = match on_exists {
+            OnExists::Overwrite => Placement::Replace,
+            OnExists::Fail | OnExists::Skip if !exists => Placement::NoReplace,
+            OnExists::Fail => return Err(already_exists().into()),
+            OnExists::Skip => return skipped(),
+            OnExists::Backup if !exists => Placement::Replace,
+            OnExists::Backup => {
+                let mut backup = path.as_os_str().to_owned();
+                backup.push(".bak");
+                Placement::Backup(PathBuf::from(backup))
+            }
+        };
+        
+        let transformed;
+        let content = match content {
+            PutContent::Whole(content) if !transforms.is_empty() => {
+                transformed = Bytes::from(transform_content(&transforms, content)?);
+                PutContent::Whole(&transformed)
+            }
+            content => content,
+        };
+        
+        // Create parent directories if requested
+        if create_dirs {
+            if let Some(parent) = path.parent() {
+                create_dir_all_masked(parent, umask).await
+                    .context("Failed to create parent directories")?;
+            }
+        }
+        
+        // Write file content; written in place, the existing file is moved aside first
+        if let (false, Placement::Backup(backup)) = (atomic, &placement) {
+            move_to_backup(path, backup).await
+                .context("Failed to back up existing file")?;
+        }
+        let exclusive = matches!(placement, Placement::NoReplace);
+        let written = match content {
+            PutContent::Whole(content) if atomic => {
+                write_atomic(path, content, umask, &placement).await.map(|()| content.len() as u64)
+            }
+            PutContent::Whole(content) => {
+                write_masked(path, content, umask, false, exclusive).await.map(|()| content.len() as u64)
+            }
+            PutContent::Streamed(input) if atomic => write_atomic_streamed(path, input, &transforms, umask, &placement).await,
+            PutContent::Streamed(input) => write_streamed(path, input, &transforms, umask, false, exclusive).await,
+        };
+        let bytes_written = match written {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && on_exists == OnExists::Skip => return skipped(),
+            written => written.context("Failed to write file")?,
+        };
+        
+        // Set the modification time before a read-only mode could get in the way
+        if let Some(modified) = modified {
+            let file = fs::OpenOptions::new().write(true).open(path).await
+                .context("Failed to open file to set its modification time")?
+                .into_std().await;
+            let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(modified);
+            tokio::task::spawn_blocking(move || file.set_modified(time)).await
+                .context("Failed to set file modification time")?
+                .context("Failed to set file modification time")?;
+        }
+        
+        // Set file permissions if specified (Unix-like systems)
+        #[cfg(unix)]
+        if let Some(mode) = _mode {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(mode);
+            fs::set_permissions(path, permissions).await
+                .context("Failed to set file permissions")?;
+        }
+        
+        let backup_path = match placement {
+            Placement::Backup(backup) => Some(backup),
+            _ => None,
+        };
+        Ok(PutOutcome { bytes_written, skipped: false, backup_path })
+    }
+    
+    /// Apply a unified diff to `path`, returning the new size and the backup path
+    ///
+    /// The result is written to a temporary file beside the original and
+    /// renamed over it, so the original is left intact if anything fails.
+    async fn handle_file_patch(
+        &self,
+        path: &Path,
+        patch: &str,
+        create_backup: bool,
+    ) -> std::result::Result<(u64, Option<PathBuf>), ErrorDetails> {
+        let io_error = |action: &str, e: std::io::Error| {
+            let code = match e.kind() {
+                std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+                std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                _ => ErrorCode::InternalError,
+            };
+            ErrorDetails::new(code, format!("Failed to {} {:?}: {}", action, path, e))
+        };
+        
+        let original = fs::read_to_string(path).await
+            .map_err(|e| io_error("read", e))?;
+        let patch = diffy::Patch::from_str(patch)
+            .map_err(|e| ErrorDetails::new(ErrorCode::InvalidRequest, format!("Invalid patch: {}", e)))?;
+        let patched = diffy::apply(&original, &patch)
+            .map_err(|e| ErrorDetails::new(ErrorCode::PatchFailed, format!("Patch does not apply to {:?}: {}", path, e)))?;
+        
+        let backup_path = if create_backup {
+            let mut backup = path.as_os_str().to_owned();
+            backup.push(".bak");
+            let backup = PathBuf::from(backup);
+            fs::copy(path, &backup).await
+                .map_err(|e| io_error("back up", e))?;
+            Some(backup)
+        } else {
+            None
+        };
+        
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(".{}.{}.patch", file_name, Uuid::new_v4()));
+        let written = async {
+            fs::write(&temp_path, patched.as_bytes()).await?;
+            let permissions = fs::metadata(path).await?.permissions();
+            fs::set_permissions(&temp_path, permissions).await?;
+            fs::rename(&temp_path, path).await
+        }.await;
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(io_error("write", e));
+        }
+        
+        Ok((patched.len() as u64, backup_path))
+    }
+    
+    /// Handle directory listing operation
+    ///
+    /// Descends at most `max_depth` levels of subdirectories, or without limit if `None`.
+    async fn handle_dir_list(&self, path: &Path, include_hidden: bool, max_depth: Option<usize>) -> Result<Vec<DirEntry>> {
+        let (tx, mut rx) = mpsc::channel(DIR_WALK_BUFFER);
+        let walk = walk_dir(path.to_path_buf(), include_hidden, max_depth, tx, CancellationToken::new());
+        let collect = async {
+            let mut entries = Vec::new();
+            while let Some(entry) = rx.recv().await {
+                entries.push(entry);
+            }
+            entries
+        };
+        
+        let (walked, entries) = tokio::join!(walk, collect);
+        walked?;
+        Ok(entries)
+    }
+    
+    /// Stream a directory listing as `DirEntries` batches, returning the last batch
+    ///
+    /// At most [`DIR_LIST_WINDOW`] batches are sent ahead of the client's
+    /// acknowledgements, so a slow client holds back the listing rather than
+    /// letting responses pile up. Ending the request stream cancels it.
+    async fn stream_dir_list(
+        &self,
+        id: Uuid,
+        path: &Path,
+        include_hidden: bool,
+        max_depth: Option<usize>,
+        batch_size: usize,
+        stream: RequestStream,
+    ) -> Result<Vec<DirEntry>> {
+        let RequestStream { mut input, output } = stream;
+        let cancel = CancellationToken::new();
+        let (tx, mut rx) = mpsc::channel(DIR_WALK_BUFFER);
+        let walk = walk_dir(path.to_path_buf(), include_hidden, max_depth, tx, cancel.clone());
+        let send = async {
+            let mut credits = DIR_LIST_WINDOW;
+            let mut batch = Vec::with_capacity(batch_size);
+            while let Some(entry) = rx.recv().await {
+                batch.push(entry);
+                if batch.len() < batch_size {
+                    continue;
+                }
+                
+                if let Some(input) = input.as_mut() {
+                    while credits == 0 {
+                        if input.recv().await.is_none() {
+                            debug!("Directory listing cancelled by the client");
+                            cancel.cancel();
+                            return Vec::new();
+                        }
+                        credits += 1;
+                    }
+                    credits -= 1;
+                }
+                let entries = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                if !output.send(Response::DirEntries { request_id: id, entries }) {
+                    cancel.cancel();
+                    return Vec::new();
+                }
+            }
+            batch
+        };
+        
+        let (walked, batch) = tokio::join!(walk, send);
+        walked?;
+        Ok(batch)
+    }
+}
+
+/// Handler for PTY process execution with privilege escalation
+#[derive(Debug, Clone)]
+pub struct PtyHandler {
+    /// Where privilege-escalated commands are recorded
+    audit: Arc<dyn AuditSink>,
+    /// Limit each command takes a slot of while it runs
+    process_limit: Option<Arc<ProcessLimit>>,
+}
+
+impl Default for PtyHandler {
+    fn default() -> Self {
+        Self { audit: audit::default_sink(), process_limit: None }
+    }
+}
+
+/// The client end of an interactive PTY
+struct Terminal {
+    /// Encoded [`PtyInput`]s from the client
+    input: StreamInput,
+    /// Sink for the terminal's output
+    output: ResponseSink,
+    /// Initial window size
+    window: PtySize,
+}
+
+#[async_trait]
+impl Handler for PtyHandler {
+    async fn handle(&self, request: Request) -> Result<Response> {
+        self.execute(request, None).await
+    }
+    
+    async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+        self.execute(request, Some(stream)).await
+    }
+}
+
+impl PtyHandler {
+    /// Create a PTY handler that doesn't audit privileged commands
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Record privilege-escalated commands to `sink`
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = sink;
+        self
+    }
+    
+    /// Take a slot of `limit` for each command, as [`ProcessHandler::with_process_limit`]
+    pub fn with_process_limit(mut self, limit: Arc<ProcessLimit>) -> Self {
+        self.process_limit = Some(limit);
+        self
+    }
+    
+    /// Run a PTY command, interactively if it asks to be and the client keeps its stream open
+    async fn execute(&self, request: Request, stream: Option<RequestStream>) -> Result<Response> {
+        match request {
+            Request::PtyExec { id, command, mut env, cwd, privilege, timeout, interactive, window, term } => {
+                debug!("Executing PTY process: {:?} (interactive: {})", command, interactive);
+                if let Some(term) = term {
+                    env.insert("TERM".to_string(), term);
+                }
+                
+                // Privileged requests are audited however they end, refused ones included
+                let principal = privilege.as_ref().map(|priv_config| {
+                    priv_config.credentials.as_ref()
+                        .and_then(|creds| creds.username.clone())
+                        .unwrap_or_else(|| "root".to_string())
+                });
+                let mut final_command = command.clone();
+                let response = 'run: {
+                    if command.is_empty() {
+                        break 'run Response::error(
+                            id,
+                            ErrorDetails::new(ErrorCode::InvalidRequest, "Empty command")
+                        );
+                    }
+                    let terminal = match stream {
+                        Some(RequestStream { input: Some(input), output }) if interactive => {
+                            Some(Terminal { input, output, window: window.unwrap_or_default() })
+                        }
+                        _ if interactive => {
+                            break 'run Response::error(
+                                id,
+                                ErrorDetails::new(ErrorCode::InvalidRequest, "Interactive PTY requests need a client stream")
+                            );
+                        }
+                        _ => None,
+                    };
+                    
+                    // The command holds a slot of the limit until it exits
+                    let _slot = match take_process_slot(self.process_limit.as_deref()) {
+                        Ok(slot) => slot,
+                        Err(e) => break 'run Response::error(id, e.into()),
+                    };
+                    
+                    // Build the command with privilege escalation if needed
+                    if let Some(priv_config) = &privilege {
+                        match self.build_privileged_command(&command, priv_config) {
+                            Ok(escalated) => final_command = escalated,
+                            Err(e) => break 'run Response::error(
+                                id,
+                                ErrorDetails::new(ErrorCode::InvalidRequest, format!("Failed to build privileged command: {:#}", e))
+                            ),
+                        }
+                    }
+                    
+                    match terminal {
+                        Some(terminal) => self.run_interactive(id, &final_command, env, cwd, timeout, terminal).await,
+                        None => self.run_pty_command(id, &final_command, env, cwd, timeout).await,
+                    }
+                };
+                let Some(principal) = principal else {
+                    return Ok(response);
+                };
+                let outcome = match &response {
+                    Response::PtyResult { exit_code, .. } => AuditResult::Exited { exit_code: *exit_code },
+                    Response::Error { error, .. } => AuditResult::Failed { error: error.message.clone() },
+                    _ => AuditResult::Succeeded,
+                };
+                self.audit.record(&AuditRecord::new(
+                    principal,
+                    AuditOperation::PrivilegedExec { command: final_command },
+                    outcome,
+                ));
+                Ok(response)
+            }
+            _ => Ok(Response::error(
+                request.id(),
+                ErrorDetails::new(ErrorCode::Unsupported, "PtyHandler only handles PtyExec requests")
+            ))
+        }
+    }
+    
+    /// Run a command to completion, combining its output
+    async fn run_pty_command(
+        &self,
+        id: Uuid,
+        final_command: &[String],
+        env: HashMap<String, String>,
+        cwd: Option<PathBuf>,
+        timeout: Option<u64>,
+    ) -> Response {
+        let start_time = std::time::Instant::now();
+        
+        // For now, we'll use regular process execution as PTY requires platform-specific code
+        // In a full implementation, this would use pty crates like `portable-pty`
+        let mut cmd = Command::new(&final_command[0]);
+        if final_command.len() > 1 {
+            cmd.args(&final_command[1..]);
+        }
+        
+        // Set environment variables
+        for (key, value) in env {
+            cmd.env(key, value);
+        }
+        
+        // Set working directory
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
+        
+        // Configure stdio - for PTY we would typically use pty, but for now use pipes
+        cmd.stdin(Stdio::piped())
+           .stdout(Stdio::piped())
+           .stderr(Stdio::piped())
+           .kill_on_drop(true);
+        
+        // Execute the process
+        let output = if let Some(timeout_secs) = timeout {
+            let timeout_duration = std::time::Duration::from_secs(timeout_secs);
+            
+            match tokio::time::timeout(timeout_duration, cmd.output()).await {
+                Ok(Ok(output)) => output,
+                Ok(Err(e)) => {
+                    return Response::error(
+                        id,
+                        ErrorDetails::new(ErrorCode::ProcessFailed, format!("Process error: {}", e))
+                    );
+                }
+                Err(_) => {
+                    return Response::error(
+                        id,
+                        ErrorDetails::new(ErrorCode::Timeout, "Process execution timed out")
+                    );
+                }
+            }
+        } else {
+            match cmd.output().await {
+                Ok(output) => output,
+                Err(e) => {
+                    return Response::error(
+                        id,
+                        ErrorDetails::new(ErrorCode::ProcessFailed, format!("Process error: {}", e))
+                    );
+                }
+            }
+        };
+        
+        let duration = start_time.elapsed();
+        
+        // Combine stdout and stderr for PTY-like behavior
+        let mut combined_output = output.stdout;
+        combined_output.extend_from_slice(&output.stderr);
+        
+        Response::PtyResult {
+            request_id: id,
+            exit_code: output.status.code().unwrap_or(-1),
+            output: Bytes::from(combined_output),
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+    
+    /// Run a command on a pseudoterminal, writing the client's input to it and streaming back its output
+    #[cfg(unix)]
+    async fn run_interactive(
+        &self,
+        id: Uuid,
+        final_command: &[String],
+        env: HashMap<String, String>,
+        cwd: Option<PathBuf>,
+        timeout: Option<u64>,
+        terminal: Terminal,
+    ) -> Response {
+        let start_time = std::time::Instant::now();
+        let Terminal { mut input, output, window } = terminal;
+        
+        let (mut child, master) = match spawn_on_pty(final_command, env, cwd, window) {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                return Response::error(
+                    id,
+                    ErrorDetails::new(ErrorCode::ProcessFailed, format!("Process error: {}", e))
+                );
+            }
+        };
+        let master = match tokio::io::unix::AsyncFd::new(master) {
+            Ok(master) => Arc::new(master),
+            Err(e) => {
+                let _ = child.kill().await;
+                return Response::error(
+                    id,
+                    ErrorDetails::new(ErrorCode::InternalError, format!("Failed to open terminal: {}", e))
+                );
+            }
+        };
+        let mut relay = tokio::spawn(relay_terminal_output(id, Arc::clone(&master), output));
+        
+        let run = async {
+            let mut input_open = true;
+            loop {
+                tokio::select! {
+                    status = child.wait() => break status,
+                    payload = input.recv(), if input_open => match payload {
+                        Some(payload) => write_terminal_input(&master, &payload).await,
+                        None => input_open = false,
+                    },
+                }
+            }
+        };
+        let status = match timeout {
+            Some(timeout_secs) => tokio::time::timeout(std::time::Duration::from_secs(timeout_secs), run).await,
+            None => Ok(run.await),
+        };
+        let status = match status {
+            Ok(Ok(status)) => status,
+            Ok(Err(e)) => {
+                relay.abort();
+                return Response::error(
+                    id,
+                    ErrorDetails::new(ErrorCode::ProcessFailed, format!("Process error: {}", e))
+                );
+            }
+            Err(_) => {
+                let _ = child.kill().await;
+                relay.abort();
+                return Response::error(
+                    id,
+                    ErrorDetails::new(ErrorCode::Timeout, "Process execution timed out")
+                );
+            }
+        };
+        
+        // The terminal reads end once every process holding it has exited; one
+        // left running in the background shouldn't hold up the result
+        if tokio::time::timeout(TIMEOUT_DRAIN_GRACE, &mut relay).await.is_err() {
+            relay.abort();
+        }
+        
+        Response::PtyResult {
+            request_id: id,
+            exit_code: status.code().unwrap_or(-1),
+            output: Bytes::new(),
+            duration_ms: start_time.elapsed().as_millis() as u64,
+        }
+    }
+    
+    /// Interactive PTYs are only supported on Unix
+    #[cfg(not(unix))]
+    async fn run_interactive(
+        &self,
+        id: Uuid,
+        _final_command: &[String],
+        _env: HashMap<String, String>,
+        _cwd: Option<PathBuf>,
+        _timeout: Option<u64>,
+        _terminal: Terminal,
+    ) -> Response {
+        Response::error(
+            id,
+            ErrorDetails::new(ErrorCode::Unsupported, "Interactive PTYs are not supported on this platform")
+        )
+    }
+    
+    /// Build a command with privilege escalation
+    fn build_privileged_command(
+        &self,
+        command: &[String],
+        privilege: &mitoxide_proto::message::PrivilegeEscalation,
+    ) -> Result<Vec<String>> {
+        let mut privileged_command = Vec::new();
+        
+        match &privilege.method {
+            PrivilegeMethod::Sudo => {
+                privileged_command.push("sudo".to_string());
+                privileged_command.push("-S".to_string()); // Read password from stdin
+                if let Some(ref creds) = privilege.credentials {
+                    if let Some(ref username) = creds.username {
+                        privileged_command.push("-u".to_string());
+                        privileged_command.push(username.clone());
+                    }
+                }
+                privileged_command.extend_from_slice(command);
+            }
+            PrivilegeMethod::Su => {
+                privileged_command.push("su".to_string());
+                if let Some(ref creds) = privilege.credentials {
+                    if let Some(ref username) = creds.username {
+                        privileged_command.push(username.clone());
+                    }
+                }
+                privileged_command.push("-c".to_string());
+                privileged_command.push(command.join(" "));
+            }
+            PrivilegeMethod::Doas => {
+                privileged_command.push("doas".to_string());
+                if let Some(ref creds) = privilege.credentials {
+                    if let Some(ref username) = creds.username {
+                        privileged_command.push("-u".to_string());
+                        privileged_command.push(username.clone());
+                    }
+                }
+                privileged_command.extend_from_slice(command);
+            }
+            PrivilegeMethod::Custom(cmd) => {
+                privileged_command.push(cmd.clone());
+                privileged_command.extend_from_slice(command);
+            }
+        }
+        
+        Ok(privileged_command)
+    }
+}
+
+/// Open a new pseudoterminal, returning its master side, non-blocking, and its slave side
+///
+/// Both are opened close-on-exec, so a command spawned meanwhile on another
+/// thread can't inherit them and hold the terminal open.
+#[cfg(unix)]
+fn open_pty() -> std::io::Result<(std::os::fd::OwnedFd, std::os::fd::OwnedFd)> {
+    use rustix::fs::{Mode, OFlags};
+    use rustix::pty::OpenptFlags;
+    
+    let master = rustix::pty::openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY | OpenptFlags::CLOEXEC)?;
+    rustix::pty::grantpt(&master)?;
+    rustix::pty::unlockpt(&master)?;
+    let name = rustix::pty::ptsname(&master, Vec::new())?;
+    let slave = rustix::fs::open(name.as_c_str(), OFlags::RDWR | OFlags::NOCTTY | OFlags::CLOEXEC, Mode::empty())?;
+    rustix::io::ioctl_fionbio(&master, true)?;
+    Ok((master, slave))
+}
+
+/// Start a command as the session leader of a new pseudoterminal, returning it and the terminal's master side
+#[cfg(unix)]
+fn spawn_on_pty(
+    command: &[String],
+    env: HashMap<String, String>,
+    cwd: Option<PathBuf>,
+    window: PtySize,
+) -> std::io::Result<(Child, std::os::fd::OwnedFd)> {
+    use std::os::fd::BorrowedFd;
+    
+    let (master, slave) = open_pty()?;
+    resize_terminal(&master, window)?;
+    
+    let mut cmd = Command::new(&command[0]);
+    cmd.args(&command[1..])
+        .envs(env)
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave))
+        .kill_on_drop(true);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    
+    // SAFETY: the hook only calls setsid and ioctl, which are async-signal-safe
+    // and do not allocate between fork and exec; stdin is the terminal by then
+    unsafe {
+        cmd.pre_exec(|| {
+            nix::unistd::setsid()?;
+            rustix::process::ioctl_tiocsctty(BorrowedFd::borrow_raw(0))?;
+            Ok(())
+        });
+    }
+    
+    // The command holds the only copies of the terminal's slave side, so they close once it starts
+    let child = cmd.spawn()?;
+    Ok((child, master))
+}
+
+/// Set the window size of a pseudoterminal; its foreground processes get `SIGWINCH`
+#[cfg(unix)]
+fn resize_terminal(master: &std::os::fd::OwnedFd, size: PtySize) -> std::io::Result<()> {
+    let winsize = rustix::termios::Winsize { ws_row: size.rows, ws_col: size.cols, ws_xpixel: 0, ws_ypixel: 0 };
+    rustix::termios::tcsetwinsize(master, winsize)?;
+    Ok(())
+}
+
+/// Master side of a pseudoterminal, read and written as the terminal is ready
+#[cfg(unix)]
+type PtyMaster = tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>;
+
+/// Write all of `data` to a terminal, waiting whenever its input buffer is full
+#[cfg(unix)]
+async fn write_terminal(master: &PtyMaster, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        let mut ready = master.writable().await?;
+        if let Ok(written) = ready.try_io(|fd| Ok(rustix::io::write(fd.get_ref(), data)?)) {
+            data = &data[written?..];
+        }
+    }
+    Ok(())
+}
+
+/// Read what a terminal has written, waiting until it writes something
+#[cfg(unix)]
+async fn read_terminal(master: &PtyMaster, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        let mut ready = master.readable().await?;
+        if let Ok(read) = ready.try_io(|fd| Ok(rustix::io::read(fd.get_ref(), &mut *buf)?)) {
+            return read;
+        }
+    }
+}
+
+/// Apply one frame of client input to an interactive PTY
+#[cfg(unix)]
+async fn write_terminal_input(master: &PtyMaster, payload: &[u8]) {
+    match PtyInput::from_bytes(payload) {
+        Ok(PtyInput::Data(data)) => {
+            if let Err(e) = write_terminal(master, &data).await {
+                debug!("Failed to write to terminal: {}", e);
+            }
+        }
+        Ok(PtyInput::Resize(size)) => {
+            debug!("Resizing terminal to {}x{}", size.cols, size.rows);
+            if let Err(e) = resize_terminal(master.get_ref(), size) {
+                warn!("Failed to resize terminal: {}", e);
+            }
+        }
+        Err(e) => warn!("Ignoring malformed terminal input: {}", e),
+    }
+}
+
+/// Stream what an interactive PTY writes until the terminal closes
+#[cfg(unix)]
+async fn relay_terminal_output(id: Uuid, master: Arc<PtyMaster>, output: ResponseSink) {
+    let mut buf = vec![0u8; 8192];
+    loop {
+        // Reading the master side fails with EIO rather than returning EOF once the terminal closes
+        match read_terminal(&master, &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                let chunk = Response::ProcessOutput {
+                    request_id: id,
+                    stream: OutputStream::Stdout,
+                    data: Bytes::copy_from_slice(&buf[..n]),
+                };
+                if !output.send(chunk) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Longest a `TcpConnect` waits for its target to accept the connection
+const TUNNEL_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Most bytes read from a tunnelled connection at a time
+const TUNNEL_READ_SIZE: usize = 64 * 1024;
+
+/// Handler for `TcpConnect` tunnels to the addresses it allows
+///
+/// A new handler refuses every tunnel; [`TunnelHandler::allow`] adds the
+/// addresses tunnels may reach. The host is matched as the client gave it,
+/// before it is resolved.
+#[derive(Debug, Clone, Default)]
+pub struct TunnelHandler {
+    /// Addresses tunnels may connect to
+    allow: Vec<HostPattern>,
+}
+
+impl TunnelHandler {
+    /// Create a tunnel handler that allows no addresses
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Allow tunnels to the hosts and ports `pattern` matches, as a [`HostPattern`]
+    ///
+    /// `db.internal:5432` allows one port of one host, `*.internal` any port
+    /// of its subdomains.
+    pub fn allow(mut self, pattern: impl Into<String>) -> Self {
+        self.allow.push(HostPattern::new(pattern));
+        self
+    }
+    
+    /// Connect to an allowed target and relay the client's stream to it until the target closes
+    async fn tunnel(&self, id: Uuid, host: &str, port: u16, input: StreamInput, output: ResponseSink) -> Response {
+        if !self.allow.iter().any(|pattern| pattern.matches(host, port)) {
+            warn!("Refusing tunnel to {}:{}, which is not allowed", host, port);
+            return Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::PermissionDenied, format!("Tunnels to {}:{} are not allowed", host, port))
+            );
+        }
+        
+        let connecting = TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), port));
+        let socket = match tokio::time::timeout(TUNNEL_CONNECT_TIMEOUT, connecting).await {
+            Ok(Ok(socket)) => socket,
+            Ok(Err(e)) => {
+                let code = match e.kind() {
+                    std::io::ErrorKind::ConnectionRefused => ErrorCode::ConnectionRefused,
+                    std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                    std::io::ErrorKind::TimedOut => ErrorCode::Timeout,
+                    _ => ErrorCode::InternalError,
+                };
+                return Response::error(id, ErrorDetails::new(code, format!("Failed to connect to {}:{}: {}", host, port, e)));
+            }
+            Err(_) => {
+                return Response::error(
+                    id,
+                    ErrorDetails::new(ErrorCode::Timeout, format!("Timed out connecting to {}:{}", host, port))
+                );
+            }
+        };
+        let peer = socket.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| format!("{}:{}", host, port));
+        debug!("Tunnel {} connected to {}", id, peer);
+        output.send(Response::TunnelOpened { request_id: id, peer });
+        
+        let (bytes_sent, bytes_received) = relay_tunnel(id, socket, input, &output).await;
+        debug!("Tunnel {} closed after sending {} and receiving {} bytes", id, bytes_sent, bytes_received);
+        Response::TunnelClosed { request_id: id, bytes_sent, bytes_received }
+    }
+}
+
+#[async_trait]
+impl Handler for TunnelHandler {
+    async fn handle(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::TcpConnect { id, .. } => Ok(Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::InvalidRequest, "Tunnels need a client stream")
+            )),
+            _ => Ok(Response::error(
+                request.id(),
+                ErrorDetails::new(ErrorCode::Unsupported, "TunnelHandler only handles TcpConnect requests")
+            ))
+        }
+    }
+    
+    async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+        let (Request::TcpConnect { id, host, port }, Some(input)) = (&request, stream.input) else {
+            return self.handle(request).await;
+        };
+        Ok(self.tunnel(*id, host, *port, input, stream.output).await)
+    }
+}
+
+/// Relay between the client's stream and a tunnel's connection until the target closes it
+///
+/// The end of the client's stream shuts down the sending side of the
+/// connection. Data read from the target is only read as fast as the client
+/// returns credit for it. Returns the bytes written to and read from the
+/// target; client data arriving once writes fail is dropped.
+async fn relay_tunnel(id: Uuid, socket: TcpStream, mut input: StreamInput, output: &ResponseSink) -> (u64, u64) {
+    let (mut reader, mut writer) = socket.into_split();
+    let mut sent = 0;
+    let received = {
+        let upstream = async {
+            let mut broken = false;
+            while let Some(data) = input.recv().await {
+                if broken {
+                    continue;
+                }
+                match writer.write_all(&data).await {
+                    Ok(()) => sent += data.len() as u64,
+                    Err(e) => {
+                        debug!("Failed to write to tunnel {}: {}", id, e);
+                        broken = true;
+                    }
+                }
+            }
+            if let Err(e) = writer.shutdown().await {
+                debug!("Failed to shut down tunnel {}: {}", id, e);
+            }
+        };
+        let downstream = async {
+            let mut received = 0;
+            let mut buf = vec![0u8; TUNNEL_READ_SIZE];
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        received += n as u64;
+                        let chunk = Response::TunnelData { request_id: id, data: Bytes::copy_from_slice(&buf[..n]) };
+                        if !output.send_data(chunk, n).await {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        debug!("Failed to read from tunnel {}: {}", id, e);
+                        break;
+                    }
+                }
+            }
+            received
+        };
+        tokio::pin!(upstream, downstream);
+        
+        // The relay ends with the target's side; the client's may end first
+        let mut upstream_done = false;
+        loop {
+            tokio::select! {
+                _ = &mut upstream, if !upstream_done => upstream_done = true,
+                received = &mut downstream => break received,
+            }
+        }
+    };
+    (sent, received)
+}
+
+/// Handler answering `Version` with the agent's build information
+pub struct VersionHandler;
+
+#[async_trait]
+impl Handler for VersionHandler {
+    async fn handle(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::Version { id } => Ok(Response::Version { request_id: id, build: crate::version::build_info() }),
+            _ => Ok(Response::error(
+                request.id(),
+                ErrorDetails::new(ErrorCode::Unsupported, "VersionHandler only handles Version requests")
+            ))
+        }
+    }
+}
+
+/// Largest probe payload a `PingBatch` may ask for
+const MAX_PING_PAYLOAD: u32 = 64 * 1024;
+
+/// Handler for ping requests
+pub struct PingHandler;
+
+#[async_trait]
+impl Handler for PingHandler {
+    async fn handle(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::Ping { id, timestamp } => {
+                debug!("Handling ping request: id={}, timestamp={}", id, timestamp);
+                Ok(Response::pong(id, timestamp))
+            }
+            Request::PingBatch { id, .. } => Ok(Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::InvalidRequest, "Ping batches need a client stream")
+            )),
+            _ => Ok(Response::error(
+                request.id(),
+                ErrorDetails::new(ErrorCode::Unsupported, "PingHandler only handles Ping requests")
+            ))
+        }
+    }
+    
+    async fn handle_stream(&self, request: Request, stream: RequestStream) -> Result<Response> {
+        let (Request::PingBatch { id, count, payload_size, .. }, Some(mut input)) = (&request, stream.input) else {
+            return self.handle(request).await;
+        };
+        let id = *id;
+        if *payload_size > MAX_PING_PAYLOAD {
+            return Ok(Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::InvalidRequest, format!("Probe payloads are limited to {} bytes", MAX_PING_PAYLOAD))
+            ));
+        }
+        debug!("Echoing {} ping probes", count);
+        
+        let mut echoed = 0;
+        while echoed < *count {
+            let Some(payload) = input.recv().await else {
+                break;
+            };
+            match PingProbe::from_bytes(&payload) {
+                Ok(probe) => {
+                    if !stream.output.send(Response::probe_pong(id, probe)) {
+                        break;
+                    }
+                    echoed += 1;
+                }
+                Err(e) => warn!("Ignoring malformed ping probe: {}", e),
+            }
+        }
+        
+        Ok(Response::PingBatchResult { request_id: id, echoed })
+    }
+}
+
+/// Request types the WASM handler is registered for
+pub const WASM_REQUEST_TYPES: [&str; 4] = ["wasm_exec", "wasm_upload", "wasm_pipeline", "wasm_inspect"];
+
+/// Default time a partial module upload is kept without another chunk arriving
+pub const DEFAULT_UPLOAD_TTL: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// A module upload still waiting for chunks
+struct PendingUpload {
+    /// Bytes received so far
+    data: Vec<u8>,
+    /// Size the upload declared
+    total: u64,
+    /// Memory held for the whole module until the upload ends
+    _reservation: Option<MemoryReservation>,
+    /// When the last chunk arrived
+    updated: std::time::Instant,
+}
+
+/// Handler for WASM module execution
+pub struct WasmHandler {
+    /// WASM runtime for executing modules
+    runtime: Arc<mitoxide_wasm::WasmRuntime>,
+    /// Module cache for hash-based caching
+    module_cache: Arc<tokio::sync::RwLock<HashMap<String, mitoxide_wasm::WasmModule>>>,
+    /// Partially uploaded modules by upload ID
+    uploads: Arc<tokio::sync::Mutex<HashMap<Uuid, PendingUpload>>>,
+    /// How long a partial upload is kept without another chunk arriving
+    upload_ttl: std::time::Duration,
+    /// Budget a module's memory limit is reserved from while it runs, and
+    /// an upload's declared size while it is received
+    memory: Option<Arc<MemoryBudget>>,
+}
+
+impl WasmHandler {
+    /// Create a new WASM handler
+    pub fn new() -> Result<Self> {
+        let runtime = Arc::new(mitoxide_wasm::WasmRuntime::new()
+            .map_err(|e| anyhow::anyhow!("Failed to create WASM runtime: {}", e))?);
+        
+        let module_cache = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+        
+        Ok(WasmHandler {
+            runtime,
+            module_cache,
+            uploads: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            upload_ttl: DEFAULT_UPLOAD_TTL,
+            memory: None,
+        })
+    }
+    
+    /// Create a new WASM handler with custom configuration
+    pub fn with_config(config: mitoxide_wasm::WasmConfig) -> Result<Self> {
+        let runtime = mitoxide_wasm::WasmRuntime::with_config(config)
+            .map_err(|e| anyhow::anyhow!("Failed to create WASM runtime: {}", e))?;
+        Ok(Self::with_runtime(runtime))
+    }
+    
+    /// Create a WASM handler running modules in `runtime`
+    pub fn with_runtime(runtime: mitoxide_wasm::WasmRuntime) -> Self {
+        WasmHandler {
+            runtime: Arc::new(runtime),
+            module_cache: Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+            uploads: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            upload_ttl: DEFAULT_UPLOAD_TTL,
+            memory: None,
+        }
+    }
+    
+    /// Reserve each module's memory limit from `budget` while it runs, and
+    /// each upload's declared size while it is received
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.memory = Some(budget);
+        self
+    }
+    
+    /// Drop partial uploads no chunk has arrived for in `ttl`
+    pub fn with_upload_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.upload_ttl = ttl;
+        self
+    }
+    
+    /// Get or load a WASM module from cache
+    async fn get_or_load_module(&self, module_bytes: &[u8]) -> Result<mitoxide_wasm::WasmModule> {
+        // Create module to get hash
+        let module = mitoxide_wasm::WasmModule::from_bytes_with_config(module_bytes.to_vec(), self.runtime.config())
+            .map_err(|e| anyhow::anyhow!("Failed to load WASM module: {}", e))?;
+        
+        let module_hash = module.hash().to_string();
+        
+        // Check cache first
+        {
+            let cache = self.module_cache.read().await;
+            if let Some(cached_module) = cache.get(&module_hash) {
+                debug!("Using cached WASM module: {}", module_hash);
+                return Ok(cached_module.clone());
+            }
+        }
+        
+        // Module not in cache, add it
+        {
+            let mut cache = self.module_cache.write().await;
+            debug!("Caching WASM module: {}", module_hash);
+            cache.insert(module_hash, module.clone());
+        }
+        
+        Ok(module)
+    }
+    
+    /// Look up a module stored by an earlier upload or execution
+    async fn stored_module(&self, hash: &str) -> Option<mitoxide_wasm::WasmModule> {
+        self.module_cache.read().await.get(hash).cloned()
+    }
+    
+    /// Append an upload chunk, storing the module once the final chunk arrives
+    ///
+    /// An upload's first chunk reserves its declared size from the memory
+    /// budget, held until the upload ends. Chunks that don't fit the upload
+    /// are rejected before anything is kept, and uploads abandoned for longer
+    /// than the upload TTL are dropped.
+    async fn receive_chunk(&self, id: Uuid, chunk: Bytes, offset: u64, total: u64, eof: bool) -> Response {
+        let invalid = |message: String| Response::error(id, ErrorDetails::new(ErrorCode::InvalidRequest, message));
+        let end = offset.saturating_add(chunk.len() as u64);
+        if end > total {
+            self.uploads.lock().await.remove(&id);
+            return invalid(format!("Upload of {} bytes exceeds declared total of {}", end, total));
+        }
+        
+        let known = {
+            let mut uploads = self.uploads.lock().await;
+            uploads.retain(|upload_id, upload| {
+                let live = upload.updated.elapsed() < self.upload_ttl;
+                if !live {
+                    debug!("Dropping WASM upload {} after {:?} without a chunk", upload_id, self.upload_ttl);
+                }
+                live
+            });
+            uploads.contains_key(&id)
+        };
+        if !known {
+            if offset > 0 {
+                return invalid(format!("Upload offset {} is past the 0 bytes received", offset));
+            }
+            // Reserved without holding the lock, since it may wait for memory
+            let reservation = match reserve_memory(self.memory.as_deref(), total).await {
+                Ok(reservation) => reservation,
+                Err(e) => return Response::error(id, e.into()),
+            };
+            self.uploads.lock().await.entry(id).or_insert_with(|| PendingUpload {
+                data: Vec::new(),
+                total,
+                _reservation: reservation,
+                updated: std::time::Instant::now(),
+            });
+        }
+        
+        let mut uploads = self.uploads.lock().await;
+        let Some(upload) = uploads.get_mut(&id) else {
+            return invalid(format!("Upload {} was dropped while the chunk waited", id));
+        };
+        if upload.total != total {
+            let declared = upload.total;
+            uploads.remove(&id);
+            return invalid(format!("Upload declared {} bytes, then {}", declared, total));
+        }
+        
+        let received = upload.data.len() as u64;
+        if offset > received {
+            return invalid(format!("Upload offset {} is past the {} bytes received", offset, received));
+        }
+        
+        // Resending from an earlier offset replaces everything after it
+        upload.data.truncate(offset as usize);
+        upload.data.extend_from_slice(&chunk);
+        upload.updated = std::time::Instant::now();
+        let received = upload.data.len() as u64;
+        
+        if !eof {
+            return Response::WasmUploaded { request_id: id, received, hash: None };
+        }
+        
+        let Some(upload) = uploads.remove(&id) else {
+            return invalid(format!("Upload {} was dropped while the chunk waited", id));
+        };
+        drop(uploads);
+        
+        if received != total {
+            return invalid(format!("Upload ended after {} of {} bytes", received, total));
+        }
+        
+        let module_bytes = upload.data;
+        match self.get_or_load_module(&module_bytes).await {
+            Ok(module) => {
+                debug!("Stored uploaded WASM module: {}", module.hash());
+                Response::WasmUploaded {
+                    request_id: id,
+                    received,
+                    hash: Some(module.hash().to_string()),
+                }
+            }
+            Err(e) => {
+                error!("Failed to load uploaded WASM module: {}", e);
+                Response::error(
+                    id,
+                    ErrorDetails::new(ErrorCode::WasmFailed, format!("Module loading failed: {}", e))
+                )
+            }
+        }
+    }
+    
+    /// Load a module sent with a request, or look up a stored one when only its hash is given
+    ///
+    /// Failures come back as the error response to send.
+    async fn resolve_module(&self, module: &[u8], module_hash: Option<String>) -> std::result::Result<mitoxide_wasm::WasmModule, ErrorDetails> {
+        match module_hash {
+            Some(hash) if module.is_empty() => self.stored_module(&hash).await.ok_or_else(|| {
+                ErrorDetails::new(ErrorCode::NotFound, format!("No stored WASM module with hash {}", hash))
+            }),
+            module_hash => {
+                let loaded = self.get_or_load_module(module).await.and_then(|module| {
+                    self.verify_module_hash(&module, module_hash.as_deref())?;
+                    Ok(module)
+                });
+                loaded.map_err(|e| {
+                    error!("Failed to load WASM module: {}", e);
+                    ErrorDetails::new(ErrorCode::WasmFailed, format!("Module loading failed: {}", e))
+                })
+            }
+        }
+    }
+    
+    /// Run each stage on the output of the one before, returning the last stage's output
+    ///
+    /// The first stage that fails ends the pipeline, with its index as the
+    /// error's `stage` context.
+    async fn run_pipeline(&self, stages: Vec<PipelineStage>, input: Bytes) -> std::result::Result<Bytes, ErrorDetails> {
+        let mut data = input;
+        for (index, stage) in stages.into_iter().enumerate() {
+            let failed = |error: ErrorDetails| {
+                ErrorDetails::new(error.code, format!("Stage {} failed: {}", index, error.message))
+                    .with_context("stage", index.to_string())
+            };
+            let mut module = self.resolve_module(&stage.module, stage.module_hash).await.map_err(failed)?;
+            let _reservation = reserve_memory(self.memory.as_deref(), self.runtime.config().max_memory).await
+                .map_err(|e| failed(e.into()))?;
+            let output = run_module(&self.runtime, &mut module, &data, mitoxide_wasm::WasmContext::new()).await
+                .map_err(|e| failed(ErrorDetails::new(wasm_error_code(&e), format!("Execution failed: {}", e))))?;
+            data = Bytes::from(output);
+        }
+        Ok(data)
+    }
+    
+    /// Verify module hash if provided
+    fn verify_module_hash(&self, module: &mitoxide_wasm::WasmModule, expected_hash: Option<&str>) -> Result<()> {
+        if let Some(expected) = expected_hash {
+            let actual = module.hash();
+            if actual != expected {
+                return Err(anyhow::anyhow!(
+                    "Module hash mismatch: expected {}, got {}",
+                    expected,
+                    actual
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Handler for WasmHandler {
+    async fn handle(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::WasmUpload { id, chunk, offset, total, eof } => {
+                debug!("Received WASM upload chunk: {} bytes at offset {}", chunk.len(), offset);
+                Ok(self.receive_chunk(id, chunk, offset, total, eof).await)
+            }
+            Request::WasmInspect { id, module, module_hash } => {
+                let wasm_module = match self.resolve_module(&module, module_hash).await {
+                    Ok(module) => module,
+                    Err(error) => return Ok(Response::error(id, error)),
+                };
+                
+                match serde_json::to_vec(&wasm_module.metadata) {
+                    Ok(metadata) => Ok(Response::WasmMetadata { request_id: id, metadata: Bytes::from(metadata) }),
+                    Err(e) => Ok(Response::error(
+                        id,
+                        ErrorDetails::new(ErrorCode::InternalError, format!("Failed to encode module metadata: {}", e))
+                    )),
+                }
+            }
+            Request::WasmExec { id, module, input, timeout: _, module_hash, network_allow } => {
+                debug!("Executing WASM module: {} bytes", module.len());
+                
+                let start_time = std::time::Instant::now();
+                
+                // Load and cache the module, or run a stored one by hash
+                let mut wasm_module = match self.resolve_module(&module, module_hash).await {
+                    Ok(module) => module,
+                    Err(error) => return Ok(Response::error(id, error)),
+                };
+                
+                // The module can grow its memory up to the runtime's limit
+                let _reservation = match reserve_memory(self.memory.as_deref(), self.runtime.config().max_memory).await {
+                    Ok(reservation) => reservation,
+                    Err(e) => return Ok(Response::error(id, e.into())),
+                };
+                
+                // Requests go only to the hosts the client allowed, and only if this agent allows any
+                let mut context = mitoxide_wasm::WasmContext::new();
+                if !network_allow.is_empty() {
+                    let allow = network_allow.into_iter().map(mitoxide_wasm::HostPattern::new).collect();
+                    context = context.grant(mitoxide_wasm::WasmCapability::Network { allow });
+                }
+                let execution_result = run_module(&self.runtime, &mut wasm_module, &input, context).await;
+                let duration = start_time.elapsed();
+                
+                match execution_result {
+                    Ok(output) => {
+                        debug!("WASM execution completed in {:?}", duration);
+                        Ok(Response::WasmResult {
+                            request_id: id,
+                            output: Bytes::from(output),
+                            duration_ms: duration.as_millis() as u64,
+                        })
+                    }
+                    Err(e) => {
+                        error!("WASM execution failed: {}", e);
+                        Ok(Response::error(id, ErrorDetails::new(wasm_error_code(&e), format!("Execution failed: {}", e))))
+                    }
+                }
+            }
+            Request::WasmPipeline { id, stages, input } => {
+                debug!("Executing WASM pipeline of {} stages", stages.len());
+                if stages.is_empty() {
+                    return Ok(Response::error(id, ErrorDetails::new(ErrorCode::InvalidRequest, "Pipeline has no stages")));
+                }
+                
+                let start_time = std::time::Instant::now();
+                match self.run_pipeline(stages, input).await {
+                    Ok(output) => Ok(Response::WasmResult {
+                        request_id: id,
+                        output,
+                        duration_ms: start_time.elapsed().as_millis() as u64,
+                    }),
+                    Err(error) => {
+                        error!("WASM pipeline failed: {}", error.message);
+                        Ok(Response::error(id, error))
+                    }
+                }
+            }
+            _ => Ok(Response::error(
+                request.id(),
+                ErrorDetails::new(
+                    ErrorCode::Unsupported,
+                    "WasmHandler only handles WasmExec, WasmPipeline, WasmUpload and WasmInspect requests"
+                )
+            ))
+        }
+    }
+}
+
+impl Default for WasmHandler {
+    fn default() -> Self {
+        Self::new().expect("Failed to create default WASM handler")
+    }
+}
+
+/// Stands in for the WASM handler when its runtime could not be created
+///
+/// Every WASM request fails with `ErrorCode::WasmFailed` and the reason, so
+/// clients can tell a broken runtime from an agent without WASM support.
+pub struct WasmUnavailableHandler {
+    /// Why the runtime could not be created
+    reason: String,
+}
+
+impl WasmUnavailableHandler {
+    /// Answer WASM requests with `reason`
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self { reason: reason.into() }
+    }
+}
+
+#[async_trait]
+impl Handler for WasmUnavailableHandler {
+    async fn handle(&self, request: Request) -> Result<Response> {
+        Ok(Response::error(
+            request.id(),
+            ErrorDetails::new(ErrorCode::WasmFailed, format!("WASM runtime unavailable: {}", self.reason))
+        ))
+    }
+}
+
+/// Run a module on `input`, as JSON where it parses and as text otherwise
+async fn run_module(
+    runtime: &mitoxide_wasm::WasmRuntime,
+    module: &mut mitoxide_wasm::WasmModule,
+    input: &[u8],
+    context: mitoxide_wasm::WasmContext,
+) -> std::result::Result<String, mitoxide_wasm::WasmError> {
+    if module.is_wasi() {
+        // For WASI modules, convert input to string and execute
+        let input_str = String::from_utf8(input.to_vec())
+            .unwrap_or_else(|_| {
+                // If input is not valid UTF-8, convert to JSON string
+                serde_json::to_string(&input.to_vec()).unwrap_or_default()
+            });
+        
+        return runtime.execute_with_stdio(module, &input_str, context).await;
+    }
+    
+    // For non-WASI modules, try to parse input as JSON and execute
+    match serde_json::from_slice::<serde_json::Value>(input) {
+        Ok(json_input) => {
+            let output = runtime.execute_json::<serde_json::Value, serde_json::Value>(module, &json_input, context).await?;
+            serde_json::to_string(&output)
+                .map_err(|e| mitoxide_wasm::WasmError::Execution(format!("JSON serialization failed: {}", e)))
+        }
+        Err(_) => {
+            // Input is not valid JSON, treat as raw bytes for WASI
+            let input_str = String::from_utf8_lossy(input);
+            runtime.execute_with_stdio(module, &input_str, context).await
+        }
+    }
+}
+
+/// Error code a failed module run is reported with
+fn wasm_error_code(error: &mitoxide_wasm::WasmError) -> ErrorCode {
+    match error {
+        mitoxide_wasm::WasmError::ResourceLimit { kind: mitoxide_wasm::ResourceLimitKind::Timeout } => ErrorCode::Timeout,
+        mitoxide_wasm::WasmError::CapabilityDenied(_) => ErrorCode::PermissionDenied,
+        _ => ErrorCode::WasmFailed,
+    }
+}
+
+/// Handler running WASM modules from a plugin directory as `JsonCall` methods
+///
+/// Each `*.wasm` file in the directory is loaded once, when the handler is
+/// created, and called by its file name without the extension: a call of
+/// method `my_module` runs `my_module.wasm` with the call's params as input
+/// and answers with the module's output as the JSON result.
+pub struct PluginHandler {
+    /// WASM runtime the plugins run in
+    runtime: Arc<mitoxide_wasm::WasmRuntime>,
+    /// Loaded plugins by method name
+    plugins: HashMap<String, mitoxide_wasm::WasmModule>,
+    /// Budget a plugin's memory limit is reserved from while it runs
+    memory: Option<Arc<MemoryBudget>>,
+}
+
+impl PluginHandler {
+    /// Load every `*.wasm` module in `dir`
+    ///
+    /// Fails if the directory can't be read or any module in it is invalid,
+    /// so a broken plugin is noticed when the agent starts.
+    pub fn load_dir(dir: &Path) -> Result<Self> {
+        let runtime = mitoxide_wasm::WasmRuntime::new()
+            .map_err(|e| anyhow::anyhow!("Failed to create WASM runtime: {}", e))?;
+        
+        let mut plugins = HashMap::new();
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read plugin directory {:?}", dir))? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "wasm") || !path.is_file() {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                warn!("Skipping plugin with a non UTF-8 name: {:?}", path);
+                continue;
+            };
+            
+            let bytes = std::fs::read(&path).with_context(|| format!("Failed to read plugin {:?}", path))?;
+            let module = mitoxide_wasm::WasmModule::from_bytes_with_config(bytes, runtime.config())
+                .map_err(|e| anyhow::anyhow!("Failed to load plugin {:?}: {}", path, e))?;
+            debug!("Loaded plugin {} ({})", name, module.hash());
+            plugins.insert(name.to_string(), module);
+        }
+        
+        Ok(Self { runtime: Arc::new(runtime), plugins, memory: None })
+    }
+    
+    /// Reserve each plugin's memory limit from `budget` while it runs
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.memory = Some(budget);
+        self
+    }
+    
+    /// Method names of the loaded plugins, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.plugins.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+#[async_trait]
+impl Handler for PluginHandler {
+    async fn handle(&self, request: Request) -> Result<Response> {
+        let Request::JsonCall { id, method, params } = request else {
+            return Ok(Response::error(
+                request.id(),
+                ErrorDetails::new(ErrorCode::Unsupported, "PluginHandler only handles JsonCall requests")
+            ));
+        };
+        
+        let Some(module) = self.plugins.get(&method) else {
+            return Ok(Response::error(
+                id,
+                ErrorDetails::new(ErrorCode::NotFound, format!("No plugin named {}", method))
+            ));
+        };
+        debug!("Calling plugin {}", method);
+        
+        let _reservation = match reserve_memory(self.memory.as_deref(), self.runtime.config().max_memory).await {
+            Ok(reservation) => reservation,
+            Err(e) => return Ok(Response::error(id, e.into())),
+        };
+        
+        let mut module = module.clone();
+        match run_module(&self.runtime, &mut module, &params, mitoxide_wasm::WasmContext::new()).await {
+            Ok(output) => Ok(Response::JsonResult { request_id: id, result: Bytes::from(output) }),
+            Err(e) => {
+                error!("Plugin {} failed: {}", method, e);
+                Ok(Response::error(id, ErrorDetails::new(wasm_error_code(&e), format!("Plugin {} failed: {}", method, e))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+    use tokio::fs;
+    use uuid::Uuid;
+    
+    #[tokio::test]
+    async fn test_process_handler_echo() {
+        let handler = ProcessHandler::new();
+        
+        // Use platform-appropriate echo command
+        let (command, args) = if cfg!(windows) {
+            ("cmd".to_string(), vec!["/c".to_string(), "echo".to_string(), "hello world".to_string()])
+        } else {
+            ("echo".to_string(), vec!["hello world".to_string()])
+        };
+        
+        let mut full_command = vec![command];
+        full_command.extend(args);
+        
+        let request = Request::process_exec(full_command, HashMap::new(), None, None, Some(10));
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { exit_code, stdout, .. } => {
+                assert_eq!(exit_code, 0);
+                let output = String::from_utf8(stdout.to_vec()).unwrap();
+                assert!(output.contains("hello world"));
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_with_env_vars() {
+        let handler = ProcessHandler::new();
+        
+        let mut env = HashMap::new();
+        env.insert("TEST_VAR".to_string(), "test_value".to_string());
+        
+        // Use platform-appropriate command to echo environment variable
+        let command = if cfg!(windows) {
+            vec!["cmd".to_string(), "/c".to_string(), "echo".to_string(), "%TEST_VAR%".to_string()]
+        } else {
+            vec!["sh".to_string(), "-c".to_string(), "echo $TEST_VAR".to_string()]
+        };
+        
+        let request = Request::process_exec(command, env, None, None, Some(10));
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { exit_code, stdout, .. } => {
+                assert_eq!(exit_code, 0);
+                let output = String::from_utf8(stdout.to_vec()).unwrap();
+                assert!(output.contains("test_value"));
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_with_stdin() {
+        let handler = ProcessHandler::new();
+        
+        let stdin_data = Bytes::from("hello from stdin");
+        
+        // Use platform-appropriate command to read from stdin
+        let command = if cfg!(windows) {
+            // On Windows, we can use 'more' or 'type' to read from stdin
+            vec!["cmd".to_string(), "/c".to_string(), "more".to_string()]
+        } else {
+            vec!["cat".to_string()]
+        };
+        
+        let request = Request::process_exec(command, HashMap::new(), None, Some(stdin_data.clone()), Some(10));
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { exit_code, stdout, .. } => {
+                assert_eq!(exit_code, 0);
+                let output = String::from_utf8(stdout.to_vec()).unwrap();
+                assert!(output.contains("hello from stdin"));
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_with_working_directory() {
+        let handler = ProcessHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        
+        // Use platform-appropriate command to show current directory
+        let command = if cfg!(windows) {
+            vec!["cmd".to_string(), "/c".to_string(), "cd".to_string()]
+        } else {
+            vec!["pwd".to_string()]
+        };
+        
+        let request = Request::process_exec(
+            command,
+            HashMap::new(),
+            Some(temp_dir.path().to_path_buf()),
+            None,
+            Some(10),
+        );
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { exit_code, stdout, .. } => {
+                assert_eq!(exit_code, 0);
+                let output = String::from_utf8(stdout.to_vec()).unwrap();
+                let temp_path_str = temp_dir.path().to_string_lossy();
+                assert!(output.contains(&*temp_path_str));
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_binary_data() {
+        let handler = ProcessHandler::new();
+        
+        // Create binary data (some bytes that are not valid UTF-8)
+        let binary_data = vec![0x01, 0x02, 0xFF, 0xFE, 0xFD];
+        let stdin_data = Bytes::from(binary_data.clone());
+        
+        // Use platform-appropriate command to output binary data
+        let command = if cfg!(windows) {
+            // On Windows, we'll use findstr which can handle binary data better
+            vec!["findstr".to_string(), ".*".to_string()]
+        } else {
+            vec!["cat".to_string()]
+        };
+        
+        let request = Request::process_exec(command, HashMap::new(), None, Some(stdin_data), Some(10));
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { exit_code, stdout, .. } => {
+                assert_eq!(exit_code, 0);
+                // On Windows, binary data handling might be different, so allow empty output
+                if !cfg!(windows) {
+                    // On Unix, cat should echo the binary data
+                    assert!(!stdout.is_empty());
+                }
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_creation() {
+        let handler = WasmHandler::new();
+        assert!(handler.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_simple_execution() {
+        let handler = WasmHandler::new().unwrap();
+        
+        // Create a simple WASM module (minimal valid module)
+        let wasm_bytes = mitoxide_wasm::test_utils::test_modules::minimal_wasm();
+        let input_data = Bytes::from(r#"{"message": "hello"}"#);
+        
+        let request = Request::WasmExec {
+            id: Uuid::new_v4(),
+            module: Bytes::from(wasm_bytes.to_vec()),
+            input: input_data,
+            timeout: Some(10),
+            module_hash: None,
+            network_allow: Vec::new(),
+        };
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::WasmResult { duration_ms, .. } => {
+                // Should complete without error; output might be empty for minimal module
+                assert!(duration_ms > 0);
+            }
+            Response::Error { error, .. } => {
+                // WASM execution might fail for minimal module, which is acceptable
+                assert!(error.code == ErrorCode::WasmFailed);
+            }
+            _ => panic!("Expected WasmResult or Error response"),
+        }
+    }
+    
+    /// A WASI module that writes its stdin to stdout, computing each output byte with `transform`
+    ///
+    /// `transform` is WAT leaving the output byte at index `$i` on the stack;
+    /// the input is at `$in` and is `$len` bytes long.
+    fn wasi_filter(transform: &str) -> Vec<u8> {
+        wat::parse_str(format!(r#"
+            (module
+              (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+              (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (func (export "_start")
+                (local $in i32) (local $out i32) (local $len i32) (local $i i32)
+                (local.set $in (i32.const 1024))
+                (local.set $out (i32.const 8192))
+                (block $read_all
+                  (loop $read
+                    (i32.store (i32.const 0) (i32.add (local.get $in) (local.get $len)))
+                    (i32.store (i32.const 4) (i32.sub (i32.const 4096) (local.get $len)))
+                    (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 16)))
+                    (br_if $read_all (i32.eqz (i32.load (i32.const 16))))
+                    (local.set $len (i32.add (local.get $len) (i32.load (i32.const 16))))
+                    (br $read)))
+                (block $transformed
+                  (loop $each
+                    (br_if $transformed (i32.ge_u (local.get $i) (local.get $len)))
+                    (i32.store8 (i32.add (local.get $out) (local.get $i)) {})
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br $each)))
+                (i32.store (i32.const 0) (local.get $out))
+                (i32.store (i32.const 4) (local.get $len))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 16)))))
+        "#, transform)).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_pipeline_feeds_each_stage_the_last_output() {
+        let byte = "(i32.load8_u (i32.add (local.get $in) (local.get $i)))";
+        let uppercase = wasi_filter(&format!(
+            "(select (i32.sub {byte} (i32.const 32)) {byte} (i32.lt_u (i32.sub {byte} (i32.const 97)) (i32.const 26)))"
+        ));
+        let reverse = wasi_filter(
+            "(i32.load8_u (i32.add (local.get $in) (i32.sub (i32.sub (local.get $len) (local.get $i)) (i32.const 1))))"
+        );
+        let handler = WasmHandler::new().unwrap();
+        
+        // The second stage runs from the module store
+        let upload = Request::WasmUpload {
+            id: Uuid::new_v4(),
+            chunk: Bytes::from(reverse.clone()),
+            offset: 0,
+            total: reverse.len() as u64,
+            eof: true,
+        };
+        let hash = match handler.handle(upload).await.unwrap() {
+            Response::WasmUploaded { hash: Some(hash), .. } => hash,
+            other => panic!("Expected WasmUploaded response, got {:?}", other),
+        };
+        let pipeline = |stages: Vec<PipelineStage>| Request::WasmPipeline {
+            id: Uuid::new_v4(),
+            stages,
+            input: Bytes::from("hello, wasm"),
+        };
+        
+        let request = pipeline(vec![PipelineStage::module(uppercase.clone()), PipelineStage::stored(hash.clone())]);
+        match handler.handle(request).await.unwrap() {
+            Response::WasmResult { output, .. } => assert_eq!(output, "MSAW ,OLLEH"),
+            other => panic!("Expected WasmResult response, got {:?}", other),
+        }
+        
+        // A failing stage is named by its index
+        let request = pipeline(vec![
+            PipelineStage::module(uppercase),
+            PipelineStage::stored("0".repeat(64)),
+            PipelineStage::stored(hash),
+        ]);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => {
+                assert_eq!(error.code, ErrorCode::NotFound);
+                assert_eq!(error.context.get("stage").map(String::as_str), Some("1"));
+                assert!(error.message.starts_with("Stage 1 failed"));
+            }
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        
+        match handler.handle(pipeline(Vec::new())).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::InvalidRequest),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_network_allowlist() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        
+        // Local server counting the requests that reach it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server_hits = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                server_hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").await;
+            }
+        });
+        
+        // A WASI module whose start makes one request and writes nothing
+        let module = |url: &str| {
+            let request = format!("{{\"url\":\"{}\"}}", url);
+            wat::parse_str(format!(r#"
+                (module
+                  (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+                  (import "mitoxide" "http_request" (func $http_request (param i32 i32) (result i32)))
+                  (memory (export "memory") 1)
+                  (data (i32.const 0) "{}")
+                  (func (export "_start")
+                    (drop (call $http_request (i32.const 0) (i32.const {})))))
+            "#, request.replace('"', "\\\""), request.len())).unwrap()
+        };
+        let exec = |url: &str| Request::WasmExec {
+            id: Uuid::new_v4(),
+            module: Bytes::from(module(url)),
+            input: Bytes::from(r#"{"ok":true}"#),
+            timeout: Some(10),
+            module_hash: None,
+            network_allow: vec!["127.0.0.1".to_string()],
+        };
+        let handler = WasmHandler::with_config(mitoxide_wasm::WasmConfig { allow_network: true, ..Default::default() }).unwrap();
+        
+        match handler.handle(exec(&format!("http://127.0.0.1:{}/", port))).await.unwrap() {
+            Response::WasmResult { output, .. } => assert!(output.is_empty(), "{:?}", output),
+            other => panic!("Expected WasmResult, got {:?}", other),
+        }
+        assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 1);
+        
+        match handler.handle(exec(&format!("http://localhost:{}/", port))).await.unwrap() {
+            Response::Error { error, .. } => {
+                assert_eq!(error.code, ErrorCode::PermissionDenied);
+                assert!(error.message.contains("localhost"), "{}", error.message);
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_with_function_module() {
+        let handler = WasmHandler::new().unwrap();
+        
+        // Create a WASM module with a simple function
+        let wasm_bytes = mitoxide_wasm::test_utils::test_modules::simple_function_wasm();
+        let input_data = Bytes::from(r#"{"a": 5, "b": 3}"#);
+        
+        let request = Request::WasmExec {
+            id: Uuid::new_v4(),
+            module: Bytes::from(wasm_bytes.to_vec()),
+            input: input_data,
+            timeout: Some(10),
+            module_hash: None,
+            network_allow: Vec::new(),
+        };
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        // This might fail since the simple function module doesn't have WASI support
+        // but it should handle the error gracefully
+        match response {
+            Response::WasmResult { .. } => {
+                // Success case
+            }
+            Response::Error { error, .. } => {
+                // Expected for non-WASI modules
+                assert!(error.code == ErrorCode::WasmFailed);
+            }
+            _ => panic!("Expected WasmResult or Error response"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_invalid_module() {
+        let handler = WasmHandler::new().unwrap();
+        
+        // Create invalid WASM bytes
+        let invalid_wasm = vec![0xFF, 0xFF, 0xFF, 0xFF];
+        let input_data = Bytes::from("test input");
+        
+        let request = Request::WasmExec {
+            id: Uuid::new_v4(),
+            module: Bytes::from(invalid_wasm),
+            input: input_data,
+            timeout: Some(10),
+            module_hash: None,
+            network_allow: Vec::new(),
+        };
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::Error { error, .. } => {
+                assert!(error.code == ErrorCode::WasmFailed);
+                assert!(error.message.contains("Module loading failed"));
+            }
+            _ => panic!("Expected Error response for invalid WASM"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_module_caching() {
+        let handler = WasmHandler::new().unwrap();
+        
+        let wasm_bytes = mitoxide_wasm::test_utils::test_modules::minimal_wasm();
+        let input_data = Bytes::from("test");
+        
+        // Execute the same module twice
+        for _ in 0..2 {
+            let request = Request::WasmExec {
+                id: Uuid::new_v4(),
+                module: Bytes::from(wasm_bytes.to_vec()),
+                input: input_data.clone(),
+                timeout: Some(10),
+                module_hash: None,
+                network_allow: Vec::new(),
+            };
+            
+            let response = handler.handle(request).await.unwrap();
+            
+            // Should handle both requests (second one should use cached module)
+            match response {
+                Response::WasmResult { .. } | Response::Error { .. } => {
+                    // Both success and error are acceptable for this test
+                }
+                _ => panic!("Expected WasmResult or Error response"),
+            }
+        }
+    }
+    
+    /// Append a custom section of `size` payload bytes to a WASM module
+    fn with_custom_section(module: &[u8], size: usize) -> Vec<u8> {
+        fn leb128(mut value: usize, out: &mut Vec<u8>) {
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                if value == 0 {
+                    out.push(byte);
+                    break;
+                }
+                out.push(byte | 0x80);
+            }
+        }
+        
+        let name = b"padding";
+        let mut out = module.to_vec();
+        out.push(0); // custom section id
+        leb128(1 + name.len() + size, &mut out);
+        leb128(name.len(), &mut out);
+        out.extend_from_slice(name);
+        out.resize(out.len() + size, 0xAB);
+        out
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_chunked_upload() {
+        let handler = WasmHandler::new().unwrap();
+        
+        let module = with_custom_section(mitoxide_wasm::test_utils::test_modules::wasi_hello_wasm(), 17 * 1024 * 1024);
+        assert!(module.len() > mitoxide_proto::codec::MAX_FRAME_SIZE);
+        
+        let upload_id = Uuid::new_v4();
+        let chunk_size = 4 * 1024 * 1024;
+        let mut hash = None;
+        for (index, chunk) in module.chunks(chunk_size).enumerate() {
+            let offset = index * chunk_size;
+            let eof = offset + chunk.len() == module.len();
+            let request = Request::WasmUpload {
+                id: upload_id,
+                chunk: Bytes::copy_from_slice(chunk),
+                offset: offset as u64,
+                total: module.len() as u64,
+                eof,
+            };
+            
+            match handler.handle(request).await.unwrap() {
+                Response::WasmUploaded { received, hash: uploaded, .. } => {
+                    assert_eq!(received, (offset + chunk.len()) as u64);
+                    assert_eq!(uploaded.is_some(), eof);
+                    hash = uploaded;
+                }
+                other => panic!("Expected WasmUploaded response, got {:?}", other),
+            }
+        }
+        
+        let hash = hash.unwrap();
+        assert_eq!(hash, mitoxide_wasm::WasmModule::from_bytes(module).unwrap().hash());
+        
+        let request = Request::WasmExec {
+            id: Uuid::new_v4(),
+            module: Bytes::new(),
+            input: Bytes::from("{}"),
+            timeout: Some(10),
+            module_hash: Some(hash),
+            network_allow: Vec::new(),
+        };
+        match handler.handle(request).await.unwrap() {
+            Response::WasmResult { .. } => {}
+            other => panic!("Expected WasmResult response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_upload_resume_and_gaps() {
+        let handler = WasmHandler::new().unwrap();
+        let module = mitoxide_wasm::test_utils::test_modules::wasi_hello_wasm();
+        let upload_id = Uuid::new_v4();
+        let upload = |chunk: &[u8], offset: usize, eof: bool| Request::WasmUpload {
+            id: upload_id,
+            chunk: Bytes::copy_from_slice(chunk),
+            offset: offset as u64,
+            total: module.len() as u64,
+            eof,
+        };
+        
+        let half = module.len() / 2;
+        handler.handle(upload(&module[..half], 0, false)).await.unwrap();
+        
+        // A chunk past what was received leaves a gap and is rejected
+        match handler.handle(upload(&module[half + 1..], half + 1, true)).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::InvalidRequest),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        
+        // Resending from an earlier offset resumes the upload
+        match handler.handle(upload(&module[1..], 1, true)).await.unwrap() {
+            Response::WasmUploaded { received, hash, .. } => {
+                assert_eq!(received, module.len() as u64);
+                assert!(hash.is_some());
+            }
+            other => panic!("Expected WasmUploaded response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_upload_limits() {
+        let budget = Arc::new(MemoryBudget::new(1024 * 1024).with_wait(std::time::Duration::from_millis(50)));
+        let handler = WasmHandler::new().unwrap()
+            .with_memory_budget(budget.clone())
+            .with_upload_ttl(std::time::Duration::from_millis(100));
+        let upload = |id: Uuid, offset: u64, len: usize, total: u64| Request::WasmUpload {
+            id,
+            chunk: Bytes::from(vec![0; len]),
+            offset,
+            total,
+            eof: false,
+        };
+        let error_code = |response: Response| match response {
+            Response::Error { error, .. } => error.code,
+            other => panic!("Expected Error response, got {:?}", other),
+        };
+        
+        // An upload bigger than the budget is refused before any of it is kept
+        let id = Uuid::new_v4();
+        assert_eq!(error_code(handler.handle(upload(id, 0, 1024, 2 * 1024 * 1024)).await.unwrap()), ErrorCode::Overloaded);
+        assert_eq!(error_code(handler.handle(upload(id, 1024, 1024, 2 * 1024 * 1024)).await.unwrap()), ErrorCode::InvalidRequest);
+        
+        // So is a chunk past the declared total
+        assert_eq!(error_code(handler.handle(upload(Uuid::new_v4(), 0, 2048, 1024)).await.unwrap()), ErrorCode::InvalidRequest);
+        assert_eq!(budget.available(), budget.total());
+        
+        // A started upload holds its declared size until it is abandoned for the TTL
+        let id = Uuid::new_v4();
+        assert!(matches!(handler.handle(upload(id, 0, 1024, 512 * 1024)).await.unwrap(), Response::WasmUploaded { .. }));
+        assert_eq!(budget.available(), budget.total() - 512 * 1024);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(error_code(handler.handle(upload(id, 1024, 1024, 512 * 1024)).await.unwrap()), ErrorCode::InvalidRequest);
+        assert_eq!(budget.available(), budget.total());
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_denied_wasi_import() {
+        let config = mitoxide_wasm::WasmConfig {
+            allowed_wasi_imports: Some(["fd_write".to_string()].into_iter().collect()),
+            ..Default::default()
+        };
+        let handler = WasmHandler::with_config(config).unwrap();
+        
+        let request = Request::WasmExec {
+            id: Uuid::new_v4(),
+            module: Bytes::from(mitoxide_wasm::test_utils::test_modules::wasi_hello_wasm().to_vec()),
+            input: Bytes::from("{}"),
+            timeout: Some(10),
+            module_hash: None,
+            network_allow: Vec::new(),
+        };
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => {
+                assert_eq!(error.code, ErrorCode::WasmFailed);
+                assert!(error.message.contains("Capability denied"));
+            }
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_unknown_hash() {
+        let handler = WasmHandler::new().unwrap();
+        
+        let request = Request::WasmExec {
+            id: Uuid::new_v4(),
+            module: Bytes::new(),
+            input: Bytes::from("{}"),
+            timeout: Some(10),
+            module_hash: Some("0".repeat(64)),
+            network_allow: Vec::new(),
+        };
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::NotFound),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_inspect() {
+        let handler = WasmHandler::new().unwrap();
+        let wasm_bytes = mitoxide_wasm::test_utils::test_modules::wasi_hello_wasm();
+        
+        let request = Request::WasmInspect {
+            id: Uuid::new_v4(),
+            module: Bytes::from(wasm_bytes.to_vec()),
+            module_hash: None,
+        };
+        let metadata: mitoxide_wasm::ModuleMetadata = match handler.handle(request).await.unwrap() {
+            Response::WasmMetadata { metadata, .. } => serde_json::from_slice(&metadata).unwrap(),
+            other => panic!("Expected WasmMetadata response, got {:?}", other),
+        };
+        
+        assert!(metadata.is_wasi);
+        assert_eq!(metadata.exports, vec!["_start".to_string(), "memory".to_string()]);
+        assert_eq!(metadata.functions.len(), 1);
+        assert_eq!(metadata.functions[0].name, "_start");
+        let imports: Vec<(&str, &str)> = metadata.imports.iter()
+            .map(|import| (import.module.as_str(), import.name.as_str()))
+            .collect();
+        assert_eq!(imports, vec![
+            ("wasi_snapshot_preview1", "fd_write"),
+            ("wasi_snapshot_preview1", "environ_get"),
+        ]);
+        
+        // Inspecting caches the module, so it can be described again by hash alone
+        let request = Request::WasmInspect {
+            id: Uuid::new_v4(),
+            module: Bytes::new(),
+            module_hash: Some(metadata.hash.clone()),
+        };
+        match handler.handle(request).await.unwrap() {
+            Response::WasmMetadata { metadata: by_hash, .. } => {
+                let by_hash: mitoxide_wasm::ModuleMetadata = serde_json::from_slice(&by_hash).unwrap();
+                assert_eq!(by_hash.hash, metadata.hash);
+            }
+            other => panic!("Expected WasmMetadata response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_wasm_handler_unsupported_request() {
+        let handler = WasmHandler::new().unwrap();
+        
+        let request = Request::Ping {
+            id: Uuid::new_v4(),
+            timestamp: 12345,
+        };
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::Error { error, .. } => {
+                assert!(error.code == ErrorCode::Unsupported);
+            }
+            _ => panic!("Expected Error response for unsupported request"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_timeout() {
+        let handler = ProcessHandler::new();
+        
+        // Use platform-appropriate command that will run for a while
+        let command = if cfg!(windows) {
+            // Use ping with a delay on Windows
+            vec!["ping".to_string(), "-n".to_string(), "10".to_string(), "127.0.0.1".to_string()]
+        } else {
+            vec!["sleep".to_string(), "5".to_string()]
+        };
+        
+        // 1 second timeout
+        let request = Request::process_exec(command, HashMap::new(), None, None, Some(1));
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { timed_out, exit_code, .. } => {
+                assert!(timed_out);
+                assert_ne!(exit_code, 0);
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_expands_request_env() {
+        let handler = ProcessHandler::new();
+        let exec = |command: &[&str], cwd: Option<&str>| {
+            let mut request = Request::process_exec(
+                command.iter().map(|arg| arg.to_string()).collect(),
+                HashMap::from([("GREETING".to_string(), "hello".to_string()), ("DIR".to_string(), "/tmp".to_string())]),
+                cwd.map(PathBuf::from),
+                None,
+                Some(10),
+            );
+            if let Request::ProcessExec { expand_env, .. } = &mut request {
+                *expand_env = true;
+            }
+            request
+        };
+        
+        let request = exec(&["printf", "%s|%s|%s", "${GREETING}", "$${GREETING}", "${NAME:-world}"], None);
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { exit_code, stdout, .. } => {
+                assert_eq!(exit_code, 0);
+                assert_eq!(&stdout[..], b"hello|${GREETING}|world");
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        
+        let request = exec(&["pwd"], Some("${DIR}"));
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { stdout, .. } => assert_eq!(String::from_utf8_lossy(&stdout).trim(), "/tmp"),
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        
+        // Variables come from the request alone, not the agent's environment
+        let request = exec(&["echo", "${PATH}"], None);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => {
+                assert_eq!(error.code, ErrorCode::InvalidRequest);
+                assert!(error.message.contains("PATH"));
+            }
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_reads_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("app.env"), concat!(
+            "# deployment settings\n",
+            "export APP_NAME=\"my \\\"app\\\"\"\n",
+            "GREETING='hello # not a comment'\n",
+            "PORT = 8080  # default\n",
+            "OVERRIDDEN=from-file\n",
+        )).unwrap();
+        std::fs::write(dir.path().join("broken.env"), "GOOD=yes\nthis is not an assignment\n").unwrap();
+        
+        let handler = ProcessHandler::new();
+        let exec = |env_file: EnvFile| {
+            let mut request = Request::process_exec(
+                vec!["sh".to_string(), "-c".to_string(), "printf '%s|' \"$APP_NAME\" \"$GREETING\" \"$PORT\" \"$OVERRIDDEN\" \"$GOOD\"".to_string()],
+                HashMap::from([("OVERRIDDEN".to_string(), "from-request".to_string())]),
+                Some(dir.path().to_path_buf()),
+                None,
+                Some(10),
+            );
+            if let Request::ProcessExec { env_file: file, .. } = &mut request {
+                *file = Some(env_file);
+            }
+            request
+        };
+        
+        // A relative path is read from the working directory, and `env` wins over the file
+        match handler.handle(exec(EnvFile::new("app.env"))).await.unwrap() {
+            Response::ProcessResult { exit_code, stdout, .. } => {
+                assert_eq!(exit_code, 0);
+                assert_eq!(&stdout[..], b"my \"app\"|hello # not a comment|8080|from-request||");
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        
+        match handler.handle(exec(EnvFile::new("broken.env"))).await.unwrap() {
+            Response::Error { error, .. } => {
+                assert_eq!(error.code, ErrorCode::InvalidRequest);
+                assert!(error.message.contains("line 2"), "{}", error.message);
+            }
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        match handler.handle(exec(EnvFile::new("broken.env").skip_malformed())).await.unwrap() {
+            Response::ProcessResult { stdout, .. } => assert_eq!(&stdout[..], b"|||from-request|yes|"),
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        
+        match handler.handle(exec(EnvFile::new("missing.env"))).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::FileNotFound),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_detached_process_keeps_running() {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        
+        let handler = ProcessHandler::new();
+        let detached = |command: &[&str], stdin: Option<Bytes>| {
+            let mut request = Request::process_exec(
+                command.iter().map(|s| s.to_string()).collect(), HashMap::new(), None, stdin, Some(10),
+            );
+            if let Request::ProcessExec { detach, .. } = &mut request {
+                *detach = true;
+            }
+            request
+        };
+        
+        // Answered straight away, well before the process or its timeout ends
+        let response = tokio::time::timeout(std::time::Duration::from_secs(5), handler.handle(detached(&["sleep", "30"], None)))
+            .await.unwrap().unwrap();
+        let pid = match response {
+            Response::ProcessResult { pid: Some(pid), exit_code, termination, .. } => {
+                assert_eq!((exit_code, termination), (0, None));
+                Pid::from_raw(pid as i32)
+            }
+            other => panic!("Expected ProcessResult with a PID, got {:?}", other),
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(kill(pid, None).is_ok(), "detached process is not running");
+        assert_eq!(nix::unistd::getsid(Some(pid)).unwrap(), pid);
+        kill(pid, Signal::SIGKILL).unwrap();
+        
+        match handler.handle(detached(&["cat"], Some(Bytes::from("input")))).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::InvalidRequest),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_detached_processes_count_against_process_limit() {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        
+        let limit = Arc::new(ProcessLimit::new(2));
+        let handler = ProcessHandler::new().with_process_limit(limit.clone());
+        let detached = || {
+            let mut request = Request::process_exec(vec!["sleep".to_string(), "30".to_string()], HashMap::new(), None, None, None);
+            if let Request::ProcessExec { detach, .. } = &mut request {
+                *detach = true;
+            }
+            request
+        };
+        
+        let mut pids = Vec::new();
+        for _ in 0..2 {
+            match handler.handle(detached()).await.unwrap() {
+                Response::ProcessResult { pid: Some(pid), .. } => pids.push(Pid::from_raw(pid as i32)),
+                other => panic!("Expected ProcessResult with a PID, got {:?}", other),
+            }
+        }
+        assert_eq!(limit.available(), 0);
+        
+        // The slots stay taken after the requests have been answered
+        match handler.handle(detached()).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::Overloaded),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        
+        // A detached process frees its slot once it has exited and been reaped
+        kill(pids.pop().unwrap(), Signal::SIGKILL).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while limit.available() == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        match handler.handle(detached()).await.unwrap() {
+            Response::ProcessResult { pid: Some(pid), .. } => pids.push(Pid::from_raw(pid as i32)),
+            other => panic!("Expected ProcessResult with a PID, got {:?}", other),
+        }
+        for pid in pids {
+            kill(pid, Signal::SIGKILL).unwrap();
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_output_redirected_to_files() {
+        let handler = ProcessHandler::new();
+        let dir = tempfile::tempdir().unwrap();
+        let redirected = |script: &str, stdout_file: Option<OutputFile>, stderr_file: Option<OutputFile>, merge: bool| {
+            let mut request = Request::process_exec(
+                vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+                HashMap::new(), Some(dir.path().to_path_buf()), None, Some(10),
+            );
+            if let Request::ProcessExec { stdout_file: out, stderr_file: err, merge_stderr, .. } = &mut request {
+                *out = stdout_file;
+                *err = stderr_file;
+                *merge_stderr = merge;
+            }
+            request
+        };
+        
+        // Relative paths are under the working directory; appending keeps what is there
+        std::fs::write(dir.path().join("out.log"), "old\n").unwrap();
+        let request = redirected(
+            "echo out; echo err >&2",
+            Some(OutputFile::append("out.log")),
+            Some(OutputFile::truncate(dir.path().join("err.log"))),
+            false,
+        );
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { exit_code, stdout, stderr, stdout_written, stderr_written, .. } => {
+                assert_eq!(exit_code, 0);
+                assert!(stdout.is_empty() && stderr.is_empty());
+                assert_eq!((stdout_written, stderr_written), (Some(4), Some(4)));
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        assert_eq!(std::fs::read_to_string(dir.path().join("out.log")).unwrap(), "old\nout\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("err.log")).unwrap(), "err\n");
+        
+        // Merged stderr goes into the stdout file
+        let request = redirected("echo a; echo b >&2", Some(OutputFile::truncate("out.log")), None, true);
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { stdout_written, stderr_written, .. } => {
+                assert_eq!((stdout_written, stderr_written), (Some(4), None));
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        assert_eq!(std::fs::read_to_string(dir.path().join("out.log")).unwrap(), "a\nb\n");
+        
+        let request = redirected("true", None, Some(OutputFile::truncate("err.log")), true);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::InvalidRequest),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        
+        let request = redirected("true", Some(OutputFile::truncate("missing/out.log")), None, false);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::FileNotFound),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_killed_by_signal_reports_signaled() {
+        let handler = ProcessHandler::new();
+        let request = Request::process_exec(
+            vec!["sh".to_string(), "-c".to_string(), "kill -SEGV $$".to_string()],
+            HashMap::new(),
+            None,
+            None,
+            Some(10),
+        );
+        
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { exit_code, termination, timed_out, .. } => {
+                assert!(!timed_out);
+                assert_eq!(exit_code, -1);
+                assert_eq!(termination, Some(Termination::Signaled(11)));
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        
+        let request = Request::process_exec(vec!["sh".to_string(), "-c".to_string(), "exit 3".to_string()], HashMap::new(), None, None, None);
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { termination, .. } => assert_eq!(termination, Some(Termination::Exited(3))),
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_timeout_returns_partial_output() {
+        let handler = ProcessHandler::new();
+        
+        // The shell forks sleep, which keeps the output pipes open after the shell is killed
+        let request = Request::process_exec(
+            vec!["sh".to_string(), "-c".to_string(), "echo started; echo waiting >&2; sleep 30".to_string()],
+            HashMap::new(),
+            None,
+            None,
+            Some(1),
+        );
+        
+        let start = std::time::Instant::now();
+        let response = handler.handle(request).await.unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "took {:?}", start.elapsed());
+        
+        match response {
+            Response::ProcessResult { exit_code, stdout, stderr, timed_out, termination, .. } => {
+                assert!(timed_out);
+                assert_eq!(exit_code, -1);
+                assert_eq!(termination, Some(Termination::Signaled(9)));
+                assert_eq!(&stdout[..], b"started\n");
+                assert_eq!(&stderr[..], b"waiting\n");
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_stderr_capture() {
+        let handler = ProcessHandler::new();
+        
+        // Use platform-appropriate command that writes to stderr
+        let command = if cfg!(windows) {
+            vec!["cmd".to_string(), "/c".to_string(), "echo error message 1>&2".to_string()]
+        } else {
+            vec!["sh".to_string(), "-c".to_string(), "echo 'error message' >&2".to_string()]
+        };
+        
+        let request = Request::process_exec(command, HashMap::new(), None, None, Some(10));
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { exit_code, stderr, .. } => {
+                assert_eq!(exit_code, 0);
+                let error_output = String::from_utf8(stderr.to_vec()).unwrap();
+                assert!(error_output.contains("error message"));
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_empty_command() {
+        let handler = ProcessHandler::new();
+        let request = Request::process_exec(vec![], HashMap::new(), None, None, None);
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::Error { error, .. } => {
+                assert_eq!(error.code, ErrorCode::InvalidRequest);
+            }
+            _ => panic!("Expected Error response"),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_handler_address_space_limit() {
+        let handler = ProcessHandler::new();
+        
+        // Try to hold ~256MB in a shell variable under a 64MB address-space cap
+        let mut request = Request::process_exec(
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "x=$(head -c 268435456 /dev/zero | tr '\\0' a); echo ${#x}".to_string(),
+            ],
+            HashMap::new(),
+            None,
+            None,
+            Some(30),
+        );
+        if let Request::ProcessExec { limits, .. } = &mut request {
+            *limits = Some(ProcessLimits {
+                address_space: Some(64 * 1024 * 1024),
+                ..Default::default()
+            });
+        }
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { exit_code, stdout, timed_out, termination, .. } => {
+                // The allocation fails, so the shell gives up instead of running on
+                assert!(!timed_out);
+                assert_ne!(exit_code, 0);
+                assert_ne!(termination, Some(Termination::Exited(0)));
+                assert!(!String::from_utf8_lossy(&stdout).contains("268435456"));
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_handler_cpu_limit_kills_with_signal() {
+        use nix::sys::signal::Signal;
+        
+        let handler = ProcessHandler::new();
+        let mut request = Request::process_exec(
+            vec!["sh".to_string(), "-c".to_string(), "while :; do :; done".to_string()],
+            HashMap::new(),
+            None,
+            None,
+            Some(30),
+        );
+        if let Request::ProcessExec { limits, .. } = &mut request {
+            *limits = Some(ProcessLimits {
+                cpu_seconds: Some(1),
+                ..Default::default()
+            });
+        }
+        
+        let start = std::time::Instant::now();
+        let response = handler.handle(request).await.unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "took {:?}", start.elapsed());
+        
+        match response {
+            Response::ProcessResult { exit_code, timed_out, termination, .. } => {
+                assert!(!timed_out);
+                assert_eq!(exit_code, -1);
+                assert_eq!(termination, Some(Termination::Signaled(Signal::SIGXCPU as i32)));
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_handler_open_files_limit() {
+        let handler = ProcessHandler::new();
+        let mut request = Request::process_exec(
+            vec!["sh".to_string(), "-c".to_string(), "ulimit -n".to_string()],
+            HashMap::new(),
+            None,
+            None,
+            Some(10),
+        );
+        if let Request::ProcessExec { limits, .. } = &mut request {
+            *limits = Some(ProcessLimits {
+                open_files: Some(32),
+                ..Default::default()
+            });
+        }
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { exit_code, stdout, .. } => {
+                assert_eq!(exit_code, 0);
+                assert_eq!(String::from_utf8_lossy(&stdout).trim(), "32");
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_handler_signal() {
+        let handler = Arc::new(ProcessHandler::new());
+        let request = Request::process_exec(
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "trap 'echo got-usr1; exit 0' USR1; while :; do sleep 0.1; done".to_string(),
+            ],
+            HashMap::new(),
+            None,
+            None,
+            Some(10),
+        );
+        let target = request.id();
+        
+        let task = {
+            let handler = Arc::clone(&handler);
+            tokio::spawn(async move { handler.handle(request).await })
+        };
+        
+        while handler.running_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // Give the shell time to install its trap
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        
+        let response = handler.handle(Request::process_signal(target, "USR1")).await.unwrap();
+        assert!(matches!(response, Response::SignalSent { .. }));
+        
+        match task.await.unwrap().unwrap() {
+            Response::ProcessResult { exit_code, stdout, .. } => {
+                assert_eq!(exit_code, 0);
+                assert!(String::from_utf8_lossy(&stdout).contains("got-usr1"));
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        assert_eq!(handler.running_count(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_signal_unknown_request() {
+        let handler = ProcessHandler::new();
+        let response = handler.handle(Request::process_signal(Uuid::new_v4(), "SIGHUP")).await.unwrap();
+        
+        match response {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::NotFound),
+            _ => panic!("Expected Error response"),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_handler_merge_stderr() {
+        let handler = ProcessHandler::new();
+        let mut request = Request::process_exec(
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "for i in 1 2 3; do echo out$i; echo err$i >&2; done".to_string(),
+            ],
+            HashMap::new(),
+            None,
+            None,
+            Some(10),
+        );
+        if let Request::ProcessExec { merge_stderr, .. } = &mut request {
+            *merge_stderr = true;
+        }
+        
+        let response = handler.handle(request).await.unwrap();
+        
+        match response {
+            Response::ProcessResult { exit_code, stdout, stderr, .. } => {
+                assert_eq!(exit_code, 0);
+                assert_eq!(
+                    String::from_utf8(stdout.to_vec()).unwrap(),
+                    "out1\nerr1\nout2\nerr2\nout3\nerr3\n"
+                );
+                assert!(stderr.is_empty());
+            }
+            _ => panic!("Expected ProcessResult response"),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_pty_is_opened_close_on_exec() {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+        use std::os::fd::AsRawFd;
+        
+        let (master, slave) = open_pty().unwrap();
+        for end in [&master, &slave] {
+            let flags = FdFlag::from_bits_truncate(fcntl(end.as_raw_fd(), FcntlArg::F_GETFD).unwrap());
+            assert!(flags.contains(FdFlag::FD_CLOEXEC));
+        }
+        // Only the master side is read without blocking
+        let status = |fd: &std::os::fd::OwnedFd| rustix::fs::fcntl_getfl(fd).unwrap();
+        assert!(status(&master).contains(rustix::fs::OFlags::NONBLOCK));
+        assert!(!status(&slave).contains(rustix::fs::OFlags::NONBLOCK));
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_output_pipes_are_close_on_exec() {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+        use std::os::fd::AsRawFd;
+        
+        let (reader, writer) = cloexec_pipe().unwrap();
+        for end in [&reader, &writer] {
+            let flags = FdFlag::from_bits_truncate(fcntl(end.as_raw_fd(), FcntlArg::F_GETFD).unwrap());
+            assert!(flags.contains(FdFlag::FD_CLOEXEC));
+        }
+    }
+    
+    /// Build a `ProcessExec` request with a capture limit
+    fn limited_exec(script: &str, max_output_bytes: u64, output_truncation: OutputTruncation, kill: bool) -> Request {
+        let mut request = Request::process_exec(
+            vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            HashMap::new(),
+            None,
+            None,
+            Some(30),
+        );
+        if let Request::ProcessExec { max_output_bytes: max, output_truncation: truncation, kill_on_output_limit, .. } = &mut request {
+            *max = Some(max_output_bytes);
+            *truncation = output_truncation;
+            *kill_on_output_limit = kill;
+        }
+        request
+    }
+    
+    #[tokio::test]
+    async fn test_memory_budget_throttles_large_operations() {
+        let mib = 1024 * 1024;
+        let budget = Arc::new(MemoryBudget::new(4 * mib).with_wait(std::time::Duration::from_millis(100)));
+        let process_handler = Arc::new(ProcessHandler::new().with_memory_budget(budget.clone()));
+        let file_handler = FileHandler::new().with_memory_budget(budget.clone());
+        let sh = |script: &str| Request::process_exec(
+            vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            HashMap::new(),
+            None,
+            None,
+            None,
+        );
+        
+        // Three commands each capture a MiB and hold on to it while they keep running
+        let running: Vec<_> = (0..3)
+            .map(|_| {
+                let handler = Arc::clone(&process_handler);
+                let request = sh("head -c 1048576 /dev/zero; sleep 1");
+                tokio::spawn(async move { handler.handle(request).await.unwrap() })
+            })
+            .collect();
+        while budget.available() > mib {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("large.bin");
+        fs::write(&path, vec![7u8; 2 * mib as usize]).await.unwrap();
+        match file_handler.handle(Request::file_get(path.clone(), None)).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::Overloaded),
+            other => panic!("Expected an Overloaded error, got {:?}", other),
+        }
+        
+        // Smaller reads still fit, and captures stop growing once the budget is gone
+        match file_handler.handle(Request::file_get(path.clone(), Some((0, 1024)))).await.unwrap() {
+            Response::FileContent { content, .. } => assert_eq!(content.len(), 1024),
+            other => panic!("Expected FileContent response, got {:?}", other),
+        }
+        match process_handler.handle(sh("head -c 2097152 /dev/zero")).await.unwrap() {
+            Response::ProcessResult { stdout, truncated, .. } => {
+                assert!(truncated);
+                assert!(stdout.len() as u64 <= mib);
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        
+        for task in running {
+            match task.await.unwrap() {
+                Response::ProcessResult { stdout, truncated, .. } => {
+                    assert!(!truncated);
+                    assert_eq!(stdout.len() as u64, mib);
+                }
+                other => panic!("Expected ProcessResult response, got {:?}", other),
+            }
+        }
+        assert_eq!(budget.available(), budget.total());
+        match file_handler.handle(Request::file_get(path, None)).await.unwrap() {
+            Response::FileContent { content, .. } => assert_eq!(content.len() as u64, 2 * mib),
+            other => panic!("Expected FileContent response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_keeps_head_and_tail() {
+        let handler = ProcessHandler::new();
+        
+        let request = limited_exec("seq 1 100000", 20, OutputTruncation::HeadAndTail, false);
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { stdout, truncated, .. } => {
+                assert!(truncated);
+                assert_eq!(&stdout[..], b"1\n2\n3\n4\n5\n99\n100000\n");
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+        
+        // Output within the limit is kept whole
+        let request = limited_exec("seq 1 5", 20, OutputTruncation::Tail, false);
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { stdout, truncated, .. } => {
+                assert!(!truncated);
+                assert_eq!(&stdout[..], b"1\n2\n3\n4\n5\n");
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_keeps_only_tail() {
+        let handler = ProcessHandler::new();
+        
+        let request = limited_exec("seq 1 1000000; seq 1 1000 >&2", 16, OutputTruncation::Tail, false);
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { exit_code, stdout, stderr, truncated, .. } => {
+                assert_eq!(exit_code, 0);
+                assert!(truncated);
+                assert_eq!(&stdout[..], b"\n999999\n1000000\n");
+                assert_eq!(&stderr[..], b"97\n998\n999\n1000\n");
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_process_handler_kills_on_output_limit() {
+        let handler = ProcessHandler::new();
+        let request = limited_exec("exec yes", 64 * 1024, OutputTruncation::Tail, true);
+        
+        let response = tokio::time::timeout(std::time::Duration::from_secs(10), handler.handle(request))
+            .await
+            .expect("process should be killed at the output limit")
+            .unwrap();
+        match response {
+            Response::ProcessResult { exit_code, stdout, truncated, .. } => {
+                assert_ne!(exit_code, 0);
+                assert!(truncated);
+                assert_eq!(stdout.len(), 64 * 1024);
+                assert!(stdout.starts_with(b"y\n"));
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
+    }
+    
+    /// Build a shell command request given the extra output descriptors `fds`
+    #[cfg(unix)]
+    fn exec_with_fds(script: &str, fds: Vec<u32>) -> Request {
+        let mut request = Request::process_exec(
+            vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            HashMap::new(),
+            None,
+            None,
+            Some(10),
+        );
+        if let Request::ProcessExec { output_fds, .. } = &mut request {
+            *output_fds = fds;
+        }
+        request
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_handler_streams_extra_fd() {
+        let handler = ProcessHandler::new();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut request = exec_with_fds("echo to-fd3 >&3; echo to-stdout", vec![3]);
+        if let Request::ProcessExec { stream_output, .. } = &mut request {
+            *stream_output = true;
+        }
+        
+        let stream = RequestStream { input: None, output: ResponseSink::new(1, tx) };
+        let response = handler.handle_stream(request, stream).await.unwrap();
+        assert!(matches!(response, Response::ProcessResult { exit_code: 0, .. }), "{:?}", response);
+        
+        let mut fd3 = Vec::new();
+        let mut stdout = Vec::new();
+        while let Ok(output) = rx.try_recv() {
+            match output.message {
+                mitoxide_proto::Message::Response(Response::ProcessOutput { stream: OutputStream::Fd(3), data, .. }) => fd3.extend_from_slice(&data),
+                mitoxide_proto::Message::Response(Response::ProcessOutput { stream: OutputStream::Stdout, data, .. }) => stdout.extend_from_slice(&data),
+                other => panic!("Unexpected output {:?}", other),
             }
-            _ => Ok(Response::error(
-                request.id(),
-                ErrorDetails::new(ErrorCode::Unsupported, "WasmHandler only handles WasmExec requests")
-            ))
         }
+        assert_eq!(fd3, b"to-fd3\n");
+        assert_eq!(stdout, b"to-stdout\n");
     }
-}
-
-impl Default for WasmHandler {
-    fn default() -> Self {
-        Self::new().expect("Failed to create default WASM handler")
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::collections::HashMap;
-    use std::path::PathBuf;
-    use tempfile::TempDir;
-    use tokio::fs;
-    use uuid::Uuid;
     
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_process_handler_echo() {
-        let handler = ProcessHandler;
+    async fn test_process_handler_captures_extra_fds() {
+        let handler = ProcessHandler::new();
+        let request = exec_with_fds("printf four >&4; printf three >&3", vec![4, 3]);
         
-        // Use platform-appropriate echo command
-        let (command, args) = if cfg!(windows) {
-            ("cmd".to_string(), vec!["/c".to_string(), "echo".to_string(), "hello world".to_string()])
-        } else {
-            ("echo".to_string(), vec!["hello world".to_string()])
-        };
+        match handler.handle(request).await.unwrap() {
+            Response::ProcessResult { exit_code, fd_output, .. } => {
+                assert_eq!(exit_code, 0);
+                assert_eq!(fd_output[&3], Bytes::from("three"));
+                assert_eq!(fd_output[&4], Bytes::from("four"));
+            }
+            other => panic!("Expected ProcessResult response, got {:?}", other),
+        }
         
-        let mut full_command = vec![command];
-        full_command.extend(args);
+        for fds in [vec![1], vec![3, 3]] {
+            match handler.handle(exec_with_fds("true", fds)).await.unwrap() {
+                Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::InvalidRequest),
+                other => panic!("Expected an error, got {:?}", other),
+            }
+        }
+    }
+    
+    #[test]
+    fn test_line_buffer_splits_lines() {
+        let mut lines = LineBuffer::new(1024);
+        
+        assert!(lines.push(b"partial").is_empty());
+        let chunks = lines.push(b" line\nsecond\nthird");
+        assert_eq!(chunks, vec![Bytes::from("partial line\n"), Bytes::from("second\n")]);
+        assert_eq!(lines.finish(), Some(Bytes::from("third")));
+    }
+    
+    #[test]
+    fn test_line_buffer_max_line_length() {
+        let mut lines = LineBuffer::new(4);
         
-        let request = Request::ProcessExec {
-            id: Uuid::new_v4(),
-            command: full_command,
-            env: HashMap::new(),
-            cwd: None,
-            stdin: None,
-            timeout: Some(10),
-        };
+        let chunks = lines.push(b"abcdefghij\nxy");
+        assert_eq!(chunks, vec![Bytes::from("abcd"), Bytes::from("efgh"), Bytes::from("ij\n")]);
+        assert_eq!(lines.finish(), Some(Bytes::from("xy")));
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_handler_line_buffered_stream() {
+        let handler = ProcessHandler::new();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        
+        let mut request = Request::process_exec(
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "printf 'one\\ntw'; sleep 0.1; printf 'o\\nthree'".to_string(),
+            ],
+            HashMap::new(),
+            None,
+            None,
+            Some(10),
+        );
+        if let Request::ProcessExec { stream_output, line_buffered, .. } = &mut request {
+            *stream_output = true;
+            *line_buffered = true;
+        }
         
-        let response = handler.handle(request).await.unwrap();
+        let stream = RequestStream { input: None, output: ResponseSink::new(1, tx) };
+        let response = handler.handle_stream(request, stream).await.unwrap();
         
         match response {
             Response::ProcessResult { exit_code, stdout, .. } => {
                 assert_eq!(exit_code, 0);
-                let output = String::from_utf8(stdout.to_vec()).unwrap();
-                assert!(output.contains("hello world"));
+                assert!(stdout.is_empty());
             }
-            _ => panic!("Expected ProcessResult response"),
+            other => panic!("Expected ProcessResult response, got {:?}", other),
         }
+        
+        let mut chunks = Vec::new();
+        while let Ok(output) = rx.try_recv() {
+            assert!(!output.last);
+            match output.message {
+                mitoxide_proto::Message::Response(Response::ProcessOutput { stream: OutputStream::Stdout, data, .. }) => chunks.push(data),
+                other => panic!("Expected stdout ProcessOutput, got {:?}", other),
+            }
+        }
+        assert_eq!(chunks, vec![Bytes::from("one\n"), Bytes::from("two\n"), Bytes::from("three")]);
     }
     
     #[tokio::test]
-    async fn test_process_handler_with_env_vars() {
-        let handler = ProcessHandler;
+    async fn test_file_handler_put_get() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("test.txt");
+        let content = Bytes::from("Hello, world!");
         
-        let mut env = HashMap::new();
-        env.insert("TEST_VAR".to_string(), "test_value".to_string());
+        // Test file put
+        let put_request = Request::file_put(file_path.clone(), content.clone(), Some(0o644), true);
         
-        // Use platform-appropriate command to echo environment variable
-        let command = if cfg!(windows) {
-            vec!["cmd".to_string(), "/c".to_string(), "echo".to_string(), "%TEST_VAR%".to_string()]
-        } else {
-            vec!["sh".to_string(), "-c".to_string(), "echo $TEST_VAR".to_string()]
-        };
+        let put_response = handler.handle(put_request).await.unwrap();
+        match put_response {
+            Response::FilePutResult { bytes_written, .. } => {
+                assert_eq!(bytes_written, content.len() as u64);
+            }
+            _ => panic!("Expected FilePutResult response"),
+        }
         
-        let request = Request::ProcessExec {
-            id: Uuid::new_v4(),
-            command,
-            env,
-            cwd: None,
-            stdin: None,
-            timeout: Some(10),
-        };
+        // Test file get
+        let get_request = Request::file_get(file_path, None);
         
-        let response = handler.handle(request).await.unwrap();
+        let get_response = handler.handle(get_request).await.unwrap();
+        match get_response {
+            Response::FileContent { content: retrieved_content, metadata, .. } => {
+                assert_eq!(retrieved_content, content);
+                assert!(!metadata.is_dir);
+                assert_eq!(metadata.size, content.len() as u64);
+            }
+            _ => panic!("Expected FileContent response"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_file_handler_get_nonexistent() {
+        let handler = FileHandler::new();
+        let request = Request::file_get(PathBuf::from("/nonexistent/file.txt"), None);
         
+        let response = handler.handle(request).await.unwrap();
         match response {
-            Response::ProcessResult { exit_code, stdout, .. } => {
-                assert_eq!(exit_code, 0);
-                let output = String::from_utf8(stdout.to_vec()).unwrap();
-                assert!(output.contains("test_value"));
+            Response::Error { error, .. } => {
+                // Print the actual error message for debugging
+                println!("Error message: {}", error.message);
+                // On Windows, the error might be different, so let's be more flexible
+                assert!(matches!(error.code, ErrorCode::FileNotFound | ErrorCode::InternalError));
             }
-            _ => panic!("Expected ProcessResult response"),
+            _ => panic!("Expected Error response"),
         }
     }
     
     #[tokio::test]
-    async fn test_process_handler_with_stdin() {
-        let handler = ProcessHandler;
+    async fn test_file_handler_dir_list() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
         
-        let stdin_data = Bytes::from("hello from stdin");
+        // Create some test files
+        let file1 = temp_dir.path().join("file1.txt");
+        let file2 = temp_dir.path().join("file2.txt");
+        let hidden_file = temp_dir.path().join(".hidden");
         
-        // Use platform-appropriate command to read from stdin
-        let command = if cfg!(windows) {
-            // On Windows, we can use 'more' or 'type' to read from stdin
-            vec!["cmd".to_string(), "/c".to_string(), "more".to_string()]
-        } else {
-            vec!["cat".to_string()]
-        };
+        fs::write(&file1, "content1").await.unwrap();
+        fs::write(&file2, "content2").await.unwrap();
+        fs::write(&hidden_file, "hidden").await.unwrap();
         
-        let request = Request::ProcessExec {
-            id: Uuid::new_v4(),
-            command,
-            env: HashMap::new(),
-            cwd: None,
-            stdin: Some(stdin_data.clone()),
-            timeout: Some(10),
-        };
+        // Test directory listing without hidden files
+        let request = Request::dir_list(temp_dir.path().to_path_buf(), false, false);
         
         let response = handler.handle(request).await.unwrap();
-        
         match response {
-            Response::ProcessResult { exit_code, stdout, .. } => {
-                assert_eq!(exit_code, 0);
-                let output = String::from_utf8(stdout.to_vec()).unwrap();
-                assert!(output.contains("hello from stdin"));
+            Response::DirListing { entries, .. } => {
+                assert_eq!(entries.len(), 2); // Should not include hidden file
+                let names: Vec<_> = entries.iter().map(|e| &e.name).collect();
+                assert!(names.contains(&&"file1.txt".to_string()));
+                assert!(names.contains(&&"file2.txt".to_string()));
+                assert!(!names.contains(&&".hidden".to_string()));
             }
-            _ => panic!("Expected ProcessResult response"),
+            _ => panic!("Expected DirListing response"),
+        }
+        
+        // Test directory listing with hidden files
+        let request_with_hidden = Request::dir_list(temp_dir.path().to_path_buf(), true, false);
+        
+        let response_with_hidden = handler.handle(request_with_hidden).await.unwrap();
+        match response_with_hidden {
+            Response::DirListing { entries, .. } => {
+                assert_eq!(entries.len(), 3); // Should include hidden file
+                let names: Vec<_> = entries.iter().map(|e| &e.name).collect();
+                assert!(names.contains(&&".hidden".to_string()));
+            }
+            _ => panic!("Expected DirListing response"),
+        }
+        
+        // A missing directory is reported as such
+        let missing = Request::dir_list(temp_dir.path().join("missing"), false, false);
+        match handler.handle(missing).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::FileNotFound),
+            other => panic!("Expected Error, got {:?}", other),
         }
     }
     
     #[tokio::test]
-    async fn test_process_handler_with_working_directory() {
-        let handler = ProcessHandler;
+    async fn test_file_handler_dir_list_max_depth() {
+        let handler = FileHandler::new();
         let temp_dir = TempDir::new().unwrap();
         
-        // Use platform-appropriate command to show current directory
-        let command = if cfg!(windows) {
-            vec!["cmd".to_string(), "/c".to_string(), "cd".to_string()]
-        } else {
-            vec!["pwd".to_string()]
-        };
+        // level1/level2/.../level5, with a file at every level
+        let mut dir = temp_dir.path().to_path_buf();
+        fs::write(dir.join("file0.txt"), "0").await.unwrap();
+        for level in 1..=5 {
+            dir = dir.join(format!("level{}", level));
+            fs::create_dir(&dir).await.unwrap();
+            fs::write(dir.join(format!("file{}.txt", level)), "x").await.unwrap();
+        }
         
-        let request = Request::ProcessExec {
-            id: Uuid::new_v4(),
-            command,
-            env: HashMap::new(),
-            cwd: Some(temp_dir.path().to_path_buf()),
-            stdin: None,
-            timeout: Some(10),
+        let names = |entries: &[DirEntry]| {
+            let mut names: Vec<String> = entries.iter()
+                .map(|entry| entry.path.strip_prefix(temp_dir.path()).unwrap().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        let listing = |depth: Option<usize>, batch: Option<u32>| {
+            let mut request = Request::dir_list(temp_dir.path().to_path_buf(), false, true);
+            if let Request::DirList { batch_size, max_depth, .. } = &mut request {
+                *batch_size = batch;
+                *max_depth = depth;
+            }
+            request
         };
         
-        let response = handler.handle(request).await.unwrap();
+        // Two levels of subdirectories are descended into; level3 is a leaf
+        let limited = vec![
+            "file0.txt", "level1", "level1/file1.txt", "level1/level2",
+            "level1/level2/file2.txt", "level1/level2/level3",
+        ];
+        match handler.handle(listing(Some(2), None)).await.unwrap() {
+            Response::DirListing { entries, .. } => assert_eq!(names(&entries), limited),
+            other => panic!("Expected DirListing, got {:?}", other),
+        }
+        
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let stream = RequestStream { input: None, output: ResponseSink::new(1, tx) };
+        match handler.handle_stream(listing(Some(2), Some(100)), stream).await.unwrap() {
+            Response::DirListing { entries, .. } => assert_eq!(names(&entries), limited),
+            other => panic!("Expected DirListing, got {:?}", other),
+        }
+        
+        match handler.handle(listing(Some(0), None)).await.unwrap() {
+            Response::DirListing { entries, .. } => assert_eq!(names(&entries), vec!["file0.txt", "level1"]),
+            other => panic!("Expected DirListing, got {:?}", other),
+        }
+        
+        match handler.handle(listing(None, None)).await.unwrap() {
+            Response::DirListing { entries, .. } => {
+                assert_eq!(entries.len(), 11);
+                assert!(names(&entries).contains(&"level1/level2/level3/level4/level5/file5.txt".to_string()));
+            }
+            other => panic!("Expected DirListing, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_dir_walk_stops_when_cancelled() {
+        let temp_dir = TempDir::new().unwrap();
+        for d in 0..20 {
+            let dir = temp_dir.path().join(format!("dir{}", d));
+            std::fs::create_dir(&dir).unwrap();
+            for f in 0..200 {
+                std::fs::write(dir.join(format!("file{}", f)), "").unwrap();
+            }
+        }
+        let total = 20 * 201;
+        
+        let cancel = CancellationToken::new();
+        let (tx, mut rx) = mpsc::channel(DIR_WALK_BUFFER);
+        let walk = tokio::spawn(walk_dir(temp_dir.path().to_path_buf(), false, None, tx, cancel.clone()));
+        let mut received = 0;
+        while received < 100 {
+            rx.recv().await.unwrap();
+            received += 1;
+        }
+        
+        cancel.cancel();
+        tokio::time::timeout(std::time::Duration::from_secs(1), walk).await
+            .expect("walk kept running after cancellation")
+            .unwrap()
+            .unwrap();
+        
+        // Only entries read ahead before the cancellation are still delivered
+        while rx.recv().await.is_some() {
+            received += 1;
+        }
+        assert!(received <= 100 + DIR_WALK_BUFFER, "{} entries after cancelling", received);
+        assert!(received < total);
+    }
+    
+    #[tokio::test]
+    async fn test_file_handler_recursive_dir_list() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
         
+        // Create nested directory structure
+        let subdir = temp_dir.path().join("subdir");
+        let nested = subdir.join("nested");
+        fs::create_dir_all(&nested).await.unwrap();
+        
+        let file1 = temp_dir.path().join("file1.txt");
+        let file2 = subdir.join("file2.txt");
+        let file3 = subdir.join("file3.txt");
+        
+        fs::write(&file1, "content1").await.unwrap();
+        fs::write(&file2, "content2").await.unwrap();
+        fs::write(&file3, "content3").await.unwrap();
+        fs::write(nested.join("file4.txt"), "content4").await.unwrap();
+        
+        // Test recursive directory listing
+        let request = Request::dir_list(temp_dir.path().to_path_buf(), false, true);
+        
+        let response = handler.handle(request).await.unwrap();
         match response {
-            Response::ProcessResult { exit_code, stdout, .. } => {
-                assert_eq!(exit_code, 0);
-                let output = String::from_utf8(stdout.to_vec()).unwrap();
-                let temp_path_str = temp_dir.path().to_string_lossy();
-                assert!(output.contains(&*temp_path_str));
+            Response::DirListing { entries, .. } => {
+                // Every entry of every level, each listed once
+                assert_eq!(entries.len(), 6);
+                let names: Vec<_> = entries.iter().map(|e| &e.name).collect();
+                assert!(names.contains(&&"file1.txt".to_string()));
+                assert!(names.contains(&&"subdir".to_string()));
+                assert!(names.contains(&&"file2.txt".to_string()));
+                assert!(names.contains(&&"file3.txt".to_string()));
+                assert!(names.contains(&&"file4.txt".to_string()));
             }
-            _ => panic!("Expected ProcessResult response"),
+            _ => panic!("Expected DirListing response"),
         }
     }
     
     #[tokio::test]
-    async fn test_process_handler_binary_data() {
-        let handler = ProcessHandler;
+    async fn test_file_handler_range_get() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("test.txt");
+        let content = "Hello, world! This is a test file with some content.";
         
-        // Create binary data (some bytes that are not valid UTF-8)
-        let binary_data = vec![0x01, 0x02, 0xFF, 0xFE, 0xFD];
-        let stdin_data = Bytes::from(binary_data.clone());
+        // Create test file
+        fs::write(&file_path, content).await.unwrap();
         
-        // Use platform-appropriate command to output binary data
-        let command = if cfg!(windows) {
-            // On Windows, we'll use findstr which can handle binary data better
-            vec!["findstr".to_string(), ".*".to_string()]
-        } else {
-            vec!["cat".to_string()]
-        };
+        // Test range get (bytes 7-12 should be "world")
+        let request = Request::file_get(file_path, Some((7, 12)));
         
-        let request = Request::ProcessExec {
-            id: Uuid::new_v4(),
-            command,
-            env: HashMap::new(),
-            cwd: None,
-            stdin: Some(stdin_data),
-            timeout: Some(10),
-        };
+        let response = handler.handle(request).await.unwrap();
+        match response {
+            Response::FileContent { content: retrieved_content, .. } => {
+                let content_str = String::from_utf8(retrieved_content.to_vec()).unwrap();
+                assert_eq!(content_str, "world");
+            }
+            _ => panic!("Expected FileContent response"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_file_handler_gzip_get() {
+        use std::io::Write;
+        
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("app.log.gz");
+        let plaintext = "line one\nline two\n".repeat(100);
+        
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(plaintext.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        fs::write(&file_path, &compressed).await.unwrap();
+        
+        // Without decompress the compressed bytes come back untouched
+        let request = Request::file_get(file_path.clone(), None);
+        match handler.handle(request).await.unwrap() {
+            Response::FileContent { content, metadata, .. } => {
+                assert_eq!(content, compressed);
+                assert_eq!(metadata.size, compressed.len() as u64);
+                assert_eq!(metadata.decompressed_size, None);
+            }
+            other => panic!("Expected FileContent response, got {:?}", other),
+        }
         
-        let response = handler.handle(request).await.unwrap();
+        let mut request = Request::file_get(file_path.clone(), None);
+        if let Request::FileGet { decompress, .. } = &mut request {
+            *decompress = Some(Compression::Gzip);
+        }
+        match handler.handle(request).await.unwrap() {
+            Response::FileContent { content, metadata, .. } => {
+                assert_eq!(content, plaintext.as_bytes());
+                assert_eq!(metadata.size, compressed.len() as u64);
+                assert_eq!(metadata.decompressed_size, Some(plaintext.len() as u64));
+            }
+            other => panic!("Expected FileContent response, got {:?}", other),
+        }
         
-        match response {
-            Response::ProcessResult { exit_code, stdout, .. } => {
-                assert_eq!(exit_code, 0);
-                // On Windows, binary data handling might be different, so just verify we got some output
-                if cfg!(windows) {
-                    // Just verify we got some response
-                    assert!(!stdout.is_empty() || true); // Allow empty on Windows
-                } else {
-                    // On Unix, cat should echo the binary data
-                    assert!(!stdout.is_empty());
-                }
+        // A range selects from the plaintext
+        let mut request = Request::file_get(file_path, Some((9, 17)));
+        if let Request::FileGet { decompress, .. } = &mut request {
+            *decompress = Some(Compression::Gzip);
+        }
+        match handler.handle(request).await.unwrap() {
+            Response::FileContent { content, metadata, .. } => {
+                assert_eq!(content, "line two".as_bytes());
+                assert_eq!(metadata.decompressed_size, None);
             }
-            _ => panic!("Expected ProcessResult response"),
+            other => panic!("Expected FileContent response, got {:?}", other),
         }
     }
     
     #[tokio::test]
-    async fn test_wasm_handler_creation() {
-        let handler = WasmHandler::new();
-        assert!(handler.is_ok());
+    async fn test_file_handler_zstd_get() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("app.log.zst");
+        let plaintext = "zstd content\n".repeat(50);
+        fs::write(&file_path, zstd::encode_all(plaintext.as_bytes(), 3).unwrap()).await.unwrap();
+        
+        let mut request = Request::file_get(file_path, None);
+        if let Request::FileGet { decompress, .. } = &mut request {
+            *decompress = Some(Compression::Zstd);
+        }
+        match handler.handle(request).await.unwrap() {
+            Response::FileContent { content, .. } => assert_eq!(content, plaintext.as_bytes()),
+            other => panic!("Expected FileContent response, got {:?}", other),
+        }
     }
     
     #[tokio::test]
-    async fn test_wasm_handler_simple_execution() {
-        let handler = WasmHandler::new().unwrap();
-        
-        // Create a simple WASM module (minimal valid module)
-        let wasm_bytes = mitoxide_wasm::test_utils::test_modules::minimal_wasm();
-        let input_data = Bytes::from(r#"{"message": "hello"}"#);
-        
-        let request = Request::WasmExec {
-            id: Uuid::new_v4(),
-            module: Bytes::from(wasm_bytes.to_vec()),
-            input: input_data,
-            timeout: Some(10),
-        };
+    async fn test_file_handler_decompress_reserves_plaintext() {
+        let budget = Arc::new(MemoryBudget::new(1024 * 1024).with_wait(std::time::Duration::from_millis(50)));
+        let handler = FileHandler::new().with_memory_budget(budget.clone());
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("zeros.zst");
+        // A few kilobytes that decompress to more than the whole budget
+        let compressed = zstd::encode_all(&vec![0u8; 4 * 1024 * 1024][..], 3).unwrap();
+        assert!((compressed.len() as u64) < budget.total());
+        fs::write(&file_path, compressed).await.unwrap();
+        
+        let mut request = Request::file_get(file_path.clone(), None);
+        if let Request::FileGet { decompress, .. } = &mut request {
+            *decompress = Some(Compression::Zstd);
+        }
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::Overloaded),
+            other => panic!("Expected an over budget error, got {:?}", other),
+        }
+        assert_eq!(budget.available(), budget.total());
         
-        let response = handler.handle(request).await.unwrap();
+        // A range that fits is reserved as it grows and released afterwards
+        let mut request = Request::file_get(file_path, Some((1024, 512 * 1024)));
+        if let Request::FileGet { decompress, .. } = &mut request {
+            *decompress = Some(Compression::Zstd);
+        }
+        match handler.handle(request).await.unwrap() {
+            Response::FileContent { content, .. } => assert_eq!(content.len(), 511 * 1024),
+            other => panic!("Expected FileContent response, got {:?}", other),
+        }
+        assert_eq!(budget.available(), budget.total());
+    }
+    
+    #[tokio::test]
+    async fn test_file_handler_decompress_invalid_data() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("plain.gz");
+        fs::write(&file_path, "not gzip").await.unwrap();
         
-        match response {
-            Response::WasmResult { output, duration_ms, .. } => {
-                // Should complete without error
-                assert!(duration_ms > 0);
-                // Output might be empty for minimal module
-                assert!(output.len() >= 0);
-            }
-            Response::Error { error, .. } => {
-                // WASM execution might fail for minimal module, which is acceptable
-                assert!(error.code == ErrorCode::WasmFailed);
+        let mut request = Request::file_get(file_path, None);
+        if let Request::FileGet { decompress, .. } = &mut request {
+            *decompress = Some(Compression::Gzip);
+        }
+        assert!(matches!(handler.handle(request).await.unwrap(), Response::Error { .. }));
+    }
+    
+    #[tokio::test]
+    async fn test_file_handler_tail_last_lines() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("tail.txt");
+        fs::write(&file_path, "1\n2\n3\n4").await.unwrap();
+        
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let stream = RequestStream { input: None, output: ResponseSink::new(1, tx) };
+        let request = Request::file_tail(file_path, 2, false);
+        let response = handler.handle_stream(request, stream).await.unwrap();
+        assert!(matches!(response, Response::FileTailEnded { offset: 7, .. }));
+        
+        let mut chunks = Vec::new();
+        while let Ok(output) = rx.try_recv() {
+            match output.message {
+                mitoxide_proto::Message::Response(Response::FileChunk { data, .. }) => chunks.push(data),
+                other => panic!("Expected FileChunk, got {:?}", other),
             }
-            _ => panic!("Expected WasmResult or Error response"),
         }
+        assert_eq!(chunks, vec![Bytes::from("3\n"), Bytes::from("4")]);
     }
     
     #[tokio::test]
-    async fn test_wasm_handler_with_function_module() {
-        let handler = WasmHandler::new().unwrap();
+    async fn test_file_handler_tail_follows_rewritten_file() {
+        /// Next chunk the tail sends
+        async fn chunk(rx: &mut tokio::sync::mpsc::UnboundedReceiver<crate::agent::HandlerOutput>) -> Bytes {
+            let output = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+            match output.message {
+                mitoxide_proto::Message::Response(Response::FileChunk { data, .. }) => data,
+                other => panic!("Expected FileChunk, got {:?}", other),
+            }
+        }
         
-        // Create a WASM module with a simple function
-        let wasm_bytes = mitoxide_wasm::test_utils::test_modules::simple_function_wasm();
-        let input_data = Bytes::from(r#"{"a": 5, "b": 3}"#);
+        let handler = Arc::new(FileHandler::new());
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("follow.log");
+        fs::write(&file_path, "first\n").await.unwrap();
+        
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let (input_tx, input) = mpsc::channel(1);
+        let stream = RequestStream { input: Some(input), output: ResponseSink::new(1, tx) };
+        let request = Request::file_tail(file_path.clone(), 1, true);
+        let tail = tokio::spawn({
+            let handler = handler.clone();
+            async move { handler.handle_stream(request, stream).await.unwrap() }
+        });
+        assert_eq!(chunk(&mut rx).await, Bytes::from("first\n"));
+        
+        // Truncated and written past the old end before the tail looks again
+        let rewritten = "second, longer than the first\n";
+        fs::write(&file_path, rewritten).await.unwrap();
+        assert_eq!(chunk(&mut rx).await, Bytes::from(rewritten));
+        
+        drop(input_tx);
+        match tail.await.unwrap() {
+            Response::FileTailEnded { offset, .. } => assert_eq!(offset, rewritten.len() as u64),
+            other => panic!("Expected FileTailEnded, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_file_handler_chunked_get() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("chunked.bin");
+        fs::write(&file_path, "0123456789").await.unwrap();
+        
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let stream = RequestStream { input: None, output: ResponseSink::new(1, tx) };
+        let request = Request::file_get_chunked(file_path, 4);
+        match handler.handle_stream(request, stream).await.unwrap() {
+            Response::FileContent { content, metadata, .. } => {
+                assert!(content.is_empty());
+                assert_eq!(metadata.size, 10);
+            }
+            other => panic!("Expected FileContent, got {:?}", other),
+        }
         
-        let request = Request::WasmExec {
-            id: Uuid::new_v4(),
-            module: Bytes::from(wasm_bytes.to_vec()),
-            input: input_data,
-            timeout: Some(10),
-        };
+        let mut chunks = Vec::new();
+        while let Ok(output) = rx.try_recv() {
+            match output.message {
+                mitoxide_proto::Message::Response(Response::FileChunk { data, .. }) => chunks.push(data),
+                other => panic!("Expected FileChunk, got {:?}", other),
+            }
+        }
+        assert_eq!(chunks, vec![Bytes::from("0123"), Bytes::from("4567"), Bytes::from("89")]);
+    }
+    
+    #[tokio::test]
+    async fn test_file_put_substitutes_template() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("app.conf");
+        let template = "host = ${DB_HOST}\r\nport = ${DB_PORT:-5432}\r\nurl = postgres://${DB_HOST}/${DB_NAME}\r\ncost = $$5\r\n";
+        let vars = HashMap::from([
+            ("DB_HOST".to_string(), "db.internal".to_string()),
+            ("DB_NAME".to_string(), "orders".to_string()),
+        ]);
+        
+        let mut request = Request::file_put(file_path.clone(), Bytes::from(template), None, false);
+        if let Request::FilePut { transforms, .. } = &mut request {
+            *transforms = vec![ContentTransform::Substitute { vars }, ContentTransform::CrlfToLf];
+        }
+        let expected = "host = db.internal\nport = 5432\nurl = postgres://db.internal/orders\ncost = $5\n";
+        match handler.handle(request).await.unwrap() {
+            Response::FilePutResult { bytes_written, .. } => assert_eq!(bytes_written, expected.len() as u64),
+            other => panic!("Expected FilePutResult, got {:?}", other),
+        }
+        assert_eq!(fs::read_to_string(&file_path).await.unwrap(), expected);
         
-        let response = handler.handle(request).await.unwrap();
+        // A placeholder missing from the map fails the put without writing anything
+        let missing_path = temp_dir.path().join("missing.conf");
+        let mut request = Request::file_put(missing_path.clone(), Bytes::from("user = ${DB_USER}\n"), None, false);
+        if let Request::FilePut { transforms, .. } = &mut request {
+            *transforms = vec![ContentTransform::Substitute { vars: HashMap::new() }];
+        }
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => {
+                assert_eq!(error.code, ErrorCode::InvalidRequest);
+                assert!(error.message.contains("DB_USER"), "{}", error.message);
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert!(!missing_path.exists());
+    }
+    
+    /// Put "new" over a file holding "old" under `on_exists`
+    async fn put_over_existing(on_exists: OnExists, atomic: bool) -> (TempDir, PathBuf, Response) {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("config.toml");
+        fs::write(&file_path, "old").await.unwrap();
         
-        // This might fail since the simple function module doesn't have WASI support
-        // but it should handle the error gracefully
+        let mut request = Request::file_put(file_path.clone(), Bytes::from("new"), None, atomic);
+        if let Request::FilePut { on_exists: policy, .. } = &mut request {
+            *policy = on_exists;
+        }
+        let response = FileHandler::new().handle(request).await.unwrap();
+        (temp_dir, file_path, response)
+    }
+    
+    #[tokio::test]
+    async fn test_file_put_overwrites_existing() {
+        let (_temp_dir, file_path, response) = put_over_existing(OnExists::Overwrite, false).await;
         match response {
-            Response::WasmResult { .. } => {
-                // Success case
-            }
-            Response::Error { error, .. } => {
-                // Expected for non-WASI modules
-                assert!(error.code == ErrorCode::WasmFailed);
+            Response::FilePutResult { bytes_written, skipped, backup_path, .. } => {
+                assert_eq!(bytes_written, 3);
+                assert!(!skipped);
+                assert_eq!(backup_path, None);
             }
-            _ => panic!("Expected WasmResult or Error response"),
+            other => panic!("Expected FilePutResult, got {:?}", other),
         }
+        assert_eq!(fs::read_to_string(&file_path).await.unwrap(), "new");
     }
     
     #[tokio::test]
-    async fn test_wasm_handler_invalid_module() {
-        let handler = WasmHandler::new().unwrap();
-        
-        // Create invalid WASM bytes
-        let invalid_wasm = vec![0xFF, 0xFF, 0xFF, 0xFF];
-        let input_data = Bytes::from("test input");
-        
-        let request = Request::WasmExec {
-            id: Uuid::new_v4(),
-            module: Bytes::from(invalid_wasm),
-            input: input_data,
-            timeout: Some(10),
-        };
-        
-        let response = handler.handle(request).await.unwrap();
+    async fn test_file_put_fails_on_existing() {
+        let (_temp_dir, file_path, response) = put_over_existing(OnExists::Fail, false).await;
+        match response {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::AlreadyExists),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert_eq!(fs::read_to_string(&file_path).await.unwrap(), "old");
         
+        // A file that isn't there is written as usual
+        let new_path = file_path.with_file_name("fresh.toml");
+        let mut request = Request::file_put(new_path.clone(), Bytes::from("new"), None, false);
+        if let Request::FilePut { on_exists, .. } = &mut request {
+            *on_exists = OnExists::Fail;
+        }
+        assert!(matches!(FileHandler::new().handle(request).await.unwrap(), Response::FilePutResult { .. }));
+        assert_eq!(fs::read_to_string(&new_path).await.unwrap(), "new");
+    }
+    
+    #[tokio::test]
+    async fn test_file_put_skips_existing() {
+        let (_temp_dir, file_path, response) = put_over_existing(OnExists::Skip, false).await;
         match response {
-            Response::Error { error, .. } => {
-                assert!(error.code == ErrorCode::WasmFailed);
-                assert!(error.message.contains("Module loading failed"));
+            Response::FilePutResult { bytes_written, skipped, .. } => {
+                assert_eq!(bytes_written, 0);
+                assert!(skipped);
             }
-            _ => panic!("Expected Error response for invalid WASM"),
+            other => panic!("Expected FilePutResult, got {:?}", other),
         }
+        assert_eq!(fs::read_to_string(&file_path).await.unwrap(), "old");
     }
     
     #[tokio::test]
-    async fn test_wasm_handler_module_caching() {
-        let handler = WasmHandler::new().unwrap();
-        
-        let wasm_bytes = mitoxide_wasm::test_utils::test_modules::minimal_wasm();
-        let input_data = Bytes::from("test");
-        
-        // Execute the same module twice
-        for _ in 0..2 {
-            let request = Request::WasmExec {
-                id: Uuid::new_v4(),
-                module: Bytes::from(wasm_bytes.to_vec()),
-                input: input_data.clone(),
-                timeout: Some(10),
-            };
-            
-            let response = handler.handle(request).await.unwrap();
-            
-            // Should handle both requests (second one should use cached module)
+    async fn test_file_put_backs_up_existing() {
+        for atomic in [false, true] {
+            let (_temp_dir, file_path, response) = put_over_existing(OnExists::Backup, atomic).await;
+            let backup = file_path.with_file_name("config.toml.bak");
             match response {
-                Response::WasmResult { .. } | Response::Error { .. } => {
-                    // Both success and error are acceptable for this test
+                Response::FilePutResult { bytes_written, skipped, backup_path, .. } => {
+                    assert_eq!(bytes_written, 3);
+                    assert!(!skipped);
+                    assert_eq!(backup_path, Some(backup.clone()));
                 }
-                _ => panic!("Expected WasmResult or Error response"),
+                other => panic!("Expected FilePutResult, got {:?}", other),
             }
+            assert_eq!(fs::read_to_string(&file_path).await.unwrap(), "new");
+            assert_eq!(fs::read_to_string(&backup).await.unwrap(), "old");
         }
     }
     
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_wasm_handler_unsupported_request() {
-        let handler = WasmHandler::new().unwrap();
-        
-        let request = Request::Ping {
-            id: Uuid::new_v4(),
-            timestamp: 12345,
-        };
+    async fn test_file_put_backup_moves_the_existing_file() {
+        use std::os::unix::fs::MetadataExt;
         
-        let response = handler.handle(request).await.unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("config.toml");
+        fs::write(&file_path, "old").await.unwrap();
+        let original = fs::metadata(&file_path).await.unwrap().ino();
         
-        match response {
-            Response::Error { error, .. } => {
-                assert!(error.code == ErrorCode::Unsupported);
-            }
-            _ => panic!("Expected Error response for unsupported request"),
+        let mut request = Request::file_put(file_path.clone(), Bytes::from("new"), None, true);
+        if let Request::FilePut { on_exists, .. } = &mut request {
+            *on_exists = OnExists::Backup;
         }
+        assert!(matches!(FileHandler::new().handle(request).await.unwrap(), Response::FilePutResult { .. }));
+        
+        // The backup is the original file itself rather than a copy of it
+        assert_eq!(fs::metadata(file_path.with_file_name("config.toml.bak")).await.unwrap().ino(), original);
+        assert_ne!(fs::metadata(&file_path).await.unwrap().ino(), original);
     }
     
     #[tokio::test]
-    async fn test_process_handler_timeout() {
-        let handler = ProcessHandler;
-        
-        // Use platform-appropriate command that will run for a while
-        let command = if cfg!(windows) {
-            // Use ping with a delay on Windows
-            vec!["ping".to_string(), "-n".to_string(), "10".to_string(), "127.0.0.1".to_string()]
-        } else {
-            vec!["sleep".to_string(), "5".to_string()]
-        };
-        
-        let request = Request::ProcessExec {
-            id: Uuid::new_v4(),
-            command,
-            env: HashMap::new(),
-            cwd: None,
-            stdin: None,
-            timeout: Some(1), // 1 second timeout
-        };
+    async fn test_exclusive_writes_leave_a_file_that_appeared() {
+        // As when the file appears after a put checked for it
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("config.toml");
+        fs::write(&file_path, "old").await.unwrap();
+        
+        let error = write_masked(&file_path, b"new", None, false, true).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&file_path).await.unwrap(), "old");
+        
+        let temp_path = temp_dir.path().join(".config.toml.put");
+        fs::write(&temp_path, "new").await.unwrap();
+        let error = place_copy(&temp_path, &file_path, &Placement::NoReplace).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&file_path).await.unwrap(), "old");
+        
+        // With the file gone, the copy takes its place and isn't left behind
+        fs::remove_file(&file_path).await.unwrap();
+        place_copy(&temp_path, &file_path, &Placement::NoReplace).await.unwrap();
+        assert_eq!(fs::read_to_string(&file_path).await.unwrap(), "new");
+        assert!(!temp_path.exists());
+    }
+    
+    #[tokio::test]
+    async fn test_file_get_transforms_chunks() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("script.sh");
+        fs::write(&file_path, "echo a\necho ${NAME}\n").await.unwrap();
+        let transforms = vec![
+            ContentTransform::Substitute { vars: HashMap::from([("NAME".to_string(), "b".to_string())]) },
+            ContentTransform::LfToCrlf,
+        ];
         
-        let response = handler.handle(request).await.unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let stream = RequestStream { input: None, output: ResponseSink::new(1, tx) };
+        let mut request = Request::file_get_chunked(file_path, 5);
+        if let Request::FileGet { transforms: get_transforms, .. } = &mut request {
+            *get_transforms = transforms;
+        }
+        match handler.handle_stream(request, stream).await.unwrap() {
+            Response::FileContent { metadata, .. } => assert_eq!(metadata.size, 20),
+            other => panic!("Expected FileContent, got {:?}", other),
+        }
         
-        match response {
-            Response::Error { error, .. } => {
-                assert_eq!(error.code, ErrorCode::Timeout);
-                // Check for timeout in a case-insensitive way
-                let message_lower = error.message.to_lowercase();
-                assert!(message_lower.contains("timeout") || message_lower.contains("timed out"), 
-                       "Error message should contain timeout: {}", error.message);
-            }
-            Response::ProcessResult { .. } => {
-                // On some systems, the command might complete quickly, which is also acceptable
-                // The important thing is that we handle timeouts properly when they do occur
-                println!("Command completed before timeout - this is acceptable");
+        let mut content = Vec::new();
+        while let Ok(output) = rx.try_recv() {
+            match output.message {
+                mitoxide_proto::Message::Response(Response::FileChunk { data, .. }) => {
+                    assert!(data.len() <= 5);
+                    content.extend_from_slice(&data);
+                }
+                other => panic!("Expected FileChunk, got {:?}", other),
             }
-            _ => panic!("Expected Error or ProcessResult response"),
         }
+        assert_eq!(content, b"echo a\r\necho b\r\n");
     }
     
     #[tokio::test]
-    async fn test_process_handler_stderr_capture() {
-        let handler = ProcessHandler;
-        
-        // Use platform-appropriate command that writes to stderr
-        let command = if cfg!(windows) {
-            vec!["cmd".to_string(), "/c".to_string(), "echo error message 1>&2".to_string()]
-        } else {
-            vec!["sh".to_string(), "-c".to_string(), "echo 'error message' >&2".to_string()]
-        };
-        
-        let request = Request::ProcessExec {
-            id: Uuid::new_v4(),
-            command,
-            env: HashMap::new(),
-            cwd: None,
-            stdin: None,
-            timeout: Some(10),
-        };
-        
-        let response = handler.handle(request).await.unwrap();
+    async fn test_file_handler_hash() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
         
-        match response {
-            Response::ProcessResult { exit_code, stderr, .. } => {
-                assert_eq!(exit_code, 0);
-                let error_output = String::from_utf8(stderr.to_vec()).unwrap();
-                assert!(error_output.contains("error message"));
+        // FIPS 180-2 test vectors, the second spanning many read blocks, and
+        // vectors from the BLAKE3 reference implementation
+        let vectors = [
+            (HashAlgorithm::Sha256, "abc".to_string(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (HashAlgorithm::Sha256, "a".repeat(1_000_000), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"),
+            (HashAlgorithm::Blake3, String::new(), "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"),
+            (HashAlgorithm::Blake3, "abc".to_string(), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+        ];
+        for (i, (algorithm, content, expected)) in vectors.iter().enumerate() {
+            let path = temp_dir.path().join(format!("vector-{}", i));
+            fs::write(&path, content).await.unwrap();
+            
+            match handler.handle(Request::file_hash(path, *algorithm)).await.unwrap() {
+                Response::FileHash { hash, size, .. } => {
+                    assert_eq!(hash, *expected);
+                    assert_eq!(size, content.len() as u64);
+                }
+                other => panic!("Expected FileHash, got {:?}", other),
             }
-            _ => panic!("Expected ProcessResult response"),
+        }
+        
+        let missing = temp_dir.path().join("missing");
+        match handler.handle(Request::file_hash(missing, HashAlgorithm::Sha256)).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::FileNotFound),
+            other => panic!("Expected Error, got {:?}", other),
         }
     }
     
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_process_handler_empty_command() {
-        let handler = ProcessHandler;
-        let request = Request::ProcessExec {
-            id: Uuid::new_v4(),
-            command: vec![],
-            env: HashMap::new(),
-            cwd: None,
-            stdin: None,
-            timeout: None,
-        };
+    async fn test_file_handler_chown() {
+        use std::os::unix::fs::MetadataExt;
         
-        let response = handler.handle(request).await.unwrap();
+        // Giving files away needs privilege
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir_all(tree.join("nested")).await.unwrap();
+        fs::write(tree.join("nested/file.txt"), "content").await.unwrap();
+        let outside = temp_dir.path().join("outside.txt");
+        fs::write(&outside, "target").await.unwrap();
+        std::os::unix::fs::symlink(&outside, tree.join("link")).unwrap();
+        
+        let request = Request::file_chown(tree.clone(), Some(FileOwner::Id(1234)), Some(FileOwner::Id(5678)), true);
+        match handler.handle(request).await.unwrap() {
+            Response::FileOwnership { uid, gid, changed, .. } => assert_eq!((uid, gid, changed), (1234, 5678, 4)),
+            other => panic!("Expected FileOwnership, got {:?}", other),
+        }
+        let file = std::fs::metadata(tree.join("nested/file.txt")).unwrap();
+        assert_eq!((file.uid(), file.gid()), (1234, 5678));
+        let link = std::fs::symlink_metadata(tree.join("link")).unwrap();
+        assert_eq!((link.uid(), link.gid()), (1234, 5678));
+        // The symlink's target is left alone
+        assert_eq!(std::fs::metadata(&outside).unwrap().uid(), 0);
+        
+        // Names resolve to IDs, and an owner left out stays as it is
+        let request = Request::file_chown(tree.join("nested/file.txt"), Some(FileOwner::from("root")), None, false);
+        match handler.handle(request).await.unwrap() {
+            Response::FileOwnership { uid, gid, changed, .. } => assert_eq!((uid, gid, changed), (0, 5678, 1)),
+            other => panic!("Expected FileOwnership, got {:?}", other),
+        }
+        match handler.handle(Request::file_chown(tree.clone(), None, None, true)).await.unwrap() {
+            Response::FileOwnership { uid, changed, .. } => assert_eq!((uid, changed), (1234, 0)),
+            other => panic!("Expected FileOwnership, got {:?}", other),
+        }
         
-        match response {
-            Response::Error { error, .. } => {
-                assert_eq!(error.code, ErrorCode::InvalidRequest);
-            }
-            _ => panic!("Expected Error response"),
+        let request = Request::file_chown(tree, Some(FileOwner::from("no-such-user-mitoxide")), None, false);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::InvalidRequest),
+            other => panic!("Expected Error, got {:?}", other),
         }
     }
     
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_file_handler_put_get() {
-        let handler = FileHandler;
+    async fn test_file_handler_chown_without_privilege() {
+        if nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let handler = FileHandler::new();
         let temp_dir = TempDir::new().unwrap();
-        let file_path = temp_dir.path().join("test.txt");
-        let content = Bytes::from("Hello, world!");
-        
-        // Test file put
-        let put_request = Request::FilePut {
-            id: Uuid::new_v4(),
-            path: file_path.clone(),
-            content: content.clone(),
-            mode: Some(0o644),
-            create_dirs: true,
-        };
+        let path = temp_dir.path().join("mine.txt");
+        fs::write(&path, "content").await.unwrap();
         
-        let put_response = handler.handle(put_request).await.unwrap();
-        match put_response {
-            Response::FilePutResult { bytes_written, .. } => {
-                assert_eq!(bytes_written, content.len() as u64);
-            }
-            _ => panic!("Expected FilePutResult response"),
+        match handler.handle(Request::file_chown(path, Some(FileOwner::Id(0)), None, false)).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::PermissionDenied),
+            other => panic!("Expected Error, got {:?}", other),
         }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_file_handler_ensure() {
+        use std::os::unix::fs::PermissionsExt;
         
-        // Test file get
-        let get_request = Request::FileGet {
-            id: Uuid::new_v4(),
-            path: file_path,
-            range: None,
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("app.conf");
+        let me = Some(FileOwner::Id(nix::unistd::geteuid().as_raw()));
+        let ensure = |content: &'static str, mode: u32| {
+            Request::file_ensure(path.clone(), Bytes::from_static(content.as_bytes()), Some(mode), me.clone())
+        };
+        let changes = |response: Response| match response {
+            Response::FileEnsure { changed, what_changed, .. } => {
+                assert_eq!(changed, !what_changed.is_empty());
+                what_changed
+            }
+            other => panic!("Expected FileEnsure, got {:?}", other),
         };
         
-        let get_response = handler.handle(get_request).await.unwrap();
-        match get_response {
-            Response::FileContent { content: retrieved_content, metadata, .. } => {
-                assert_eq!(retrieved_content, content);
-                assert!(!metadata.is_dir);
-                assert_eq!(metadata.size, content.len() as u64);
-            }
-            _ => panic!("Expected FileContent response"),
+        // A missing file is created with the content, then given the mode
+        let created = changes(handler.handle(ensure("port = 80\n", 0o600)).await.unwrap());
+        assert_eq!(created.first(), Some(&FileChange::Content));
+        assert!(!created.contains(&FileChange::Owner));
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "port = 80\n");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o600);
+        
+        // Asking again changes nothing
+        assert_eq!(changes(handler.handle(ensure("port = 80\n", 0o600)).await.unwrap()), Vec::new());
+        
+        // Only what differs is changed
+        assert_eq!(changes(handler.handle(ensure("port = 80\n", 0o640)).await.unwrap()), vec![FileChange::Mode]);
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o640);
+        assert_eq!(changes(handler.handle(ensure("port = 8080\n", 0o640)).await.unwrap()), vec![FileChange::Content]);
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "port = 8080\n");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o640);
+        
+        let request = Request::file_ensure(temp_dir.path().join("missing/app.conf"), Bytes::new(), None, None);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert!(error.message.contains("File ensure failed"), "{}", error.message),
+            other => panic!("Expected Error, got {:?}", other),
         }
     }
     
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_file_handler_get_nonexistent() {
-        let handler = FileHandler;
-        let request = Request::FileGet {
-            id: Uuid::new_v4(),
-            path: PathBuf::from("/nonexistent/file.txt"),
-            range: None,
-        };
+    async fn test_file_handler_ensure_keeps_owner() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
         
-        let response = handler.handle(request).await.unwrap();
-        match response {
-            Response::Error { error, .. } => {
-                // Print the actual error message for debugging
-                println!("Error message: {}", error.message);
-                // On Windows, the error might be different, so let's be more flexible
-                assert!(matches!(error.code, ErrorCode::FileNotFound | ErrorCode::InternalError));
+        // Files owned by someone else need privilege to write
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("app.conf");
+        fs::write(&path, "port = 80\n").await.unwrap();
+        std::os::unix::fs::chown(&path, Some(1234), Some(5678)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        
+        // New content keeps the owners and mode it isn't asked to change
+        let request = Request::file_ensure(path.clone(), Bytes::from_static(b"port = 8080\n"), None, None);
+        match handler.handle(request).await.unwrap() {
+            Response::FileEnsure { what_changed, .. } => assert_eq!(what_changed, vec![FileChange::Content]),
+            other => panic!("Expected FileEnsure, got {:?}", other),
+        }
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+        
+        // and takes on the ones it is asked for
+        let request = Request::file_ensure(path.clone(), Bytes::from_static(b"port = 443\n"), Some(0o600), Some(FileOwner::Id(4321)));
+        match handler.handle(request).await.unwrap() {
+            Response::FileEnsure { what_changed, .. } => {
+                assert_eq!(what_changed, vec![FileChange::Content, FileChange::Mode, FileChange::Owner]);
             }
-            _ => panic!("Expected Error response"),
+            other => panic!("Expected FileEnsure, got {:?}", other),
         }
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (4321, 5678));
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
     }
     
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_file_handler_dir_list() {
-        let handler = FileHandler;
+    async fn test_file_handler_disk_space() {
+        let handler = FileHandler::new();
         let temp_dir = TempDir::new().unwrap();
+        let space = |response: Response| match response {
+            Response::DiskSpace { space, .. } => space,
+            other => panic!("Expected DiskSpace, got {:?}", other),
+        };
         
-        // Create some test files
-        let file1 = temp_dir.path().join("file1.txt");
-        let file2 = temp_dir.path().join("file2.txt");
-        let hidden_file = temp_dir.path().join(".hidden");
-        
-        fs::write(&file1, "content1").await.unwrap();
-        fs::write(&file2, "content2").await.unwrap();
-        fs::write(&hidden_file, "hidden").await.unwrap();
+        let existing = space(handler.handle(Request::disk_space(temp_dir.path().to_path_buf())).await.unwrap());
+        assert!(existing.total > 0);
+        assert!(existing.available <= existing.total);
+        assert!(existing.used <= existing.total);
         
-        // Test directory listing without hidden files
-        let request = Request::DirList {
-            id: Uuid::new_v4(),
-            path: temp_dir.path().to_path_buf(),
-            include_hidden: false,
-            recursive: false,
-        };
+        // A path yet to be created is on its parent's filesystem
+        let missing = temp_dir.path().join("not/yet/created.bin");
+        let planned = space(handler.handle(Request::disk_space(missing)).await.unwrap());
+        assert_eq!(planned.total, existing.total);
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_file_handler_xattrs() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("labelled.txt");
+        fs::write(&path, "content").await.unwrap();
         
-        let response = handler.handle(request).await.unwrap();
-        match response {
-            Response::DirListing { entries, .. } => {
-                assert_eq!(entries.len(), 2); // Should not include hidden file
-                let names: Vec<_> = entries.iter().map(|e| &e.name).collect();
-                assert!(names.contains(&&"file1.txt".to_string()));
-                assert!(names.contains(&&"file2.txt".to_string()));
-                assert!(!names.contains(&&".hidden".to_string()));
-            }
-            _ => panic!("Expected DirListing response"),
+        let xattrs = HashMap::from([("user.origin".to_string(), Bytes::from("build-42"))]);
+        match handler.handle(Request::file_xattr_set(path.clone(), xattrs.clone())).await.unwrap() {
+            Response::FileXattrs { xattrs: after, .. } => assert_eq!(after, xattrs),
+            other => panic!("Expected FileXattrs, got {:?}", other),
+        }
+        match handler.handle(Request::file_xattr_get(path.clone())).await.unwrap() {
+            Response::FileXattrs { xattrs: read, .. } => assert_eq!(read, xattrs),
+            other => panic!("Expected FileXattrs, got {:?}", other),
         }
         
-        // Test directory listing with hidden files
-        let request_with_hidden = Request::DirList {
-            id: Uuid::new_v4(),
-            path: temp_dir.path().to_path_buf(),
-            include_hidden: true,
-            recursive: false,
-        };
+        // Only included in the metadata when asked for
+        match handler.handle(Request::file_get(path.clone(), None)).await.unwrap() {
+            Response::FileContent { metadata, .. } => assert_eq!(metadata.xattrs, None),
+            other => panic!("Expected FileContent, got {:?}", other),
+        }
+        let mut request = Request::file_get(path, None);
+        if let Request::FileGet { include_xattrs, .. } = &mut request {
+            *include_xattrs = true;
+        }
+        match handler.handle(request).await.unwrap() {
+            Response::FileContent { metadata, .. } => assert_eq!(metadata.xattrs, Some(xattrs.clone())),
+            other => panic!("Expected FileContent, got {:?}", other),
+        }
         
-        let response_with_hidden = handler.handle(request_with_hidden).await.unwrap();
-        match response_with_hidden {
-            Response::DirListing { entries, .. } => {
-                assert_eq!(entries.len(), 3); // Should include hidden file
-                let names: Vec<_> = entries.iter().map(|e| &e.name).collect();
-                assert!(names.contains(&&".hidden".to_string()));
+        // procfs has no user attributes
+        let request = Request::file_xattr_set(PathBuf::from("/proc/self/status"), xattrs);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => {
+                assert_eq!(error.code, ErrorCode::Unsupported);
+                assert!(error.message.contains("not supported"));
             }
-            _ => panic!("Expected DirListing response"),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        
+        match handler.handle(Request::file_xattr_get(temp_dir.path().join("missing"))).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::FileNotFound),
+            other => panic!("Expected Error, got {:?}", other),
         }
     }
     
     #[tokio::test]
-    async fn test_file_handler_recursive_dir_list() {
-        let handler = FileHandler;
+    async fn test_file_handler_put_sets_modified_time() {
+        let handler = FileHandler::new();
         let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("dated.txt");
         
-        // Create nested directory structure
-        let subdir = temp_dir.path().join("subdir");
-        fs::create_dir(&subdir).await.unwrap();
-        
-        let file1 = temp_dir.path().join("file1.txt");
-        let file2 = subdir.join("file2.txt");
-        let file3 = subdir.join("file3.txt");
+        let mut request = Request::file_put(path.clone(), Bytes::from("dated"), Some(0o444), false);
+        if let Request::FilePut { modified, .. } = &mut request {
+            *modified = Some(1_000_000_000);
+        }
+        assert!(matches!(handler.handle(request).await.unwrap(), Response::FilePutResult { bytes_written: 5, .. }));
         
-        fs::write(&file1, "content1").await.unwrap();
-        fs::write(&file2, "content2").await.unwrap();
-        fs::write(&file3, "content3").await.unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let modified = metadata.modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(modified.as_secs(), 1_000_000_000);
+        #[cfg(unix)]
+        assert!(metadata.permissions().readonly());
+    }
+    
+    #[tokio::test]
+    async fn test_file_handler_delta_put_rebuilds_file() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data.bin");
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &old).await.unwrap();
         
-        // Test recursive directory listing
-        let request = Request::DirList {
-            id: Uuid::new_v4(),
-            path: temp_dir.path().to_path_buf(),
-            include_hidden: false,
-            recursive: true,
+        let signature = match handler.handle(Request::file_signature(path.clone(), 1024)).await.unwrap() {
+            Response::FileSignature { signature, .. } => signature,
+            other => panic!("Expected FileSignature, got {:?}", other),
         };
+        assert_eq!((signature.size, signature.blocks.len()), (20_000, 20));
+        
+        let mut new = old.clone();
+        new.splice(5000..5000, b"inserted".iter().copied());
+        let ops = delta::delta(&signature, &new);
+        let mut request = Request::file_delta_put(path.clone(), &signature, ops.clone());
+        if let Request::FileDeltaPut { mode, .. } = &mut request {
+            *mode = Some(0o600);
+        }
+        match handler.handle(request).await.unwrap() {
+            Response::FilePutResult { bytes_written, .. } => assert_eq!(bytes_written, 20_008),
+            other => panic!("Expected FilePutResult, got {:?}", other),
+        }
+        assert_eq!(fs::read(&path).await.unwrap(), new);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
         
-        let response = handler.handle(request).await.unwrap();
-        match response {
-            Response::DirListing { entries, .. } => {
-                // Should include files from both root and subdirectory
-                assert!(entries.len() >= 4); // file1.txt, subdir, file2.txt, file3.txt
-                let names: Vec<_> = entries.iter().map(|e| &e.name).collect();
-                assert!(names.contains(&&"file1.txt".to_string()));
-                assert!(names.contains(&&"subdir".to_string()));
-                assert!(names.contains(&&"file2.txt".to_string()));
-                assert!(names.contains(&&"file3.txt".to_string()));
-            }
-            _ => panic!("Expected DirListing response"),
+        // The file changed since the signature, so its blocks no longer fit the ops
+        match handler.handle(Request::file_delta_put(path.clone(), &signature, ops)).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::PatchFailed),
+            other => panic!("Expected Error, got {:?}", other),
         }
+        assert_eq!(fs::read(&path).await.unwrap(), new);
     }
     
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_file_handler_range_get() {
-        let handler = FileHandler;
-        let temp_dir = TempDir::new().unwrap();
-        let file_path = temp_dir.path().join("test.txt");
-        let content = "Hello, world! This is a test file with some content.";
-        
-        // Create test file
-        fs::write(&file_path, content).await.unwrap();
-        
-        // Test range get (bytes 7-12 should be "world")
-        let request = Request::FileGet {
-            id: Uuid::new_v4(),
-            path: file_path,
-            range: Some((7, 12)),
-        };
+    async fn test_file_handler_set_attributes_and_remove() {
+        use std::os::unix::fs::PermissionsExt;
         
-        let response = handler.handle(request).await.unwrap();
-        match response {
-            Response::FileContent { content: retrieved_content, .. } => {
-                let content_str = String::from_utf8(retrieved_content.to_vec()).unwrap();
-                assert_eq!(content_str, "world");
-            }
-            _ => panic!("Expected FileContent response"),
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file.txt");
+        fs::write(&path, "content").await.unwrap();
+        
+        // A read-only file can still be dated
+        let request = Request::file_set_attributes(path.clone(), Some(0o444), Some(1_000_000_000));
+        match handler.handle(request).await.unwrap() {
+            Response::FileAttributes { mode, modified, .. } => assert_eq!((mode, modified), (0o444, 1_000_000_000)),
+            other => panic!("Expected FileAttributes, got {:?}", other),
         }
+        let request = Request::file_set_attributes(path.clone(), None, Some(2_000_000_000));
+        assert!(matches!(handler.handle(request).await.unwrap(), Response::FileAttributes { mode: 0o444, modified: 2_000_000_000, .. }));
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o444);
+        
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir_all(tree.join("nested")).await.unwrap();
+        fs::write(tree.join("nested/file.txt"), "content").await.unwrap();
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(&tree, &link).unwrap();
+        
+        // The link goes, not what it points to
+        assert!(matches!(handler.handle(Request::file_remove(link.clone(), false)).await.unwrap(), Response::FileRemoved { existed: true, .. }));
+        assert!(tree.join("nested/file.txt").exists());
+        
+        match handler.handle(Request::file_remove(tree.clone(), false)).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::InvalidRequest),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert!(matches!(handler.handle(Request::file_remove(tree.clone(), true)).await.unwrap(), Response::FileRemoved { existed: true, .. }));
+        assert!(!tree.exists());
+        assert!(matches!(handler.handle(Request::file_remove(tree, true)).await.unwrap(), Response::FileRemoved { existed: false, .. }));
+        assert!(matches!(handler.handle(Request::file_remove(path.clone(), false)).await.unwrap(), Response::FileRemoved { existed: true, .. }));
+        assert!(!path.exists());
     }
     
     #[tokio::test]
     async fn test_file_handler_create_dirs() {
-        let handler = FileHandler;
+        let handler = FileHandler::new();
         let temp_dir = TempDir::new().unwrap();
         let nested_path = temp_dir.path().join("nested").join("dirs").join("test.txt");
         let content = Bytes::from("test content");
         
         // Test file put with create_dirs = true
-        let request = Request::FilePut {
-            id: Uuid::new_v4(),
-            path: nested_path.clone(),
-            content: content.clone(),
-            mode: Some(0o644),
-            create_dirs: true,
-        };
+        let request = Request::file_put(nested_path.clone(), content.clone(), Some(0o644), true);
         
         let response = handler.handle(request).await.unwrap();
         match response {
@@ -1396,7 +6821,7 @@ mod tests {
     
     #[tokio::test]
     async fn test_file_handler_large_file() {
-        let handler = FileHandler;
+        let handler = FileHandler::new();
         let temp_dir = TempDir::new().unwrap();
         let file_path = temp_dir.path().join("large.txt");
         
@@ -1405,13 +6830,7 @@ mod tests {
         let content = Bytes::from(large_content.clone());
         
         // Test putting large file
-        let put_request = Request::FilePut {
-            id: Uuid::new_v4(),
-            path: file_path.clone(),
-            content: content.clone(),
-            mode: Some(0o644),
-            create_dirs: false,
-        };
+        let put_request = Request::file_put(file_path.clone(), content.clone(), Some(0o644), false);
         
         let put_response = handler.handle(put_request).await.unwrap();
         match put_response {
@@ -1422,11 +6841,7 @@ mod tests {
         }
         
         // Test getting large file
-        let get_request = Request::FileGet {
-            id: Uuid::new_v4(),
-            path: file_path,
-            range: None,
-        };
+        let get_request = Request::file_get(file_path, None);
         
         let get_response = handler.handle(get_request).await.unwrap();
         match get_response {
@@ -1441,19 +6856,13 @@ mod tests {
     
     #[tokio::test]
     async fn test_file_handler_permissions() {
-        let handler = FileHandler;
+        let handler = FileHandler::new();
         let temp_dir = TempDir::new().unwrap();
         let file_path = temp_dir.path().join("test_perms.txt");
         let content = Bytes::from("test content");
         
         // Test file put with specific permissions
-        let request = Request::FilePut {
-            id: Uuid::new_v4(),
-            path: file_path.clone(),
-            content: content.clone(),
-            mode: Some(0o755),
-            create_dirs: false,
-        };
+        let request = Request::file_put(file_path.clone(), content.clone(), Some(0o755), false);
         
         let response = handler.handle(request).await.unwrap();
         match response {
@@ -1477,17 +6886,252 @@ mod tests {
         }
     }
     
+    /// Sink that keeps records for inspection
+    #[derive(Default)]
+    struct RecordingSink {
+        records: Mutex<Vec<AuditRecord>>,
+    }
+    
+    impl AuditSink for RecordingSink {
+        fn record(&self, record: &AuditRecord) {
+            self.records.lock().unwrap().push(record.clone());
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_privileged_command_and_file_write_are_audited() {
+        use mitoxide_proto::message::{Credentials, PrivilegeEscalation};
+        
+        let sink = Arc::new(RecordingSink::default());
+        let pty_handler = PtyHandler::new().with_audit_sink(sink.clone());
+        let file_handler = FileHandler::new().with_audit_sink(sink.clone());
+        
+        // `env` stands in for an escalation command so the test needs no privileges
+        let privilege = PrivilegeEscalation {
+            method: PrivilegeMethod::Custom("env".to_string()),
+            credentials: Some(Credentials {
+                username: Some("deploy".to_string()),
+                password: None,
+            }),
+            prompt_patterns: Vec::new(),
+        };
+        let mut request = Request::pty_exec(vec!["true".to_string()], HashMap::new(), None, Some(10));
+        if let Request::PtyExec { privilege: escalation, .. } = &mut request {
+            *escalation = Some(privilege);
+        }
+        pty_handler.handle(request).await.unwrap();
+        
+        // Unprivileged commands are not audited
+        let request = Request::pty_exec(vec!["true".to_string()], HashMap::new(), None, Some(10));
+        pty_handler.handle(request).await.unwrap();
+        
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("written.txt");
+        let request = Request::file_put(path.clone(), Bytes::from_static(b"audited"), None, false);
+        file_handler.handle(request).await.unwrap();
+        
+        let records = sink.records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        
+        assert_eq!(records[0].principal, "deploy");
+        assert_eq!(records[0].operation, AuditOperation::PrivilegedExec {
+            command: vec!["env".to_string(), "true".to_string()],
+        });
+        assert_eq!(records[0].result, AuditResult::Exited { exit_code: 0 });
+        assert!(records[0].timestamp_ms > 0);
+        
+        assert_eq!(records[1].principal, audit::agent_principal());
+        assert_eq!(records[1].operation, AuditOperation::FileWrite { path });
+        assert_eq!(records[1].result, AuditResult::Succeeded);
+        assert!(records[1].timestamp_ms >= records[0].timestamp_ms);
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_failed_and_attribute_changes_are_audited() {
+        use mitoxide_proto::message::{FileOwner, PrivilegeEscalation};
+        
+        let sink = Arc::new(RecordingSink::default());
+        let pty_handler = PtyHandler::new().with_audit_sink(sink.clone());
+        let file_handler = FileHandler::new().with_audit_sink(sink.clone());
+        
+        // A privileged request refused before anything runs
+        let mut request = Request::pty_exec(vec!["true".to_string()], HashMap::new(), None, Some(10));
+        if let Request::PtyExec { privilege, interactive, .. } = &mut request {
+            *privilege = Some(PrivilegeEscalation {
+                method: PrivilegeMethod::Custom("env".to_string()),
+                credentials: None,
+                prompt_patterns: Vec::new(),
+            });
+            *interactive = true;
+        }
+        pty_handler.handle(request).await.unwrap();
+        
+        // Giving a file to its own owner is allowed without privileges
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("owned.txt");
+        std::fs::write(&path, "owned").unwrap();
+        let uid = nix::unistd::getuid().as_raw();
+        let request = Request::FileChown { id: Uuid::new_v4(), path: path.clone(), uid: Some(FileOwner::Id(uid)), gid: None, recursive: false };
+        file_handler.handle(request).await.unwrap();
+        let missing = dir.path().join("missing.txt");
+        let request = Request::FileChown { id: Uuid::new_v4(), path: missing.clone(), uid: Some(FileOwner::Id(uid)), gid: None, recursive: false };
+        file_handler.handle(request).await.unwrap();
+        
+        // Whether or not the filesystem supports extended attributes, the attempt is recorded
+        let xattrs = HashMap::from([("user.mitoxide.b".to_string(), Bytes::from_static(b"2")), ("user.mitoxide.a".to_string(), Bytes::from_static(b"1"))]);
+        let request = Request::FileXattrSet { id: Uuid::new_v4(), path: missing.clone(), xattrs };
+        file_handler.handle(request).await.unwrap();
+        
+        file_handler.handle(Request::file_set_attributes(path.clone(), Some(0o600), None)).await.unwrap();
+        file_handler.handle(Request::file_remove(path.clone(), false)).await.unwrap();
+        
+        let records = sink.records.lock().unwrap();
+        assert_eq!(records.len(), 6);
+        
+        assert_eq!(records[0].principal, "root");
+        assert_eq!(records[0].operation, AuditOperation::PrivilegedExec { command: vec!["true".to_string()] });
+        assert!(matches!(&records[0].result, AuditResult::Failed { error } if error.contains("client stream")));
+        
+        assert_eq!(records[1].operation, AuditOperation::Chown { path: path.clone(), uid: Some(FileOwner::Id(uid)), gid: None, recursive: false });
+        assert_eq!(records[1].result, AuditResult::Succeeded);
+        assert!(matches!(records[2].operation, AuditOperation::Chown { .. }));
+        assert!(matches!(records[2].result, AuditResult::Failed { .. }));
+        
+        assert_eq!(records[3].operation, AuditOperation::XattrSet {
+            path: missing,
+            names: vec!["user.mitoxide.a".to_string(), "user.mitoxide.b".to_string()],
+        });
+        assert!(matches!(records[3].result, AuditResult::Failed { .. }));
+        
+        assert_eq!(records[4].operation, AuditOperation::SetAttributes { path: path.clone(), mode: Some(0o600), modified: None });
+        assert_eq!(records[5].operation, AuditOperation::Remove { path, recursive: false });
+        assert_eq!(records[5].result, AuditResult::Succeeded);
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_file_handler_umask_restricts_created_files() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let handler = FileHandler::new().with_umask(0o077);
+        let temp_dir = TempDir::new().unwrap();
+        let secret_path = temp_dir.path().join("secrets").join("token");
+        
+        let request = Request::file_put(secret_path.clone(), Bytes::from("s3cr3t"), None, true);
+        let response = handler.handle(request).await.unwrap();
+        assert!(matches!(response, Response::FilePutResult { .. }), "{:?}", response);
+        
+        let file_mode = std::fs::metadata(&secret_path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(file_mode & 0o077, 0, "file mode {:o}", file_mode);
+        let dir_mode = std::fs::metadata(secret_path.parent().unwrap()).unwrap().permissions().mode() & 0o777;
+        assert_eq!(dir_mode & 0o077, 0, "directory mode {:o}", dir_mode);
+        
+        // A per-request umask overrides the handler's
+        let shared_path = temp_dir.path().join("shared.txt");
+        let mut request = Request::file_put(shared_path.clone(), Bytes::from("hello"), None, false);
+        if let Request::FilePut { umask, .. } = &mut request {
+            *umask = Some(0o022);
+        }
+        handler.handle(request).await.unwrap();
+        let shared_mode = std::fs::metadata(&shared_path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(shared_mode & 0o022, 0, "shared mode {:o}", shared_mode);
+    }
+    
+    /// Unified diff changing the port in a small config file
+    const CONFIG_PATCH: &str = "\
+--- a/app.conf
++++ b/app.conf
+@@ -1,3 +1,3 @@
+ host = example.com
+-port = 80
++port = 8080
+ debug = false
+";
+    
+    #[tokio::test]
+    async fn test_file_patch_applies_cleanly() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("app.conf");
+        std::fs::write(&path, "host = example.com\nport = 80\ndebug = false\n").unwrap();
+        
+        let request = Request::file_patch_text(path.clone(), CONFIG_PATCH, false);
+        match handler.handle(request).await.unwrap() {
+            Response::FilePatchResult { bytes_written, backup_path, .. } => {
+                assert_eq!(bytes_written, 45);
+                assert_eq!(backup_path, None);
+            }
+            other => panic!("Expected FilePatchResult response, got {:?}", other),
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "host = example.com\nport = 8080\ndebug = false\n");
+        
+        // No backup or temporary file is left behind
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_file_patch_failed_hunk_leaves_original() {
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("app.conf");
+        let modified = "host = example.com\nport = 443\ndebug = false\n";
+        std::fs::write(&path, modified).unwrap();
+        
+        let request = Request::file_patch_text(path.clone(), CONFIG_PATCH, true);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::PatchFailed),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), modified);
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+        
+        let request = Request::file_patch_text(path.clone(), "--- a/app.conf\n+++ b/app.conf\n@@ -one +two @@\n", false);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::InvalidRequest),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        
+        let request = Request::file_patch_text(temp_dir.path().join("missing.conf"), CONFIG_PATCH, false);
+        match handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::FileNotFound),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_file_patch_keeps_backup_and_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let handler = FileHandler::new();
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("app.conf");
+        let original = "host = example.com\nport = 80\ndebug = false\n";
+        std::fs::write(&path, original).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        
+        let request = Request::file_patch_text(path.clone(), CONFIG_PATCH, true);
+        let backup = match handler.handle(request).await.unwrap() {
+            Response::FilePatchResult { backup_path, .. } => backup_path.unwrap(),
+            other => panic!("Expected FilePatchResult response, got {:?}", other),
+        };
+        assert_eq!(backup, temp_dir.path().join("app.conf.bak"));
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), original);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("port = 8080"));
+        
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o640);
+    }
+    
     #[tokio::test]
     async fn test_file_handler_directory_as_file_error() {
-        let handler = FileHandler;
+        let handler = FileHandler::new();
         let temp_dir = TempDir::new().unwrap();
         
         // Try to get a directory as if it were a file
-        let request = Request::FileGet {
-            id: Uuid::new_v4(),
-            path: temp_dir.path().to_path_buf(),
-            range: None,
-        };
+        let request = Request::file_get(temp_dir.path().to_path_buf(), None);
         
         let response = handler.handle(request).await.unwrap();
         match response {
@@ -1501,19 +7145,13 @@ mod tests {
     
     #[tokio::test]
     async fn test_file_handler_put_without_create_dirs() {
-        let handler = FileHandler;
+        let handler = FileHandler::new();
         let temp_dir = TempDir::new().unwrap();
         let nested_path = temp_dir.path().join("nonexistent").join("test.txt");
         let content = Bytes::from("test content");
         
         // Test file put with create_dirs = false (should fail)
-        let request = Request::FilePut {
-            id: Uuid::new_v4(),
-            path: nested_path,
-            content,
-            mode: Some(0o644),
-            create_dirs: false,
-        };
+        let request = Request::file_put(nested_path, content, Some(0o644), false);
         
         let response = handler.handle(request).await.unwrap();
         match response {
@@ -1525,6 +7163,77 @@ mod tests {
         }
     }
     
+    #[cfg(unix)]
+    #[test]
+    fn test_file_write_error_code_reports_full_disk() {
+        for errno in [nix::errno::Errno::ENOSPC, nix::errno::Errno::EDQUOT] {
+            let io_error = std::io::Error::from_raw_os_error(errno as i32);
+            let e = anyhow::Error::new(io_error).context("Failed to write temp file");
+            assert_eq!(file_write_error_code(&e), ErrorCode::DiskFull);
+            assert_eq!(file_io_error_code(&std::io::Error::from_raw_os_error(errno as i32)), ErrorCode::DiskFull);
+        }
+        
+        let e = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert_eq!(file_write_error_code(&e), ErrorCode::PermissionDenied);
+        assert_eq!(file_write_error_code(&anyhow::anyhow!("no I/O error")), ErrorCode::InternalError);
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[ignore = "needs permission to mount a tmpfs"]
+    async fn test_file_put_on_full_disk_leaves_no_partial_file() {
+        /// A tiny tmpfs, unmounted when dropped
+        struct TinyFs(TempDir);
+        
+        impl Drop for TinyFs {
+            fn drop(&mut self) {
+                let _ = std::process::Command::new("umount").arg(self.0.path()).status();
+            }
+        }
+        
+        let dir = TempDir::new().unwrap();
+        let mounted = std::process::Command::new("mount")
+            .args(["-t", "tmpfs", "-o", "size=64k", "tmpfs"])
+            .arg(dir.path())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map_or(false, |status| status.success());
+        assert!(mounted, "Failed to mount a tmpfs");
+        let fs = TinyFs(dir);
+        let handler = FileHandler::new();
+        let too_big = Bytes::from(vec![b'x'; 256 * 1024]);
+        let put = |path: PathBuf, content: &Bytes, atomic: bool| {
+            let mut request = Request::file_put(path, content.clone(), None, false);
+            if let Request::FilePut { atomic: a, .. } = &mut request {
+                *a = atomic;
+            }
+            request
+        };
+        
+        // In place, the partly written file is removed
+        let path = fs.0.path().join("image.bin");
+        match handler.handle(put(path.clone(), &too_big, false)).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::DiskFull),
+            other => panic!("Expected DiskFull error, got {:?}", other),
+        }
+        assert!(!path.exists());
+        
+        // Atomically, the existing file keeps its content and no copy is left behind
+        let path = fs.0.path().join("app.conf");
+        std::fs::write(&path, "port = 80\n").unwrap();
+        match handler.handle(put(path.clone(), &too_big, true)).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::DiskFull),
+            other => panic!("Expected DiskFull error, got {:?}", other),
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "port = 80\n");
+        assert_eq!(std::fs::read_dir(fs.0.path()).unwrap().count(), 1);
+        
+        // What fits is written whole either way
+        let request = put(path.clone(), &Bytes::from("port = 8080\n"), true);
+        assert!(matches!(handler.handle(request).await.unwrap(), Response::FilePutResult { bytes_written: 12, .. }));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "port = 8080\n");
+    }
+    
     #[tokio::test]
     async fn test_ping_handler() {
         let handler = PingHandler;
@@ -1546,9 +7255,143 @@ mod tests {
         }
     }
     
+    #[tokio::test]
+    async fn test_version_handler_reports_build() {
+        let request = Request::version();
+        let request_id = request.id();
+        let build = match VersionHandler.handle(request).await.unwrap() {
+            Response::Version { request_id: resp_id, build } => {
+                assert_eq!(resp_id, request_id);
+                build
+            }
+            other => panic!("Expected Version, got {:?}", other),
+        };
+        
+        assert_eq!(build.version, env!("CARGO_PKG_VERSION"));
+        let compiled = [
+            ("docker", cfg!(feature = "docker")),
+            ("jsonrpc", cfg!(feature = "jsonrpc")),
+            ("k8s", cfg!(feature = "k8s")),
+            ("lxc", cfg!(feature = "lxc")),
+            ("sudo", cfg!(feature = "sudo")),
+            ("wasm", cfg!(feature = "wasm")),
+        ];
+        let expected: Vec<&str> = compiled.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect();
+        assert_eq!(build.features, expected);
+        assert!(build.target.contains(std::env::consts::ARCH), "{}", build.target);
+        assert!(build.rustc.unwrap().starts_with("rustc "));
+    }
+    
+    /// Open a tunnel through `handler` whose client sends nothing
+    async fn tunnel_to(handler: &TunnelHandler, host: &str, port: u16) -> Response {
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_input_tx, input) = mpsc::channel(1);
+        let stream = RequestStream { input: Some(input), output: ResponseSink::new(1, tx) };
+        handler.handle_stream(Request::tcp_connect(host, port), stream).await.unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_tunnel_refuses_addresses_not_allowed() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        
+        // Nothing is allowed by default, and a pattern's port has to match
+        for handler in [TunnelHandler::new(), TunnelHandler::new().allow(format!("127.0.0.1:{}", port.wrapping_add(1)))] {
+            match tunnel_to(&handler, "127.0.0.1", port).await {
+                Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::PermissionDenied),
+                other => panic!("Expected Error, got {:?}", other),
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_tunnel_reports_refused_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        
+        let handler = TunnelHandler::new().allow("127.0.0.1");
+        match tunnel_to(&handler, "127.0.0.1", port).await {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::ConnectionRefused, "{}", error.message),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_limit_shared_by_process_and_pty_handlers() {
+        let limit = Arc::new(ProcessLimit::new(2));
+        let process_handler = Arc::new(ProcessHandler::new().with_process_limit(limit.clone()));
+        let pty_handler = PtyHandler::new().with_process_limit(limit.clone());
+        let pty_exec = || Request::pty_exec(vec!["true".to_string()], HashMap::new(), None, Some(10));
+        
+        // Two processes that run until their input ends take both slots
+        let mut inputs = Vec::new();
+        let mut running = Vec::new();
+        for _ in 0..2 {
+            let (input_tx, input) = mpsc::channel(1);
+            let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+            let stream = RequestStream { input: Some(input), output: ResponseSink::new(1, tx) };
+            let request = Request::process_exec(vec!["cat".to_string()], HashMap::new(), None, None, Some(10));
+            let handler = process_handler.clone();
+            running.push(tokio::spawn(async move { handler.handle_stream(request, stream).await.unwrap() }));
+            inputs.push(input_tx);
+        }
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while limit.available() > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        
+        // Neither handler starts another
+        match pty_handler.handle(pty_exec()).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::Overloaded),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        // A refused process leaves the file it would have written alone
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.log");
+        std::fs::write(&output, "earlier run\n").unwrap();
+        let mut request = Request::process_exec(vec!["true".to_string()], HashMap::new(), None, None, Some(10));
+        if let Request::ProcessExec { stdout_file, .. } = &mut request {
+            *stdout_file = Some(OutputFile::truncate(&output));
+        }
+        match process_handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::Overloaded),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "earlier run\n");
+        
+        // Nor a detached process
+        let mut request = Request::process_exec(vec!["true".to_string()], HashMap::new(), None, None, None);
+        if let Request::ProcessExec { detach, .. } = &mut request {
+            *detach = true;
+        }
+        match process_handler.handle(request).await.unwrap() {
+            Response::Error { error, .. } => assert_eq!(error.code, ErrorCode::Overloaded),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert_eq!(limit.available(), 0);
+        
+        // A process that exits frees its slot for the next
+        inputs.pop();
+        let response = running.pop().unwrap().await.unwrap();
+        assert!(matches!(response, Response::ProcessResult { exit_code: 0, .. }), "{:?}", response);
+        match pty_handler.handle(pty_exec()).await.unwrap() {
+            Response::PtyResult { exit_code, .. } => assert_eq!(exit_code, 0),
+            other => panic!("Expected PtyResult, got {:?}", other),
+        }
+        
+        drop(inputs);
+        for task in running {
+            task.await.unwrap();
+        }
+        assert_eq!(limit.available(), 2);
+    }
+    
     #[tokio::test]
     async fn test_pty_handler_basic_command() {
-        let handler = PtyHandler;
+        let handler = PtyHandler::new();
         
         // Use platform-appropriate echo command
         let command = if cfg!(windows) {
@@ -1557,14 +7400,7 @@ mod tests {
             vec!["echo".to_string(), "hello pty".to_string()]
         };
         
-        let request = Request::PtyExec {
-            id: Uuid::new_v4(),
-            command,
-            env: HashMap::new(),
-            cwd: None,
-            privilege: None,
-            timeout: Some(10),
-        };
+        let request = Request::pty_exec(command, HashMap::new(), None, Some(10));
         
         let response = handler.handle(request).await.unwrap();
         
@@ -1580,7 +7416,7 @@ mod tests {
     
     #[tokio::test]
     async fn test_pty_handler_sudo_command() {
-        let handler = PtyHandler;
+        let handler = PtyHandler::new();
         
         use mitoxide_proto::message::{PrivilegeEscalation, PrivilegeMethod, Credentials};
         
@@ -1596,14 +7432,10 @@ mod tests {
         // Use a simple command that should work with sudo
         let command = vec!["whoami".to_string()];
         
-        let request = Request::PtyExec {
-            id: Uuid::new_v4(),
-            command,
-            env: HashMap::new(),
-            cwd: None,
-            privilege: Some(privilege),
-            timeout: Some(10),
-        };
+        let mut request = Request::pty_exec(command, HashMap::new(), None, Some(10));
+        if let Request::PtyExec { privilege: escalation, .. } = &mut request {
+            *escalation = Some(privilege);
+        }
         
         let response = handler.handle(request).await.unwrap();
         
@@ -1621,25 +7453,9 @@ mod tests {
         }
     }
     
-    #[tokio::test]
-    async fn test_pty_handler_prompt_detection() {
-        let handler = PtyHandler;
-        
-        // Test default prompt patterns
-        assert!(handler.detect_privilege_prompt("Password:", &[]));
-        assert!(handler.detect_privilege_prompt("[sudo] password for user:", &[]));
-        assert!(handler.detect_privilege_prompt("su: password", &[]));
-        assert!(!handler.detect_privilege_prompt("normal output", &[]));
-        
-        // Test custom patterns
-        let custom_patterns = vec!["Enter passphrase:".to_string()];
-        assert!(handler.detect_privilege_prompt("Enter passphrase: ", &custom_patterns));
-        assert!(!handler.detect_privilege_prompt("Password:", &custom_patterns));
-    }
-    
     #[tokio::test]
     async fn test_pty_handler_build_privileged_command() {
-        let handler = PtyHandler;
+        let handler = PtyHandler::new();
         
         use mitoxide_proto::message::{PrivilegeEscalation, PrivilegeMethod, Credentials};
         
@@ -1699,16 +7515,9 @@ mod tests {
     
     #[tokio::test]
     async fn test_pty_handler_empty_command() {
-        let handler = PtyHandler;
+        let handler = PtyHandler::new();
         
-        let request = Request::PtyExec {
-            id: Uuid::new_v4(),
-            command: vec![],
-            env: HashMap::new(),
-            cwd: None,
-            privilege: None,
-            timeout: None,
-        };
+        let request = Request::pty_exec(vec![], HashMap::new(), None, None);
         
         let response = handler.handle(request).await.unwrap();
         
@@ -1731,14 +7540,7 @@ mod tests {
             vec!["echo".to_string()]
         };
         
-        let process_request = Request::ProcessExec {
-            id: Uuid::new_v4(),
-            command,
-            env: HashMap::new(),
-            cwd: None,
-            stdin: None,
-            timeout: None,
-        };
+        let process_request = Request::process_exec(command, HashMap::new(), None, None, None);
         
         let response = ping_handler.handle(process_request).await.unwrap();
         match response {
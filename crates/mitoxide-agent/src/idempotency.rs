@@ -0,0 +1,356 @@
+//! Responses remembered by idempotency key
+//!
+//! A request sent with `Request::WithIdempotencyKey` runs once per key. While
+//! it runs, requests repeating the key wait for its response; once it is done,
+//! they get the remembered response until it expires.
+//!
+//! Responses are kept in memory. An agent that exits forgets them unless they
+//! are saved with [`IdempotencyCache::save`] and loaded by the next one.
+
+use crate::agent::HandlerOutput;
+use crate::state;
+use mitoxide_proto::{Message, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Default time a response is remembered for its key
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(300);
+
+/// Default number of completed responses remembered
+pub const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 1024;
+
+/// A request repeating a key while the first one is still running
+#[derive(Debug)]
+pub(crate) struct Waiter {
+    /// Stream the repeated request arrived on
+    pub(crate) stream_id: u32,
+    /// Frame sequence number of the repeated request
+    pub(crate) sequence: u32,
+    /// Output queue of the connection the repeated request arrived on
+    pub(crate) tx: mpsc::UnboundedSender<HandlerOutput>,
+}
+
+/// What the agent knows about a key
+#[derive(Debug)]
+enum Entry {
+    /// The first request with the key is running
+    Running(Vec<Waiter>),
+    /// The request finished with this response
+    Done(Instant, Response),
+}
+
+/// A response as saved by [`IdempotencyCache::save`]
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedResponse {
+    /// Idempotency key
+    key: String,
+    /// When the request finished, in milliseconds since the Unix epoch
+    completed_ms: u64,
+    /// Final response of the request
+    response: Response,
+}
+
+/// How a request with an idempotency key should be handled
+#[derive(Debug)]
+pub(crate) enum Claim {
+    /// No request with the key is known, so this one runs
+    Run,
+    /// A request with the key already finished with this response
+    Done(Response),
+    /// A request with the key is running; the waiter gets its response
+    Waiting,
+}
+
+/// Remembers final responses by idempotency key so repeated requests run once
+///
+/// The cache can be shared between connections, so a resend over a new
+/// connection finds the response of a request the old one ran.
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    /// Entries by key
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Completed keys, oldest first, for expiry and the capacity limit
+    completed: Mutex<VecDeque<String>>,
+    /// How long a response is remembered
+    ttl: Duration,
+    /// Most completed responses remembered
+    capacity: usize,
+}
+
+impl IdempotencyCache {
+    /// Create a cache with the default TTL and capacity
+    pub fn new() -> Self {
+        Self::with_limits(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_IDEMPOTENCY_CAPACITY)
+    }
+    
+    /// Create a cache with a custom TTL and capacity
+    pub fn with_limits(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            completed: Mutex::new(VecDeque::new()),
+            ttl,
+            capacity,
+        }
+    }
+    
+    /// Number of keys remembered or running
+    pub fn len(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        self.purge(&mut entries, Instant::now());
+        entries.len()
+    }
+    
+    /// Whether no keys are remembered or running
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    /// Decide how a request with `key` is handled, registering `waiter` if it has to wait
+    pub(crate) fn claim(&self, key: &str, waiter: Waiter) -> Claim {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        self.purge(&mut entries, Instant::now());
+        
+        match entries.get_mut(key) {
+            Some(Entry::Done(_, response)) => {
+                debug!("Answering repeated idempotency key {} from cache", key);
+                Claim::Done(response.clone())
+            }
+            Some(Entry::Running(waiters)) => {
+                debug!("Idempotency key {} is running, waiting for its response", key);
+                waiters.push(waiter);
+                Claim::Waiting
+            }
+            None => {
+                entries.insert(key.to_string(), Entry::Running(Vec::new()));
+                Claim::Run
+            }
+        }
+    }
+    
+    /// Forget a key whose request was claimed but not run, such as one rejected as overloaded
+    ///
+    /// Requests waiting on the key get `response` too.
+    pub(crate) fn release(&self, key: &str, response: &Response) {
+        let entry = self.entries.lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+        if let Some(Entry::Running(waiters)) = entry {
+            answer(waiters, response);
+        }
+    }
+    
+    /// Remember the final response for `key` and pass it to any waiting requests
+    pub(crate) fn complete(&self, key: &str, response: &Response) {
+        let now = Instant::now();
+        let waiters = {
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            let previous = entries.insert(key.to_string(), Entry::Done(now, response.clone()));
+            let mut completed = self.completed.lock().unwrap_or_else(|e| e.into_inner());
+            completed.push_back(key.to_string());
+            while completed.len() > self.capacity {
+                if let Some(oldest) = completed.pop_front() {
+                    if matches!(entries.get(&oldest), Some(Entry::Done(..))) {
+                        entries.remove(&oldest);
+                    }
+                }
+            }
+            match previous {
+                Some(Entry::Running(waiters)) => waiters,
+                _ => Vec::new(),
+            }
+        };
+        answer(waiters, response);
+    }
+    
+    /// Save the remembered responses to `dir`, a file each, for an agent started later to [`load`](Self::load)
+    ///
+    /// Requests still running are not saved. Returns the number of responses saved.
+    pub fn save(&self, dir: &Path) -> io::Result<usize> {
+        std::fs::create_dir_all(dir)?;
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        self.purge(&mut entries, Instant::now());
+        
+        let mut saved = 0;
+        for (key, entry) in entries.iter() {
+            let Entry::Done(completed, response) = entry else {
+                continue;
+            };
+            let file = SavedResponse {
+                key: key.clone(),
+                completed_ms: state::to_unix_ms(*completed),
+                response: response.clone(),
+            };
+            // Keys are chosen by clients, so they don't name the file
+            let name = format!("{:x}.idempotency", Sha256::digest(key.as_bytes()));
+            state::save(dir, &name, &file)?;
+            saved += 1;
+        }
+        Ok(saved)
+    }
+    
+    /// Load the responses saved to `dir` that haven't expired, removing their files
+    ///
+    /// Keys this cache already knows keep their entry. Returns the number of
+    /// responses loaded.
+    pub fn load(&self, dir: &Path) -> io::Result<usize> {
+        let mut saved: Vec<SavedResponse> = state::take_all(dir, "idempotency")?;
+        saved.sort_by_key(|file| file.completed_ms);
+        
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut completed = self.completed.lock().unwrap_or_else(|e| e.into_inner());
+        let mut loaded = 0;
+        for file in saved {
+            let Some(finished) = state::from_unix_ms(file.completed_ms, self.ttl) else {
+                continue;
+            };
+            if entries.contains_key(&file.key) {
+                continue;
+            }
+            entries.insert(file.key.clone(), Entry::Done(finished, file.response));
+            completed.push_back(file.key);
+            loaded += 1;
+        }
+        while completed.len() > self.capacity {
+            if let Some(oldest) = completed.pop_front() {
+                if matches!(entries.get(&oldest), Some(Entry::Done(..))) {
+                    entries.remove(&oldest);
+                    loaded -= 1;
+                }
+            }
+        }
+        Ok(loaded)
+    }
+    
+    /// Drop responses older than the TTL
+    fn purge(&self, entries: &mut HashMap<String, Entry>, now: Instant) {
+        let mut completed = self.completed.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some(key) = completed.front() {
+            match entries.get(key) {
+                Some(Entry::Done(finished, _)) if now.duration_since(*finished) < self.ttl => break,
+                Some(Entry::Done(..)) => {
+                    entries.remove(key);
+                }
+                _ => {}
+            }
+            completed.pop_front();
+        }
+    }
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Send `response` to requests that waited for it
+///
+/// Waiters are not counted in flight, so their copies don't finish a request.
+fn answer(waiters: Vec<Waiter>, response: &Response) {
+    for waiter in waiters {
+        let _ = waiter.tx.send(HandlerOutput {
+            stream_id: waiter.stream_id,
+            sequence: waiter.sequence,
+            message: Message::response(response.clone()),
+            last: false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+    
+    fn waiter(tx: &mpsc::UnboundedSender<HandlerOutput>, stream_id: u32) -> Waiter {
+        Waiter { stream_id, sequence: 1, tx: tx.clone() }
+    }
+    
+    #[test]
+    fn test_repeated_key_waits_then_hits_cache() {
+        let cache = IdempotencyCache::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let request_id = Uuid::new_v4();
+        
+        assert!(matches!(cache.claim("put", waiter(&tx, 1)), Claim::Run));
+        assert!(matches!(cache.claim("put", waiter(&tx, 3)), Claim::Waiting));
+        
+        cache.complete("put", &Response::pong(request_id, 7));
+        let output = rx.try_recv().unwrap();
+        assert_eq!(output.stream_id, 3);
+        assert!(!output.last);
+        assert!(matches!(output.message, Message::Response(Response::Pong { timestamp: 7, .. })));
+        
+        match cache.claim("put", waiter(&tx, 5)) {
+            Claim::Done(response) => assert_eq!(response.request_id(), request_id),
+            other => panic!("Expected Done, got {:?}", other),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+    
+    #[test]
+    fn test_released_key_runs_again() {
+        let cache = IdempotencyCache::new();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        
+        assert!(matches!(cache.claim("put", waiter(&tx, 1)), Claim::Run));
+        cache.release("put", &Response::pong(Uuid::new_v4(), 0));
+        assert!(matches!(cache.claim("put", waiter(&tx, 3)), Claim::Run));
+    }
+    
+    #[test]
+    fn test_expiry_and_capacity() {
+        let cache = IdempotencyCache::with_limits(Duration::from_millis(10), 2);
+        let (tx, _rx) = mpsc::unbounded_channel();
+        for key in ["a", "b", "c"] {
+            cache.claim(key, waiter(&tx, 1));
+            cache.complete(key, &Response::pong(Uuid::new_v4(), 0));
+        }
+        
+        // The oldest response made way for the newest
+        assert_eq!(cache.len(), 2);
+        assert!(matches!(cache.claim("a", waiter(&tx, 1)), Claim::Run));
+        
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(matches!(cache.claim("b", waiter(&tx, 1)), Claim::Run));
+        // Running keys don't expire
+        assert_eq!(cache.len(), 2);
+    }
+    
+    #[test]
+    fn test_saved_responses_answer_repeats_in_another_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = IdempotencyCache::new();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let request_id = Uuid::new_v4();
+        cache.claim("put", waiter(&tx, 1));
+        cache.complete("put", &Response::pong(request_id, 7));
+        // Still running, so there is no response to save
+        cache.claim("get", waiter(&tx, 3));
+        assert_eq!(cache.save(dir.path()).unwrap(), 1);
+        
+        // As an agent started after this one exited would
+        let next = IdempotencyCache::new();
+        assert_eq!(next.load(dir.path()).unwrap(), 1);
+        match next.claim("put", waiter(&tx, 5)) {
+            Claim::Done(response) => assert_eq!(response.request_id(), request_id),
+            other => panic!("Expected Done, got {:?}", other),
+        }
+        assert!(matches!(next.claim("get", waiter(&tx, 7)), Claim::Run));
+    }
+    
+    #[test]
+    fn test_unsaved_responses_end_with_the_cache() {
+        let cache = IdempotencyCache::new();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        cache.claim("put", waiter(&tx, 1));
+        cache.complete("put", &Response::pong(Uuid::new_v4(), 0));
+        
+        assert!(matches!(IdempotencyCache::new().claim("put", waiter(&tx, 3)), Claim::Run));
+    }
+}
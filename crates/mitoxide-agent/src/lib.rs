@@ -10,8 +10,29 @@ pub mod agent;
 /// Request handlers for different operation types
 pub mod handlers;
 
+/// Audit records of privileged operations
+pub mod audit;
+
+/// Session resume tokens and retained responses
+pub mod resume;
+
+/// Responses remembered by idempotency key
+pub mod idempotency;
+
+/// Files that carry agent state over to agents started later
+mod state;
+
+/// Memory budget shared by request handlers
+pub mod memory;
+
+/// Limit on the processes request handlers run at once
+pub mod process_limit;
+
 /// Agent-side routing for multiplexed streams
 pub mod router;
 
 /// Agent bootstrap and platform detection
-pub mod bootstrap;
\ No newline at end of file
+pub mod bootstrap;
+
+/// Build information reported to clients
+pub mod version;
\ No newline at end of file
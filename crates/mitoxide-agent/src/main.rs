@@ -3,16 +3,19 @@
 //! The remote agent that executes operations on behalf of the client.
 
 use anyhow::Result;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::{info, error};
 
-mod agent;
-mod handlers;
-mod router;
-mod bootstrap;
-
-use agent::AgentLoop;
-use handlers::{ProcessHandler, FileHandler, PtyHandler, PingHandler, WasmHandler};
+use mitoxide_agent::agent::{AgentLoop, Handler, ShutdownReason, DEFAULT_KEEPALIVE_INTERVAL};
+use mitoxide_agent::audit::{self, AuditSink, JsonLinesAuditSink};
+use mitoxide_agent::handlers::{ProcessHandler, FileHandler, PtyHandler, PingHandler, PluginHandler, TunnelHandler, VersionHandler, WasmHandler, WasmUnavailableHandler, WASM_REQUEST_TYPES};
+use mitoxide_agent::idempotency::IdempotencyCache;
+use mitoxide_agent::memory::{MemoryBudget, DEFAULT_MEMORY_BUDGET};
+use mitoxide_agent::process_limit::{ProcessLimit, DEFAULT_MAX_PROCESSES};
+use mitoxide_agent::resume::ResumeStore;
+use mitoxide_proto::{CompressionDictionary, RateLimit};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -20,39 +23,266 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
-
+    
     info!("Starting Mitoxide agent");
-
+    
     // Create and run the agent loop
-    let mut agent = AgentLoop::new();
+    let mut agent = AgentLoop::new().with_keepalive_interval(DEFAULT_KEEPALIVE_INTERVAL);
+    
+    // Stream compression is primed with MITOXIDE_COMPRESSION_DICTIONARY for clients that have it too
+    if let Some(path) = std::env::var_os("MITOXIDE_COMPRESSION_DICTIONARY") {
+        match std::fs::read(&path) {
+            Ok(data) => {
+                let dictionary = CompressionDictionary::new(data);
+                info!("Loaded compression dictionary {} from {:?}", dictionary.id(), path);
+                agent = agent.with_compression_dictionary(dictionary);
+            }
+            Err(e) => {
+                error!("Failed to read compression dictionary {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    // File content from MITOXIDE_ATTACHMENT_THRESHOLD bytes up is sent as frame attachments,
+    // and none is with 0; a client's session may still raise it or turn attachments off
+    if let Some(threshold) = std::env::var("MITOXIDE_ATTACHMENT_THRESHOLD").ok().and_then(|threshold| threshold.parse().ok()) {
+        info!("Attachment threshold: {} bytes", threshold);
+        agent = agent.with_attachment_threshold((threshold > 0).then_some(threshold));
+    }
+    
+    // A client closing the session waits at most MITOXIDE_DRAIN_TIMEOUT_SECS for running requests
+    if let Some(secs) = std::env::var("MITOXIDE_DRAIN_TIMEOUT_SECS").ok().and_then(|secs| secs.parse().ok()) {
+        info!("Drain timeout: {} seconds", secs);
+        agent = agent.with_drain_timeout(Duration::from_secs(secs));
+    }
+    
+    // No one request gets more than MITOXIDE_MAX_RESPONSE_BYTES of responses if set
+    if let Some(limit) = std::env::var("MITOXIDE_MAX_RESPONSE_BYTES").ok().and_then(|limit| limit.parse().ok()) {
+        info!("Response limit: {} bytes per request", limit);
+        agent = agent.with_max_response_bytes(limit);
+    }
+    
+    // At most MITOXIDE_MAX_CONCURRENT_REQUESTS requests are handled at once if set, and up to
+    // MITOXIDE_REQUEST_QUEUE more wait for a slot by QoS class instead of being rejected
+    if let Some(max) = std::env::var("MITOXIDE_MAX_CONCURRENT_REQUESTS").ok().and_then(|max| max.parse().ok()) {
+        info!("Concurrency limit: {} requests", max);
+        agent = agent.with_max_concurrent_requests(max);
+    }
+    if let Some(capacity) = std::env::var("MITOXIDE_REQUEST_QUEUE").ok().and_then(|capacity| capacity.parse().ok()) {
+        info!("Request queue: {} requests", capacity);
+        agent = agent.with_request_queue(capacity);
+    }
+    
+    // Data responses are paced to MITOXIDE_SEND_RATE bytes per second per connection,
+    // and MITOXIDE_STREAM_SEND_RATE per request, if set
+    if let Some(rate) = std::env::var("MITOXIDE_SEND_RATE").ok().and_then(|rate| rate.parse().ok()) {
+        info!("Send rate limit: {} bytes per second", rate);
+        agent = agent.with_send_rate_limit(RateLimit::new(rate));
+    }
+    if let Some(rate) = std::env::var("MITOXIDE_STREAM_SEND_RATE").ok().and_then(|rate| rate.parse().ok()) {
+        info!("Send rate limit: {} bytes per second per request", rate);
+        agent = agent.with_stream_send_rate_limit(RateLimit::new(rate));
+    }
+    
+    // Sessions and idempotent responses outlive this agent in MITOXIDE_STATE_DIR if set,
+    // for the agent serving the client's next connection to load
+    let resume = Arc::new(ResumeStore::new());
+    let idempotency = Arc::new(IdempotencyCache::new());
+    let state_dir = std::env::var_os("MITOXIDE_STATE_DIR").map(PathBuf::from);
+    if let Some(dir) = &state_dir {
+        match resume.load(&dir.join("sessions")) {
+            Ok(count) => info!("Loaded {} sessions from {:?}", count, dir),
+            Err(e) => error!("Failed to load sessions from {:?}: {}", dir, e),
+        }
+        match idempotency.load(&dir.join("idempotency")) {
+            Ok(count) => info!("Loaded {} idempotent responses from {:?}", count, dir),
+            Err(e) => error!("Failed to load idempotent responses from {:?}: {}", dir, e),
+        }
+    }
+    agent = agent.with_resume_store(resume.clone()).with_idempotency_cache(idempotency.clone());
+    
+    // Privileged commands and file writes are audited to MITOXIDE_AUDIT_LOG if set
+    let audit_sink: Arc<dyn AuditSink> = match std::env::var_os("MITOXIDE_AUDIT_LOG") {
+        Some(path) => match JsonLinesAuditSink::open(&path) {
+            Ok(sink) => {
+                info!("Writing audit log to {:?}", path);
+                Arc::new(sink)
+            }
+            Err(e) => {
+                error!("Failed to open audit log {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => audit::default_sink(),
+    };
+    
+    // Large buffers of all requests share MITOXIDE_MEMORY_BUDGET bytes
+    let memory_budget = std::env::var("MITOXIDE_MEMORY_BUDGET").ok()
+        .and_then(|budget| budget.parse().ok())
+        .unwrap_or(DEFAULT_MEMORY_BUDGET);
+    info!("Memory budget: {} bytes", memory_budget);
+    let memory_budget = Arc::new(MemoryBudget::new(memory_budget));
+    
+    // Processes and PTYs together run at most MITOXIDE_MAX_PROCESSES at once,
+    // detached processes included
+    let max_processes = std::env::var("MITOXIDE_MAX_PROCESSES").ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_PROCESSES);
+    info!("Process limit: {}", max_processes);
+    let process_limit = Arc::new(ProcessLimit::new(max_processes));
     
     // Register handlers
-    agent.register_handler("process_exec".to_string(), Arc::new(ProcessHandler)).await;
-    agent.register_handler("file_get".to_string(), Arc::new(FileHandler)).await;
-    agent.register_handler("file_put".to_string(), Arc::new(FileHandler)).await;
-    agent.register_handler("dir_list".to_string(), Arc::new(FileHandler)).await;
-    agent.register_handler("pty_exec".to_string(), Arc::new(PtyHandler)).await;
+    let process_handler = Arc::new(ProcessHandler::new()
+        .with_memory_budget(memory_budget.clone())
+        .with_process_limit(process_limit.clone()));
+    agent.register_handler("process_exec".to_string(), process_handler.clone()).await;
+    agent.register_handler("process_signal".to_string(), process_handler).await;
+    let mut file_handler = FileHandler::new()
+        .with_audit_sink(audit_sink.clone())
+        .with_memory_budget(memory_budget.clone());
+    // Files and directories created without a mode also have the octal MITOXIDE_UMASK masked out if set
+    if let Some(umask) = std::env::var("MITOXIDE_UMASK").ok().and_then(|umask| u32::from_str_radix(&umask, 8).ok()) {
+        info!("File umask: {:03o}", umask);
+        file_handler = file_handler.with_umask(umask);
+    }
+    let file_handler = Arc::new(file_handler);
+    agent.register_handler("file_get".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_put".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_patch_text".to_string(), file_handler.clone()).await;
+    agent.register_handler("dir_list".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_tail".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_hash".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_xattr_get".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_xattr_set".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_chown".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_ensure".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_signature".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_delta_put".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_set_attributes".to_string(), file_handler.clone()).await;
+    agent.register_handler("file_remove".to_string(), file_handler.clone()).await;
+    agent.register_handler("disk_space".to_string(), file_handler).await;
+    let pty_handler = PtyHandler::new()
+        .with_audit_sink(audit_sink)
+        .with_process_limit(process_limit);
+    agent.register_handler("pty_exec".to_string(), Arc::new(pty_handler)).await;
     agent.register_handler("ping".to_string(), Arc::new(PingHandler)).await;
+    agent.register_handler("ping_batch".to_string(), Arc::new(PingHandler)).await;
+    agent.register_handler("version".to_string(), Arc::new(VersionHandler)).await;
+    
+    // Tunnels may reach the comma-separated host patterns in MITOXIDE_TUNNEL_ALLOW, and nothing else
+    let mut tunnel_handler = TunnelHandler::new();
+    if let Ok(allow) = std::env::var("MITOXIDE_TUNNEL_ALLOW") {
+        for pattern in allow.split(',').map(str::trim).filter(|pattern| !pattern.is_empty()) {
+            tunnel_handler = tunnel_handler.allow(pattern);
+        }
+        info!("Tunnels allowed to: {}", allow);
+    }
+    agent.register_handler("tcp_connect".to_string(), Arc::new(tunnel_handler)).await;
     
-    // Register WASM handler
-    match WasmHandler::new() {
-        Ok(wasm_handler) => {
-            agent.register_handler("wasm_exec".to_string(), Arc::new(wasm_handler)).await;
+    // WASM modules in MITOXIDE_PLUGIN_DIR are called as JSON methods named after them
+    if let Some(dir) = std::env::var_os("MITOXIDE_PLUGIN_DIR") {
+        match PluginHandler::load_dir(dir.as_ref()) {
+            Ok(plugins) => {
+                info!("Loaded plugins from {:?}: {:?}", dir, plugins.names());
+                let plugins = plugins.with_memory_budget(memory_budget.clone());
+                agent.register_handler("json_call".to_string(), Arc::new(plugins)).await;
+            }
+            Err(e) => {
+                error!("Failed to load plugins: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    // Register WASM handler; MITOXIDE_WASM_ALLOW_NETWORK lets modules make HTTP(S)
+    // requests to the hosts each request allows, run through MITOXIDE_WASM_CURL if
+    // set and the curl on PATH otherwise
+    let wasm_config = mitoxide_wasm::WasmConfig {
+        allow_network: std::env::var_os("MITOXIDE_WASM_ALLOW_NETWORK").is_some(),
+        ..Default::default()
+    };
+    let http_client = match std::env::var_os("MITOXIDE_WASM_CURL") {
+        Some(program) => mitoxide_wasm::CurlHttpClient::with_program(program),
+        None => mitoxide_wasm::CurlHttpClient::new(),
+    };
+    let wasm_handler: Arc<dyn Handler> = match mitoxide_wasm::WasmRuntime::with_config(wasm_config) {
+        Ok(runtime) => {
             info!("WASM handler registered successfully");
+            let runtime = runtime.with_http_client(Arc::new(http_client));
+            Arc::new(WasmHandler::with_runtime(runtime).with_memory_budget(memory_budget))
         }
         Err(e) => {
             error!("Failed to create WASM handler: {}", e);
-            // Continue without WASM support
+            // Continue without WASM support, telling clients why
+            Arc::new(WasmUnavailableHandler::new(e.to_string()))
         }
+    };
+    for request_type in WASM_REQUEST_TYPES {
+        agent.register_handler(request_type.to_string(), wasm_handler.clone()).await;
+    }
+    
+    // SIGTERM and SIGINT stop the loop, which reports the signal in the exit code
+    if let Some(shutdown_tx) = agent.shutdown_sender() {
+        tokio::spawn(async move {
+            match termination_signal().await {
+                Ok(signal) => {
+                    let _ = shutdown_tx.send(ShutdownReason::Signal(signal));
+                }
+                Err(e) => {
+                    error!("Failed to listen for signals: {}", e);
+                    // Dropping the sender would stop the loop
+                    std::future::pending::<()>().await;
+                }
+            }
+        });
     }
     
     info!("All handlers registered, starting agent loop");
     
-    if let Err(e) = agent.run().await {
-        error!("Agent error: {}", e);
-        std::process::exit(1);
+    let result = agent.run().await;
+    
+    if let Some(dir) = &state_dir {
+        if let Err(e) = resume.save(&dir.join("sessions")) {
+            error!("Failed to save sessions to {:?}: {}", dir, e);
+        }
+        if let Err(e) = idempotency.save(&dir.join("idempotency")) {
+            error!("Failed to save idempotent responses to {:?}: {}", dir, e);
+        }
+    }
+    
+    let reason = match result {
+        Ok(reason) => reason,
+        Err(e) => {
+            error!("Agent error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    info!("Agent shutting down: {}", reason);
+    if reason.exit_code() != 0 {
+        std::process::exit(reason.exit_code());
     }
-
-    info!("Agent shutting down");
     Ok(())
+}
+
+/// Wait for SIGTERM or SIGINT, returning its number
+#[cfg(unix)]
+async fn termination_signal() -> std::io::Result<i32> {
+    use nix::sys::signal::Signal;
+    use tokio::signal::unix::{signal, SignalKind};
+    
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok(Signal::SIGTERM as i32),
+        _ = interrupt.recv() => Ok(Signal::SIGINT as i32),
+    }
+}
+
+/// Wait for Ctrl-C, returning the number of SIGINT
+#[cfg(not(unix))]
+async fn termination_signal() -> std::io::Result<i32> {
+    tokio::signal::ctrl_c().await?;
+    Ok(2)
 }
\ No newline at end of file
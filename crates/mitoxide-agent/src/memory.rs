@@ -0,0 +1,209 @@
+//! Memory shared by the requests an agent handles
+//!
+//! Each request is bounded on its own, but enough large ones at once can still
+//! run the agent out of memory. Handlers given a [`MemoryBudget`] reserve what
+//! they are about to buffer and release it when they are done. A reservation
+//! that doesn't fit waits for memory to be released, and fails with
+//! [`OverBudget`] (`ErrorCode::Overloaded`) if none is released in time.
+
+use mitoxide_proto::message::{ErrorCode, ErrorDetails};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Default memory budget of the agent binary
+pub const DEFAULT_MEMORY_BUDGET: u64 = 1024 * 1024 * 1024;
+
+/// Default time a reservation waits for memory before failing
+pub const DEFAULT_MEMORY_WAIT: Duration = Duration::from_secs(5);
+
+/// Bytes per semaphore permit, so budgets beyond what a permit count can hold still fit
+const UNIT: u64 = 1024;
+
+/// Permits needed to cover `bytes`
+fn units(bytes: u64) -> u64 {
+    bytes.div_ceil(UNIT)
+}
+
+/// Bytes of memory shared by all handlers that are given the budget
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// One permit per [`UNIT`] of memory not reserved
+    semaphore: Arc<Semaphore>,
+    /// Permits in the whole budget
+    total_units: u64,
+    /// How long a reservation waits for memory
+    wait: Duration,
+}
+
+impl MemoryBudget {
+    /// Create a budget of `total` bytes
+    pub fn new(total: u64) -> Self {
+        let total_units = units(total).min(u32::MAX as u64);
+        Self {
+            semaphore: Arc::new(Semaphore::new(total_units as usize)),
+            total_units,
+            wait: DEFAULT_MEMORY_WAIT,
+        }
+    }
+    
+    /// Wait at most `wait` for memory before failing a reservation
+    pub fn with_wait(mut self, wait: Duration) -> Self {
+        self.wait = wait;
+        self
+    }
+    
+    /// Bytes in the whole budget
+    pub fn total(&self) -> u64 {
+        self.total_units * UNIT
+    }
+    
+    /// Bytes not currently reserved
+    pub fn available(&self) -> u64 {
+        self.semaphore.available_permits() as u64 * UNIT
+    }
+    
+    /// Reserve `bytes`, waiting for other reservations to be released if they don't fit yet
+    pub async fn reserve(&self, bytes: u64) -> Result<MemoryReservation, OverBudget> {
+        let over_budget = OverBudget { requested: bytes, total: self.total() };
+        let needed = units(bytes);
+        if needed > self.total_units {
+            return Err(over_budget);
+        }
+        
+        let acquire = Arc::clone(&self.semaphore).acquire_many_owned(needed as u32);
+        match tokio::time::timeout(self.wait, acquire).await {
+            Ok(Ok(permit)) => Ok(MemoryReservation {
+                semaphore: Arc::clone(&self.semaphore),
+                permit: Some(permit),
+                bytes,
+            }),
+            _ => Err(over_budget),
+        }
+    }
+    
+    /// An empty reservation to grow as memory is used
+    pub fn reservation(&self) -> MemoryReservation {
+        MemoryReservation {
+            semaphore: Arc::clone(&self.semaphore),
+            permit: None,
+            bytes: 0,
+        }
+    }
+}
+
+/// Memory reserved from a [`MemoryBudget`], released when dropped
+#[derive(Debug)]
+pub struct MemoryReservation {
+    /// Semaphore of the budget the memory came from
+    semaphore: Arc<Semaphore>,
+    /// Permits held, if any
+    permit: Option<OwnedSemaphorePermit>,
+    /// Bytes reserved
+    bytes: u64,
+}
+
+impl MemoryReservation {
+    /// Bytes reserved
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+    
+    /// Reserve `bytes` more if the budget has them now, without waiting
+    pub fn try_grow(&mut self, bytes: u64) -> bool {
+        let held = units(self.bytes);
+        let needed = units(self.bytes.saturating_add(bytes)) - held;
+        if needed > 0 {
+            let Ok(needed) = u32::try_from(needed) else {
+                return false;
+            };
+            let Ok(permit) = Arc::clone(&self.semaphore).try_acquire_many_owned(needed) else {
+                return false;
+            };
+            match &mut self.permit {
+                Some(held) => held.merge(permit),
+                None => self.permit = Some(permit),
+            }
+        }
+        self.bytes += bytes;
+        true
+    }
+}
+
+/// A reservation that the memory budget can't fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverBudget {
+    /// Bytes asked for
+    pub requested: u64,
+    /// Bytes in the whole budget
+    pub total: u64,
+}
+
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.requested > self.total {
+            write!(f, "Request needs {} bytes, more than the agent's memory budget of {}", self.requested, self.total)
+        } else {
+            write!(f, "Agent memory budget of {} bytes is in use, {} more can't be reserved", self.total, self.requested)
+        }
+    }
+}
+
+impl std::error::Error for OverBudget {}
+
+impl From<OverBudget> for ErrorDetails {
+    fn from(error: OverBudget) -> Self {
+        ErrorDetails::new(ErrorCode::Overloaded, error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn test_reservation_waits_for_release() {
+        let budget = MemoryBudget::new(4 * UNIT).with_wait(Duration::from_millis(200));
+        let held = budget.reserve(3 * UNIT).await.unwrap();
+        assert_eq!(budget.available(), UNIT);
+        
+        let error = budget.reserve(2 * UNIT).await.unwrap_err();
+        assert_eq!(error, OverBudget { requested: 2 * UNIT, total: 4 * UNIT });
+        assert_eq!(ErrorDetails::from(error).code, ErrorCode::Overloaded);
+        
+        // A waiting reservation gets memory as soon as it is released
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        });
+        let reservation = budget.reserve(2 * UNIT).await.unwrap();
+        release.await.unwrap();
+        assert_eq!(reservation.bytes(), 2 * UNIT);
+        assert_eq!(budget.available(), 2 * UNIT);
+    }
+    
+    #[tokio::test]
+    async fn test_larger_than_budget_fails_immediately() {
+        let budget = MemoryBudget::new(UNIT).with_wait(Duration::from_secs(60));
+        let error = tokio::time::timeout(Duration::from_secs(1), budget.reserve(2 * UNIT)).await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("more than the agent's memory budget"));
+    }
+    
+    #[test]
+    fn test_reservation_grows_until_exhausted() {
+        let budget = MemoryBudget::new(2 * UNIT);
+        let mut reservation = budget.reservation();
+        
+        // Growth within a held unit needs no more permits
+        assert!(reservation.try_grow(10));
+        assert!(reservation.try_grow(UNIT - 10));
+        assert_eq!(budget.available(), UNIT);
+        assert!(reservation.try_grow(UNIT));
+        assert!(!reservation.try_grow(1));
+        assert_eq!(reservation.bytes(), 2 * UNIT);
+        
+        drop(reservation);
+        assert_eq!(budget.available(), 2 * UNIT);
+    }
+}
@@ -0,0 +1,99 @@
+//! Processes shared by the handlers that start them
+//!
+//! Each process a client starts holds agent resources until it exits, and a
+//! client starting them faster than they finish can exhaust the host. Handlers
+//! given a [`ProcessLimit`] take a [`ProcessSlot`] before starting a process
+//! and hold it until the process exits. With every slot taken, starting
+//! another fails straight away with [`TooManyProcesses`]
+//! (`ErrorCode::Overloaded`) rather than waiting. Detached processes take a
+//! slot too, held past the request that started them until they are reaped.
+
+use mitoxide_proto::message::{ErrorCode, ErrorDetails};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Default limit on the processes the agent binary runs at once
+pub const DEFAULT_MAX_PROCESSES: usize = 256;
+
+/// Processes that may run at once across all handlers given the limit
+#[derive(Debug)]
+pub struct ProcessLimit {
+    /// One permit per process that may still start
+    semaphore: Arc<Semaphore>,
+    /// Processes that may run at once
+    max: usize,
+}
+
+impl ProcessLimit {
+    /// Allow at most `max` processes at once
+    pub fn new(max: usize) -> Self {
+        Self { semaphore: Arc::new(Semaphore::new(max)), max }
+    }
+    
+    /// Processes that may run at once
+    pub fn max(&self) -> usize {
+        self.max
+    }
+    
+    /// Processes that may still start
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+    
+    /// Take a slot for a process about to start, if one is free
+    pub fn try_acquire(&self) -> Result<ProcessSlot, TooManyProcesses> {
+        Arc::clone(&self.semaphore).try_acquire_owned()
+            .map(|permit| ProcessSlot { _permit: permit })
+            .map_err(|_| TooManyProcesses { max: self.max })
+    }
+}
+
+/// A process's share of a [`ProcessLimit`], freed when dropped
+#[derive(Debug)]
+pub struct ProcessSlot {
+    /// Permit held while the process runs
+    _permit: OwnedSemaphorePermit,
+}
+
+/// A process that can't start because the limit's slots are all taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyProcesses {
+    /// Processes that may run at once
+    pub max: usize,
+}
+
+impl fmt::Display for TooManyProcesses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Agent is already running its limit of {} processes", self.max)
+    }
+}
+
+impl std::error::Error for TooManyProcesses {}
+
+impl From<TooManyProcesses> for ErrorDetails {
+    fn from(error: TooManyProcesses) -> Self {
+        ErrorDetails::new(ErrorCode::Overloaded, error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_slots_are_freed_when_dropped() {
+        let limit = ProcessLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert_eq!(limit.available(), 0);
+        
+        let error = limit.try_acquire().unwrap_err();
+        assert_eq!(error, TooManyProcesses { max: 2 });
+        assert_eq!(ErrorDetails::from(error).code, ErrorCode::Overloaded);
+        
+        drop(first);
+        assert_eq!(limit.available(), 1);
+        limit.try_acquire().unwrap();
+    }
+}
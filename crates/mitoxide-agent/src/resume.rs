@@ -0,0 +1,312 @@
+//! Session resume tokens and retained responses
+
+use crate::state;
+use mitoxide_proto::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+use uuid::Uuid;
+
+/// Default time a disconnected session stays resumable
+pub const DEFAULT_RESUME_TTL: Duration = Duration::from_secs(60);
+
+/// Default number of completed responses retained per session
+pub const DEFAULT_RETAINED_RESPONSES: usize = 256;
+
+/// Recently completed responses of one session
+#[derive(Debug)]
+struct RetainedSession {
+    /// Last time the session was opened or completed a request
+    last_active: Instant,
+    /// Final responses, oldest first
+    responses: VecDeque<(Instant, Response)>,
+    /// Working directory set with `Chdir`
+    cwd: Option<PathBuf>,
+}
+
+/// A session as saved by [`ResumeStore::save`], with times in milliseconds since the Unix epoch
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedSession {
+    /// Resume token
+    token: Uuid,
+    /// Last time the session was opened or completed a request
+    last_active_ms: u64,
+    /// Final responses with their completion times, oldest first
+    responses: Vec<(u64, Response)>,
+    /// Working directory set with `Chdir`
+    cwd: Option<PathBuf>,
+}
+
+/// Keeps completed responses of agent sessions so a client that reconnects
+/// with its resume token can collect the ones it missed
+///
+/// The store is shared between connections; each `AgentLoop` only records
+/// responses for the session opened on it. It lives in memory, so sessions
+/// last only as long as the agent process unless they are carried over to
+/// the next one with [`save`](Self::save) and [`load`](Self::load).
+#[derive(Debug)]
+pub struct ResumeStore {
+    /// Sessions by resume token
+    sessions: Mutex<HashMap<Uuid, RetainedSession>>,
+    /// How long a session and its responses are kept
+    ttl: Duration,
+    /// Maximum responses retained per session
+    capacity: usize,
+}
+
+impl ResumeStore {
+    /// Create a store with the default TTL and capacity
+    pub fn new() -> Self {
+        Self::with_limits(DEFAULT_RESUME_TTL, DEFAULT_RETAINED_RESPONSES)
+    }
+    
+    /// Create a store with a custom TTL and per-session capacity
+    pub fn with_limits(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            ttl,
+            capacity,
+        }
+    }
+    
+    /// Open a session, resuming the one for `resume_token` if it is still alive
+    ///
+    /// Returns the session token, whether it was resumed, and the responses
+    /// retained for it. Resuming hands the retained responses over to the caller.
+    pub fn open(&self, resume_token: Option<Uuid>) -> (Uuid, bool, Vec<Response>) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        self.purge(&mut sessions, now);
+        
+        if let Some(token) = resume_token {
+            if let Some(session) = sessions.get_mut(&token) {
+                session.last_active = now;
+                let responses = session.responses.drain(..).map(|(_, response)| response).collect();
+                debug!("Resumed session {}", token);
+                return (token, true, responses);
+            }
+            debug!("Resume token {} is unknown or expired", token);
+        }
+        
+        let token = Uuid::new_v4();
+        sessions.insert(token, RetainedSession {
+            last_active: now,
+            responses: VecDeque::new(),
+            cwd: None,
+        });
+        (token, false, Vec::new())
+    }
+    
+    /// Retain a completed response for a session
+    pub fn retain(&self, token: Uuid, response: Response) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(session) = sessions.get_mut(&token) {
+            session.last_active = now;
+            if session.responses.len() >= self.capacity {
+                session.responses.pop_front();
+            }
+            session.responses.push_back((now, response));
+        }
+    }
+    
+    /// Remember a session's working directory so it survives reconnects
+    pub fn set_cwd(&self, token: Uuid, cwd: PathBuf) {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(session) = sessions.get_mut(&token) {
+            session.last_active = Instant::now();
+            session.cwd = Some(cwd);
+        }
+    }
+    
+    /// Working directory of a session, if it changed one
+    pub fn cwd(&self, token: Uuid) -> Option<PathBuf> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.get(&token).and_then(|session| session.cwd.clone())
+    }
+    
+    /// Save the live sessions to `dir`, a file each, for an agent started later to [`load`](Self::load)
+    ///
+    /// Returns the number of sessions saved.
+    pub fn save(&self, dir: &Path) -> io::Result<usize> {
+        std::fs::create_dir_all(dir)?;
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        self.purge(&mut sessions, Instant::now());
+        
+        for (token, session) in sessions.iter() {
+            let saved = SavedSession {
+                token: *token,
+                last_active_ms: state::to_unix_ms(session.last_active),
+                responses: session.responses.iter()
+                    .map(|(completed, response)| (state::to_unix_ms(*completed), response.clone()))
+                    .collect(),
+                cwd: session.cwd.clone(),
+            };
+            state::save(dir, &format!("{}.session", token), &saved)?;
+        }
+        Ok(sessions.len())
+    }
+    
+    /// Load the sessions saved to `dir` that haven't expired, removing their files
+    ///
+    /// Each saved session is loaded by one agent only. Returns the number of
+    /// sessions loaded.
+    pub fn load(&self, dir: &Path) -> io::Result<usize> {
+        let saved: Vec<SavedSession> = state::take_all(dir, "session")?;
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let before = sessions.len();
+        for session in saved {
+            let Some(last_active) = state::from_unix_ms(session.last_active_ms, self.ttl) else {
+                continue;
+            };
+            let responses = session.responses.into_iter()
+                .filter_map(|(completed, response)| Some((state::from_unix_ms(completed, self.ttl)?, response)))
+                .collect();
+            sessions.insert(session.token, RetainedSession { last_active, responses, cwd: session.cwd });
+        }
+        Ok(sessions.len() - before)
+    }
+    
+    /// Number of live sessions
+    pub fn session_count(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        self.purge(&mut sessions, Instant::now());
+        sessions.len()
+    }
+    
+    /// Drop expired sessions and responses
+    fn purge(&self, sessions: &mut HashMap<Uuid, RetainedSession>, now: Instant) {
+        sessions.retain(|_, session| now.duration_since(session.last_active) < self.ttl);
+        for session in sessions.values_mut() {
+            while let Some((completed, _)) = session.responses.front() {
+                if now.duration_since(*completed) < self.ttl {
+                    break;
+                }
+                session.responses.pop_front();
+            }
+        }
+    }
+}
+
+impl Default for ResumeStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_resume_returns_retained_responses() {
+        let store = ResumeStore::new();
+        let (token, resumed, responses) = store.open(None);
+        assert!(!resumed);
+        assert!(responses.is_empty());
+        
+        let request_id = Uuid::new_v4();
+        store.retain(token, Response::pong(request_id, 1));
+        
+        let (resumed_token, resumed, responses) = store.open(Some(token));
+        assert_eq!(resumed_token, token);
+        assert!(resumed);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].request_id(), request_id);
+        
+        // Responses are handed over once
+        let (_, _, responses) = store.open(Some(token));
+        assert!(responses.is_empty());
+    }
+    
+    #[test]
+    fn test_expired_session_is_not_resumed() {
+        let store = ResumeStore::with_limits(Duration::from_millis(10), 8);
+        let (token, _, _) = store.open(None);
+        store.retain(token, Response::pong(Uuid::new_v4(), 1));
+        
+        std::thread::sleep(Duration::from_millis(20));
+        
+        let (new_token, resumed, responses) = store.open(Some(token));
+        assert_ne!(new_token, token);
+        assert!(!resumed);
+        assert!(responses.is_empty());
+        assert_eq!(store.session_count(), 1);
+    }
+    
+    #[test]
+    fn test_cwd_survives_resume() {
+        let store = ResumeStore::new();
+        let (token, _, _) = store.open(None);
+        assert_eq!(store.cwd(token), None);
+        
+        store.set_cwd(token, PathBuf::from("/tmp"));
+        let (token, resumed, _) = store.open(Some(token));
+        assert!(resumed);
+        assert_eq!(store.cwd(token), Some(PathBuf::from("/tmp")));
+    }
+    
+    #[test]
+    fn test_saved_sessions_resume_in_another_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ResumeStore::new();
+        let (token, _, _) = store.open(None);
+        let request_id = Uuid::new_v4();
+        store.retain(token, Response::pong(request_id, 1));
+        store.set_cwd(token, PathBuf::from("/srv/app"));
+        assert_eq!(store.save(dir.path()).unwrap(), 1);
+        
+        // As an agent started after this one exited would
+        let next = ResumeStore::new();
+        assert_eq!(next.load(dir.path()).unwrap(), 1);
+        assert_eq!(next.cwd(token), Some(PathBuf::from("/srv/app")));
+        let (resumed_token, resumed, responses) = next.open(Some(token));
+        assert_eq!(resumed_token, token);
+        assert!(resumed);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].request_id(), request_id);
+        
+        // Only one agent loads a saved session
+        assert_eq!(ResumeStore::new().load(dir.path()).unwrap(), 0);
+    }
+    
+    #[test]
+    fn test_unsaved_sessions_end_with_the_store() {
+        let store = ResumeStore::new();
+        let (token, _, _) = store.open(None);
+        store.retain(token, Response::pong(Uuid::new_v4(), 1));
+        
+        let (_, resumed, responses) = ResumeStore::new().open(Some(token));
+        assert!(!resumed);
+        assert!(responses.is_empty());
+    }
+    
+    #[test]
+    fn test_expired_saved_sessions_are_not_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ResumeStore::with_limits(Duration::from_millis(50), 8);
+        store.open(None);
+        store.save(dir.path()).unwrap();
+        
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(ResumeStore::with_limits(Duration::from_millis(50), 8).load(dir.path()).unwrap(), 0);
+    }
+    
+    #[test]
+    fn test_capacity_drops_oldest() {
+        let store = ResumeStore::with_limits(DEFAULT_RESUME_TTL, 2);
+        let (token, _, _) = store.open(None);
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for id in &ids {
+            store.retain(token, Response::pong(*id, 1));
+        }
+        
+        let (_, _, responses) = store.open(Some(token));
+        let kept: Vec<Uuid> = responses.iter().map(Response::request_id).collect();
+        assert_eq!(kept, ids[1..]);
+    }
+}
@@ -1,26 +1,21 @@
 //! Agent-side routing for multiplexed streams
 
-use crate::agent::Handler;
+use crate::agent::{request_type, run_batch, Handler};
 use anyhow::{Context, Result};
 use bytes::Bytes;
 use mitoxide_proto::{Frame, FrameCodec, Message, Request, Response};
-use mitoxide_proto::message::{ErrorCode, ErrorDetails};
+use mitoxide_proto::message::{ErrorCode, ErrorDetails, DEFAULT_ATTACHMENT_THRESHOLD};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::io::AsyncWrite;
 use tokio::sync::{mpsc, oneshot, RwLock};
 use tracing::{debug, error, info, warn};
-use uuid::Uuid;
 
 /// Stream information for tracking active streams
 #[derive(Debug, Clone)]
 struct StreamInfo {
-    /// Stream ID
-    stream_id: u32,
     /// Current sequence number
     sequence: u32,
-    /// Request ID being processed
-    request_id: Option<Uuid>,
 }
 
 /// Agent-side router for handling multiplexed streams and request dispatch
@@ -42,6 +37,8 @@ where
     request_rx: Option<mpsc::UnboundedReceiver<(u32, u32, Request)>>,
     /// Shutdown signal
     shutdown_tx: Option<oneshot::Sender<()>>,
+    /// Size from which file content is sent as a frame attachment
+    attachment_threshold: Option<usize>,
 }
 
 impl<W> AgentRouter<W>
@@ -61,9 +58,16 @@ where
             request_tx,
             request_rx: Some(request_rx),
             shutdown_tx: Some(shutdown_tx),
+            attachment_threshold: Some(DEFAULT_ATTACHMENT_THRESHOLD),
         }
     }
     
+    /// Send file content of at least `threshold` bytes as frame attachments, or none with None
+    pub fn with_attachment_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.attachment_threshold = threshold;
+        self
+    }
+    
     /// Register a handler for a specific request type
     pub async fn register_handler(&self, request_type: String, handler: Arc<dyn Handler>) {
         let mut handlers = self.handlers.write().await;
@@ -98,11 +102,12 @@ where
         self.update_stream_info(frame.stream_id, frame.sequence).await;
         
         // Deserialize message from frame payload
-        let message = match rmp_serde::from_slice::<Message>(&frame.payload) {
+        let (stream_id, sequence) = (frame.stream_id, frame.sequence);
+        let message = match Message::from_frame(frame) {
             Ok(msg) => msg,
             Err(e) => {
                 error!("Failed to deserialize message: {}", e);
-                self.send_error_frame(frame.stream_id, frame.sequence, 
+                self.send_error_frame(stream_id, sequence, 
                                     ErrorCode::InvalidRequest, 
                                     format!("Invalid message format: {}", e)).await?;
                 return Ok(());
@@ -113,13 +118,16 @@ where
         match message {
             Message::Request(request) => {
                 // Send request for processing
-                if let Err(e) = self.request_tx.send((frame.stream_id, frame.sequence, request)) {
+                if let Err(e) = self.request_tx.send((stream_id, sequence, request)) {
                     error!("Failed to send request for processing: {}", e);
                 }
             }
             Message::Response(_) => {
                 warn!("Received unexpected response message on agent router");
             }
+            Message::Event(_) => {
+                warn!("Received unexpected event message on agent router");
+            }
         }
         
         Ok(())
@@ -132,6 +140,7 @@ where
         
         let handlers = Arc::clone(&self.handlers);
         let writer = Arc::clone(&self.writer);
+        let attachment_threshold = self.attachment_threshold;
         
         info!("Starting request processing loop");
         
@@ -144,7 +153,7 @@ where
                 let response = Self::process_request(request, &handlers).await;
                 let codec = FrameCodec::new(); // Create new codec instance
                 
-                if let Err(e) = Self::send_response(stream_id, sequence, response, &writer, &codec).await {
+                if let Err(e) = Self::send_response(stream_id, sequence, response, attachment_threshold, &writer, &codec).await {
                     error!("Failed to send response: {}", e);
                 }
             });
@@ -156,20 +165,16 @@ where
     
     /// Process a single request using registered handlers
     async fn process_request(request: Request, handlers: &Arc<RwLock<HashMap<String, Arc<dyn Handler>>>>) -> Response {
+        let request = request.into_inner();
         let request_id = request.id();
         debug!("Processing request: id={}, type={:?}", request_id, std::mem::discriminant(&request));
         
+        if let Request::Batch { id, requests, stop_on_error } = request {
+            return run_batch(handlers, id, requests, stop_on_error).await;
+        }
+        
         // Determine request type for handler lookup
-        let request_type = match &request {
-            Request::ProcessExec { .. } => "process_exec",
-            Request::FileGet { .. } => "file_get",
-            Request::FilePut { .. } => "file_put",
-            Request::DirList { .. } => "dir_list",
-            Request::WasmExec { .. } => "wasm_exec",
-            Request::JsonCall { .. } => "json_call",
-            Request::Ping { .. } => "ping",
-            Request::PtyExec { .. } => "pty_exec",
-        };
+        let request_type = request_type(&request);
         
         // Look up handler
         let handler = {
@@ -206,15 +211,14 @@ where
         stream_id: u32, 
         sequence: u32, 
         response: Response,
+        attachment_threshold: Option<usize>,
         writer: &Arc<tokio::sync::Mutex<W>>,
         codec: &FrameCodec
     ) -> Result<()> {
-        let message = Message::response(response);
-        let payload = rmp_serde::to_vec(&message)
+        let frame = Message::response(response)
+            .to_frame(stream_id, sequence, attachment_threshold)
             .context("Failed to serialize response message")?;
         
-        let frame = Frame::data(stream_id, sequence, Bytes::from(payload));
-        
         let mut writer_guard = writer.lock().await;
         codec.write_frame(&mut *writer_guard, &frame).await
             .context("Failed to write response frame")?;
@@ -242,11 +246,7 @@ where
     /// Update stream information
     async fn update_stream_info(&self, stream_id: u32, sequence: u32) {
         let mut streams = self.streams.write().await;
-        streams.insert(stream_id, StreamInfo {
-            stream_id,
-            sequence,
-            request_id: None,
-        });
+        streams.insert(stream_id, StreamInfo { sequence });
     }
     
     /// Close a stream
@@ -268,6 +268,12 @@ where
         let streams = self.streams.read().await;
         streams.keys().copied().collect()
     }
+    
+    /// Get the last sequence number seen on an active stream
+    pub async fn stream_sequence(&self, stream_id: u32) -> Option<u32> {
+        let streams = self.streams.read().await;
+        streams.get(&stream_id).map(|info| info.sequence)
+    }
 }
 
 #[cfg(test)]
@@ -277,8 +283,8 @@ mod tests {
     use mitoxide_proto::{Request, Response};
     use std::collections::HashMap;
     use std::io::Cursor;
-
     
+
     #[tokio::test]
     async fn test_router_creation() {
         let output = Cursor::new(Vec::<u8>::new());
@@ -309,11 +315,13 @@ mod tests {
         router.update_stream_info(1, 42).await;
         assert_eq!(router.active_stream_count().await, 1);
         assert_eq!(router.active_streams().await, vec![1]);
+        assert_eq!(router.stream_sequence(1).await, Some(42));
         
         // Close stream
         router.close_stream(1).await;
         assert_eq!(router.active_stream_count().await, 0);
         assert!(router.active_streams().await.is_empty());
+        assert_eq!(router.stream_sequence(1).await, None);
     }
     
     #[tokio::test]
@@ -0,0 +1,107 @@
+//! Files that carry agent state over to agents started later
+//!
+//! The agent binary serves one connection and exits with it, so sessions and
+//! idempotent responses only outlive the process if they are saved. Each
+//! entry is a file of its own in a state directory, written atomically, and
+//! an agent loading it renames it first so no two agents load the same one.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Current time in milliseconds since the Unix epoch
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// `instant` as milliseconds since the Unix epoch
+pub(crate) fn to_unix_ms(instant: Instant) -> u64 {
+    unix_ms().saturating_sub(instant.elapsed().as_millis() as u64)
+}
+
+/// Milliseconds since the Unix epoch as an instant, if less than `ttl` ago
+pub(crate) fn from_unix_ms(ms: u64, ttl: Duration) -> Option<Instant> {
+    let age = Duration::from_millis(unix_ms().saturating_sub(ms));
+    if age >= ttl {
+        return None;
+    }
+    Instant::now().checked_sub(age)
+}
+
+/// Write `value` to `dir/name`, replacing any earlier file of the name
+pub(crate) fn save<T: Serialize>(dir: &Path, name: &str, value: &T) -> io::Result<()> {
+    let data = rmp_serde::to_vec_named(value).map_err(io::Error::other)?;
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(&data)?;
+    file.persist(dir.join(name)).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Take the files in `dir` ending in `.extension`, removing them as they are read
+///
+/// A directory that doesn't exist holds nothing. Files that can't be taken
+/// or read are skipped with a warning.
+pub(crate) fn take_all<T: DeserializeOwned>(dir: &Path, extension: &str) -> io::Result<Vec<T>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut taken = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != extension) {
+            continue;
+        }
+        // Only one agent gets to rename it
+        let claimed = path.with_extension(format!("{}.loading", extension));
+        if fs::rename(&path, &claimed).is_err() {
+            continue;
+        }
+        let value = fs::read(&claimed).map_err(|e| e.to_string())
+            .and_then(|data| rmp_serde::from_slice(&data).map_err(|e| e.to_string()));
+        let _ = fs::remove_file(&claimed);
+        match value {
+            Ok(value) => taken.push(value),
+            Err(e) => warn!("Skipping unreadable state file {:?}: {}", path, e),
+        }
+    }
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saved_files_are_taken_once() {
+        let dir = tempfile::tempdir().unwrap();
+        save(dir.path(), "a.entry", &"first".to_string()).unwrap();
+        save(dir.path(), "b.entry", &"second".to_string()).unwrap();
+        fs::write(dir.path().join("c.entry"), b"\xc1 not msgpack").unwrap();
+        fs::write(dir.path().join("d.other"), b"ignored").unwrap();
+
+        let mut taken: Vec<String> = take_all(dir.path(), "entry").unwrap();
+        taken.sort();
+        assert_eq!(taken, vec!["first", "second"]);
+        assert!(take_all::<String>(dir.path(), "entry").unwrap().is_empty());
+        assert!(dir.path().join("d.other").exists());
+
+        assert!(take_all::<String>(&dir.path().join("missing"), "entry").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_times_older_than_the_ttl_are_dropped() {
+        let ms = to_unix_ms(Instant::now());
+        assert!(from_unix_ms(ms, Duration::from_secs(60)).is_some());
+        assert!(from_unix_ms(ms.saturating_sub(120_000), Duration::from_secs(60)).is_none());
+    }
+}
@@ -0,0 +1,31 @@
+//! Build information captured at compile time
+//!
+//! The build script records the commit, compiler, target and profile; the
+//! features are those this crate was compiled with.
+
+use mitoxide_proto::message::BuildInfo;
+
+/// Cargo features of this crate compiled into the agent, in name order
+pub const FEATURES: &[(&str, bool)] = &[
+    ("docker", cfg!(feature = "docker")),
+    ("jsonrpc", cfg!(feature = "jsonrpc")),
+    ("k8s", cfg!(feature = "k8s")),
+    ("lxc", cfg!(feature = "lxc")),
+    ("sudo", cfg!(feature = "sudo")),
+    ("wasm", cfg!(feature = "wasm")),
+];
+
+/// What this agent was built from and for
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_hash: option_env!("MITOXIDE_GIT_HASH").map(str::to_string),
+        features: FEATURES.iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name.to_string())
+            .collect(),
+        target: env!("MITOXIDE_TARGET").to_string(),
+        rustc: option_env!("MITOXIDE_RUSTC_VERSION").map(str::to_string),
+        profile: env!("MITOXIDE_PROFILE").to_string(),
+    }
+}
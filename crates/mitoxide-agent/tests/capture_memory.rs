@@ -0,0 +1,57 @@
+//! Peak memory of capturing a command's output under a limit
+//!
+//! A counting global allocator records the most memory held at once while a
+//! command writes far more output than its capture limit. The handler reads
+//! all of it but keeps only the limit, so the peak stays near the limit
+//! rather than the size of the output.
+
+use mitoxide_agent::agent::Handler;
+use mitoxide_agent::handlers::ProcessHandler;
+use mitoxide_proto::message::OutputTruncation;
+use mitoxide_proto::{Request, Response};
+use mitoxide_test_alloc::{peak_since, reset_peak, CountingAllocator};
+use std::collections::HashMap;
+
+/// Bytes of output the command writes
+const OUTPUT_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Bytes of output the handler keeps
+const CAPTURE_LIMIT: u64 = 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// The only test in this binary, so nothing else allocates while it measures
+#[cfg(unix)]
+#[tokio::test]
+async fn test_truncated_capture_memory_is_bounded() {
+    let handler = ProcessHandler::new();
+    let script = format!("head -c {} /dev/zero", OUTPUT_SIZE);
+    let mut request = Request::process_exec(
+        vec!["sh".to_string(), "-c".to_string(), script],
+        HashMap::new(),
+        None,
+        None,
+        Some(30),
+    );
+    if let Request::ProcessExec { max_output_bytes, output_truncation, .. } = &mut request {
+        *max_output_bytes = Some(CAPTURE_LIMIT);
+        *output_truncation = OutputTruncation::Head;
+    }
+    
+    let baseline = reset_peak();
+    let response = handler.handle(request).await.unwrap();
+    let peak = peak_since(baseline);
+    
+    match response {
+        Response::ProcessResult { exit_code, stdout, truncated, .. } => {
+            // The whole output was read, but only the limit was kept
+            assert_eq!(exit_code, 0);
+            assert!(truncated);
+            assert_eq!(stdout.len() as u64, CAPTURE_LIMIT);
+        }
+        other => panic!("Expected ProcessResult response, got {:?}", other),
+    }
+    // The limit plus read buffers, nowhere near the 100MB written
+    assert!(peak < 8 * CAPTURE_LIMIT as usize, "capturing held {} bytes at peak", peak);
+}
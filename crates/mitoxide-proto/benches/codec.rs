@@ -0,0 +1,59 @@
+//! Frame codec throughput
+//!
+//! Encodes and decodes data frames with payloads from 64 bytes to 1 MiB, and
+//! a frame carrying its payload as an attachment. Run with
+//! `cargo bench -p mitoxide-proto --bench codec`; `cargo test --benches`
+//! runs each benchmark once as a smoke test.
+
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mitoxide_proto::{Frame, FrameCodec};
+
+/// Payload sizes benchmarked
+const PAYLOAD_SIZES: [usize; 5] = [64, 1024, 16 * 1024, 256 * 1024, 1024 * 1024];
+
+fn data_frame(size: usize) -> Frame {
+    Frame::data(1, 0, Bytes::from(vec![0xa5; size]))
+}
+
+fn attachment_frame(size: usize) -> Frame {
+    Frame::data(1, 0, Bytes::from_static(b"header")).with_attachment(Bytes::from(vec![0xa5; size]))
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec/encode");
+    let codec = FrameCodec::new();
+    for size in PAYLOAD_SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        let frame = data_frame(size);
+        group.bench_with_input(BenchmarkId::new("payload", size), &frame, |b, frame| {
+            b.iter(|| codec.encode_frame(black_box(frame)).unwrap());
+        });
+        let frame = attachment_frame(size);
+        group.bench_with_input(BenchmarkId::new("attachment", size), &frame, |b, frame| {
+            b.iter(|| codec.encode_frame(black_box(frame)).unwrap());
+        });
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec/decode");
+    for size in PAYLOAD_SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        for (kind, frame) in [("payload", data_frame(size)), ("attachment", attachment_frame(size))] {
+            let encoded = FrameCodec::new().encode_frame(&frame).unwrap();
+            group.bench_with_input(BenchmarkId::new(kind, size), &encoded, |b, encoded| {
+                let mut codec = FrameCodec::new();
+                b.iter(|| {
+                    codec.feed(black_box(encoded));
+                    codec.try_decode_frame().unwrap().unwrap()
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -0,0 +1,87 @@
+//! Stream multiplexer throughput
+//!
+//! Routes incoming frames to 1 to 256 concurrent streams, and sends data on
+//! a stream with the peer acknowledging each frame through a window update.
+//! Run with `cargo bench -p mitoxide-proto --bench multiplexer`;
+//! `cargo test --benches` runs each benchmark once as a smoke test.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mitoxide_proto::{FlowControlMessage, Frame, StreamHandle, StreamMultiplexer};
+use tokio::runtime::Runtime;
+
+/// Frames routed to each stream per iteration
+const FRAMES_PER_STREAM: u32 = 16;
+
+/// Payload size of each data frame
+const PAYLOAD_SIZE: usize = 1024;
+
+/// Concurrent stream counts benchmarked
+const STREAM_COUNTS: [usize; 4] = [1, 16, 64, 256];
+
+fn routing(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let payload = Bytes::from(vec![0xa5; PAYLOAD_SIZE]);
+    let mut group = c.benchmark_group("multiplexer/route");
+    
+    for streams in STREAM_COUNTS {
+        let multiplexer = StreamMultiplexer::new();
+        let mut handles: Vec<StreamHandle> = runtime.block_on(async {
+            let mut handles = Vec::with_capacity(streams);
+            for _ in 0..streams {
+                handles.push(multiplexer.create_stream(None).await.unwrap());
+            }
+            handles
+        });
+        let mut sequences = vec![0u32; streams];
+        
+        group.throughput(Throughput::Bytes((streams * PAYLOAD_SIZE) as u64 * FRAMES_PER_STREAM as u64));
+        group.bench_function(BenchmarkId::from_parameter(streams), |b| {
+            b.iter(|| runtime.block_on(async {
+                // Interleave the streams' frames as they would arrive on one connection
+                for _ in 0..FRAMES_PER_STREAM {
+                    for (handle, sequence) in handles.iter().zip(sequences.iter_mut()) {
+                        let frame = Frame::data(handle.stream_id(), *sequence, payload.clone());
+                        multiplexer.route_frame(frame).await.unwrap();
+                        *sequence += 1;
+                    }
+                }
+                for handle in handles.iter_mut() {
+                    for _ in 0..FRAMES_PER_STREAM {
+                        handle.recv_frame().await.unwrap();
+                    }
+                }
+            }));
+        });
+    }
+    group.finish();
+}
+
+fn flow_control(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let payload = Bytes::from(vec![0xa5; PAYLOAD_SIZE]);
+    let mut group = c.benchmark_group("multiplexer/flow_control");
+    
+    let multiplexer = StreamMultiplexer::new();
+    // Sent frames are written to nowhere so they don't pile up
+    let writer = multiplexer.clone();
+    runtime.spawn(async move { writer.write_frames(tokio::io::sink()).await });
+    let mut stream = runtime.block_on(multiplexer.create_stream(None)).unwrap();
+    let mut ack_sequence = 0;
+    
+    group.throughput(Throughput::Bytes(PAYLOAD_SIZE as u64));
+    group.bench_function("send_ack", |b| {
+        b.iter(|| runtime.block_on(async {
+            stream.send_data(payload.clone()).await.unwrap();
+            let ack = FlowControlMessage::WindowUpdate { delta: PAYLOAD_SIZE as u32 }
+                .to_frame(stream.stream_id(), ack_sequence)
+                .unwrap();
+            multiplexer.route_frame(ack).await.unwrap();
+            ack_sequence += 1;
+        }));
+    });
+    group.finish();
+}
+
+criterion_group!(benches, routing, flow_control);
+criterion_main!(benches);
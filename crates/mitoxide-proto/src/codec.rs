@@ -3,16 +3,25 @@
 use crate::{Frame, ProtocolError};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::trace;
 
 /// Maximum frame size (16MB)
 pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
 
 /// Frame codec for encoding/decoding frames over async streams
+///
+/// Each frame is a big-endian `u32` length and the frame's MessagePack
+/// encoding. A frame with [`crate::FrameFlags::ATTACHMENT`] is followed by
+/// another length and its raw attachment bytes.
 pub struct FrameCodec {
     /// Read buffer for incoming data
     read_buf: BytesMut,
     /// Maximum frame size allowed
     max_frame_size: usize,
+    /// Payload bytes hex-dumped per traced frame, or None if frames aren't traced
+    trace_dump: Option<usize>,
+    /// Header already decoded whose attachment hasn't been read in full
+    pending: Option<Frame>,
 }
 
 impl Default for FrameCodec {
@@ -27,6 +36,8 @@ impl FrameCodec {
         Self {
             read_buf: BytesMut::with_capacity(8192),
             max_frame_size: MAX_FRAME_SIZE,
+            trace_dump: None,
+            pending: None,
         }
     }
     
@@ -35,37 +46,84 @@ impl FrameCodec {
         Self {
             read_buf: BytesMut::with_capacity(8192),
             max_frame_size,
+            trace_dump: None,
+            pending: None,
         }
     }
     
-    /// Encode a frame to bytes with length prefix
+    /// Log every frame read or written at trace level
+    ///
+    /// Each frame is logged with [`Frame::describe`] and a hex dump of its
+    /// first `dump_bytes` payload bytes, if `dump_bytes` isn't zero.
+    pub fn with_frame_trace(mut self, dump_bytes: usize) -> Self {
+        self.trace_dump = Some(dump_bytes);
+        self
+    }
+    
+    /// Whether frames are logged as they are read and written
+    pub fn traces_frames(&self) -> bool {
+        self.trace_dump.is_some()
+    }
+    
+    /// Log `frame` if frame tracing is on
+    fn trace_frame(&self, direction: &str, frame: &Frame) {
+        match self.trace_dump {
+            Some(0) => trace!("{} frame {}", direction, frame.describe()),
+            Some(dump_bytes) => {
+                trace!("{} frame {} payload: {}", direction, frame.describe(), frame.payload_hex(dump_bytes));
+            }
+            None => {}
+        }
+    }
+    
+    /// Encode a frame to bytes with length prefix, followed by its attachment if it has one
     pub fn encode_frame(&self, frame: &Frame) -> Result<Bytes, ProtocolError> {
+        let mut buf = self.encode_header(frame)?;
+        buf.put_slice(&frame.attachment);
+        Ok(buf.freeze())
+    }
+    
+    /// Encode a frame with its length prefix and, if it has an attachment, the attachment's length
+    fn encode_header(&self, frame: &Frame) -> Result<BytesMut, ProtocolError> {
         // Serialize the frame to MessagePack
         let frame_bytes = frame.to_msgpack()?;
         
         // Check frame size limit
-        if frame_bytes.len() > self.max_frame_size {
-            return Err(ProtocolError::FrameTooLarge {
-                size: frame_bytes.len(),
-                max: self.max_frame_size,
-            });
+        for size in [frame_bytes.len(), frame.attachment.len()] {
+            if size > self.max_frame_size {
+                return Err(ProtocolError::FrameTooLarge {
+                    size,
+                    max: self.max_frame_size,
+                });
+            }
         }
         
         // Create buffer with length prefix (4 bytes) + frame data
-        let mut buf = BytesMut::with_capacity(4 + frame_bytes.len());
+        let mut buf = BytesMut::with_capacity(8 + frame_bytes.len());
         buf.put_u32(frame_bytes.len() as u32);
         buf.put_slice(&frame_bytes);
+        if frame.has_attachment() {
+            buf.put_u32(frame.attachment.len() as u32);
+        }
         
-        Ok(buf.freeze())
+        Ok(buf)
     }
     
     /// Write a frame to an async writer
+    ///
+    /// The frame is checked with [`Frame::validate`] first, so malformed frames
+    /// never reach the peer. An attachment is written from the frame's own
+    /// buffer, without copying it.
     pub async fn write_frame<W>(&self, writer: &mut W, frame: &Frame) -> Result<(), ProtocolError>
     where
         W: AsyncWrite + Unpin,
     {
-        let encoded = self.encode_frame(frame)?;
-        writer.write_all(&encoded).await
+        frame.validate()?;
+        let header = self.encode_header(frame)?;
+        self.trace_frame("Writing", frame);
+        writer.write_all(&header).await
+            .map_err(|e| ProtocolError::Serialization(format!("Write error: {}", e)))?;
+        writer.write_all(&frame.attachment).await
             .map_err(|e| ProtocolError::Serialization(format!("Write error: {}", e)))?;
         writer.flush().await
             .map_err(|e| ProtocolError::Serialization(format!("Flush error: {}", e)))?;
@@ -73,6 +131,12 @@ impl FrameCodec {
     }
     
     /// Read a frame from an async reader
+    ///
+    /// Reads may return any number of bytes, so the length prefix and payload
+    /// can arrive split across many of them; bytes are buffered until a whole
+    /// frame is there, and anything after it is kept for the next call.
+    /// Returns `None` at end of file between frames and
+    /// [`ProtocolError::UnexpectedEof`] at end of file inside one.
     pub async fn read_frame<R>(&mut self, reader: &mut R) -> Result<Option<Frame>, ProtocolError>
     where
         R: AsyncRead + Unpin,
@@ -85,15 +149,18 @@ impl FrameCodec {
             
             // Need more data, read from the stream
             let mut temp_buf = [0u8; 8192];
-            let n = reader.read(&mut temp_buf).await
-                .map_err(|e| ProtocolError::Serialization(format!("Read error: {}", e)))?;
+            let n = match reader.read(&mut temp_buf).await {
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ProtocolError::Transport(format!("Read error: {}", e))),
+            };
             
             if n == 0 {
                 // EOF reached
-                if self.read_buf.is_empty() {
+                if self.read_buf.is_empty() && self.pending.is_none() {
                     return Ok(None);
                 } else {
-                    return Err(ProtocolError::InvalidFrame);
+                    return Err(ProtocolError::UnexpectedEof { buffered: self.read_buf.len() });
                 }
             }
             
@@ -103,36 +170,73 @@ impl FrameCodec {
     
     /// Try to decode a frame from the internal buffer
     pub fn try_decode_frame(&mut self) -> Result<Option<Frame>, ProtocolError> {
+        let mut frame = match self.pending.take() {
+            Some(frame) => frame,
+            None => {
+                let Some(frame_data) = self.try_split_prefixed()? else {
+                    return Ok(None);
+                };
+                
+                // Deserialize the frame
+                let frame = Frame::from_msgpack(&frame_data)?;
+                frame.flags.validate()?;
+                if !frame.has_attachment() {
+                    self.trace_frame("Read", &frame);
+                    return Ok(Some(frame));
+                }
+                frame
+            }
+        };
+        
+        // The attachment follows the header; it is split off the buffer, not copied
+        match self.try_split_prefixed()? {
+            Some(attachment) => {
+                frame.attachment = attachment.freeze();
+                self.trace_frame("Read", &frame);
+                Ok(Some(frame))
+            }
+            None => {
+                self.pending = Some(frame);
+                Ok(None)
+            }
+        }
+    }
+    
+    /// Take the next length-prefixed block off the buffer once all of it is there
+    fn try_split_prefixed(&mut self) -> Result<Option<BytesMut>, ProtocolError> {
         if self.read_buf.len() < 4 {
             // Not enough data for length prefix
             return Ok(None);
         }
         
         // Read the length prefix without consuming it
-        let frame_len = (&self.read_buf[..4]).get_u32() as usize;
+        let len = (&self.read_buf[..4]).get_u32() as usize;
         
         // Check frame size limit
-        if frame_len > self.max_frame_size {
+        if len > self.max_frame_size {
             return Err(ProtocolError::FrameTooLarge {
-                size: frame_len,
+                size: len,
                 max: self.max_frame_size,
             });
         }
         
-        // Check if we have the complete frame
-        if self.read_buf.len() < 4 + frame_len {
+        // Make room for the rest at once, so a large block isn't copied as the buffer grows
+        if self.read_buf.len() < 4 + len {
+            self.read_buf.reserve(4 + len - self.read_buf.len());
             return Ok(None);
         }
         
-        // We have a complete frame, consume the length prefix
+        // We have the complete block, consume the length prefix
         self.read_buf.advance(4);
-        
-        // Extract the frame data
-        let frame_data = self.read_buf.split_to(frame_len);
-        
-        // Deserialize the frame
-        let frame = Frame::from_msgpack(&frame_data)?;
-        Ok(Some(frame))
+        Ok(Some(self.read_buf.split_to(len)))
+    }
+    
+    /// Append bytes received by other means to the read buffer
+    ///
+    /// Frames in them are decoded with [`FrameCodec::try_decode_frame`], for
+    /// callers that read the transport themselves.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.read_buf.extend_from_slice(data);
     }
     
     /// Get the current buffer size
@@ -143,15 +247,41 @@ impl FrameCodec {
     /// Clear the internal buffer
     pub fn clear_buffer(&mut self) {
         self.read_buf.clear();
+        self.pending = None;
+    }
+    
+    /// Take the bytes read past the last decoded frame
+    ///
+    /// Used when the byte stream changes encoding after a frame, so the bytes
+    /// already read can be handed to whatever decodes the new encoding.
+    pub fn take_buffered(&mut self) -> BytesMut {
+        self.read_buf.split()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::FrameFlags;
     use std::io::Cursor;
     use proptest::prelude::*;
     
+    #[test]
+    fn test_feed_then_decode() {
+        let mut codec = FrameCodec::new();
+        let frame = Frame::data(3, 7, Bytes::from("fed by hand"));
+        let encoded = codec.encode_frame(&frame).unwrap();
+        
+        // Half a frame decodes to nothing until the rest arrives
+        codec.feed(&encoded[..5]);
+        assert!(codec.try_decode_frame().unwrap().is_none());
+        codec.feed(&encoded[5..]);
+        let decoded = codec.try_decode_frame().unwrap().unwrap();
+        assert_eq!(decoded.stream_id, 3);
+        assert_eq!(decoded.payload, frame.payload);
+        assert_eq!(codec.buffer_size(), 0);
+    }
+    
     #[tokio::test]
     async fn test_frame_encode_decode() {
         let codec = FrameCodec::new();
@@ -187,6 +317,22 @@ mod tests {
         assert!(decoded.is_end_stream());
     }
     
+    #[tokio::test]
+    async fn test_traced_codec_roundtrip() {
+        let codec = FrameCodec::new().with_frame_trace(4);
+        assert!(codec.traces_frames());
+        assert!(!FrameCodec::new().traces_frames());
+        
+        let frame = Frame::data(5, 1, Bytes::from("traced payload"));
+        let mut buffer = Vec::new();
+        codec.write_frame(&mut buffer, &frame).await.unwrap();
+        
+        let mut reader = FrameCodec::with_max_frame_size(1024).with_frame_trace(0);
+        let decoded = reader.read_frame(&mut Cursor::new(buffer)).await.unwrap().unwrap();
+        assert_eq!(decoded.describe(), frame.describe());
+        assert_eq!(decoded.payload, frame.payload);
+    }
+    
     #[tokio::test]
     async fn test_partial_frame_reading() {
         let codec = FrameCodec::new();
@@ -271,6 +417,68 @@ mod tests {
         assert!(matches!(result, Err(ProtocolError::Serialization(_))));
     }
     
+    #[tokio::test]
+    async fn test_reserved_flag_bits_rejected() {
+        let codec = FrameCodec::new();
+        let frame = Frame::new(1, 1, FrameFlags::from_bits_retain(0x40), Bytes::from("data"));
+        let encoded = codec.encode_frame(&frame).unwrap();
+        
+        let mut codec2 = FrameCodec::new();
+        let mut cursor = Cursor::new(encoded);
+        let result = codec2.read_frame(&mut cursor).await;
+        assert!(matches!(result, Err(ProtocolError::InvalidFlags(0x40))));
+    }
+    
+    #[tokio::test]
+    async fn test_exclusive_flags_rejected() {
+        let codec = FrameCodec::new();
+        let frame = Frame::new(1, 1, FrameFlags::FLOW_CONTROL | FrameFlags::END_STREAM, Bytes::new());
+        let encoded = codec.encode_frame(&frame).unwrap();
+        
+        let mut codec2 = FrameCodec::new();
+        let mut cursor = Cursor::new(encoded);
+        assert!(matches!(codec2.read_frame(&mut cursor).await, Err(ProtocolError::InvalidFlags(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_write_rejects_malformed_frame() {
+        let codec = FrameCodec::new();
+        let mut buffer = Vec::new();
+        
+        let frame = Frame::new(1, 1, FrameFlags::END_STREAM, Bytes::from("trailing"));
+        let result = codec.write_frame(&mut buffer, &frame).await;
+        assert!(matches!(result, Err(ProtocolError::InvalidFrame(_))));
+        
+        let frame = Frame::error(1, 1, Bytes::new());
+        let result = codec.write_frame(&mut buffer, &frame).await;
+        assert!(matches!(result, Err(ProtocolError::InvalidFrame(_))));
+        assert!(buffer.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_empty_data_frame_roundtrip() {
+        let codec = FrameCodec::new();
+        let mut buffer = Vec::new();
+        codec.write_frame(&mut buffer, &Frame::data(1, 0, Bytes::new())).await.unwrap();
+        codec.write_frame(&mut buffer, &Frame::data(1, 1, Bytes::from("after"))).await.unwrap();
+        codec.write_frame(&mut buffer, &Frame::end_stream(1, 2)).await.unwrap();
+        
+        let mut codec2 = FrameCodec::new();
+        let mut cursor = Cursor::new(buffer);
+        let empty = codec2.read_frame(&mut cursor).await.unwrap().unwrap();
+        assert_eq!(empty.sequence, 0);
+        assert!(empty.payload.is_empty());
+        assert!(!empty.is_end_stream());
+        
+        let data = codec2.read_frame(&mut cursor).await.unwrap().unwrap();
+        assert_eq!(data.sequence, 1);
+        assert_eq!(data.payload, Bytes::from("after"));
+        
+        let end = codec2.read_frame(&mut cursor).await.unwrap().unwrap();
+        assert!(end.is_end_stream());
+        assert!(codec2.read_frame(&mut cursor).await.unwrap().is_none());
+    }
+    
     #[tokio::test]
     async fn test_empty_stream() {
         let mut codec = FrameCodec::new();
@@ -280,6 +488,83 @@ mod tests {
         assert!(result.is_none());
     }
     
+    /// Reader that hands out one byte per read, waking itself in between
+    struct ByteAtATime {
+        data: Bytes,
+        pending: bool,
+    }
+    
+    impl AsyncRead for ByteAtATime {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            // Alternate between no data yet and a single byte, like a slow pipe
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            if !self.data.is_empty() {
+                let byte = self.data.split_to(1);
+                buf.put_slice(&byte);
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_byte_at_a_time_reads_decode_like_bulk_read() {
+        let codec = FrameCodec::new();
+        let frames = vec![
+            Frame::data(1, 1, Bytes::from("split across many reads")),
+            Frame::data(1, 2, Bytes::new()),
+            Frame::end_stream(1, 3),
+            Frame::data(3, 1, Bytes::from(vec![0xAB; 300])),
+            Frame::data(3, 2, Bytes::from("header")).with_attachment(Bytes::from(vec![0xCD; 500])),
+        ];
+        let mut encoded = BytesMut::new();
+        for frame in &frames {
+            encoded.extend_from_slice(&codec.encode_frame(frame).unwrap());
+        }
+        let encoded = encoded.freeze();
+        
+        let mut bulk_codec = FrameCodec::new();
+        let mut bulk = Cursor::new(encoded.clone());
+        let mut slow_codec = FrameCodec::new();
+        let mut slow = ByteAtATime { data: encoded, pending: false };
+        
+        for frame in &frames {
+            let from_bulk = bulk_codec.read_frame(&mut bulk).await.unwrap().unwrap();
+            let from_slow = slow_codec.read_frame(&mut slow).await.unwrap().unwrap();
+            for decoded in [&from_bulk, &from_slow] {
+                assert_eq!(decoded.stream_id, frame.stream_id);
+                assert_eq!(decoded.sequence, frame.sequence);
+                assert_eq!(decoded.flags, frame.flags);
+                assert_eq!(decoded.payload, frame.payload);
+                assert_eq!(decoded.attachment, frame.attachment);
+            }
+        }
+        assert!(slow_codec.read_frame(&mut slow).await.unwrap().is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_eof_inside_frame_is_unexpected_eof() {
+        let codec = FrameCodec::new();
+        let encoded = codec.encode_frame(&Frame::data(1, 1, Bytes::from("cut short"))).unwrap();
+        
+        // End of file inside the length prefix and inside the payload
+        for cut in [2, encoded.len() - 1] {
+            let mut reader = ByteAtATime { data: encoded.slice(..cut), pending: false };
+            let result = FrameCodec::new().read_frame(&mut reader).await;
+            match result {
+                Err(ProtocolError::UnexpectedEof { buffered }) => assert_eq!(buffered, cut),
+                other => panic!("Expected UnexpectedEof, got {:?}", other),
+            }
+        }
+    }
+    
     proptest! {
         #[test]
         fn test_codec_roundtrip_properties(
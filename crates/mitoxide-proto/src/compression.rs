@@ -0,0 +1,479 @@
+//! Connection-level stream compression
+//!
+//! Once negotiated, the whole frame byte stream of a connection runs through a
+//! single zstd stream in each direction. Every flush ends a zstd block, so a
+//! frame is decodable as soon as it is written, while the shared compression
+//! window lets later frames reference earlier ones. Many small similar frames
+//! compress far better this way than one at a time.
+//!
+//! Both ends can also prime their streams with the same
+//! [`CompressionDictionary`], so even the first frames of a connection
+//! compress well when the workload's payloads are alike.
+
+use bytes::Bytes;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use zstd::stream::raw::{Decoder, Encoder, Operation};
+
+/// zstd level used for connection compression
+pub const STREAM_COMPRESSION_LEVEL: i32 = 3;
+
+/// Size of the scratch buffers used while compressing and decompressing
+const SCRATCH_SIZE: usize = 16 * 1024;
+
+/// Dictionary that both ends of a connection prime their zstd streams with
+///
+/// A dictionary trained on a workload's typical payloads, such as the JSON
+/// results of the commands it runs, lets the compressor reference them from
+/// the first frame on. Both ends must load the same dictionary; the session
+/// handshake compares [`CompressionDictionary::id`]s and compresses without a
+/// dictionary when they differ.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CompressionDictionary {
+    /// Identifies the dictionary in the session handshake
+    id: u32,
+    /// Dictionary content
+    data: Bytes,
+}
+
+impl CompressionDictionary {
+    /// Use `data` as a dictionary, either one trained by zstd or raw content
+    ///
+    /// A trained dictionary keeps the ID zstd recorded in it; raw content is
+    /// identified by a hash of its bytes.
+    pub fn new(data: impl Into<Bytes>) -> Self {
+        let data = data.into();
+        let id = match zstd::zstd_safe::get_dict_id_from_dict(&data) {
+            0 => content_hash(&data),
+            id => id,
+        };
+        Self { id, data }
+    }
+    
+    /// Train a dictionary of at most `max_size` bytes from sample payloads
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> io::Result<Self> {
+        zstd::dict::from_samples(samples, max_size).map(Self::new)
+    }
+    
+    /// ID both ends compare before using the dictionary
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    
+    /// Dictionary content
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for CompressionDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompressionDictionary")
+            .field("id", &self.id)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+/// 32-bit FNV-1a hash identifying a raw content dictionary
+fn content_hash(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Writer that compresses everything written once compression is enabled
+///
+/// Before [`CompressedWriter::enable_compression`] is called, data passes through unchanged.
+pub struct CompressedWriter<W> {
+    /// Underlying writer
+    inner: W,
+    /// Stream encoder, once compression is enabled
+    ///
+    /// Only ever accessed through `&mut self`; the mutex just makes the writer `Sync`.
+    encoder: Option<Mutex<Encoder<'static>>>,
+    /// Compressed bytes not yet written to `inner`
+    pending: Vec<u8>,
+    /// Bytes of `pending` already written
+    pending_pos: usize,
+    /// Whether data was compressed since the last zstd flush
+    unflushed: bool,
+    /// Whether the zstd stream has been ended
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> CompressedWriter<W> {
+    /// Wrap a writer, passing data through until compression is enabled
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            encoder: None,
+            pending: Vec::new(),
+            pending_pos: 0,
+            unflushed: false,
+            finished: false,
+        }
+    }
+    
+    /// Compress everything written from now on
+    ///
+    /// Anything written before must already have been flushed.
+    pub fn enable_compression(&mut self) -> io::Result<()> {
+        self.enable_compression_with(None)
+    }
+    
+    /// Compress everything written from now on, primed with `dictionary`
+    ///
+    /// The reader must enable decompression with the same dictionary.
+    pub fn enable_compression_with(&mut self, dictionary: Option<&CompressionDictionary>) -> io::Result<()> {
+        if self.encoder.is_none() {
+            let dictionary = dictionary.map(CompressionDictionary::data).unwrap_or_default();
+            self.encoder = Some(Mutex::new(Encoder::with_dictionary(STREAM_COMPRESSION_LEVEL, dictionary)?));
+        }
+        Ok(())
+    }
+    
+    /// Whether compression is enabled
+    pub fn is_compressed(&self) -> bool {
+        self.encoder.is_some()
+    }
+    
+    /// Get a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+    
+    /// Get a mutable reference to the underlying writer
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+    
+    /// Write out compressed bytes held back so far
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_pos += written;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+    
+    /// Run an encoder step that only produces output (flush or end) until it completes
+    fn drain_encoder(&mut self, end: bool) -> io::Result<()> {
+        let Some(encoder) = self.encoder.as_mut() else {
+            return Ok(());
+        };
+        let encoder = encoder.get_mut().unwrap_or_else(|e| e.into_inner());
+        let mut scratch = [0u8; SCRATCH_SIZE];
+        loop {
+            let mut output = zstd::stream::raw::OutBuffer::around(&mut scratch[..]);
+            let remaining = if end {
+                encoder.finish(&mut output, false)?
+            } else {
+                encoder.flush(&mut output)?
+            };
+            let written = output.pos();
+            self.pending.extend_from_slice(&scratch[..written]);
+            if remaining == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CompressedWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.encoder.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if this.finished {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "Compressed stream already ended")));
+        }
+        ready!(this.poll_drain(cx))?;
+        
+        let encoder = this.encoder.as_mut().expect("checked above").get_mut().unwrap_or_else(|e| e.into_inner());
+        let mut scratch = [0u8; SCRATCH_SIZE];
+        let mut consumed = 0;
+        while consumed < buf.len() {
+            let status = encoder.run_on_buffers(&buf[consumed..], &mut scratch)?;
+            consumed += status.bytes_read;
+            this.pending.extend_from_slice(&scratch[..status.bytes_written]);
+        }
+        this.unflushed = true;
+        Poll::Ready(Ok(buf.len()))
+    }
+    
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.unflushed {
+            this.drain_encoder(false)?;
+            this.unflushed = false;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+    
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.encoder.is_some() && !this.finished {
+            this.drain_encoder(true)?;
+            this.unflushed = false;
+            this.finished = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reader that decompresses everything read once compression is enabled
+///
+/// Before [`CompressedReader::enable_compression`] is called, data passes through unchanged.
+pub struct CompressedReader<R> {
+    /// Underlying reader
+    inner: R,
+    /// Stream decoder, once compression is enabled
+    ///
+    /// Only ever accessed through `&mut self`; the mutex just makes the reader `Sync`.
+    decoder: Option<Mutex<Decoder<'static>>>,
+    /// Compressed bytes read from `inner`
+    input: Vec<u8>,
+    /// Bytes of `input` already decoded
+    input_pos: usize,
+}
+
+impl<R: AsyncRead + Unpin> CompressedReader<R> {
+    /// Wrap a reader, passing data through until compression is enabled
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            decoder: None,
+            input: Vec::new(),
+            input_pos: 0,
+        }
+    }
+    
+    /// Decompress everything read from now on
+    ///
+    /// `buffered` holds bytes already read from the underlying reader that
+    /// belong to the compressed stream, such as a codec's unparsed input.
+    pub fn enable_compression(&mut self, buffered: &[u8]) -> io::Result<()> {
+        self.enable_compression_with(buffered, None)
+    }
+    
+    /// Decompress everything read from now on, with the writer's `dictionary`
+    pub fn enable_compression_with(&mut self, buffered: &[u8], dictionary: Option<&CompressionDictionary>) -> io::Result<()> {
+        if self.decoder.is_none() {
+            let dictionary = dictionary.map(CompressionDictionary::data).unwrap_or_default();
+            self.decoder = Some(Mutex::new(Decoder::with_dictionary(dictionary)?));
+            self.input = buffered.to_vec();
+            self.input_pos = 0;
+        }
+        Ok(())
+    }
+    
+    /// Whether compression is enabled
+    pub fn is_compressed(&self) -> bool {
+        self.decoder.is_some()
+    }
+    
+    /// Get a reference to the underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+    
+    /// Get a mutable reference to the underlying reader
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CompressedReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(decoder) = this.decoder.as_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let decoder = decoder.get_mut().unwrap_or_else(|e| e.into_inner());
+        
+        loop {
+            // Decoding with no new input still drains output the decoder held back
+            let output = buf.initialize_unfilled();
+            if output.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            let status = decoder.run_on_buffers(&this.input[this.input_pos..], output)?;
+            this.input_pos += status.bytes_read;
+            buf.advance(status.bytes_written);
+            if status.bytes_written > 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if this.input_pos < this.input.len() {
+                continue;
+            }
+            
+            this.input.resize(SCRATCH_SIZE, 0);
+            this.input_pos = 0;
+            let mut read_buf = ReadBuf::new(&mut this.input);
+            let result = Pin::new(&mut this.inner).poll_read(cx, &mut read_buf);
+            let read = read_buf.filled().len();
+            this.input.truncate(read);
+            ready!(result)?;
+            if read == 0 {
+                // End of the underlying stream
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Frame, FrameCodec};
+    use bytes::Bytes;
+    
+    /// Frames like those of a busy session: small, with similar payloads
+    fn similar_frames() -> Vec<Frame> {
+        (0..500u32)
+            .map(|i| Frame::data(i % 7 + 1, i, Bytes::from(format!("{{\"status\":\"running\",\"progress\":{},\"host\":\"web-01\"}}", i))))
+            .collect()
+    }
+    
+    fn assert_same_frame(actual: &Frame, expected: &Frame) {
+        assert_eq!(actual.stream_id, expected.stream_id);
+        assert_eq!(actual.sequence, expected.sequence);
+        assert_eq!(actual.flags, expected.flags);
+        assert_eq!(actual.payload, expected.payload);
+    }
+    
+    #[tokio::test]
+    async fn test_compressed_round_trip() {
+        let frames = similar_frames();
+        let codec = FrameCodec::new();
+        
+        let mut writer = CompressedWriter::new(Vec::new());
+        writer.enable_compression().unwrap();
+        for frame in &frames {
+            codec.write_frame(&mut writer, frame).await.unwrap();
+        }
+        let compressed = std::mem::take(writer.get_mut());
+        
+        let mut reader = CompressedReader::new(compressed.as_slice());
+        reader.enable_compression(&[]).unwrap();
+        let mut read_codec = FrameCodec::new();
+        for frame in &frames {
+            let decoded = read_codec.read_frame(&mut reader).await.unwrap().unwrap();
+            assert_same_frame(&decoded, frame);
+        }
+        assert!(read_codec.read_frame(&mut reader).await.unwrap().is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_each_flushed_frame_is_decodable() {
+        let (client, agent) = tokio::io::duplex(64 * 1024);
+        let codec = FrameCodec::new();
+        let mut writer = CompressedWriter::new(client);
+        writer.enable_compression().unwrap();
+        let mut reader = CompressedReader::new(agent);
+        reader.enable_compression(&[]).unwrap();
+        let mut read_codec = FrameCodec::new();
+        
+        // The writer stays open, so each frame must arrive on its own flush
+        for frame in similar_frames().iter().take(5) {
+            codec.write_frame(&mut writer, frame).await.unwrap();
+            let decoded = read_codec.read_frame(&mut reader).await.unwrap().unwrap();
+            assert_same_frame(&decoded, frame);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_stream_compresses_better_than_per_frame() {
+        let frames = similar_frames();
+        let codec = FrameCodec::new();
+        
+        let mut plain_size = 0;
+        let mut per_frame_size = 0;
+        let mut writer = CompressedWriter::new(Vec::new());
+        writer.enable_compression().unwrap();
+        for frame in &frames {
+            let encoded = codec.encode_frame(frame).unwrap();
+            plain_size += encoded.len();
+            per_frame_size += zstd::bulk::compress(&encoded, STREAM_COMPRESSION_LEVEL).unwrap().len();
+            codec.write_frame(&mut writer, frame).await.unwrap();
+        }
+        let stream_size = writer.get_mut().len();
+        
+        assert!(stream_size < per_frame_size / 2, "stream: {}, per frame: {}", stream_size, per_frame_size);
+        assert!(stream_size < plain_size / 2, "stream: {}, plain: {}", stream_size, plain_size);
+    }
+    
+    /// Size of `frames` written through a stream flushed after each frame, and the stream
+    async fn flushed_stream(frames: &[Frame], dictionary: Option<&CompressionDictionary>) -> (Vec<usize>, Vec<u8>) {
+        use tokio::io::AsyncWriteExt;
+        
+        let codec = FrameCodec::new();
+        let mut writer = CompressedWriter::new(Vec::new());
+        writer.enable_compression_with(dictionary).unwrap();
+        let mut sizes = Vec::new();
+        for frame in frames {
+            let before = writer.get_ref().len();
+            codec.write_frame(&mut writer, frame).await.unwrap();
+            writer.flush().await.unwrap();
+            sizes.push(writer.get_ref().len() - before);
+        }
+        (sizes, std::mem::take(writer.get_mut()))
+    }
+    
+    #[tokio::test]
+    async fn test_dictionary_shrinks_repetitive_frames() {
+        let payload = |i: u32| format!(
+            "{{\"exit_code\":0,\"stdout\":\"deployed release {} to web-{:02}\",\"stderr\":\"\",\"duration_ms\":{},\"host\":\"web-{:02}.example.internal\"}}",
+            i, i % 16, 100 + i * 7, i % 16,
+        );
+        let samples: Vec<Vec<u8>> = (1000..1400).map(|i| payload(i).into_bytes()).collect();
+        let dictionary = CompressionDictionary::train(&samples, 4096).unwrap();
+        assert_ne!(dictionary.id(), 0);
+        
+        // Without a dictionary the first frame has no history to reference; later
+        // frames reference the earlier ones either way
+        let frames: Vec<Frame> = (0..8u32).map(|i| Frame::data(1, i, Bytes::from(payload(i)))).collect();
+        let (plain_sizes, _) = flushed_stream(&frames, None).await;
+        let (dict_sizes, compressed) = flushed_stream(&frames, Some(&dictionary)).await;
+        assert!(dict_sizes[0] * 2 < plain_sizes[0], "with dictionary: {:?}, without: {:?}", dict_sizes, plain_sizes);
+        assert!(dict_sizes.iter().sum::<usize>() * 4 < plain_sizes.iter().sum::<usize>() * 3);
+        
+        let mut reader = CompressedReader::new(compressed.as_slice());
+        reader.enable_compression_with(&[], Some(&dictionary)).unwrap();
+        let mut read_codec = FrameCodec::new();
+        for frame in &frames {
+            let decoded = read_codec.read_frame(&mut reader).await.unwrap().unwrap();
+            assert_same_frame(&decoded, frame);
+        }
+    }
+    
+    #[test]
+    fn test_raw_dictionary_identified_by_content() {
+        let first = CompressionDictionary::new(&b"{\"status\":\"running\"}"[..]);
+        let second = CompressionDictionary::new(&b"{\"status\":\"stopped\"}"[..]);
+        assert_eq!(first.id(), CompressionDictionary::new(first.data().to_vec()).id());
+        assert_ne!(first.id(), second.id());
+    }
+    
+    #[tokio::test]
+    async fn test_passthrough_until_enabled() {
+        let codec = FrameCodec::new();
+        let frame = Frame::data(1, 0, Bytes::from("hello"));
+        
+        let mut writer = CompressedWriter::new(Vec::new());
+        codec.write_frame(&mut writer, &frame).await.unwrap();
+        assert!(!writer.is_compressed());
+        assert_eq!(writer.get_mut().as_slice(), codec.encode_frame(&frame).unwrap().as_ref());
+    }
+}
@@ -0,0 +1,356 @@
+//! Block signatures and deltas for sending only the changed parts of a file
+//!
+//! The side holding the old copy of a file describes it as a [`Signature`]:
+//! a weak rolling checksum and a strong hash per block. The side holding the
+//! new copy finds those blocks in its data at any offset with [`delta`], and
+//! sends the new file as [`DeltaOp`]s that copy blocks of the old copy or
+//! carry literal data. [`apply`] rebuilds the new file from the old copy and
+//! the ops.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Smallest block size [`block_size_for`] picks
+pub const MIN_BLOCK_SIZE: u32 = 512;
+
+/// Largest block size [`block_size_for`] picks
+pub const MAX_BLOCK_SIZE: u32 = 64 * 1024;
+
+/// Checksums of one block of the old copy of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockSignature {
+    /// Rolling checksum, cheap to compute at every offset
+    pub weak: u32,
+    /// Start of the block's SHA-256, to confirm a weak match
+    pub strong: [u8; 16],
+}
+
+/// Checksums of the old copy of a file, block by block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    /// Bytes per block; only the last block may be shorter
+    pub block_size: u32,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// One entry per block, in file order
+    pub blocks: Vec<BlockSignature>,
+}
+
+/// One step of rebuilding a file from its old copy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeltaOp {
+    /// Copy `count` blocks of the old copy, starting with block `block`
+    Copy {
+        /// Index of the first block
+        block: u64,
+        /// Number of consecutive blocks
+        count: u64,
+    },
+    /// Bytes that aren't in the old copy
+    Data(Bytes),
+}
+
+/// Block size for a file of `len` bytes, about its square root
+pub fn block_size_for(len: u64) -> u32 {
+    let root = (len as f64).sqrt() as u64;
+    root.clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32
+}
+
+/// Signature of the file `reader` reads, in blocks of `block_size` bytes
+pub fn signature<R: Read>(mut reader: R, block_size: u32) -> io::Result<Signature> {
+    if block_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Block size must not be zero"));
+    }
+    
+    let mut block = vec![0; block_size as usize];
+    let mut blocks = Vec::new();
+    let mut size = 0;
+    loop {
+        let len = read_full(&mut reader, &mut block)?;
+        if len == 0 {
+            break;
+        }
+        blocks.push(BlockSignature {
+            weak: Rolling::new(&block[..len]).digest(),
+            strong: strong_hash(&block[..len]),
+        });
+        size += len as u64;
+        if len < block.len() {
+            break;
+        }
+    }
+    Ok(Signature { block_size, size, blocks })
+}
+
+/// Ops that turn the file `signature` describes into `data`
+///
+/// Blocks of the old copy are found at any offset of `data`, so content that
+/// moved because bytes were inserted or removed before it is still copied.
+pub fn delta(signature: &Signature, data: &[u8]) -> Vec<DeltaOp> {
+    let block_size = signature.block_size as usize;
+    let mut ops = Vec::new();
+    if block_size == 0 {
+        push_data(&mut ops, data);
+        return ops;
+    }
+    
+    // Only full blocks can match at an arbitrary offset; a short last block is tried at the end
+    let full_blocks = (signature.size / block_size as u64) as usize;
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, block) in signature.blocks.iter().enumerate().take(full_blocks) {
+        by_weak.entry(block.weak).or_default().push(index);
+    }
+    
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut rolling = (data.len() >= block_size).then(|| Rolling::new(&data[..block_size]));
+    while let Some(window) = rolling.as_mut().filter(|_| pos + block_size <= data.len()) {
+        let matched = by_weak.get(&window.digest()).and_then(|candidates| {
+            let strong = strong_hash(&data[pos..pos + block_size]);
+            candidates.iter().copied().find(|&index| signature.blocks[index].strong == strong)
+        });
+        match matched {
+            Some(index) => {
+                push_data(&mut ops, &data[literal_start..pos]);
+                push_copy(&mut ops, index as u64);
+                pos += block_size;
+                literal_start = pos;
+                if pos + block_size <= data.len() {
+                    *window = Rolling::new(&data[pos..pos + block_size]);
+                }
+            }
+            None => {
+                if pos + block_size < data.len() {
+                    window.roll(data[pos], data[pos + block_size]);
+                }
+                pos += 1;
+            }
+        }
+    }
+    
+    // The old copy's short last block may still end the new data
+    let tail_len = (signature.size % block_size as u64) as usize;
+    if tail_len > 0 && data.len() - literal_start >= tail_len {
+        let tail = &data[data.len() - tail_len..];
+        let last = &signature.blocks[full_blocks];
+        if Rolling::new(tail).digest() == last.weak && strong_hash(tail) == last.strong {
+            push_data(&mut ops, &data[literal_start..data.len() - tail_len]);
+            push_copy(&mut ops, full_blocks as u64);
+            return ops;
+        }
+    }
+    push_data(&mut ops, &data[literal_start..]);
+    ops
+}
+
+/// Write the file `ops` describe to `output`, copying blocks from the old copy `base`
+///
+/// `base_size` is the size in the old copy's signature; an old copy of
+/// another size changed since, so its blocks aren't used. Returns the number
+/// of bytes written.
+pub fn apply<R: Read + Seek, W: Write>(mut base: R, base_size: u64, block_size: u32, ops: &[DeltaOp], mut output: W) -> io::Result<u64> {
+    if base.seek(SeekFrom::End(0))? != base_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Old copy changed since its signature was taken"));
+    }
+    
+    let mut written = 0;
+    for op in ops {
+        match op {
+            DeltaOp::Copy { block, count } => {
+                let start = block.saturating_mul(block_size as u64);
+                let end = block.saturating_add(*count).saturating_mul(block_size as u64).min(base_size);
+                if *count == 0 || start >= end {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Blocks are past the end of the old copy"));
+                }
+                base.seek(SeekFrom::Start(start))?;
+                let copied = io::copy(&mut (&mut base).take(end - start), &mut output)?;
+                if copied != end - start {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Old copy ended early"));
+                }
+                written += copied;
+            }
+            DeltaOp::Data(data) => {
+                output.write_all(data)?;
+                written += data.len() as u64;
+            }
+        }
+    }
+    output.flush()?;
+    Ok(written)
+}
+
+/// Bytes of literal data in `ops`
+pub fn data_len(ops: &[DeltaOp]) -> u64 {
+    ops.iter()
+        .map(|op| match op {
+            DeltaOp::Data(data) => data.len() as u64,
+            DeltaOp::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+/// Append a copy of block `index`, extending the last copy if it ends just before
+fn push_copy(ops: &mut Vec<DeltaOp>, index: u64) {
+    if let Some(DeltaOp::Copy { block, count }) = ops.last_mut() {
+        if *block + *count == index {
+            *count += 1;
+            return;
+        }
+    }
+    ops.push(DeltaOp::Copy { block: index, count: 1 });
+}
+
+/// Append literal `data`, if there is any
+fn push_data(ops: &mut Vec<DeltaOp>, data: &[u8]) {
+    if !data.is_empty() {
+        ops.push(DeltaOp::Data(Bytes::copy_from_slice(data)));
+    }
+}
+
+/// Start of the SHA-256 of `data`
+fn strong_hash(data: &[u8]) -> [u8; 16] {
+    let digest = Sha256::digest(data);
+    let mut strong = [0; 16];
+    strong.copy_from_slice(&digest[..16]);
+    strong
+}
+
+/// Fill `buf` from `reader` unless it ends first, returning the bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// The rsync rolling checksum of a window that slides one byte at a time
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    /// Sum of the bytes
+    a: u32,
+    /// Sum of the bytes weighted by their distance from the window's end
+    b: u32,
+    /// Window length
+    len: u32,
+}
+
+impl Rolling {
+    /// Checksum of `window`
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+    
+    /// Slide the window past `out` to take in `next`
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+    
+    /// The checksum, both sums folded to 16 bits
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    
+    /// Rebuild `new` from `old` through a signature and a delta, returning the literal bytes sent
+    fn round_trip(old: &[u8], new: &[u8], block_size: u32) -> u64 {
+        let signature = signature(old, block_size).unwrap();
+        assert_eq!(signature.size, old.len() as u64);
+        let ops = delta(&signature, new);
+        let mut rebuilt = Vec::new();
+        assert_eq!(apply(Cursor::new(old), signature.size, block_size, &ops, &mut rebuilt).unwrap(), new.len() as u64);
+        assert_eq!(rebuilt, new);
+        data_len(&ops)
+    }
+    
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7919 % 251) as u8).collect()
+    }
+    
+    #[test]
+    fn test_rolling_matches_fresh_checksum() {
+        let data = sample(100);
+        let mut rolling = Rolling::new(&data[..16]);
+        for start in 1..=84 {
+            rolling.roll(data[start - 1], data[start + 15]);
+            assert_eq!(rolling.digest(), Rolling::new(&data[start..start + 16]).digest());
+        }
+    }
+    
+    #[test]
+    fn test_unchanged_file_is_all_copies() {
+        let data = sample(10_000);
+        let signature = signature(&data[..], 1024).unwrap();
+        assert_eq!(signature.blocks.len(), 10);
+        assert_eq!(delta(&signature, &data), vec![DeltaOp::Copy { block: 0, count: 10 }]);
+    }
+    
+    #[test]
+    fn test_only_changed_bytes_are_sent() {
+        let old = sample(64 * 1024);
+        
+        let mut edited = old.clone();
+        edited[40_000..40_010].copy_from_slice(b"0123456789");
+        assert!(round_trip(&old, &edited, 1024) <= 1024);
+        
+        // Inserting shifts everything after it, which must still be found
+        let mut inserted = old.clone();
+        inserted.splice(100..100, b"inserted".iter().copied());
+        assert!(round_trip(&old, &inserted, 1024) <= 1024 + 8);
+        
+        let truncated = &old[..old.len() - 3000];
+        assert!(round_trip(&old, truncated, 1024) < 1024);
+        
+        let mut appended = old.clone();
+        appended.extend_from_slice(b"appended");
+        assert_eq!(round_trip(&old, &appended, 1024), 8);
+    }
+    
+    #[test]
+    fn test_edge_cases_round_trip() {
+        round_trip(b"", b"new", 512);
+        round_trip(b"old", b"", 512);
+        round_trip(b"short old", b"short old", 512);
+        assert_eq!(round_trip(&sample(1000), &sample(1000), 512), 0);
+        round_trip(&sample(5000), &sample(3000)[..], 700);
+    }
+    
+    #[test]
+    fn test_apply_refuses_a_changed_old_copy() {
+        let ops = [DeltaOp::Copy { block: 1, count: 2 }];
+        assert_eq!(apply(Cursor::new(vec![0; 1024]), 1024, 512, &ops, Vec::new()).unwrap(), 512);
+        
+        let error = apply(Cursor::new(vec![0; 1000]), 1024, 512, &ops, Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = apply(Cursor::new(vec![0; 1024]), 1024, 512, &[DeltaOp::Copy { block: 2, count: 1 }], Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+    
+    #[test]
+    fn test_block_size_grows_with_the_file() {
+        assert_eq!(block_size_for(0), MIN_BLOCK_SIZE);
+        assert_eq!(block_size_for(100 * 1024 * 1024), 10240);
+        assert_eq!(block_size_for(u64::MAX), MAX_BLOCK_SIZE);
+    }
+}
@@ -0,0 +1,148 @@
+//! Parsing of `.env` files for process requests
+//!
+//! Each line holds one `KEY=VALUE` assignment:
+//!
+//! - blank lines and lines starting with `#` are ignored
+//! - a leading `export ` is allowed and ignored
+//! - keys are letters, digits and `_`, not starting with a digit
+//! - unquoted values are trimmed, and a ` #` starts a comment
+//! - `'single quoted'` values are taken literally
+//! - `"double quoted"` values understand `\n`, `\r`, `\t`, `\"`, `\\` and `\$`
+//!
+//! Nothing is expanded, and a quoted value ends on its own line. A later
+//! assignment of a key replaces an earlier one.
+
+/// A line of an env file that is not a valid assignment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedLine {
+    /// Line number, starting at 1
+    pub line: usize,
+    /// What is wrong with it
+    pub reason: String,
+}
+
+/// Contents of an env file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvFileContents {
+    /// Assignments in the order they appear
+    pub vars: Vec<(String, String)>,
+    /// Lines that could not be parsed
+    pub malformed: Vec<MalformedLine>,
+}
+
+/// Parse the assignments in `content`
+pub fn parse_env_file(content: &str) -> EnvFileContents {
+    let mut contents = EnvFileContents::default();
+    for (index, line) in content.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(var)) => contents.vars.push(var),
+            Ok(None) => {}
+            Err(reason) => contents.malformed.push(MalformedLine { line: index + 1, reason }),
+        }
+    }
+    contents
+}
+
+/// The assignment on one line, or `None` for a blank or comment line
+fn parse_line(line: &str) -> Result<Option<(String, String)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let line = line.strip_prefix("export ").map(str::trim_start).unwrap_or(line);
+    
+    let (key, value) = line.split_once('=').ok_or_else(|| "expected KEY=VALUE".to_string())?;
+    let key = key.trim_end();
+    let valid = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("invalid variable name {:?}", key));
+    }
+    
+    let value = value.trim_start();
+    let (value, rest) = match value.chars().next() {
+        Some('\'') => {
+            let end = value[1..].find('\'').ok_or_else(|| "unterminated single quote".to_string())?;
+            (value[1..end + 1].to_string(), &value[end + 2..])
+        }
+        Some('"') => double_quoted(&value[1..])?,
+        _ => {
+            let end = value.find(" #").or_else(|| value.find("\t#")).unwrap_or(value.len());
+            (value[..end].trim_end().to_string(), "")
+        }
+    };
+    
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected {:?} after quoted value", rest));
+    }
+    Ok(Some((key.to_string(), value)))
+}
+
+/// Unescape a double-quoted value, returning it and what follows the closing quote
+fn double_quoted(input: &str) -> Result<(String, &str), String> {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &input[index + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                Some(c) => {
+                    value.push('\\');
+                    value.push(c);
+                }
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated double quote".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn vars(content: &str) -> Vec<(String, String)> {
+        let contents = parse_env_file(content);
+        assert_eq!(contents.malformed, Vec::new());
+        contents.vars
+    }
+    
+    fn var(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+    
+    #[test]
+    fn test_plain_assignments_and_comments() {
+        let content = "# database\n\nDB_HOST=db.internal\nexport DB_PORT = 5432  # default port\nEMPTY=\n";
+        assert_eq!(vars(content), vec![var("DB_HOST", "db.internal"), var("DB_PORT", "5432"), var("EMPTY", "")]);
+        assert_eq!(vars("URL=http://host/#anchor"), vec![var("URL", "http://host/#anchor")]);
+    }
+    
+    #[test]
+    fn test_quoted_values() {
+        let content = r#"
+            GREETING="hello, \"world\"\n"
+            LITERAL='no $expansion \n here'   # kept as written
+            PRICE="\$5 # not a comment"
+        "#;
+        assert_eq!(vars(content), vec![
+            var("GREETING", "hello, \"world\"\n"),
+            var("LITERAL", "no $expansion \\n here"),
+            var("PRICE", "$5 # not a comment"),
+        ]);
+    }
+    
+    #[test]
+    fn test_malformed_lines_are_reported() {
+        let contents = parse_env_file("GOOD=1\nno equals sign\n1BAD=x\nOPEN=\"unterminated\nTRAILING='a' b\n");
+        assert_eq!(contents.vars, vec![var("GOOD", "1")]);
+        let lines: Vec<usize> = contents.malformed.iter().map(|malformed| malformed.line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 5]);
+    }
+}
@@ -10,9 +10,13 @@ pub enum ProtocolError {
     #[error("Serialization error: {0}")]
     Serialization(String),
     
-    /// Invalid frame format
-    #[error("Invalid frame format")]
-    InvalidFrame,
+    /// Malformed frame, with the reason it was rejected
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+    
+    /// Reserved or mutually exclusive frame flags
+    #[error("Invalid frame flags: {0:#010b}")]
+    InvalidFlags(u8),
     
     /// Frame too large
     #[error("Frame too large: {size} bytes (max: {max})")]
@@ -23,6 +27,17 @@ pub enum ProtocolError {
         max: usize 
     },
     
+    /// The reader reached end of file part way through a frame
+    #[error("Unexpected end of stream inside a frame ({buffered} bytes buffered)")]
+    UnexpectedEof {
+        /// Bytes of the unfinished frame that had arrived
+        buffered: usize,
+    },
+    
+    /// Reading from or writing to the underlying transport failed
+    #[error("Transport error: {0}")]
+    Transport(String),
+    
     /// Stream closed
     #[error("Stream closed")]
     StreamClosed,
@@ -39,7 +54,7 @@ pub enum ProtocolError {
 impl From<ErrorDetails> for ProtocolError {
     fn from(details: ErrorDetails) -> Self {
         match details.code {
-            ErrorCode::InvalidRequest => Self::InvalidFrame,
+            ErrorCode::InvalidRequest => Self::InvalidFrame(details.message),
             _ => Self::Serialization(details.message),
         }
     }
@@ -51,8 +66,11 @@ impl From<ProtocolError> for ErrorDetails {
             ProtocolError::Serialization(msg) => {
                 ErrorDetails::new(ErrorCode::InvalidRequest, msg)
             }
-            ProtocolError::InvalidFrame => {
-                ErrorDetails::new(ErrorCode::InvalidRequest, "Invalid frame format")
+            ProtocolError::InvalidFrame(reason) => {
+                ErrorDetails::new(ErrorCode::InvalidRequest, format!("Invalid frame: {}", reason))
+            }
+            ProtocolError::InvalidFlags(bits) => {
+                ErrorDetails::new(ErrorCode::InvalidRequest, format!("Invalid frame flags: {:#010b}", bits))
             }
             ProtocolError::FrameTooLarge { size, max } => {
                 ErrorDetails::new(
@@ -60,6 +78,15 @@ impl From<ProtocolError> for ErrorDetails {
                     format!("Frame too large: {} bytes (max: {})", size, max)
                 )
             }
+            ProtocolError::UnexpectedEof { buffered } => {
+                ErrorDetails::new(
+                    ErrorCode::InvalidRequest,
+                    format!("Unexpected end of stream inside a frame ({} bytes buffered)", buffered)
+                )
+            }
+            ProtocolError::Transport(msg) => {
+                ErrorDetails::new(ErrorCode::InternalError, format!("Transport error: {}", msg))
+            }
             ProtocolError::StreamClosed => {
                 ErrorDetails::new(ErrorCode::InternalError, "Stream closed")
             }
@@ -0,0 +1,104 @@
+//! Explicit `${VAR}` expansion from a request's own environment
+//!
+//! Only the braced form is recognised, and only variables from the map passed
+//! in are used, never the agent's environment:
+//!
+//! - `${NAME}` is replaced by the value of `NAME`; an undefined name is an error
+//! - `${NAME:-fallback}` uses `fallback` when `NAME` is undefined or empty
+//! - `$$` is a literal `$`
+//!
+//! Any other `$` is left as it is, so `$HOME` passes through untouched.
+
+use crate::message::{ErrorCode, ErrorDetails};
+use std::collections::HashMap;
+
+/// Expand the `${VAR}` tokens in `input` using `vars`
+pub fn expand_vars(input: &str, vars: &HashMap<String, String>) -> Result<String, ErrorDetails> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    
+    while let Some(dollar) = rest.find('$') {
+        output.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        
+        if let Some(after) = after.strip_prefix('$') {
+            output.push('$');
+            rest = after;
+        } else if let Some(token) = after.strip_prefix('{') {
+            let end = token.find('}').ok_or_else(|| {
+                ErrorDetails::new(ErrorCode::InvalidRequest, format!("Unterminated variable reference in {:?}", input))
+            })?;
+            output.push_str(&lookup(&token[..end], vars)?);
+            rest = &token[end + 1..];
+        } else {
+            output.push('$');
+            rest = after;
+        }
+    }
+    
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Value of a `NAME` or `NAME:-fallback` reference
+fn lookup(reference: &str, vars: &HashMap<String, String>) -> Result<String, ErrorDetails> {
+    let (name, fallback) = match reference.split_once(":-") {
+        Some((name, fallback)) => (name, Some(fallback)),
+        None => (reference, None),
+    };
+    
+    let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(ErrorDetails::new(ErrorCode::InvalidRequest, format!("Invalid variable name {:?}", name)));
+    }
+    
+    match (vars.get(name), fallback) {
+        (Some(value), Some(fallback)) if value.is_empty() => Ok(fallback.to_string()),
+        (Some(value), _) => Ok(value.clone()),
+        (None, Some(fallback)) => Ok(fallback.to_string()),
+        (None, None) => Err(ErrorDetails::new(ErrorCode::InvalidRequest, format!("Undefined variable {}", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn vars() -> HashMap<String, String> {
+        HashMap::from([
+            ("HOME".to_string(), "/home/deploy".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ])
+    }
+    
+    #[test]
+    fn test_expands_defined_variable() {
+        assert_eq!(expand_vars("${HOME}/app", &vars()).unwrap(), "/home/deploy/app");
+        assert_eq!(expand_vars("no variables", &vars()).unwrap(), "no variables");
+    }
+    
+    #[test]
+    fn test_undefined_variable_is_an_error() {
+        let error = expand_vars("${MISSING}/app", &vars()).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+        assert!(error.message.contains("MISSING"));
+        
+        assert!(expand_vars("${HOME", &vars()).is_err());
+        assert!(expand_vars("${1BAD}", &vars()).is_err());
+    }
+    
+    #[test]
+    fn test_default_syntax() {
+        assert_eq!(expand_vars("${MISSING:-/tmp}", &vars()).unwrap(), "/tmp");
+        assert_eq!(expand_vars("${EMPTY:-fallback}", &vars()).unwrap(), "fallback");
+        assert_eq!(expand_vars("${HOME:-/tmp}", &vars()).unwrap(), "/home/deploy");
+    }
+    
+    #[test]
+    fn test_escaped_and_bare_dollars_are_left_alone() {
+        assert_eq!(expand_vars("$${HOME}", &vars()).unwrap(), "${HOME}");
+        assert_eq!(expand_vars("cost: $$5", &vars()).unwrap(), "cost: $5");
+        assert_eq!(expand_vars("$HOME and $", &vars()).unwrap(), "$HOME and $");
+    }
+}
@@ -1,36 +1,62 @@
 //! Frame structure and serialization
 
+use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
 use bytes::Bytes;
 use crate::ProtocolError;
 
-/// Frame flags for protocol control
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub struct FrameFlags(pub u8);
+bitflags! {
+    /// Frame flags for protocol control
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct FrameFlags: u8 {
+        /// End of stream flag
+        const END_STREAM = 1;
+        /// Error flag
+        const ERROR = 1 << 1;
+        /// Flow control flag
+        const FLOW_CONTROL = 1 << 2;
+        /// The frame's header is followed by a binary attachment
+        const ATTACHMENT = 1 << 3;
+    }
+}
 
 impl FrameFlags {
     /// No special flags
-    pub const NONE: Self = Self(0);
-    /// End of stream flag
-    pub const END_STREAM: Self = Self(1);
-    /// Error flag
-    pub const ERROR: Self = Self(2);
-    /// Flow control flag
-    pub const FLOW_CONTROL: Self = Self(4);
+    pub const NONE: Self = Self::empty();
+    /// Bits not assigned to any flag, which a peer must not set
+    pub const RESERVED_BITS: u8 = !Self::all().bits();
     
     /// Check if a flag is set
     pub fn has_flag(self, flag: FrameFlags) -> bool {
-        (self.0 & flag.0) != 0
+        self.intersects(flag)
     }
     
     /// Set a flag
     pub fn set_flag(&mut self, flag: FrameFlags) {
-        self.0 |= flag.0;
+        self.insert(flag);
     }
     
     /// Clear a flag
     pub fn clear_flag(&mut self, flag: FrameFlags) {
-        self.0 &= !flag.0;
+        self.remove(flag);
+    }
+    
+    /// Check that no reserved bits are set and no mutually exclusive flags are combined
+    ///
+    /// Flow control frames only carry window updates, so they can't also end a
+    /// stream or report an error. Only data frames carry attachments.
+    pub fn validate(self) -> Result<Self, ProtocolError> {
+        if self.bits() & Self::RESERVED_BITS != 0 {
+            return Err(ProtocolError::InvalidFlags(self.bits()));
+        }
+        if self.contains(Self::FLOW_CONTROL) && self.intersects(Self::END_STREAM | Self::ERROR) {
+            return Err(ProtocolError::InvalidFlags(self.bits()));
+        }
+        if self.contains(Self::ATTACHMENT) && self.intersects(Self::END_STREAM | Self::ERROR | Self::FLOW_CONTROL) {
+            return Err(ProtocolError::InvalidFlags(self.bits()));
+        }
+        Ok(self)
     }
 }
 
@@ -45,6 +71,14 @@ pub struct Frame {
     pub flags: FrameFlags,
     /// Frame payload
     pub payload: Bytes,
+    /// Binary data sent after the frame's header rather than inside its encoding
+    ///
+    /// [`crate::FrameCodec`] writes it straight from this buffer and splits it
+    /// off the read buffer on the other side, so large message fields moved
+    /// here with [`crate::Message::to_frame`] are never copied into an
+    /// encoding. Set only with [`FrameFlags::ATTACHMENT`].
+    #[serde(skip)]
+    pub attachment: Bytes,
 }
 
 impl Frame {
@@ -55,9 +89,29 @@ impl Frame {
             sequence,
             flags,
             payload,
+            attachment: Bytes::new(),
         }
     }
     
+    /// Carry `attachment` after the frame's header, setting [`FrameFlags::ATTACHMENT`]
+    pub fn with_attachment(mut self, attachment: Bytes) -> Self {
+        self.flags.insert(FrameFlags::ATTACHMENT);
+        self.attachment = attachment;
+        self
+    }
+    
+    /// Create a frame, checking it with [`Frame::validate`]
+    pub fn try_new(stream_id: u32, sequence: u32, flags: FrameFlags, payload: Bytes) -> Result<Self, ProtocolError> {
+        let frame = Self::new(stream_id, sequence, flags, payload);
+        frame.validate()?;
+        Ok(frame)
+    }
+    
+    /// Create a data frame, checking it with [`Frame::validate`]
+    pub fn try_data(stream_id: u32, sequence: u32, payload: Bytes) -> Result<Self, ProtocolError> {
+        Self::try_new(stream_id, sequence, FrameFlags::NONE, payload)
+    }
+    
     /// Create a data frame
     pub fn data(stream_id: u32, sequence: u32, payload: Bytes) -> Self {
         Self::new(stream_id, sequence, FrameFlags::NONE, payload)
@@ -73,6 +127,39 @@ impl Frame {
         Self::new(stream_id, sequence, FrameFlags::ERROR, payload)
     }
     
+    /// Create a flow control frame
+    pub fn flow_control(stream_id: u32, sequence: u32, payload: Bytes) -> Self {
+        Self::new(stream_id, sequence, FrameFlags::FLOW_CONTROL, payload)
+    }
+    
+    /// Check that the flags and payload agree with the kind of frame
+    ///
+    /// Error and flow control frames carry a payload; end-of-stream frames
+    /// don't. A data frame may be empty: it is a no-op that still takes a
+    /// sequence number, and unlike an end-of-stream frame leaves the stream
+    /// open. Flag combinations must also pass [`FrameFlags::validate`].
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        self.flags.validate()?;
+        
+        let reason = if self.is_error() {
+            self.payload.is_empty().then(|| "error frame has no error payload".to_string())
+        } else if self.is_end_stream() {
+            (!self.payload.is_empty())
+                .then(|| format!("end-of-stream frame carries {} payload bytes", self.payload.len()))
+        } else if self.flags.contains(FrameFlags::FLOW_CONTROL) {
+            self.payload.is_empty().then(|| "flow control frame has no window update".to_string())
+        } else if self.has_attachment() == self.attachment.is_empty() {
+            Some("attachment flag doesn't match the attachment".to_string())
+        } else {
+            None
+        };
+        
+        match reason {
+            Some(reason) => Err(ProtocolError::InvalidFrame(format!("{} (stream {})", reason, self.stream_id))),
+            None => Ok(()),
+        }
+    }
+    
     /// Serialize frame to MessagePack bytes
     pub fn to_msgpack(&self) -> Result<Vec<u8>, ProtocolError> {
         rmp_serde::to_vec(self)
@@ -99,6 +186,46 @@ impl Frame {
     pub fn is_error(&self) -> bool {
         self.flags.has_flag(FrameFlags::ERROR)
     }
+    
+    /// Check if this is a flow control frame
+    pub fn is_flow_control(&self) -> bool {
+        self.flags.has_flag(FrameFlags::FLOW_CONTROL)
+    }
+    
+    /// Check if the frame carries an attachment
+    pub fn has_attachment(&self) -> bool {
+        self.flags.has_flag(FrameFlags::ATTACHMENT)
+    }
+    
+    /// One-line summary of the frame's header and payload size, for logs
+    ///
+    /// For example `stream=3 seq=7 flags=END_STREAM|ERROR len=12`, with
+    /// `flags=NONE` for a data frame.
+    pub fn describe(&self) -> String {
+        let flags = if self.flags.is_empty() {
+            "NONE".to_string()
+        } else {
+            self.flags.iter_names().map(|(name, _)| name).collect::<Vec<_>>().join("|")
+        };
+        let mut description = format!("stream={} seq={} flags={} len={}", self.stream_id, self.sequence, flags, self.payload.len());
+        if self.has_attachment() {
+            description.push_str(&format!(" attachment={}", self.attachment.len()));
+        }
+        description
+    }
+    
+    /// Hex of the first `limit` payload bytes, with `..` if the payload is longer
+    pub fn payload_hex(&self, limit: usize) -> String {
+        let mut hex: String = self.payload.iter()
+            .take(limit)
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if self.payload.len() > limit {
+            hex.push_str(" ..");
+        }
+        hex
+    }
 }
 
 #[cfg(test)]
@@ -118,6 +245,36 @@ mod tests {
         assert!(!flags.has_flag(FrameFlags::END_STREAM));
     }
     
+    #[test]
+    fn test_frame_flag_combinations() {
+        let mut flags = FrameFlags::END_STREAM | FrameFlags::ERROR;
+        assert!(flags.contains(FrameFlags::END_STREAM));
+        assert!(flags.contains(FrameFlags::ERROR));
+        assert!(!flags.contains(FrameFlags::FLOW_CONTROL));
+        assert!(flags.validate().is_ok());
+        
+        flags.toggle(FrameFlags::ERROR);
+        assert_eq!(flags, FrameFlags::END_STREAM);
+        
+        flags.set(FrameFlags::END_STREAM, false);
+        assert_eq!(flags, FrameFlags::NONE);
+        assert!(flags.is_empty());
+        
+        assert_eq!(FrameFlags::all().bits(), 0b1111);
+        assert_eq!(FrameFlags::RESERVED_BITS, 0b1111_0000);
+        assert_eq!(FrameFlags::from_bits(0b1_0000), None);
+    }
+    
+    #[test]
+    fn test_frame_flag_validation() {
+        assert!(FrameFlags::FLOW_CONTROL.validate().is_ok());
+        assert!(FrameFlags::from_bits_retain(0x80).validate().is_err());
+        assert!((FrameFlags::FLOW_CONTROL | FrameFlags::END_STREAM).validate().is_err());
+        assert!((FrameFlags::FLOW_CONTROL | FrameFlags::ERROR).validate().is_err());
+        assert!(FrameFlags::ATTACHMENT.validate().is_ok());
+        assert!((FrameFlags::ATTACHMENT | FrameFlags::END_STREAM).validate().is_err());
+    }
+    
     #[test]
     fn test_frame_creation() {
         let payload = Bytes::from("test payload");
@@ -148,6 +305,50 @@ mod tests {
         assert_eq!(frame.payload, payload);
     }
     
+    #[test]
+    fn test_frame_validation() {
+        assert!(Frame::data(1, 0, Bytes::from("data")).validate().is_ok());
+        assert!(Frame::data(1, 0, Bytes::new()).validate().is_ok());
+        assert!(Frame::end_stream(1, 1).validate().is_ok());
+        assert!(Frame::error(1, 1, Bytes::from("failed")).validate().is_ok());
+        assert!(Frame::new(1, 1, FrameFlags::ERROR | FrameFlags::END_STREAM, Bytes::from("failed")).validate().is_ok());
+        assert!(Frame::new(1, 1, FrameFlags::FLOW_CONTROL, Bytes::from(vec![0, 0, 1, 0])).validate().is_ok());
+        
+        assert!(Frame::data(1, 0, Bytes::from("header")).with_attachment(Bytes::from("body")).validate().is_ok());
+        
+        let invalid = [
+            Frame::new(1, 1, FrameFlags::END_STREAM, Bytes::from("trailing")),
+            Frame::error(1, 1, Bytes::new()),
+            Frame::new(1, 1, FrameFlags::FLOW_CONTROL, Bytes::new()),
+            Frame::data(1, 1, Bytes::from("header")).with_attachment(Bytes::new()),
+        ];
+        for frame in invalid {
+            assert!(matches!(frame.validate(), Err(ProtocolError::InvalidFrame(_))), "{:?}", frame);
+        }
+        
+        let reserved = Frame::new(1, 1, FrameFlags::from_bits_retain(0x10), Bytes::from("data"));
+        assert!(matches!(reserved.validate(), Err(ProtocolError::InvalidFlags(0x10))));
+        
+        match Frame::try_new(7, 0, FrameFlags::ERROR, Bytes::new()) {
+            Err(ProtocolError::InvalidFrame(reason)) => assert!(reason.contains("stream 7"), "{}", reason),
+            other => panic!("expected InvalidFrame, got {:?}", other),
+        }
+        assert!(Frame::try_new(1, 1, FrameFlags::END_STREAM, Bytes::new()).is_ok());
+        assert!(Frame::try_data(1, 1, Bytes::new()).is_ok());
+    }
+    
+    #[test]
+    fn test_describe_frame() {
+        let frame = Frame::data(3, 7, Bytes::from_static(b"\x01\xabpayload"));
+        assert_eq!(frame.describe(), "stream=3 seq=7 flags=NONE len=9");
+        assert_eq!(frame.payload_hex(3), "01 ab 70 ..");
+        assert_eq!(frame.payload_hex(9), "01 ab 70 61 79 6c 6f 61 64");
+        
+        let frame = Frame::new(1, 0, FrameFlags::END_STREAM | FrameFlags::ERROR, Bytes::new());
+        assert_eq!(frame.describe(), "stream=1 seq=0 flags=END_STREAM|ERROR len=0");
+        assert_eq!(frame.payload_hex(16), "");
+    }
+    
     #[test]
     fn test_msgpack_serialization_roundtrip() {
         let payload = Bytes::from("test payload data");
@@ -162,6 +363,17 @@ mod tests {
         assert_eq!(original.payload, deserialized.payload);
     }
     
+    #[test]
+    fn test_empty_data_frame_is_not_end_stream() {
+        let frame = Frame::data(1, 3, Bytes::new());
+        let deserialized = Frame::from_msgpack(&frame.to_msgpack().unwrap()).unwrap();
+        
+        assert_eq!(deserialized.sequence, 3);
+        assert_eq!(deserialized.flags, FrameFlags::NONE);
+        assert!(deserialized.payload.is_empty());
+        assert!(!deserialized.is_end_stream());
+    }
+    
     #[test]
     fn test_empty_payload_serialization() {
         let frame = Frame::end_stream(1, 1);
@@ -186,7 +398,7 @@ mod tests {
             let frame = Frame::new(
                 stream_id,
                 sequence,
-                FrameFlags(flags),
+                FrameFlags::from_bits_retain(flags),
                 Bytes::from(payload)
             );
             
@@ -0,0 +1,215 @@
+//! JSON-RPC 2.0 mapping onto `JsonCall` requests
+//!
+//! Lets standard JSON-RPC clients talk to an agent using newline-delimited
+//! JSON instead of binary frames. A connection whose first byte is `{`
+//! ([`JSONRPC_HANDSHAKE_BYTE`]) selects this mode; binary frames always start
+//! with a length prefix whose first byte is at most `0x01`.
+
+use crate::message::{ErrorCode, ErrorDetails, Request, Response};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// First byte of a connection that speaks JSON-RPC
+pub const JSONRPC_HANDSHAKE_BYTE: u8 = b'{';
+
+/// Protocol version string required by JSON-RPC 2.0
+pub const JSONRPC_VERSION: &str = "2.0";
+
+/// Invalid JSON was received
+pub const PARSE_ERROR: i64 = -32700;
+/// The JSON sent is not a valid request object
+pub const INVALID_REQUEST: i64 = -32600;
+/// The method does not exist
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// Invalid method parameters
+pub const INVALID_PARAMS: i64 = -32602;
+/// Internal JSON-RPC error
+pub const INTERNAL_ERROR: i64 = -32603;
+/// Implementation-defined server error
+pub const SERVER_ERROR: i64 = -32000;
+
+/// JSON-RPC 2.0 request object
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcRequest {
+    /// Protocol version, must be "2.0"
+    pub jsonrpc: String,
+    /// Method to invoke
+    pub method: String,
+    /// Method parameters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    /// Request identifier; absent for notifications
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+}
+
+/// JSON-RPC 2.0 error object
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcError {
+    /// Error code
+    pub code: i64,
+    /// Short error description
+    pub message: String,
+    /// Additional error information
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+/// JSON-RPC 2.0 response object
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcResponse {
+    /// Protocol version, always "2.0"
+    pub jsonrpc: String,
+    /// Result on success
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    /// Error on failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+    /// Identifier of the request this answers (null if it could not be read)
+    pub id: Value,
+}
+
+impl JsonRpcRequest {
+    /// Whether this is a notification, which gets no response
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+    
+    /// Convert into a `JsonCall` request
+    pub fn to_request(&self) -> Result<Request, JsonRpcError> {
+        if self.jsonrpc != JSONRPC_VERSION {
+            return Err(JsonRpcError::new(INVALID_REQUEST, "Unsupported JSON-RPC version"));
+        }
+        
+        let params = match &self.params {
+            Some(params @ (Value::Array(_) | Value::Object(_))) => params.clone(),
+            Some(_) => return Err(JsonRpcError::new(INVALID_PARAMS, "Params must be an array or object")),
+            None => Value::Null,
+        };
+        let params = serde_json::to_vec(&params)
+            .map_err(|e| JsonRpcError::new(INTERNAL_ERROR, e.to_string()))?;
+        
+        Ok(Request::JsonCall {
+            id: Uuid::new_v4(),
+            method: self.method.clone(),
+            params: Bytes::from(params),
+        })
+    }
+}
+
+impl JsonRpcError {
+    /// Create an error object without data
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+impl From<&ErrorDetails> for JsonRpcError {
+    fn from(details: &ErrorDetails) -> Self {
+        let code = match details.code {
+            ErrorCode::InvalidRequest => INVALID_PARAMS,
+            ErrorCode::Unsupported => METHOD_NOT_FOUND,
+            ErrorCode::InternalError => INTERNAL_ERROR,
+            _ => SERVER_ERROR,
+        };
+        Self {
+            code,
+            message: details.message.clone(),
+            data: serde_json::to_value(details).ok(),
+        }
+    }
+}
+
+impl JsonRpcResponse {
+    /// Create a success response
+    pub fn success(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            result: Some(result),
+            error: None,
+            id,
+        }
+    }
+    
+    /// Create an error response
+    pub fn failure(id: Value, error: JsonRpcError) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            result: None,
+            error: Some(error),
+            id,
+        }
+    }
+    
+    /// Convert an agent response to the request with the given JSON-RPC id
+    pub fn from_response(id: Value, response: Response) -> Self {
+        match response {
+            Response::JsonResult { result, .. } => match serde_json::from_slice(&result) {
+                Ok(value) => Self::success(id, value),
+                Err(e) => Self::failure(id, JsonRpcError::new(INTERNAL_ERROR, format!("Invalid result JSON: {}", e))),
+            },
+            Response::Error { error, .. } => Self::failure(id, JsonRpcError::from(&error)),
+            _ => Self::failure(id, JsonRpcError::new(INTERNAL_ERROR, "Unexpected response type")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    #[test]
+    fn test_request_to_json_call() {
+        let request: JsonRpcRequest = serde_json::from_value(json!({
+            "jsonrpc": "2.0", "method": "sum", "params": [1, 2], "id": 7
+        })).unwrap();
+        
+        assert!(!request.is_notification());
+        match request.to_request().unwrap() {
+            Request::JsonCall { method, params, .. } => {
+                assert_eq!(method, "sum");
+                assert_eq!(serde_json::from_slice::<Value>(&params).unwrap(), json!([1, 2]));
+            }
+            _ => panic!("Expected JsonCall request"),
+        }
+    }
+    
+    #[test]
+    fn test_request_validation() {
+        let wrong_version: JsonRpcRequest = serde_json::from_value(json!({
+            "jsonrpc": "1.0", "method": "sum", "id": 1
+        })).unwrap();
+        assert_eq!(wrong_version.to_request().unwrap_err().code, INVALID_REQUEST);
+        
+        let scalar_params: JsonRpcRequest = serde_json::from_value(json!({
+            "jsonrpc": "2.0", "method": "sum", "params": 3, "id": 1
+        })).unwrap();
+        assert_eq!(scalar_params.to_request().unwrap_err().code, INVALID_PARAMS);
+    }
+    
+    #[test]
+    fn test_response_conversion() {
+        let ok = JsonRpcResponse::from_response(
+            json!(7),
+            Response::JsonResult { request_id: Uuid::new_v4(), result: Bytes::from("3") },
+        );
+        assert_eq!(serde_json::to_value(&ok).unwrap(), json!({"jsonrpc": "2.0", "result": 3, "id": 7}));
+        
+        let err = JsonRpcResponse::from_response(
+            json!("a"),
+            Response::error(Uuid::new_v4(), ErrorDetails::new(ErrorCode::Unsupported, "no such method")),
+        );
+        let error = err.error.unwrap();
+        assert_eq!(error.code, METHOD_NOT_FOUND);
+        assert_eq!(error.message, "no such method");
+        assert!(err.result.is_none());
+    }
+}
@@ -19,8 +19,35 @@ pub mod stream;
 /// Error types for protocol operations
 pub mod error;
 
+/// Connection-level stream compression
+pub mod compression;
+
+/// Send rate limiting
+pub mod rate_limit;
+
+/// `${VAR}` expansion in process requests
+pub mod expand;
+
+/// `.env` file parsing for process requests
+pub mod envfile;
+
+/// Restricted rewriting of transferred file content
+pub mod transform;
+
+/// Block signatures and deltas of changed files
+pub mod delta;
+
+/// Lossless encoding of paths in messages
+pub mod path;
+
+/// JSON-RPC 2.0 interop framing
+#[cfg(feature = "jsonrpc")]
+pub mod jsonrpc;
+
 pub use frame::{Frame, FrameFlags};
-pub use message::{Message, Request, Response};
+pub use message::{Event, EventKind, Message, Request, Response};
 pub use codec::FrameCodec;
-pub use stream::{StreamMultiplexer, StreamHandle, StreamState};
+pub use compression::{CompressedReader, CompressedWriter, CompressionDictionary};
+pub use rate_limit::{RateLimit, RateLimiter};
+pub use stream::{FlowControlMessage, StreamMultiplexer, StreamHandle, StreamState};
 pub use error::ProtocolError;
\ No newline at end of file
@@ -2,9 +2,34 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use bytes::Bytes;
 use uuid::Uuid;
+use crate::delta::{DeltaOp, Signature};
+use crate::error::ProtocolError;
+use crate::expand::expand_vars;
+use crate::frame::Frame;
+
+/// Size from which [`Message::to_frame`] sends file content as a frame attachment
+///
+/// Both ends use it until a `SessionOpen` agrees on another.
+pub const DEFAULT_ATTACHMENT_THRESHOLD: usize = 64 * 1024;
+
+/// Bytes a client may send on a request's stream before the agent returns
+/// credit for them with a [`FlowControlMessage::WindowUpdate`](crate::FlowControlMessage)
+pub const STREAM_INPUT_WINDOW: u32 = 256 * 1024;
+
+/// Bytes of partial responses the agent may send for a request with an
+/// output window before the client returns credit for them
+pub const STREAM_OUTPUT_WINDOW: u32 = 256 * 1024;
+
+/// Smallest output window a client may ask for with a
+/// [`FlowControlMessage::ResizeWindow`](crate::FlowControlMessage); smaller
+/// sizes are raised to it
+pub const MIN_STREAM_OUTPUT_WINDOW: u32 = 64 * 1024;
+
+/// Largest output window a client may ask for; larger sizes are lowered to it
+pub const MAX_STREAM_OUTPUT_WINDOW: u32 = 16 * 1024 * 1024;
 
 /// Top-level message wrapper
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,6 +38,8 @@ pub enum Message {
     Request(Request),
     /// Response message
     Response(Response),
+    /// Unsolicited notification from the agent
+    Event(Event),
 }
 
 impl Message {
@@ -26,11 +53,66 @@ impl Message {
         Self::Response(resp)
     }
     
+    /// Create an event message
+    pub fn event(event: Event) -> Self {
+        Self::Event(event)
+    }
+    
     /// Get the request ID if this is a request
     pub fn request_id(&self) -> Option<Uuid> {
         match self {
             Self::Request(req) => Some(req.id()),
             Self::Response(resp) => Some(resp.request_id()),
+            Self::Event(event) => event.request_id(),
+        }
+    }
+    
+    /// Encode the message as a data frame
+    ///
+    /// File content of at least `attachment_threshold` bytes, in `FilePut`,
+    /// `FileContent` or `FileChunk`, is moved out of the message and carried
+    /// as the frame's attachment, so it is never copied into the encoding.
+    /// With no threshold everything is encoded inline.
+    pub fn to_frame(mut self, stream_id: u32, sequence: u32, attachment_threshold: Option<usize>) -> Result<Frame, ProtocolError> {
+        let attachment = match (attachment_threshold, self.binary_field()) {
+            (Some(threshold), Some(field)) if !field.is_empty() && field.len() >= threshold => {
+                Some(std::mem::take(field))
+            }
+            _ => None,
+        };
+        
+        let payload = rmp_serde::to_vec(&self)
+            .map_err(|e| ProtocolError::Serialization(e.to_string()))?;
+        let frame = Frame::data(stream_id, sequence, Bytes::from(payload));
+        Ok(match attachment {
+            Some(attachment) => frame.with_attachment(attachment),
+            None => frame,
+        })
+    }
+    
+    /// Decode a message from a data frame, putting back file content sent as its attachment
+    pub fn from_frame(frame: Frame) -> Result<Self, ProtocolError> {
+        let mut message: Self = rmp_serde::from_slice(&frame.payload)
+            .map_err(|e| ProtocolError::Serialization(e.to_string()))?;
+        if frame.has_attachment() {
+            let Some(field) = message.binary_field() else {
+                return Err(ProtocolError::InvalidFrame(format!(
+                    "attachment on a message without file content (stream {})", frame.stream_id
+                )));
+            };
+            *field = frame.attachment;
+        }
+        Ok(message)
+    }
+    
+    /// File content of the message that can travel as a frame attachment
+    fn binary_field(&mut self) -> Option<&mut Bytes> {
+        match self {
+            Self::Request(request) => request.binary_field(),
+            Self::Response(Response::FileContent { content, .. }) => Some(content),
+            Self::Response(Response::FileChunk { data, .. }) => Some(data),
+            Self::Response(Response::TunnelData { data, .. }) => Some(data),
+            _ => None,
         }
     }
 }
@@ -47,11 +129,64 @@ pub enum Request {
         /// Environment variables
         env: HashMap<String, String>,
         /// Working directory
+        #[serde(with = "crate::path::option")]
         cwd: Option<PathBuf>,
         /// Standard input data
         stdin: Option<Bytes>,
         /// Timeout in seconds
         timeout: Option<u64>,
+        /// Standard input is streamed as data frames on the request's stream,
+        /// terminated by an end-of-stream frame
+        #[serde(default)]
+        stdin_stream: bool,
+        /// Resource limits applied to the child before it runs
+        #[serde(default)]
+        limits: Option<ProcessLimits>,
+        /// Send output as `ProcessOutput` responses while the process runs
+        #[serde(default)]
+        stream_output: bool,
+        /// Split streamed output on newlines so each chunk holds whole lines
+        #[serde(default)]
+        line_buffered: bool,
+        /// Longest line kept in the line buffer before it is flushed as-is
+        #[serde(default)]
+        max_line_length: Option<usize>,
+        /// Send stderr into the stdout pipe, preserving their interleaving
+        #[serde(default)]
+        merge_stderr: bool,
+        /// Most bytes captured per output stream, overriding the agent default
+        #[serde(default)]
+        max_output_bytes: Option<u64>,
+        /// Which part of the output is kept when capture is truncated
+        #[serde(default)]
+        output_truncation: OutputTruncation,
+        /// Kill the process as soon as its output exceeds the capture limit
+        #[serde(default)]
+        kill_on_output_limit: bool,
+        /// Expand `${VAR}` in the command and working directory from `env` alone;
+        /// see [`crate::expand`]
+        #[serde(default)]
+        expand_env: bool,
+        /// Extra descriptors (3 and up) the child gets as the write ends of pipes;
+        /// what it writes to them is returned as `OutputStream::Fd` output
+        #[serde(default)]
+        output_fds: Vec<u32>,
+        /// Start the process in a session of its own with its stdio on the null
+        /// device, and answer with its PID straight away instead of waiting for it
+        ///
+        /// The process outlives the request and the connection.
+        #[serde(default)]
+        detach: bool,
+        /// Write stdout to this file on the agent instead of returning it;
+        /// with `merge_stderr`, stderr goes there too
+        #[serde(default)]
+        stdout_file: Option<OutputFile>,
+        /// Write stderr to this file on the agent instead of returning it
+        #[serde(default)]
+        stderr_file: Option<OutputFile>,
+        /// Read more variables from this file on the agent; `env` takes precedence
+        #[serde(default)]
+        env_file: Option<EnvFile>,
     },
     
     /// File get operation
@@ -59,9 +194,25 @@ pub enum Request {
         /// Request ID for correlation
         id: Uuid,
         /// Path to file
+        #[serde(with = "crate::path")]
         path: PathBuf,
         /// Optional byte range (start, end)
         range: Option<(u64, u64)>,
+        /// Decompress the file before returning it; the range then applies to the plaintext
+        #[serde(default)]
+        decompress: Option<Compression>,
+        /// Send the content as `FileChunk` responses of at most this many bytes,
+        /// followed by a `FileContent` with empty content and the metadata;
+        /// the chunks are held to the request's output window
+        #[serde(default)]
+        chunk_size: Option<u32>,
+        /// Include the file's extended attributes in the metadata
+        #[serde(default)]
+        include_xattrs: bool,
+        /// Rewrite the content, in order, before it is sent; the metadata
+        /// still describes the file as stored
+        #[serde(default)]
+        transforms: Vec<ContentTransform>,
     },
     
     /// File put operation
@@ -69,6 +220,7 @@ pub enum Request {
         /// Request ID for correlation
         id: Uuid,
         /// Path to file
+        #[serde(with = "crate::path")]
         path: PathBuf,
         /// File content
         content: Bytes,
@@ -76,6 +228,44 @@ pub enum Request {
         mode: Option<u32>,
         /// Create parent directories
         create_dirs: bool,
+        /// Permission bits masked out of created files and directories,
+        /// overriding the agent's umask; an explicit `mode` still wins for the file
+        #[serde(default)]
+        umask: Option<u32>,
+        /// Last modified time to give the file, in seconds since the Unix epoch
+        #[serde(default)]
+        modified: Option<u64>,
+        /// Write a temporary file beside the path and rename it over the path,
+        /// so a failed put leaves any existing file unchanged
+        #[serde(default)]
+        atomic: bool,
+        /// Rewrite the content, in order, before it is written
+        #[serde(default)]
+        transforms: Vec<ContentTransform>,
+        /// What to do if the path already exists
+        #[serde(default)]
+        on_exists: OnExists,
+        /// The content follows on the request's stream instead of in `content`,
+        /// so neither end has to hold all of it
+        #[serde(default)]
+        stream_content: bool,
+    },
+    
+    /// Apply a unified diff to a text file
+    ///
+    /// The patched file replaces the original atomically, so a patch that
+    /// fails to apply leaves it untouched.
+    FilePatchText {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to the file to patch
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// Unified diff to apply
+        patch: String,
+        /// Keep the original next to the file with a `.bak` suffix
+        #[serde(default)]
+        create_backup: bool,
     },
     
     /// Directory listing
@@ -83,23 +273,80 @@ pub enum Request {
         /// Request ID for correlation
         id: Uuid,
         /// Directory path
+        #[serde(with = "crate::path")]
         path: PathBuf,
         /// Include hidden files
         include_hidden: bool,
         /// Recursive listing
         recursive: bool,
+        /// Stream entries in `DirEntries` batches of at most this many, with the
+        /// client acknowledging each batch on the request's stream
+        #[serde(default)]
+        batch_size: Option<u32>,
+        /// Levels of subdirectories a recursive listing descends into; deeper
+        /// directories are listed as entries without their contents. 0 lists
+        /// the target directory only, `None` descends without limit
+        #[serde(default)]
+        max_depth: Option<usize>,
     },
     
     /// WASM module execution
     WasmExec {
         /// Request ID for correlation
         id: Uuid,
-        /// WASM module bytecode
+        /// WASM module bytecode, empty when running a stored module by hash
         module: Bytes,
         /// JSON input data
         input: Bytes,
         /// Execution timeout in seconds
         timeout: Option<u64>,
+        /// Hash of a module previously stored with `WasmUpload`
+        #[serde(default)]
+        module_hash: Option<String>,
+        /// Host patterns the module may send HTTP requests to through the agent
+        ///
+        /// Empty grants no network access; the agent must also allow it.
+        #[serde(default)]
+        network_allow: Vec<String>,
+    },
+    
+    /// Run WASM modules one after another on the agent, each on the previous one's output
+    ///
+    /// Answered with the last stage's output as a `WasmResult`. The first
+    /// stage that fails ends the pipeline with an error whose `stage` context
+    /// is its index.
+    WasmPipeline {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Modules to run, in order
+        stages: Vec<PipelineStage>,
+        /// Input of the first stage
+        input: Bytes,
+    },
+    
+    /// Describe a WASM module without running it
+    WasmInspect {
+        /// Request ID for correlation
+        id: Uuid,
+        /// WASM module bytecode, empty when inspecting a stored module by hash
+        module: Bytes,
+        /// Hash of a module previously stored with `WasmUpload`
+        #[serde(default)]
+        module_hash: Option<String>,
+    },
+    
+    /// One chunk of a WASM module upload; every chunk of an upload shares the same ID
+    WasmUpload {
+        /// Upload ID, reused for each chunk
+        id: Uuid,
+        /// Module bytes starting at `offset`
+        chunk: Bytes,
+        /// Offset of this chunk within the module
+        offset: u64,
+        /// Total module size in bytes
+        total: u64,
+        /// Whether this is the final chunk
+        eof: bool,
     },
     
     /// JSON RPC call
@@ -120,6 +367,23 @@ pub enum Request {
         timestamp: u64,
     },
     
+    /// Echo a series of probes to measure the link, answered with `PingBatchResult`
+    ///
+    /// The client sends one encoded [`PingProbe`] per stream frame, every
+    /// `interval_ms`, and the agent answers each with a partial `Pong` carrying
+    /// the probe's sequence number and payload. The batch ends after `count`
+    /// probes or when the client ends its stream.
+    PingBatch {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Probes the client sends
+        count: u32,
+        /// Milliseconds between probes
+        interval_ms: u64,
+        /// Bytes of padding in each probe, echoed back in its `Pong`
+        payload_size: u32,
+    },
+    
     /// PTY process execution with privilege escalation
     PtyExec {
         /// Request ID for correlation
@@ -129,26 +393,611 @@ pub enum Request {
         /// Environment variables
         env: HashMap<String, String>,
         /// Working directory
+        #[serde(with = "crate::path::option")]
         cwd: Option<PathBuf>,
         /// Privilege escalation method
         privilege: Option<PrivilegeEscalation>,
         /// Execution timeout in seconds
         timeout: Option<u64>,
+        /// Run the command on a real pseudoterminal and write the client's stream to it
+        ///
+        /// The stream carries one encoded [`PtyInput`] per frame; terminal output
+        /// comes back as partial `ProcessOutput` responses before the final
+        /// `PtyResult`, whose `output` is then empty.
+        #[serde(default)]
+        interactive: bool,
+        /// Initial window size of an interactive pseudoterminal
+        #[serde(default)]
+        window: Option<PtySize>,
+        /// Terminal type the command sees as `TERM`, replacing any set in `env`
+        #[serde(default)]
+        term: Option<String>,
+    },
+    
+    /// Send a signal to a running process
+    ProcessSignal {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Request ID of the ProcessExec whose process receives the signal
+        target: Uuid,
+        /// Signal name (`SIGHUP`, `USR1`) or number
+        signal: String,
+    },
+    
+    /// Open an agent session, resuming an earlier one if a token is given
+    SessionOpen {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Token from a previous `SessionOpened` on another connection
+        resume_token: Option<Uuid>,
+        /// Compress the connection's frame stream after this exchange
+        ///
+        /// The client must not send further frames until `SessionOpened` arrives.
+        #[serde(default)]
+        compression: Option<StreamCompression>,
+        /// ID of the `CompressionDictionary` to prime stream compression with
+        #[serde(default)]
+        dictionary_id: Option<u32>,
+        /// Smallest file content the client sends and takes as a frame attachment,
+        /// or None if it takes no attachments
+        #[serde(default)]
+        attachment_threshold: Option<u32>,
+    },
+    
+    /// Close the connection cleanly, answered with `SessionClosed`
+    ///
+    /// The agent stops reading requests, waits for those still running to
+    /// finish, or ends them with `ErrorCode::Cancelled` once its drain timeout
+    /// passes, removes the temporary files and directories `MkTemp` created
+    /// with `cleanup`, and answers last before it stops. The client must not
+    /// send further requests.
+    SessionClose {
+        /// Request ID for correlation
+        id: Uuid,
+    },
+    
+    /// Run requests in order on the agent, answered with one `BatchResult`
+    ///
+    /// Each request is handled as if it were sent on its own once the one
+    /// before it is answered, so a `Chdir` applies to the requests after it,
+    /// and the batch's labels go on all of them. Only final responses are
+    /// collected. Nested batches, `SessionOpen` and requests that take stream
+    /// input are refused, and a `SessionClose` must come last.
+    Batch {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Requests to run, in order
+        requests: Vec<Request>,
+        /// Skip the remaining requests after the first failed one
+        stop_on_error: bool,
+    },
+    
+    /// Read the end of a file, optionally following appended lines
+    FileTail {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to file
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// Number of lines before the end to start from
+        from_end_lines: u64,
+        /// Keep streaming appended lines until the client ends the stream
+        follow: bool,
+    },
+    
+    /// Change the session's working directory, answered with `Cwd`
+    Chdir {
+        /// Request ID for correlation
+        id: Uuid,
+        /// New working directory, relative to the current one
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+    },
+    
+    /// Get the session's working directory, answered with `Cwd`
+    Getcwd {
+        /// Request ID for correlation
+        id: Uuid,
+    },
+    
+    /// Hash a file on the agent without transferring it, answered with `FileHash`
+    FileHash {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to file
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// Digest to compute
+        algorithm: HashAlgorithm,
+    },
+    
+    /// Read a file's extended attributes, answered with `FileXattrs`
+    FileXattrGet {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to file
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+    },
+    
+    /// Set extended attributes on a file, answered with `FileXattrs` listing all of them afterwards
+    ///
+    /// Attributes not named are left as they are.
+    FileXattrSet {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to file
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// Values by attribute name, such as `user.origin` or `security.selinux`
+        xattrs: HashMap<String, Bytes>,
+    },
+    
+    /// Change who owns a file, answered with `FileOwnership`
+    ///
+    /// An owner that isn't given is left as it is, so a request with neither
+    /// only reports the current ones. With `recursive`, everything below a
+    /// directory changes too, without following symlinks.
+    FileChown {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to file or directory
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// New owning user
+        uid: Option<FileOwner>,
+        /// New owning group
+        gid: Option<FileOwner>,
+        /// Also change everything below a directory
+        #[serde(default)]
+        recursive: bool,
+    },
+    
+    /// Make a file hold exactly `content`, answered with `FileEnsure`
+    ///
+    /// Only what differs from the existing file is changed: the content is
+    /// replaced in a single rename when its hash differs, and the mode and
+    /// owner are set when given and different. Repeating the request changes
+    /// nothing.
+    FileEnsure {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to file
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// Content the file should have
+        content: Bytes,
+        /// Permission bits the file should have
+        mode: Option<u32>,
+        /// User who should own the file
+        owner: Option<FileOwner>,
+    },
+    
+    /// Compute the block signature of a file, answered with `FileSignature`
+    ///
+    /// The client compares it with its own copy to send only what changed
+    /// with `FileDeltaPut`; see [`crate::delta`].
+    FileSignature {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to file
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// Bytes per block
+        block_size: u32,
+    },
+    
+    /// Replace a file with one rebuilt from its blocks and literal data, answered with `FilePutResult`
+    ///
+    /// The ops refer to the blocks of the signature the client got with
+    /// `FileSignature`; a file whose size changed since is refused. The new
+    /// file is renamed over the old one, keeping its permissions unless
+    /// `mode` is given.
+    FileDeltaPut {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to file
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// Bytes per block of the signature the ops refer to
+        block_size: u32,
+        /// Size of the file when its signature was taken
+        base_size: u64,
+        /// Steps that rebuild the new content
+        ops: Vec<DeltaOp>,
+        /// Permission bits to give the file
+        mode: Option<u32>,
+        /// Last modified time to give the file, in seconds since the Unix epoch
+        modified: Option<u64>,
+    },
+    
+    /// Set a file's permission bits and modification time, answered with `FileAttributes`
+    ///
+    /// Attributes that aren't given are left as they are.
+    FileSetAttributes {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to file or directory
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// Permission bits to give it
+        mode: Option<u32>,
+        /// Last modified time to give it, in seconds since the Unix epoch
+        modified: Option<u64>,
+    },
+    
+    /// Remove a file, symlink or directory, answered with `FileRemoved`
+    ///
+    /// Symlinks are removed rather than followed. A directory that isn't
+    /// empty is only removed with `recursive`, along with everything below it.
+    /// Removing a path that doesn't exist succeeds.
+    FileRemove {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path to remove
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        /// Also remove everything below a directory
+        #[serde(default)]
+        recursive: bool,
+    },
+    
+    /// Report the size of the filesystem holding `path`, answered with `DiskSpace`
+    ///
+    /// The path need not exist yet: the filesystem of its nearest existing
+    /// ancestor is reported, which is where a file created there would go.
+    DiskSpace {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Path on the filesystem
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+    },
+    
+    /// Create a uniquely named temporary file or directory, answered with `TempCreated`
+    ///
+    /// The name is random and the entry is created exclusively, so concurrent
+    /// requests never get the same path and nothing can be put there first.
+    MkTemp {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Directory to create it in; the agent's temporary directory if unset
+        #[serde(with = "crate::path::option")]
+        dir: Option<PathBuf>,
+        /// Start of the name
+        prefix: String,
+        /// End of the name
+        suffix: String,
+        /// Whether to create a file or a directory
+        kind: TempKind,
+        /// Remove it, with anything below it, when the session's connection closes
+        #[serde(default)]
+        cleanup: bool,
+    },
+    
+    /// List the requests running on this connection, answered with `Operations`
+    ///
+    /// Answered by the agent loop itself, so it is answered even when the
+    /// agent is running its maximum number of requests.
+    ListOperations {
+        /// Request ID for correlation
+        id: Uuid,
+    },
+    
+    /// Cancel a running request, answered with `OperationCancelled`
+    ///
+    /// The cancelled request ends with `ErrorCode::Cancelled`.
+    CancelOperation {
+        /// Request ID for correlation
+        id: Uuid,
+        /// ID of the request to cancel
+        target: Uuid,
+    },
+    
+    /// Ask the agent what build it is, answered with `Version`
+    Version {
+        /// Request ID for correlation
+        id: Uuid,
+    },
+    
+    /// Connect the agent to a TCP address and relay bytes between it and the client
+    ///
+    /// Once connected the agent sends `TunnelOpened`. Each frame of the
+    /// client's stream is written to the connection as is, and ending the
+    /// stream shuts down its sending side; what the target sends comes back
+    /// as partial `TunnelData` responses. The request ends with
+    /// `TunnelClosed` when the target closes the connection. An address the
+    /// agent doesn't allow is refused with `ErrorCode::PermissionDenied`,
+    /// and a target that refuses the connection with
+    /// `ErrorCode::ConnectionRefused`.
+    TcpConnect {
+        /// Request ID for correlation
+        id: Uuid,
+        /// Host name or IP address, resolved by the agent
+        host: String,
+        /// TCP port
+        port: u16,
+    },
+    
+    /// Run a request under an explicit QoS class, answered as the request itself
+    WithQos {
+        /// Class the request is scheduled under
+        qos: QosClass,
+        /// Request to run
+        request: Box<Request>,
+    },
+    
+    /// Run a request at most once per key, answered as the request itself
+    ///
+    /// The agent remembers the final response for a while; a request sent
+    /// again with the same key, such as a resend after a reconnect, gets that
+    /// response instead of running again. Responses outlive the agent process
+    /// only if it saves them to its state directory.
+    WithIdempotencyKey {
+        /// Key identifying the operation, unique per client operation
+        key: String,
+        /// Request to run
+        request: Box<Request>,
+    },
+    
+    /// Run a request tagged with labels, answered as the request itself
+    ///
+    /// Labels are opaque to the handler; the agent attaches them to the
+    /// request's tracing span and audit records so they can be filtered on.
+    WithLabels {
+        /// Labels such as the deploy or job the request belongs to
+        labels: HashMap<String, String>,
+        /// Request to run
+        request: Box<Request>,
     },
 }
 
 impl Request {
+    /// File content of the request that can travel as a frame attachment
+    fn binary_field(&mut self) -> Option<&mut Bytes> {
+        match self {
+            Self::FilePut { content, .. } | Self::FileEnsure { content, .. } => Some(content),
+            Self::WithQos { request, .. }
+            | Self::WithIdempotencyKey { request, .. }
+            | Self::WithLabels { request, .. } => request.binary_field(),
+            _ => None,
+        }
+    }
+    
     /// Get the request ID
     pub fn id(&self) -> Uuid {
         match self {
             Self::ProcessExec { id, .. } => *id,
             Self::FileGet { id, .. } => *id,
             Self::FilePut { id, .. } => *id,
+            Self::FilePatchText { id, .. } => *id,
             Self::DirList { id, .. } => *id,
             Self::WasmExec { id, .. } => *id,
+            Self::WasmPipeline { id, .. } => *id,
+            Self::WasmUpload { id, .. } => *id,
+            Self::WasmInspect { id, .. } => *id,
             Self::JsonCall { id, .. } => *id,
             Self::Ping { id, .. } => *id,
+            Self::PingBatch { id, .. } => *id,
             Self::PtyExec { id, .. } => *id,
+            Self::ProcessSignal { id, .. } => *id,
+            Self::SessionOpen { id, .. } => *id,
+            Self::SessionClose { id } => *id,
+            Self::Batch { id, .. } => *id,
+            Self::FileTail { id, .. } => *id,
+            Self::Chdir { id, .. } => *id,
+            Self::Getcwd { id } => *id,
+            Self::FileHash { id, .. } => *id,
+            Self::FileXattrGet { id, .. } => *id,
+            Self::FileXattrSet { id, .. } => *id,
+            Self::FileChown { id, .. } => *id,
+            Self::FileSignature { id, .. } => *id,
+            Self::FileDeltaPut { id, .. } => *id,
+            Self::FileSetAttributes { id, .. } => *id,
+            Self::FileRemove { id, .. } => *id,
+            Self::FileEnsure { id, .. } => *id,
+            Self::DiskSpace { id, .. } => *id,
+            Self::MkTemp { id, .. } => *id,
+            Self::ListOperations { id } => *id,
+            Self::CancelOperation { id, .. } => *id,
+            Self::Version { id } => *id,
+            Self::TcpConnect { id, .. } => *id,
+            Self::WithQos { request, .. } => request.id(),
+            Self::WithIdempotencyKey { request, .. } => request.id(),
+            Self::WithLabels { request, .. } => request.id(),
+        }
+    }
+    
+    /// Whether the client keeps this request's stream open after the request, to send data or end it
+    pub fn has_stream_input(&self) -> bool {
+        match self {
+            Self::WithQos { request, .. }
+            | Self::WithIdempotencyKey { request, .. }
+            | Self::WithLabels { request, .. } => request.has_stream_input(),
+            _ => matches!(
+                self,
+                Self::ProcessExec { stdin_stream: true, .. }
+                    | Self::PtyExec { interactive: true, .. }
+                    | Self::PingBatch { .. }
+                    | Self::FileTail { follow: true, .. }
+                    | Self::DirList { batch_size: Some(_), .. }
+                    | Self::TcpConnect { .. }
+                    | Self::FilePut { stream_content: true, .. }
+            ),
+        }
+    }
+    
+    /// Whether the agent's partial responses for the request are flow controlled
+    ///
+    /// The agent sends at most [`STREAM_OUTPUT_WINDOW`] bytes of data ahead
+    /// of the client's window updates, or as many as the client asks for with
+    /// a resize, so the request is held to the pace the client reads at
+    /// rather than to a cap on its total output.
+    pub fn has_output_window(&self) -> bool {
+        match self {
+            Self::WithQos { request, .. }
+            | Self::WithIdempotencyKey { request, .. }
+            | Self::WithLabels { request, .. } => request.has_output_window(),
+            _ => matches!(self, Self::TcpConnect { .. } | Self::FileGet { chunk_size: Some(_), .. }),
+        }
+    }
+    
+    /// QoS class the request is scheduled under
+    ///
+    /// Without an explicit class, quick control requests (pings, signals,
+    /// session and working directory requests) are interactive and everything
+    /// else is batch work.
+    pub fn qos(&self) -> QosClass {
+        match self {
+            Self::WithQos { qos, .. } => *qos,
+            Self::WithIdempotencyKey { request, .. } | Self::WithLabels { request, .. } => request.qos(),
+            Self::Ping { .. }
+            | Self::PingBatch { .. }
+            | Self::ProcessSignal { .. }
+            | Self::SessionOpen { .. }
+            | Self::SessionClose { .. }
+            | Self::Chdir { .. }
+            | Self::Getcwd { .. }
+            | Self::ListOperations { .. }
+            | Self::CancelOperation { .. }
+            | Self::Version { .. } => QosClass::Interactive,
+            _ => QosClass::Batch,
+        }
+    }
+    
+    /// Whether the request takes one of the agent's concurrency slots
+    ///
+    /// Pings, signals and session and operation control requests are answered
+    /// even while the agent is at its limit, so a busy agent can still be
+    /// probed and the work keeping it busy signalled or cancelled.
+    pub fn counts_toward_limit(&self) -> bool {
+        match self {
+            Self::WithQos { request, .. }
+            | Self::WithIdempotencyKey { request, .. }
+            | Self::WithLabels { request, .. } => request.counts_toward_limit(),
+            Self::Ping { .. }
+            | Self::ProcessSignal { .. }
+            | Self::SessionOpen { .. }
+            | Self::SessionClose { .. }
+            | Self::Chdir { .. }
+            | Self::Getcwd { .. }
+            | Self::MkTemp { .. }
+            | Self::ListOperations { .. }
+            | Self::CancelOperation { .. } => false,
+            _ => true,
+        }
+    }
+    
+    /// Schedule the request under `qos`, replacing any class it already had
+    pub fn with_qos(self, qos: QosClass) -> Self {
+        match self {
+            Self::WithIdempotencyKey { key, request } => Self::WithIdempotencyKey {
+                key,
+                request: Box::new(request.with_qos(qos)),
+            },
+            Self::WithLabels { labels, request } => Self::WithLabels {
+                labels,
+                request: Box::new(request.with_qos(qos)),
+            },
+            request => Self::WithQos {
+                qos,
+                request: Box::new(request.into_inner()),
+            },
+        }
+    }
+    
+    /// Run the request at most once for `key`, replacing any key it already had
+    pub fn with_idempotency_key(self, key: impl Into<String>) -> Self {
+        let request = match self {
+            Self::WithIdempotencyKey { request, .. } => *request,
+            Self::WithLabels { labels, request } => {
+                return Self::WithLabels {
+                    labels,
+                    request: Box::new(request.with_idempotency_key(key)),
+                };
+            }
+            request => request,
+        };
+        Self::WithIdempotencyKey {
+            key: key.into(),
+            request: Box::new(request),
+        }
+    }
+    
+    /// Idempotency key the request was sent with, if any
+    pub fn idempotency_key(&self) -> Option<&str> {
+        match self {
+            Self::WithIdempotencyKey { key, .. } => Some(key),
+            Self::WithQos { request, .. } | Self::WithLabels { request, .. } => request.idempotency_key(),
+            _ => None,
+        }
+    }
+    
+    /// Tag the request with `labels`, replacing any it already had
+    pub fn with_labels(self, labels: HashMap<String, String>) -> Self {
+        let request = match self {
+            Self::WithLabels { request, .. } => *request,
+            request => request,
+        };
+        Self::WithLabels {
+            labels,
+            request: Box::new(request),
+        }
+    }
+    
+    /// Labels the request was tagged with, if any
+    pub fn labels(&self) -> Option<&HashMap<String, String>> {
+        match self {
+            Self::WithLabels { labels, .. } => Some(labels),
+            Self::WithQos { request, .. } | Self::WithIdempotencyKey { request, .. } => request.labels(),
+            _ => None,
+        }
+    }
+    
+    /// The request without its explicit QoS class, idempotency key and labels
+    pub fn into_inner(self) -> Self {
+        match self {
+            Self::WithQos { request, .. }
+            | Self::WithIdempotencyKey { request, .. }
+            | Self::WithLabels { request, .. } => request.into_inner(),
+            request => request,
+        }
+    }
+    
+    /// The request without its explicit QoS class, idempotency key and labels, by reference
+    pub fn inner(&self) -> &Self {
+        match self {
+            Self::WithQos { request, .. }
+            | Self::WithIdempotencyKey { request, .. }
+            | Self::WithLabels { request, .. } => request.inner(),
+            request => request,
+        }
+    }
+    
+    /// Whether running the request twice has the same effect as running it once
+    ///
+    /// Only these are retried on another connection when the one they were sent
+    /// on dies before answering.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Self::WithQos { request, .. }
+            | Self::WithIdempotencyKey { request, .. }
+            | Self::WithLabels { request, .. } => request.is_idempotent(),
+            Self::Ping { .. }
+            | Self::FileGet { .. }
+            | Self::DirList { .. }
+            | Self::Getcwd { .. }
+            | Self::FileHash { .. }
+            | Self::FileXattrGet { .. }
+            | Self::FileEnsure { .. }
+            | Self::FileSignature { .. }
+            | Self::FileSetAttributes { .. }
+            | Self::FileRemove { .. }
+            | Self::DiskSpace { .. }
+            | Self::ListOperations { .. }
+            | Self::Version { .. }
+            | Self::WasmInspect { .. } => true,
+            _ => false,
         }
     }
     
@@ -167,6 +1016,36 @@ impl Request {
             cwd,
             stdin,
             timeout,
+            stdin_stream: false,
+            limits: None,
+            stream_output: false,
+            line_buffered: false,
+            max_line_length: None,
+            merge_stderr: false,
+            max_output_bytes: None,
+            output_truncation: OutputTruncation::Head,
+            kill_on_output_limit: false,
+            expand_env: false,
+            output_fds: Vec::new(),
+            detach: false,
+            stdout_file: None,
+            stderr_file: None,
+            env_file: None,
+        }
+    }
+    
+    /// Create a pseudoterminal execution request that returns the terminal's output when done
+    pub fn pty_exec(command: Vec<String>, env: HashMap<String, String>, cwd: Option<PathBuf>, timeout: Option<u64>) -> Self {
+        Self::PtyExec {
+            id: Uuid::new_v4(),
+            command,
+            env,
+            cwd,
+            privilege: None,
+            timeout,
+            interactive: false,
+            window: None,
+            term: None,
         }
     }
     
@@ -176,6 +1055,23 @@ impl Request {
             id: Uuid::new_v4(),
             path,
             range,
+            decompress: None,
+            chunk_size: None,
+            include_xattrs: false,
+            transforms: Vec::new(),
+        }
+    }
+    
+    /// Create a file get request whose content arrives in chunks of at most `chunk_size` bytes
+    pub fn file_get_chunked(path: PathBuf, chunk_size: u32) -> Self {
+        Self::FileGet {
+            id: Uuid::new_v4(),
+            path,
+            range: None,
+            decompress: None,
+            chunk_size: Some(chunk_size),
+            include_xattrs: false,
+            transforms: Vec::new(),
         }
     }
     
@@ -187,102 +1083,672 @@ impl Request {
             content,
             mode,
             create_dirs,
+            umask: None,
+            modified: None,
+            atomic: false,
+            transforms: Vec::new(),
+            on_exists: OnExists::Overwrite,
+            stream_content: false,
+        }
+    }
+    
+    /// Create a request applying a unified diff to a file
+    pub fn file_patch_text(path: PathBuf, patch: impl Into<String>, create_backup: bool) -> Self {
+        Self::FilePatchText {
+            id: Uuid::new_v4(),
+            path,
+            patch: patch.into(),
+            create_backup,
+        }
+    }
+    
+    /// Create a process signal request
+    pub fn process_signal(target: Uuid, signal: impl Into<String>) -> Self {
+        Self::ProcessSignal {
+            id: Uuid::new_v4(),
+            target,
+            signal: signal.into(),
+        }
+    }
+    
+    /// Create a session open request
+    pub fn session_open(resume_token: Option<Uuid>) -> Self {
+        Self::SessionOpen {
+            id: Uuid::new_v4(),
+            resume_token,
+            compression: None,
+            dictionary_id: None,
+            attachment_threshold: Some(DEFAULT_ATTACHMENT_THRESHOLD as u32),
+        }
+    }
+    
+    /// Create a request closing the connection cleanly
+    pub fn session_close() -> Self {
+        Self::SessionClose { id: Uuid::new_v4() }
+    }
+    
+    /// Create a batch request
+    pub fn batch(requests: Vec<Request>, stop_on_error: bool) -> Self {
+        Self::Batch {
+            id: Uuid::new_v4(),
+            requests,
+            stop_on_error,
+        }
+    }
+    
+    /// Create a file tail request
+    pub fn file_tail(path: PathBuf, from_end_lines: u64, follow: bool) -> Self {
+        Self::FileTail {
+            id: Uuid::new_v4(),
+            path,
+            from_end_lines,
+            follow,
+        }
+    }
+    
+    /// Create a directory listing request
+    pub fn dir_list(path: PathBuf, include_hidden: bool, recursive: bool) -> Self {
+        Self::DirList {
+            id: Uuid::new_v4(),
+            path,
+            include_hidden,
+            recursive,
+            batch_size: None,
+            max_depth: None,
+        }
+    }
+    
+    /// Create a directory listing request that streams entries in batches
+    pub fn dir_list_streaming(path: PathBuf, include_hidden: bool, recursive: bool, batch_size: u32) -> Self {
+        Self::DirList {
+            id: Uuid::new_v4(),
+            path,
+            include_hidden,
+            recursive,
+            batch_size: Some(batch_size.max(1)),
+            max_depth: None,
         }
     }
     
-    /// Create a ping request
-    pub fn ping() -> Self {
-        Self::Ping {
-            id: Uuid::new_v4(),
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-        }
-    }
-}
-
-/// Response message types
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Response {
-    /// Process execution result
-    ProcessResult {
+    /// Create a working directory change request
+    pub fn chdir(path: PathBuf) -> Self {
+        Self::Chdir {
+            id: Uuid::new_v4(),
+            path,
+        }
+    }
+    
+    /// Create a working directory query
+    pub fn getcwd() -> Self {
+        Self::Getcwd { id: Uuid::new_v4() }
+    }
+    
+    /// Create a file hash request
+    pub fn file_hash(path: PathBuf, algorithm: HashAlgorithm) -> Self {
+        Self::FileHash {
+            id: Uuid::new_v4(),
+            path,
+            algorithm,
+        }
+    }
+    
+    /// Create a request reading a file's extended attributes
+    pub fn file_xattr_get(path: PathBuf) -> Self {
+        Self::FileXattrGet { id: Uuid::new_v4(), path }
+    }
+    
+    /// Create a request setting extended attributes on a file
+    pub fn file_xattr_set(path: PathBuf, xattrs: HashMap<String, Bytes>) -> Self {
+        Self::FileXattrSet { id: Uuid::new_v4(), path, xattrs }
+    }
+    
+    /// Create a request changing who owns a file
+    pub fn file_chown(path: PathBuf, uid: Option<FileOwner>, gid: Option<FileOwner>, recursive: bool) -> Self {
+        Self::FileChown { id: Uuid::new_v4(), path, uid, gid, recursive }
+    }
+    
+    /// Create a request making a file hold exactly `content`
+    pub fn file_ensure(path: PathBuf, content: Bytes, mode: Option<u32>, owner: Option<FileOwner>) -> Self {
+        Self::FileEnsure { id: Uuid::new_v4(), path, content, mode, owner }
+    }
+    
+    /// Create a request for the block signature of a file
+    pub fn file_signature(path: PathBuf, block_size: u32) -> Self {
+        Self::FileSignature { id: Uuid::new_v4(), path, block_size }
+    }
+    
+    /// Create a request replacing the file `signature` was taken of with the one `ops` rebuild
+    pub fn file_delta_put(path: PathBuf, signature: &Signature, ops: Vec<DeltaOp>) -> Self {
+        Self::FileDeltaPut {
+            id: Uuid::new_v4(),
+            path,
+            block_size: signature.block_size,
+            base_size: signature.size,
+            ops,
+            mode: None,
+            modified: None,
+        }
+    }
+    
+    /// Create a request setting a file's permission bits and modification time
+    pub fn file_set_attributes(path: PathBuf, mode: Option<u32>, modified: Option<u64>) -> Self {
+        Self::FileSetAttributes { id: Uuid::new_v4(), path, mode, modified }
+    }
+    
+    /// Create a request removing a file or directory
+    pub fn file_remove(path: PathBuf, recursive: bool) -> Self {
+        Self::FileRemove { id: Uuid::new_v4(), path, recursive }
+    }
+    
+    /// Create a request for the size of the filesystem holding `path`
+    pub fn disk_space(path: PathBuf) -> Self {
+        Self::DiskSpace { id: Uuid::new_v4(), path }
+    }
+    
+    /// Create a request for an empty temporary file or directory in the agent's temporary directory
+    pub fn mk_temp(kind: TempKind) -> Self {
+        Self::MkTemp {
+            id: Uuid::new_v4(),
+            dir: None,
+            prefix: String::new(),
+            suffix: String::new(),
+            kind,
+            cleanup: false,
+        }
+    }
+    
+    /// Create a request listing the requests running on the connection
+    pub fn list_operations() -> Self {
+        Self::ListOperations { id: Uuid::new_v4() }
+    }
+    
+    /// Create a request cancelling the running request `target`
+    pub fn cancel_operation(target: Uuid) -> Self {
+        Self::CancelOperation { id: Uuid::new_v4(), target }
+    }
+    
+    /// Create a request for the agent's build information
+    pub fn version() -> Self {
+        Self::Version { id: Uuid::new_v4() }
+    }
+    
+    /// Create a request tunnelling a stream to `host:port` through the agent
+    pub fn tcp_connect(host: impl Into<String>, port: u16) -> Self {
+        Self::TcpConnect { id: Uuid::new_v4(), host: host.into(), port }
+    }
+    
+    /// Resolve relative paths, and a process's missing working directory, against `cwd`
+    ///
+    /// Requests in a batch are resolved too.
+    pub fn resolve_paths(&mut self, cwd: &Path) {
+        match self {
+            Self::ProcessExec { cwd: process_cwd, .. } | Self::PtyExec { cwd: process_cwd, .. } => {
+                *process_cwd = Some(match process_cwd.take() {
+                    Some(path) => cwd.join(path),
+                    None => cwd.to_path_buf(),
+                });
+            }
+            Self::FileGet { path, .. }
+            | Self::FilePut { path, .. }
+            | Self::FilePatchText { path, .. }
+            | Self::DirList { path, .. }
+            | Self::FileTail { path, .. }
+            | Self::FileHash { path, .. }
+            | Self::FileXattrGet { path, .. }
+            | Self::FileXattrSet { path, .. }
+            | Self::FileChown { path, .. }
+            | Self::FileEnsure { path, .. }
+            | Self::FileSignature { path, .. }
+            | Self::FileDeltaPut { path, .. }
+            | Self::FileSetAttributes { path, .. }
+            | Self::FileRemove { path, .. }
+            | Self::DiskSpace { path, .. }
+            | Self::Chdir { path, .. } => {
+                *path = cwd.join(&*path);
+            }
+            Self::MkTemp { dir: Some(dir), .. } => {
+                *dir = cwd.join(&*dir);
+            }
+            Self::Batch { requests, .. } => {
+                for request in requests {
+                    request.resolve_paths(cwd);
+                }
+            }
+            Self::WithQos { request, .. }
+            | Self::WithIdempotencyKey { request, .. }
+            | Self::WithLabels { request, .. } => request.resolve_paths(cwd),
+            _ => {}
+        }
+    }
+    
+    /// Expand `${VAR}` tokens in a process request that asked for it
+    ///
+    /// The command and working directory are expanded from the request's own
+    /// `env`; the flag is cleared afterwards so expanding again is a no-op.
+    pub fn expand_env(&mut self) -> Result<(), ErrorDetails> {
+        match self {
+            Self::ProcessExec { command, env, cwd, expand_env, .. } if *expand_env => {
+                for arg in command.iter_mut() {
+                    *arg = expand_vars(arg, env)?;
+                }
+                if let Some(path) = cwd.as_mut() {
+                    if let Some(raw) = path.to_str() {
+                        *path = PathBuf::from(expand_vars(raw, env)?);
+                    }
+                }
+                *expand_env = false;
+                Ok(())
+            }
+            Self::Batch { requests, .. } => requests.iter_mut().try_for_each(Self::expand_env),
+            Self::WithQos { request, .. }
+            | Self::WithIdempotencyKey { request, .. }
+            | Self::WithLabels { request, .. } => request.expand_env(),
+            _ => Ok(()),
+        }
+    }
+    
+    /// Create a ping request
+    pub fn ping() -> Self {
+        Self::Ping {
+            id: Uuid::new_v4(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+    
+    /// Create a request echoing `count` probes of `payload_size` bytes, sent every `interval_ms`
+    pub fn ping_batch(count: u32, interval_ms: u64, payload_size: u32) -> Self {
+        Self::PingBatch { id: Uuid::new_v4(), count, interval_ms, payload_size }
+    }
+}
+
+/// Response message types
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    /// Process execution result
+    ProcessResult {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Exit code
+        exit_code: i32,
+        /// Standard output
+        stdout: Bytes,
+        /// Standard error
+        stderr: Bytes,
+        /// Execution duration in milliseconds
+        duration_ms: u64,
+        /// Output went over the capture limit, so stdout or stderr is incomplete
+        #[serde(default)]
+        truncated: bool,
+        /// The process was killed for running past its timeout; the output is what it wrote until then
+        #[serde(default)]
+        timed_out: bool,
+        /// How the process ended, by exit code or by the signal that
+        /// terminated it; absent from agents that predate it
+        #[serde(default)]
+        termination: Option<Termination>,
+        /// Output captured from the requested `output_fds`, by descriptor
+        #[serde(default)]
+        fd_output: HashMap<u32, Bytes>,
+        /// PID of a process started with `detach`, which is still running
+        #[serde(default)]
+        pid: Option<u32>,
+        /// Bytes the process wrote to its `stdout_file`
+        #[serde(default)]
+        stdout_written: Option<u64>,
+        /// Bytes the process wrote to its `stderr_file`
+        #[serde(default)]
+        stderr_written: Option<u64>,
+    },
+    
+    /// File get result
+    FileContent {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// File content
+        content: Bytes,
+        /// File metadata
+        metadata: FileMetadata,
+    },
+    
+    /// File put result
+    FilePutResult {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Bytes written
+        bytes_written: u64,
+        /// The path existed and `OnExists::Skip` left it alone
+        #[serde(default)]
+        skipped: bool,
+        /// Where the existing file was moved by `OnExists::Backup`
+        #[serde(default, with = "crate::path::option")]
+        backup_path: Option<PathBuf>,
+    },
+    
+    /// File patch result
+    FilePatchResult {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Size of the patched file
+        bytes_written: u64,
+        /// Where the original was kept, if a backup was requested
+        #[serde(with = "crate::path::option")]
+        backup_path: Option<PathBuf>,
+    },
+    
+    /// Directory listing result
+    DirListing {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Directory entries
+        entries: Vec<DirEntry>,
+    },
+    
+    /// WASM execution result
+    WasmResult {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// JSON output data
+        output: Bytes,
+        /// Execution duration in milliseconds
+        duration_ms: u64,
+    },
+    
+    /// WASM upload progress, with the module hash once the upload is complete
+    WasmUploaded {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Bytes received so far
+        received: u64,
+        /// SHA256 hash of the stored module, set after the final chunk
+        hash: Option<String>,
+    },
+    
+    /// Description of an inspected WASM module
+    WasmMetadata {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// JSON-encoded module metadata: hash, exports and their signatures,
+        /// imports, WASI-ness and custom section names
+        metadata: Bytes,
+    },
+    
+    /// JSON RPC result
+    JsonResult {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// JSON result
+        result: Bytes,
+    },
+    
+    /// Pong response
+    Pong {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Original timestamp
+        timestamp: u64,
+        /// Response timestamp, in microseconds for the probes of a `PingBatch`
+        response_timestamp: u64,
+        /// Sequence number of the `PingBatch` probe this echoes
+        #[serde(default)]
+        sequence: Option<u32>,
+        /// Payload of the probe
+        #[serde(default)]
+        payload: Bytes,
+    },
+    
+    /// End of a `PingBatch`
+    PingBatchResult {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Probes echoed
+        echoed: u32,
+    },
+    
+    /// PTY process execution result
+    PtyResult {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Exit code
+        exit_code: i32,
+        /// Combined stdout/stderr output
+        output: Bytes,
+        /// Execution duration in milliseconds
+        duration_ms: u64,
+    },
+    
+    /// Signal delivered to a running process
+    SignalSent {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Process ID that received the signal
+        pid: u32,
+    },
+    
+    /// Partial process output, sent while a streaming ProcessExec runs
+    ProcessOutput {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Pipe the data was read from
+        stream: OutputStream,
+        /// Output data
+        data: Bytes,
+    },
+    
+    /// Agent session opened
+    SessionOpened {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Token to present when reconnecting
+        token: Uuid,
+        /// Whether an earlier session was resumed
+        resumed: bool,
+        /// Final responses retained from the resumed session
+        responses: Vec<Response>,
+        /// Most requests the agent handles at once, if it is limited
+        #[serde(default)]
+        max_concurrent_requests: Option<u32>,
+        /// Requests the agent was handling when the session opened
+        #[serde(default)]
+        in_flight: u32,
+        /// Stream compression in effect for all frames following this one
+        #[serde(default)]
+        compression: Option<StreamCompression>,
+        /// ID of the dictionary stream compression is primed with, if both ends have it
+        #[serde(default)]
+        dictionary_id: Option<u32>,
+        /// Smallest file content both ends send as a frame attachment for the
+        /// rest of the connection: the larger of their thresholds, or None if
+        /// either takes no attachments
+        #[serde(default)]
+        attachment_threshold: Option<u32>,
+    },
+    
+    /// Agent finished with the connection, for `SessionClose`
+    ///
+    /// Nothing follows it; the agent stops once it is written.
+    SessionClosed {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Temporary files and directories removed
+        temp_paths_removed: u32,
+    },
+    
+    /// Part of a file, sent before the final response of a tail or chunked get
+    FileChunk {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// For a tail, one or more lines, each ending in a newline except possibly
+        /// the last at EOF; for a chunked get, the next bytes of the content
+        data: Bytes,
+    },
+    
+    /// File tail finished
+    FileTailEnded {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// File offset reached
+        offset: u64,
+    },
+    
+    /// A batch of a streamed directory listing; the final `DirListing` holds the last batch
+    DirEntries {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Directory entries
+        entries: Vec<DirEntry>,
+    },
+    
+    /// The session's working directory, after `Chdir` or for `Getcwd`
+    Cwd {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Absolute working directory
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+    },
+    
+    /// Digest of a file, for `FileHash`
+    FileHash {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Lowercase hex digest
+        hash: String,
+        /// Bytes hashed
+        size: u64,
+    },
+    
+    /// Extended attributes of a file, for `FileXattrGet` and `FileXattrSet`
+    FileXattrs {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Values by attribute name
+        xattrs: HashMap<String, Bytes>,
+    },
+    
+    /// Owners of a file after `FileChown`
+    FileOwnership {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Owning user ID
+        uid: u32,
+        /// Owning group ID
+        gid: u32,
+        /// Files and directories whose ownership was set
+        changed: u64,
+    },
+    
+    /// What `FileEnsure` had to change
+    FileEnsure {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Whether the file differed from what was asked for
+        changed: bool,
+        /// Each part of the file that was changed, in the order it was changed
+        what_changed: Vec<FileChange>,
+    },
+    
+    /// Block signature of a file, for `FileSignature`
+    FileSignature {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Checksums of the file's blocks
+        signature: Signature,
+    },
+    
+    /// Attributes of a file after `FileSetAttributes`
+    FileAttributes {
         /// Request ID this responds to
         request_id: Uuid,
-        /// Exit code
-        exit_code: i32,
-        /// Standard output
-        stdout: Bytes,
-        /// Standard error
-        stderr: Bytes,
-        /// Execution duration in milliseconds
-        duration_ms: u64,
+        /// Permission bits
+        mode: u32,
+        /// Last modified time in seconds since the Unix epoch
+        modified: u64,
     },
     
-    /// File get result
-    FileContent {
+    /// Outcome of `FileRemove`
+    FileRemoved {
         /// Request ID this responds to
         request_id: Uuid,
-        /// File content
-        content: Bytes,
-        /// File metadata
-        metadata: FileMetadata,
+        /// Whether there was anything at the path to remove
+        existed: bool,
     },
     
-    /// File put result
-    FilePutResult {
+    /// Size of a filesystem, for `DiskSpace`
+    DiskSpace {
         /// Request ID this responds to
         request_id: Uuid,
-        /// Bytes written
-        bytes_written: u64,
+        /// Space on the filesystem
+        space: FilesystemSpace,
     },
     
-    /// Directory listing result
-    DirListing {
+    /// Temporary file or directory created for `MkTemp`
+    TempCreated {
         /// Request ID this responds to
         request_id: Uuid,
-        /// Directory entries
-        entries: Vec<DirEntry>,
+        /// Absolute path of the new entry
+        #[serde(with = "crate::path")]
+        path: PathBuf,
     },
     
-    /// WASM execution result
-    WasmResult {
+    /// Requests running on the connection, for `ListOperations`
+    Operations {
         /// Request ID this responds to
         request_id: Uuid,
-        /// JSON output data
-        output: Bytes,
-        /// Execution duration in milliseconds
-        duration_ms: u64,
+        /// Running requests, oldest first
+        operations: Vec<OperationInfo>,
     },
     
-    /// JSON RPC result
-    JsonResult {
+    /// A request was cancelled by `CancelOperation`
+    OperationCancelled {
         /// Request ID this responds to
         request_id: Uuid,
-        /// JSON result
-        result: Bytes,
+        /// ID of the cancelled request
+        target: Uuid,
     },
     
-    /// Pong response
-    Pong {
+    /// Build of the agent, for `Version`
+    Version {
         /// Request ID this responds to
         request_id: Uuid,
-        /// Original timestamp
-        timestamp: u64,
-        /// Response timestamp
-        response_timestamp: u64,
+        /// What the agent was built from and for
+        build: BuildInfo,
     },
     
-    /// PTY process execution result
-    PtyResult {
+    /// The agent connected a `TcpConnect` to its target
+    TunnelOpened {
         /// Request ID this responds to
         request_id: Uuid,
-        /// Exit code
-        exit_code: i32,
-        /// Combined stdout/stderr output
-        output: Bytes,
-        /// Execution duration in milliseconds
-        duration_ms: u64,
+        /// Address the agent connected to, after resolving the host
+        peer: String,
+    },
+    
+    /// Bytes the target of a `TcpConnect` sent
+    TunnelData {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Data as read from the connection
+        data: Bytes,
+    },
+    
+    /// The target closed a `TcpConnect`'s connection
+    TunnelClosed {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Bytes written to the target
+        bytes_sent: u64,
+        /// Bytes read from the target
+        bytes_received: u64,
+    },
+    
+    /// Batch result
+    BatchResult {
+        /// Request ID this responds to
+        request_id: Uuid,
+        /// Responses of the requests that ran, in order
+        responses: Vec<Response>,
     },
     
     /// Error response
@@ -301,15 +1767,68 @@ impl Response {
             Self::ProcessResult { request_id, .. } => *request_id,
             Self::FileContent { request_id, .. } => *request_id,
             Self::FilePutResult { request_id, .. } => *request_id,
+            Self::FilePatchResult { request_id, .. } => *request_id,
             Self::DirListing { request_id, .. } => *request_id,
             Self::WasmResult { request_id, .. } => *request_id,
+            Self::WasmUploaded { request_id, .. } => *request_id,
+            Self::WasmMetadata { request_id, .. } => *request_id,
             Self::JsonResult { request_id, .. } => *request_id,
             Self::Pong { request_id, .. } => *request_id,
+            Self::PingBatchResult { request_id, .. } => *request_id,
             Self::PtyResult { request_id, .. } => *request_id,
+            Self::SignalSent { request_id, .. } => *request_id,
+            Self::ProcessOutput { request_id, .. } => *request_id,
+            Self::SessionOpened { request_id, .. } => *request_id,
+            Self::SessionClosed { request_id, .. } => *request_id,
+            Self::BatchResult { request_id, .. } => *request_id,
+            Self::FileChunk { request_id, .. } => *request_id,
+            Self::FileTailEnded { request_id, .. } => *request_id,
+            Self::DirEntries { request_id, .. } => *request_id,
+            Self::Cwd { request_id, .. } => *request_id,
+            Self::FileHash { request_id, .. } => *request_id,
+            Self::FileXattrs { request_id, .. } => *request_id,
+            Self::FileOwnership { request_id, .. } => *request_id,
+            Self::FileEnsure { request_id, .. } => *request_id,
+            Self::FileSignature { request_id, .. } => *request_id,
+            Self::FileAttributes { request_id, .. } => *request_id,
+            Self::FileRemoved { request_id, .. } => *request_id,
+            Self::DiskSpace { request_id, .. } => *request_id,
+            Self::TempCreated { request_id, .. } => *request_id,
+            Self::Operations { request_id, .. } => *request_id,
+            Self::OperationCancelled { request_id, .. } => *request_id,
+            Self::Version { request_id, .. } => *request_id,
+            Self::TunnelOpened { request_id, .. } => *request_id,
+            Self::TunnelData { request_id, .. } => *request_id,
+            Self::TunnelClosed { request_id, .. } => *request_id,
             Self::Error { request_id, .. } => *request_id,
         }
     }
     
+    /// Whether more responses follow this one for the same request
+    ///
+    /// A `Pong` answering a probe of a `PingBatch` is followed by more pongs
+    /// and the `PingBatchResult`.
+    pub fn is_partial(&self) -> bool {
+        matches!(
+            self,
+            Self::ProcessOutput { .. }
+                | Self::FileChunk { .. }
+                | Self::DirEntries { .. }
+                | Self::Pong { sequence: Some(_), .. }
+                | Self::TunnelOpened { .. }
+                | Self::TunnelData { .. }
+        )
+    }
+    
+    /// Whether the request failed: an error, or a process that exited non-zero
+    pub fn is_failure(&self) -> bool {
+        match self {
+            Self::Error { .. } => true,
+            Self::ProcessResult { exit_code, .. } | Self::PtyResult { exit_code, .. } => *exit_code != 0,
+            _ => false,
+        }
+    }
+    
     /// Create an error response
     pub fn error(request_id: Uuid, error: ErrorDetails) -> Self {
         Self::Error { request_id, error }
@@ -324,6 +1843,86 @@ impl Response {
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_secs(),
+            sequence: None,
+            payload: Bytes::new(),
+        }
+    }
+    
+    /// Create a pong echoing one probe of a `PingBatch`
+    pub fn probe_pong(request_id: Uuid, probe: PingProbe) -> Self {
+        Self::Pong {
+            request_id,
+            timestamp: probe.timestamp,
+            response_timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            sequence: Some(probe.sequence),
+            payload: probe.payload,
+        }
+    }
+}
+
+/// Notification pushed by the agent outside the request/response flow
+///
+/// Clients route events to subscribers by [`EventKind`] instead of matching
+/// them against pending requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    /// A request is still being handled
+    Keepalive {
+        /// Request being handled
+        request_id: Uuid,
+    },
+    
+    /// Progress of a request
+    Progress {
+        /// Request making progress
+        request_id: Uuid,
+        /// Units of work done so far
+        completed: u64,
+        /// Total units of work, if known
+        total: Option<u64>,
+        /// Description of the current step
+        message: Option<String>,
+    },
+    
+    /// Log record from the agent
+    Log {
+        /// Level name (`error`, `warn`, `info`, `debug`, `trace`)
+        level: String,
+        /// Log message
+        message: String,
+    },
+}
+
+/// Kinds of events a client can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EventKind {
+    /// `Event::Keepalive`
+    Keepalive,
+    /// `Event::Progress`
+    Progress,
+    /// `Event::Log`
+    Log,
+}
+
+impl Event {
+    /// Get the kind of this event
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::Keepalive { .. } => EventKind::Keepalive,
+            Self::Progress { .. } => EventKind::Progress,
+            Self::Log { .. } => EventKind::Log,
+        }
+    }
+    
+    /// Get the request this event relates to, if any
+    pub fn request_id(&self) -> Option<Uuid> {
+        match self {
+            Self::Keepalive { request_id } => Some(*request_id),
+            Self::Progress { request_id, .. } => Some(*request_id),
+            Self::Log { .. } => None,
         }
     }
 }
@@ -341,14 +1940,133 @@ pub struct FileMetadata {
     pub is_dir: bool,
     /// Whether this is a symlink
     pub is_symlink: bool,
+    /// Size after decompression, when the content was decompressed and fully read
+    #[serde(default)]
+    pub decompressed_size: Option<u64>,
+    /// Extended attributes by name, when requested and the filesystem supports them
+    #[serde(default)]
+    pub xattrs: Option<HashMap<String, Bytes>>,
+}
+
+/// Compression applied to a connection's whole frame stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamCompression {
+    /// A single Zstandard stream per direction
+    Zstd,
+}
+
+/// Compression format of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// gzip (`.gz`)
+    Gzip,
+    /// Zstandard (`.zst`)
+    Zstd,
+}
+
+/// Digest algorithm for `FileHash`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    /// SHA-256
+    #[default]
+    Sha256,
+    /// BLAKE3, with the default 32-byte output
+    Blake3,
+}
+
+/// User or group to give a file to with `FileChown`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileOwner {
+    /// Numeric user or group ID
+    Id(u32),
+    /// Name looked up in the agent host's user or group database
+    Name(String),
+}
+
+impl From<u32> for FileOwner {
+    fn from(id: u32) -> Self {
+        Self::Id(id)
+    }
+}
+
+impl From<&str> for FileOwner {
+    fn from(name: &str) -> Self {
+        Self::Name(name.to_string())
+    }
+}
+
+/// Part of a file changed by `FileEnsure`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileChange {
+    /// The content was replaced
+    Content,
+    /// The permission bits were set
+    Mode,
+    /// The owning user was set
+    Owner,
+}
+
+/// Size of a filesystem in bytes, reported by `DiskSpace`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilesystemSpace {
+    /// Size of the filesystem
+    pub total: u64,
+    /// Free space an unprivileged user can write to
+    pub available: u64,
+    /// Space in use
+    pub used: u64,
+}
+
+/// A request running on the agent, reported by `ListOperations`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationInfo {
+    /// ID of the request
+    pub id: Uuid,
+    /// Kind of request, such as `process_exec`
+    pub request_type: String,
+    /// Time since the request started running, in milliseconds
+    pub age_ms: u64,
+    /// Stream the request arrived on
+    pub stream_id: u32,
+}
+
+/// Build of an agent, reported by `Version`
+///
+/// Captured when the agent was compiled, so it identifies the binary
+/// rather than the host it runs on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    /// Crate version, such as `0.1.0`
+    pub version: String,
+    /// Commit the agent was built from, if it was built from a repository
+    pub git_hash: Option<String>,
+    /// Cargo features compiled in, such as `wasm` or `docker`, in name order
+    pub features: Vec<String>,
+    /// Target triple, such as `x86_64-unknown-linux-musl`
+    pub target: String,
+    /// Version of the compiler, as `rustc --version` prints it
+    pub rustc: Option<String>,
+    /// Cargo profile, `debug` or `release`
+    pub profile: String,
+}
+
+/// What `MkTemp` creates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TempKind {
+    /// An empty regular file
+    #[default]
+    File,
+    /// An empty directory
+    Dir,
 }
 
 /// Directory entry information
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DirEntry {
-    /// Entry name
+    /// Entry name, with anything that isn't UTF-8 replaced
     pub name: String,
-    /// Full path
+    /// Full path, exactly as the agent read it, to refer back to the entry
+    #[serde(with = "crate::path")]
     pub path: PathBuf,
     /// File metadata
     pub metadata: FileMetadata,
@@ -365,6 +2083,239 @@ pub struct ErrorDetails {
     pub context: HashMap<String, String>,
 }
 
+/// Output pipe of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputStream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+    /// An extra descriptor the process was given with `output_fds`
+    Fd(u32),
+}
+
+/// File on the agent a process's output is written into
+///
+/// A relative path is taken from the process's working directory, as a
+/// shell redirect would.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputFile {
+    /// File to write, created if it doesn't exist
+    #[serde(with = "crate::path")]
+    pub path: PathBuf,
+    /// Add to the end of the file instead of replacing its content
+    #[serde(default)]
+    pub append: bool,
+}
+
+/// Env file a process gets variables from, in the format of [`crate::envfile`]
+///
+/// A relative path is taken from the process's working directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvFile {
+    /// File to read
+    #[serde(with = "crate::path")]
+    pub path: PathBuf,
+    /// Skip malformed lines with a warning instead of failing the request
+    #[serde(default)]
+    pub skip_malformed: bool,
+}
+
+impl EnvFile {
+    /// Read `path`, failing the request on a malformed line
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), skip_malformed: false }
+    }
+    
+    /// Skip malformed lines instead of failing the request
+    pub fn skip_malformed(mut self) -> Self {
+        self.skip_malformed = true;
+        self
+    }
+}
+
+/// What a `FilePut` does when its path already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnExists {
+    /// Replace the file
+    #[default]
+    Overwrite,
+    /// Fail with `ErrorCode::AlreadyExists`
+    Fail,
+    /// Leave it as it is, answering with `skipped` set
+    Skip,
+    /// Rename it to the path with `.bak` appended, replacing any earlier
+    /// backup, then write the file
+    Backup,
+}
+
+/// Rewriting of file content by `FilePut` and `FileGet`
+///
+/// See [`crate::transform`] for exactly what each one does.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContentTransform {
+    /// Turn `\r\n` line endings into `\n`
+    CrlfToLf,
+    /// Turn `\n` line endings into `\r\n`
+    LfToCrlf,
+    /// Expand `${NAME}` references from `vars`
+    Substitute {
+        /// Values of the names that may be referenced
+        vars: HashMap<String, String>,
+    },
+}
+
+impl OutputFile {
+    /// Replace the content of `path`, as `>` does
+    pub fn truncate(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), append: false }
+    }
+    
+    /// Add to the end of `path`, as `>>` does
+    pub fn append(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), append: true }
+    }
+}
+
+/// One module of a `WasmPipeline`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PipelineStage {
+    /// WASM module bytecode, empty when running a stored module by hash
+    #[serde(default)]
+    pub module: Bytes,
+    /// Hash of a module previously stored with `WasmUpload`
+    #[serde(default)]
+    pub module_hash: Option<String>,
+}
+
+impl PipelineStage {
+    /// Run the module `bytes`
+    pub fn module(bytes: impl Into<Bytes>) -> Self {
+        Self { module: bytes.into(), module_hash: None }
+    }
+    
+    /// Run the module stored on the agent under `hash`
+    pub fn stored(hash: impl Into<String>) -> Self {
+        Self { module: Bytes::new(), module_hash: Some(hash.into()) }
+    }
+}
+
+/// Window size of a pseudoterminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PtySize {
+    /// Rows of characters
+    pub rows: u16,
+    /// Columns of characters
+    pub cols: u16,
+}
+
+impl Default for PtySize {
+    fn default() -> Self {
+        Self { rows: 24, cols: 80 }
+    }
+}
+
+/// Client data for an interactive `PtyExec`, one per stream frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PtyInput {
+    /// Bytes typed into the terminal
+    Data(Bytes),
+    /// The client's terminal window changed size
+    Resize(PtySize),
+}
+
+impl PtyInput {
+    /// Encode the input as a stream frame payload
+    pub fn to_bytes(&self) -> Result<Bytes, ProtocolError> {
+        rmp_serde::to_vec(self)
+            .map(Bytes::from)
+            .map_err(|e| ProtocolError::Serialization(e.to_string()))
+    }
+    
+    /// Decode the input from a stream frame payload
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        rmp_serde::from_slice(bytes)
+            .map_err(|e| ProtocolError::Serialization(e.to_string()))
+    }
+}
+
+/// Client data for a `PingBatch`, one per stream frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PingProbe {
+    /// Position of the probe in the batch, from 0
+    pub sequence: u32,
+    /// When the client sent it, on the client's own clock
+    pub timestamp: u64,
+    /// Padding echoed back with the pong
+    pub payload: Bytes,
+}
+
+impl PingProbe {
+    /// Encode the probe as a stream frame payload
+    pub fn to_bytes(&self) -> Result<Bytes, ProtocolError> {
+        rmp_serde::to_vec(self)
+            .map(Bytes::from)
+            .map_err(|e| ProtocolError::Serialization(e.to_string()))
+    }
+    
+    /// Decode the probe from a stream frame payload
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        rmp_serde::from_slice(bytes)
+            .map_err(|e| ProtocolError::Serialization(e.to_string()))
+    }
+}
+
+/// Part of a process's output kept once it exceeds the capture limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputTruncation {
+    /// Keep the first bytes
+    #[default]
+    Head,
+    /// Keep the last bytes
+    Tail,
+    /// Keep the first and last bytes, half the limit each
+    HeadAndTail,
+}
+
+/// How a process ended
+///
+/// A process killed by a signal has no exit code, so `ProcessResult.exit_code`
+/// reports -1 for it; this tells the two apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Termination {
+    /// The process exited with this code
+    Exited(i32),
+    /// The process was killed by this signal number (Unix only)
+    Signaled(i32),
+}
+
+/// Scheduling class of a request
+///
+/// When requests have to wait for a free slot, higher classes are started
+/// first; requests of the same class start in the order they were sent. The
+/// agent also writes the output of higher classes first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum QosClass {
+    /// Work nobody is waiting on
+    Background,
+    /// Regular work
+    #[default]
+    Batch,
+    /// Requests a user is waiting on
+    Interactive,
+}
+
+/// Resource limits for a spawned process (`setrlimit` on Unix)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessLimits {
+    /// Maximum CPU time in seconds (`RLIMIT_CPU`), after which the process gets `SIGXCPU`
+    pub cpu_seconds: Option<u64>,
+    /// Maximum address space in bytes (`RLIMIT_AS`)
+    pub address_space: Option<u64>,
+    /// Maximum number of open file descriptors (`RLIMIT_NOFILE`)
+    pub open_files: Option<u64>,
+}
+
 /// Privilege escalation configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PrivilegeEscalation {
@@ -421,6 +2372,22 @@ pub enum ErrorCode {
     ResourceExhausted,
     /// Privilege escalation failed
     PrivilegeEscalationFailed,
+    /// Referenced resource does not exist
+    NotFound,
+    /// Agent is already handling its maximum number of concurrent requests
+    Overloaded,
+    /// A patch did not apply to the file it targets
+    PatchFailed,
+    /// A request's responses added up to more than the agent sends for one request
+    ResponseTooLarge,
+    /// The file system ran out of space or quota during a write
+    DiskFull,
+    /// The request was cancelled with `CancelOperation`
+    Cancelled,
+    /// The path a request would create is already taken
+    AlreadyExists,
+    /// Nothing accepted a connection at the address a request connects to
+    ConnectionRefused,
 }
 
 impl ErrorDetails {
@@ -464,6 +2431,124 @@ mod tests {
         }
     }
     
+    #[test]
+    fn test_stream_input_flag() {
+        let mut req = Request::process_exec(vec!["cat".to_string()], HashMap::new(), None, None, None);
+        assert!(!req.has_stream_input());
+        
+        if let Request::ProcessExec { stdin_stream, .. } = &mut req {
+            *stdin_stream = true;
+        }
+        assert!(req.has_stream_input());
+        assert!(!Request::ping().has_stream_input());
+        
+        let mut pty = Request::pty_exec(vec!["bash".to_string()], HashMap::new(), None, None);
+        if let Request::PtyExec { interactive, window, .. } = &mut pty {
+            *interactive = true;
+            *window = Some(PtySize::default());
+        }
+        assert!(pty.has_stream_input());
+        
+        let mut put = Request::file_put(PathBuf::from("/tmp/big"), Bytes::new(), None, false);
+        assert!(!put.has_stream_input());
+        if let Request::FilePut { stream_content, .. } = &mut put {
+            *stream_content = true;
+        }
+        assert!(put.has_stream_input());
+    }
+    
+    #[test]
+    fn test_output_window_flag() {
+        assert!(Request::tcp_connect("db.internal", 5432).has_output_window());
+        assert!(Request::tcp_connect("db.internal", 5432).with_qos(QosClass::Background).has_output_window());
+        assert!(!Request::ping().has_output_window());
+    }
+    
+    #[test]
+    fn test_pty_input_roundtrip() {
+        for input in [PtyInput::Data(Bytes::from_static(b"echo hi\n")), PtyInput::Resize(PtySize { rows: 40, cols: 120 })] {
+            assert_eq!(PtyInput::from_bytes(&input.to_bytes().unwrap()).unwrap(), input);
+        }
+        assert!(PtyInput::from_bytes(b"not msgpack").is_err());
+    }
+    
+    #[test]
+    fn test_control_requests_do_not_count_toward_limit() {
+        assert!(!Request::ping().counts_toward_limit());
+        assert!(!Request::process_signal(Uuid::new_v4(), "TERM").counts_toward_limit());
+        assert!(!Request::cancel_operation(Uuid::new_v4()).counts_toward_limit());
+        assert!(!Request::ping().with_qos(QosClass::Batch).counts_toward_limit());
+        assert!(Request::ping_batch(10, 0, 0).counts_toward_limit());
+        assert!(Request::process_exec(vec!["true".to_string()], HashMap::new(), None, None, None).counts_toward_limit());
+    }
+    
+    #[test]
+    fn test_qos_class() {
+        assert_eq!(Request::ping().qos(), QosClass::Interactive);
+        assert_eq!(Request::getcwd().qos(), QosClass::Interactive);
+        
+        let request = Request::dir_list(PathBuf::from("/tmp"), false, false);
+        let id = request.id();
+        assert_eq!(request.qos(), QosClass::Batch);
+        
+        let request = request.with_qos(QosClass::Interactive).with_qos(QosClass::Background);
+        assert_eq!(request.qos(), QosClass::Background);
+        assert_eq!(request.id(), id);
+        assert!(matches!(request.into_inner(), Request::DirList { .. }));
+        
+        assert!(QosClass::Interactive > QosClass::Batch && QosClass::Batch > QosClass::Background);
+    }
+    
+    #[test]
+    fn test_idempotency_key() {
+        let request = Request::file_put(PathBuf::from("/tmp/motd"), Bytes::from("hi"), None, false);
+        let id = request.id();
+        assert_eq!(request.idempotency_key(), None);
+        
+        let request = request.with_idempotency_key("first").with_qos(QosClass::Background).with_idempotency_key("put-motd");
+        assert_eq!(request.idempotency_key(), Some("put-motd"));
+        assert_eq!(request.qos(), QosClass::Background);
+        assert_eq!(request.id(), id);
+        
+        let bytes = rmp_serde::to_vec(&request).unwrap();
+        let decoded: Request = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded.idempotency_key(), Some("put-motd"));
+        assert!(matches!(decoded.into_inner(), Request::FilePut { .. }));
+    }
+    
+    #[test]
+    fn test_request_labels() {
+        let request = Request::file_put(PathBuf::from("/tmp/motd"), Bytes::from("hi"), None, false);
+        let id = request.id();
+        assert_eq!(request.labels(), None);
+        
+        let labels = HashMap::from([("deploy".to_string(), "42".to_string())]);
+        let request = request
+            .with_labels(HashMap::from([("job".to_string(), "old".to_string())]))
+            .with_labels(labels.clone())
+            .with_idempotency_key("put-motd")
+            .with_qos(QosClass::Background);
+        assert_eq!(request.labels(), Some(&labels));
+        assert_eq!(request.idempotency_key(), Some("put-motd"));
+        assert_eq!(request.qos(), QosClass::Background);
+        assert_eq!(request.id(), id);
+        
+        let bytes = rmp_serde::to_vec(&request).unwrap();
+        let decoded: Request = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded.labels(), Some(&labels));
+        assert!(matches!(decoded.into_inner(), Request::FilePut { .. }));
+    }
+    
+    #[test]
+    fn test_idempotent_requests() {
+        assert!(Request::ping().is_idempotent());
+        assert!(Request::file_hash(PathBuf::from("/tmp/a"), HashAlgorithm::Sha256).is_idempotent());
+        assert!(Request::dir_list(PathBuf::from("/tmp"), false, false).with_qos(QosClass::Background).is_idempotent());
+        assert!(Request::file_ensure(PathBuf::from("/tmp/a"), Bytes::new(), Some(0o644), None).is_idempotent());
+        assert!(!Request::chdir(PathBuf::from("/tmp")).is_idempotent());
+        assert!(!Request::process_exec(vec!["true".to_string()], HashMap::new(), None, None, None).is_idempotent());
+    }
+    
     #[test]
     fn test_response_creation() {
         let request_id = Uuid::new_v4();
@@ -514,6 +2599,63 @@ mod tests {
         assert_eq!(msg.request_id(), deserialized.request_id());
     }
     
+    #[test]
+    fn test_large_content_travels_as_attachment() {
+        let content = Bytes::from(vec![7u8; 1024]);
+        let request = Request::file_put(PathBuf::from("/tmp/large"), content.clone(), None, false)
+            .with_qos(QosClass::Background);
+        
+        // Below the threshold the content stays in the encoding
+        let inline = Message::request(request.clone()).to_frame(1, 0, Some(2048)).unwrap();
+        assert!(!inline.has_attachment());
+        assert!(inline.payload.len() > content.len());
+        
+        let frame = Message::request(request).to_frame(1, 0, Some(1024)).unwrap();
+        assert_eq!(frame.attachment, content);
+        assert!(frame.payload.len() < content.len());
+        match Message::from_frame(frame).unwrap() {
+            Message::Request(Request::WithQos { request, .. }) => {
+                assert!(matches!(*request, Request::FilePut { content: ref c, .. } if *c == content));
+            }
+            other => panic!("Expected QoS request, got {:?}", other),
+        }
+        
+        // An attachment needs a field to go into
+        let frame = Message::request(Request::ping()).to_frame(1, 0, None).unwrap()
+            .with_attachment(content);
+        assert!(matches!(Message::from_frame(frame), Err(ProtocolError::InvalidFrame(_))));
+    }
+    
+    #[test]
+    fn test_event_serialization() {
+        let request_id = Uuid::new_v4();
+        let msg = Message::event(Event::Progress {
+            request_id,
+            completed: 3,
+            total: Some(10),
+            message: Some("copying".to_string()),
+        });
+        
+        let serialized = rmp_serde::to_vec(&msg).unwrap();
+        let deserialized: Message = rmp_serde::from_slice(&serialized).unwrap();
+        
+        assert_eq!(deserialized.request_id(), Some(request_id));
+        match deserialized {
+            Message::Event(event) => {
+                assert_eq!(event.kind(), EventKind::Progress);
+                assert!(matches!(
+                    event,
+                    Event::Progress { completed: 3, total: Some(10), message: Some(ref m), .. } if m == "copying"
+                ));
+            }
+            other => panic!("Expected event, got {:?}", other),
+        }
+        
+        let log = Message::event(Event::Log { level: "info".to_string(), message: "ready".to_string() });
+        let deserialized: Message = rmp_serde::from_slice(&rmp_serde::to_vec(&log).unwrap()).unwrap();
+        assert_eq!(deserialized.request_id(), None);
+    }
+    
     proptest! {
         #[test]
         fn test_request_id_consistency(
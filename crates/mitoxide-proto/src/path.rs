@@ -0,0 +1,154 @@
+//! Lossless encoding of paths in messages
+//!
+//! Serde only encodes paths that are valid UTF-8, but a Unix file name can be
+//! any bytes. With `#[serde(with = "crate::path")]` a path is written as a
+//! string when it is UTF-8 and as its raw bytes otherwise, so a name read from
+//! one directory listing can be sent back to refer to exactly that file.
+//! Either form is read back; bytes become a lossy string on non-Unix hosts.
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserializer, Serializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Write `path` as a string, or as bytes when it isn't UTF-8
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    if let Some(path) = path.to_str() {
+        return serializer.serialize_str(path);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        serializer.serialize_bytes(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+/// Read a path written by [`serialize`]
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    deserializer.deserialize_any(PathVisitor)
+}
+
+/// The same encoding for an optional path
+pub mod option {
+    use super::PathVisitor;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::path::PathBuf;
+    
+    /// Write `path` as [`super::serialize`] does, or nothing
+    pub fn serialize<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+        match path {
+            Some(path) => serializer.serialize_some(&Encoded(path)),
+            None => serializer.serialize_none(),
+        }
+    }
+    
+    /// Read an optional path written by [`serialize`]
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PathBuf>, D::Error> {
+        Ok(Option::<Decoded>::deserialize(deserializer)?.map(|decoded| decoded.0))
+    }
+    
+    /// A path borrowed to be written in the lossless encoding
+    struct Encoded<'a>(&'a PathBuf);
+    
+    impl serde::Serialize for Encoded<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(self.0, serializer)
+        }
+    }
+    
+    /// A path read from the lossless encoding
+    struct Decoded(PathBuf);
+    
+    impl<'de> Deserialize<'de> for Decoded {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(PathVisitor).map(Decoded)
+        }
+    }
+}
+
+/// Accepts a path as a string or as bytes
+struct PathVisitor;
+
+impl<'de> Visitor<'de> for PathVisitor {
+    type Value = PathBuf;
+    
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a path as a string or bytes")
+    }
+    
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<PathBuf, E> {
+        Ok(PathBuf::from(value))
+    }
+    
+    fn visit_string<E: de::Error>(self, value: String) -> Result<PathBuf, E> {
+        Ok(PathBuf::from(value))
+    }
+    
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<PathBuf, E> {
+        Ok(path_from_bytes(value.to_vec()))
+    }
+    
+    fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<PathBuf, E> {
+        Ok(path_from_bytes(value))
+    }
+    
+    // Formats without a bytes type, such as JSON, write them as a sequence
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PathBuf, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(path_from_bytes(bytes))
+    }
+}
+
+/// The path with these raw bytes
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+/// The path these bytes spell, replacing what isn't UTF-8
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use std::path::PathBuf;
+    
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Paths {
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+        #[serde(with = "crate::path::option")]
+        backup: Option<PathBuf>,
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path_roundtrip() {
+        use std::os::unix::ffi::OsStrExt;
+        
+        let name = std::ffi::OsStr::from_bytes(b"/tmp/caf\xe9.txt");
+        let paths = Paths { path: PathBuf::from(name), backup: Some(PathBuf::from(name)) };
+        
+        let encoded = rmp_serde::to_vec_named(&paths).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Paths>(&encoded).unwrap(), paths);
+        let encoded = serde_json::to_vec(&paths).unwrap();
+        assert_eq!(serde_json::from_slice::<Paths>(&encoded).unwrap(), paths);
+    }
+    
+    #[test]
+    fn test_utf8_path_is_a_string() {
+        let paths = Paths { path: PathBuf::from("/tmp/a.txt"), backup: None };
+        let json = serde_json::to_value(&paths).unwrap();
+        assert_eq!(json, serde_json::json!({ "path": "/tmp/a.txt", "backup": null }));
+        assert_eq!(serde_json::from_value::<Paths>(json).unwrap(), paths);
+    }
+}
@@ -0,0 +1,118 @@
+//! Token bucket rate limiting for outgoing data
+
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Cap on the rate data is sent at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained rate in bytes per second
+    pub bytes_per_second: u64,
+    /// Bytes that may be sent at once after a quiet period
+    pub burst_bytes: u64,
+}
+
+impl RateLimit {
+    /// Limit to `bytes_per_second`, with bursts of a tenth of a second's worth
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            burst_bytes: (bytes_per_second / 10).max(1),
+        }
+    }
+    
+    /// Set the burst size
+    pub fn with_burst(mut self, burst_bytes: u64) -> Self {
+        self.burst_bytes = burst_bytes;
+        self
+    }
+}
+
+/// Token bucket over bytes, shared by everything sending under one limit
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Configured limit
+    limit: RateLimit,
+    /// Current bucket level
+    bucket: Mutex<Bucket>,
+}
+
+/// Token bucket level
+#[derive(Debug)]
+struct Bucket {
+    /// Available bytes; negative while paying off an oversized send
+    tokens: f64,
+    /// When `tokens` was last brought up to date
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter with a full bucket
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            bucket: Mutex::new(Bucket {
+                tokens: limit.burst_bytes as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+    
+    /// Configured limit
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+    
+    /// Wait until `bytes` may be sent
+    ///
+    /// A send larger than the bucket goes into debt, which later sends wait
+    /// to pay off, so the long-run rate holds for any payload size.
+    pub async fn acquire(&self, bytes: usize) {
+        if let Some(wait) = self.reserve(bytes) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+    
+    /// Take `bytes` from the bucket, returning how long to wait before sending them
+    fn reserve(&self, bytes: usize) -> Option<Duration> {
+        let rate = self.limit.bytes_per_second.max(1) as f64;
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.limit.burst_bytes as f64);
+        bucket.updated = now;
+        bucket.tokens -= bytes as f64;
+        
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn test_burst_is_immediate() {
+        let limiter = RateLimiter::new(RateLimit::new(1_000).with_burst(500));
+        
+        let start = Instant::now();
+        limiter.acquire(500).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        
+        // The bucket is empty now, so the next send waits for its share
+        assert!(limiter.reserve(100).is_some());
+    }
+    
+    #[tokio::test]
+    async fn test_oversized_send_is_paid_off() {
+        let limiter = RateLimiter::new(RateLimit::new(10_000).with_burst(1_000));
+        
+        // 5000 bytes over the burst take half a second to earn back
+        let wait = limiter.reserve(6_000).unwrap();
+        assert!((wait.as_secs_f64() - 0.5).abs() < 0.01, "waited {:?}", wait);
+        let wait = limiter.reserve(1_000).unwrap();
+        assert!((wait.as_secs_f64() - 0.6).abs() < 0.01, "waited {:?}", wait);
+    }
+}
@@ -1,25 +1,45 @@
 //! Stream multiplexing and management
 
-use crate::{Frame, ProtocolError};
+use crate::message::{ErrorCode, ErrorDetails};
+use crate::{Frame, FrameCodec, ProtocolError, RateLimit, RateLimiter};
 use bytes::Bytes;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
 use uuid::Uuid;
 
+/// How long a stream waits for the peer's end-of-stream after ending its own side
+pub const DEFAULT_HALF_CLOSED_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Stream multiplexer for managing multiple logical streams
 pub struct StreamMultiplexer {
-    /// Next stream ID to assign
-    next_stream_id: AtomicU32,
+    /// Next stream ID to assign, shared by clones so IDs stay unique
+    next_stream_id: Arc<AtomicU32>,
     /// Active streams
     streams: Arc<Mutex<HashMap<u32, StreamInfo>>>,
     /// Incoming frame sender
     frame_sender: mpsc::UnboundedSender<Frame>,
     /// Incoming frame receiver
     frame_receiver: Arc<Mutex<mpsc::UnboundedReceiver<Frame>>>,
+    /// Flush requests, answered once every frame sent before them is written out
+    flush_sender: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    /// Flush request receiver, drained alongside the frames
+    flush_receiver: Arc<Mutex<mpsc::UnboundedReceiver<oneshot::Sender<()>>>>,
     /// Global flow control settings
     flow_control_config: FlowControlConfig,
+    /// Send rate limit shared by all streams
+    connection_rate_limiter: Option<Arc<RateLimiter>>,
+    /// Send rate limit given to each new stream
+    stream_rate_limit: Option<RateLimit>,
+    /// How long a stream may go without a frame before it is reset
+    idle_timeout: Option<Duration>,
+    /// How long a stream waits for the peer's end-of-stream after ending its own side
+    half_closed_timeout: Duration,
 }
 
 /// Flow control configuration
@@ -33,6 +53,39 @@ pub struct FlowControlConfig {
     pub connection_window_size: u32,
 }
 
+/// Control message carried in the payload of a flow control frame
+///
+/// Flow control frames are consumed by the multiplexer that receives them
+/// instead of being delivered to the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlowControlMessage {
+    /// The receiver processed `delta` bytes and returns them as send credit
+    WindowUpdate {
+        /// Bytes of credit returned
+        delta: u32,
+    },
+    /// The receiver asks the sender to keep at most `window_size` bytes in flight
+    ResizeWindow {
+        /// New window size, at most the configured `max_window_size`
+        window_size: u32,
+    },
+}
+
+impl FlowControlMessage {
+    /// Wrap the message in a flow control frame
+    pub fn to_frame(self, stream_id: u32, sequence: u32) -> Result<Frame, ProtocolError> {
+        let payload = rmp_serde::to_vec(&self)
+            .map_err(|e| ProtocolError::Serialization(e.to_string()))?;
+        Ok(Frame::flow_control(stream_id, sequence, Bytes::from(payload)))
+    }
+    
+    /// Read the message from a flow control frame
+    pub fn from_frame(frame: &Frame) -> Result<Self, ProtocolError> {
+        rmp_serde::from_slice(&frame.payload)
+            .map_err(|e| ProtocolError::Serialization(e.to_string()))
+    }
+}
+
 /// Information about an active stream
 #[derive(Debug)]
 struct StreamInfo {
@@ -46,6 +99,14 @@ struct StreamInfo {
     request_id: Option<Uuid>,
     /// Flow control state
     flow_control: FlowControlState,
+    /// Woken when the send window grows or the stream closes
+    window_notify: Arc<Notify>,
+    /// Next sequence number for outgoing frames, shared with the handle
+    send_sequence: Arc<AtomicU32>,
+    /// When a frame was last sent or received
+    last_activity: Instant,
+    /// When this side sent its end-of-stream, if it has
+    local_closed_at: Option<Instant>,
 }
 
 /// Flow control state for a stream
@@ -55,8 +116,10 @@ struct FlowControlState {
     send_window: u32,
     /// Receive window (credits we can receive)
     recv_window: u32,
-    /// Initial window size
-    initial_window_size: u32,
+    /// Most bytes we may have in flight, changed when the peer resizes the window
+    send_window_size: u32,
+    /// Most bytes the peer may have in flight, changed when we resize the window
+    recv_window_size: u32,
     /// Bytes sent but not yet acknowledged
     bytes_in_flight: u32,
     /// Bytes received but not yet processed
@@ -64,16 +127,49 @@ struct FlowControlState {
 }
 
 /// Stream state enumeration
+///
+/// Each side ends its half of the stream with an end-of-stream frame; the
+/// stream is closed once both have.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StreamState {
     /// Stream is open and active
     Open,
-    /// Stream is half-closed (local end closed)
-    HalfClosed,
+    /// We sent end-of-stream, the peer may still send
+    HalfClosedLocal,
+    /// The peer sent end-of-stream, we may still send
+    HalfClosedRemote,
     /// Stream is fully closed
     Closed,
 }
 
+impl StreamState {
+    /// Whether this side may still send data
+    pub fn can_send(self) -> bool {
+        matches!(self, Self::Open | Self::HalfClosedRemote)
+    }
+    
+    /// Whether the peer may still send data
+    pub fn can_recv(self) -> bool {
+        matches!(self, Self::Open | Self::HalfClosedLocal)
+    }
+    
+    /// State after this side sends end-of-stream
+    fn close_local(self) -> Self {
+        match self {
+            Self::Open | Self::HalfClosedLocal => Self::HalfClosedLocal,
+            Self::HalfClosedRemote | Self::Closed => Self::Closed,
+        }
+    }
+    
+    /// State after the peer's end-of-stream arrives
+    fn close_remote(self) -> Self {
+        match self {
+            Self::Open | Self::HalfClosedRemote => Self::HalfClosedRemote,
+            Self::HalfClosedLocal | Self::Closed => Self::Closed,
+        }
+    }
+}
+
 /// Handle to a specific stream
 pub struct StreamHandle {
     /// Stream ID
@@ -83,9 +179,11 @@ pub struct StreamHandle {
     /// Reference to the multiplexer for sending frames
     multiplexer: Arc<StreamMultiplexer>,
     /// Next sequence number for outgoing frames
-    next_sequence: AtomicU32,
+    next_sequence: Arc<AtomicU32>,
     /// Stream state
     state: StreamState,
+    /// Send rate limit of this stream alone
+    rate_limiter: Option<RateLimiter>,
 }
 
 impl Default for FlowControlConfig {
@@ -103,7 +201,8 @@ impl FlowControlState {
         Self {
             send_window: initial_window_size,
             recv_window: initial_window_size,
-            initial_window_size,
+            send_window_size: initial_window_size,
+            recv_window_size: initial_window_size,
             bytes_in_flight: 0,
             bytes_buffered: 0,
         }
@@ -111,7 +210,7 @@ impl FlowControlState {
     
     /// Check if we can send data of the given size
     fn can_send(&self, size: u32) -> bool {
-        self.send_window >= size && self.bytes_in_flight + size <= self.initial_window_size
+        self.send_window >= size && self.bytes_in_flight + size <= self.send_window_size
     }
     
     /// Consume send credits
@@ -147,6 +246,47 @@ impl FlowControlState {
         self.send_window += delta;
         self.bytes_in_flight = self.bytes_in_flight.saturating_sub(delta);
     }
+    
+    /// Apply a window size the peer asked for, keeping the credits already used
+    fn resize_send_window(&mut self, window_size: u32) {
+        self.send_window = resized(self.send_window, self.send_window_size, window_size);
+        self.send_window_size = window_size;
+    }
+    
+    /// Apply a window size we asked the peer to use
+    fn resize_recv_window(&mut self, window_size: u32) {
+        self.recv_window = resized(self.recv_window, self.recv_window_size, window_size);
+        self.recv_window_size = window_size;
+    }
+}
+
+/// Validate and encode a frame into a buffered writer without flushing it
+async fn write_buffered<W>(codec: &FrameCodec, writer: &mut BufWriter<W>, frame: &Frame) -> Result<(), ProtocolError>
+where
+    W: AsyncWrite + Unpin,
+{
+    frame.validate()?;
+    let encoded = codec.encode_frame(frame)?;
+    writer.write_all(&encoded).await
+        .map_err(|e| ProtocolError::Serialization(format!("Write error: {}", e)))
+}
+
+/// Hand everything buffered to the underlying writer
+async fn flush_writer<W>(writer: &mut BufWriter<W>) -> Result<(), ProtocolError>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.flush().await
+        .map_err(|e| ProtocolError::Serialization(format!("Flush error: {}", e)))
+}
+
+/// Credits left after a window of `old_size` becomes one of `new_size`
+fn resized(credits: u32, old_size: u32, new_size: u32) -> u32 {
+    if new_size >= old_size {
+        credits.saturating_add(new_size - old_size)
+    } else {
+        credits.saturating_sub(old_size - new_size)
+    }
 }
 
 impl StreamMultiplexer {
@@ -158,20 +298,60 @@ impl StreamMultiplexer {
     /// Create a new stream multiplexer with custom flow control config
     pub fn with_config(config: FlowControlConfig) -> Self {
         let (frame_sender, frame_receiver) = mpsc::unbounded_channel();
+        let (flush_sender, flush_receiver) = mpsc::unbounded_channel();
         
         Self {
-            next_stream_id: AtomicU32::new(1),
+            next_stream_id: Arc::new(AtomicU32::new(1)),
             streams: Arc::new(Mutex::new(HashMap::new())),
             frame_sender,
             frame_receiver: Arc::new(Mutex::new(frame_receiver)),
+            flush_sender,
+            flush_receiver: Arc::new(Mutex::new(flush_receiver)),
             flow_control_config: config,
+            connection_rate_limiter: None,
+            stream_rate_limit: None,
+            idle_timeout: None,
+            half_closed_timeout: DEFAULT_HALF_CLOSED_TIMEOUT,
         }
     }
     
+    /// Cap the combined send rate of all streams
+    pub fn with_connection_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.connection_rate_limiter = Some(Arc::new(RateLimiter::new(limit)));
+        self
+    }
+    
+    /// Cap the send rate of each stream created from now on
+    pub fn with_stream_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.stream_rate_limit = Some(limit);
+        self
+    }
+    
+    /// Reset streams that go `timeout` without a frame sent or received
+    ///
+    /// Idle streams are swept while [`StreamMultiplexer::process_frames`] or
+    /// [`StreamMultiplexer::write_frames`] runs, or on demand with
+    /// [`StreamMultiplexer::reap_idle_streams`].
+    pub fn with_stream_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+    
+    /// Forget streams whose peer hasn't ended its side `timeout` after this side ended
+    ///
+    /// A peer that goes silent would otherwise leave them half-closed for
+    /// good. They are swept along with idle streams. Defaults to
+    /// [`DEFAULT_HALF_CLOSED_TIMEOUT`].
+    pub fn with_half_closed_timeout(mut self, timeout: Duration) -> Self {
+        self.half_closed_timeout = timeout;
+        self
+    }
+    
     /// Create a new stream
     pub async fn create_stream(&self, request_id: Option<Uuid>) -> Result<StreamHandle, ProtocolError> {
         let stream_id = self.next_stream_id.fetch_add(1, Ordering::SeqCst);
         let (frame_sender, frame_receiver) = mpsc::unbounded_channel();
+        let next_sequence = Arc::new(AtomicU32::new(0));
         
         let stream_info = StreamInfo {
             state: StreamState::Open,
@@ -179,6 +359,10 @@ impl StreamMultiplexer {
             next_sequence: 0,
             request_id,
             flow_control: FlowControlState::new(self.flow_control_config.initial_window_size),
+            window_notify: Arc::new(Notify::new()),
+            send_sequence: Arc::clone(&next_sequence),
+            last_activity: Instant::now(),
+            local_closed_at: None,
         };
         
         {
@@ -190,8 +374,9 @@ impl StreamMultiplexer {
             stream_id,
             frame_receiver,
             multiplexer: Arc::new(self.clone()),
-            next_sequence: AtomicU32::new(0),
+            next_sequence,
             state: StreamState::Open,
+            rate_limiter: self.stream_rate_limit.map(RateLimiter::new),
         })
     }
     
@@ -204,18 +389,43 @@ impl StreamMultiplexer {
         if let Some(stream_info) = streams.get_mut(&stream_id) {
             // Check sequence number
             if frame.sequence != stream_info.next_sequence {
-                return Err(ProtocolError::InvalidFrame);
+                return Err(ProtocolError::InvalidFrame(format!(
+                    "stream {} expected sequence {}, got {}",
+                    stream_id, stream_info.next_sequence, frame.sequence
+                )));
             }
             
             stream_info.next_sequence += 1;
+            stream_info.last_activity = Instant::now();
+            
+            if frame.is_flow_control() {
+                match FlowControlMessage::from_frame(&frame)? {
+                    FlowControlMessage::WindowUpdate { delta } => {
+                        stream_info.flow_control.update_send_window(delta);
+                    }
+                    FlowControlMessage::ResizeWindow { window_size } => {
+                        if window_size == 0 || window_size > self.flow_control_config.max_window_size {
+                            return Err(ProtocolError::FlowControlViolation);
+                        }
+                        stream_info.flow_control.resize_send_window(window_size);
+                    }
+                }
+                stream_info.window_notify.notify_one();
+                return Ok(());
+            }
             
             // Handle end-of-stream
+            if !stream_info.state.can_recv() {
+                return Err(ProtocolError::InvalidFrame(format!(
+                    "stream {} got a frame after the peer's end-of-stream", stream_id
+                )));
+            }
             if frame.is_end_stream() {
-                stream_info.state = StreamState::Closed;
+                stream_info.state = stream_info.state.close_remote();
             }
             
             // Send frame to stream
-            if let Err(_) = stream_info.frame_sender.send(frame) {
+            if stream_info.frame_sender.send(frame).is_err() {
                 // Stream receiver dropped, clean up
                 streams.remove(&stream_id);
             }
@@ -233,6 +443,8 @@ impl StreamMultiplexer {
         
         if let Some(stream_info) = streams.get_mut(&stream_id) {
             stream_info.state = StreamState::Closed;
+            // Wake a sender waiting for window so it sees the stream is gone
+            stream_info.window_notify.notify_one();
             streams.remove(&stream_id);
             Ok(())
         } else {
@@ -240,6 +452,51 @@ impl StreamMultiplexer {
         }
     }
     
+    /// Reset and forget the streams idle for longer than the idle timeout,
+    /// and those left half-closed by the peer past the half-closed timeout
+    ///
+    /// Each stream the peer may still expect frames on gets an error frame
+    /// with `ErrorCode::Timeout`; its handle receives no more frames and can
+    /// no longer send. Returns the IDs of the streams removed.
+    pub async fn reap_idle_streams(&self) -> Vec<u32> {
+        let mut streams = self.streams.lock().await;
+        let idle: Vec<u32> = streams.iter()
+            .filter(|(_, stream_info)| {
+                self.idle_timeout.is_some_and(|timeout| stream_info.last_activity.elapsed() >= timeout)
+                    || stream_info.local_closed_at.is_some_and(|closed| closed.elapsed() >= self.half_closed_timeout)
+            })
+            .map(|(&stream_id, _)| stream_id)
+            .collect();
+        
+        for &stream_id in &idle {
+            let Some(stream_info) = streams.remove(&stream_id) else {
+                continue;
+            };
+            stream_info.window_notify.notify_one();
+            if !stream_info.state.can_send() {
+                continue;
+            }
+            let details = ErrorDetails::new(
+                ErrorCode::Timeout,
+                format!("Stream {} reset after {:?} without activity", stream_id, stream_info.last_activity.elapsed())
+            );
+            if let Ok(payload) = rmp_serde::to_vec(&details) {
+                let sequence = stream_info.send_sequence.fetch_add(1, Ordering::SeqCst);
+                let _ = self.send_frame(Frame::error(stream_id, sequence, Bytes::from(payload)));
+            }
+        }
+        idle
+    }
+    
+    /// Timer for sweeping idle and half-closed streams
+    fn idle_sweep(&self) -> Interval {
+        let timeout = self.idle_timeout.map_or(self.half_closed_timeout, |idle| idle.min(self.half_closed_timeout));
+        let period = (timeout / 4).max(Duration::from_millis(1));
+        let mut sweep = tokio::time::interval_at(Instant::now() + period, period);
+        sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        sweep
+    }
+    
     /// Get the number of active streams
     pub async fn stream_count(&self) -> usize {
         let streams = self.streams.lock().await;
@@ -252,17 +509,91 @@ impl StreamMultiplexer {
         streams.get(&stream_id).map(|info| info.state)
     }
     
+    /// Get the request ID a stream was created for
+    pub async fn stream_request_id(&self, stream_id: u32) -> Option<Uuid> {
+        let streams = self.streams.lock().await;
+        streams.get(&stream_id).and_then(|info| info.request_id)
+    }
+    
     /// Process incoming frames (should be called in a loop)
     pub async fn process_frames(&self) -> Result<(), ProtocolError> {
         let mut receiver = self.frame_receiver.lock().await;
+        let mut flushes = self.flush_receiver.lock().await;
+        let mut sweep = self.idle_sweep();
         
-        while let Some(frame) = receiver.recv().await {
-            self.route_frame(frame).await?;
+        loop {
+            tokio::select! {
+                biased;
+                frame = receiver.recv() => match frame {
+                    Some(frame) => self.route_frame(frame).await?,
+                    None => break,
+                },
+                Some(done) = flushes.recv() => {
+                    while let Ok(frame) = receiver.try_recv() {
+                        self.route_frame(frame).await?;
+                    }
+                    let _ = done.send(());
+                }
+                _ = sweep.tick() => {
+                    self.reap_idle_streams().await;
+                }
+            }
         }
         
         Ok(())
     }
     
+    /// Write outgoing frames to `writer` until it fails
+    ///
+    /// Frames are buffered and flushed whenever no more are queued, or
+    /// straight away when a stream asks with [`StreamHandle::flush`].
+    pub async fn write_frames<W>(&self, writer: W) -> Result<(), ProtocolError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let codec = FrameCodec::new();
+        let mut writer = BufWriter::new(writer);
+        let mut receiver = self.frame_receiver.lock().await;
+        let mut flushes = self.flush_receiver.lock().await;
+        let mut sweep = self.idle_sweep();
+        
+        loop {
+            tokio::select! {
+                biased;
+                frame = receiver.recv() => {
+                    let Some(frame) = frame else {
+                        break;
+                    };
+                    write_buffered(&codec, &mut writer, &frame).await?;
+                    if receiver.is_empty() {
+                        flush_writer(&mut writer).await?;
+                    }
+                }
+                Some(done) = flushes.recv() => {
+                    while let Ok(frame) = receiver.try_recv() {
+                        write_buffered(&codec, &mut writer, &frame).await?;
+                    }
+                    flush_writer(&mut writer).await?;
+                    let _ = done.send(());
+                }
+                _ = sweep.tick() => {
+                    self.reap_idle_streams().await;
+                }
+            }
+        }
+        
+        flush_writer(&mut writer).await
+    }
+    
+    /// Wait until every frame sent so far has been written out
+    async fn flush(&self) -> Result<(), ProtocolError> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.flush_sender.send(done_tx)
+            .map_err(|_| ProtocolError::StreamClosed)?;
+        done_rx.await
+            .map_err(|_| ProtocolError::StreamClosed)
+    }
+    
     /// Send a frame through the multiplexer
     pub fn send_frame(&self, frame: Frame) -> Result<(), ProtocolError> {
         self.frame_sender.send(frame)
@@ -286,12 +617,21 @@ impl StreamMultiplexer {
         
         if let Some(stream_info) = streams.get_mut(&stream_id) {
             stream_info.flow_control.update_send_window(delta);
+            stream_info.window_notify.notify_one();
             Ok(())
         } else {
             Err(ProtocolError::InvalidStreamId(stream_id))
         }
     }
     
+    /// Most bytes a stream may currently have in flight
+    pub async fn window_size(&self, stream_id: u32) -> Result<u32, ProtocolError> {
+        let streams = self.streams.lock().await;
+        streams.get(&stream_id)
+            .map(|stream_info| stream_info.flow_control.send_window_size)
+            .ok_or(ProtocolError::InvalidStreamId(stream_id))
+    }
+    
     /// Process received data and update flow control
     pub async fn process_received_data(&self, stream_id: u32, size: u32) -> Result<(), ProtocolError> {
         let mut streams = self.streams.lock().await;
@@ -320,11 +660,17 @@ impl StreamMultiplexer {
 impl Clone for StreamMultiplexer {
     fn clone(&self) -> Self {
         Self {
-            next_stream_id: AtomicU32::new(self.next_stream_id.load(Ordering::SeqCst)),
+            next_stream_id: Arc::clone(&self.next_stream_id),
             streams: Arc::clone(&self.streams),
             frame_sender: self.frame_sender.clone(),
             frame_receiver: Arc::clone(&self.frame_receiver),
+            flush_sender: self.flush_sender.clone(),
+            flush_receiver: Arc::clone(&self.flush_receiver),
             flow_control_config: self.flow_control_config.clone(),
+            connection_rate_limiter: self.connection_rate_limiter.clone(),
+            stream_rate_limit: self.stream_rate_limit,
+            idle_timeout: self.idle_timeout,
+            half_closed_timeout: self.half_closed_timeout,
         }
     }
 }
@@ -341,9 +687,20 @@ impl StreamHandle {
         self.stream_id
     }
     
+    /// Set or clear the send rate limit of this stream alone
+    ///
+    /// The connection's limit, if any, still applies on top.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.rate_limiter = limit.map(RateLimiter::new);
+    }
+    
     /// Send a data frame on this stream
+    ///
+    /// Fails with `FlowControlViolation` if the send window is too small; use
+    /// [`StreamHandle::send_data_blocking`] to wait for a window update instead.
+    /// Waits as long as the stream and connection rate limits require.
     pub async fn send_data(&mut self, payload: Bytes) -> Result<(), ProtocolError> {
-        if self.state == StreamState::Closed {
+        if !self.state.can_send() {
             return Err(ProtocolError::StreamClosed);
         }
         
@@ -354,48 +711,168 @@ impl StreamHandle {
             return Err(ProtocolError::FlowControlViolation);
         }
         
-        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
-        let frame = Frame::data(self.stream_id, sequence, payload);
-        
         // Consume flow control credits
         {
             let mut streams = self.multiplexer.streams.lock().await;
             if let Some(stream_info) = streams.get_mut(&self.stream_id) {
                 stream_info.flow_control.consume_send_credits(payload_size)?;
+                stream_info.last_activity = Instant::now();
             }
         }
         
+        self.pace(payload.len()).await;
+        self.send_data_frame(payload)
+    }
+    
+    /// Send a data frame on this stream, waiting for window updates until the
+    /// payload fits or the stream closes
+    ///
+    /// Payloads larger than the stream's window can never fit and fail with
+    /// `FlowControlViolation` right away.
+    pub async fn send_data_blocking(&mut self, payload: Bytes) -> Result<(), ProtocolError> {
+        let payload_size = payload.len() as u32;
+        
+        loop {
+            if !self.state.can_send() {
+                return Err(ProtocolError::StreamClosed);
+            }
+            
+            let window_notify = {
+                let mut streams = self.multiplexer.streams.lock().await;
+                let stream_info = streams.get_mut(&self.stream_id)
+                    .ok_or(ProtocolError::StreamClosed)?;
+                
+                if payload_size > stream_info.flow_control.send_window_size {
+                    return Err(ProtocolError::FlowControlViolation);
+                }
+                if stream_info.flow_control.can_send(payload_size) {
+                    stream_info.flow_control.consume_send_credits(payload_size)?;
+                    stream_info.last_activity = Instant::now();
+                    break;
+                }
+                Arc::clone(&stream_info.window_notify)
+            };
+            
+            // Updates made after the lock was released leave a permit, so none are missed
+            window_notify.notified().await;
+        }
+        
+        self.pace(payload.len()).await;
+        self.send_data_frame(payload)
+    }
+    
+    /// Wait until the rate limits allow `size` more bytes to be sent
+    async fn pace(&self, size: usize) {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.acquire(size).await;
+        }
+        if let Some(limiter) = &self.multiplexer.connection_rate_limiter {
+            limiter.acquire(size).await;
+        }
+    }
+    
+    /// Queue a data frame whose credits have already been consumed
+    fn send_data_frame(&self, payload: Bytes) -> Result<(), ProtocolError> {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        let frame = Frame::data(self.stream_id, sequence, payload);
         self.multiplexer.send_frame(frame)
     }
     
-    /// Send an end-of-stream frame
-    pub async fn send_end_stream(&mut self) -> Result<(), ProtocolError> {
+    /// Ask the peer to let up to `window_size` bytes be in flight on this stream
+    ///
+    /// Grows or shrinks the receive window straight away and sends a
+    /// [`FlowControlMessage::ResizeWindow`] for the peer to apply to its send
+    /// window. The size must be between 1 and the configured `max_window_size`.
+    pub async fn request_window_size(&mut self, window_size: u32) -> Result<(), ProtocolError> {
         if self.state == StreamState::Closed {
             return Err(ProtocolError::StreamClosed);
         }
+        if window_size == 0 || window_size > self.multiplexer.flow_control_config.max_window_size {
+            return Err(ProtocolError::FlowControlViolation);
+        }
+        
+        {
+            let mut streams = self.multiplexer.streams.lock().await;
+            let stream_info = streams.get_mut(&self.stream_id)
+                .ok_or(ProtocolError::StreamClosed)?;
+            stream_info.flow_control.resize_recv_window(window_size);
+            stream_info.last_activity = Instant::now();
+        }
+        
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        let frame = FlowControlMessage::ResizeWindow { window_size }.to_frame(self.stream_id, sequence)?;
+        self.multiplexer.send_frame(frame)
+    }
+    
+    /// Send an end-of-stream frame, half-closing the local side
+    ///
+    /// Nothing more can be sent, but frames from the peer are still received
+    /// until its own end-of-stream. Fails with `StreamClosed` if this side has
+    /// already ended.
+    pub async fn send_end_stream(&mut self) -> Result<(), ProtocolError> {
+        if !self.state.can_send() {
+            return Err(ProtocolError::StreamClosed);
+        }
         
         let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
         let frame = Frame::end_stream(self.stream_id, sequence);
         
-        self.state = StreamState::HalfClosed;
-        self.multiplexer.send_frame(frame)
+        {
+            let mut streams = self.multiplexer.streams.lock().await;
+            if let Some(stream_info) = streams.get_mut(&self.stream_id) {
+                stream_info.state = stream_info.state.close_local();
+                stream_info.last_activity = Instant::now();
+                stream_info.local_closed_at = Some(stream_info.last_activity);
+            }
+        }
+        self.state = self.state.close_local();
+        self.multiplexer.send_frame(frame)?;
+        self.release_if_closed().await;
+        Ok(())
+    }
+    
+    /// Wait until every frame this stream has sent is written out
+    ///
+    /// Returns once [`StreamMultiplexer::write_frames`] has flushed them to
+    /// its writer (or [`StreamMultiplexer::process_frames`] has routed them).
+    /// Frames sent by other streams in the meantime are flushed too.
+    pub async fn flush(&self) -> Result<(), ProtocolError> {
+        self.multiplexer.flush().await
     }
     
     /// Receive the next frame on this stream
+    ///
+    /// Receiving the peer's end-of-stream half-closes the remote side.
     pub async fn recv_frame(&mut self) -> Option<Frame> {
-        self.frame_receiver.recv().await
+        let frame = self.frame_receiver.recv().await?;
+        if frame.is_end_stream() {
+            self.state = self.state.close_remote();
+            self.release_if_closed().await;
+        }
+        Some(frame)
     }
     
     /// Close this stream
+    ///
+    /// Ends the local side if it hasn't been already. The stream is only
+    /// fully closed, and removed from the multiplexer, once the peer's
+    /// end-of-stream has also been received; until then it stays
+    /// [`StreamState::HalfClosedLocal`].
     pub async fn close(&mut self) -> Result<(), ProtocolError> {
-        if self.state != StreamState::Closed {
+        if self.state.can_send() {
             self.send_end_stream().await?;
-            self.state = StreamState::Closed;
-            self.multiplexer.close_stream(self.stream_id).await?;
         }
         Ok(())
     }
     
+    /// Remove the stream from the multiplexer once both sides have ended
+    async fn release_if_closed(&self) {
+        if self.state == StreamState::Closed {
+            // A stream reset with `close_stream` is already gone
+            let _ = self.multiplexer.close_stream(self.stream_id).await;
+        }
+    }
+    
     /// Get the current stream state
     pub fn state(&self) -> StreamState {
         self.state
@@ -415,6 +892,11 @@ mod tests {
         assert_eq!(stream.stream_id(), 1);
         assert_eq!(stream.state(), StreamState::Open);
         assert_eq!(multiplexer.stream_count().await, 1);
+        assert_eq!(multiplexer.stream_request_id(1).await, None);
+        
+        let request_id = Uuid::new_v4();
+        let stream = multiplexer.create_stream(Some(request_id)).await.unwrap();
+        assert_eq!(multiplexer.stream_request_id(stream.stream_id()).await, Some(request_id));
     }
     
     #[tokio::test]
@@ -431,6 +913,31 @@ mod tests {
         assert_eq!(multiplexer.stream_count().await, 3);
     }
     
+    #[tokio::test]
+    async fn test_clones_allocate_unique_stream_ids() {
+        let multiplexer = StreamMultiplexer::new();
+        let clone = multiplexer.clone();
+        
+        let create = |multiplexer: StreamMultiplexer| tokio::spawn(async move {
+            let mut streams = Vec::new();
+            for _ in 0..100 {
+                streams.push(multiplexer.create_stream(None).await.unwrap());
+            }
+            streams
+        });
+        let first = create(multiplexer.clone());
+        let second = create(clone);
+        
+        let mut ids: Vec<u32> = first.await.unwrap().iter()
+            .chain(second.await.unwrap().iter())
+            .map(StreamHandle::stream_id)
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 200);
+        assert_eq!(multiplexer.stream_count().await, 200);
+    }
+    
     #[tokio::test]
     async fn test_frame_routing() {
         let multiplexer = StreamMultiplexer::new();
@@ -469,16 +976,74 @@ mod tests {
     async fn test_stream_close() {
         let multiplexer = StreamMultiplexer::new();
         let mut stream = multiplexer.create_stream(None).await.unwrap();
-        let _stream_id = stream.stream_id();
+        let stream_id = stream.stream_id();
         
         assert_eq!(multiplexer.stream_count().await, 1);
         
+        // Closing ends the local side; the peer may still send
         stream.close().await.unwrap();
+        assert_eq!(stream.state(), StreamState::HalfClosedLocal);
+        assert_eq!(multiplexer.stream_state(stream_id).await, Some(StreamState::HalfClosedLocal));
+        assert!(matches!(stream.send_data(Bytes::from("late")).await, Err(ProtocolError::StreamClosed)));
+        stream.close().await.unwrap();
+        assert!(matches!(stream.send_end_stream().await, Err(ProtocolError::StreamClosed)));
+        
+        multiplexer.route_frame(Frame::data(stream_id, 0, Bytes::from("trailer"))).await.unwrap();
+        multiplexer.route_frame(Frame::end_stream(stream_id, 1)).await.unwrap();
+        assert_eq!(stream.recv_frame().await.unwrap().payload, Bytes::from("trailer"));
+        assert_eq!(stream.state(), StreamState::HalfClosedLocal);
         
+        // The peer's end-of-stream completes the close
+        assert!(stream.recv_frame().await.unwrap().is_end_stream());
+        assert_eq!(stream.state(), StreamState::Closed);
+        assert_eq!(multiplexer.stream_count().await, 0);
+        
+        let mut outgoing = multiplexer.frame_receiver.lock().await;
+        let end = outgoing.try_recv().unwrap();
+        assert!(end.is_end_stream());
+        assert!(outgoing.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_remote_half_close_first() {
+        let multiplexer = StreamMultiplexer::new();
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        let stream_id = stream.stream_id();
+        
+        multiplexer.route_frame(Frame::end_stream(stream_id, 0)).await.unwrap();
+        assert_eq!(multiplexer.stream_state(stream_id).await, Some(StreamState::HalfClosedRemote));
+        assert!(stream.recv_frame().await.unwrap().is_end_stream());
+        assert_eq!(stream.state(), StreamState::HalfClosedRemote);
+        
+        // The peer is done, so more frames from it are a protocol error
+        let late = multiplexer.route_frame(Frame::data(stream_id, 1, Bytes::from("late"))).await;
+        assert!(matches!(late, Err(ProtocolError::InvalidFrame(_))));
+        
+        // This side can still answer before ending its own half
+        stream.send_data(Bytes::from("response")).await.unwrap();
+        stream.close().await.unwrap();
         assert_eq!(stream.state(), StreamState::Closed);
         assert_eq!(multiplexer.stream_count().await, 0);
     }
     
+    #[tokio::test]
+    async fn test_half_closes_crossing() {
+        let multiplexer = StreamMultiplexer::new();
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        let stream_id = stream.stream_id();
+        
+        // The peer's end-of-stream arrives before this side has read it
+        stream.send_end_stream().await.unwrap();
+        multiplexer.route_frame(Frame::end_stream(stream_id, 0)).await.unwrap();
+        assert_eq!(multiplexer.stream_state(stream_id).await, Some(StreamState::Closed));
+        assert_eq!(stream.state(), StreamState::HalfClosedLocal);
+        
+        assert!(stream.recv_frame().await.unwrap().is_end_stream());
+        assert_eq!(stream.state(), StreamState::Closed);
+        assert_eq!(multiplexer.stream_count().await, 0);
+        stream.close().await.unwrap();
+    }
+    
     #[tokio::test]
     async fn test_invalid_stream_id() {
         let multiplexer = StreamMultiplexer::new();
@@ -499,7 +1064,56 @@ mod tests {
         let frame = Frame::data(stream_id, 5, Bytes::from("test"));
         let result = multiplexer.route_frame(frame).await;
         
-        assert!(matches!(result, Err(ProtocolError::InvalidFrame)));
+        assert!(matches!(result, Err(ProtocolError::InvalidFrame(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_empty_data_frame_keeps_sequence_and_stream_open() {
+        let multiplexer = StreamMultiplexer::new();
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        let stream_id = stream.stream_id();
+        
+        multiplexer.route_frame(Frame::data(stream_id, 0, Bytes::new())).await.unwrap();
+        multiplexer.route_frame(Frame::data(stream_id, 1, Bytes::from("payload"))).await.unwrap();
+        assert_eq!(multiplexer.stream_state(stream_id).await, Some(StreamState::Open));
+        
+        let empty = stream.recv_frame().await.unwrap();
+        assert_eq!(empty.sequence, 0);
+        assert!(empty.payload.is_empty());
+        assert!(!empty.is_end_stream());
+        let data = stream.recv_frame().await.unwrap();
+        assert_eq!(data.sequence, 1);
+        assert_eq!(data.payload, Bytes::from("payload"));
+        
+        // The empty frame took sequence 0, so a replay of it is out of order
+        assert!(multiplexer.route_frame(Frame::data(stream_id, 1, Bytes::new())).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_send_empty_data() {
+        let config = FlowControlConfig {
+            initial_window_size: 100,
+            max_window_size: 200,
+            connection_window_size: 500,
+        };
+        let multiplexer = StreamMultiplexer::with_config(config);
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        let stream_id = stream.stream_id();
+        
+        stream.send_data(Bytes::new()).await.unwrap();
+        stream.send_data(Bytes::from(vec![0u8; 100])).await.unwrap();
+        assert!(!multiplexer.can_send_data(stream_id, 1).await.unwrap());
+        
+        // Empty frames use no window, so they go out even when it is full
+        stream.send_data(Bytes::new()).await.unwrap();
+        timeout(Duration::from_secs(1), stream.send_data_blocking(Bytes::new())).await.unwrap().unwrap();
+        
+        let mut outgoing = multiplexer.frame_receiver.lock().await;
+        for (sequence, len) in [(0, 0), (1, 100), (2, 0), (3, 0)] {
+            let frame = outgoing.recv().await.unwrap();
+            assert_eq!((frame.sequence, frame.payload.len()), (sequence, len));
+            assert!(!frame.is_end_stream());
+        }
     }
     
     #[tokio::test]
@@ -512,8 +1126,8 @@ mod tests {
         let frame = Frame::end_stream(stream_id, 0);
         multiplexer.route_frame(frame).await.unwrap();
         
-        // Stream should be marked as closed
-        assert_eq!(multiplexer.stream_state(stream_id).await, Some(StreamState::Closed));
+        // Only the remote side is closed
+        assert_eq!(multiplexer.stream_state(stream_id).await, Some(StreamState::HalfClosedRemote));
     }
     
     #[tokio::test]
@@ -583,6 +1197,212 @@ mod tests {
         assert!(multiplexer.can_send_data(stream_id, 50).await.unwrap());
     }
     
+    #[tokio::test]
+    async fn test_send_data_blocking_waits_for_window() {
+        let config = FlowControlConfig {
+            initial_window_size: 100,
+            max_window_size: 200,
+            connection_window_size: 500,
+        };
+        let multiplexer = StreamMultiplexer::with_config(config);
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        let stream_id = stream.stream_id();
+        
+        stream.send_data(Bytes::from(vec![0u8; 100])).await.unwrap();
+        
+        // The non-blocking variant still fails on an exhausted window
+        assert!(matches!(
+            stream.send_data(Bytes::from(vec![0u8; 50])).await,
+            Err(ProtocolError::FlowControlViolation)
+        ));
+        
+        let sender = tokio::spawn(async move {
+            stream.send_data_blocking(Bytes::from(vec![0u8; 50])).await
+        });
+        
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sender.is_finished());
+        
+        // Too small an update keeps it waiting
+        multiplexer.update_window(stream_id, 20).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sender.is_finished());
+        
+        multiplexer.update_window(stream_id, 30).await.unwrap();
+        let result = timeout(Duration::from_secs(1), sender).await.unwrap().unwrap();
+        assert!(result.is_ok());
+        assert!(!multiplexer.can_send_data(stream_id, 1).await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_send_data_blocking_unblocked_by_close() {
+        let config = FlowControlConfig {
+            initial_window_size: 100,
+            max_window_size: 200,
+            connection_window_size: 500,
+        };
+        let multiplexer = StreamMultiplexer::with_config(config);
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        let stream_id = stream.stream_id();
+        
+        stream.send_data(Bytes::from(vec![0u8; 100])).await.unwrap();
+        let sender = tokio::spawn(async move {
+            stream.send_data_blocking(Bytes::from(vec![0u8; 10])).await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        
+        multiplexer.close_stream(stream_id).await.unwrap();
+        let result = timeout(Duration::from_secs(1), sender).await.unwrap().unwrap();
+        assert!(matches!(result, Err(ProtocolError::StreamClosed)));
+    }
+    
+    #[tokio::test]
+    async fn test_send_data_blocking_oversized_payload() {
+        let config = FlowControlConfig {
+            initial_window_size: 100,
+            max_window_size: 200,
+            connection_window_size: 500,
+        };
+        let multiplexer = StreamMultiplexer::with_config(config);
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        
+        let result = stream.send_data_blocking(Bytes::from(vec![0u8; 101])).await;
+        assert!(matches!(result, Err(ProtocolError::FlowControlViolation)));
+    }
+    
+    /// Send 100-byte chunks until the window is full and return how many bytes went out
+    async fn fill_window(stream: &mut StreamHandle) -> usize {
+        let mut sent = 0;
+        while stream.send_data(Bytes::from(vec![0u8; 100])).await.is_ok() {
+            sent += 100;
+        }
+        sent
+    }
+    
+    #[tokio::test]
+    async fn test_request_window_size_mid_transfer() {
+        let config = FlowControlConfig {
+            initial_window_size: 1000,
+            max_window_size: 4000,
+            connection_window_size: 8000,
+        };
+        let sender = StreamMultiplexer::with_config(config.clone());
+        let receiver = StreamMultiplexer::with_config(config);
+        let mut upload = sender.create_stream(None).await.unwrap();
+        let mut download = receiver.create_stream(None).await.unwrap();
+        let stream_id = upload.stream_id();
+        assert_eq!(download.stream_id(), stream_id);
+        
+        // With the initial window, 1000 bytes fit in flight
+        assert_eq!(fill_window(&mut upload).await, 1000);
+        
+        // Past max_window_size is refused without sending anything
+        assert!(matches!(download.request_window_size(4001).await, Err(ProtocolError::FlowControlViolation)));
+        
+        download.request_window_size(4000).await.unwrap();
+        let control = receiver.frame_receiver.lock().await.recv().await.unwrap();
+        assert!(control.is_flow_control());
+        assert_eq!(
+            FlowControlMessage::from_frame(&control).unwrap(),
+            FlowControlMessage::ResizeWindow { window_size: 4000 }
+        );
+        sender.route_frame(control).await.unwrap();
+        assert_eq!(sender.window_size(stream_id).await.unwrap(), 4000);
+        
+        // 3000 more bytes now fit alongside the 1000 still in flight
+        assert_eq!(fill_window(&mut upload).await, 3000);
+        
+        // The receiver takes the larger amount too
+        receiver.process_received_data(stream_id, 4000).await.unwrap();
+        assert!(receiver.process_received_data(stream_id, 1).await.is_err());
+        
+        // Shrinking leaves the bytes already in flight owing
+        download.request_window_size(2000).await.unwrap();
+        let control = receiver.frame_receiver.lock().await.recv().await.unwrap();
+        sender.route_frame(control).await.unwrap();
+        sender.update_window(stream_id, 1900).await.unwrap();
+        assert!(!sender.can_send_data(stream_id, 100).await.unwrap());
+        sender.update_window(stream_id, 200).await.unwrap();
+        assert_eq!(fill_window(&mut upload).await, 100);
+    }
+    
+    #[tokio::test]
+    async fn test_window_update_frame() {
+        let config = FlowControlConfig {
+            initial_window_size: 100,
+            max_window_size: 200,
+            connection_window_size: 500,
+        };
+        let multiplexer = StreamMultiplexer::with_config(config);
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        let stream_id = stream.stream_id();
+        stream.send_data(Bytes::from(vec![0u8; 100])).await.unwrap();
+        
+        let frame = FlowControlMessage::WindowUpdate { delta: 50 }.to_frame(stream_id, 0).unwrap();
+        multiplexer.route_frame(frame).await.unwrap();
+        assert!(multiplexer.can_send_data(stream_id, 50).await.unwrap());
+        
+        // Control frames are not delivered to the stream
+        let data = Frame::data(stream_id, 1, Bytes::from_static(b"x"));
+        multiplexer.route_frame(data).await.unwrap();
+        assert_eq!(&stream.recv_frame().await.unwrap().payload[..], b"x");
+    }
+    
+    /// Config with a window large enough that rate limits are the only brake
+    fn unthrottled_window() -> FlowControlConfig {
+        FlowControlConfig {
+            initial_window_size: 1048576,
+            max_window_size: 1048576,
+            connection_window_size: 1048576,
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_stream_rate_limit_paces_send_data() {
+        let multiplexer = StreamMultiplexer::with_config(unthrottled_window())
+            .with_stream_rate_limit(RateLimit::new(100_000).with_burst(10_000));
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        
+        // 50KB at 100KB/s, less the 10KB burst, takes about 0.4s
+        let start = tokio::time::Instant::now();
+        for _ in 0..50 {
+            stream.send_data(Bytes::from(vec![0u8; 1000])).await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(350), "too fast: {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(800), "too slow: {:?}", elapsed);
+        
+        // Lifting the limit on the stream sends at full speed again
+        stream.set_rate_limit(None);
+        let start = tokio::time::Instant::now();
+        stream.send_data(Bytes::from(vec![0u8; 50_000])).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+    
+    #[tokio::test]
+    async fn test_connection_rate_limit_is_shared_by_streams() {
+        let multiplexer = StreamMultiplexer::with_config(unthrottled_window())
+            .with_connection_rate_limit(RateLimit::new(100_000).with_burst(10_000));
+        
+        // Two streams of 25KB each share the 100KB/s, so together they take about 0.4s
+        let start = tokio::time::Instant::now();
+        let mut senders = Vec::new();
+        for _ in 0..2 {
+            let mut stream = multiplexer.create_stream(None).await.unwrap();
+            senders.push(tokio::spawn(async move {
+                for _ in 0..25 {
+                    stream.send_data_blocking(Bytes::from(vec![0u8; 1000])).await.unwrap();
+                }
+            }));
+        }
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(350), "too fast: {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(800), "too slow: {:?}", elapsed);
+    }
+    
     #[tokio::test]
     async fn test_receive_flow_control() {
         let multiplexer = StreamMultiplexer::new();
@@ -599,6 +1419,139 @@ mod tests {
         multiplexer.process_received_data(stream_id, 500).await.unwrap();
     }
     
+    /// Writer that only counts bytes as delivered once they are flushed
+    #[derive(Clone, Default)]
+    struct CountingWriter {
+        /// Bytes written but not yet flushed
+        pending: Arc<std::sync::Mutex<Vec<u8>>>,
+        /// Bytes flushed through to the "transport"
+        delivered: Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+    
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.pending.lock().unwrap().extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+        
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+            self.delivered.lock().unwrap().extend_from_slice(&pending);
+            std::task::Poll::Ready(Ok(()))
+        }
+        
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_flush_returns_after_frames_reach_writer() {
+        let multiplexer = StreamMultiplexer::new();
+        let writer = CountingWriter::default();
+        let pump = multiplexer.clone();
+        let pump_writer = writer.clone();
+        tokio::spawn(async move { pump.write_frames(pump_writer).await });
+        
+        let mut stream = multiplexer.create_stream(None).await.unwrap();
+        for i in 0..5u8 {
+            stream.send_data(Bytes::from(vec![i; 100])).await.unwrap();
+        }
+        timeout(Duration::from_secs(1), stream.flush()).await.unwrap().unwrap();
+        
+        let delivered = writer.delivered.lock().unwrap().clone();
+        let mut reader = delivered.as_slice();
+        let mut codec = FrameCodec::new();
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.read_frame(&mut reader).await.unwrap() {
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 5);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.stream_id, stream.stream_id());
+            assert_eq!(frame.payload, Bytes::from(vec![i as u8; 100]));
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_flush_without_writer_is_stream_closed() {
+        let multiplexer = StreamMultiplexer::new();
+        let stream = multiplexer.create_stream(None).await.unwrap();
+        let mut flushes = multiplexer.flush_receiver.lock().await;
+        flushes.close();
+        drop(flushes);
+        
+        assert!(matches!(stream.flush().await, Err(ProtocolError::StreamClosed)));
+    }
+    
+    #[tokio::test]
+    async fn test_idle_stream_is_reset_and_reclaimed() {
+        let multiplexer = StreamMultiplexer::new().with_stream_idle_timeout(Duration::from_millis(100));
+        let writer = CountingWriter::default();
+        let pump = multiplexer.clone();
+        let pump_writer = writer.clone();
+        tokio::spawn(async move { pump.write_frames(pump_writer).await });
+        
+        let mut idle = multiplexer.create_stream(None).await.unwrap();
+        let mut busy = multiplexer.create_stream(None).await.unwrap();
+        assert_eq!(multiplexer.stream_count().await, 2);
+        
+        // Frames from the peer keep the busy stream alive past the timeout
+        for sequence in 0..8 {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            multiplexer.route_frame(Frame::data(busy.stream_id(), sequence, Bytes::from("tick"))).await.unwrap();
+            assert!(busy.recv_frame().await.is_some());
+        }
+        
+        assert_eq!(multiplexer.stream_count().await, 1);
+        assert_eq!(multiplexer.stream_state(idle.stream_id()).await, None);
+        assert_eq!(multiplexer.stream_state(busy.stream_id()).await, Some(StreamState::Open));
+        assert!(timeout(Duration::from_secs(1), idle.recv_frame()).await.unwrap().is_none());
+        assert!(idle.send_data(Bytes::from("late")).await.is_err());
+        
+        // The peer is told the stream was reset
+        timeout(Duration::from_secs(1), busy.flush()).await.unwrap().unwrap();
+        let delivered = writer.delivered.lock().unwrap().clone();
+        let mut reader = delivered.as_slice();
+        let frame = FrameCodec::new().read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(frame.stream_id, idle.stream_id());
+        assert!(frame.is_error());
+        let details: ErrorDetails = rmp_serde::from_slice(&frame.payload).unwrap();
+        assert_eq!(details.code, ErrorCode::Timeout);
+    }
+    
+    #[tokio::test]
+    async fn test_stream_left_half_closed_by_silent_peer_is_reclaimed() {
+        let multiplexer = StreamMultiplexer::new().with_half_closed_timeout(Duration::from_millis(50));
+        let mut silent = multiplexer.create_stream(None).await.unwrap();
+        let mut open = multiplexer.create_stream(None).await.unwrap();
+        silent.send_end_stream().await.unwrap();
+        assert_eq!(multiplexer.stream_state(silent.stream_id()).await, Some(StreamState::HalfClosedLocal));
+        
+        // Only the stream waiting on the peer's end-of-stream times out, however idle the other is
+        assert!(multiplexer.reap_idle_streams().await.is_empty());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(multiplexer.reap_idle_streams().await, vec![silent.stream_id()]);
+        assert_eq!(multiplexer.stream_count().await, 1);
+        assert!(silent.recv_frame().await.is_none());
+        assert_eq!(open.state(), StreamState::Open);
+        
+        // The peer's late end-of-stream is for a stream that no longer exists
+        let late = multiplexer.route_frame(Frame::end_stream(silent.stream_id(), 0)).await;
+        assert!(matches!(late, Err(ProtocolError::InvalidStreamId(_))));
+        open.close().await.unwrap();
+    }
+    
     // Property-based tests
     use proptest::prelude::*;
     
@@ -696,10 +1649,17 @@ mod tests {
                     }
                 }
                 
-                // Close all streams
+                // Close all streams, which stay open to the peer until it ends them too
                 for stream in &mut streams {
                     stream.close().await?;
                 }
+                prop_assert_eq!(multiplexer.stream_count().await, num_streams);
+                
+                for stream in &mut streams {
+                    multiplexer.route_frame(Frame::end_stream(stream.stream_id(), 0)).await?;
+                    stream.recv_frame().await;
+                    prop_assert_eq!(stream.state(), StreamState::Closed);
+                }
                 
                 prop_assert_eq!(multiplexer.stream_count().await, 0);
                 
@@ -0,0 +1,266 @@
+//! Rewriting of file content as it passes through the agent
+//!
+//! Content is rewritten as it arrives, applying each [`ContentTransform`] in
+//! turn:
+//!
+//! - [`ContentTransform::CrlfToLf`] turns every `\r\n` into `\n`
+//! - [`ContentTransform::LfToCrlf`] turns every `\n` not after a `\r` into `\r\n`
+//! - [`ContentTransform::Substitute`] expands `${NAME}` references as
+//!   [`expand_vars`](crate::expand::expand_vars) does, from the given map only
+//!
+//! A reference can't span lines, and a file being substituted must be UTF-8.
+//! Nothing else is supported: a transform never runs code or reads anything
+//! besides the content and the request.
+
+use crate::expand::expand_vars;
+use crate::message::{ContentTransform, ErrorCode, ErrorDetails};
+use std::fmt;
+
+/// Content that a transform can't be applied to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformError {
+    /// Line the problem is on, starting at 1
+    pub line: usize,
+    /// What is wrong with it
+    pub reason: String,
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Can't transform line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+impl From<TransformError> for ErrorDetails {
+    fn from(error: TransformError) -> Self {
+        ErrorDetails::new(ErrorCode::InvalidRequest, error.to_string())
+    }
+}
+
+/// Longest `${…}` reference held back waiting for its `}`
+///
+/// Past it the reference is transformed as it is, and fails as unterminated.
+const MAX_REFERENCE_LEN: usize = 4096;
+
+/// Applies transforms to content that arrives in pieces
+///
+/// Each piece is transformed as soon as it arrives, so a long line costs no
+/// more memory than a short one. Held back until the next piece or
+/// [`ContentTransformer::finish`] are only a trailing `\r`, which may start a
+/// `\r\n`, and when substituting, a reference or UTF-8 character that isn't
+/// complete yet.
+#[derive(Debug)]
+pub struct ContentTransformer<'a> {
+    /// Transforms applied to each line, in order
+    transforms: &'a [ContentTransform],
+    /// Content that can't be transformed before more arrives
+    pending: Vec<u8>,
+    /// Number of the next line
+    line: usize,
+}
+
+impl<'a> ContentTransformer<'a> {
+    /// Apply `transforms` in order
+    pub fn new(transforms: &'a [ContentTransform]) -> Self {
+        Self { transforms, pending: Vec::new(), line: 1 }
+    }
+    
+    /// Transform `data` and what was held back before it, up to what must wait for more
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, TransformError> {
+        if self.transforms.is_empty() {
+            return Ok(data.to_vec());
+        }
+        self.pending.extend_from_slice(data);
+        let rest = self.pending.split_off(self.ready_len());
+        let ready = std::mem::replace(&mut self.pending, rest);
+        self.transform(&ready)
+    }
+    
+    /// Transform whatever was held back
+    pub fn finish(mut self) -> Result<Vec<u8>, TransformError> {
+        let last = std::mem::take(&mut self.pending);
+        self.transform(&last)
+    }
+    
+    /// Length of the start of the pending content that doesn't depend on what follows
+    fn ready_len(&self) -> usize {
+        let pending = &self.pending;
+        let mut ready = pending.len();
+        if pending.last() == Some(&b'\r') {
+            ready -= 1;
+        }
+        if self.transforms.iter().any(|transform| matches!(transform, ContentTransform::Substitute { .. })) {
+            // A reference can't span lines, so only the last one can be incomplete
+            let line_start = pending[..ready].iter().rposition(|&b| b == b'\n').map_or(0, |newline| newline + 1);
+            if let Some(reference) = incomplete_reference(&pending[line_start..ready]) {
+                ready = line_start + reference;
+            }
+            ready = complete_utf8_len(&pending[..ready]);
+        }
+        ready
+    }
+    
+    /// Transform content line by line, counting the lines it ends
+    fn transform(&mut self, content: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let mut output = Vec::with_capacity(content.len());
+        for part in content.split_inclusive(|&b| b == b'\n') {
+            output.extend(self.transform_part(part)?);
+            if part.ends_with(b"\n") {
+                self.line += 1;
+            }
+        }
+        Ok(output)
+    }
+    
+    /// Transform part of a single line
+    fn transform_part(&self, part: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let mut line = part.to_vec();
+        for transform in self.transforms {
+            line = match transform {
+                ContentTransform::CrlfToLf => crlf_to_lf(&line),
+                ContentTransform::LfToCrlf => lf_to_crlf(&line),
+                ContentTransform::Substitute { vars } => {
+                    let text = std::str::from_utf8(&line).map_err(|_| TransformError {
+                        line: self.line,
+                        reason: "content to substitute is not UTF-8".to_string(),
+                    })?;
+                    let expanded = expand_vars(text, vars).map_err(|e| TransformError {
+                        line: self.line,
+                        reason: e.message,
+                    })?;
+                    expanded.into_bytes()
+                }
+            };
+        }
+        Ok(line)
+    }
+}
+
+/// Offset of a `$` in `line` that may start a reference once more content arrives
+///
+/// A reference longer than [`MAX_REFERENCE_LEN`] isn't waited for.
+fn incomplete_reference(line: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(offset) = line[start..].iter().position(|&b| b == b'$') {
+        let dollar = start + offset;
+        match line.get(dollar + 1) {
+            None => return Some(dollar),
+            Some(b'$') => start = dollar + 2,
+            Some(b'{') => match line[dollar + 2..].iter().position(|&b| b == b'}') {
+                Some(end) => start = dollar + 2 + end + 1,
+                None if line.len() - dollar <= MAX_REFERENCE_LEN => return Some(dollar),
+                None => return None,
+            },
+            Some(_) => start = dollar + 1,
+        }
+    }
+    None
+}
+
+/// Length of `bytes` without a UTF-8 character cut off at its end
+fn complete_utf8_len(bytes: &[u8]) -> usize {
+    match std::str::from_utf8(bytes) {
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => bytes.len(),
+    }
+}
+
+/// Apply `transforms` to the whole of `content`
+pub fn transform_content(transforms: &[ContentTransform], content: &[u8]) -> Result<Vec<u8>, TransformError> {
+    let mut transformer = ContentTransformer::new(transforms);
+    let mut output = transformer.push(content)?;
+    output.extend(transformer.finish()?);
+    Ok(output)
+}
+
+fn crlf_to_lf(line: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(line.len());
+    for (index, &b) in line.iter().enumerate() {
+        if !(b == b'\r' && line.get(index + 1) == Some(&b'\n')) {
+            output.push(b);
+        }
+    }
+    output
+}
+
+fn lf_to_crlf(line: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(line.len() + 1);
+    for (index, &b) in line.iter().enumerate() {
+        if b == b'\n' && (index == 0 || line[index - 1] != b'\r') {
+            output.push(b'\r');
+        }
+        output.push(b);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    
+    fn substitute(vars: &[(&str, &str)]) -> ContentTransform {
+        ContentTransform::Substitute {
+            vars: vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>(),
+        }
+    }
+    
+    #[test]
+    fn test_line_endings() {
+        let content = b"one\r\ntwo\nthree\r\n";
+        assert_eq!(transform_content(&[ContentTransform::CrlfToLf], content).unwrap(), b"one\ntwo\nthree\n");
+        assert_eq!(transform_content(&[ContentTransform::LfToCrlf], content).unwrap(), b"one\r\ntwo\r\nthree\r\n");
+        // Binary content passes through the line ending transforms
+        assert_eq!(transform_content(&[ContentTransform::CrlfToLf], b"\xff\r\n\xfe").unwrap(), b"\xff\n\xfe");
+    }
+    
+    #[test]
+    fn test_substitution_across_pieces() {
+        let transforms = [substitute(&[("HOST", "db.internal"), ("PORT", "5432")]), ContentTransform::LfToCrlf];
+        let mut transformer = ContentTransformer::new(&transforms);
+        let mut output = Vec::new();
+        for piece in [&b"host=${HO"[..], b"ST}\nport=${PORT:-1}", b"\ncost=$$5"] {
+            output.extend(transformer.push(piece).unwrap());
+        }
+        output.extend(transformer.finish().unwrap());
+        assert_eq!(output, b"host=db.internal\r\nport=5432\r\ncost=$5");
+    }
+    
+    #[test]
+    fn test_long_lines_are_not_buffered() {
+        let mut transformer = ContentTransformer::new(&[ContentTransform::CrlfToLf]);
+        let line = vec![b'x'; 1024 * 1024];
+        assert_eq!(transformer.push(&line).unwrap().len(), line.len());
+        // Only a `\r` that may be followed by `\n` waits
+        assert_eq!(transformer.push(b"a\r").unwrap(), b"a");
+        assert_eq!(transformer.push(b"\nb").unwrap(), b"\nb");
+        assert_eq!(transformer.push(b"\r").unwrap(), b"");
+        assert_eq!(transformer.finish().unwrap(), b"\r");
+        
+        let transforms = [substitute(&[("HOST", "db")])];
+        let mut transformer = ContentTransformer::new(&transforms);
+        assert_eq!(transformer.push(b"host=${HO").unwrap(), b"host=");
+        assert_eq!(transformer.push(b"ST} cost=$").unwrap(), b"db cost=");
+        assert_eq!(transformer.push(b"$5 caf\xc3").unwrap(), b"$5 caf");
+        assert_eq!(transformer.push(b"\xa9 ${").unwrap(), "\u{e9} ".as_bytes());
+        assert_eq!(transformer.push(b"HOST}").unwrap(), b"db");
+        assert_eq!(transformer.finish().unwrap(), b"");
+        
+        // A reference that never ends isn't held back forever
+        let mut transformer = ContentTransformer::new(&transforms);
+        assert_eq!(transformer.push(b"${").unwrap(), b"");
+        assert!(transformer.push(&vec![b'A'; MAX_REFERENCE_LEN]).is_err());
+    }
+    
+    #[test]
+    fn test_substitution_errors_name_the_line() {
+        let error = transform_content(&[substitute(&[])], b"fine\n${MISSING}\n").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(error.reason.contains("MISSING"), "{}", error);
+        
+        let error = transform_content(&[substitute(&[])], b"\xff\n").unwrap_err();
+        assert_eq!(error.line, 1);
+    }
+}
@@ -304,7 +304,7 @@ impl Default for Bootstrap {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Transport, TransportError, ConnectionInfo, TransportType};
+    use crate::{Transport, TransportError, ConnectionInfo, ServerInfo, TransportType};
     use async_trait::async_trait;
     
     // Mock transport for testing
@@ -342,6 +342,7 @@ mod tests {
                 port: 22,
                 username: "mockuser".to_string(),
                 transport_type: TransportType::Local,
+                server: ServerInfo::default(),
             }
         }
         
@@ -382,7 +383,7 @@ mod tests {
     
     #[test]
     fn test_bootstrap_method_detection() {
-        let methods = vec![
+        let methods = [
             BootstrapMethod::MemfdCreate,
             BootstrapMethod::Python,
             BootstrapMethod::DevShm,
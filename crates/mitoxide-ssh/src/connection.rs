@@ -1,28 +1,73 @@
 //! SSH connection management
 
 use crate::TransportError;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::process::Child;
 use tracing::{debug, warn};
 
+/// Reader half of a connection that isn't backed by a process
+pub type BoxedReader = Box<dyn AsyncRead + Unpin + Send + Sync>;
+
+/// Writer half of a connection that isn't backed by a process
+pub type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send + Sync>;
+
 /// SSH connection wrapper
 #[derive(Debug)]
 pub struct Connection {
     /// SSH process handle
     ssh_process: Option<Child>,
+    /// Streams used instead of a process, e.g. an in-memory pipe
+    io: Option<ConnectionIo>,
     /// Connection state
     connected: bool,
 }
 
+/// Streams of a connection that isn't backed by a process
+struct ConnectionIo {
+    /// Data from the agent
+    reader: BoxedReader,
+    /// Data to the agent
+    writer: BoxedWriter,
+}
+
+impl std::fmt::Debug for ConnectionIo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionIo").finish_non_exhaustive()
+    }
+}
+
 impl Connection {
     /// Create a new connection from an SSH process
     pub fn new(ssh_process: Option<Child>) -> Self {
         let connected = ssh_process.is_some();
         Self {
             ssh_process,
+            io: None,
             connected,
         }
     }
     
+    /// Create a connection over custom streams instead of a process
+    pub fn from_io<R, W>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + Sync + 'static,
+        W: AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        Self {
+            ssh_process: None,
+            io: Some(ConnectionIo {
+                reader: Box::new(reader),
+                writer: Box::new(writer),
+            }),
+            connected: true,
+        }
+    }
+    
+    /// Take the streams of a connection created with [`Connection::from_io`]
+    pub fn take_io(&mut self) -> Option<(BoxedReader, BoxedWriter)> {
+        self.io.take().map(|io| (io.reader, io.writer))
+    }
+    
     /// Check if the connection is active
     pub fn is_connected(&self) -> bool {
         self.connected
@@ -60,6 +105,7 @@ impl Connection {
             }
         }
         
+        self.io = None;
         self.connected = false;
         Ok(())
     }
@@ -99,6 +145,17 @@ mod tests {
         assert!(!conn.is_connected());
     }
     
+    #[tokio::test]
+    async fn test_connection_from_io() {
+        let (client, _agent) = tokio::io::duplex(64);
+        let (reader, writer) = tokio::io::split(client);
+        let mut conn = Connection::from_io(reader, writer);
+        assert!(conn.is_connected());
+        assert!(conn.process_mut().is_none());
+        assert!(conn.take_io().is_some());
+        assert!(conn.take_io().is_none());
+    }
+    
     #[tokio::test]
     async fn test_connection_close() {
         let mut conn = Connection::new(None);
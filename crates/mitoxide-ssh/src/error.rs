@@ -34,6 +34,15 @@ pub enum TransportError {
     #[error("Configuration error: {0}")]
     Configuration(String),
     
+    /// The host failed too often in a row and is not being connected to for now
+    #[error("Circuit open for {host}: not connecting for another {retry_after:?}")]
+    CircuitOpen {
+        /// Host the circuit is open for
+        host: String,
+        /// Time left until a trial connection is allowed
+        retry_after: std::time::Duration,
+    },
+    
     /// Remote command failed
     #[error("Remote command failed with exit code {code}: {message}")]
     CommandFailed { 
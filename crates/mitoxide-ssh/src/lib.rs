@@ -19,8 +19,8 @@ pub mod bootstrap;
 /// SSH-specific error types
 pub mod error;
 
-pub use transport::{Transport, StdioTransport, SshConfig, ConnectionInfo, TransportType};
+pub use transport::{Transport, StdioTransport, SshConfig, ConnectionInfo, ServerInfo, TransportType};
 pub use connection::Connection;
-pub use pool::{ConnectionPool, PoolConfig, PooledConnection};
+pub use pool::{ConnectionPool, PoolConfig, PoolEvent, PooledConnection, TransportFactory};
 pub use bootstrap::{Bootstrap, PlatformInfo, BootstrapMethod};
 pub use error::TransportError;
\ No newline at end of file
@@ -2,9 +2,10 @@
 
 use crate::{Transport, Connection, TransportError, SshConfig, StdioTransport};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, watch, RwLock, Semaphore};
 use tokio::time::{sleep, timeout};
 use tracing::{debug, info, warn};
 use uuid::Uuid;
@@ -24,6 +25,12 @@ pub struct PoolConfig {
     pub max_retries: u32,
     /// Retry delay
     pub retry_delay: Duration,
+    /// Maximum number of connections being established at once, across all hosts
+    pub max_concurrent_connects: usize,
+    /// Consecutive failed connections to a host that open its circuit; 0 never opens it
+    pub circuit_failure_threshold: u32,
+    /// How long an open circuit fails connections fast before allowing a trial connection
+    pub circuit_cooldown: Duration,
 }
 
 impl Default for PoolConfig {
@@ -35,10 +42,75 @@ impl Default for PoolConfig {
             health_check_interval: Duration::from_secs(60), // 1 minute
             max_retries: 3,
             retry_delay: Duration::from_secs(1),
+            max_concurrent_connects: 16,
+            circuit_failure_threshold: 5,
+            circuit_cooldown: Duration::from_secs(30),
         }
     }
 }
 
+/// Number of pool events buffered for a subscriber that falls behind
+const POOL_EVENT_CAPACITY: usize = 256;
+
+/// A connection lifecycle transition, sent to [`ConnectionPool::subscribe`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolEvent {
+    /// A new connection was opened and handed out
+    Created {
+        /// Host the connection is to
+        host: String,
+    },
+    
+    /// An idle pooled connection was handed out again
+    Reused {
+        /// Host the connection is to
+        host: String,
+    },
+    
+    /// A connection attempt failed; more follow up to `max_retries`
+    ConnectFailed {
+        /// Host being connected to
+        host: String,
+        /// Attempt number, starting at 1
+        attempt: u32,
+        /// Why the attempt failed
+        error: String,
+    },
+    
+    /// A handed out connection came back and was kept for reuse
+    Returned {
+        /// Host the connection is to
+        host: String,
+    },
+    
+    /// A handed out connection came back and was closed: disconnected, pool full or draining
+    Closed {
+        /// Host the connection was to
+        host: String,
+    },
+    
+    /// The health check removed an idle or dead pooled connection
+    Evicted {
+        /// Host the connection was to
+        host: String,
+    },
+    
+    /// Connections to the host failed too often in a row, so new ones fail fast for a while
+    CircuitOpened {
+        /// Host the circuit opened for
+        host: String,
+    },
+    
+    /// A trial connection to a host with an open circuit succeeded
+    CircuitClosed {
+        /// Host the circuit closed for
+        host: String,
+    },
+}
+
+/// Builds the transport used to open a new connection to a host
+pub type TransportFactory = Arc<dyn Fn(SshConfig) -> Box<dyn Transport> + Send + Sync>;
+
 /// Connection pool entry
 #[derive(Debug)]
 struct PoolEntry {
@@ -52,6 +124,25 @@ struct PoolEntry {
     use_count: u64,
 }
 
+/// Circuit breaker state of a host
+#[derive(Debug, Default)]
+struct Breaker {
+    /// Failed connections since the last successful one
+    failures: u32,
+    /// Set while the circuit is open: until then connections fail fast
+    open_until: Option<Instant>,
+    /// Whether a trial connection was let through since the circuit last opened
+    trial: bool,
+}
+
+/// Whether a connection may be attempted, and how
+enum Admission {
+    /// The circuit is closed
+    Connect,
+    /// The cooldown is over; this is the single trial connection
+    Trial,
+}
+
 /// Connection pool for managing SSH connections
 pub struct ConnectionPool {
     /// Pool configuration
@@ -62,6 +153,18 @@ pub struct ConnectionPool {
     ssh_configs: Arc<RwLock<HashMap<String, SshConfig>>>,
     /// Health check task handle
     health_check_handle: Option<tokio::task::JoinHandle<()>>,
+    /// Number of connections currently checked out
+    checked_out: Arc<watch::Sender<usize>>,
+    /// Set once draining starts; no connections are handed out after that
+    draining: Arc<AtomicBool>,
+    /// Permits for connection attempts, so warming many hosts queues instead of stampeding
+    connect_permits: Arc<Semaphore>,
+    /// Builds transports for new connections
+    transport_factory: TransportFactory,
+    /// Lifecycle events for subscribers
+    events: broadcast::Sender<PoolEvent>,
+    /// Circuit breakers by host, for hosts that failed since their last successful connection
+    breakers: Arc<Mutex<HashMap<String, Breaker>>>,
 }
 
 /// A pooled connection wrapper
@@ -79,14 +182,37 @@ pub struct PooledConnection {
 impl ConnectionPool {
     /// Create a new connection pool
     pub fn new(config: PoolConfig) -> Self {
-        let pool = Self {
-            config,
+        Self {
             connections: Arc::new(RwLock::new(HashMap::new())),
             ssh_configs: Arc::new(RwLock::new(HashMap::new())),
             health_check_handle: None,
-        };
-        
-        pool
+            checked_out: Arc::new(watch::Sender::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+            connect_permits: Arc::new(Semaphore::new(config.max_concurrent_connects.max(1))),
+            transport_factory: Arc::new(|ssh_config| Box::new(StdioTransport::new(ssh_config))),
+            events: broadcast::channel(POOL_EVENT_CAPACITY).0,
+            breakers: Arc::new(Mutex::new(HashMap::new())),
+            config,
+        }
+    }
+    
+    /// Open new connections with transports built by `factory` instead of SSH subprocesses
+    pub fn with_transport_factory(mut self, factory: TransportFactory) -> Self {
+        self.transport_factory = factory;
+        self
+    }
+    
+    /// Receive an event for every connection lifecycle transition from now on
+    ///
+    /// A subscriber that falls more than a few hundred events behind misses
+    /// the oldest ones and gets `RecvError::Lagged` instead.
+    pub fn subscribe(&self) -> broadcast::Receiver<PoolEvent> {
+        self.events.subscribe()
+    }
+    
+    /// Send an event to subscribers, if there are any
+    fn emit(&self, event: PoolEvent) {
+        let _ = self.events.send(event);
     }
     
     /// Start the connection pool with health checking
@@ -96,9 +222,10 @@ impl ConnectionPool {
         // Start health check task
         let connections = Arc::clone(&self.connections);
         let config = self.config.clone();
+        let events = self.events.clone();
         
         let handle = tokio::spawn(async move {
-            Self::health_check_loop(connections, config).await;
+            Self::health_check_loop(connections, config, events).await;
         });
         
         self.health_check_handle = Some(handle);
@@ -128,6 +255,42 @@ impl ConnectionPool {
         Ok(())
     }
     
+    /// Drain the pool ahead of shutting it down or replacing it
+    ///
+    /// Stops handing out connections, waits up to `grace` for the ones checked
+    /// out to be returned, then closes every idle connection. Connections
+    /// returned after this are closed rather than pooled. Returns how many
+    /// connections were still checked out when the grace period ran out.
+    pub async fn drain(&self, grace: Duration) -> Result<usize, TransportError> {
+        info!("Draining connection pool");
+        self.draining.store(true, Ordering::SeqCst);
+        
+        let mut checked_out = self.checked_out.subscribe();
+        let returned = timeout(grace, checked_out.wait_for(|count| *count == 0)).await.is_ok();
+        let outstanding = if returned { 0 } else { *checked_out.borrow() };
+        if outstanding > 0 {
+            warn!("{} connections still checked out after the drain grace period", outstanding);
+        }
+        
+        // Close idle connections, including the ones just returned
+        let mut connections = self.connections.write().await;
+        for (host, entries) in connections.drain() {
+            debug!("Closing {} idle connections for host: {}", entries.len(), host);
+            for mut entry in entries {
+                if let Err(e) = entry.connection.close().await {
+                    warn!("Error closing connection to {}: {}", host, e);
+                }
+            }
+        }
+        
+        Ok(outstanding)
+    }
+    
+    /// Whether [`ConnectionPool::drain`] has been called
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+    
     /// Add SSH configuration for a host
     pub async fn add_host(&self, host: String, config: SshConfig) {
         let mut configs = self.ssh_configs.write().await;
@@ -135,10 +298,26 @@ impl ConnectionPool {
         debug!("Added SSH configuration for host: {}", host);
     }
     
+    /// Build the transport the pool opens connections to `host` with
+    ///
+    /// For work over the host's own transport, such as bootstrapping its agent.
+    pub async fn transport(&self, host: &str) -> Result<Box<dyn Transport>, TransportError> {
+        let configs = self.ssh_configs.read().await;
+        let ssh_config = configs.get(host).cloned()
+            .ok_or_else(|| TransportError::Configuration(
+                format!("No SSH configuration found for host: {}", host)
+            ))?;
+        Ok((self.transport_factory)(ssh_config))
+    }
+    
     /// Get a connection from the pool
     pub async fn get_connection(&self, host: &str) -> Result<PooledConnection, TransportError> {
         let host_key = host.to_string();
         
+        if self.is_draining() {
+            return Err(TransportError::Configuration("Connection pool is draining".to_string()));
+        }
+        
         // Try to get an existing connection
         if let Some(connection) = self.get_existing_connection(&host_key).await? {
             return Ok(connection);
@@ -161,13 +340,9 @@ impl ConnectionPool {
                     entry.use_count += 1;
                     
                     debug!("Reusing existing connection to {}", host_key);
+                    self.emit(PoolEvent::Reused { host: host_key.to_string() });
                     
-                    return Ok(Some(PooledConnection {
-                        id: Uuid::new_v4(),
-                        host_key: host_key.to_string(),
-                        connection: Some(entry.connection),
-                        pool: Arc::new(self.clone()),
-                    }));
+                    return Ok(Some(self.check_out(host_key, entry.connection)));
                 }
             }
         }
@@ -198,47 +373,132 @@ impl ConnectionPool {
                 ))?
         };
         
+        // Fail fast while the host's circuit is open; a trial gets a single attempt
+        let attempts = match self.admit(host_key)? {
+            Admission::Connect => self.config.max_retries,
+            Admission::Trial => 1,
+        };
+        
         debug!("Creating new connection to {}", host_key);
         
         // Create transport and connect with retries
-        let connection = self.connect_with_retries(ssh_config).await?;
+        let result = self.connect_with_retries(host_key, ssh_config, attempts).await;
+        self.record_connect(host_key, result.is_ok());
+        let connection = result?;
         
         info!("Successfully created new connection to {}", host_key);
+        self.emit(PoolEvent::Created { host: host_key.to_string() });
+        
+        Ok(self.check_out(host_key, connection))
+    }
+    
+    /// Check the circuit breaker of `host_key` before connecting to it
+    fn admit(&self, host_key: &str) -> Result<Admission, TransportError> {
+        let mut breakers = self.breakers.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(breaker) = breakers.get_mut(host_key) else {
+            return Ok(Admission::Connect);
+        };
+        let Some(open_until) = breaker.open_until else {
+            return Ok(Admission::Connect);
+        };
+        
+        let now = Instant::now();
+        if now < open_until {
+            return Err(TransportError::CircuitOpen {
+                host: host_key.to_string(),
+                retry_after: open_until - now,
+            });
+        }
+        // Others keep failing fast during the trial, and get a trial of their own
+        // after another cooldown if this one is abandoned
+        debug!("Cooldown over, allowing a trial connection to {}", host_key);
+        breaker.open_until = Some(now + self.config.circuit_cooldown);
+        breaker.trial = true;
+        Ok(Admission::Trial)
+    }
+    
+    /// Update the circuit breaker of `host_key` with the outcome of a connection
+    ///
+    /// A success closes the circuit. A failed trial opens it again for another
+    /// cooldown, as does reaching `circuit_failure_threshold` failures in a row.
+    fn record_connect(&self, host_key: &str, connected: bool) {
+        let mut breakers = self.breakers.lock().unwrap_or_else(|e| e.into_inner());
+        if connected {
+            if let Some(breaker) = breakers.remove(host_key) {
+                if breaker.open_until.is_some() {
+                    info!("Circuit for {} closed after a successful trial connection", host_key);
+                    self.emit(PoolEvent::CircuitClosed { host: host_key.to_string() });
+                }
+            }
+            return;
+        }
         
-        Ok(PooledConnection {
+        let threshold = self.config.circuit_failure_threshold;
+        let breaker = breakers.entry(host_key.to_string()).or_default();
+        breaker.failures += 1;
+        if breaker.trial || (threshold > 0 && breaker.failures >= threshold && breaker.open_until.is_none()) {
+            warn!(
+                "Circuit for {} opened after {} failed connections, failing fast for {:?}",
+                host_key, breaker.failures, self.config.circuit_cooldown
+            );
+            breaker.open_until = Some(Instant::now() + self.config.circuit_cooldown);
+            breaker.trial = false;
+            self.emit(PoolEvent::CircuitOpened { host: host_key.to_string() });
+        }
+    }
+    
+    /// Hand out a connection, counting it as checked out until it is dropped
+    fn check_out(&self, host_key: &str, connection: Connection) -> PooledConnection {
+        self.checked_out.send_modify(|count| *count += 1);
+        PooledConnection {
             id: Uuid::new_v4(),
             host_key: host_key.to_string(),
             connection: Some(connection),
             pool: Arc::new(self.clone()),
-        })
+        }
     }
     
-    /// Connect with retries
-    async fn connect_with_retries(&self, ssh_config: SshConfig) -> Result<Connection, TransportError> {
+    /// Count a checked out connection as returned
+    fn check_in(&self) {
+        self.checked_out.send_modify(|count| *count = count.saturating_sub(1));
+    }
+    
+    /// Connect, making up to `attempts` attempts
+    ///
+    /// Holds a connect permit for all attempts, waiting for one if
+    /// `max_concurrent_connects` attempts are already in progress.
+    async fn connect_with_retries(&self, host_key: &str, ssh_config: SshConfig, attempts: u32) -> Result<Connection, TransportError> {
+        let _permit = self.connect_permits.acquire().await
+            .map_err(|_| TransportError::Connection("Connection pool is closed".to_string()))?;
         let mut last_error = None;
         
-        for attempt in 1..=self.config.max_retries {
-            debug!("Connection attempt {} of {}", attempt, self.config.max_retries);
+        for attempt in 1..=attempts {
+            debug!("Connection attempt {} of {}", attempt, attempts);
             
-            let mut transport = StdioTransport::new(ssh_config.clone());
+            let mut transport = (self.transport_factory)(ssh_config.clone());
             
-            match timeout(self.config.connection_timeout, transport.connect()).await {
+            let error = match timeout(self.config.connection_timeout, transport.connect()).await {
                 Ok(Ok(connection)) => {
                     debug!("Connection successful on attempt {}", attempt);
                     return Ok(connection);
                 }
                 Ok(Err(e)) => {
                     warn!("Connection attempt {} failed: {}", attempt, e);
-                    last_error = Some(e);
+                    e
                 }
                 Err(_) => {
-                    let timeout_error = TransportError::Timeout;
                     warn!("Connection attempt {} timed out", attempt);
-                    last_error = Some(timeout_error);
+                    TransportError::Timeout
                 }
-            }
+            };
+            self.emit(PoolEvent::ConnectFailed {
+                host: host_key.to_string(),
+                attempt,
+                error: error.to_string(),
+            });
+            last_error = Some(error);
             
-            if attempt < self.config.max_retries {
+            if attempt < attempts {
                 sleep(self.config.retry_delay).await;
             }
         }
@@ -252,9 +512,17 @@ impl ConnectionPool {
     async fn return_connection(&self, host_key: String, connection: Connection) -> Result<(), TransportError> {
         if !connection.is_connected() {
             debug!("Not returning disconnected connection to pool");
+            self.emit(PoolEvent::Closed { host: host_key });
             return Ok(());
         }
         
+        if self.is_draining() {
+            debug!("Pool draining, closing returned connection for host: {}", host_key);
+            self.emit(PoolEvent::Closed { host: host_key });
+            let mut connection = connection;
+            return connection.close().await;
+        }
+        
         let entry = PoolEntry {
             connection,
             last_used: Instant::now(),
@@ -269,10 +537,12 @@ impl ConnectionPool {
         if entries.len() < self.config.max_connections_per_host {
             entries.push(entry);
             debug!("Returned connection to pool for host: {}", host_key);
+            self.emit(PoolEvent::Returned { host: host_key });
         } else {
             debug!("Pool full, closing connection for host: {}", host_key);
             // Pool is full, close the connection
             drop(entry);
+            self.emit(PoolEvent::Closed { host: host_key });
         }
         
         Ok(())
@@ -282,6 +552,7 @@ impl ConnectionPool {
     async fn health_check_loop(
         connections: Arc<RwLock<HashMap<String, Vec<PoolEntry>>>>,
         config: PoolConfig,
+        events: broadcast::Sender<PoolEvent>,
     ) {
         let mut interval = tokio::time::interval(config.health_check_interval);
         
@@ -297,8 +568,9 @@ impl ConnectionPool {
                 entries.retain_mut(|entry| {
                     // Check if connection is too old
                     if now.duration_since(entry.last_used) > config.max_idle_time {
+                        // Dropping the entry kills the underlying SSH process
                         debug!("Closing idle connection to {}", host);
-                        let _ = entry.connection.close();
+                        let _ = events.send(PoolEvent::Evicted { host: host.clone() });
                         return false;
                     }
                     
@@ -306,6 +578,7 @@ impl ConnectionPool {
                     if !entry.connection.is_connected() {
                         debug!("Removing unhealthy connection to {}", host);
                         entry.healthy = false;
+                        let _ = events.send(PoolEvent::Evicted { host: host.clone() });
                         return false;
                     }
                     
@@ -339,6 +612,7 @@ impl ConnectionPool {
             total_connections,
             healthy_connections,
             hosts,
+            checked_out: *self.checked_out.borrow(),
         }
     }
 }
@@ -350,6 +624,12 @@ impl Clone for ConnectionPool {
             connections: Arc::clone(&self.connections),
             ssh_configs: Arc::clone(&self.ssh_configs),
             health_check_handle: None, // Don't clone the handle
+            checked_out: Arc::clone(&self.checked_out),
+            draining: Arc::clone(&self.draining),
+            connect_permits: Arc::clone(&self.connect_permits),
+            transport_factory: Arc::clone(&self.transport_factory),
+            events: self.events.clone(),
+            breakers: Arc::clone(&self.breakers),
         }
     }
 }
@@ -371,6 +651,8 @@ pub struct PoolStats {
     pub healthy_connections: usize,
     /// Number of hosts
     pub hosts: usize,
+    /// Number of connections checked out
+    pub checked_out: usize,
 }
 
 impl PooledConnection {
@@ -411,7 +693,10 @@ impl Drop for PooledConnection {
                 if let Err(e) = pool.return_connection(host_key, connection).await {
                     warn!("Failed to return connection to pool: {}", e);
                 }
+                pool.check_in();
             });
+        } else {
+            self.pool.check_in();
         }
     }
 }
@@ -419,7 +704,79 @@ impl Drop for PooledConnection {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::SshConfig;
+    use crate::{ConnectionInfo, ServerInfo, SshConfig, TransportType};
+    use async_trait::async_trait;
+    use std::sync::atomic::AtomicUsize;
+    
+    /// Transport that counts how many connects are in progress at once
+    struct CountingTransport {
+        in_progress: Arc<AtomicUsize>,
+        max_seen: Arc<AtomicUsize>,
+    }
+    
+    #[async_trait]
+    impl Transport for CountingTransport {
+        async fn connect(&mut self) -> Result<Connection, TransportError> {
+            let now = self.in_progress.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_seen.fetch_max(now, Ordering::SeqCst);
+            sleep(Duration::from_millis(50)).await;
+            self.in_progress.fetch_sub(1, Ordering::SeqCst);
+            Ok(Connection::new(None))
+        }
+        
+        async fn bootstrap_agent(&mut self, _agent_binary: &[u8]) -> Result<(), TransportError> {
+            Ok(())
+        }
+        
+        fn connection_info(&self) -> ConnectionInfo {
+            ConnectionInfo {
+                host: "mock".to_string(),
+                port: 0,
+                username: String::new(),
+                transport_type: TransportType::Local,
+                server: ServerInfo::default(),
+            }
+        }
+        
+        async fn test_connection(&mut self) -> Result<(), TransportError> {
+            Ok(())
+        }
+    }
+    
+    /// Transport that fails its first `failures` connects, then connects over a pipe
+    struct FlakyTransport {
+        failures: Arc<AtomicUsize>,
+    }
+    
+    #[async_trait]
+    impl Transport for FlakyTransport {
+        async fn connect(&mut self) -> Result<Connection, TransportError> {
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(TransportError::Connection("refused".to_string()));
+            }
+            let (client, _agent) = tokio::io::duplex(64);
+            let (reader, writer) = tokio::io::split(client);
+            Ok(Connection::from_io(reader, writer))
+        }
+        
+        async fn bootstrap_agent(&mut self, _agent_binary: &[u8]) -> Result<(), TransportError> {
+            Ok(())
+        }
+        
+        fn connection_info(&self) -> ConnectionInfo {
+            ConnectionInfo {
+                host: "mock".to_string(),
+                port: 0,
+                username: String::new(),
+                transport_type: TransportType::Local,
+                server: ServerInfo::default(),
+            }
+        }
+        
+        async fn test_connection(&mut self) -> Result<(), TransportError> {
+            Ok(())
+        }
+    }
     
     #[test]
     fn test_pool_config_default() {
@@ -427,6 +784,8 @@ mod tests {
         assert_eq!(config.max_connections_per_host, 10);
         assert_eq!(config.max_idle_time, Duration::from_secs(300));
         assert_eq!(config.connection_timeout, Duration::from_secs(30));
+        assert_eq!(config.max_concurrent_connects, 16);
+        assert_eq!(config.circuit_failure_threshold, 5);
     }
     
     #[tokio::test]
@@ -472,12 +831,7 @@ mod tests {
         let config = PoolConfig::default();
         let pool = Arc::new(ConnectionPool::new(config));
         
-        let pooled_conn = PooledConnection {
-            id: Uuid::new_v4(),
-            host_key: "test.example.com".to_string(),
-            connection: Some(Connection::new(None)),
-            pool,
-        };
+        let pooled_conn = pool.check_out("test.example.com", Connection::new(None));
         
         assert_eq!(pooled_conn.host_key(), "test.example.com");
         assert!(!pooled_conn.is_connected()); // No actual SSH process
@@ -489,10 +843,183 @@ mod tests {
             total_connections: 5,
             healthy_connections: 4,
             hosts: 2,
+            checked_out: 1,
         };
         
         assert_eq!(stats.total_connections, 5);
         assert_eq!(stats.healthy_connections, 4);
         assert_eq!(stats.hosts, 2);
     }
-}
\ No newline at end of file
+    
+    #[tokio::test]
+    async fn test_drain_waits_for_checked_out_connections() {
+        let pool = ConnectionPool::new(PoolConfig::default());
+        pool.add_host("test.example.com".to_string(), SshConfig::default()).await;
+        
+        let pooled_conn = pool.check_out("test.example.com", Connection::new(None));
+        assert_eq!(pool.stats().await.checked_out, 1);
+        
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(200)).await;
+            drop(pooled_conn);
+        });
+        
+        let start = Instant::now();
+        let outstanding = pool.drain(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(outstanding, 0);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(pool.stats().await.checked_out, 0);
+        
+        // Nothing is handed out once draining has started
+        assert!(pool.is_draining());
+        assert!(matches!(
+            pool.get_connection("test.example.com").await,
+            Err(TransportError::Configuration(_))
+        ));
+    }
+    
+    #[tokio::test]
+    async fn test_drain_gives_up_after_grace() {
+        let pool = ConnectionPool::new(PoolConfig::default());
+        let _pooled_conn = pool.check_out("test.example.com", Connection::new(None));
+        
+        let start = Instant::now();
+        let outstanding = pool.drain(Duration::from_millis(100)).await.unwrap();
+        assert_eq!(outstanding, 1);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+    
+    #[tokio::test]
+    async fn test_concurrent_connects_are_limited() {
+        let in_progress = Arc::new(AtomicUsize::new(0));
+        let max_seen = Arc::new(AtomicUsize::new(0));
+        let factory: TransportFactory = {
+            let in_progress = Arc::clone(&in_progress);
+            let max_seen = Arc::clone(&max_seen);
+            Arc::new(move |_| Box::new(CountingTransport {
+                in_progress: Arc::clone(&in_progress),
+                max_seen: Arc::clone(&max_seen),
+            }))
+        };
+        let config = PoolConfig {
+            max_concurrent_connects: 3,
+            max_retries: 1,
+            ..PoolConfig::default()
+        };
+        let pool = Arc::new(ConnectionPool::new(config).with_transport_factory(factory));
+        
+        let hosts: Vec<String> = (0..12).map(|i| format!("host-{}.example.com", i)).collect();
+        for host in &hosts {
+            pool.add_host(host.clone(), SshConfig::default()).await;
+        }
+        
+        let tasks: Vec<_> = hosts.into_iter()
+            .map(|host| {
+                let pool = Arc::clone(&pool);
+                tokio::spawn(async move { pool.get_connection(&host).await.map(|_| ()) })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        
+        assert_eq!(max_seen.load(Ordering::SeqCst), 3);
+        assert_eq!(in_progress.load(Ordering::SeqCst), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_pool_events() {
+        let failures = Arc::new(AtomicUsize::new(1));
+        let factory: TransportFactory = {
+            let failures = Arc::clone(&failures);
+            Arc::new(move |_| Box::new(FlakyTransport { failures: Arc::clone(&failures) }))
+        };
+        let config = PoolConfig {
+            retry_delay: Duration::ZERO,
+            ..PoolConfig::default()
+        };
+        let pool = ConnectionPool::new(config).with_transport_factory(factory);
+        pool.add_host("test.example.com".to_string(), SshConfig::default()).await;
+        let mut events = pool.subscribe();
+        let host = "test.example.com".to_string();
+        
+        let pooled_conn = pool.get_connection("test.example.com").await.unwrap();
+        assert_eq!(events.recv().await.unwrap(), PoolEvent::ConnectFailed {
+            host: host.clone(),
+            attempt: 1,
+            error: "SSH connection error: refused".to_string(),
+        });
+        assert_eq!(events.recv().await.unwrap(), PoolEvent::Created { host: host.clone() });
+        
+        drop(pooled_conn);
+        assert_eq!(events.recv().await.unwrap(), PoolEvent::Returned { host: host.clone() });
+        
+        let _pooled_conn = pool.get_connection("test.example.com").await.unwrap();
+        assert_eq!(events.recv().await.unwrap(), PoolEvent::Reused { host });
+        assert!(events.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let failures = Arc::new(AtomicUsize::new(3));
+        let factory: TransportFactory = {
+            let failures = Arc::clone(&failures);
+            Arc::new(move |_| Box::new(FlakyTransport { failures: Arc::clone(&failures) }))
+        };
+        let config = PoolConfig {
+            max_retries: 1,
+            circuit_failure_threshold: 2,
+            circuit_cooldown: Duration::from_millis(200),
+            ..PoolConfig::default()
+        };
+        let pool = ConnectionPool::new(config).with_transport_factory(factory);
+        pool.add_host("test.example.com".to_string(), SshConfig::default()).await;
+        let mut events = pool.subscribe();
+        
+        for _ in 0..2 {
+            assert!(matches!(
+                pool.get_connection("test.example.com").await,
+                Err(TransportError::Connection(_))
+            ));
+        }
+        
+        // Open: fails fast without trying to connect
+        let start = Instant::now();
+        match pool.get_connection("test.example.com").await {
+            Err(TransportError::CircuitOpen { host, retry_after }) => {
+                assert_eq!(host, "test.example.com");
+                assert!(retry_after <= Duration::from_millis(200));
+            }
+            other => panic!("Expected CircuitOpen, got {:?}", other.map(|_| ())),
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+        
+        // A failed trial opens it again
+        sleep(Duration::from_millis(250)).await;
+        assert!(matches!(
+            pool.get_connection("test.example.com").await,
+            Err(TransportError::Connection(_))
+        ));
+        assert!(matches!(
+            pool.get_connection("test.example.com").await,
+            Err(TransportError::CircuitOpen { .. })
+        ));
+        
+        // A successful trial closes it
+        sleep(Duration::from_millis(250)).await;
+        let _first = pool.get_connection("test.example.com").await.unwrap();
+        let _second = pool.get_connection("test.example.com").await.unwrap();
+        
+        let circuit_events: Vec<PoolEvent> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|event| matches!(event, PoolEvent::CircuitOpened { .. } | PoolEvent::CircuitClosed { .. }))
+            .collect();
+        let host = "test.example.com".to_string();
+        assert_eq!(circuit_events, vec![
+            PoolEvent::CircuitOpened { host: host.clone() },
+            PoolEvent::CircuitOpened { host: host.clone() },
+            PoolEvent::CircuitClosed { host },
+        ]);
+    }
+}
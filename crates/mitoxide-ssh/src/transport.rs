@@ -3,6 +3,7 @@
 use async_trait::async_trait;
 use crate::{Connection, TransportError};
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::process::Stdio;
 use tokio::process::{Child, Command};
@@ -35,6 +36,62 @@ pub struct ConnectionInfo {
     pub username: String,
     /// Connection type
     pub transport_type: TransportType,
+    /// What the server announced and negotiated, once connected
+    pub server: ServerInfo,
+}
+
+/// SSH server identification and negotiated algorithms
+///
+/// Parsed from the debug output of `ssh -v`. Fields stay None when the output
+/// does not mention them, as with a transport other than the ssh subprocess.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// SSH protocol version from the server's banner, e.g. `2.0`
+    pub protocol_version: Option<String>,
+    /// Software version from the server's banner, e.g. `OpenSSH_9.6p1 Ubuntu-3ubuntu13`
+    pub software_version: Option<String>,
+    /// Key exchange algorithm
+    pub kex_algorithm: Option<String>,
+    /// Host key algorithm the server authenticated with
+    pub host_key_algorithm: Option<String>,
+    /// Cipher for traffic from the server
+    pub cipher: Option<String>,
+    /// MAC for traffic from the server, None for AEAD ciphers that include one
+    pub mac: Option<String>,
+}
+
+impl ServerInfo {
+    /// Parse the debug lines `ssh -v` writes to stderr
+    pub fn from_verbose_output(output: &str) -> Self {
+        let mut info = Self::default();
+        for line in output.lines() {
+            let Some(message) = line.trim().strip_prefix("debug1: ") else {
+                continue;
+            };
+            
+            if let Some(versions) = message.strip_prefix("Remote protocol version ") {
+                // "2.0, remote software version OpenSSH_9.6p1"
+                if let Some((protocol, software)) = versions.split_once(", remote software version ") {
+                    info.protocol_version = Some(protocol.to_string());
+                    info.software_version = Some(software.to_string());
+                }
+            } else if let Some(kex) = message.strip_prefix("kex: algorithm: ") {
+                info.kex_algorithm = Some(kex.trim().to_string());
+            } else if let Some(algorithm) = message.strip_prefix("kex: host key algorithm: ") {
+                info.host_key_algorithm = Some(algorithm.trim().to_string());
+            } else if let Some(negotiated) = message.strip_prefix("kex: server->client cipher: ") {
+                // "chacha20-poly1305@openssh.com MAC: <implicit> compression: none"
+                let mut parts = negotiated.split_whitespace();
+                info.cipher = parts.next().map(str::to_string);
+                info.mac = parts
+                    .skip_while(|part| *part != "MAC:")
+                    .nth(1)
+                    .filter(|mac| *mac != "<implicit>")
+                    .map(str::to_string);
+            }
+        }
+        info
+    }
 }
 
 /// Transport type enumeration
@@ -51,14 +108,29 @@ pub enum TransportType {
 /// SSH configuration
 #[derive(Debug, Clone)]
 pub struct SshConfig {
+    /// ssh executable to run (default: `ssh` from `PATH`)
+    pub ssh_program: PathBuf,
     /// Remote hostname or IP
     pub host: String,
+    /// Address to connect to instead of resolving `host`; the host key is
+    /// still checked against `host` (`HostKeyAlias`)
+    pub resolved_addr: Option<IpAddr>,
     /// Remote port (default: 22)
     pub port: u16,
     /// Username
     pub username: String,
     /// SSH key path
     pub key_path: Option<PathBuf>,
+    /// CA-signed certificate for the key (`CertificateFile`)
+    pub certificate_file: Option<PathBuf>,
+    /// Preferred host key algorithms, most preferred first (`HostKeyAlgorithms`)
+    pub host_key_algorithms: Vec<String>,
+    /// Public key algorithms offered for authentication (`PubkeyAcceptedKeyTypes`)
+    pub pubkey_accepted_key_types: Vec<String>,
+    /// Seconds of silence before ssh probes the server (`ServerAliveInterval`)
+    pub server_alive_interval: Option<u64>,
+    /// Unanswered probes before ssh gives up on the server (`ServerAliveCountMax`)
+    pub server_alive_count_max: Option<u32>,
     /// SSH options
     pub options: HashMap<String, String>,
     /// Connection timeout in seconds
@@ -70,10 +142,18 @@ pub struct SshConfig {
 impl Default for SshConfig {
     fn default() -> Self {
         Self {
+            ssh_program: PathBuf::from("ssh"),
             host: "localhost".to_string(),
+            resolved_addr: None,
             port: 22,
             username: "root".to_string(),
             key_path: None,
+            certificate_file: None,
+            host_key_algorithms: Vec::new(),
+            pubkey_accepted_key_types: Vec::new(),
+            // Probe often enough to keep NAT mappings alive on idle sessions
+            server_alive_interval: Some(30),
+            server_alive_count_max: Some(3),
             options: HashMap::new(),
             connect_timeout: 30,
             command_timeout: 300,
@@ -89,6 +169,8 @@ pub struct StdioTransport {
     ssh_process: Option<Child>,
     /// Connection state
     connected: bool,
+    /// Server details seen by the last connection test
+    server_info: ServerInfo,
 }
 
 impl StdioTransport {
@@ -98,6 +180,7 @@ impl StdioTransport {
             config,
             ssh_process: None,
             connected: false,
+            server_info: ServerInfo::default(),
         }
     }
     
@@ -116,14 +199,55 @@ impl StdioTransport {
             args.push(key_path.to_string_lossy().to_string());
         }
         
+        // Add certificate and algorithm preferences if specified
+        if let Some(certificate_file) = &self.config.certificate_file {
+            args.push("-o".to_string());
+            args.push(format!("CertificateFile={}", certificate_file.to_string_lossy()));
+        }
+        if !self.config.host_key_algorithms.is_empty() {
+            args.push("-o".to_string());
+            args.push(format!("HostKeyAlgorithms={}", self.config.host_key_algorithms.join(",")));
+        }
+        if !self.config.pubkey_accepted_key_types.is_empty() {
+            args.push("-o".to_string());
+            args.push(format!("PubkeyAcceptedKeyTypes={}", self.config.pubkey_accepted_key_types.join(",")));
+        }
+        
+        // Add keepalive settings, unless the custom options set them (ssh option
+        // names are case-insensitive)
+        let keepalive = [
+            ("ServerAliveInterval", self.config.server_alive_interval.map(|secs| secs.to_string())),
+            ("ServerAliveCountMax", self.config.server_alive_count_max.map(|count| count.to_string())),
+        ];
+        for (option, value) in keepalive {
+            if let Some(value) = value {
+                if !self.config.options.keys().any(|key| key.eq_ignore_ascii_case(option)) {
+                    args.push("-o".to_string());
+                    args.push(format!("{}={}", option, value));
+                }
+            }
+        }
+        
+        // Check the host key of a pinned address against the hostname, in the
+        // form known_hosts records it for the port
+        if self.config.resolved_addr.is_some() && !self.config.options.contains_key("HostKeyAlias") {
+            let alias = match self.config.port {
+                22 => self.config.host.clone(),
+                port => format!("[{}]:{}", self.config.host, port),
+            };
+            args.push("-o".to_string());
+            args.push(format!("HostKeyAlias={}", alias));
+        }
+        
         // Add custom options
         for (key, value) in &self.config.options {
             args.push("-o".to_string());
             args.push(format!("{}={}", key, value));
         }
         
-        // Add target
-        args.push(format!("{}@{}", self.config.username, self.config.host));
+        // Add target, connecting to the pinned address if there is one
+        let target = self.config.resolved_addr.map_or_else(|| self.config.host.clone(), |addr| addr.to_string());
+        args.push(format!("{}@{}", self.config.username, target));
         
         args
     }
@@ -135,7 +259,7 @@ impl StdioTransport {
         
         debug!("Executing SSH command: ssh {}", ssh_args.join(" "));
         
-        let output = Command::new("ssh")
+        let output = Command::new(&self.config.ssh_program)
             .args(&ssh_args)
             .output()
             .await
@@ -155,7 +279,7 @@ impl StdioTransport {
         
         debug!("Starting interactive SSH session: ssh {}", ssh_args.join(" "));
         
-        let child = Command::new("ssh")
+        let child = Command::new(&self.config.ssh_program)
             .args(&ssh_args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -196,8 +320,7 @@ impl Transport for StdioTransport {
         debug!("Remote platform: {}", platform_info.trim());
         
         // Try to use memfd_create for in-memory execution (Linux only)
-        let bootstrap_script = format!(
-            r#"
+        let bootstrap_script = r#"
             set -e
             
             # Try memfd_create approach first (Linux)
@@ -232,14 +355,13 @@ with tempfile.NamedTemporaryFile(delete=False) as f:
                 echo "No suitable location for agent bootstrap" >&2
                 exit 1
             fi
-            "#
-        );
+            "#;
         
         // Send agent binary through stdin
         let mut ssh_args = self.build_ssh_args();
         ssh_args.push("bash".to_string());
         
-        let mut child = Command::new("ssh")
+        let mut child = Command::new(&self.config.ssh_program)
             .args(&ssh_args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -280,19 +402,35 @@ with tempfile.NamedTemporaryFile(delete=False) as f:
             port: self.config.port,
             username: self.config.username.clone(),
             transport_type: TransportType::SshSubprocess,
+            server: self.server_info.clone(),
         }
     }
     
     async fn test_connection(&mut self) -> Result<(), TransportError> {
         debug!("Testing connection to {}@{}", self.config.username, self.config.host);
         
-        // Simple connectivity test
-        let result = self.execute_command("echo 'connection_test'").await?;
+        // Simple connectivity test, verbose so the server's details can be read
+        let mut ssh_args = vec!["-v".to_string()];
+        ssh_args.extend(self.build_ssh_args());
+        ssh_args.push("echo 'connection_test'".to_string());
+        
+        let output = Command::new(&self.config.ssh_program)
+            .args(&ssh_args)
+            .output()
+            .await
+            .map_err(|e| TransportError::Connection(format!("Failed to execute SSH: {}", e)))?;
         
-        if !result.trim().contains("connection_test") {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(TransportError::Connection(format!("SSH command failed: {}", stderr)));
+        }
+        if !String::from_utf8_lossy(&output.stdout).contains("connection_test") {
             return Err(TransportError::Connection("Connection test failed".to_string()));
         }
         
+        self.server_info = ServerInfo::from_verbose_output(&stderr);
+        debug!("Remote SSH server: {:?}", self.server_info);
+        
         debug!("Connection test successful");
         Ok(())
     }
@@ -310,8 +448,8 @@ impl Drop for StdioTransport {
 #[cfg(test)]
 mod tests {
     use super::*;
-
     
+
     #[test]
     fn test_ssh_config_default() {
         let config = SshConfig::default();
@@ -336,11 +474,13 @@ mod tests {
     
     #[test]
     fn test_ssh_args_building() {
-        let mut config = SshConfig::default();
-        config.host = "example.com".to_string();
-        config.port = 2222;
-        config.username = "testuser".to_string();
-        config.key_path = Some(PathBuf::from("/path/to/key"));
+        let mut config = SshConfig {
+            host: "example.com".to_string(),
+            port: 2222,
+            username: "testuser".to_string(),
+            key_path: Some(PathBuf::from("/path/to/key")),
+            ..Default::default()
+        };
         config.options.insert("ServerAliveInterval".to_string(), "60".to_string());
         
         let transport = StdioTransport::new(config);
@@ -352,9 +492,104 @@ mod tests {
         assert!(args.contains(&"/path/to/key".to_string()));
         assert!(args.contains(&"-o".to_string()));
         assert!(args.contains(&"ServerAliveInterval=60".to_string()));
+        // An explicit option replaces the configured keepalive interval
+        assert!(!args.contains(&"ServerAliveInterval=30".to_string()));
         assert!(args.contains(&"testuser@example.com".to_string()));
     }
     
+    #[test]
+    fn test_ssh_args_certificate_and_algorithms() {
+        let config = SshConfig {
+            key_path: Some(PathBuf::from("/path/to/id_ed25519")),
+            certificate_file: Some(PathBuf::from("/path/to/id_ed25519-cert.pub")),
+            host_key_algorithms: vec!["ssh-ed25519-cert-v01@openssh.com".to_string(), "ssh-ed25519".to_string()],
+            pubkey_accepted_key_types: vec!["ssh-ed25519-cert-v01@openssh.com".to_string()],
+            ..Default::default()
+        };
+        
+        let args = StdioTransport::new(config).build_ssh_args();
+        
+        assert!(args.contains(&"/path/to/id_ed25519".to_string()));
+        assert!(args.contains(&"CertificateFile=/path/to/id_ed25519-cert.pub".to_string()));
+        assert!(args.contains(&"HostKeyAlgorithms=ssh-ed25519-cert-v01@openssh.com,ssh-ed25519".to_string()));
+        assert!(args.contains(&"PubkeyAcceptedKeyTypes=ssh-ed25519-cert-v01@openssh.com".to_string()));
+        
+        // Nothing is rendered when they are not configured
+        let args = StdioTransport::new(SshConfig::default()).build_ssh_args();
+        assert!(!args.iter().any(|arg| arg.starts_with("CertificateFile=")
+            || arg.starts_with("HostKeyAlgorithms=")
+            || arg.starts_with("PubkeyAcceptedKeyTypes=")));
+    }
+    
+    #[test]
+    fn test_ssh_args_server_alive() {
+        let args = StdioTransport::new(SshConfig::default()).build_ssh_args();
+        assert!(args.contains(&"ServerAliveInterval=30".to_string()));
+        assert!(args.contains(&"ServerAliveCountMax=3".to_string()));
+        
+        let config = SshConfig {
+            server_alive_interval: Some(15),
+            server_alive_count_max: Some(8),
+            ..Default::default()
+        };
+        let args = StdioTransport::new(config).build_ssh_args();
+        assert!(args.contains(&"ServerAliveInterval=15".to_string()));
+        assert!(args.contains(&"ServerAliveCountMax=8".to_string()));
+        
+        let config = SshConfig {
+            server_alive_interval: None,
+            server_alive_count_max: None,
+            ..Default::default()
+        };
+        let args = StdioTransport::new(config).build_ssh_args();
+        assert!(!args.iter().any(|arg| arg.starts_with("ServerAliveInterval=")
+            || arg.starts_with("ServerAliveCountMax=")));
+        
+        // Custom options override the keepalive settings whatever their case
+        let mut config = SshConfig::default();
+        config.options.insert("serveraliveinterval".to_string(), "60".to_string());
+        config.options.insert("SERVERALIVECOUNTMAX".to_string(), "5".to_string());
+        let args = StdioTransport::new(config).build_ssh_args();
+        assert!(args.contains(&"serveraliveinterval=60".to_string()));
+        assert!(args.contains(&"SERVERALIVECOUNTMAX=5".to_string()));
+        assert!(!args.iter().any(|arg| arg.starts_with("ServerAliveInterval=")
+            || arg.starts_with("ServerAliveCountMax=")));
+    }
+    
+    #[test]
+    fn test_ssh_args_pinned_address() {
+        let config = SshConfig {
+            host: "db.example.com".to_string(),
+            resolved_addr: Some("192.0.2.10".parse().unwrap()),
+            username: "deploy".to_string(),
+            ..Default::default()
+        };
+        let transport = StdioTransport::new(config);
+        let args = transport.build_ssh_args();
+        
+        // Connects to the pinned IP but checks the host key under the hostname
+        assert_eq!(args.last().unwrap(), "deploy@192.0.2.10");
+        assert!(args.contains(&"HostKeyAlias=db.example.com".to_string()));
+        assert!(!args.iter().any(|arg| arg.contains("@db.example.com")));
+        assert_eq!(transport.connection_info().host, "db.example.com");
+        
+        // known_hosts records non-default ports with the host
+        let config = SshConfig {
+            host: "db.example.com".to_string(),
+            resolved_addr: Some("2001:db8::10".parse().unwrap()),
+            port: 2222,
+            ..Default::default()
+        };
+        let args = StdioTransport::new(config).build_ssh_args();
+        assert_eq!(args.last().unwrap(), "root@2001:db8::10");
+        assert!(args.contains(&"HostKeyAlias=[db.example.com]:2222".to_string()));
+        
+        // Without a pinned address the hostname is resolved as usual
+        let args = StdioTransport::new(SshConfig::default()).build_ssh_args();
+        assert_eq!(args.last().unwrap(), "root@localhost");
+        assert!(!args.iter().any(|arg| arg.starts_with("HostKeyAlias=")));
+    }
+    
     #[test]
     fn test_connection_info() {
         let config = SshConfig {
@@ -373,6 +608,59 @@ mod tests {
         assert_eq!(info.transport_type, TransportType::SshSubprocess);
     }
     
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_captures_server_info() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        // Stands in for ssh: prints what OpenSSH 9.6 logs with -v, then runs the command
+        let dir = tempfile::tempdir().unwrap();
+        let program = dir.path().join("ssh");
+        std::fs::write(&program, r#"#!/bin/sh
+cat >&2 <<'LOG'
+OpenSSH_9.6p1, OpenSSL 3.0.13 30 Jan 2024
+debug1: Connecting to example.com [192.0.2.1] port 22.
+debug1: Local version string SSH-2.0-OpenSSH_9.6p1
+debug1: Remote protocol version 2.0, remote software version OpenSSH_9.2p1 Debian-2+deb12u3
+debug1: compat_banner: match: OpenSSH_9.2p1 Debian-2+deb12u3 pat OpenSSH* compat 0x04000000
+debug1: kex: algorithm: curve25519-sha256
+debug1: kex: host key algorithm: ssh-ed25519
+debug1: kex: server->client cipher: aes128-ctr MAC: hmac-sha2-256-etm@openssh.com compression: none
+debug1: kex: client->server cipher: aes128-ctr MAC: hmac-sha2-256-etm@openssh.com compression: none
+LOG
+for last; do :; done
+case "$last" in *connection_test*) echo connection_test ;; esac
+"#).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        
+        let config = SshConfig {
+            ssh_program: program,
+            host: "example.com".to_string(),
+            ..Default::default()
+        };
+        let mut transport = StdioTransport::new(config);
+        assert_eq!(transport.connection_info().server, ServerInfo::default());
+        
+        let mut connection = transport.connect().await.unwrap();
+        let server = transport.connection_info().server;
+        assert_eq!(server.protocol_version.as_deref(), Some("2.0"));
+        assert_eq!(server.software_version.as_deref(), Some("OpenSSH_9.2p1 Debian-2+deb12u3"));
+        assert_eq!(server.kex_algorithm.as_deref(), Some("curve25519-sha256"));
+        assert_eq!(server.host_key_algorithm.as_deref(), Some("ssh-ed25519"));
+        assert_eq!(server.cipher.as_deref(), Some("aes128-ctr"));
+        assert_eq!(server.mac.as_deref(), Some("hmac-sha2-256-etm@openssh.com"));
+        connection.close().await.unwrap();
+    }
+    
+    #[test]
+    fn test_server_info_with_aead_cipher() {
+        // AEAD ciphers carry their own MAC
+        let info = ServerInfo::from_verbose_output(
+            "debug1: kex: server->client cipher: chacha20-poly1305@openssh.com MAC: <implicit> compression: none\n");
+        assert_eq!(info.cipher.as_deref(), Some("chacha20-poly1305@openssh.com"));
+        assert_eq!(info.mac, None);
+    }
+    
     // Mock transport for testing
     #[cfg(test)]
     pub struct MockTransport {
@@ -390,6 +678,7 @@ mod tests {
                     port: 22,
                     username: "mockuser".to_string(),
                     transport_type: TransportType::Local,
+                    server: ServerInfo::default(),
                 },
             }
         }
@@ -0,0 +1,63 @@
+//! # Mitoxide Test Allocator
+//!
+//! Global allocator for tests that measure the peak memory of an operation.
+//! A test binary installs it and brackets the operation with
+//! [`reset_peak`] and [`peak_since`]:
+//!
+//! ```
+//! use mitoxide_test_alloc::{peak_since, reset_peak, CountingAllocator};
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: CountingAllocator = CountingAllocator;
+//!
+//! fn main() {
+//!     let baseline = reset_peak();
+//!     let buffer = vec![0u8; 1024 * 1024];
+//!     assert!(peak_since(baseline) >= buffer.len());
+//! }
+//! ```
+//!
+//! The counters are process-wide, so a binary measuring with it should hold
+//! only the one test.
+
+#![warn(missing_docs)]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Allocator that tracks the bytes currently allocated and their peak
+pub struct CountingAllocator;
+
+/// Bytes allocated and not yet freed
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Most bytes allocated at once since the last [`reset_peak`]
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(current, Ordering::SeqCst);
+        }
+        ptr
+    }
+    
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+/// Start measuring from what is allocated now, returning that as the baseline
+pub fn reset_peak() -> usize {
+    let current = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(current, Ordering::SeqCst);
+    current
+}
+
+/// Most bytes allocated at once beyond `baseline` since [`reset_peak`]
+pub fn peak_since(baseline: usize) -> usize {
+    PEAK.load(Ordering::SeqCst).saturating_sub(baseline)
+}
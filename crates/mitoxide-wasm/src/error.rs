@@ -2,6 +2,21 @@
 
 use thiserror::Error;
 
+/// Limit a module ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceLimitKind {
+    /// The execution ran past the runtime's `max_execution_time`
+    Timeout,
+}
+
+impl std::fmt::Display for ResourceLimitKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => f.write_str("execution time"),
+        }
+    }
+}
+
 /// WASM-specific errors
 #[derive(Debug, Error)]
 pub enum WasmError {
@@ -21,10 +36,21 @@ pub enum WasmError {
     #[error("Unsupported capability: {0}")]
     UnsupportedCapability(String),
     
+    /// Capability denied by the agent's policy
+    #[error("Capability denied: {0}")]
+    CapabilityDenied(String),
+    
     /// Execution error
     #[error("Execution error: {0}")]
     Execution(String),
     
+    /// Execution was stopped for exceeding a limit
+    #[error("Resource limit exceeded: {kind}")]
+    ResourceLimit {
+        /// Which limit was exceeded
+        kind: ResourceLimitKind,
+    },
+    
     /// I/O error
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
@@ -13,9 +13,17 @@ pub mod runtime;
 /// WASM-specific error types
 pub mod error;
 
+/// Outbound HTTP for modules granted network access
+pub mod network;
+
+/// Module instances kept between calls, and snapshots of their state
+pub mod snapshot;
+
 /// Test utilities for WASM modules
 pub mod test_utils;
 
-pub use module::{WasmModule, ModuleMetadata, WasmCapability, WasmImport};
+pub use module::{WasmModule, ModuleMetadata, FunctionSignature, WasmCapability, WasmImport};
 pub use runtime::{WasmRuntime, WasmContext, WasmConfig};
-pub use error::WasmError;
\ No newline at end of file
+pub use network::{CurlHttpClient, HostPattern, HttpClient, HttpRequest, HttpResponse, PlainHttpClient};
+pub use snapshot::{GlobalValue, WasmInstance, WasmSnapshot};
+pub use error::{ResourceLimitKind, WasmError};
\ No newline at end of file
@@ -1,12 +1,14 @@
 //! WASM module loading and validation
 
 use crate::error::WasmError;
+use crate::network::HostPattern;
+use crate::runtime::WasmConfig;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
-use wasmtime::{Engine, Module};
+use wasmtime::{Engine, ExternType, Module};
 
 /// WASM module capabilities
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -23,6 +25,14 @@ pub enum WasmCapability {
     WasiNet,
     /// Custom host functions
     HostFunctions,
+    /// HTTP requests made by the runtime on the module's behalf, to allowed hosts only
+    ///
+    /// Granted per execution with [`WasmContext::grant`](crate::WasmContext::grant);
+    /// see [`network`](crate::network) for the host functions it exposes.
+    Network {
+        /// Hosts requests may go to
+        allow: Vec<HostPattern>,
+    },
 }
 
 /// WASM module metadata extracted from the module
@@ -40,6 +50,27 @@ pub struct ModuleMetadata {
     pub imports: Vec<WasmImport>,
     /// Whether the module is WASI-compatible
     pub is_wasi: bool,
+    /// Signatures of the exported functions
+    #[serde(default)]
+    pub functions: Vec<FunctionSignature>,
+    /// Names of the module's custom sections, in the order they appear
+    #[serde(default)]
+    pub custom_sections: Vec<String>,
+    /// Memories and mutable globals the module doesn't export, such as
+    /// `memory 0` or `global 0`; a snapshot can't capture them
+    #[serde(default)]
+    pub unexported_state: Vec<String>,
+}
+
+/// Signature of an exported function
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionSignature {
+    /// Export name
+    pub name: String,
+    /// Parameter types (e.g., "i32")
+    pub params: Vec<String>,
+    /// Result types
+    pub results: Vec<String>,
 }
 
 /// Information about a WASM import
@@ -78,6 +109,13 @@ impl WasmModule {
         })
     }
     
+    /// Load a WASM module from bytes, also enforcing the configured WASI import allowlist
+    pub fn from_bytes_with_config(bytes: Vec<u8>, config: &WasmConfig) -> Result<Self, WasmError> {
+        let module = Self::from_bytes(bytes)?;
+        module.check_wasi_imports(config.allowed_wasi_imports.as_ref())?;
+        Ok(module)
+    }
+    
     /// Load a WASM module from a file
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, WasmError> {
         let bytes = fs::read(path)?;
@@ -103,6 +141,27 @@ impl WasmModule {
         self.metadata.capabilities.contains(capability)
     }
     
+    /// Reject the module if it imports a WASI function outside `allowed`
+    ///
+    /// `None` allows every import.
+    pub fn check_wasi_imports(&self, allowed: Option<&HashSet<String>>) -> Result<(), WasmError> {
+        let Some(allowed) = allowed else {
+            return Ok(());
+        };
+        
+        let denied: Vec<&str> = self.metadata.imports.iter()
+            .filter(|import| import.module.starts_with("wasi_") && !allowed.contains(&import.name))
+            .map(|import| import.name.as_str())
+            .collect();
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(WasmError::CapabilityDenied(format!(
+                "WASI imports not allowed on this agent: {}", denied.join(", ")
+            )))
+        }
+    }
+    
     /// Check if the module is WASI-compatible
     pub fn is_wasi(&self) -> bool {
         self.metadata.is_wasi
@@ -122,12 +181,20 @@ impl WasmModule {
         
         let mut capabilities = HashSet::new();
         let mut exports = Vec::new();
+        let mut functions = Vec::new();
         let mut imports = Vec::new();
         let mut is_wasi = false;
         
         // Extract exports
         for export in module.exports() {
             exports.push(export.name().to_string());
+            if let ExternType::Func(func) = export.ty() {
+                functions.push(FunctionSignature {
+                    name: export.name().to_string(),
+                    params: func.params().map(|ty| ty.to_string()).collect(),
+                    results: func.results().map(|ty| ty.to_string()).collect(),
+                });
+            }
         }
         
         // Extract imports and detect capabilities
@@ -178,6 +245,9 @@ impl WasmModule {
             exports,
             imports,
             is_wasi,
+            functions,
+            custom_sections: custom_section_names(bytes),
+            unexported_state: unexported_state(bytes)?,
         })
     }
     
@@ -229,6 +299,96 @@ impl WasmModule {
     }
 }
 
+/// Names of the custom sections in a module, in the order they appear
+///
+/// wasmtime doesn't expose custom sections, so this walks the section headers
+/// of a module that has already been parsed successfully.
+fn custom_section_names(bytes: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    // Skip the magic number and version
+    let mut pos = 8;
+    while pos < bytes.len() {
+        let id = bytes[pos];
+        pos += 1;
+        let Some((size, read)) = read_leb128(&bytes[pos..]) else {
+            break;
+        };
+        pos += read;
+        let end = pos.saturating_add(size as usize).min(bytes.len());
+        
+        if id == 0 {
+            if let Some((len, read)) = read_leb128(&bytes[pos..end]) {
+                let start = pos + read;
+                if let Some(name) = bytes.get(start..start + len as usize) {
+                    names.push(String::from_utf8_lossy(name).into_owned());
+                }
+            }
+        }
+        pos = end;
+    }
+    names
+}
+
+/// Memories and mutable globals of a module that it doesn't export
+fn unexported_state(bytes: &[u8]) -> Result<Vec<String>, WasmError> {
+    let invalid = |e: wasmparser::BinaryReaderError| WasmError::ModuleLoad(e.to_string());
+    let mut memories = 0;
+    // Mutability of each global, imported ones first as they come first in the index space
+    let mut globals = Vec::new();
+    let mut exported_memories = HashSet::new();
+    let mut exported_globals = HashSet::new();
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        match payload.map_err(invalid)? {
+            wasmparser::Payload::ImportSection(reader) => {
+                for import in reader {
+                    match import.map_err(invalid)?.ty {
+                        wasmparser::TypeRef::Memory(_) => memories += 1,
+                        wasmparser::TypeRef::Global(global) => globals.push(global.mutable),
+                        _ => {}
+                    }
+                }
+            }
+            wasmparser::Payload::MemorySection(reader) => memories += reader.count(),
+            wasmparser::Payload::GlobalSection(reader) => {
+                for global in reader {
+                    globals.push(global.map_err(invalid)?.ty.mutable);
+                }
+            }
+            wasmparser::Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export.map_err(invalid)?;
+                    match export.kind {
+                        wasmparser::ExternalKind::Memory => exported_memories.insert(export.index),
+                        wasmparser::ExternalKind::Global => exported_globals.insert(export.index),
+                        _ => false,
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+    
+    let memories = (0..memories)
+        .filter(|index| !exported_memories.contains(index))
+        .map(|index| format!("memory {}", index));
+    let globals = globals.iter().enumerate()
+        .filter(|&(index, &mutable)| mutable && !exported_globals.contains(&(index as u32)))
+        .map(|(index, _)| format!("global {}", index));
+    Ok(memories.chain(globals).collect())
+}
+
+/// Decode an unsigned LEB128 value, returning it and the bytes it took
+fn read_leb128(bytes: &[u8]) -> Option<(u32, usize)> {
+    let mut value = 0u32;
+    for (i, byte) in bytes.iter().take(5).enumerate() {
+        value |= u32::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -272,6 +432,27 @@ mod tests {
         assert!(module.requires_capability(&WasmCapability::WasiEnv));
     }
     
+    #[test]
+    fn test_function_signatures_and_custom_sections() {
+        let mut bytes = simple_function_wasm().to_vec();
+        // Custom section "build" with a one byte payload
+        bytes.extend_from_slice(&[0x00, 0x07, 0x05, b'b', b'u', b'i', b'l', b'd', 0x01]);
+        let module = WasmModule::from_bytes(bytes).unwrap();
+        
+        assert_eq!(module.metadata.functions, vec![FunctionSignature {
+            name: "add".to_string(),
+            params: vec!["i32".to_string(), "i32".to_string()],
+            results: vec!["i32".to_string()],
+        }]);
+        assert_eq!(module.metadata.custom_sections.last().map(String::as_str), Some("build"));
+        
+        let wasi = WasmModule::from_bytes(wasi_hello_wasm().to_vec()).unwrap();
+        // Memory exports are not functions
+        assert_eq!(wasi.metadata.functions.len(), 1);
+        assert_eq!(wasi.metadata.functions[0].name, "_start");
+        assert!(wasi.metadata.functions[0].params.is_empty());
+    }
+    
     #[test]
     fn test_invalid_wasm_magic() {
         let result = WasmModule::from_bytes(INVALID_MAGIC_WASM.to_vec());
@@ -331,6 +512,29 @@ mod tests {
         assert!(!simple_module.requires_capability(&WasmCapability::WasiStdio));
     }
     
+    #[test]
+    fn test_wasi_import_allowlist() {
+        let allow = |names: &[&str]| WasmConfig {
+            allowed_wasi_imports: Some(names.iter().map(|name| name.to_string()).collect()),
+            ..Default::default()
+        };
+        
+        // The module requests the environment capability, but the agent forbids environ_get
+        let result = WasmModule::from_bytes_with_config(wasi_hello_wasm().to_vec(), &allow(&["fd_write"]));
+        match result {
+            Err(WasmError::CapabilityDenied(message)) => assert!(message.contains("environ_get")),
+            other => panic!("Expected CapabilityDenied, got {:?}", other.map(|_| ())),
+        }
+        
+        assert!(WasmModule::from_bytes_with_config(wasi_hello_wasm().to_vec(), &allow(&["fd_write", "environ_get"])).is_ok());
+        
+        // Non-WASI modules import nothing the allowlist covers
+        assert!(WasmModule::from_bytes_with_config(simple_function_wasm().to_vec(), &allow(&[])).is_ok());
+        
+        // Without an allowlist only per-module capability checks apply
+        assert!(WasmModule::from_bytes_with_config(wasi_hello_wasm().to_vec(), &WasmConfig::default()).is_ok());
+    }
+    
     #[test]
     fn test_compiled_module_caching() {
         let mut module = WasmModule::from_bytes(minimal_wasm().to_vec()).unwrap();
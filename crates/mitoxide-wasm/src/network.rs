@@ -0,0 +1,640 @@
+//! Outbound HTTP for modules granted network access
+//!
+//! A module can't open sockets itself, so [`WasmCapability::Network`] exposes
+//! host functions through which the runtime makes HTTP requests on the
+//! module's behalf. Each request's host is checked against the capability's
+//! allowlist first; a request to any other host traps the module.
+//!
+//! The functions are imported from the `mitoxide` module:
+//!
+//! - `http_request(ptr: i32, len: i32) -> i32` sends the JSON
+//!   [`HttpRequest`] at `ptr` and returns the response status, or -1 if the
+//!   request failed
+//! - `http_response_len() -> i32` returns the length of the last response
+//!   body, or -1 if there is none
+//! - `http_response_read(ptr: i32, len: i32) -> i32` copies up to `len` bytes
+//!   of that body to `ptr` and returns how many were copied
+//!
+//! [`WasmCapability::Network`]: crate::WasmCapability::Network
+
+use crate::error::WasmError;
+use crate::runtime::WasmContext;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use url::Url;
+use wasmtime::{Caller, Extern, Linker};
+
+/// Name of the module the network host functions are imported from
+pub const NETWORK_IMPORT_MODULE: &str = "mitoxide";
+
+/// Largest response a request may return, headers included
+pub const MAX_RESPONSE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How long a request may take to connect by default
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a response may take to arrive in full by default, once connected
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Host a module granted network access may reach
+///
+/// `example.com` matches only that host, `*.example.com` any of its
+/// subdomains but not `example.com` itself. A `:port` suffix restricts the
+/// pattern to that port; without one, any port matches.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HostPattern(String);
+
+impl HostPattern {
+    /// Create a pattern; hosts are compared case-insensitively
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Self(pattern.into().to_ascii_lowercase())
+    }
+    
+    /// The pattern as written
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    
+    /// Whether a request to `host` on `port` is allowed
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let (pattern, pattern_port) = split_port(&self.0);
+        if pattern_port.is_some_and(|pattern_port| pattern_port != port) {
+            return false;
+        }
+        let host = host.to_ascii_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => host == pattern,
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Split a `:port` suffix off a pattern, leaving bracketed IPv6 addresses whole
+fn split_port(pattern: &str) -> (&str, Option<u16>) {
+    if let Some((host, port)) = pattern.rsplit_once(':') {
+        if !host.contains(':') || host.ends_with(']') {
+            if let Ok(port) = port.parse() {
+                return (host, Some(port));
+            }
+        }
+    }
+    (pattern, None)
+}
+
+/// Request a module passes to `http_request`, as JSON
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HttpRequest {
+    /// Method, `GET` if not given
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Absolute URL
+    pub url: String,
+    /// Request headers
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Request body
+    #[serde(default)]
+    pub body: String,
+}
+
+/// Method of a request that doesn't name one
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// Response to an [`HttpRequest`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    /// Status code
+    pub status: u16,
+    /// Response headers, in the order received
+    pub headers: Vec<(String, String)>,
+    /// Response body
+    pub body: Vec<u8>,
+}
+
+/// Makes the HTTP requests of modules granted network access
+///
+/// Requests reach the client only after their host passed the allowlist.
+/// Redirects must not be followed, as they could lead outside it.
+#[async_trait]
+pub trait HttpClient: Send + Sync {
+    /// Send `request` and return the response
+    async fn send(&self, request: &HttpRequest) -> Result<HttpResponse, WasmError>;
+}
+
+/// HTTP/1.1 client over plain TCP, for `http` URLs
+///
+/// `https` URLs need a client with TLS support, such as [`CurlHttpClient`],
+/// set with [`WasmRuntime::with_http_client`](crate::WasmRuntime::with_http_client).
+#[derive(Debug, Clone, Copy)]
+pub struct PlainHttpClient {
+    /// How long connecting may take
+    connect_timeout: Duration,
+    /// How long sending the request and reading the response may take
+    response_timeout: Duration,
+}
+
+impl PlainHttpClient {
+    /// Client with the default timeouts
+    pub fn new() -> Self {
+        Self {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            response_timeout: DEFAULT_RESPONSE_TIMEOUT,
+        }
+    }
+    
+    /// Set how long connecting, and then the exchange of request and response, may take
+    pub fn with_timeouts(mut self, connect: Duration, response: Duration) -> Self {
+        self.connect_timeout = connect;
+        self.response_timeout = response;
+        self
+    }
+}
+
+impl Default for PlainHttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl HttpClient for PlainHttpClient {
+    async fn send(&self, request: &HttpRequest) -> Result<HttpResponse, WasmError> {
+        let url = parse_url(&request.url)?;
+        if url.scheme() != "http" {
+            return Err(WasmError::UnsupportedCapability(format!("{} requests need an HTTP client with TLS support", url.scheme())));
+        }
+        check_request_head(request)?;
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port_or_known_default().unwrap_or(80);
+        
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            request.method, &url[url::Position::BeforePath..url::Position::AfterQuery], host, request.body.len(),
+        );
+        for (name, value) in &request.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        
+        let connect = TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), port));
+        let mut stream = tokio::time::timeout(self.connect_timeout, connect).await
+            .map_err(|_| WasmError::Execution(format!("Connecting to {} timed out after {:?}", host, self.connect_timeout)))??;
+        let exchange = async {
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(request.body.as_bytes()).await?;
+            let mut raw = Vec::new();
+            stream.take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut raw).await?;
+            Ok::<_, std::io::Error>(raw)
+        };
+        let raw = tokio::time::timeout(self.response_timeout, exchange).await
+            .map_err(|_| WasmError::Execution(format!("HTTP response from {} timed out after {:?}", host, self.response_timeout)))??;
+        if raw.len() as u64 > MAX_RESPONSE_SIZE {
+            return Err(WasmError::Execution(format!("HTTP response larger than {} bytes", MAX_RESPONSE_SIZE)));
+        }
+        parse_response(&raw)
+    }
+}
+
+/// HTTP and HTTPS client running the `curl` program for each request
+///
+/// TLS is left to curl and the certificates it trusts. Proxies and the
+/// user's `.curlrc` are ignored, so a request goes to the host the allowlist
+/// let through. Where the program can't be found, `http` URLs are sent
+/// with [`PlainHttpClient`] instead.
+#[derive(Debug, Clone)]
+pub struct CurlHttpClient {
+    /// The `curl` program to run
+    program: PathBuf,
+}
+
+impl CurlHttpClient {
+    /// Client running the `curl` found on `PATH`
+    pub fn new() -> Self {
+        Self::with_program("curl")
+    }
+    
+    /// Client running the `curl` program at `program`
+    pub fn with_program(program: impl Into<PathBuf>) -> Self {
+        Self { program: program.into() }
+    }
+}
+
+impl Default for CurlHttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl HttpClient for CurlHttpClient {
+    async fn send(&self, request: &HttpRequest) -> Result<HttpResponse, WasmError> {
+        let url = parse_url(&request.url)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(WasmError::UnsupportedCapability(format!("{} requests are not supported", url.scheme())));
+        }
+        
+        check_request_head(request)?;
+        
+        let mut command = Command::new(&self.program);
+        // Raw HTTP/1.1 with the headers, so it is parsed like PlainHttpClient's
+        command.args(["-q", "--silent", "--show-error", "--globoff", "--include", "--raw", "--http1.1"])
+            .args(["--proto", "=http,https", "--noproxy", "*", "--max-filesize", &MAX_RESPONSE_SIZE.to_string()])
+            .args(["--connect-timeout", &DEFAULT_CONNECT_TIMEOUT.as_secs().to_string()])
+            .args(["--max-time", &(DEFAULT_CONNECT_TIMEOUT + DEFAULT_RESPONSE_TIMEOUT).as_secs().to_string()])
+            .args(["--header", "Expect:"]);
+        command.args(["--request", &request.method]);
+        for (name, value) in &request.headers {
+            // curl drops a header given as `Name:`, and sends it empty given as `Name;`
+            let header = if value.is_empty() { format!("{};", name) } else { format!("{}: {}", name, value) };
+            command.args(["--header", &header]);
+        }
+        if !request.body.is_empty() {
+            command.args(["--data-binary", "@-"]);
+        }
+        command.arg("--").arg(url.as_str())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && url.scheme() == "http" => {
+                return PlainHttpClient::new().send(request).await;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = child.stdout.take().expect("stdout is piped").take(MAX_RESPONSE_SIZE + 1);
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let body = request.body.clone();
+        let write_body = async move {
+            // curl may fail before reading the body; its exit status says why
+            let _ = stdin.write_all(body.as_bytes()).await;
+        };
+        let mut raw = Vec::new();
+        let (_, read) = tokio::join!(write_body, stdout.read_to_end(&mut raw));
+        read?;
+        if raw.len() as u64 > MAX_RESPONSE_SIZE {
+            return Err(WasmError::Execution(format!("HTTP response larger than {} bytes", MAX_RESPONSE_SIZE)));
+        }
+        
+        // Only a line or two, which fits in the pipe until now
+        let mut errors = Vec::new();
+        stderr.read_to_end(&mut errors).await?;
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(WasmError::Execution(format!(
+                "HTTP request failed ({}): {}", status, String::from_utf8_lossy(&errors).trim(),
+            )));
+        }
+        parse_response(&raw)
+    }
+}
+
+/// Check that a request's method and headers can't break out of the request head
+///
+/// The method must be an RFC 7230 token, and header values must not contain
+/// line breaks; otherwise a module could smuggle a second request to the host.
+fn check_request_head(request: &HttpRequest) -> Result<(), WasmError> {
+    if !is_token(&request.method) {
+        return Err(WasmError::Execution(format!("Invalid HTTP method: {:?}", request.method)));
+    }
+    for (name, value) in &request.headers {
+        if !is_token(name) || value.contains(['\r', '\n']) {
+            return Err(WasmError::Execution(format!("Invalid HTTP header: {:?}", name)));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `value` is a non-empty RFC 7230 token
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Parse a request URL, which must name a host
+fn parse_url(url: &str) -> Result<Url, WasmError> {
+    let url = Url::parse(url).map_err(|e| WasmError::Execution(format!("Invalid URL {:?}: {}", url, e)))?;
+    if url.host_str().is_none() {
+        return Err(WasmError::Execution(format!("URL {:?} has no host", url.as_str())));
+    }
+    Ok(url)
+}
+
+/// Parse a complete HTTP/1.1 response read up to the end of the connection
+fn parse_response(raw: &[u8]) -> Result<HttpResponse, WasmError> {
+    let invalid = |what: &str| WasmError::Execution(format!("Invalid HTTP response: {}", what));
+    let head_end = raw.windows(4).position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid("no end of headers"))?;
+    let head = std::str::from_utf8(&raw[..head_end]).map_err(|_| invalid("headers are not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    
+    let status = lines.next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("bad status line"))?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let header = |wanted: &str| headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+        .map(|(_, value)| value.as_str());
+    
+    let mut body = &raw[head_end + 4..];
+    let body = if header("transfer-encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")) {
+        decode_chunked(body).ok_or_else(|| invalid("bad chunked body"))?
+    } else {
+        if let Some(length) = header("content-length").and_then(|length| length.parse::<usize>().ok()) {
+            body = body.get(..length).ok_or_else(|| invalid("body shorter than its Content-Length"))?;
+        }
+        body.to_vec()
+    };
+    Ok(HttpResponse { status, headers, body })
+}
+
+/// Join the chunks of a `Transfer-Encoding: chunked` body
+fn decode_chunked(mut raw: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = raw.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&raw[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        raw = &raw[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(raw.get(..size)?);
+        raw = raw.get(size + 2..)?;
+    }
+}
+
+/// Network access of one execution, kept in its store
+pub(crate) struct NetworkAccess {
+    /// Hosts requests may go to
+    allow: Vec<HostPattern>,
+    /// Makes the requests
+    client: Arc<dyn HttpClient>,
+    /// Response to the last request, read by the module
+    response: Option<HttpResponse>,
+}
+
+impl NetworkAccess {
+    /// Access to the hosts matching `allow`, through `client`
+    pub(crate) fn new(allow: Vec<HostPattern>, client: Arc<dyn HttpClient>) -> Self {
+        Self { allow, client, response: None }
+    }
+    
+    /// Check the request's host against the allowlist
+    fn check(&self, request: &HttpRequest) -> Result<(), NetworkDenied> {
+        let url = parse_url(&request.url).map_err(|e| NetworkDenied(e.to_string()))?;
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port_or_known_default().unwrap_or(0);
+        if self.allow.iter().any(|pattern| pattern.matches(host, port)) {
+            Ok(())
+        } else {
+            Err(NetworkDenied(format!("network access to {}:{} is not allowed", host, port)))
+        }
+    }
+}
+
+/// Trap raised when a module asks for a host outside its allowlist
+#[derive(Debug)]
+pub(crate) struct NetworkDenied(pub(crate) String);
+
+impl fmt::Display for NetworkDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NetworkDenied {}
+
+/// Define the network host functions in `linker`
+pub(crate) fn add_to_linker(linker: &mut Linker<WasmContext>) -> wasmtime::Result<()> {
+    linker.func_wrap2_async(NETWORK_IMPORT_MODULE, "http_request", |mut caller: Caller<'_, WasmContext>, ptr: i32, len: i32| {
+        Box::new(async move {
+            let request = read_memory(&mut caller, ptr, len)?;
+            let request: HttpRequest = serde_json::from_slice(&request)
+                .map_err(|e| wasmtime::Error::msg(format!("Invalid HTTP request: {}", e)))?;
+            let access = caller.data_mut().network.as_mut()
+                .ok_or_else(|| wasmtime::Error::new(NetworkDenied("network access was not granted".to_string())))?;
+            access.check(&request).map_err(wasmtime::Error::new)?;
+            let client = Arc::clone(&access.client);
+            
+            let response = client.send(&request).await.ok();
+            let status = response.as_ref().map_or(-1, |response| response.status as i32);
+            if let Some(access) = caller.data_mut().network.as_mut() {
+                access.response = response;
+            }
+            Ok(status)
+        })
+    })?;
+    linker.func_wrap(NETWORK_IMPORT_MODULE, "http_response_len", |caller: Caller<'_, WasmContext>| {
+        let response = caller.data().network.as_ref().and_then(|access| access.response.as_ref());
+        response.map_or(-1, |response| response.body.len() as i32)
+    })?;
+    linker.func_wrap(NETWORK_IMPORT_MODULE, "http_response_read", |mut caller: Caller<'_, WasmContext>, ptr: i32, len: i32| {
+        let body = caller.data().network.as_ref()
+            .and_then(|access| access.response.as_ref())
+            .map(|response| response.body.clone())
+            .unwrap_or_default();
+        let copied = body.len().min(len.max(0) as usize);
+        write_memory(&mut caller, ptr, &body[..copied])?;
+        Ok(copied as i32)
+    })?;
+    Ok(())
+}
+
+/// The module's exported memory
+fn memory<T>(caller: &mut Caller<'_, T>) -> wasmtime::Result<wasmtime::Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Ok(memory),
+        _ => Err(wasmtime::Error::msg("Module exports no memory")),
+    }
+}
+
+/// Copy `len` bytes at `ptr` out of the module's memory
+fn read_memory<T>(caller: &mut Caller<'_, T>, ptr: i32, len: i32) -> wasmtime::Result<Vec<u8>> {
+    let memory = memory(caller)?;
+    let mut buffer = vec![0; len.max(0) as usize];
+    memory.read(&*caller, ptr as u32 as usize, &mut buffer)?;
+    Ok(buffer)
+}
+
+/// Copy `data` into the module's memory at `ptr`
+fn write_memory<T>(caller: &mut Caller<'_, T>, ptr: i32, data: &[u8]) -> wasmtime::Result<()> {
+    let memory = memory(caller)?;
+    memory.write(&mut *caller, ptr as u32 as usize, data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_host_pattern_matching() {
+        let exact = HostPattern::new("API.example.com");
+        assert!(exact.matches("api.example.com", 443));
+        assert!(exact.matches("Api.Example.Com", 80));
+        assert!(!exact.matches("example.com", 443));
+        assert!(!exact.matches("evil-api.example.com", 443));
+        
+        let wildcard = HostPattern::new("*.example.com");
+        assert!(wildcard.matches("api.example.com", 443));
+        assert!(wildcard.matches("a.b.example.com", 443));
+        assert!(!wildcard.matches("example.com", 443));
+        assert!(!wildcard.matches("badexample.com", 443));
+        
+        let with_port = HostPattern::new("127.0.0.1:8080");
+        assert!(with_port.matches("127.0.0.1", 8080));
+        assert!(!with_port.matches("127.0.0.1", 8081));
+        assert!(HostPattern::new("[::1]:8080").matches("[::1]", 8080));
+        assert!(HostPattern::new("[::1]").matches("[::1]", 1));
+    }
+    
+    #[test]
+    fn test_parse_response_bodies() {
+        let response = parse_response(b"HTTP/1.1 201 Created\r\nContent-Length: 5\r\nX-Id: 7\r\n\r\nhello, and more").unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, b"hello");
+        assert!(response.headers.contains(&("X-Id".to_string(), "7".to_string())));
+        
+        let chunked = parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5;x=y\r\npedia\r\n0\r\n\r\n").unwrap();
+        assert_eq!(chunked.body, b"Wikipedia");
+        
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort").is_err());
+        assert!(parse_response(b"garbage").is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_curl_client_sends_requests() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"hello") {
+                    let mut buffer = [0; 1024];
+                    let n = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                }
+                stream.write_all(b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n").await.unwrap();
+                seen.push(String::from_utf8(request).unwrap());
+            }
+            seen
+        });
+        let request = HttpRequest {
+            method: "PUT".to_string(),
+            url: format!("http://127.0.0.1:{}/items?id=1", port),
+            headers: BTreeMap::from([("X-Token".to_string(), "secret".to_string())]),
+            body: "hello".to_string(),
+        };
+        
+        // Without the program, http requests are sent by PlainHttpClient
+        for client in [CurlHttpClient::new(), CurlHttpClient::with_program("/nonexistent/curl")] {
+            let response = client.send(&request).await.unwrap();
+            assert_eq!(response.status, 201);
+            assert_eq!(response.body, b"ok");
+        }
+        for seen in server.await.unwrap() {
+            assert!(seen.starts_with("PUT /items?id=1 HTTP/1.1\r\n"), "{}", seen);
+            assert!(seen.contains("X-Token: secret\r\n"), "{}", seen);
+        }
+        
+        let https = HttpRequest { url: format!("https://127.0.0.1:{}/", port), ..request };
+        assert!(CurlHttpClient::with_program("/nonexistent/curl").send(&https).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_clients_reject_injected_request_heads() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let request = |method: &str, name: &str| HttpRequest {
+            method: method.to_string(),
+            url: format!("http://127.0.0.1:{}/", port),
+            headers: BTreeMap::from([(name.to_string(), "1".to_string())]),
+            body: String::new(),
+        };
+        let smuggled = "GET / HTTP/1.1\r\nHost: internal\r\n\r\nDELETE";
+        
+        let clients: [Arc<dyn HttpClient>; 2] = [Arc::new(PlainHttpClient::new()), Arc::new(CurlHttpClient::new())];
+        for client in clients {
+            for request in [request(smuggled, "X-Id"), request("GET PUT", "X-Id"), request("", "X-Id"), request("GET", "X-Id: 2\r\nX")] {
+                match client.send(&request).await {
+                    Err(WasmError::Execution(message)) => assert!(message.starts_with("Invalid HTTP"), "{}", message),
+                    other => panic!("Expected the request to be rejected, got {:?}", other),
+                }
+            }
+        }
+        // Nothing reached the host
+        assert!(tokio::time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+        assert!(is_token("M-SEARCH") && is_token("PATCH"));
+    }
+    
+    #[tokio::test]
+    async fn test_plain_client_times_out_on_stalled_peer() {
+        // Accepts the connection but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            drop(stream);
+        });
+        let request = HttpRequest {
+            method: default_method(),
+            url: format!("http://127.0.0.1:{}/", port),
+            headers: BTreeMap::new(),
+            body: String::new(),
+        };
+        
+        let client = PlainHttpClient::new().with_timeouts(Duration::from_secs(5), Duration::from_millis(200));
+        let start = std::time::Instant::now();
+        match client.send(&request).await {
+            Err(WasmError::Execution(message)) => assert!(message.contains("timed out"), "{}", message),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "took {:?}", start.elapsed());
+        server.abort();
+    }
+    
+    #[test]
+    fn test_allowlist_checks_url_host() {
+        let access = NetworkAccess::new(vec![HostPattern::new("*.example.com")], Arc::new(PlainHttpClient::new()));
+        let request = |url: &str| HttpRequest {
+            method: default_method(),
+            url: url.to_string(),
+            headers: BTreeMap::new(),
+            body: String::new(),
+        };
+        
+        assert!(access.check(&request("https://api.example.com/v1?q=1")).is_ok());
+        // Userinfo doesn't change the host the request goes to
+        assert!(access.check(&request("http://api.example.com@evil.test/")).is_err());
+        assert!(access.check(&request("http://evil.test/?api.example.com")).is_err());
+        assert!(access.check(&request("not a url")).is_err());
+    }
+}
@@ -1,11 +1,17 @@
 //! WASM execution runtime
 
-use crate::error::WasmError;
-use crate::module::WasmModule;
+use crate::error::{ResourceLimitKind, WasmError};
+use crate::module::{WasmCapability, WasmModule};
+use crate::network::{self, CurlHttpClient, HttpClient, NetworkAccess, NetworkDenied, NETWORK_IMPORT_MODULE};
+use crate::snapshot::{WasmInstance, WasmSnapshot};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::Duration;
-use wasmtime::{Engine, Linker, Store, WasmParams, WasmResults};
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use wasmtime::{Engine, Linker, Store, Trap, UpdateDeadline, WasmParams, WasmResults};
+use wasi_common::pipe::{ReadPipe, WritePipe};
 use wasmtime_wasi::{WasiCtx, WasiCtxBuilder};
 
 /// WASM execution context with WASI support
@@ -16,6 +22,10 @@ pub struct WasmContext {
     env: HashMap<String, String>,
     /// Working directory
     cwd: Option<String>,
+    /// Capabilities granted to the execution
+    granted: Vec<WasmCapability>,
+    /// Network access, set up when a granted module imports the network host functions
+    pub(crate) network: Option<NetworkAccess>,
 }
 
 impl std::fmt::Debug for WasmContext {
@@ -24,6 +34,7 @@ impl std::fmt::Debug for WasmContext {
             .field("wasi", &self.wasi.is_some())
             .field("env", &self.env)
             .field("cwd", &self.cwd)
+            .field("granted", &self.granted)
             .finish()
     }
 }
@@ -35,6 +46,8 @@ impl WasmContext {
             wasi: None,
             env: HashMap::new(),
             cwd: None,
+            granted: Vec::new(),
+            network: None,
         }
     }
     
@@ -49,6 +62,15 @@ impl WasmContext {
         self.cwd = Some(cwd.into());
         self
     }
+    
+    /// Grant the module a capability for this execution
+    ///
+    /// [`WasmCapability::Network`] only takes effect if the runtime's
+    /// [`WasmConfig::allow_network`] is set.
+    pub fn grant(mut self, capability: WasmCapability) -> Self {
+        self.granted.push(capability);
+        self
+    }
 }
 
 impl Default for WasmContext {
@@ -68,10 +90,13 @@ pub struct WasmConfig {
     pub max_fuel: Option<u64>,
     /// Enable WASI support
     pub enable_wasi: bool,
-    /// Allow network access (if WASI networking is supported)
+    /// Allow network access for modules granted [`WasmCapability::Network`]
     pub allow_network: bool,
     /// Allow filesystem access
     pub allow_filesystem: bool,
+    /// WASI functions any module may import, regardless of its own capabilities
+    /// (`None` leaves the decision to per-module capability checks)
+    pub allowed_wasi_imports: Option<HashSet<String>>,
 }
 
 impl Default for WasmConfig {
@@ -83,16 +108,53 @@ impl Default for WasmConfig {
             enable_wasi: true,
             allow_network: false,
             allow_filesystem: false,
+            allowed_wasi_imports: None,
         }
     }
 }
 
+/// How often the engine's epoch advances, and so how often running modules check their deadline
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Thread advancing an engine's epoch every [`EPOCH_TICK`], stopped on drop
+struct EpochTicker {
+    /// Set to stop the thread
+    stop: Arc<AtomicBool>,
+}
+
+impl EpochTicker {
+    /// Start advancing `engine`'s epoch
+    fn start(engine: Engine) -> Result<Self, WasmError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        std::thread::Builder::new()
+            .name("wasm-epoch".to_string())
+            .spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    std::thread::sleep(EPOCH_TICK);
+                    engine.increment_epoch();
+                }
+            })?;
+        Ok(Self { stop })
+    }
+}
+
+impl Drop for EpochTicker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
 /// WASM execution runtime with wasmtime integration
 pub struct WasmRuntime {
     /// Wasmtime engine
     engine: Engine,
     /// Runtime configuration
     config: WasmConfig,
+    /// Advances the engine's epoch so running modules notice their deadline
+    _epoch_ticker: EpochTicker,
+    /// Makes the HTTP requests of modules granted network access
+    http_client: Arc<dyn HttpClient>,
 }
 
 impl WasmRuntime {
@@ -116,9 +178,52 @@ impl WasmRuntime {
         // Enable async support for timeouts
         wasmtime_config.async_support(true);
         
+        // A module in a tight loop never yields to the timer, so compiled code
+        // checks the epoch and stops itself once its deadline has passed
+        wasmtime_config.epoch_interruption(true);
+        
         let engine = Engine::new(&wasmtime_config)?;
+        let epoch_ticker = EpochTicker::start(engine.clone())?;
+        
+        Ok(WasmRuntime { engine, config, _epoch_ticker: epoch_ticker, http_client: Arc::new(CurlHttpClient::new()) })
+    }
+    
+    /// Make the HTTP requests of modules granted network access with `client`
+    ///
+    /// The default [`CurlHttpClient`] runs the `curl` on `PATH`.
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.http_client = client;
+        self
+    }
+    
+    /// Create a store for one execution, with its fuel and a deadline of `max_execution_time` from now
+    fn new_store(&self, context: WasmContext) -> Result<Store<WasmContext>, WasmError> {
+        let mut store = Store::new(&self.engine, context);
+        reset_limits(&self.config, &mut store)?;
+        Ok(store)
+    }
+    
+    /// Define the network host functions for a module that imports them
+    ///
+    /// The module must have been granted [`WasmCapability::Network`], and the
+    /// configuration must allow network access.
+    fn link_network(&self, module: &WasmModule, linker: &mut Linker<WasmContext>, store: &mut Store<WasmContext>) -> Result<(), WasmError> {
+        if !module.metadata.imports.iter().any(|import| import.module == NETWORK_IMPORT_MODULE) {
+            return Ok(());
+        }
+        let allow = store.data().granted.iter()
+            .find_map(|capability| match capability {
+                WasmCapability::Network { allow } => Some(allow.clone()),
+                _ => None,
+            })
+            .ok_or_else(|| WasmError::CapabilityDenied("module imports network functions, but network access was not granted".to_string()))?;
+        if !self.config.allow_network {
+            return Err(WasmError::CapabilityDenied("network access is disabled on this agent".to_string()));
+        }
         
-        Ok(WasmRuntime { engine, config })
+        store.data_mut().network = Some(NetworkAccess::new(allow, Arc::clone(&self.http_client)));
+        network::add_to_linker(linker)?;
+        Ok(())
     }
     
     /// Execute a WASM module with JSON input/output
@@ -147,25 +252,25 @@ impl WasmRuntime {
     }
     
     /// Execute a WASM module with string input/output via stdio
+    ///
+    /// A WASI module reads `input` from stdin and its stdout is the output,
+    /// empty if it writes nothing.
     pub async fn execute_with_stdio(
         &self,
         module: &mut WasmModule,
         input: &str,
         context: WasmContext,
     ) -> Result<String, WasmError> {
+        module.check_wasi_imports(self.config.allowed_wasi_imports.as_ref())?;
         let is_wasi = module.is_wasi();
-        let compiled_module = module.get_compiled(&self.engine)?;
         
         // Create store with context
-        let mut store = Store::new(&self.engine, context);
-        
-        // Set fuel limit if configured
-        if let Some(fuel) = self.config.max_fuel {
-            store.add_fuel(fuel)?;
-        }
+        let mut store = self.new_store(context)?;
         
         // Create linker and add WASI if needed
         let mut linker = Linker::new(&self.engine);
+        self.link_network(module, &mut linker, &mut store)?;
+        let compiled_module = module.get_compiled(&self.engine)?;
         
         if self.config.enable_wasi && is_wasi {
             // Configure WASI context with basic setup
@@ -176,6 +281,12 @@ impl WasmRuntime {
                 let _ = wasi_builder.env(key, value);
             }
             
+            // Input goes to stdin; stdout is kept to return
+            let stdout = Arc::new(RwLock::new(Cursor::new(Vec::new())));
+            wasi_builder
+                .stdin(Box::new(ReadPipe::from(input)))
+                .stdout(Box::new(WritePipe::from_shared(stdout.clone())));
+            
             // Build WASI context
             let wasi_ctx = wasi_builder.build();
             store.data_mut().wasi = Some(wasi_ctx);
@@ -201,12 +312,12 @@ impl WasmRuntime {
             
             match execution_result {
                 Ok(Ok(())) => {
-                    // Return the input as output for now (echo behavior)
-                    // In a real implementation, we'd capture actual stdout
-                    Ok(input.to_string())
+                    let output = std::mem::take(stdout.write().unwrap_or_else(|e| e.into_inner()).get_mut());
+                    String::from_utf8(output)
+                        .map_err(|_| WasmError::Execution("Module wrote output that is not UTF-8".to_string()))
                 }
-                Ok(Err(e)) => Err(WasmError::Execution(format!("WASM execution failed: {}", e))),
-                Err(_) => Err(WasmError::Execution("WASM execution timed out".to_string())),
+                Ok(Err(e)) => Err(execution_error(e, "WASM execution failed")),
+                Err(_) => Err(WasmError::ResourceLimit { kind: ResourceLimitKind::Timeout }),
             }
         } else {
             // Non-WASI execution - look for a main function or exported function
@@ -222,8 +333,8 @@ impl WasmRuntime {
                 
                 match execution_result {
                     Ok(Ok(())) => Ok(String::new()), // No output for non-WASI
-                    Ok(Err(e)) => Err(WasmError::Execution(format!("WASM execution failed: {}", e))),
-                    Err(_) => Err(WasmError::Execution("WASM execution timed out".to_string())),
+                    Ok(Err(e)) => Err(execution_error(e, "WASM execution failed")),
+                    Err(_) => Err(WasmError::ResourceLimit { kind: ResourceLimitKind::Timeout }),
                 }
             } else {
                 Err(WasmError::Execution(
@@ -245,15 +356,12 @@ impl WasmRuntime {
         Params: WasmParams,
         Results: WasmResults,
     {
-        let compiled_module = module.get_compiled(&self.engine)?;
-        let mut store = Store::new(&self.engine, context);
-        
-        // Set fuel limit if configured
-        if let Some(fuel) = self.config.max_fuel {
-            store.add_fuel(fuel)?;
-        }
+        module.check_wasi_imports(self.config.allowed_wasi_imports.as_ref())?;
+        let mut store = self.new_store(context)?;
         
-        let linker = Linker::new(&self.engine);
+        let mut linker = Linker::new(&self.engine);
+        self.link_network(module, &mut linker, &mut store)?;
+        let compiled_module = module.get_compiled(&self.engine)?;
         let instance = linker.instantiate_async(&mut store, compiled_module).await?;
         
         let func = instance.get_typed_func::<Params, Results>(&mut store, function_name)?;
@@ -266,9 +374,52 @@ impl WasmRuntime {
         
         match execution_result {
             Ok(Ok(result)) => Ok(result),
-            Ok(Err(e)) => Err(WasmError::Execution(format!("Function call failed: {}", e))),
-            Err(_) => Err(WasmError::Execution("Function call timed out".to_string())),
+            Ok(Err(e)) => Err(execution_error(e, "Function call failed")),
+            Err(_) => Err(WasmError::ResourceLimit { kind: ResourceLimitKind::Timeout }),
+        }
+    }
+    
+    /// Instantiate a module to make several calls on, keeping its state between them
+    ///
+    /// WASI modules are refused, as their host-side state can't be snapshotted.
+    pub async fn instantiate(&self, module: &mut WasmModule, context: WasmContext) -> Result<WasmInstance, WasmError> {
+        if module.is_wasi() {
+            return Err(WasmError::UnsupportedCapability("WASI modules can't be kept as instances".to_string()));
         }
+        let mut store = self.new_store(context)?;
+        
+        let mut linker = Linker::new(&self.engine);
+        self.link_network(module, &mut linker, &mut store)?;
+        let compiled_module = module.get_compiled(&self.engine)?;
+        let instance = linker.instantiate_async(&mut store, compiled_module).await?;
+        
+        Ok(WasmInstance::new(store, instance, &module.metadata, self.config.clone()))
+    }
+    
+    /// Capture an instance's exported memories and mutable globals
+    ///
+    /// Taken between calls, so a long computation made of repeated calls can
+    /// be paused after any of them and carried on later, or on another agent,
+    /// with [`WasmRuntime::resume`]. A module with a memory or mutable global
+    /// it doesn't export, such as the stack pointer of most compiled modules,
+    /// would resume with part of its state lost, so it can't be snapshotted.
+    pub fn snapshot(&self, instance: &mut WasmInstance) -> Result<WasmSnapshot, WasmError> {
+        instance.capture()
+    }
+    
+    /// Instantiate `module` again with the state captured in `snapshot`
+    ///
+    /// The module must be the one the snapshot was taken of. Its start
+    /// function runs as usual before the state is restored.
+    pub async fn resume(&self, module: &mut WasmModule, snapshot: &WasmSnapshot, context: WasmContext) -> Result<WasmInstance, WasmError> {
+        if snapshot.module_hash != module.hash() {
+            return Err(WasmError::Execution(format!(
+                "Snapshot is of module {}, not {}", snapshot.module_hash, module.hash()
+            )));
+        }
+        let mut instance = self.instantiate(module, context).await?;
+        instance.restore(snapshot)?;
+        Ok(instance)
     }
     
     /// Get the runtime configuration
@@ -277,6 +428,46 @@ impl WasmRuntime {
     }
 }
 
+/// Top `store` up to the configured fuel and set its deadline `max_execution_time` from now
+///
+/// On every epoch tick the module yields to the executor; once the
+/// deadline has passed it traps instead, which unwinds it and frees its thread.
+pub(crate) fn reset_limits(config: &WasmConfig, store: &mut Store<WasmContext>) -> Result<(), WasmError> {
+    // Set fuel limit if configured
+    if let Some(fuel) = config.max_fuel {
+        let remaining = store.fuel_remaining().unwrap_or(0);
+        if remaining < fuel {
+            store.add_fuel(fuel - remaining)?;
+        }
+    }
+    
+    let deadline = Instant::now() + config.max_execution_time;
+    store.set_epoch_deadline(1);
+    store.epoch_deadline_callback(move |_| {
+        if Instant::now() >= deadline {
+            Err(Trap::Interrupt.into())
+        } else {
+            Ok(UpdateDeadline::Yield(1))
+        }
+    });
+    Ok(())
+}
+
+/// Turn an error from running a module into a [`WasmError`]
+///
+/// The deadline callback interrupts a module that ran out of time, so an
+/// interrupt trap is reported as the timeout it stands for. A request to a
+/// host outside the network allowlist is reported as denied.
+pub(crate) fn execution_error(error: wasmtime::Error, what: &str) -> WasmError {
+    if let Some(denied) = error.downcast_ref::<NetworkDenied>() {
+        return WasmError::CapabilityDenied(denied.0.clone());
+    }
+    match error.downcast_ref::<Trap>() {
+        Some(Trap::Interrupt) => WasmError::ResourceLimit { kind: ResourceLimitKind::Timeout },
+        _ => WasmError::Execution(format!("{}: {}", what, error)),
+    }
+}
+
 impl Default for WasmRuntime {
     fn default() -> Self {
         Self::new().expect("Failed to create default WASM runtime")
@@ -286,7 +477,8 @@ impl Default for WasmRuntime {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_utils::test_modules::{simple_function_wasm, wasi_hello_wasm};
+    use crate::network::HostPattern;
+    use crate::test_utils::test_modules::{simple_function_wasm, wasi_echo_wasm, wasi_hello_wasm};
     use serde_json::json;
     
     #[tokio::test]
@@ -306,6 +498,7 @@ mod tests {
             enable_wasi: false,
             allow_network: false,
             allow_filesystem: true,
+            allowed_wasi_imports: None,
         };
         
         let runtime = WasmRuntime::with_config(config).unwrap();
@@ -336,13 +529,15 @@ mod tests {
         let mut module = WasmModule::from_bytes(wasi_hello_wasm().to_vec()).unwrap();
         let context = WasmContext::new();
         
-        // Execute WASI module (it should run without error even if it doesn't produce output)
+        // A module that writes nothing has empty output, whatever its input
         let result = runtime
-            .execute_with_stdio(&mut module, "", context)
+            .execute_with_stdio(&mut module, "ignored", context)
             .await;
+        assert_eq!(result.unwrap(), "");
         
-        // The module should execute successfully
-        assert!(result.is_ok());
+        let mut echo = WasmModule::from_bytes(wasi_echo_wasm().to_vec()).unwrap();
+        let output = runtime.execute_with_stdio(&mut echo, "hello", WasmContext::new()).await.unwrap();
+        assert_eq!(output, "hello");
     }
     
     #[tokio::test]
@@ -381,6 +576,21 @@ mod tests {
         assert_eq!(context.cwd, Some("/tmp".to_string()));
     }
     
+    #[tokio::test]
+    async fn test_allowed_wasi_imports_enforced_at_execution() {
+        let config = WasmConfig {
+            allowed_wasi_imports: Some(["fd_write".to_string()].into_iter().collect()),
+            ..Default::default()
+        };
+        
+        let runtime = WasmRuntime::with_config(config).unwrap();
+        // Loaded without the policy, so the runtime has to catch it
+        let mut module = WasmModule::from_bytes(wasi_hello_wasm().to_vec()).unwrap();
+        
+        let result = runtime.execute_with_stdio(&mut module, "", WasmContext::new()).await;
+        assert!(matches!(result, Err(WasmError::CapabilityDenied(_))));
+    }
+    
     #[tokio::test]
     async fn test_fuel_limit() {
         let config = WasmConfig {
@@ -405,4 +615,108 @@ mod tests {
             Err(e) => panic!("Unexpected error type: {:?}", e),
         }
     }
+    
+    /// Module with `fetch` and `fetch_body_len` exports requesting `url`
+    fn fetch_module(url: &str) -> WasmModule {
+        let request = serde_json::to_string(&json!({ "url": url })).unwrap();
+        let wat = format!(r#"
+            (module
+              (import "mitoxide" "http_request" (func $http_request (param i32 i32) (result i32)))
+              (import "mitoxide" "http_response_read" (func $http_response_read (param i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (data (i32.const 0) "{}")
+              (func (export "fetch") (result i32)
+                (call $http_request (i32.const 0) (i32.const {})))
+              (func (export "fetch_body_len") (result i32)
+                (drop (call $http_request (i32.const 0) (i32.const {})))
+                (call $http_response_read (i32.const 4096) (i32.const 4096))))
+        "#, request.replace('"', "\\\""), request.len(), request.len());
+        WasmModule::from_bytes(wat::parse_str(wat).unwrap()).unwrap()
+    }
+    
+    /// Serve `body` to every HTTP connection on a local port
+    async fn serve(body: &'static str) -> std::net::SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        addr
+    }
+    
+    #[tokio::test]
+    async fn test_network_requests_limited_to_allowlist() {
+        let addr = serve("hello").await;
+        let runtime = WasmRuntime::with_config(WasmConfig { allow_network: true, ..Default::default() }).unwrap();
+        let granted = || WasmContext::new().grant(WasmCapability::Network {
+            allow: vec![HostPattern::new("127.0.0.1"), HostPattern::new("*.example.com")],
+        });
+        
+        let mut allowed = fetch_module(&format!("http://127.0.0.1:{}/status", addr.port()));
+        let status: i32 = runtime.call_function(&mut allowed, "fetch", (), granted()).await.unwrap();
+        assert_eq!(status, 200);
+        let body_len: i32 = runtime.call_function(&mut allowed, "fetch_body_len", (), granted()).await.unwrap();
+        assert_eq!(body_len, 5);
+        
+        // A host outside the allowlist traps before anything is sent
+        let mut denied = fetch_module("http://blocked.test/status");
+        let result = runtime.call_function::<(), i32>(&mut denied, "fetch", (), granted()).await;
+        match result {
+            Err(WasmError::CapabilityDenied(message)) => assert!(message.contains("blocked.test"), "{}", message),
+            other => panic!("Expected CapabilityDenied, got {:?}", other),
+        }
+        
+        // Without the grant, or with network access disabled, the module doesn't run at all
+        let result = runtime.call_function::<(), i32>(&mut allowed, "fetch", (), WasmContext::new()).await;
+        assert!(matches!(result, Err(WasmError::CapabilityDenied(_))), "{:?}", result);
+        let disabled = WasmRuntime::new().unwrap();
+        let result = disabled.call_function::<(), i32>(&mut allowed, "fetch", (), granted()).await;
+        assert!(matches!(result, Err(WasmError::CapabilityDenied(_))), "{:?}", result);
+    }
+    
+    #[test]
+    fn test_infinite_loop_is_stopped_at_timeout() {
+        let config = WasmConfig {
+            max_execution_time: Duration::from_millis(100),
+            max_fuel: None,
+            ..Default::default()
+        };
+        let runtime = WasmRuntime::with_config(config).unwrap();
+        let wasm = wat::parse_str(r#"
+            (module
+              (func $spin (loop $forever (br $forever)))
+              (export "spin" (func $spin)))
+        "#).unwrap();
+        let mut module = WasmModule::from_bytes(wasm).unwrap();
+        
+        // Run on a thread of its own: joining it shows the loop no longer holds it
+        let start = std::time::Instant::now();
+        let worker = std::thread::spawn(move || {
+            let executor = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            executor.block_on(runtime.call_function::<(), ()>(&mut module, "spin", (), WasmContext::new()))
+        });
+        let result = worker.join().unwrap();
+        let elapsed = start.elapsed();
+        
+        assert!(
+            matches!(result, Err(WasmError::ResourceLimit { kind: ResourceLimitKind::Timeout })),
+            "{:?}", result
+        );
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(2), "Loop ran for {:?}", elapsed);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,257 @@
+//! Module instances kept between calls, and snapshots of their state
+
+use crate::error::{ResourceLimitKind, WasmError};
+use crate::module::ModuleMetadata;
+use crate::runtime::{execution_error, reset_limits, WasmConfig, WasmContext};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use wasmtime::{Extern, Instance, Mutability, Store, Val, WasmParams, WasmResults};
+
+/// Size of a WASM memory page
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+/// A module instance whose memory and globals carry over from one call to the next
+///
+/// Created with [`WasmRuntime::instantiate`](crate::WasmRuntime::instantiate)
+/// or [`WasmRuntime::resume`](crate::WasmRuntime::resume). Every call gets the
+/// runtime's full fuel and execution time.
+pub struct WasmInstance {
+    /// Store holding the instance's state
+    store: Store<WasmContext>,
+    /// The instantiated module
+    instance: Instance,
+    /// Hash of the module, checked when a snapshot is restored
+    module_hash: String,
+    /// State of the module a snapshot would miss
+    unexported_state: Vec<String>,
+    /// Limits applied to each call
+    config: WasmConfig,
+}
+
+impl std::fmt::Debug for WasmInstance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmInstance")
+            .field("module_hash", &self.module_hash)
+            .finish_non_exhaustive()
+    }
+}
+
+/// State of a [`WasmInstance`] between calls
+///
+/// Only what the module exports is captured: its memories and mutable
+/// globals, which must be all of them. Serializable, so it can be stored or
+/// sent to another agent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WasmSnapshot {
+    /// Hash of the module the snapshot was taken of
+    pub module_hash: String,
+    /// Contents of each exported memory, by export name
+    pub memories: BTreeMap<String, Vec<u8>>,
+    /// Value of each exported mutable global, by export name
+    pub globals: BTreeMap<String, GlobalValue>,
+}
+
+/// Value of a global in a [`WasmSnapshot`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GlobalValue {
+    /// 32-bit integer
+    I32(i32),
+    /// 64-bit integer
+    I64(i64),
+    /// 32-bit float, as its bits
+    F32(u32),
+    /// 64-bit float, as its bits
+    F64(u64),
+}
+
+impl WasmInstance {
+    /// Wrap an instantiated module
+    pub(crate) fn new(store: Store<WasmContext>, instance: Instance, metadata: &ModuleMetadata, config: WasmConfig) -> Self {
+        Self {
+            store,
+            instance,
+            module_hash: metadata.hash.clone(),
+            unexported_state: metadata.unexported_state.clone(),
+            config,
+        }
+    }
+    
+    /// Hash of the module this is an instance of
+    pub fn module_hash(&self) -> &str {
+        &self.module_hash
+    }
+    
+    /// Call an exported function
+    ///
+    /// A call stopped by a limit may leave the state half updated.
+    pub async fn call<Params, Results>(&mut self, function_name: &str, params: Params) -> Result<Results, WasmError>
+    where
+        Params: WasmParams,
+        Results: WasmResults,
+    {
+        reset_limits(&self.config, &mut self.store)?;
+        let func = self.instance.get_typed_func::<Params, Results>(&mut self.store, function_name)?;
+        
+        let execution_future = func.call_async(&mut self.store, params);
+        match tokio::time::timeout(self.config.max_execution_time, execution_future).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(e)) => Err(execution_error(e, "Function call failed")),
+            Err(_) => Err(WasmError::ResourceLimit { kind: ResourceLimitKind::Timeout }),
+        }
+    }
+    
+    /// Capture the exported memories and mutable globals, refusing if there are others
+    pub(crate) fn capture(&mut self) -> Result<WasmSnapshot, WasmError> {
+        if !self.unexported_state.is_empty() {
+            return Err(WasmError::UnsupportedCapability(format!(
+                "module state that isn't exported can't be snapshotted: {}", self.unexported_state.join(", ")
+            )));
+        }
+        
+        let exports: Vec<(String, Extern)> = self.instance.exports(&mut self.store)
+            .map(|export| (export.name().to_string(), export.into_extern()))
+            .collect();
+        
+        let mut memories = BTreeMap::new();
+        let mut globals = BTreeMap::new();
+        for (name, export) in exports {
+            match export {
+                Extern::Memory(memory) => {
+                    memories.insert(name, memory.data(&self.store).to_vec());
+                }
+                Extern::Global(global) if global.ty(&self.store).mutability() == Mutability::Var => {
+                    let value = match global.get(&mut self.store) {
+                        Val::I32(value) => GlobalValue::I32(value),
+                        Val::I64(value) => GlobalValue::I64(value),
+                        Val::F32(bits) => GlobalValue::F32(bits),
+                        Val::F64(bits) => GlobalValue::F64(bits),
+                        other => return Err(WasmError::UnsupportedCapability(format!(
+                            "global {:?} of type {} can't be snapshotted", name, other.ty()
+                        ))),
+                    };
+                    globals.insert(name, value);
+                }
+                _ => {}
+            }
+        }
+        
+        Ok(WasmSnapshot { module_hash: self.module_hash.clone(), memories, globals })
+    }
+    
+    /// Overwrite the exported memories and globals with those in `snapshot`
+    pub(crate) fn restore(&mut self, snapshot: &WasmSnapshot) -> Result<(), WasmError> {
+        for (name, data) in &snapshot.memories {
+            let memory = self.instance.get_memory(&mut self.store, name)
+                .ok_or_else(|| WasmError::Execution(format!("Module has no memory {:?} to restore", name)))?;
+            let size = memory.data_size(&self.store);
+            if data.len() > size {
+                memory.grow(&mut self.store, ((data.len() - size).div_ceil(WASM_PAGE_SIZE)) as u64)?;
+            }
+            // Memory only grows, so anything past the snapshot's size was grown by the start function
+            let contents = memory.data_mut(&mut self.store);
+            contents[..data.len()].copy_from_slice(data);
+            contents[data.len()..].fill(0);
+        }
+        
+        for (name, value) in &snapshot.globals {
+            let global = self.instance.get_global(&mut self.store, name)
+                .ok_or_else(|| WasmError::Execution(format!("Module has no global {:?} to restore", name)))?;
+            let value = match *value {
+                GlobalValue::I32(value) => Val::I32(value),
+                GlobalValue::I64(value) => Val::I64(value),
+                GlobalValue::F32(bits) => Val::F32(bits),
+                GlobalValue::F64(bits) => Val::F64(bits),
+            };
+            global.set(&mut self.store, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_modules::{simple_function_wasm, wasi_hello_wasm};
+    use crate::{WasmModule, WasmRuntime};
+    
+    /// Module counting calls to `step` in a global and summing the counts in memory
+    fn counter_module() -> WasmModule {
+        let wasm = wat::parse_str(r#"
+            (module
+              (memory (export "memory") 1)
+              (global $count (export "count") (mut i32) (i32.const 0))
+              (func (export "step") (result i32)
+                (global.set $count (i32.add (global.get $count) (i32.const 1)))
+                (i32.store (i32.const 0) (i32.add (i32.load (i32.const 0)) (global.get $count)))
+                (global.get $count))
+              (func (export "sum") (result i32)
+                (i32.load (i32.const 0))))
+        "#).unwrap();
+        WasmModule::from_bytes(wasm).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_snapshot_and_resume() {
+        let runtime = WasmRuntime::new().unwrap();
+        let mut module = counter_module();
+        
+        let mut instance = runtime.instantiate(&mut module, WasmContext::new()).await.unwrap();
+        for expected in 1..=5 {
+            assert_eq!(instance.call::<(), i32>("step", ()).await.unwrap(), expected);
+        }
+        let snapshot = runtime.snapshot(&mut instance).unwrap();
+        assert_eq!(snapshot.globals.get("count"), Some(&GlobalValue::I32(5)));
+        
+        // The original carries on independently of the snapshot
+        assert_eq!(instance.call::<(), i32>("step", ()).await.unwrap(), 6);
+        assert_eq!(instance.call::<(), i32>("step", ()).await.unwrap(), 7);
+        
+        // Resuming from the snapshot, after a round trip as a blob, continues from 5
+        let blob = serde_json::to_vec(&snapshot).unwrap();
+        let snapshot: WasmSnapshot = serde_json::from_slice(&blob).unwrap();
+        let mut resumed = runtime.resume(&mut module, &snapshot, WasmContext::new()).await.unwrap();
+        assert_eq!(resumed.call::<(), i32>("step", ()).await.unwrap(), 6);
+        assert_eq!(resumed.call::<(), i32>("sum", ()).await.unwrap(), 21);
+    }
+    
+    #[tokio::test]
+    async fn test_resume_requires_same_module() {
+        let runtime = WasmRuntime::new().unwrap();
+        let mut module = counter_module();
+        let mut instance = runtime.instantiate(&mut module, WasmContext::new()).await.unwrap();
+        let snapshot = runtime.snapshot(&mut instance).unwrap();
+        
+        let mut other = WasmModule::from_bytes(simple_function_wasm().to_vec()).unwrap();
+        let result = runtime.resume(&mut other, &snapshot, WasmContext::new()).await;
+        assert!(matches!(result, Err(WasmError::Execution(_))), "{:?}", result);
+        
+        let mut wasi = WasmModule::from_bytes(wasi_hello_wasm().to_vec()).unwrap();
+        let result = runtime.instantiate(&mut wasi, WasmContext::new()).await;
+        assert!(matches!(result, Err(WasmError::UnsupportedCapability(_))), "{:?}", result);
+    }
+    
+    #[tokio::test]
+    async fn test_snapshot_refuses_unexported_state() {
+        let runtime = WasmRuntime::new().unwrap();
+        // Like a compiled module's stack pointer, and a memory only the module sees
+        let wasm = wat::parse_str(r#"
+            (module
+              (memory 1)
+              (global $sp (mut i32) (i32.const 1024))
+              (global $limit i32 (i32.const 4096))
+              (global (export "count") (mut i32) (i32.const 0))
+              (func (export "step") (result i32)
+                (global.set $sp (i32.sub (global.get $sp) (i32.const 16)))
+                (global.get $sp)))
+        "#).unwrap();
+        let mut module = WasmModule::from_bytes(wasm).unwrap();
+        assert_eq!(module.metadata.unexported_state, vec!["memory 0", "global 0"]);
+        
+        let mut instance = runtime.instantiate(&mut module, WasmContext::new()).await.unwrap();
+        assert_eq!(instance.call::<(), i32>("step", ()).await.unwrap(), 1008);
+        let result = runtime.snapshot(&mut instance);
+        assert!(matches!(&result, Err(WasmError::UnsupportedCapability(message)) if message.contains("memory 0, global 0")), "{:?}", result);
+        
+        assert!(counter_module().metadata.unexported_state.is_empty());
+    }
+}
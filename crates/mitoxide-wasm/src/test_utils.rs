@@ -1,5 +1,6 @@
 //! Test utilities for WASM module testing
 
+/// Pre-built WASM modules for tests
 pub mod test_modules {
     use std::sync::OnceLock;
     
@@ -34,10 +35,36 @@ pub mod test_modules {
         "#).unwrap()
     }
     
+    fn generate_wasi_echo_wasm() -> Vec<u8> {
+        wat::parse_str(r#"
+            (module
+              (import "wasi_snapshot_preview1" "fd_read"
+                (func $fd_read (param i32 i32 i32 i32) (result i32)))
+              (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+              (func $_start
+                (local $read i32)
+                (block $done
+                  (loop $copy
+                    (i32.store (i32.const 0) (i32.const 64))
+                    (i32.store (i32.const 4) (i32.const 4096))
+                    (br_if $done (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                    (local.set $read (i32.load (i32.const 8)))
+                    (br_if $done (i32.eqz (local.get $read)))
+                    (i32.store (i32.const 4) (local.get $read))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+                    (br $copy))))
+              (export "_start" (func $_start))
+              (memory 1)
+              (export "memory" (memory 0)))
+        "#).unwrap()
+    }
+    
     // Use OnceLock to cache the generated WASM modules
     static MINIMAL_WASM: OnceLock<Vec<u8>> = OnceLock::new();
     static SIMPLE_FUNCTION_WASM: OnceLock<Vec<u8>> = OnceLock::new();
     static WASI_HELLO_WASM: OnceLock<Vec<u8>> = OnceLock::new();
+    static WASI_ECHO_WASM: OnceLock<Vec<u8>> = OnceLock::new();
     
     /// A minimal valid WASM module that does nothing
     pub fn minimal_wasm() -> &'static [u8] {
@@ -54,6 +81,11 @@ pub mod test_modules {
         WASI_HELLO_WASM.get_or_init(generate_wasi_hello_wasm)
     }
     
+    /// A WASI module that copies its stdin to its stdout
+    pub fn wasi_echo_wasm() -> &'static [u8] {
+        WASI_ECHO_WASM.get_or_init(generate_wasi_echo_wasm)
+    }
+    
     /// Invalid WASM with wrong magic number
     pub const INVALID_MAGIC_WASM: &[u8] = &[
         0xFF, 0xFF, 0xFF, 0xFF, // wrong magic
@@ -0,0 +1,278 @@
+//! Operations of a session grouped by what they act on
+//!
+//! [`ConnectedSession::files`], [`ConnectedSession::process`] and
+//! [`ConnectedSession::wasm`] return handles with just the file, process or
+//! WASM operations of a [`Context`]. Each handle wraps its own context over the
+//! session's connection, so requests made through different handles share it.
+
+use crate::context::{CommandBuilder, DirListStream, FileTail, ProcessOutput, PtySession, ResponseStream, SyncOptions, SyncReport};
+use crate::{ConnectedSession, Context, Result};
+use bytes::Bytes;
+use mitoxide_proto::message::{Compression, FileChange, FileOwner, FilesystemSpace, HashAlgorithm, PtySize, TempKind};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[cfg(feature = "wasm")]
+use mitoxide_proto::message::PipelineStage;
+#[cfg(feature = "wasm")]
+use serde::{de::DeserializeOwned, Serialize};
+
+/// File transfer and inspection on the remote host
+pub struct FileApi {
+    /// Context the operations run in
+    context: Context,
+}
+
+impl FileApi {
+    /// Context the operations run in, for anything not exposed here
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+    
+    /// Upload a local file, as [`Context::put`]
+    pub async fn put(&self, local_path: &Path, remote_path: &Path) -> Result<u64> {
+        self.context.put(local_path, remote_path).await
+    }
+    
+    /// Upload a local file, replacing any existing one in a single rename, as [`Context::put_atomic`]
+    pub async fn put_atomic(&self, local_path: &Path, remote_path: &Path) -> Result<u64> {
+        self.context.put_atomic(local_path, remote_path).await
+    }
+    
+    /// Download a remote file, as [`Context::get`]
+    pub async fn get(&self, remote_path: &Path, local_path: &Path) -> Result<u64> {
+        self.context.get(remote_path, local_path).await
+    }
+    
+    /// Download a remote file over concurrent ranged requests, as [`Context::get_parallel`]
+    pub async fn get_parallel(&self, remote_path: &Path, local_path: &Path, streams: usize) -> Result<u64> {
+        self.context.get_parallel(remote_path, local_path, streams).await
+    }
+    
+    /// Download a compressed remote file, decompressed on the agent, as [`Context::get_decompressed`]
+    pub async fn get_decompressed(&self, remote_path: &Path, local_path: &Path, compression: Compression) -> Result<u64> {
+        self.context.get_decompressed(remote_path, local_path, compression).await
+    }
+    
+    /// Download a remote file in chunks, as [`Context::get_stream`]
+    pub async fn get_stream(&self, remote_path: &Path, chunk_size: u32) -> Result<ResponseStream> {
+        self.context.get_stream(remote_path, chunk_size).await
+    }
+    
+    /// Apply a unified diff to a remote file, as [`Context::patch`]
+    pub async fn patch(&self, remote_path: &Path, patch: &str, create_backup: bool) -> Result<Option<PathBuf>> {
+        self.context.patch(remote_path, patch, create_backup).await
+    }
+    
+    /// Follow the end of a remote file, as [`Context::tail`]
+    pub async fn tail(&self, remote_path: &Path, lines: u64, follow: bool) -> Result<FileTail> {
+        self.context.tail(remote_path, lines, follow).await
+    }
+    
+    /// Hash a remote file on the agent, as [`Context::hash`]
+    pub async fn hash(&self, remote_path: &Path, algorithm: HashAlgorithm) -> Result<(String, u64)> {
+        self.context.hash(remote_path, algorithm).await
+    }
+    
+    /// Size of the remote filesystem holding a path, as [`Context::disk_space`]
+    pub async fn disk_space(&self, remote_path: &Path) -> Result<FilesystemSpace> {
+        self.context.disk_space(remote_path).await
+    }
+    
+    /// Read the extended attributes of a remote file, as [`Context::xattrs`]
+    pub async fn xattrs(&self, remote_path: &Path) -> Result<HashMap<String, Bytes>> {
+        self.context.xattrs(remote_path).await
+    }
+    
+    /// Set extended attributes on a remote file, as [`Context::set_xattrs`]
+    pub async fn set_xattrs(&self, remote_path: &Path, xattrs: HashMap<String, Bytes>) -> Result<HashMap<String, Bytes>> {
+        self.context.set_xattrs(remote_path, xattrs).await
+    }
+    
+    /// Change who owns a remote file, as [`Context::chown`]
+    pub async fn chown(
+        &self,
+        remote_path: &Path,
+        uid: Option<FileOwner>,
+        gid: Option<FileOwner>,
+        recursive: bool,
+    ) -> Result<(u32, u32)> {
+        self.context.chown(remote_path, uid, gid, recursive).await
+    }
+    
+    /// Make a remote file hold exactly some content, as [`Context::ensure_file`]
+    pub async fn ensure_file(
+        &self,
+        remote_path: &Path,
+        content: &[u8],
+        mode: Option<u32>,
+        owner: Option<FileOwner>,
+    ) -> Result<Vec<FileChange>> {
+        self.context.ensure_file(remote_path, content, mode, owner).await
+    }
+    
+    /// List a remote directory in batches, as [`Context::list_dir_stream`]
+    pub async fn list_dir_stream(
+        &self,
+        remote_path: &Path,
+        include_hidden: bool,
+        recursive: bool,
+        batch_size: u32,
+    ) -> Result<DirListStream> {
+        self.context.list_dir_stream(remote_path, include_hidden, recursive, batch_size).await
+    }
+    
+    /// Create a uniquely named temporary file or directory, as [`Context::mktemp`]
+    pub async fn mktemp(
+        &self,
+        kind: TempKind,
+        dir: Option<&Path>,
+        prefix: &str,
+        suffix: &str,
+        cleanup: bool,
+    ) -> Result<PathBuf> {
+        self.context.mktemp(kind, dir, prefix, suffix, cleanup).await
+    }
+    
+    /// Mirror a local directory tree onto the remote host, as [`Context::sync`]
+    pub async fn sync(&self, local_dir: &Path, remote_dir: &Path, options: SyncOptions) -> Result<SyncReport> {
+        self.context.sync(local_dir, remote_dir, options).await
+    }
+}
+
+/// Process execution on the remote host
+pub struct ProcessApi {
+    /// Context the operations run in
+    context: Context,
+}
+
+impl ProcessApi {
+    /// Context the operations run in, for anything not exposed here
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+    
+    /// Start building a command, as [`Context::command`]
+    pub fn command(&self, command: &[&str]) -> CommandBuilder<'_> {
+        self.context.command(command)
+    }
+    
+    /// Run a command and wait for its output, as [`Context::proc_exec`]
+    pub async fn exec(&self, command: &[&str]) -> Result<ProcessOutput> {
+        self.context.proc_exec(command).await
+    }
+    
+    /// Run a command with its own environment, directory and input, as [`Context::proc_exec_with_env`]
+    pub async fn exec_with_env(
+        &self,
+        command: &[&str],
+        env: HashMap<String, String>,
+        cwd: Option<&Path>,
+        stdin: Option<&[u8]>,
+    ) -> Result<ProcessOutput> {
+        self.context.proc_exec_with_env(command, env, cwd, stdin).await
+    }
+    
+    /// Start a command on a pseudoterminal and interact with it, as [`Context::pty`]
+    pub async fn pty(&self, command: &[&str], window: PtySize) -> Result<PtySession> {
+        self.context.pty(command, window).await
+    }
+    
+    /// Start a command on a pseudoterminal of a given type, as [`Context::pty_with_term`]
+    pub async fn pty_with_term(&self, command: &[&str], window: PtySize, term: &str) -> Result<PtySession> {
+        self.context.pty_with_term(command, window, term).await
+    }
+    
+    /// Send a signal to a running command, as [`Context::signal`]
+    pub async fn signal(&self, target: Uuid, signal: &str) -> Result<u32> {
+        self.context.signal(target, signal).await
+    }
+}
+
+/// WASM module execution on the remote host
+#[cfg(feature = "wasm")]
+pub struct WasmApi {
+    /// Context the operations run in
+    context: Context,
+}
+
+#[cfg(feature = "wasm")]
+impl WasmApi {
+    /// Context the operations run in, for anything not exposed here
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+    
+    /// Run a module, as [`Context::call_wasm`]
+    pub async fn call<T, R>(&self, module: &[u8], input: &T) -> Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        self.context.call_wasm(module, input).await
+    }
+    
+    /// Run a module allowed to make HTTP requests to some hosts, as [`Context::call_wasm_with_network`]
+    pub async fn call_with_network<T, R>(&self, module: &[u8], input: &T, allow: &[&str]) -> Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        self.context.call_wasm_with_network(module, input, allow).await
+    }
+    
+    /// Run modules one after another on the agent, as [`Context::call_wasm_pipeline`]
+    pub async fn pipeline<T, R>(&self, stages: Vec<PipelineStage>, input: &T) -> Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        self.context.call_wasm_pipeline(stages, input).await
+    }
+    
+    /// Store a module on the agent and return its hash, as [`Context::upload_wasm`]
+    pub async fn upload(&self, module: &[u8]) -> Result<String> {
+        self.context.upload_wasm(module).await
+    }
+    
+    /// Run a stored module, as [`Context::call_wasm_by_hash`]
+    pub async fn call_by_hash<T, R>(&self, hash: &str, input: &T) -> Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        self.context.call_wasm_by_hash(hash, input).await
+    }
+    
+    /// Describe a module without running it, as [`Context::inspect_wasm`]
+    pub async fn inspect(&self, module: &[u8]) -> Result<mitoxide_wasm::ModuleMetadata> {
+        self.context.inspect_wasm(module).await
+    }
+    
+    /// Describe a stored module, as [`Context::inspect_wasm_by_hash`]
+    pub async fn inspect_by_hash(&self, hash: &str) -> Result<mitoxide_wasm::ModuleMetadata> {
+        self.context.inspect_wasm_by_hash(hash).await
+    }
+}
+
+impl ConnectedSession {
+    /// File operations, over this session's connection
+    pub async fn files(&self) -> Result<FileApi> {
+        Ok(FileApi { context: self.context().await? })
+    }
+    
+    /// Process operations, over this session's connection
+    pub async fn process(&self) -> Result<ProcessApi> {
+        Ok(ProcessApi { context: self.context().await? })
+    }
+    
+    /// WASM operations, over this session's connection
+    #[cfg(feature = "wasm")]
+    pub async fn wasm(&self) -> Result<WasmApi> {
+        Ok(WasmApi { context: self.context().await? })
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,54 @@
+//! Unit tests for the grouped session APIs
+
+use super::*;
+use crate::test_support::LoopbackTransport;
+
+#[tokio::test]
+async fn test_files_and_process_share_the_session() {
+    let session = LoopbackTransport::new().connect_session().await.unwrap();
+    let files = session.files().await.unwrap();
+    let process = session.process().await.unwrap();
+    let id = session.id().await;
+    assert_eq!(files.context().session_id(), id);
+    assert_eq!(process.context().session_id(), id);
+    
+    let dir = tempfile::tempdir().unwrap();
+    let local = dir.path().join("local.txt");
+    let remote = dir.path().join("remote.txt");
+    std::fs::write(&local, "grouped").unwrap();
+    
+    assert_eq!(files.put(&local, &remote).await.unwrap(), 7);
+    let (hash, size) = files.hash(&remote, HashAlgorithm::Sha256).await.unwrap();
+    assert_eq!(size, 7);
+    assert_eq!(hash.len(), 64);
+    
+    // What one handle wrote is visible to the other
+    let remote_arg = remote.to_string_lossy();
+    let output = process.exec(&["cat", &remote_arg]).await.unwrap();
+    assert_eq!(output.stdout_string().unwrap(), "grouped");
+    let output = process.command(&["sh", "-c", "printf built"]).run().await.unwrap();
+    assert_eq!(output.stdout_string().unwrap(), "built");
+    
+    let copy = dir.path().join("copy.txt");
+    assert_eq!(files.get(&remote, &copy).await.unwrap(), 7);
+    assert_eq!(std::fs::read_to_string(&copy).unwrap(), "grouped");
+}
+
+#[cfg(feature = "wasm")]
+#[tokio::test]
+async fn test_wasm_forwards_to_the_session() {
+    use mitoxide_agent::handlers::WasmHandler;
+    use std::sync::Arc;
+    
+    let session = LoopbackTransport::new()
+        .with_handler("wasm_inspect", Arc::new(WasmHandler::new().unwrap()))
+        .connect_session().await.unwrap();
+    let wasm = session.wasm().await.unwrap();
+    assert_eq!(wasm.context().session_id(), session.id().await);
+    
+    // The smallest valid module: magic number and version
+    let module = b"\0asm\x01\0\0\0";
+    let metadata = wasm.inspect(module).await.unwrap();
+    assert_eq!(metadata.size, module.len());
+    assert!(metadata.exports.is_empty());
+}
@@ -1,22 +1,74 @@
 //! Execution context for remote operations
 
 use crate::{Result, MitoxideError, Router};
+use crate::router::OutputWindow;
 use mitoxide_proto::{Message, Request, Response};
-// use std::collections::HashMap;
-use std::path::Path;
+use mitoxide_proto::message::{BuildInfo, Compression, ContentTransform, DirEntry, EnvFile, FileChange, FileOwner, FilesystemSpace, HashAlgorithm, OnExists, OperationInfo, OutputFile, OutputStream, OutputTruncation, ProcessLimits, QosClass, StreamCompression, TempKind, Termination};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
 use std::time::{Duration, Instant};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
 use serde::{Serialize, de::DeserializeOwned};
-use tracing::debug;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
 use uuid::Uuid;
 
+#[cfg(feature = "wasm")]
+use mitoxide_proto::message::PipelineStage;
+
+mod ping;
+mod pty;
+mod sync;
+mod tunnel;
+
+pub use ping::PingStats;
+pub use pty::{PtyEvent, PtySession};
+pub use sync::{SyncOptions, SyncReport};
+pub use tunnel::Tunnel;
+
+/// Chunk size used when streaming a local file to a remote process
+const STDIN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunk size used when uploading a file on the request's stream
+const PUT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Files smaller than this are downloaded with a single request by [`Context::get_parallel`]
+pub const PARALLEL_DOWNLOAD_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+/// Chunk size used when uploading a WASM module, well under the frame size limit
+#[cfg(feature = "wasm")]
+const WASM_UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
 /// Execution context for remote operations
 pub struct Context {
     /// Session ID this context belongs to
     session_id: Uuid,
     /// Router for sending requests
     router: Arc<Router>,
+    /// Defaults merged into every process execution
+    defaults: ExecDefaults,
+    /// QoS class given to every request, instead of the per-type default
+    qos: Option<QosClass>,
+    /// Labels every request is tagged with
+    labels: HashMap<String, String>,
+    /// Whether uploads check the remote filesystem has room first
+    check_disk_space: bool,
+}
+
+/// Environment and working directory applied to process executions
+///
+/// Request-level values take precedence over these defaults.
+#[derive(Debug, Clone, Default)]
+pub struct ExecDefaults {
+    /// Environment variables
+    pub env: HashMap<String, String>,
+    /// Working directory
+    pub cwd: Option<PathBuf>,
 }
 
 impl Context {
@@ -25,14 +77,105 @@ impl Context {
         Ok(Self {
             session_id,
             router,
+            defaults: ExecDefaults::default(),
+            qos: None,
+            labels: HashMap::new(),
+            check_disk_space: false,
         })
     }
     
+    /// Replace the process execution defaults
+    pub(crate) fn with_defaults(mut self, defaults: ExecDefaults) -> Self {
+        self.defaults = defaults;
+        self
+    }
+    
+    /// Process execution defaults of this context
+    pub fn defaults(&self) -> &ExecDefaults {
+        &self.defaults
+    }
+    
+    /// Set a default environment variable for processes started from this context
+    pub fn set_default_env(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.defaults.env.insert(key.into(), value.into());
+    }
+    
+    /// Set the default working directory for processes started from this context
+    pub fn set_default_cwd(&mut self, cwd: Option<PathBuf>) {
+        self.defaults.cwd = cwd;
+    }
+    
+    /// Schedule every request sent from this context under `qos`
+    ///
+    /// When requests have to wait for a free slot at the agent, interactive
+    /// ones go ahead of batch and background work. Without a class, each
+    /// request gets the default for its type (see [`Request::qos`]).
+    pub fn set_qos(&mut self, qos: Option<QosClass>) {
+        self.qos = qos;
+    }
+    
+    /// Tag every request sent from this context with `labels`, or none if empty
+    ///
+    /// The agent puts the labels on the tracing span and audit records of each
+    /// request, so they can be correlated with the deploy or job they belong to.
+    pub fn set_labels(&mut self, labels: HashMap<String, String>) {
+        self.labels = labels;
+    }
+    
+    /// Check the remote filesystem has room before each upload from this context
+    ///
+    /// With the check, an upload larger than the space available fails with a
+    /// clear error before anything is written, instead of partway through with
+    /// a full disk. It costs one extra round trip per upload.
+    pub fn set_check_disk_space(&mut self, check: bool) {
+        self.check_disk_space = check;
+    }
+    
+    /// Wrap a request for sending, applying this context's labels and QoS class
+    fn message(&self, request: Request) -> Message {
+        let request = if self.labels.is_empty() {
+            request
+        } else {
+            request.with_labels(self.labels.clone())
+        };
+        match self.qos {
+            Some(qos) => Message::request(request.with_qos(qos)),
+            None => Message::request(request),
+        }
+    }
+    
     /// Get the session ID
     pub fn session_id(&self) -> Uuid {
         self.session_id
     }
     
+    /// Build a remote process execution
+    pub fn command(&self, command: &[&str]) -> CommandBuilder<'_> {
+        CommandBuilder {
+            context: self,
+            request_id: Uuid::new_v4(),
+            command: command.iter().map(|s| s.to_string()).collect(),
+            env: self.defaults.env.clone(),
+            cwd: self.defaults.cwd.clone(),
+            stdin: None,
+            stdin_file: None,
+            timeout: Some(300),
+            limits: None,
+            line_buffered: false,
+            max_line_length: None,
+            merge_stderr: false,
+            max_output_bytes: None,
+            output_truncation: OutputTruncation::Head,
+            kill_on_output_limit: false,
+            expand_env: false,
+            output_fds: Vec::new(),
+            detach: false,
+            stdout_file: None,
+            stderr_file: None,
+            env_file: None,
+        }
+    }
+    
     /// Execute a process on the remote host
     pub async fn proc_exec(&self, command: &[&str]) -> Result<ProcessOutput> {
         let cmd: Vec<String> = command.iter().map(|s| s.to_string()).collect();
@@ -41,28 +184,14 @@ impl Context {
         
         let request = Request::process_exec(
             cmd,
-            std::collections::HashMap::new(),
-            None,
+            self.defaults.env.clone(),
+            self.defaults.cwd.clone(),
             None,
             Some(300), // 5 minute default timeout
         );
         
         let response = self.send_request(request).await?;
-        
-        match response {
-            Response::ProcessResult { exit_code, stdout, stderr, duration_ms, .. } => {
-                Ok(ProcessOutput {
-                    exit_code,
-                    stdout,
-                    stderr,
-                    duration: Duration::from_millis(duration_ms),
-                })
-            }
-            Response::Error { error, .. } => {
-                Err(MitoxideError::Agent(format!("Process execution failed: {}", error.message)))
-            }
-            _ => Err(MitoxideError::Protocol("Unexpected response type".to_string())),
-        }
+        ProcessOutput::from_response(response)
     }
     
     /// Execute a process with environment variables and working directory
@@ -77,55 +206,127 @@ impl Context {
         
         debug!("Executing process with env: {:?}", cmd);
         
+        let mut merged_env = self.defaults.env.clone();
+        merged_env.extend(env);
+        
         let request = Request::process_exec(
             cmd,
-            env,
-            cwd.map(|p| p.to_path_buf()),
-            stdin.map(|data| Bytes::copy_from_slice(data)),
+            merged_env,
+            cwd.map(|p| p.to_path_buf()).or_else(|| self.defaults.cwd.clone()),
+            stdin.map(Bytes::copy_from_slice),
             Some(300),
         );
         
         let response = self.send_request(request).await?;
-        
-        match response {
-            Response::ProcessResult { exit_code, stdout, stderr, duration_ms, .. } => {
-                Ok(ProcessOutput {
-                    exit_code,
-                    stdout,
-                    stderr,
-                    duration: Duration::from_millis(duration_ms),
-                })
-            }
-            Response::Error { error, .. } => {
-                Err(MitoxideError::Agent(format!("Process execution failed: {}", error.message)))
-            }
-            _ => Err(MitoxideError::Protocol("Unexpected response type".to_string())),
-        }
+        ProcessOutput::from_response(response)
     }
     
     /// Upload a file to the remote host
+    ///
+    /// A failed upload leaves no partly written file behind.
     pub async fn put(&self, local_path: &Path, remote_path: &Path) -> Result<u64> {
-        debug!("Uploading file: {:?} -> {:?}", local_path, remote_path);
+        Ok(self.put_file(local_path, remote_path, false, Vec::new(), OnExists::Overwrite).await?.bytes_written)
+    }
+    
+    /// Upload a file to the remote host, replacing any existing one in a single rename
+    ///
+    /// A failed upload leaves an existing file unchanged.
+    pub async fn put_atomic(&self, local_path: &Path, remote_path: &Path) -> Result<u64> {
+        Ok(self.put_file(local_path, remote_path, true, Vec::new(), OnExists::Overwrite).await?.bytes_written)
+    }
+    
+    /// Upload a file, rewriting its content on the agent with `transforms`
+    ///
+    /// Returns the size of the file as written. A template with `${NAME}`
+    /// placeholders is filled in with [`ContentTransform::Substitute`].
+    pub async fn put_transformed(&self, local_path: &Path, remote_path: &Path, transforms: Vec<ContentTransform>) -> Result<u64> {
+        Ok(self.put_file(local_path, remote_path, false, transforms, OnExists::Overwrite).await?.bytes_written)
+    }
+    
+    /// Upload a file, deciding with `on_exists` what happens to one already there
+    ///
+    /// [`OnExists::Fail`] fails the upload with an `AlreadyExists` error,
+    /// [`OnExists::Skip`] reports the upload as skipped without writing, and
+    /// [`OnExists::Backup`] reports where the existing file was moved.
+    pub async fn put_with_policy(&self, local_path: &Path, remote_path: &Path, on_exists: OnExists) -> Result<PutOutcome> {
+        self.put_file(local_path, remote_path, false, Vec::new(), on_exists).await
+    }
+    
+    /// Upload a file, in place or atomically
+    ///
+    /// The content follows the request on its stream. The file is read a chunk
+    /// at a time as the agent takes the chunks before it, so only about a
+    /// stream window of it is held at once, however large it is.
+    async fn put_file(&self, local_path: &Path, remote_path: &Path, atomic: bool, transforms: Vec<ContentTransform>, on_exists: OnExists) -> Result<PutOutcome> {
+        debug!("Uploading file: {:?} -> {:?} (atomic: {})", local_path, remote_path, atomic);
+        
+        let read_error = |e: std::io::Error| MitoxideError::agent(format!("Failed to read local file: {}", e));
+        let file = tokio::fs::File::open(local_path).await.map_err(read_error)?;
+        let size = file.metadata().await.map_err(read_error)?.len();
         
-        // Read local file
-        let content = tokio::fs::read(local_path).await
-            .map_err(|e| MitoxideError::Agent(format!("Failed to read local file: {}", e)))?;
+        if self.check_disk_space {
+            let space = self.disk_space(remote_path).await?;
+            if size > space.available {
+                return Err(MitoxideError::agent(format!(
+                    "Not enough space for {:?}: {} bytes needed, {} available on the remote filesystem",
+                    remote_path, size, space.available
+                )));
+            }
+        }
         
-        let request = Request::file_put(
+        let mut request = Request::file_put(
             remote_path.to_path_buf(),
-            Bytes::from(content),
+            Bytes::new(),
             None, // Use default permissions
             true, // Create parent directories
         );
+        if let Request::FilePut { atomic: put_atomic, transforms: put_transforms, on_exists: put_on_exists, stream_content, .. } = &mut request {
+            *put_atomic = atomic;
+            *put_transforms = transforms;
+            *put_on_exists = on_exists;
+            *stream_content = true;
+        }
+        let request_id = request.id();
         
-        let response = self.send_request(request).await?;
+        let (input, mut read_failed) = upload_file(file);
+        let sending = self.router.send_message_with_input(self.message(request), input);
+        tokio::pin!(sending);
+        let response = tokio::select! {
+            response = &mut sending => response?,
+            Ok((e, _input)) = &mut read_failed => {
+                // Ending the stream would leave a truncated file, so it stays open until the put is cancelled
+                if let Err(cancel_error) = self.cancel_operation(request_id).await {
+                    warn!("Failed to cancel upload to {:?}: {}", remote_path, cancel_error);
+                }
+                return Err(read_error(e));
+            }
+        };
         
         match response {
-            Response::FilePutResult { bytes_written, .. } => Ok(bytes_written),
+            Response::FilePutResult { bytes_written, skipped, backup_path, .. } => {
+                Ok(PutOutcome { bytes_written, skipped, backup_path })
+            }
             Response::Error { error, .. } => {
-                Err(MitoxideError::Agent(format!("File upload failed: {}", error.message)))
+                Err(MitoxideError::agent(format!("File upload failed: {}", error.message)))
             }
-            _ => Err(MitoxideError::Protocol("Unexpected response type".to_string())),
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Apply a unified diff to a file on the remote host
+    ///
+    /// Returns where the original was kept when `create_backup` is set. A
+    /// patch that doesn't apply leaves the file as it was.
+    pub async fn patch(&self, remote_path: &Path, patch: &str, create_backup: bool) -> Result<Option<PathBuf>> {
+        debug!("Patching file: {:?}", remote_path);
+        
+        let request = Request::file_patch_text(remote_path.to_path_buf(), patch, create_backup);
+        match self.send_request(request).await? {
+            Response::FilePatchResult { backup_path, .. } => Ok(backup_path),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("File patch failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
         }
     }
     
@@ -134,26 +335,270 @@ impl Context {
         debug!("Downloading file: {:?} -> {:?}", remote_path, local_path);
         
         let request = Request::file_get(remote_path.to_path_buf(), None);
+        self.download(request, local_path).await
+    }
+    
+    /// Download a file, rewriting its content on the agent with `transforms`
+    pub async fn get_transformed(&self, remote_path: &Path, local_path: &Path, transforms: Vec<ContentTransform>) -> Result<u64> {
+        debug!("Downloading file with {} transforms: {:?} -> {:?}", transforms.len(), remote_path, local_path);
+        
+        let mut request = Request::file_get(remote_path.to_path_buf(), None);
+        if let Request::FileGet { transforms: get_transforms, .. } = &mut request {
+            *get_transforms = transforms;
+        }
+        self.download(request, local_path).await
+    }
+    
+    /// Download a file from the remote host as up to `streams` byte ranges fetched concurrently
+    ///
+    /// On a high-latency link a single request is limited to what its stream
+    /// can carry per round trip; concurrent ranged requests multiply that.
+    /// The ranges are written locally in order. Files smaller than
+    /// [`PARALLEL_DOWNLOAD_THRESHOLD`] are downloaded with a single request,
+    /// as by [`Context::get`]. A file that changes size mid-download is an error.
+    pub async fn get_parallel(&self, remote_path: &Path, local_path: &Path, streams: usize) -> Result<u64> {
+        // An empty range returns just the metadata
+        let size = match self.send_request(Request::file_get(remote_path.to_path_buf(), Some((0, 0)))).await? {
+            Response::FileContent { metadata, .. } => metadata.size,
+            Response::Error { error, .. } => {
+                return Err(MitoxideError::agent(format!("File download failed: {}", error.message)));
+            }
+            _ => return Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        };
+        let streams = streams.max(1) as u64;
+        if streams == 1 || size < PARALLEL_DOWNLOAD_THRESHOLD {
+            return self.get(remote_path, local_path).await;
+        }
+        
+        debug!("Downloading file in {} ranges: {:?} -> {:?}", streams, remote_path, local_path);
+        let range_size = size.div_ceil(streams);
+        let ranges = (0..streams)
+            .map(|i| (i * range_size, ((i + 1) * range_size).min(size)))
+            .filter(|(start, end)| start < end);
+        let parts = futures::future::try_join_all(ranges.map(|(start, end)| async move {
+            let request = Request::file_get(remote_path.to_path_buf(), Some((start, end)));
+            match self.send_request(request).await? {
+                Response::FileContent { content, metadata, .. } => {
+                    if metadata.size != size || content.len() as u64 != end - start {
+                        return Err(MitoxideError::agent(format!("File {:?} changed during download", remote_path)));
+                    }
+                    Ok(content)
+                }
+                Response::Error { error, .. } => {
+                    Err(MitoxideError::agent(format!("File download failed: {}", error.message)))
+                }
+                _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+            }
+        }))
+        .await?;
+        
+        write_download(local_path, &parts).await?;
+        Ok(size)
+    }
+    
+    /// Download a compressed file from the remote host, decompressing it on the agent
+    pub async fn get_decompressed(&self, remote_path: &Path, local_path: &Path, compression: Compression) -> Result<u64> {
+        debug!("Downloading {:?} file: {:?} -> {:?}", compression, remote_path, local_path);
+        
+        let mut request = Request::file_get(remote_path.to_path_buf(), None);
+        if let Request::FileGet { decompress, .. } = &mut request {
+            *decompress = Some(compression);
+        }
+        self.download(request, local_path).await
+    }
+    
+    /// Download a remote file as a stream of `FileChunk` responses of at most
+    /// `chunk_size` bytes, ending with a `FileContent` holding its metadata
+    pub async fn get_stream(&self, remote_path: &Path, chunk_size: u32) -> Result<ResponseStream> {
+        debug!("Streaming file: {:?} (chunk size: {})", remote_path, chunk_size);
+        
+        self.stream_request(Request::file_get_chunked(remote_path.to_path_buf(), chunk_size)).await
+    }
+    
+    /// Send a request whose responses may arrive over several frames
+    ///
+    /// The stream yields every partial response and then the final one. No
+    /// request timeout is applied; the caller decides how long to wait.
+    /// Requests with an output window, such as chunked file gets, only run
+    /// a window of data ahead of what has been taken from the stream.
+    pub async fn stream_request(&self, request: Request) -> Result<ResponseStream> {
+        if request.has_output_window() {
+            let (responses, window) = self.router
+                .send_message_windowed(self.message(request), None).await?;
+            return Ok(ResponseStream::new(responses, Some(window)));
+        }
+        let responses = self.router
+            .send_message_streaming(self.message(request), None).await?;
+        
+        Ok(ResponseStream::new(responses, None))
+    }
+    
+    /// Read the last `lines` lines of a remote file, then with `follow` keep
+    /// receiving lines appended to it, like `tail -f`
+    ///
+    /// A followed tail runs until [`FileTail::stop`] is called or the tail is dropped.
+    pub async fn tail(&self, remote_path: &Path, lines: u64, follow: bool) -> Result<FileTail> {
+        debug!("Tailing file: {:?} (lines: {}, follow: {})", remote_path, lines, follow);
+        
+        let request = Request::file_tail(remote_path.to_path_buf(), lines, follow);
+        let (stop_tx, input) = if follow {
+            let (tx, rx) = mpsc::channel(1);
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+        let responses = self.router
+            .send_message_streaming(self.message(request), input).await?;
+        
+        Ok(FileTail { responses: ResponseStream::new(responses, None), stop_tx })
+    }
+    
+    /// Hash a remote file on the agent, returning its lowercase hex digest and size
+    ///
+    /// Only the digest crosses the connection, so comparing it with a local
+    /// hash tells whether a transfer is needed without making one.
+    pub async fn hash(&self, remote_path: &Path, algorithm: HashAlgorithm) -> Result<(String, u64)> {
+        debug!("Hashing file: {:?} ({:?})", remote_path, algorithm);
+        
+        let request = Request::file_hash(remote_path.to_path_buf(), algorithm);
+        match self.send_request(request).await? {
+            Response::FileHash { hash, size, .. } => Ok((hash, size)),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("File hash failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Size of the remote filesystem that holds `remote_path`
+    ///
+    /// The path need not exist; the filesystem it would be created on is
+    /// reported.
+    pub async fn disk_space(&self, remote_path: &Path) -> Result<FilesystemSpace> {
+        debug!("Checking disk space: {:?}", remote_path);
+        
+        let request = Request::disk_space(remote_path.to_path_buf());
+        match self.send_request(request).await? {
+            Response::DiskSpace { space, .. } => Ok(space),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Disk space check failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Read the extended attributes of a remote file, by name
+    ///
+    /// Fails if the remote filesystem doesn't support extended attributes.
+    pub async fn xattrs(&self, remote_path: &Path) -> Result<HashMap<String, Bytes>> {
+        debug!("Reading extended attributes: {:?}", remote_path);
+        
+        let request = Request::file_xattr_get(remote_path.to_path_buf());
+        self.send_xattr_request(request).await
+    }
+    
+    /// Set extended attributes on a remote file, returning all of its attributes afterwards
+    ///
+    /// Attributes not in `xattrs` are left as they are.
+    pub async fn set_xattrs(&self, remote_path: &Path, xattrs: HashMap<String, Bytes>) -> Result<HashMap<String, Bytes>> {
+        debug!("Setting {} extended attributes: {:?}", xattrs.len(), remote_path);
+        
+        let request = Request::file_xattr_set(remote_path.to_path_buf(), xattrs);
+        self.send_xattr_request(request).await
+    }
+    
+    /// Change who owns a remote file, returning its owning user and group IDs afterwards
+    ///
+    /// Owners given as names are looked up on the remote host; one that is
+    /// `None` is left as it is. With `recursive`, everything below a directory
+    /// changes too. Giving files to another user usually needs a privileged agent.
+    pub async fn chown(
+        &self,
+        remote_path: &Path,
+        uid: Option<FileOwner>,
+        gid: Option<FileOwner>,
+        recursive: bool,
+    ) -> Result<(u32, u32)> {
+        debug!("Changing owner of {:?} to {:?}:{:?}", remote_path, uid, gid);
+        
+        let request = Request::file_chown(remote_path.to_path_buf(), uid, gid, recursive);
+        match self.send_request(request).await? {
+            Response::FileOwnership { uid, gid, .. } => Ok((uid, gid)),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Chown failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Make a remote file hold exactly `content`, returning what had to change
+    ///
+    /// The mode and owning user are set too when given. Nothing is written
+    /// when the file already matches, so the result is empty on a repeat.
+    pub async fn ensure_file(
+        &self,
+        remote_path: &Path,
+        content: &[u8],
+        mode: Option<u32>,
+        owner: Option<FileOwner>,
+    ) -> Result<Vec<FileChange>> {
+        debug!("Ensuring content of {:?}", remote_path);
+        
+        let request = Request::file_ensure(remote_path.to_path_buf(), Bytes::copy_from_slice(content), mode, owner);
+        match self.send_request(request).await? {
+            Response::FileEnsure { what_changed, .. } => Ok(what_changed),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("File ensure failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Send a request answered with `FileXattrs`
+    async fn send_xattr_request(&self, request: Request) -> Result<HashMap<String, Bytes>> {
+        match self.send_request(request).await? {
+            Response::FileXattrs { xattrs, .. } => Ok(xattrs),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Extended attribute request failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// List a remote directory, receiving its entries in batches of at most `batch_size`
+    ///
+    /// The agent only runs a few batches ahead of the ones taken from the
+    /// [`DirListStream`], so huge directories are never held in memory at once.
+    pub async fn list_dir_stream(
+        &self,
+        remote_path: &Path,
+        include_hidden: bool,
+        recursive: bool,
+        batch_size: u32,
+    ) -> Result<DirListStream> {
+        debug!("Streaming directory listing: {:?} (batch size: {})", remote_path, batch_size);
+        
+        let request = Request::dir_list_streaming(remote_path.to_path_buf(), include_hidden, recursive, batch_size);
+        let (ack_tx, input) = mpsc::channel(DIR_LIST_ACKS);
+        let responses = self.router
+            .send_message_streaming(self.message(request), Some(input)).await?;
+        
+        Ok(DirListStream { responses: ResponseStream::new(responses, None), ack_tx: Some(ack_tx), buffered: VecDeque::new() })
+    }
+    
+    /// Send a file get request and write the returned content locally
+    async fn download(&self, request: Request, local_path: &Path) -> Result<u64> {
         let response = self.send_request(request).await?;
         
         match response {
             Response::FileContent { content, .. } => {
-                // Create parent directories if needed
-                if let Some(parent) = local_path.parent() {
-                    tokio::fs::create_dir_all(parent).await
-                        .map_err(|e| MitoxideError::Agent(format!("Failed to create local directory: {}", e)))?;
-                }
-                
-                // Write file content
-                tokio::fs::write(local_path, &content).await
-                    .map_err(|e| MitoxideError::Agent(format!("Failed to write local file: {}", e)))?;
-                
+                write_download(local_path, std::slice::from_ref(&content)).await?;
                 Ok(content.len() as u64)
             }
             Response::Error { error, .. } => {
-                Err(MitoxideError::Agent(format!("File download failed: {}", error.message)))
+                Err(MitoxideError::agent(format!("File download failed: {}", error.message)))
             }
-            _ => Err(MitoxideError::Protocol("Unexpected response type".to_string())),
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
         }
     }
     
@@ -166,7 +611,7 @@ impl Context {
         debug!("Calling JSON RPC method: {}", method);
         
         let params_json = serde_json::to_vec(params)
-            .map_err(|e| MitoxideError::Protocol(format!("Failed to serialize params: {}", e)))?;
+            .map_err(|e| MitoxideError::protocol(format!("Failed to serialize params: {}", e)))?;
         
         let request = Request::JsonCall {
             id: Uuid::new_v4(),
@@ -179,12 +624,12 @@ impl Context {
         match response {
             Response::JsonResult { result, .. } => {
                 serde_json::from_slice(&result)
-                    .map_err(|e| MitoxideError::Protocol(format!("Failed to deserialize result: {}", e)))
+                    .map_err(|e| MitoxideError::protocol(format!("Failed to deserialize result: {}", e)))
             }
             Response::Error { error, .. } => {
-                Err(MitoxideError::Agent(format!("JSON RPC call failed: {}", error.message)))
+                Err(MitoxideError::agent(format!("JSON RPC call failed: {}", error.message)))
             }
-            _ => Err(MitoxideError::Protocol("Unexpected response type".to_string())),
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
         }
     }
     
@@ -195,32 +640,418 @@ impl Context {
         T: Serialize,
         R: DeserializeOwned,
     {
-        debug!("Executing WASM module");
+        self.call_wasm_with_network(module, input, &[]).await
+    }
+    
+    /// Execute a WASM module that may send HTTP requests to the hosts in `allow`
+    ///
+    /// The agent makes the requests on the module's behalf, through the
+    /// host functions described in [`mitoxide_wasm::network`], and traps the
+    /// module if it asks for any other host. Patterns are host names, such as
+    /// `api.example.com` or `*.example.com`, optionally with a `:port`. The
+    /// agent must have network access for modules enabled.
+    #[cfg(feature = "wasm")]
+    pub async fn call_wasm_with_network<T, R>(&self, module: &[u8], input: &T, allow: &[&str]) -> Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        debug!("Executing WASM module (network allowed to {:?})", allow);
         
         let input_json = serde_json::to_vec(input)
-            .map_err(|e| MitoxideError::Protocol(format!("Failed to serialize WASM input: {}", e)))?;
+            .map_err(|e| MitoxideError::protocol(format!("Failed to serialize WASM input: {}", e)))?;
         
         let request = Request::WasmExec {
             id: Uuid::new_v4(),
             module: Bytes::copy_from_slice(module),
             input: Bytes::from(input_json),
             timeout: Some(60), // 1 minute default timeout
+            module_hash: None,
+            network_allow: allow.iter().map(|host| host.to_string()).collect(),
+        };
+        
+        self.run_wasm(request).await
+    }
+    
+    /// Upload a WASM module to the remote host in chunks, returning its hash
+    ///
+    /// The hash can be passed to [`Context::call_wasm_by_hash`] to run the module
+    /// without sending it again.
+    #[cfg(feature = "wasm")]
+    pub async fn upload_wasm(&self, module: &[u8]) -> Result<String> {
+        debug!("Uploading WASM module: {} bytes", module.len());
+        
+        let upload_id = Uuid::new_v4();
+        let total = module.len() as u64;
+        let mut offset = 0;
+        loop {
+            let end = (offset + WASM_UPLOAD_CHUNK_SIZE).min(module.len());
+            let eof = end == module.len();
+            let request = Request::WasmUpload {
+                id: upload_id,
+                chunk: Bytes::copy_from_slice(&module[offset..end]),
+                offset: offset as u64,
+                total,
+                eof,
+            };
+            
+            match self.send_request(request).await? {
+                Response::WasmUploaded { hash: Some(hash), .. } if eof => return Ok(hash),
+                Response::WasmUploaded { .. } if !eof => offset = end,
+                Response::Error { error, .. } => {
+                    return Err(MitoxideError::agent(format!("WASM upload failed: {}", error.message)));
+                }
+                _ => return Err(MitoxideError::protocol("Unexpected response type".to_string())),
+            }
+        }
+    }
+    
+    /// Execute a WASM module previously stored with [`Context::upload_wasm`]
+    #[cfg(feature = "wasm")]
+    pub async fn call_wasm_by_hash<T, R>(&self, hash: &str, input: &T) -> Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        debug!("Executing stored WASM module {}", hash);
+        
+        let input_json = serde_json::to_vec(input)
+            .map_err(|e| MitoxideError::protocol(format!("Failed to serialize WASM input: {}", e)))?;
+        
+        let request = Request::WasmExec {
+            id: Uuid::new_v4(),
+            module: Bytes::new(),
+            input: Bytes::from(input_json),
+            timeout: Some(60), // 1 minute default timeout
+            module_hash: Some(hash.to_string()),
+            network_allow: Vec::new(),
+        };
+        
+        self.run_wasm(request).await
+    }
+    
+    /// Run WASM modules one after another on the agent, each on the previous one's output
+    ///
+    /// `input` goes to the first stage as JSON and the last stage's output is
+    /// decoded as the result; nothing in between travels back to the client.
+    /// A failing stage is named in the error.
+    #[cfg(feature = "wasm")]
+    pub async fn call_wasm_pipeline<T, R>(&self, stages: Vec<PipelineStage>, input: &T) -> Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        debug!("Executing WASM pipeline of {} stages", stages.len());
+        
+        let input_json = serde_json::to_vec(input)
+            .map_err(|e| MitoxideError::protocol(format!("Failed to serialize WASM input: {}", e)))?;
+        
+        let request = Request::WasmPipeline {
+            id: Uuid::new_v4(),
+            stages,
+            input: Bytes::from(input_json),
         };
         
+        self.run_wasm(request).await
+    }
+    
+    /// Describe a WASM module without running it
+    #[cfg(feature = "wasm")]
+    pub async fn inspect_wasm(&self, module: &[u8]) -> Result<mitoxide_wasm::ModuleMetadata> {
+        let request = Request::WasmInspect {
+            id: Uuid::new_v4(),
+            module: Bytes::copy_from_slice(module),
+            module_hash: None,
+        };
+        self.run_wasm_inspect(request).await
+    }
+    
+    /// Describe a WASM module previously stored with [`Context::upload_wasm`]
+    #[cfg(feature = "wasm")]
+    pub async fn inspect_wasm_by_hash(&self, hash: &str) -> Result<mitoxide_wasm::ModuleMetadata> {
+        let request = Request::WasmInspect {
+            id: Uuid::new_v4(),
+            module: Bytes::new(),
+            module_hash: Some(hash.to_string()),
+        };
+        self.run_wasm_inspect(request).await
+    }
+    
+    /// Send a WASM inspection request and decode the module metadata
+    #[cfg(feature = "wasm")]
+    async fn run_wasm_inspect(&self, request: Request) -> Result<mitoxide_wasm::ModuleMetadata> {
+        match self.send_request(request).await? {
+            Response::WasmMetadata { metadata, .. } => {
+                serde_json::from_slice(&metadata)
+                    .map_err(|e| MitoxideError::protocol(format!("Failed to deserialize WASM metadata: {}", e)))
+            }
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("WASM inspection failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Send a WASM execution request and decode its JSON output
+    #[cfg(feature = "wasm")]
+    async fn run_wasm<R: DeserializeOwned>(&self, request: Request) -> Result<R> {
         let response = self.send_request(request).await?;
         
         match response {
             Response::WasmResult { output, .. } => {
                 serde_json::from_slice(&output)
-                    .map_err(|e| MitoxideError::Protocol(format!("Failed to deserialize WASM output: {}", e)))
+                    .map_err(|e| MitoxideError::protocol(format!("Failed to deserialize WASM output: {}", e)))
+            }
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("WASM execution failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Send a signal to a process started by this session, returning its PID
+    pub async fn signal(&self, target: Uuid, signal: &str) -> Result<u32> {
+        debug!("Sending {} to process of request {}", signal, target);
+        
+        let request = Request::process_signal(target, signal);
+        let response = self.send_request(request).await?;
+        
+        match response {
+            Response::SignalSent { pid, .. } => Ok(pid),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Signal delivery failed: {}", error.message)))
             }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Change the working directory of this connection's agent session
+    ///
+    /// Relative paths in later requests, and processes started without their
+    /// own working directory, resolve against it. A resumed session keeps it.
+    /// Returns the canonical new directory.
+    pub async fn chdir(&self, path: &Path) -> Result<PathBuf> {
+        debug!("Changing working directory to {:?}", path);
+        
+        let request = Request::chdir(path.to_path_buf());
+        let response = self.send_request(request).await?;
+        
+        match response {
+            Response::Cwd { path, .. } => Ok(path),
             Response::Error { error, .. } => {
-                Err(MitoxideError::Agent(format!("WASM execution failed: {}", error.message)))
+                Err(MitoxideError::agent(format!("Chdir failed: {}", error.message)))
             }
-            _ => Err(MitoxideError::Protocol("Unexpected response type".to_string())),
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
         }
     }
     
+    /// Working directory of this connection's agent session
+    pub async fn getcwd(&self) -> Result<PathBuf> {
+        let request = Request::getcwd();
+        let response = self.send_request(request).await?;
+        
+        match response {
+            Response::Cwd { path, .. } => Ok(path),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Getcwd failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Create a uniquely named temporary file or directory on the remote host
+    ///
+    /// It is created in `dir`, or the agent's temporary directory, with a name
+    /// starting with `prefix` and ending with `suffix`. With `cleanup`, the
+    /// agent removes it when this connection ends. Returns its absolute path.
+    pub async fn mktemp(
+        &self,
+        kind: TempKind,
+        dir: Option<&Path>,
+        prefix: &str,
+        suffix: &str,
+        cleanup: bool,
+    ) -> Result<PathBuf> {
+        debug!("Creating temporary {:?} in {:?}", kind, dir);
+        
+        let request = Request::MkTemp {
+            id: Uuid::new_v4(),
+            dir: dir.map(Path::to_path_buf),
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+            kind,
+            cleanup,
+        };
+        let response = self.send_request(request).await?;
+        
+        match response {
+            Response::TempCreated { path, .. } => Ok(path),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("MkTemp failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// What the agent was built from: its version, commit, features and target
+    pub async fn version(&self) -> Result<BuildInfo> {
+        match self.send_request(Request::version()).await? {
+            Response::Version { build, .. } => Ok(build),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Version request failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Requests the agent is running for this connection, oldest first
+    pub async fn list_operations(&self) -> Result<Vec<OperationInfo>> {
+        match self.send_request(Request::list_operations()).await? {
+            Response::Operations { operations, .. } => Ok(operations),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Listing operations failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Cancel a request the agent is running, by its ID
+    ///
+    /// The cancelled request fails with `ErrorCode::Cancelled`.
+    pub async fn cancel_operation(&self, target: Uuid) -> Result<()> {
+        debug!("Cancelling request {}", target);
+        
+        match self.send_request(Request::cancel_operation(target)).await? {
+            Response::OperationCancelled { .. } => Ok(()),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Cancelling {} failed: {}", target, error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Open an agent session, resuming the one identified by `resume_token`
+    ///
+    /// After reconnecting, presenting the token of the previous session returns
+    /// the final responses the agent retained for it, so completed work does not
+    /// have to be run again. The agent keeps sessions in memory, so a new agent
+    /// process only knows them if the last one saved them to the directory named
+    /// by `MITOXIDE_STATE_DIR`. If the agent advertises a concurrency limit, requests
+    /// sent through this context's router are queued locally to stay within it.
+    pub async fn open_session(&self, resume_token: Option<Uuid>) -> Result<AgentSession> {
+        self.negotiate_session(resume_token, None).await
+    }
+    
+    /// Open an agent session, also asking for connection stream compression
+    ///
+    /// Nothing else may be sent on the connection until this returns.
+    pub(crate) async fn negotiate_session(&self, resume_token: Option<Uuid>, compression: Option<StreamCompression>) -> Result<AgentSession> {
+        debug!("Opening agent session (resume token: {:?}, compression: {:?})", resume_token, compression);
+        
+        let dictionary_id = match compression {
+            Some(_) => self.router.compression_dictionary_id().await,
+            None => None,
+        };
+        let attachment_threshold = self.router.attachment_threshold().await;
+        let request = Request::SessionOpen {
+            id: Uuid::new_v4(),
+            resume_token,
+            compression,
+            dictionary_id,
+            attachment_threshold: attachment_threshold.map(|threshold| u32::try_from(threshold).unwrap_or(u32::MAX)),
+        };
+        let response = self.send_request(request).await?;
+        
+        match response {
+            Response::SessionOpened {
+                token, resumed, responses, max_concurrent_requests, in_flight, compression, dictionary_id, attachment_threshold, ..
+            } => {
+                if let Some(max) = max_concurrent_requests {
+                    self.router.set_max_in_flight(max as usize).await;
+                }
+                Ok(AgentSession {
+                    token, resumed, responses, max_concurrent_requests, in_flight, compression, dictionary_id, attachment_threshold,
+                })
+            }
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Session open failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Close the connection cleanly, returning how many temporary paths the agent removed
+    ///
+    /// The agent answers once the requests it is running have finished, or
+    /// it has cancelled those still running after its drain timeout, and it
+    /// has cleaned up; nothing may be sent on the connection afterwards.
+    pub(crate) async fn close_session(&self) -> Result<u32> {
+        debug!("Closing agent session");
+        
+        match self.send_request(Request::session_close()).await? {
+            Response::SessionClosed { temp_paths_removed, .. } => Ok(temp_paths_removed),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Session close failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Send requests without waiting for each response before the next
+    ///
+    /// Every request goes out on its own stream as soon as the router's
+    /// pipeline depth allows, and the responses come back in the order of
+    /// `requests`. A failed request doesn't stop the others.
+    pub async fn pipeline(&self, requests: Vec<Request>) -> Vec<Result<Response>> {
+        debug!("Pipelining {} requests", requests.len());
+        
+        let handles: Vec<_> = requests.into_iter()
+            .map(|request| {
+                let router = self.router.clone();
+                let message = self.message(request);
+                tokio::spawn(async move { router.send_message(message).await })
+            })
+            .collect();
+        
+        let mut responses = Vec::with_capacity(handles.len());
+        for handle in handles {
+            responses.push(handle.await.unwrap_or_else(|e| {
+                Err(MitoxideError::protocol(format!("Pipelined request task failed: {}", e)))
+            }));
+        }
+        responses
+    }
+    
+    /// Run requests in order on the agent in a single round-trip
+    ///
+    /// Returns the responses of the requests that ran. With `stop_on_error`, the
+    /// agent skips everything after the first failed request (an error response or
+    /// a process exiting non-zero), so the result may be shorter than `requests`.
+    /// Requests that already ran are not rolled back. Each request is handled
+    /// as if it were sent on its own, so a `Chdir` applies to those after it;
+    /// see [`Request::Batch`] for the requests a batch can't hold.
+    pub async fn batch(&self, requests: Vec<Request>, stop_on_error: bool) -> Result<Vec<Response>> {
+        debug!("Running batch of {} requests (stop on error: {})", requests.len(), stop_on_error);
+        
+        let request = Request::batch(requests, stop_on_error);
+        let response = self.send_request(request).await?;
+        
+        match response {
+            Response::BatchResult { responses, .. } => Ok(responses),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Batch failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Wait until the requests and input sent so far are handed to the transport
+    ///
+    /// See [`Router::flush`] for what is covered.
+    pub async fn flush(&self) -> Result<()> {
+        self.router.flush().await
+    }
+    
     /// Ping the remote host to test connectivity
     pub async fn ping(&self) -> Result<Duration> {
         debug!("Pinging remote host");
@@ -233,19 +1064,615 @@ impl Context {
         match response {
             Response::Pong { .. } => Ok(duration),
             Response::Error { error, .. } => {
-                Err(MitoxideError::Agent(format!("Ping failed: {}", error.message)))
+                Err(MitoxideError::agent(format!("Ping failed: {}", error.message)))
             }
-            _ => Err(MitoxideError::Protocol("Unexpected response type".to_string())),
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
         }
     }
     
     /// Send a request and wait for response
     async fn send_request(&self, request: Request) -> Result<Response> {
-        let message = Message::request(request);
+        let message = self.message(request);
         self.router.send_message(message).await
     }
 }
 
+/// Write downloaded content to `local_path`, creating its parent directories
+async fn write_download(local_path: &Path, parts: &[Bytes]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+    
+    if let Some(parent) = local_path.parent() {
+        tokio::fs::create_dir_all(parent).await
+            .map_err(|e| MitoxideError::agent(format!("Failed to create local directory: {}", e)))?;
+    }
+    
+    let write = async {
+        let mut file = tokio::fs::File::create(local_path).await?;
+        for part in parts {
+            file.write_all(part).await?;
+        }
+        file.flush().await
+    };
+    write.await.map_err(|e| MitoxideError::agent(format!("Failed to write local file: {}", e)))
+}
+
+/// Builder for a remote process execution, created by [`Context::command`]
+pub struct CommandBuilder<'a> {
+    /// Context the command runs in
+    context: &'a Context,
+    /// ID of the request that will run the command
+    request_id: Uuid,
+    /// Command and arguments
+    command: Vec<String>,
+    /// Environment variables
+    env: HashMap<String, String>,
+    /// Working directory
+    cwd: Option<PathBuf>,
+    /// Standard input data
+    stdin: Option<Bytes>,
+    /// Local file streamed as standard input
+    stdin_file: Option<PathBuf>,
+    /// Timeout in seconds
+    timeout: Option<u64>,
+    /// Resource limits for the remote process
+    limits: Option<ProcessLimits>,
+    /// Split streamed output on newlines
+    line_buffered: bool,
+    /// Longest buffered line
+    max_line_length: Option<usize>,
+    /// Send stderr into stdout
+    merge_stderr: bool,
+    /// Capture limit per output stream
+    max_output_bytes: Option<u64>,
+    /// Part of the output kept past the capture limit
+    output_truncation: OutputTruncation,
+    /// Kill the process when the capture limit is hit
+    kill_on_output_limit: bool,
+    /// Expand `${VAR}` from the command's environment on the agent
+    expand_env: bool,
+    /// Extra descriptors the process writes output to
+    output_fds: Vec<u32>,
+    /// Leave the process running and return its PID
+    detach: bool,
+    /// Remote file stdout is written into
+    stdout_file: Option<OutputFile>,
+    /// Remote file stderr is written into
+    stderr_file: Option<OutputFile>,
+    /// Remote env file the agent reads more variables from
+    env_file: Option<EnvFile>,
+}
+
+impl CommandBuilder<'_> {
+    /// Request ID the command runs under, usable with [`Context::signal`]
+    pub fn id(&self) -> Uuid {
+        self.request_id
+    }
+    
+    /// Set an environment variable
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.insert(key.into(), value.into());
+        self
+    }
+    
+    /// Load variables from an env file on the remote host
+    ///
+    /// Variables set with [`CommandBuilder::env`] take precedence over the file's.
+    pub fn env_file(mut self, file: EnvFile) -> Self {
+        self.env_file = Some(file);
+        self
+    }
+    
+    /// Drop an environment variable inherited from the session defaults
+    pub fn env_remove(mut self, key: &str) -> Self {
+        self.env.remove(key);
+        self
+    }
+    
+    /// Drop all environment variables inherited from the session defaults
+    pub fn env_clear(mut self) -> Self {
+        self.env.clear();
+        self
+    }
+    
+    /// Set the working directory
+    pub fn cwd(mut self, cwd: impl Into<PathBuf>) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+    
+    /// Run in the agent's own working directory, ignoring the session default
+    pub fn clear_cwd(mut self) -> Self {
+        self.cwd = None;
+        self
+    }
+    
+    /// Send the given bytes as standard input
+    pub fn stdin(mut self, stdin: impl Into<Bytes>) -> Self {
+        self.stdin = Some(stdin.into());
+        self.stdin_file = None;
+        self
+    }
+    
+    /// Stream a local file into the process's standard input
+    ///
+    /// The file is sent in chunks as it is read, so it never has to fit in
+    /// memory, and stdin is closed once the end of the file is reached.
+    pub fn stdin_from_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.stdin_file = Some(path.into());
+        self.stdin = None;
+        self
+    }
+    
+    /// Set the execution timeout (None disables it)
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout.map(|t| t.as_secs());
+        self
+    }
+    
+    /// Limit the resources the remote process may use
+    pub fn limits(mut self, limits: ProcessLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+    
+    /// Split streamed output into whole lines, flushing lines longer than
+    /// `max_line_length` (agent default when None) as-is
+    pub fn line_buffered(mut self, max_line_length: Option<usize>) -> Self {
+        self.line_buffered = true;
+        self.max_line_length = max_line_length;
+        self
+    }
+    
+    /// Capture stderr interleaved with stdout, leaving stderr empty
+    pub fn merge_stderr(mut self, merge: bool) -> Self {
+        self.merge_stderr = merge;
+        self
+    }
+    
+    /// Capture at most `max_bytes` of stdout and of stderr, keeping the part
+    /// chosen by `truncation` (agent default limit when not set)
+    pub fn max_output_bytes(mut self, max_bytes: u64, truncation: OutputTruncation) -> Self {
+        self.max_output_bytes = Some(max_bytes);
+        self.output_truncation = truncation;
+        self
+    }
+    
+    /// Capture only the last `max_bytes` of stdout and of stderr
+    ///
+    /// Earlier output is dropped as it arrives, so memory stays bounded however
+    /// much the process prints; [`ProcessOutput::truncated`] tells whether any was.
+    pub fn tail_output(self, max_bytes: u64) -> Self {
+        self.max_output_bytes(max_bytes, OutputTruncation::Tail)
+    }
+    
+    /// Kill the process as soon as its output exceeds the capture limit
+    pub fn kill_on_output_limit(mut self, kill: bool) -> Self {
+        self.kill_on_output_limit = kill;
+        self
+    }
+    
+    /// Expand `${VAR}` tokens in the command and working directory on the agent
+    ///
+    /// Only variables set with [`CommandBuilder::env`] (or the session defaults)
+    /// are used, never the remote environment. `${VAR:-fallback}` supplies a
+    /// default and `$$` is a literal `$`; referencing an undefined variable
+    /// fails the command before it starts.
+    pub fn expand_env(mut self, expand: bool) -> Self {
+        self.expand_env = expand;
+        self
+    }
+    
+    /// Give the process an extra output descriptor `fd` (3 or higher)
+    ///
+    /// What it writes there is returned in [`ProcessOutput::fd_output`], or
+    /// as [`ProcessEvent::Fd`] when streaming. Only supported by Unix agents.
+    pub fn output_fd(mut self, fd: u32) -> Self {
+        if !self.output_fds.contains(&fd) {
+            self.output_fds.push(fd);
+        }
+        self
+    }
+    
+    /// Write stdout into a file on the remote host instead of returning it
+    ///
+    /// The agent hands the file to the process as its stdout, so the output
+    /// never crosses the connection; [`ProcessOutput::stdout_written`] tells
+    /// how much was written. With [`CommandBuilder::merge_stderr`], stderr
+    /// goes into the file too.
+    pub fn stdout_to_file(mut self, file: OutputFile) -> Self {
+        self.stdout_file = Some(file);
+        self
+    }
+    
+    /// Write stderr into a file on the remote host instead of returning it
+    pub fn stderr_to_file(mut self, file: OutputFile) -> Self {
+        self.stderr_file = Some(file);
+        self
+    }
+    
+    /// Run the command and wait for it to finish
+    pub async fn run(self) -> Result<ProcessOutput> {
+        let context = self.context;
+        let (request, stdin_file) = self.into_request(false);
+        
+        let Some(path) = stdin_file else {
+            let response = context.send_request(request).await?;
+            return ProcessOutput::from_response(response);
+        };
+        
+        let input = stream_file(&path).await?;
+        let response = context.router
+            .send_message_with_input(context.message(request), input).await?;
+        ProcessOutput::from_response(response)
+    }
+    
+    /// Start the command detached from the agent and return its remote PID
+    ///
+    /// The process runs in a session of its own with its stdio on the null
+    /// device and keeps running after this connection closes, so it suits
+    /// daemons. It can't be given stdin, and its output isn't captured.
+    pub async fn spawn_detached(mut self) -> Result<u32> {
+        self.detach = true;
+        let context = self.context;
+        // The agent rejects stdin, so a stdin file is never streamed
+        let (request, _) = self.into_request(false);
+        
+        match context.send_request(request).await? {
+            Response::ProcessResult { pid: Some(pid), .. } => Ok(pid),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Detached process failed to start: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Start the command and receive its output as it is produced
+    pub async fn stream(self) -> Result<ProcessStream> {
+        let context = self.context;
+        let (request, stdin_file) = self.into_request(true);
+        
+        let input = match stdin_file {
+            Some(path) => Some(stream_file(&path).await?),
+            None => None,
+        };
+        let responses = context.router
+            .send_message_streaming(context.message(request), input).await?;
+        
+        Ok(ProcessStream { responses: ResponseStream::new(responses, None) })
+    }
+    
+    /// Build the request, returning the stdin file to stream alongside it
+    fn into_request(self, stream_output: bool) -> (Request, Option<PathBuf>) {
+        debug!("Executing process: {:?}", self.command);
+        
+        let request = Request::ProcessExec {
+            id: self.request_id,
+            command: self.command,
+            env: self.env,
+            cwd: self.cwd,
+            stdin: self.stdin,
+            timeout: self.timeout,
+            stdin_stream: self.stdin_file.is_some(),
+            limits: self.limits,
+            stream_output,
+            line_buffered: self.line_buffered,
+            max_line_length: self.max_line_length,
+            merge_stderr: self.merge_stderr,
+            max_output_bytes: self.max_output_bytes,
+            output_truncation: self.output_truncation,
+            kill_on_output_limit: self.kill_on_output_limit,
+            expand_env: self.expand_env,
+            output_fds: self.output_fds,
+            detach: self.detach,
+            stdout_file: self.stdout_file,
+            stderr_file: self.stderr_file,
+            env_file: self.env_file,
+        };
+        
+        (request, self.stdin_file)
+    }
+}
+
+/// Open a local file and read it into a channel of chunks in the background
+async fn stream_file(path: &Path) -> Result<mpsc::Receiver<Bytes>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let path = path.to_path_buf();
+    
+    let (chunk_tx, chunk_rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        loop {
+            let mut buf = vec![0u8; STDIN_CHUNK_SIZE];
+            match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf.truncate(n);
+                    if chunk_tx.send(Bytes::from(buf)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to read stdin file {:?}: {}", path, e);
+                    break;
+                }
+            }
+        }
+    });
+    
+    Ok(chunk_rx)
+}
+
+/// Read a file being uploaded into a channel of chunks in the background
+///
+/// The channel holds a single chunk, so reading keeps pace with the upload.
+/// The channel closes once the whole file is read. If reading fails, the
+/// failure arrives on the returned receiver together with the sender, so the
+/// stream isn't ended as if the file were complete.
+fn upload_file(mut file: tokio::fs::File) -> (mpsc::Receiver<Bytes>, oneshot::Receiver<(std::io::Error, mpsc::Sender<Bytes>)>) {
+    let (chunk_tx, chunk_rx) = mpsc::channel(1);
+    let (failed_tx, failed_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        loop {
+            let mut chunk = BytesMut::with_capacity(PUT_CHUNK_SIZE);
+            match file.read_buf(&mut chunk).await {
+                Ok(0) => break,
+                Ok(_) => {
+                    if chunk_tx.send(chunk.freeze()).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = failed_tx.send((e, chunk_tx));
+                    break;
+                }
+            }
+        }
+    });
+    
+    (chunk_rx, failed_rx)
+}
+
+/// Responses to a request sent with [`Context::stream_request`]
+///
+/// Yields each partial response as it arrives and ends after the final one.
+/// An error response from the agent is yielded as an error and also ends the stream.
+/// The data of a request with an output window is granted back to the agent
+/// as it is yielded; dropping the stream before the end cancels the request.
+/// [`ProcessStream`], [`FileTail`] and [`DirListStream`] are typed views of one.
+pub struct ResponseStream {
+    /// Responses for the request, ending with the final one
+    responses: mpsc::UnboundedReceiver<Response>,
+    /// Credit for the data the agent sends, if the request has an output window
+    window: Option<OutputWindow>,
+    /// Whether the final response has been returned
+    finished: bool,
+}
+
+impl ResponseStream {
+    /// Stream the responses arriving on `responses`, granting their data back to `window`
+    fn new(responses: mpsc::UnboundedReceiver<Response>, window: Option<OutputWindow>) -> Self {
+        Self { responses, window, finished: false }
+    }
+    
+    /// Wait for every response, partial ones first and the final one last
+    pub async fn collect(mut self) -> Result<Vec<Response>> {
+        let mut responses = Vec::new();
+        while let Some(response) = futures::StreamExt::next(&mut self).await {
+            responses.push(response?);
+        }
+        Ok(responses)
+    }
+}
+
+impl Stream for ResponseStream {
+    type Item = Result<Response>;
+    
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        
+        let Some(response) = std::task::ready!(self.responses.poll_recv(cx)) else {
+            self.finished = true;
+            return Poll::Ready(Some(Err(MitoxideError::protocol("Response stream closed before completion".to_string()))));
+        };
+        
+        if response.is_partial() {
+            if let (Some(window), Response::FileChunk { data, .. }) = (&self.window, &response) {
+                window.grant(data.len());
+            }
+            return Poll::Ready(Some(Ok(response)));
+        }
+        self.finished = true;
+        match response {
+            Response::Error { error, .. } => Poll::Ready(Some(Err(MitoxideError::agent(format!("Request failed: {}", error.message))))),
+            response => Poll::Ready(Some(Ok(response))),
+        }
+    }
+}
+
+impl Drop for ResponseStream {
+    fn drop(&mut self) {
+        // Without a reader the agent would wait for credit until the connection closed
+        if let (false, Some(window)) = (self.finished, &self.window) {
+            window.reset();
+        }
+    }
+}
+
+/// Event from a streaming process execution
+#[derive(Debug, Clone)]
+pub enum ProcessEvent {
+    /// Data written to standard output
+    Stdout(Bytes),
+    /// Data written to standard error
+    Stderr(Bytes),
+    /// Data written to an extra descriptor asked for with [`CommandBuilder::output_fd`]
+    Fd(u32, Bytes),
+    /// The process finished; streamed output is not repeated here
+    Exited(ProcessOutput),
+}
+
+/// Output of a process started with [`CommandBuilder::stream`]
+pub struct ProcessStream {
+    /// Responses for the request, ending with the final result
+    responses: ResponseStream,
+}
+
+impl ProcessStream {
+    /// Wait for the next event, returning None after the process has exited
+    pub async fn next(&mut self) -> Option<Result<ProcessEvent>> {
+        futures::StreamExt::next(self).await
+    }
+}
+
+impl Stream for ProcessStream {
+    type Item = Result<ProcessEvent>;
+    
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let response = std::task::ready!(Pin::new(&mut self.responses).poll_next(cx));
+        Poll::Ready(response.map(|response| match response? {
+            Response::ProcessOutput { stream: OutputStream::Stdout, data, .. } => Ok(ProcessEvent::Stdout(data)),
+            Response::ProcessOutput { stream: OutputStream::Stderr, data, .. } => Ok(ProcessEvent::Stderr(data)),
+            Response::ProcessOutput { stream: OutputStream::Fd(fd), data, .. } => Ok(ProcessEvent::Fd(fd, data)),
+            response => ProcessOutput::from_response(response).map(ProcessEvent::Exited),
+        }))
+    }
+}
+
+/// Lines of a remote file read with [`Context::tail`]
+pub struct FileTail {
+    /// Responses for the request, ending with `FileTailEnded`
+    responses: ResponseStream,
+    /// Dropping this ends the request stream, which stops a followed tail
+    stop_tx: Option<mpsc::Sender<Bytes>>,
+}
+
+impl FileTail {
+    /// Wait for the next chunk of lines, returning None once the tail has ended
+    pub async fn next(&mut self) -> Option<Result<Bytes>> {
+        futures::StreamExt::next(self).await
+    }
+    
+    /// Stop following the file; lines already read are still returned by [`FileTail::next`]
+    pub fn stop(&mut self) {
+        self.stop_tx = None;
+    }
+}
+
+impl Stream for FileTail {
+    type Item = Result<Bytes>;
+    
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let response = std::task::ready!(Pin::new(&mut self.responses).poll_next(cx));
+        Poll::Ready(match response {
+            Some(Ok(Response::FileChunk { data, .. })) => Some(Ok(data)),
+            Some(Ok(Response::FileTailEnded { .. })) | None => None,
+            Some(Ok(_)) => Some(Err(MitoxideError::protocol("Unexpected response type".to_string()))),
+            Some(Err(e)) => Some(Err(e)),
+        })
+    }
+}
+
+/// Acknowledgements queued for the agent before [`DirListStream`] waits
+///
+/// The agent sends no more batches ahead than this, so there is always room
+/// to acknowledge the one just taken.
+const DIR_LIST_ACKS: usize = 4;
+
+/// Entries of a remote directory listed with [`Context::list_dir_stream`]
+pub struct DirListStream {
+    /// Responses for the request, ending with `DirListing`
+    responses: ResponseStream,
+    /// Acknowledges each batch taken; dropping it cancels the listing
+    ack_tx: Option<mpsc::Sender<Bytes>>,
+    /// Entries of the current batch not yet returned by [`DirListStream::next`]
+    buffered: VecDeque<DirEntry>,
+}
+
+impl DirListStream {
+    /// Wait for the next batch of entries, returning None once the listing is complete
+    pub async fn next_batch(&mut self) -> Option<Result<Vec<DirEntry>>> {
+        std::future::poll_fn(|cx| self.poll_next_batch(cx)).await
+    }
+    
+    /// Wait for the next entry, returning None once the listing is complete
+    pub async fn next(&mut self) -> Option<Result<DirEntry>> {
+        futures::StreamExt::next(self).await
+    }
+    
+    /// Poll for the next batch of entries, acknowledging it to the agent
+    fn poll_next_batch(&mut self, cx: &mut TaskContext<'_>) -> Poll<Option<Result<Vec<DirEntry>>>> {
+        if !self.buffered.is_empty() {
+            return Poll::Ready(Some(Ok(self.buffered.drain(..).collect())));
+        }
+        
+        let response = std::task::ready!(Pin::new(&mut self.responses).poll_next(cx));
+        Poll::Ready(match response {
+            Some(Ok(Response::DirEntries { entries, .. })) => {
+                // Let the agent send another batch in place of this one
+                if let Some(ack_tx) = &self.ack_tx {
+                    let _ = ack_tx.try_send(Bytes::from_static(b"\x01"));
+                }
+                Some(Ok(entries))
+            }
+            Some(Ok(Response::DirListing { entries, .. })) => {
+                self.ack_tx = None;
+                (!entries.is_empty()).then_some(Ok(entries))
+            }
+            Some(Ok(_)) => Some(Err(MitoxideError::protocol("Unexpected response type".to_string()))),
+            Some(Err(e)) => Some(Err(e)),
+            None => None,
+        })
+    }
+}
+
+impl Stream for DirListStream {
+    type Item = Result<DirEntry>;
+    
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        while self.buffered.is_empty() {
+            match std::task::ready!(self.poll_next_batch(cx)) {
+                Some(Ok(entries)) => self.buffered.extend(entries),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+        Poll::Ready(self.buffered.pop_front().map(Ok))
+    }
+}
+
+/// Agent session opened with [`Context::open_session`]
+#[derive(Debug, Clone)]
+pub struct AgentSession {
+    /// Token to present when reconnecting
+    pub token: Uuid,
+    /// Whether an earlier session was resumed
+    pub resumed: bool,
+    /// Final responses the agent retained for the resumed session
+    pub responses: Vec<Response>,
+    /// Most requests the agent handles at once, if it is limited
+    pub max_concurrent_requests: Option<u32>,
+    /// Requests the agent was handling when the session opened
+    pub in_flight: u32,
+    /// Stream compression in effect on the connection
+    pub compression: Option<StreamCompression>,
+    /// ID of the dictionary stream compression is primed with, if both ends have it
+    pub dictionary_id: Option<u32>,
+    /// Size from which both ends send file content as frame attachments, if they do
+    pub attachment_threshold: Option<u32>,
+}
+
+/// Result of an upload with [`Context::put_with_policy`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PutOutcome {
+    /// Bytes written, 0 if the upload was skipped
+    pub bytes_written: u64,
+    /// The file already existed and was left as it was
+    pub skipped: bool,
+    /// Where the existing file was moved before being replaced
+    pub backup_path: Option<PathBuf>,
+}
+
 /// Process execution output
 #[derive(Debug, Clone)]
 pub struct ProcessOutput {
@@ -257,9 +1684,48 @@ pub struct ProcessOutput {
     pub stderr: Bytes,
     /// Execution duration
     pub duration: Duration,
+    /// Output exceeded the agent's capture limit and was cut short
+    pub truncated: bool,
+    /// The process ran past its timeout and was killed
+    pub timed_out: bool,
+    /// Whether the process exited or was killed by a signal, and which, if the agent reported it
+    pub termination: Option<Termination>,
+    /// Output written to the extra descriptors asked for with [`CommandBuilder::output_fd`]
+    pub fd_output: HashMap<u32, Bytes>,
+    /// Bytes written to the remote file given to [`CommandBuilder::stdout_to_file`]
+    pub stdout_written: Option<u64>,
+    /// Bytes written to the remote file given to [`CommandBuilder::stderr_to_file`]
+    pub stderr_written: Option<u64>,
 }
 
 impl ProcessOutput {
+    /// Convert a process execution response into output
+    fn from_response(response: Response) -> Result<Self> {
+        match response {
+            Response::ProcessResult {
+                exit_code, stdout, stderr, duration_ms, truncated, timed_out, termination, fd_output,
+                stdout_written, stderr_written, ..
+            } => {
+                Ok(ProcessOutput {
+                    exit_code,
+                    stdout,
+                    stderr,
+                    duration: Duration::from_millis(duration_ms),
+                    truncated,
+                    timed_out,
+                    termination,
+                    fd_output,
+                    stdout_written,
+                    stderr_written,
+                })
+            }
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Process execution failed: {}", error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
     /// Check if the process succeeded (exit code 0)
     pub fn success(&self) -> bool {
         self.exit_code == 0
@@ -268,13 +1734,13 @@ impl ProcessOutput {
     /// Get stdout as UTF-8 string
     pub fn stdout_string(&self) -> Result<String> {
         String::from_utf8(self.stdout.to_vec())
-            .map_err(|e| MitoxideError::Protocol(format!("Invalid UTF-8 in stdout: {}", e)))
+            .map_err(|e| MitoxideError::protocol(format!("Invalid UTF-8 in stdout: {}", e)))
     }
     
     /// Get stderr as UTF-8 string
     pub fn stderr_string(&self) -> Result<String> {
         String::from_utf8(self.stderr.to_vec())
-            .map_err(|e| MitoxideError::Protocol(format!("Invalid UTF-8 in stderr: {}", e)))
+            .map_err(|e| MitoxideError::protocol(format!("Invalid UTF-8 in stderr: {}", e)))
     }
 }
 
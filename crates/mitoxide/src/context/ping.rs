@@ -0,0 +1,132 @@
+//! Link measurement with batches of pings
+
+use super::Context;
+use crate::{MitoxideError, Result};
+use bytes::Bytes;
+use mitoxide_proto::message::PingProbe;
+use mitoxide_proto::{Request, Response};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// How long pongs are waited for after the last probe before the rest count as lost
+const PING_BATCH_GRACE: Duration = Duration::from_secs(2);
+
+/// Probes queued for the agent before [`Context::ping_batch`] waits
+const PING_PROBE_CAPACITY: usize = 16;
+
+/// Round-trip times of the probes of a [`Context::ping_batch`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingStats {
+    /// Probes sent
+    pub sent: u32,
+    /// Probes whose pong came back
+    pub received: u32,
+    /// Fastest round trip
+    pub min: Duration,
+    /// Mean round trip
+    pub avg: Duration,
+    /// Slowest round trip
+    pub max: Duration,
+    /// Standard deviation of the round trips, the jitter
+    pub stddev: Duration,
+}
+
+impl PingStats {
+    /// Statistics of `sent` probes, of which these round trips came back
+    pub fn from_round_trips(sent: u32, round_trips: &[Duration]) -> Self {
+        let received = round_trips.len() as u32;
+        if round_trips.is_empty() {
+            return Self { sent, received, min: Duration::ZERO, avg: Duration::ZERO, max: Duration::ZERO, stddev: Duration::ZERO };
+        }
+        
+        let secs: Vec<f64> = round_trips.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance = secs.iter().map(|rtt| (rtt - mean).powi(2)).sum::<f64>() / secs.len() as f64;
+        Self {
+            sent,
+            received,
+            min: round_trips.iter().copied().min().unwrap_or_default(),
+            avg: Duration::from_secs_f64(mean),
+            max: round_trips.iter().copied().max().unwrap_or_default(),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+    
+    /// Fraction of probes that never came back, from 0 to 1
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        f64::from(self.sent - self.received) / f64::from(self.sent)
+    }
+}
+
+impl Context {
+    /// Measure the link with `count` pings of `payload_size` bytes, one every `interval`
+    ///
+    /// Each probe's round trip is timed from when it is sent until its pong
+    /// arrives. Probes not answered within a grace period after the last one
+    /// count as lost.
+    pub async fn ping_batch(&self, count: u32, interval: Duration, payload_size: u32) -> Result<PingStats> {
+        debug!("Pinging remote host {} times every {:?}", count, interval);
+        
+        let request = Request::ping_batch(count, interval.as_millis() as u64, payload_size);
+        let (input_tx, input_rx) = mpsc::channel(PING_PROBE_CAPACITY);
+        let mut responses = self.router
+            .send_message_streaming(self.message(request), Some(input_rx)).await?;
+        
+        let start = Instant::now();
+        let padding = Bytes::from(vec![0u8; payload_size as usize]);
+        let mut input = Some(input_tx);
+        let mut sent = 0;
+        let mut round_trips = vec![None; count as usize];
+        let mut next_probe = tokio::time::Instant::now();
+        let mut deadline = None;
+        
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_probe), if sent < count => {
+                    let probe = PingProbe {
+                        sequence: sent,
+                        timestamp: start.elapsed().as_micros() as u64,
+                        payload: padding.clone(),
+                    };
+                    let payload = probe.to_bytes()
+                        .map_err(|e| MitoxideError::protocol(e.to_string()))?;
+                    if let Some(tx) = &input {
+                        tx.send(payload).await
+                            .map_err(|_| MitoxideError::protocol("Ping batch closed before completion".to_string()))?;
+                    }
+                    sent += 1;
+                    next_probe += interval;
+                    if sent == count {
+                        input = None;
+                        deadline = Some(tokio::time::Instant::now() + PING_BATCH_GRACE);
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {
+                    debug!("Gave up waiting for the remaining pongs");
+                    break;
+                }
+                response = responses.recv() => match response {
+                    Some(Response::Pong { sequence: Some(sequence), timestamp, .. }) => {
+                        let round_trip = start.elapsed().saturating_sub(Duration::from_micros(timestamp));
+                        if let Some(slot) = round_trips.get_mut(sequence as usize) {
+                            *slot = Some(round_trip);
+                        }
+                    }
+                    Some(Response::PingBatchResult { .. }) => break,
+                    Some(Response::Error { error, .. }) => {
+                        return Err(MitoxideError::agent(format!("Ping batch failed: {}", error.message)));
+                    }
+                    Some(_) => return Err(MitoxideError::protocol("Unexpected response type".to_string())),
+                    None => return Err(MitoxideError::protocol("Ping batch closed before completion".to_string())),
+                },
+            }
+        }
+        
+        let round_trips: Vec<Duration> = round_trips.into_iter().flatten().collect();
+        Ok(PingStats::from_round_trips(sent, &round_trips))
+    }
+}
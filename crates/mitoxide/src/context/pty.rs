@@ -0,0 +1,133 @@
+//! Interactive commands on a remote pseudoterminal
+
+use super::Context;
+use crate::{MitoxideError, Result};
+use bytes::Bytes;
+use mitoxide_proto::message::{OutputStream, PtyInput, PtySize};
+use mitoxide_proto::{Request, Response};
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tracing::debug;
+use uuid::Uuid;
+
+/// Client input queued for the agent before [`PtySession`] writes wait
+const PTY_INPUT_CAPACITY: usize = 16;
+
+/// Event from an interactive command started with [`Context::pty`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PtyEvent {
+    /// Bytes the terminal displayed, including the echo of typed input
+    Output(Bytes),
+    /// The command exited with this code
+    Exited(i32),
+}
+
+/// An interactive command on a remote pseudoterminal
+///
+/// Keystrokes written with [`PtySession::write`] reach the command as if
+/// typed on its terminal, and [`PtySession::next`] returns what the terminal
+/// displays until the command exits.
+pub struct PtySession {
+    /// Request ID of the `PtyExec`
+    request_id: Uuid,
+    /// Responses for the request, ending with `PtyResult`
+    responses: mpsc::UnboundedReceiver<Response>,
+    /// Encoded input for the agent; dropping it ends the request stream
+    input: Option<mpsc::Sender<Bytes>>,
+    /// Whether the command has exited
+    finished: bool,
+}
+
+impl Context {
+    /// Start `command` on a remote pseudoterminal of the given size and interact with it
+    ///
+    /// The command sees this process's `TERM`, if it has one.
+    pub async fn pty(&self, command: &[&str], window: PtySize) -> Result<PtySession> {
+        self.start_pty(command, window, std::env::var("TERM").ok()).await
+    }
+    
+    /// Start `command` on a remote pseudoterminal of type `term`, as [`Context::pty`]
+    pub async fn pty_with_term(&self, command: &[&str], window: PtySize, term: &str) -> Result<PtySession> {
+        self.start_pty(command, window, Some(term.to_string())).await
+    }
+    
+    /// Send the `PtyExec` for an interactive command
+    async fn start_pty(&self, command: &[&str], window: PtySize, term: Option<String>) -> Result<PtySession> {
+        debug!("Starting interactive PTY: {:?} ({}x{}, TERM={:?})", command, window.cols, window.rows, term);
+        
+        let mut request = Request::pty_exec(command.iter().map(|s| s.to_string()).collect(), HashMap::new(), None, None);
+        if let Request::PtyExec { interactive, window: size, term: term_type, .. } = &mut request {
+            *interactive = true;
+            *size = Some(window);
+            *term_type = term;
+        }
+        let request_id = request.id();
+        let (input_tx, input_rx) = mpsc::channel(PTY_INPUT_CAPACITY);
+        let responses = self.router
+            .send_message_streaming(self.message(request), Some(input_rx)).await?;
+        
+        Ok(PtySession { request_id, responses, input: Some(input_tx), finished: false })
+    }
+}
+
+impl PtySession {
+    /// Request ID of the command, for [`Context::signal`]
+    pub fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+    
+    /// Type `data` into the terminal
+    pub async fn write(&self, data: impl Into<Bytes>) -> Result<()> {
+        self.send(PtyInput::Data(data.into())).await
+    }
+    
+    /// Change the terminal's window size; the command gets `SIGWINCH`
+    pub async fn resize(&self, window: PtySize) -> Result<()> {
+        self.send(PtyInput::Resize(window)).await
+    }
+    
+    /// Stop sending input; the command keeps running until it exits
+    pub fn close_input(&mut self) {
+        self.input = None;
+    }
+    
+    /// Wait for the next event, returning None after the command has exited
+    pub async fn next(&mut self) -> Option<Result<PtyEvent>> {
+        if self.finished {
+            return None;
+        }
+        
+        let Some(response) = self.responses.recv().await else {
+            self.finished = true;
+            return Some(Err(MitoxideError::protocol("PTY stream closed before completion".to_string())));
+        };
+        
+        match response {
+            Response::ProcessOutput { stream: OutputStream::Stdout, data, .. } => Some(Ok(PtyEvent::Output(data))),
+            Response::PtyResult { exit_code, .. } => {
+                self.finished = true;
+                self.input = None;
+                Some(Ok(PtyEvent::Exited(exit_code)))
+            }
+            Response::Error { error, .. } => {
+                self.finished = true;
+                Some(Err(MitoxideError::agent(format!("PTY command failed: {}", error.message))))
+            }
+            _ => {
+                self.finished = true;
+                Some(Err(MitoxideError::protocol("Unexpected response type".to_string())))
+            }
+        }
+    }
+    
+    /// Queue one frame of input for the agent
+    async fn send(&self, input: PtyInput) -> Result<()> {
+        let Some(tx) = &self.input else {
+            return Err(MitoxideError::protocol("PTY input is closed".to_string()));
+        };
+        let payload = input.to_bytes()
+            .map_err(|e| MitoxideError::protocol(e.to_string()))?;
+        tx.send(payload).await
+            .map_err(|_| MitoxideError::protocol("PTY input is closed".to_string()))
+    }
+}
@@ -0,0 +1,302 @@
+//! Mirroring a local directory tree onto the remote host
+
+use super::Context;
+use crate::{MitoxideError, Result};
+use bytes::Bytes;
+use mitoxide_proto::delta::{self, Signature};
+use mitoxide_proto::message::{ErrorCode, FileMetadata, HashAlgorithm};
+use mitoxide_proto::{Request, Response};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+/// What [`Context::sync`] does besides copying changed files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncOptions {
+    /// Remove remote files and directories that don't exist locally
+    pub delete: bool,
+    /// Give remote files their local permission bits
+    pub preserve_mode: bool,
+    /// Give remote files their local modification time
+    pub preserve_times: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            delete: false,
+            preserve_mode: true,
+            preserve_times: true,
+        }
+    }
+}
+
+/// What a [`Context::sync`] did, by path relative to the synced directories
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Files that were missing or different remotely and were uploaded
+    pub transferred: Vec<PathBuf>,
+    /// Files whose size and hash already matched
+    pub skipped: Vec<PathBuf>,
+    /// Skipped files whose permission bits or modification time were set
+    pub attributes_updated: Vec<PathBuf>,
+    /// Remote entries removed because they don't exist locally
+    pub deleted: Vec<PathBuf>,
+    /// Content bytes uploaded; for files sent as a delta, only the data the
+    /// remote copy didn't already have
+    pub bytes_transferred: u64,
+}
+
+/// A file found in the local tree
+#[derive(Debug, Clone, Copy)]
+struct LocalFile {
+    /// Size in bytes
+    size: u64,
+    /// Permission bits, where the platform has them
+    mode: Option<u32>,
+    /// Modification time in seconds since the Unix epoch
+    modified: u64,
+}
+
+/// Files and directories of the local tree, by relative path
+#[derive(Debug, Default)]
+struct LocalTree {
+    /// Regular files
+    files: BTreeMap<PathBuf, LocalFile>,
+    /// Directories below the root
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl Context {
+    /// Mirror the tree under `local_dir` onto `remote_dir`
+    ///
+    /// Remote files of the same size are hashed on the agent and compared
+    /// with a local hash, so only files that are missing or differ are
+    /// uploaded. Missing files are uploaded in full; for files that differ,
+    /// only the parts the remote copy doesn't already have are sent, as a
+    /// delta against its block signature. Files whose content already
+    /// matches only get their mode and modification time set, where those
+    /// differ. Symlinks and empty directories are not copied. With
+    /// [`SyncOptions::delete`], remote entries with no local counterpart are
+    /// removed.
+    pub async fn sync(&self, local_dir: &Path, remote_dir: &Path, options: SyncOptions) -> Result<SyncReport> {
+        info!("Syncing {:?} to {:?}", local_dir, remote_dir);
+        
+        let root = local_dir.to_path_buf();
+        let local = tokio::task::spawn_blocking(move || walk_local(&root)).await
+            .map_err(|e| MitoxideError::agent(format!("Local directory walk failed: {}", e)))?
+            .map_err(|e| MitoxideError::agent(format!("Failed to read local directory {:?}: {}", local_dir, e)))?;
+        let remote = self.remote_tree(remote_dir).await?;
+        
+        let mut report = SyncReport::default();
+        for (relative, file) in &local.files {
+            let local_path = local_dir.join(relative);
+            let remote_path = remote_dir.join(relative);
+            let mode = file.mode.filter(|_| options.preserve_mode);
+            let modified = Some(file.modified).filter(|_| options.preserve_times);
+            
+            let existing = remote.get(relative).filter(|metadata| !metadata.is_dir && !metadata.is_symlink);
+            let Some(existing) = existing else {
+                debug!("Uploading {:?}", relative);
+                report.bytes_transferred += self.put_whole(&local_path, &remote_path, mode, modified).await?;
+                report.transferred.push(relative.clone());
+                continue;
+            };
+            
+            let unchanged = existing.size == file.size
+                && self.hash(&remote_path, HashAlgorithm::Sha256).await?.0 == local_hash(local_path.clone()).await?;
+            if unchanged {
+                debug!("Skipping unchanged {:?}", relative);
+                let mode = mode.filter(|mode| *mode != existing.mode & 0o7777);
+                let modified = modified.filter(|modified| *modified != existing.modified);
+                if mode.is_some() || modified.is_some() {
+                    self.set_remote_attributes(&remote_path, mode, modified).await?;
+                    report.attributes_updated.push(relative.clone());
+                }
+                report.skipped.push(relative.clone());
+                continue;
+            }
+            
+            debug!("Uploading changes to {:?}", relative);
+            report.bytes_transferred += self.put_delta(&local_path, &remote_path, existing.size, mode, modified).await?;
+            report.transferred.push(relative.clone());
+        }
+        
+        if options.delete {
+            // Removing a directory removes what is below it, so only the topmost extraneous entries are named
+            let extraneous: Vec<PathBuf> = remote.keys()
+                .filter(|relative| !local.files.contains_key(*relative) && !local.dirs.contains(*relative))
+                .filter(|relative| !relative.ancestors().skip(1).any(|parent| {
+                    remote.contains_key(parent) && !local.dirs.contains(parent)
+                }))
+                .cloned()
+                .collect();
+            for relative in extraneous {
+                let recursive = remote.get(&relative).is_some_and(|metadata| metadata.is_dir);
+                self.remove_remote(&remote_dir.join(&relative), recursive).await?;
+                report.deleted.push(relative);
+            }
+        }
+        
+        info!(
+            "Synced {:?}: {} transferred ({} bytes), {} unchanged, {} deleted",
+            remote_dir, report.transferred.len(), report.bytes_transferred, report.skipped.len(), report.deleted.len()
+        );
+        Ok(report)
+    }
+    
+    /// Metadata of every entry under `remote_dir`, by relative path; empty if it doesn't exist
+    async fn remote_tree(&self, remote_dir: &Path) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        let request = Request::dir_list(remote_dir.to_path_buf(), true, true);
+        match self.send_request(request).await? {
+            Response::DirListing { entries, .. } => Ok(entries.into_iter()
+                .filter_map(|entry| {
+                    let relative = entry.path.strip_prefix(remote_dir).ok()?.to_path_buf();
+                    Some((relative, entry.metadata))
+                })
+                .collect()),
+            Response::Error { error, .. } if error.code == ErrorCode::FileNotFound => Ok(BTreeMap::new()),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Failed to list {:?}: {}", remote_dir, error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Upload the whole of `local_path`, returning the bytes written
+    async fn put_whole(&self, local_path: &Path, remote_path: &Path, mode: Option<u32>, modified: Option<u64>) -> Result<u64> {
+        let content = tokio::fs::read(local_path).await
+            .map_err(|e| MitoxideError::agent(format!("Failed to read local file {:?}: {}", local_path, e)))?;
+        let mut request = Request::file_put(remote_path.to_path_buf(), Bytes::from(content), mode, true);
+        if let Request::FilePut { modified: put_modified, .. } = &mut request {
+            *put_modified = modified;
+        }
+        match self.send_request(request).await? {
+            Response::FilePutResult { bytes_written, .. } => Ok(bytes_written),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Sync of {:?} failed: {}", remote_path, error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Upload what of `local_path` the remote copy of `remote_size` bytes lacks, returning the bytes sent
+    async fn put_delta(
+        &self,
+        local_path: &Path,
+        remote_path: &Path,
+        remote_size: u64,
+        mode: Option<u32>,
+        modified: Option<u64>,
+    ) -> Result<u64> {
+        let request = Request::file_signature(remote_path.to_path_buf(), delta::block_size_for(remote_size));
+        let signature: Signature = match self.send_request(request).await? {
+            Response::FileSignature { signature, .. } => signature,
+            Response::Error { error, .. } => {
+                return Err(MitoxideError::agent(format!("Signature of {:?} failed: {}", remote_path, error.message)));
+            }
+            _ => return Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        };
+        
+        let content = tokio::fs::read(local_path).await
+            .map_err(|e| MitoxideError::agent(format!("Failed to read local file {:?}: {}", local_path, e)))?;
+        let (signature, ops) = tokio::task::spawn_blocking(move || {
+            let ops = delta::delta(&signature, &content);
+            (signature, ops)
+        }).await
+            .map_err(|e| MitoxideError::agent(format!("Delta computation failed: {}", e)))?;
+        let sent = delta::data_len(&ops);
+        
+        let mut request = Request::file_delta_put(remote_path.to_path_buf(), &signature, ops);
+        if let Request::FileDeltaPut { mode: put_mode, modified: put_modified, .. } = &mut request {
+            *put_mode = mode;
+            *put_modified = modified;
+        }
+        match self.send_request(request).await? {
+            Response::FilePutResult { .. } => Ok(sent),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Sync of {:?} failed: {}", remote_path, error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Set the permission bits and modification time of a remote file
+    async fn set_remote_attributes(&self, remote_path: &Path, mode: Option<u32>, modified: Option<u64>) -> Result<()> {
+        let request = Request::file_set_attributes(remote_path.to_path_buf(), mode, modified);
+        match self.send_request(request).await? {
+            Response::FileAttributes { .. } => Ok(()),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Setting attributes of {:?} failed: {}", remote_path, error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+    
+    /// Remove a remote file, or a directory with everything below it
+    async fn remove_remote(&self, remote_path: &Path, recursive: bool) -> Result<()> {
+        match self.send_request(Request::file_remove(remote_path.to_path_buf(), recursive)).await? {
+            Response::FileRemoved { .. } => Ok(()),
+            Response::Error { error, .. } => {
+                Err(MitoxideError::agent(format!("Failed to remove extraneous {:?}: {}", remote_path, error.message)))
+            }
+            _ => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+        }
+    }
+}
+
+/// Collect the files and directories below `root`, skipping symlinks
+fn walk_local(root: &Path) -> std::io::Result<LocalTree> {
+    let mut tree = LocalTree::default();
+    let mut pending = vec![PathBuf::new()];
+    
+    while let Some(relative) = pending.pop() {
+        for entry in std::fs::read_dir(root.join(&relative))? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let path = relative.join(entry.file_name());
+            if metadata.is_dir() {
+                tree.dirs.insert(path.clone());
+                pending.push(path);
+            } else if metadata.is_file() {
+                let modified = metadata.modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |elapsed| elapsed.as_secs());
+                tree.files.insert(path, LocalFile { size: metadata.len(), mode: file_mode(&metadata), modified });
+            } else {
+                debug!("Not syncing {:?}, which is neither a file nor a directory", path);
+            }
+        }
+    }
+    
+    Ok(tree)
+}
+
+/// Permission bits of a local file
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+/// Permission bits are only known on Unix
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Lowercase hex SHA-256 of a local file, matching what the agent computes
+async fn local_hash(path: PathBuf) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok::<_, std::io::Error>(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .map_err(|e| MitoxideError::agent(format!("Local hash failed: {}", e)))?
+    .map_err(|e| MitoxideError::agent(format!("Failed to hash local file: {}", e)))
+}
@@ -1,70 +1,47 @@
 //! Unit tests for execution context
 
 use super::*;
-use crate::MitoxideError;
-use mitoxide_proto::{Message, Request, Response};
-use mitoxide_proto::message::{ErrorDetails, ErrorCode};
+use crate::ErrorKind;
+use mitoxide_agent::agent::AgentLoop;
+use mitoxide_agent::handlers::{FileHandler, ProcessHandler, PtyHandler};
+use mitoxide_proto::{Request, Response};
+use mitoxide_proto::message::DEFAULT_ATTACHMENT_THRESHOLD;
 use std::sync::Arc;
-use tokio::sync::RwLock;
 use uuid::Uuid;
 
-// Mock router for testing
-struct MockRouter {
-    expected_requests: Arc<RwLock<Vec<Request>>>,
-    responses: Arc<RwLock<Vec<Response>>>,
-}
-
-impl MockRouter {
-    fn new() -> Self {
-        Self {
-            expected_requests: Arc::new(RwLock::new(Vec::new())),
-            responses: Arc::new(RwLock::new(Vec::new())),
-        }
-    }
+/// Create a context wired to an in-process agent over an in-memory pipe
+async fn local_context() -> Context {
+    let (client_io, agent_io) = tokio::io::duplex(64 * 1024);
     
-    async fn add_expected_request(&self, request: Request) {
-        self.expected_requests.write().await.push(request);
-    }
-    
-    async fn add_response(&self, response: Response) {
-        self.responses.write().await.push(response);
-    }
-    
-    async fn send_message(&self, message: Message) -> Result<Response> {
-        match message {
-            Message::Request(_req) => {
-                // Verify expected request
-                let mut expected = self.expected_requests.write().await;
-                if expected.is_empty() {
-                    return Err(MitoxideError::Protocol("Unexpected request".to_string()));
-                }
-                
-                let _expected_req = expected.remove(0);
-                // In a real test, we'd compare the requests more thoroughly
-                
-                // Return next response
-                let mut responses = self.responses.write().await;
-                if responses.is_empty() {
-                    return Err(MitoxideError::Protocol("No response available".to_string()));
-                }
-                
-                Ok(responses.remove(0))
-            }
-            _ => Err(MitoxideError::Protocol("Expected request message".to_string())),
-        }
-    }
+    let (agent_read, agent_write) = tokio::io::split(agent_io);
+    let mut agent = AgentLoop::with_io(agent_read, agent_write);
+    agent.register_handler("process_exec".to_string(), Arc::new(ProcessHandler::new())).await;
+    agent.register_handler("file_tail".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("dir_list".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_get".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_hash".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_put".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_xattr_get".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_xattr_set".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_chown".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_ensure".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_signature".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_delta_put".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_set_attributes".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("file_remove".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("disk_space".to_string(), Arc::new(FileHandler::new())).await;
+    agent.register_handler("pty_exec".to_string(), Arc::new(PtyHandler::new())).await;
+    tokio::spawn(async move { agent.run().await });
+    
+    let (client_read, client_write) = tokio::io::split(client_io);
+    let (router, _shutdown_tx) = Router::with_io(client_read, client_write, 16, Duration::from_secs(30)).unwrap();
+    Context::new(Uuid::new_v4(), Arc::new(router)).unwrap()
 }
 
 #[tokio::test]
 async fn test_context_creation() {
-    let session_id = Uuid::new_v4();
-    let _mock_router = Arc::new(MockRouter::new());
-    
-    // We can't directly create a Router for testing, so we'll test the Context struct itself
-    // In a real implementation, we'd need to create a proper mock or use dependency injection
-    
-    // For now, test the basic structure
-    assert_eq!(session_id.to_string().len(), 36); // UUID length
+    let context = local_context().await;
+    assert_eq!(context.session_id().to_string().len(), 36); // UUID length
 }
 
 #[tokio::test]
@@ -74,6 +51,12 @@ async fn test_process_output_success() {
         stdout: Bytes::from("Hello, World!"),
         stderr: Bytes::new(),
         duration: Duration::from_millis(100),
+        truncated: false,
+        timed_out: false,
+        termination: None,
+        fd_output: HashMap::new(),
+        stdout_written: None,
+        stderr_written: None,
     };
     
     assert!(output.success());
@@ -88,6 +71,12 @@ async fn test_process_output_failure() {
         stdout: Bytes::new(),
         stderr: Bytes::from("Error occurred"),
         duration: Duration::from_millis(50),
+        truncated: false,
+        timed_out: false,
+        termination: None,
+        fd_output: HashMap::new(),
+        stdout_written: None,
+        stderr_written: None,
     };
     
     assert!(!output.success());
@@ -103,6 +92,12 @@ async fn test_process_output_invalid_utf8() {
         stdout: Bytes::from(invalid_utf8),
         stderr: Bytes::new(),
         duration: Duration::from_millis(10),
+        truncated: false,
+        timed_out: false,
+        termination: None,
+        fd_output: HashMap::new(),
+        stdout_written: None,
+        stderr_written: None,
     };
     
     assert!(output.stdout_string().is_err());
@@ -115,6 +110,12 @@ fn test_process_output_clone() {
         stdout: Bytes::from("test output"),
         stderr: Bytes::from("test error"),
         duration: Duration::from_millis(200),
+        truncated: false,
+        timed_out: false,
+        termination: None,
+        fd_output: HashMap::new(),
+        stdout_written: None,
+        stderr_written: None,
     };
     
     let cloned = output.clone();
@@ -162,4 +163,959 @@ fn test_duration_operations() {
     
     let longer = Duration::from_secs(60);
     assert!(longer > duration);
-}
\ No newline at end of file
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_command_stdin_from_file() {
+    let size = 5 * 1024 * 1024 + 123;
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    std::fs::write(file.path(), &data).unwrap();
+    
+    let context = local_context().await;
+    let output = context.command(&["wc", "-c"])
+        .stdin_from_file(file.path())
+        .run()
+        .await
+        .unwrap();
+    
+    assert!(output.success());
+    assert_eq!(output.stdout_string().unwrap().trim(), size.to_string());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_command_stdin_from_empty_file() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    
+    let context = local_context().await;
+    let output = context.command(&["cat"])
+        .stdin_from_file(file.path())
+        .run()
+        .await
+        .unwrap();
+    
+    // cat only exits once stdin is closed
+    assert!(output.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[tokio::test]
+async fn test_command_stdin_from_missing_file() {
+    let context = local_context().await;
+    let result = context.command(&["cat"])
+        .stdin_from_file("/nonexistent/mitoxide-stdin")
+        .run()
+        .await;
+    
+    assert!(matches!(result.unwrap_err().kind(), ErrorKind::Io(..)));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_command_stream_line_buffered() {
+    let context = local_context().await;
+    let mut stream = context.command(&["sh", "-c", "printf 'first\\nsec'; sleep 0.1; printf 'ond\\nlast partial'"])
+        .line_buffered(None)
+        .stream()
+        .await
+        .unwrap();
+    
+    let mut lines = Vec::new();
+    let mut exited = None;
+    while let Some(event) = stream.next().await {
+        match event.unwrap() {
+            ProcessEvent::Stdout(data) => lines.push(String::from_utf8(data.to_vec()).unwrap()),
+            ProcessEvent::Stderr(data) => panic!("Unexpected stderr: {:?}", data),
+            ProcessEvent::Fd(fd, data) => panic!("Unexpected output on fd {}: {:?}", fd, data),
+            ProcessEvent::Exited(output) => exited = Some(output),
+        }
+    }
+    
+    assert_eq!(lines, vec!["first\n", "second\n", "last partial"]);
+    let output = exited.expect("process should report its exit");
+    assert!(output.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_command_merge_stderr() {
+    let context = local_context().await;
+    let output = context.command(&["sh", "-c", "echo a; echo b >&2; echo c; echo d >&2"])
+        .merge_stderr(true)
+        .run()
+        .await
+        .unwrap();
+    
+    assert_eq!(output.stdout_string().unwrap(), "a\nb\nc\nd\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_command_stdout_to_remote_file() {
+    let size = 10 * 1024 * 1024;
+    let dir = tempfile::tempdir().unwrap();
+    let remote_path = dir.path().join("output.bin");
+    
+    let context = local_context().await;
+    let script = format!("yes mitoxide | head -c {}", size);
+    let output = context.command(&["sh", "-c", &script])
+        .stdout_to_file(OutputFile::truncate(&remote_path))
+        .run()
+        .await
+        .unwrap();
+    
+    // Only the exit status and byte count come back
+    assert!(output.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(output.stdout_written, Some(size as u64));
+    assert_eq!(output.stderr_written, None);
+    
+    let expected: Vec<u8> = b"mitoxide\n".iter().copied().cycle().take(size).collect();
+    assert!(std::fs::read(&remote_path).unwrap() == expected);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_command_tail_output() {
+    let context = local_context().await;
+    let output = context.command(&["seq", "1", "200000"]).tail_output(14).run().await.unwrap();
+    
+    assert!(output.success());
+    assert!(output.truncated);
+    assert_eq!(output.stdout_string().unwrap(), "199999\n200000\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_exec_defaults_apply() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut context = local_context().await;
+    context.set_default_env("MITOXIDE_MODE", "session");
+    context.set_default_cwd(Some(dir.path().to_path_buf()));
+    
+    let output = context.command(&["sh", "-c", "echo $MITOXIDE_MODE; pwd"]).run().await.unwrap();
+    let expected = format!("session\n{}\n", dir.path().canonicalize().unwrap().display());
+    assert_eq!(output.stdout_string().unwrap(), expected);
+    
+    // The older helpers honour the defaults too
+    let output = context.proc_exec(&["sh", "-c", "echo $MITOXIDE_MODE"]).await.unwrap();
+    assert_eq!(output.stdout_string().unwrap(), "session\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_exec_defaults_overridden_per_request() {
+    let other = tempfile::TempDir::new().unwrap();
+    let mut context = local_context().await;
+    context.set_default_env("MITOXIDE_MODE", "session");
+    context.set_default_cwd(Some(std::env::temp_dir()));
+    
+    let output = context.command(&["sh", "-c", "echo $MITOXIDE_MODE; pwd"])
+        .env("MITOXIDE_MODE", "request")
+        .cwd(other.path())
+        .run()
+        .await
+        .unwrap();
+    let expected = format!("request\n{}\n", other.path().canonicalize().unwrap().display());
+    assert_eq!(output.stdout_string().unwrap(), expected);
+    
+    let mut env = std::collections::HashMap::new();
+    env.insert("MITOXIDE_MODE".to_string(), "explicit".to_string());
+    let output = context.proc_exec_with_env(&["sh", "-c", "echo $MITOXIDE_MODE"], env, None, None).await.unwrap();
+    assert_eq!(output.stdout_string().unwrap(), "explicit\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_exec_defaults_cleared_per_request() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut context = local_context().await;
+    context.set_default_env("MITOXIDE_MODE", "session");
+    context.set_default_cwd(Some(dir.path().to_path_buf()));
+    
+    let output = context.command(&["sh", "-c", "echo \"[${MITOXIDE_MODE:-unset}]\"; pwd"])
+        .env_remove("MITOXIDE_MODE")
+        .clear_cwd()
+        .run()
+        .await
+        .unwrap();
+    let stdout = output.stdout_string().unwrap();
+    assert!(stdout.starts_with("[unset]\n"));
+    assert!(!stdout.contains(&dir.path().canonicalize().unwrap().display().to_string()));
+}
+
+#[tokio::test]
+async fn test_open_session_resume() {
+    let context = local_context().await;
+    
+    let session = context.open_session(None).await.unwrap();
+    assert!(!session.resumed);
+    assert!(session.responses.is_empty());
+    
+    let command = context.command(&["echo", "done"]);
+    let request_id = command.id();
+    command.run().await.unwrap();
+    
+    let resumed = context.open_session(Some(session.token)).await.unwrap();
+    assert!(resumed.resumed);
+    assert_eq!(resumed.token, session.token);
+    assert!(resumed.responses.iter().any(|response| response.request_id() == request_id));
+}
+
+#[tokio::test]
+async fn test_open_session_agrees_on_attachment_threshold() {
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("file.bin");
+    std::fs::write(&path, vec![7u8; 256 * 1024]).unwrap();
+    
+    // The agent's default is larger, so both ends use it
+    context.router.set_attachment_threshold(Some(1024)).await;
+    let session = context.open_session(None).await.unwrap();
+    assert_eq!(session.attachment_threshold, Some(DEFAULT_ATTACHMENT_THRESHOLD as u32));
+    assert_eq!(context.router.attachment_threshold().await, Some(DEFAULT_ATTACHMENT_THRESHOLD));
+    
+    context.router.set_attachment_threshold(None).await;
+    assert_eq!(context.open_session(None).await.unwrap().attachment_threshold, None);
+    assert_eq!(context.router.attachment_threshold().await, None);
+    
+    // Transfers work without attachments too
+    let copy = dir.path().join("copy.bin");
+    let back = dir.path().join("back.bin");
+    context.put(&path, &copy).await.unwrap();
+    context.get(&copy, &back).await.unwrap();
+    assert_eq!(std::fs::read(&back).unwrap(), std::fs::read(&path).unwrap());
+}
+
+/// Ping handler that records the most requests it saw running at once
+struct CountingHandler {
+    running: std::sync::atomic::AtomicUsize,
+    peak: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[async_trait::async_trait]
+impl mitoxide_agent::agent::Handler for CountingHandler {
+    async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+        use std::sync::atomic::Ordering;
+        
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(running, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        Ok(Response::pong(request.id(), 0))
+    }
+}
+
+#[tokio::test]
+async fn test_client_respects_advertised_concurrency_limit() {
+    let (client_io, agent_io) = tokio::io::duplex(64 * 1024);
+    
+    let peak = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let (agent_read, agent_write) = tokio::io::split(agent_io);
+    let mut agent = AgentLoop::with_io(agent_read, agent_write).with_max_concurrent_requests(2);
+    let handler = CountingHandler {
+        running: std::sync::atomic::AtomicUsize::new(0),
+        peak: peak.clone(),
+    };
+    agent.register_handler("version".to_string(), Arc::new(handler)).await;
+    tokio::spawn(async move { agent.run().await });
+    
+    let (client_read, client_write) = tokio::io::split(client_io);
+    let (router, _shutdown_tx) = Router::with_io(client_read, client_write, 16, Duration::from_secs(30)).unwrap();
+    let context = Context::new(Uuid::new_v4(), Arc::new(router)).unwrap();
+    
+    let session = context.open_session(None).await.unwrap();
+    assert_eq!(session.max_concurrent_requests, Some(2));
+    assert_eq!(session.in_flight, 0);
+    
+    // Every request must succeed: none may reach the agent while it is at its limit
+    let requests = (0..10).map(|_| Request::version()).collect();
+    for result in context.pipeline(requests).await {
+        assert!(matches!(result.unwrap(), Response::Pong { .. }));
+    }
+    assert_eq!(peak.load(std::sync::atomic::Ordering::SeqCst), 2);
+}
+
+/// Build a process request for a batch
+fn exec(command: &[&str]) -> Request {
+    let command = command.iter().map(|arg| arg.to_string()).collect();
+    Request::process_exec(command, HashMap::new(), None, None, None)
+}
+
+#[tokio::test]
+async fn test_batch_runs_requests_in_order() {
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let sub = dir.path().join("sub");
+    let file = sub.join("file.txt");
+    let file_arg = file.to_str().unwrap();
+    
+    let responses = context.batch(vec![
+        exec(&["mkdir", sub.to_str().unwrap()]),
+        exec(&["sh", "-c", &format!("echo hello > {}", file_arg)]),
+        exec(&["chmod", "600", file_arg]),
+    ], true).await.unwrap();
+    
+    assert_eq!(responses.len(), 3);
+    assert!(responses.iter().all(|response| !response.is_failure()));
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "hello\n");
+}
+
+#[tokio::test]
+async fn test_batch_stops_on_error() {
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("marker");
+    
+    let failing = exec(&["sh", "-c", "exit 3"]);
+    let failing_id = failing.id();
+    let responses = context.batch(vec![
+        exec(&["true"]),
+        failing,
+        exec(&["touch", marker.to_str().unwrap()]),
+    ], true).await.unwrap();
+    
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[1].request_id(), failing_id);
+    match &responses[1] {
+        Response::ProcessResult { exit_code, .. } => assert_eq!(*exit_code, 3),
+        other => panic!("Expected ProcessResult, got {:?}", other),
+    }
+    assert!(!marker.exists());
+}
+
+/// Read tail chunks until `expected` has been received
+async fn read_tail(tail: &mut FileTail, expected: &str) -> String {
+    let mut received = String::new();
+    while received.len() < expected.len() {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), tail.next()).await
+            .expect("Timed out waiting for tailed lines")
+            .expect("Tail ended early")
+            .unwrap();
+        received.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    received
+}
+
+#[tokio::test]
+async fn test_tail_follows_appends_and_truncation() {
+    use std::io::Write;
+    
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("app.log");
+    std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+    
+    let mut tail = context.tail(&path, 2, true).await.unwrap();
+    assert_eq!(read_tail(&mut tail, "two\nthree\n").await, "two\nthree\n");
+    
+    // A line written in pieces is only delivered once complete
+    let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"four\nfi").unwrap();
+    assert_eq!(read_tail(&mut tail, "four\n").await, "four\n");
+    file.write_all(b"ve\nsix\n").unwrap();
+    assert_eq!(read_tail(&mut tail, "five\nsix\n").await, "five\nsix\n");
+    
+    // After truncation the file is read again from the start
+    std::fs::write(&path, "").unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    std::fs::write(&path, "seven\n").unwrap();
+    assert_eq!(read_tail(&mut tail, "seven\n").await, "seven\n");
+    
+    tail.stop();
+    let end = tokio::time::timeout(Duration::from_secs(5), tail.next()).await.unwrap();
+    assert!(end.is_none());
+}
+
+#[tokio::test]
+async fn test_list_dir_stream_arrives_in_bounded_batches() {
+    let dir = tempfile::tempdir().unwrap();
+    let total = 20_000;
+    for i in 0..total {
+        std::fs::write(dir.path().join(format!("entry-{:05}", i)), b"").unwrap();
+    }
+    
+    let context = local_context().await;
+    let mut listing = context.list_dir_stream(dir.path(), false, false, 500).await.unwrap();
+    
+    let first = listing.next_batch().await.unwrap().unwrap();
+    assert_eq!(first.len(), 500);
+    
+    // The agent only runs a few batches ahead of what has been taken
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let queued = listing.responses.responses.len();
+    assert!(queued <= 4, "{} batches queued", queued);
+    
+    let mut names: std::collections::HashSet<String> = first.into_iter().map(|entry| entry.name).collect();
+    let mut batches = 1;
+    while let Some(batch) = listing.next_batch().await {
+        let batch = batch.unwrap();
+        assert!(!batch.is_empty() && batch.len() <= 500);
+        names.extend(batch.into_iter().map(|entry| entry.name));
+        batches += 1;
+    }
+    assert_eq!(names.len(), total);
+    assert_eq!(batches, total / 500);
+    
+    // Entry by entry, the same listing comes out whole
+    let mut listing = context.list_dir_stream(dir.path(), false, false, 777).await.unwrap();
+    let mut count = 0;
+    while let Some(entry) = listing.next().await {
+        assert!(entry.unwrap().name.starts_with("entry-"));
+        count += 1;
+    }
+    assert_eq!(count, total);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_chdir_resolves_relative_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("remote.txt"), b"relative").unwrap();
+    let canonical = dir.path().canonicalize().unwrap();
+    
+    let context = local_context().await;
+    assert_eq!(context.chdir(dir.path()).await.unwrap(), canonical);
+    assert_eq!(context.getcwd().await.unwrap(), canonical);
+    
+    let local = dir.path().join("local.txt");
+    context.get(Path::new("remote.txt"), &local).await.unwrap();
+    assert_eq!(std::fs::read(&local).unwrap(), b"relative");
+    
+    // Processes without their own cwd start in the session's
+    let output = context.proc_exec(&["pwd"]).await.unwrap();
+    assert_eq!(output.stdout_string().unwrap().trim(), canonical.to_str().unwrap());
+    
+    assert!(context.chdir(Path::new("remote.txt")).await.is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_spawn_detached_returns_running_pid() {
+    let context = local_context().await;
+    let pid = context.command(&["sleep", "30"]).spawn_detached().await.unwrap().to_string();
+    
+    assert!(context.proc_exec(&["kill", "-0", &pid]).await.unwrap().success());
+    assert!(context.proc_exec(&["kill", "-9", &pid]).await.unwrap().success());
+}
+
+#[tokio::test]
+async fn test_mktemp_creates_unique_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let context = local_context().await;
+    
+    let file = context.mktemp(TempKind::File, Some(dir.path()), "job-", ".log", false).await.unwrap();
+    let other = context.mktemp(TempKind::File, Some(dir.path()), "job-", ".log", false).await.unwrap();
+    let subdir = context.mktemp(TempKind::Dir, Some(dir.path()), "", "", false).await.unwrap();
+    assert_ne!(file, other);
+    assert!(file.is_file() && other.is_file() && subdir.is_dir());
+    assert!(file.file_name().unwrap().to_str().unwrap().starts_with("job-"));
+    
+    let missing = dir.path().join("missing");
+    assert!(context.mktemp(TempKind::File, Some(&missing), "", "", false).await.is_err());
+}
+
+#[tokio::test]
+async fn test_chunked_download_as_stream() {
+    use futures::StreamExt;
+    
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("download.bin");
+    let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, &content).unwrap();
+    
+    let mut stream = context.get_stream(&path, 4096).await.unwrap();
+    let mut chunks = Vec::new();
+    let mut metadata = None;
+    while let Some(response) = stream.next().await {
+        match response.unwrap() {
+            Response::FileChunk { data, .. } => chunks.push(data),
+            Response::FileContent { content, metadata: final_metadata, .. } => {
+                assert!(content.is_empty());
+                metadata = Some(final_metadata);
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+    
+    assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![4096, 4096, 1808]);
+    assert_eq!(chunks.concat(), content);
+    assert_eq!(metadata.unwrap().size, 10_000);
+    assert!(stream.next().await.is_none());
+}
+
+#[tokio::test]
+async fn test_chunked_download_held_to_output_window() {
+    use futures::StreamExt;
+    use mitoxide_proto::message::STREAM_OUTPUT_WINDOW;
+    
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("large.bin");
+    let content: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, &content).unwrap();
+    
+    let chunk_size = 64 * 1024;
+    let mut stream = context.get_stream(&path, chunk_size).await.unwrap();
+    let mut received = match stream.next().await.unwrap().unwrap() {
+        Response::FileChunk { data, .. } => data.to_vec(),
+        other => panic!("Unexpected response {:?}", other),
+    };
+    
+    // The agent only reads a window ahead of what has been taken
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let queued = stream.responses.len();
+    assert!(queued <= (STREAM_OUTPUT_WINDOW / chunk_size) as usize, "{} chunks queued", queued);
+    
+    while let Some(response) = stream.next().await {
+        if let Response::FileChunk { data, .. } = response.unwrap() {
+            received.extend_from_slice(&data);
+        }
+    }
+    assert_eq!(received, content);
+}
+
+#[tokio::test]
+async fn test_chunked_download_collect() {
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("download.txt");
+    std::fs::write(&path, "collected all at once").unwrap();
+    
+    let responses = context.get_stream(&path, 8).await.unwrap().collect().await.unwrap();
+    assert_eq!(responses.len(), 4);
+    assert!(matches!(responses.last(), Some(Response::FileContent { .. })));
+    let content: Vec<u8> = responses.iter()
+        .filter_map(|response| match response {
+            Response::FileChunk { data, .. } => Some(data.to_vec()),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    assert_eq!(content, b"collected all at once");
+    
+    let missing = context.get_stream(&dir.path().join("missing"), 8).await.unwrap().collect().await;
+    assert!(missing.unwrap_err().to_string().contains("File get failed"));
+}
+
+/// Copy everything read from `from` to `to`, holding each read back by `delay`
+async fn forward_delayed<R, W>(mut from: R, mut to: W, delay: Duration)
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+    
+    let mut buf = vec![0u8; 64 * 1024];
+    while let Ok(n) = from.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        tokio::time::sleep(delay).await;
+        if to.write_all(&buf[..n]).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_ping_batch_statistics() {
+    let delay = Duration::from_millis(15);
+    let (client_io, client_proxy) = tokio::io::duplex(64 * 1024);
+    let (agent_proxy, agent_io) = tokio::io::duplex(64 * 1024);
+    
+    let (agent_read, agent_write) = tokio::io::split(agent_io);
+    let mut agent = AgentLoop::with_io(agent_read, agent_write);
+    agent.register_handler("ping_batch".to_string(), Arc::new(mitoxide_agent::handlers::PingHandler)).await;
+    tokio::spawn(async move { agent.run().await });
+    
+    // Responses reach the client `delay` after the agent sends them
+    let (client_proxy_read, client_proxy_write) = tokio::io::split(client_proxy);
+    let (agent_proxy_read, agent_proxy_write) = tokio::io::split(agent_proxy);
+    tokio::spawn(forward_delayed(client_proxy_read, agent_proxy_write, Duration::ZERO));
+    tokio::spawn(forward_delayed(agent_proxy_read, client_proxy_write, delay));
+    
+    let (client_read, client_write) = tokio::io::split(client_io);
+    let (router, _shutdown_tx) = Router::with_io(client_read, client_write, 16, Duration::from_secs(30)).unwrap();
+    let context = Context::new(Uuid::new_v4(), Arc::new(router)).unwrap();
+    
+    let stats = context.ping_batch(10, Duration::from_millis(30), 512).await.unwrap();
+    assert_eq!((stats.sent, stats.received), (10, 10));
+    assert_eq!(stats.loss(), 0.0);
+    assert!(stats.min >= delay, "{:?}", stats);
+    assert!(stats.max < delay + Duration::from_millis(200), "{:?}", stats);
+    assert!(stats.min <= stats.avg && stats.avg <= stats.max, "{:?}", stats);
+    assert!(stats.stddev <= stats.max - stats.min, "{:?}", stats);
+    
+    // Statistics of probes that didn't all come back
+    let rtts = [Duration::from_millis(10), Duration::from_millis(30)];
+    let stats = PingStats::from_round_trips(4, &rtts);
+    assert_eq!((stats.avg, stats.stddev), (Duration::from_millis(20), Duration::from_millis(10)));
+    assert_eq!(stats.loss(), 0.5);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_non_utf8_file_name_roundtrip() {
+    use std::os::unix::ffi::OsStrExt;
+    
+    let dir = tempfile::tempdir().unwrap();
+    let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+    std::fs::write(dir.path().join(name), "latin-1").unwrap();
+    
+    let context = local_context().await;
+    let mut listing = context.list_dir_stream(dir.path(), false, false, 10).await.unwrap();
+    let entry = listing.next().await.unwrap().unwrap();
+    assert!(listing.next().await.is_none());
+    assert_eq!(entry.name, "caf\u{fffd}.txt");
+    assert_eq!(entry.path.file_name(), Some(name));
+    
+    // The listed path refers back to the same file
+    let local = dir.path().join("downloaded.txt");
+    assert_eq!(context.get(&entry.path, &local).await.unwrap(), 7);
+    assert_eq!(std::fs::read(&local).unwrap(), b"latin-1");
+}
+
+#[tokio::test]
+async fn test_remote_file_hash() {
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("abc.txt");
+    std::fs::write(&path, "abc").unwrap();
+    
+    let (hash, size) = context.hash(&path, HashAlgorithm::Sha256).await.unwrap();
+    assert_eq!(hash, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(size, 3);
+    
+    let missing = context.hash(&dir.path().join("missing"), HashAlgorithm::Sha256).await;
+    assert!(missing.unwrap_err().to_string().contains("File hash failed"));
+}
+
+#[tokio::test]
+async fn test_remote_ensure_file() {
+    use mitoxide_proto::message::FileChange;
+    
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("motd");
+    
+    assert_eq!(context.ensure_file(&path, b"welcome\n", None, None).await.unwrap(), vec![FileChange::Content]);
+    assert!(context.ensure_file(&path, b"welcome\n", None, None).await.unwrap().is_empty());
+    assert_eq!(std::fs::read(&path).unwrap(), b"welcome\n");
+    
+    let missing = context.ensure_file(&dir.path().join("missing/motd"), b"", None, None).await;
+    assert!(missing.unwrap_err().to_string().contains("File ensure failed"));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_remote_xattrs() {
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("labelled.txt");
+    std::fs::write(&path, "content").unwrap();
+    
+    let xattrs = HashMap::from([("user.origin".to_string(), Bytes::from("build-42"))]);
+    assert_eq!(context.set_xattrs(&path, xattrs.clone()).await.unwrap(), xattrs);
+    assert_eq!(context.xattrs(&path).await.unwrap(), xattrs);
+    
+    // procfs lists no attributes but can't store any
+    assert!(context.xattrs(Path::new("/proc/self/status")).await.unwrap().is_empty());
+    let unsupported = context.set_xattrs(Path::new("/proc/self/status"), xattrs).await;
+    assert!(unsupported.unwrap_err().to_string().contains("not supported"));
+}
+
+/// File handler on a filesystem with only a few bytes free
+struct NearlyFullFileHandler {
+    inner: FileHandler,
+    available: u64,
+}
+
+#[async_trait::async_trait]
+impl mitoxide_agent::agent::Handler for NearlyFullFileHandler {
+    async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+        match self.inner.handle(request).await? {
+            Response::DiskSpace { request_id, mut space } => {
+                space.available = self.available;
+                Ok(Response::DiskSpace { request_id, space })
+            }
+            response => Ok(response),
+        }
+    }
+    
+    async fn handle_stream(&self, request: Request, stream: mitoxide_agent::agent::RequestStream) -> anyhow::Result<Response> {
+        match request {
+            Request::DiskSpace { .. } => self.handle(request).await,
+            request => self.inner.handle_stream(request, stream).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_upload_checks_disk_space() {
+    let (client_io, agent_io) = tokio::io::duplex(64 * 1024);
+    let (agent_read, agent_write) = tokio::io::split(agent_io);
+    let mut agent = AgentLoop::with_io(agent_read, agent_write);
+    let handler = Arc::new(NearlyFullFileHandler { inner: FileHandler::new(), available: 100 });
+    agent.register_handler("disk_space".to_string(), handler.clone()).await;
+    agent.register_handler("file_put".to_string(), handler).await;
+    tokio::spawn(async move { agent.run().await });
+    let (client_read, client_write) = tokio::io::split(client_io);
+    let (router, _shutdown_tx) = Router::with_io(client_read, client_write, 16, Duration::from_secs(30)).unwrap();
+    let mut context = Context::new(Uuid::new_v4(), Arc::new(router)).unwrap();
+    
+    let dir = tempfile::tempdir().unwrap();
+    let local = dir.path().join("large.bin");
+    std::fs::write(&local, vec![7u8; 1024]).unwrap();
+    let remote = dir.path().join("upload/large.bin");
+    
+    let space = context.disk_space(&remote).await.unwrap();
+    assert_eq!(space.available, 100);
+    assert!(space.total > 0);
+    
+    context.set_check_disk_space(true);
+    let error = context.put(&local, &remote).await.unwrap_err().to_string();
+    assert!(error.contains("1024 bytes needed, 100 available"), "{}", error);
+    assert!(!dir.path().join("upload").exists());
+    
+    // Without the check nothing stops the upload
+    context.set_check_disk_space(false);
+    assert_eq!(context.put(&local, &remote).await.unwrap(), 1024);
+    assert_eq!(std::fs::read(&remote).unwrap().len(), 1024);
+}
+
+/// File handler that answers each request after a fixed delay, standing in for a slow link
+#[derive(Default)]
+struct SlowFileHandler {
+    inner: FileHandler,
+    running: std::sync::atomic::AtomicUsize,
+    peak: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl SlowFileHandler {
+    const LATENCY: Duration = Duration::from_millis(100);
+}
+
+#[async_trait::async_trait]
+impl mitoxide_agent::agent::Handler for SlowFileHandler {
+    async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+        use std::sync::atomic::Ordering;
+        
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(running, Ordering::SeqCst);
+        tokio::time::sleep(Self::LATENCY).await;
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        self.inner.handle(request).await
+    }
+}
+
+#[tokio::test]
+async fn test_parallel_download() {
+    let (client_io, agent_io) = tokio::io::duplex(1024 * 1024);
+    let (agent_read, agent_write) = tokio::io::split(agent_io);
+    let mut agent = AgentLoop::with_io(agent_read, agent_write);
+    let handler = SlowFileHandler::default();
+    let peak = handler.peak.clone();
+    agent.register_handler("file_get".to_string(), Arc::new(handler)).await;
+    tokio::spawn(async move { agent.run().await });
+    let (client_read, client_write) = tokio::io::split(client_io);
+    let (router, _shutdown_tx) = Router::with_io(client_read, client_write, 16, Duration::from_secs(30)).unwrap();
+    let context = Context::new(Uuid::new_v4(), Arc::new(router)).unwrap();
+    
+    let dir = tempfile::tempdir().unwrap();
+    let remote = dir.path().join("large.bin");
+    let content: Vec<u8> = (0..8 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&remote, &content).unwrap();
+    
+    let start = Instant::now();
+    let parallel = dir.path().join("parallel.bin");
+    assert_eq!(context.get_parallel(&remote, &parallel, 4).await.unwrap(), content.len() as u64);
+    let elapsed = start.elapsed();
+    assert_eq!(std::fs::read(&parallel).unwrap(), content);
+    
+    // The size, then all four ranges at once, instead of five requests one after another
+    assert_eq!(peak.load(std::sync::atomic::Ordering::SeqCst), 4);
+    assert!(elapsed < SlowFileHandler::LATENCY * 4, "parallel download took {:?}", elapsed);
+    
+    // Below the threshold a single request is made
+    let small = dir.path().join("small.txt");
+    std::fs::write(&small, "small").unwrap();
+    let copy = dir.path().join("copy.txt");
+    assert_eq!(context.get_parallel(&small, &copy, 4).await.unwrap(), 5);
+    assert_eq!(std::fs::read_to_string(&copy).unwrap(), "small");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_sync_transfers_only_changed_files() {
+    use std::os::unix::fs::PermissionsExt;
+    
+    let context = local_context().await;
+    let dir = tempfile::tempdir().unwrap();
+    let local = dir.path().join("local");
+    let remote = dir.path().join("remote");
+    std::fs::create_dir_all(local.join("sub/deep")).unwrap();
+    std::fs::write(local.join("a.txt"), "alpha").unwrap();
+    std::fs::write(local.join("sub/b.txt"), "bravo").unwrap();
+    std::fs::write(local.join("sub/deep/c.txt"), "charlie").unwrap();
+    let mut big: Vec<u8> = (0..200_000u32).map(|i| (i * 7919 % 251) as u8).collect();
+    std::fs::write(local.join("big.bin"), &big).unwrap();
+    std::fs::set_permissions(local.join("a.txt"), std::fs::Permissions::from_mode(0o600)).unwrap();
+    let dated = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    std::fs::File::options().write(true).open(local.join("sub/b.txt")).unwrap().set_modified(dated).unwrap();
+    
+    let report = context.sync(&local, &remote, SyncOptions::default()).await.unwrap();
+    let relative = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+    assert_eq!(report.transferred, relative(&["a.txt", "big.bin", "sub/b.txt", "sub/deep/c.txt"]));
+    assert!(report.skipped.is_empty());
+    assert_eq!(report.bytes_transferred, 17 + 200_000);
+    assert_eq!(std::fs::read_to_string(remote.join("sub/deep/c.txt")).unwrap(), "charlie");
+    assert_eq!(std::fs::metadata(remote.join("a.txt")).unwrap().permissions().mode() & 0o777, 0o600);
+    assert_eq!(std::fs::metadata(remote.join("sub/b.txt")).unwrap().modified().unwrap(), dated);
+    
+    // Same size but different content, a few bytes changed in a big file,
+    // a new mode on an unchanged file, plus remote leftovers
+    std::fs::write(local.join("sub/b.txt"), "BRAVO").unwrap();
+    big[150_000..150_010].copy_from_slice(b"0123456789");
+    big.splice(1000..1000, b"inserted".iter().copied());
+    std::fs::write(local.join("big.bin"), &big).unwrap();
+    std::fs::set_permissions(local.join("a.txt"), std::fs::Permissions::from_mode(0o640)).unwrap();
+    let a_modified = std::fs::metadata(local.join("a.txt")).unwrap().modified().unwrap();
+    std::fs::File::options().write(true).open(remote.join("a.txt")).unwrap().set_modified(dated).unwrap();
+    std::fs::write(remote.join("extra.txt"), "stale").unwrap();
+    std::fs::create_dir_all(remote.join("old/nested")).unwrap();
+    std::fs::write(remote.join("old/nested/x.txt"), "stale").unwrap();
+    
+    let options = SyncOptions { delete: true, ..Default::default() };
+    let report = context.sync(&local, &remote, options).await.unwrap();
+    assert_eq!(report.transferred, relative(&["big.bin", "sub/b.txt"]));
+    assert_eq!(report.skipped, relative(&["a.txt", "sub/deep/c.txt"]));
+    assert_eq!(report.attributes_updated, relative(&["a.txt"]));
+    assert_eq!(report.deleted, relative(&["extra.txt", "old"]));
+    // Only the blocks around the two edits of the big file were sent
+    assert!(report.bytes_transferred > 5 && report.bytes_transferred <= 5 + 2 * 1024, "{}", report.bytes_transferred);
+    assert_eq!(std::fs::read(remote.join("big.bin")).unwrap(), big);
+    assert_eq!(std::fs::read_to_string(remote.join("sub/b.txt")).unwrap(), "BRAVO");
+    let a_metadata = std::fs::metadata(remote.join("a.txt")).unwrap();
+    assert_eq!(a_metadata.permissions().mode() & 0o777, 0o640);
+    assert_eq!(
+        a_metadata.modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+        a_modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    );
+    assert!(!remote.join("extra.txt").exists());
+    assert!(!remote.join("old").exists());
+    
+    // A third run finds nothing to do
+    let report = context.sync(&local, &remote, options).await.unwrap();
+    assert!(report.transferred.is_empty() && report.deleted.is_empty() && report.attributes_updated.is_empty());
+    assert_eq!(report.skipped.len(), 4);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_command_output_fd_arrives_on_its_stream() {
+    let context = local_context().await;
+    
+    let mut stream = context.command(&["sh", "-c", "printf progress >&3; printf done"])
+        .output_fd(3)
+        .stream().await.unwrap();
+    let mut fd3 = Vec::new();
+    let mut stdout = Vec::new();
+    while let Some(event) = stream.next().await {
+        match event.unwrap() {
+            ProcessEvent::Fd(3, data) => fd3.extend_from_slice(&data),
+            ProcessEvent::Stdout(data) => stdout.extend_from_slice(&data),
+            ProcessEvent::Exited(output) => assert!(output.success()),
+            other => panic!("Unexpected event {:?}", other),
+        }
+    }
+    assert_eq!(fd3, b"progress");
+    assert_eq!(stdout, b"done");
+    
+    let output = context.command(&["sh", "-c", "printf captured >&3"]).output_fd(3).run().await.unwrap();
+    assert_eq!(output.fd_output[&3], Bytes::from("captured"));
+}
+
+/// Read terminal output until it contains `needle`, returning everything read
+#[cfg(unix)]
+async fn read_until(pty: &mut PtySession, needle: &str) -> String {
+    let mut output = String::new();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !output.contains(needle) {
+            match pty.next().await {
+                Some(Ok(PtyEvent::Output(data))) => output.push_str(&String::from_utf8_lossy(&data)),
+                other => panic!("Unexpected event {:?} while waiting for {:?} in {:?}", other, needle, output),
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Timed out waiting for {:?} in {:?}", needle, output));
+    output
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_interactive_pty_shell() {
+    use mitoxide_proto::message::PtySize;
+    
+    let context = local_context().await;
+    let mut pty = context.pty(&["env", "PS1=pty> ", "bash", "--norc", "--noprofile", "-i"], PtySize::default())
+        .await.unwrap();
+    read_until(&mut pty, "pty> ").await;
+    
+    // The terminal echoes what is typed before the command's own output,
+    // with whatever escape sequences the shell adds in between
+    pty.write("echo hi\n").await.unwrap();
+    let output = read_until(&mut pty, "pty> ").await;
+    let echoed = output.find("echo hi\r\n").unwrap_or_else(|| panic!("No echo in {:?}", output));
+    assert!(output[echoed + "echo hi\r\n".len()..].contains("hi\r\n"), "{:?}", output);
+    
+    pty.resize(PtySize { rows: 40, cols: 120 }).await.unwrap();
+    pty.write("stty size\n").await.unwrap();
+    read_until(&mut pty, "40 120\r\n").await;
+    
+    pty.write("exit\n").await.unwrap();
+    loop {
+        match pty.next().await {
+            Some(Ok(PtyEvent::Output(_))) => continue,
+            Some(Ok(PtyEvent::Exited(code))) => {
+                assert_eq!(code, 0);
+                break;
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+    }
+    assert!(pty.next().await.is_none());
+    assert!(pty.write("ignored\n").await.is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_pty_term_and_window_size() {
+    use mitoxide_proto::message::PtySize;
+    
+    let context = local_context().await;
+    let script = "echo \"term=$TERM\"; stty size; read line; stty size";
+    let mut pty = context.pty_with_term(&["sh", "-c", script], PtySize { rows: 30, cols: 100 }, "vt100")
+        .await.unwrap();
+    read_until(&mut pty, "term=vt100\r\n30 100\r\n").await;
+    
+    pty.resize(PtySize { rows: 50, cols: 132 }).await.unwrap();
+    pty.write("\n").await.unwrap();
+    read_until(&mut pty, "50 132\r\n").await;
+    loop {
+        match pty.next().await {
+            Some(Ok(PtyEvent::Output(_))) => continue,
+            Some(Ok(PtyEvent::Exited(code))) => {
+                assert_eq!(code, 0);
+                break;
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Raw byte tunnels to TCP ports the agent can reach
+
+use super::Context;
+use crate::router::OutputWindow;
+use crate::{MitoxideError, Result};
+use bytes::Bytes;
+use mitoxide_proto::{Request, Response};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::debug;
+use uuid::Uuid;
+
+/// Writes queued for the agent before [`Tunnel`] writes wait
+const TUNNEL_INPUT_CAPACITY: usize = 16;
+
+/// Most bytes one write sends to the agent, in a single frame
+const MAX_TUNNEL_WRITE: usize = 64 * 1024;
+
+/// Wait for room in the tunnel's input queue
+type Reserve = Pin<Box<dyn Future<Output = std::result::Result<mpsc::OwnedPermit<Bytes>, mpsc::error::SendError<()>>> + Send>>;
+
+/// A TCP connection the agent made with [`Context::tunnel`], relaying bytes to and from it
+///
+/// Reads return what the target sends, until it closes the connection. The
+/// agent only reads ahead of them by its output window, so a tunnel that
+/// isn't read holds the target back. Writes are queued for the agent, which
+/// writes them to the target in order; flushing doesn't wait for them to
+/// arrive. Shutting down the write side shuts down the sending side of the
+/// agent's connection; dropping the tunnel before it is finished closes the
+/// connection altogether.
+pub struct Tunnel {
+    /// Request ID of the `TcpConnect`
+    request_id: Uuid,
+    /// Address the agent connected to
+    peer: String,
+    /// Responses for the request, ending with `TunnelClosed`
+    responses: mpsc::UnboundedReceiver<Response>,
+    /// Credit for the data the agent sends
+    window: OutputWindow,
+    /// Bytes of the chunk in `buffered`, granted back once it is read
+    taken: usize,
+    /// Data for the agent; dropping it ends the request stream
+    input: Option<mpsc::Sender<Bytes>>,
+    /// Pending wait for room to queue a write
+    reserving: Option<Reserve>,
+    /// Data received from the target but not read yet
+    buffered: Bytes,
+    /// Whether the tunnel has ended, so reads return EOF
+    finished: bool,
+}
+
+impl Context {
+    /// Open a tunnel through the agent to `remote_host:remote_port`
+    ///
+    /// The agent resolves the host and connects, so the target only has to
+    /// be reachable from the remote host. The agent must allow the address:
+    /// one it doesn't is refused with a permission error, and a port no one
+    /// listens on fails with the connection refused error the agent saw.
+    pub async fn tunnel(&self, remote_host: &str, remote_port: u16) -> Result<Tunnel> {
+        debug!("Opening tunnel to {}:{}", remote_host, remote_port);
+        
+        let request = Request::tcp_connect(remote_host, remote_port);
+        let request_id = request.id();
+        let (input_tx, input_rx) = mpsc::channel(TUNNEL_INPUT_CAPACITY);
+        let (mut responses, window) = self.router
+            .send_message_windowed(self.message(request), Some(input_rx)).await?;
+        
+        match responses.recv().await {
+            Some(Response::TunnelOpened { peer, .. }) => Ok(Tunnel {
+                request_id,
+                peer,
+                responses,
+                window,
+                taken: 0,
+                input: Some(input_tx),
+                reserving: None,
+                buffered: Bytes::new(),
+                finished: false,
+            }),
+            Some(Response::Error { error, .. }) => Err(MitoxideError::agent(format!(
+                "Tunnel to {}:{} failed: {}", remote_host, remote_port, error.message
+            ))),
+            Some(_) => Err(MitoxideError::protocol("Unexpected response type".to_string())),
+            None => Err(MitoxideError::protocol("Tunnel stream closed before the connection opened".to_string())),
+        }
+    }
+}
+
+impl Tunnel {
+    /// Request ID of the tunnel, for [`Context::cancel_operation`]
+    pub fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+    
+    /// Address the agent connected to, after resolving the host
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+    
+    /// Let the agent read up to `bytes` from the target ahead of this tunnel's reads
+    ///
+    /// Raising it from the default
+    /// [`STREAM_OUTPUT_WINDOW`](mitoxide_proto::message::STREAM_OUTPUT_WINDOW)
+    /// speeds up bulk transfers over links with a long round trip; see
+    /// [`OutputWindow::resize`] for the bounds.
+    pub fn set_receive_window(&self, bytes: u32) {
+        self.window.resize(bytes);
+    }
+}
+
+impl fmt::Debug for Tunnel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tunnel")
+            .field("request_id", &self.request_id)
+            .field("peer", &self.peer)
+            .field("finished", &self.finished)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        // Without a reader the agent would hold the connection open until the target closed it
+        if !self.finished {
+            self.window.reset();
+        }
+    }
+}
+
+impl AsyncRead for Tunnel {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.buffered.is_empty() {
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+            // The last chunk is read, so the agent may send another
+            this.window.grant(std::mem::take(&mut this.taken));
+            match ready!(this.responses.poll_recv(cx)) {
+                Some(Response::TunnelData { data, .. }) => {
+                    this.taken = data.len();
+                    this.buffered = data;
+                }
+                Some(Response::TunnelClosed { .. }) => this.finished = true,
+                Some(Response::Error { error, .. }) => {
+                    this.finished = true;
+                    return Poll::Ready(Err(io::Error::other(format!("Tunnel failed: {}", error.message))));
+                }
+                Some(_) => {
+                    this.finished = true;
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected response type")));
+                }
+                None => {
+                    this.finished = true;
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Tunnel stream closed before the connection did",
+                    )));
+                }
+            }
+        }
+        
+        let len = buf.remaining().min(this.buffered.len());
+        buf.put_slice(&this.buffered.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Tunnel {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(input) = &this.input else {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "Tunnel is shut down")));
+        };
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        
+        let reserving = this.reserving.get_or_insert_with(|| Box::pin(input.clone().reserve_owned()));
+        let permit = ready!(reserving.as_mut().poll(cx));
+        this.reserving = None;
+        match permit {
+            Ok(permit) => {
+                let len = buf.len().min(MAX_TUNNEL_WRITE);
+                permit.send(Bytes::copy_from_slice(&buf[..len]));
+                Poll::Ready(Ok(len))
+            }
+            Err(_) => Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "Tunnel connection is closed"))),
+        }
+    }
+    
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.reserving = None;
+        this.input = None;
+        Poll::Ready(Ok(()))
+    }
+}
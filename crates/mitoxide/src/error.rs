@@ -1,11 +1,30 @@
 //! Error types for the Mitoxide library
 
 use thiserror::Error;
+use std::backtrace::Backtrace;
+use std::fmt;
 use std::time::Duration;
 
 /// Main error type for Mitoxide operations
+///
+/// An error is of one [`ErrorKind`], which [`MitoxideError::kind`] returns,
+/// and captures a [`Backtrace`] of where it was created, which
+/// [`MitoxideError::backtrace`] returns. Capturing follows the standard
+/// library: it only happens when `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`
+/// enables it, and otherwise costs nothing. Create errors with the
+/// lowercase constructors, such as [`MitoxideError::protocol`], or from an
+/// [`ErrorKind`], so the backtrace is captured for you.
+#[derive(Debug)]
+pub struct MitoxideError {
+    /// What went wrong
+    kind: ErrorKind,
+    /// Where the error was created
+    backtrace: Box<Backtrace>,
+}
+
+/// Kinds of [`MitoxideError`]
 #[derive(Debug, Error)]
-pub enum MitoxideError {
+pub enum ErrorKind {
     /// Transport-related errors
     #[error("Transport error: {0}")]
     Transport(String),
@@ -51,35 +70,126 @@ pub enum MitoxideError {
     Session(String),
 }
 
+impl MitoxideError {
+    /// Transport-related error
+    pub fn transport(message: impl Into<String>) -> Self {
+        ErrorKind::Transport(message.into()).into()
+    }
+    
+    /// Protocol-related error
+    pub fn protocol(message: impl Into<String>) -> Self {
+        ErrorKind::Protocol(message.into()).into()
+    }
+    
+    /// Agent-related error
+    pub fn agent(message: impl Into<String>) -> Self {
+        ErrorKind::Agent(message.into()).into()
+    }
+    
+    /// Authentication error
+    pub fn auth(message: impl Into<String>) -> Self {
+        ErrorKind::Auth(message.into()).into()
+    }
+    
+    /// Timeout after `duration`
+    pub fn timeout(duration: Duration) -> Self {
+        ErrorKind::Timeout { duration }.into()
+    }
+    
+    /// WASM execution error
+    #[cfg(feature = "wasm")]
+    pub fn wasm(message: impl Into<String>) -> Self {
+        ErrorKind::Wasm(message.into()).into()
+    }
+    
+    /// Serialization error
+    pub fn serialization(message: impl Into<String>) -> Self {
+        ErrorKind::Serialization(message.into()).into()
+    }
+    
+    /// Connection error
+    pub fn connection(message: impl Into<String>) -> Self {
+        ErrorKind::Connection(message.into()).into()
+    }
+    
+    /// Session error
+    pub fn session(message: impl Into<String>) -> Self {
+        ErrorKind::Session(message.into()).into()
+    }
+    
+    /// What went wrong
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+    
+    /// What went wrong, dropping the backtrace
+    pub fn into_kind(self) -> ErrorKind {
+        self.kind
+    }
+    
+    /// Where the error was created
+    ///
+    /// Disabled unless backtraces were enabled through the environment
+    /// when the error was created.
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+}
+
+impl From<ErrorKind> for MitoxideError {
+    fn from(kind: ErrorKind) -> Self {
+        Self { kind, backtrace: Box::new(Backtrace::capture()) }
+    }
+}
+
+impl fmt::Display for MitoxideError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for MitoxideError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.kind.source()
+    }
+}
+
+impl From<std::io::Error> for MitoxideError {
+    fn from(err: std::io::Error) -> Self {
+        ErrorKind::Io(err).into()
+    }
+}
+
 impl From<mitoxide_ssh::TransportError> for MitoxideError {
     fn from(err: mitoxide_ssh::TransportError) -> Self {
         match err {
-            mitoxide_ssh::TransportError::Connection(msg) => Self::Connection(msg),
-            mitoxide_ssh::TransportError::Bootstrap(msg) => Self::Agent(msg),
-            mitoxide_ssh::TransportError::Protocol(msg) => Self::Protocol(msg),
-            mitoxide_ssh::TransportError::Io(e) => Self::Io(e),
-            mitoxide_ssh::TransportError::Authentication(msg) => Self::Auth(msg),
-            mitoxide_ssh::TransportError::Timeout => Self::Timeout { duration: Duration::from_secs(30) },
-            mitoxide_ssh::TransportError::Configuration(msg) => Self::Protocol(msg),
-            mitoxide_ssh::TransportError::CommandFailed { .. } => Self::Agent("Command failed".to_string()),
+            mitoxide_ssh::TransportError::Connection(msg) => Self::connection(msg),
+            mitoxide_ssh::TransportError::Bootstrap(msg) => Self::agent(msg),
+            mitoxide_ssh::TransportError::Protocol(msg) => Self::protocol(msg),
+            mitoxide_ssh::TransportError::Io(e) => Self::from(e),
+            mitoxide_ssh::TransportError::Authentication(msg) => Self::auth(msg),
+            mitoxide_ssh::TransportError::Timeout => Self::timeout(Duration::from_secs(30)),
+            mitoxide_ssh::TransportError::Configuration(msg) => Self::protocol(msg),
+            mitoxide_ssh::TransportError::CommandFailed { .. } => Self::agent("Command failed"),
+            err @ mitoxide_ssh::TransportError::CircuitOpen { .. } => Self::connection(err.to_string()),
         }
     }
 }
 
 impl From<rmp_serde::encode::Error> for MitoxideError {
     fn from(err: rmp_serde::encode::Error) -> Self {
-        Self::Serialization(format!("MessagePack encode error: {}", err))
+        Self::serialization(format!("MessagePack encode error: {}", err))
     }
 }
 
 impl From<rmp_serde::decode::Error> for MitoxideError {
     fn from(err: rmp_serde::decode::Error) -> Self {
-        Self::Serialization(format!("MessagePack decode error: {}", err))
+        Self::serialization(format!("MessagePack decode error: {}", err))
     }
 }
 
 impl From<serde_json::Error> for MitoxideError {
     fn from(err: serde_json::Error) -> Self {
-        Self::Serialization(format!("JSON error: {}", err))
+        Self::serialization(format!("JSON error: {}", err))
     }
 }
\ No newline at end of file
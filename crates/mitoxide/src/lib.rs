@@ -22,10 +22,24 @@ pub mod context;
 /// Connection routing and multiplexing
 pub mod router;
 
-pub use error::MitoxideError;
-pub use session::{Session, SessionBuilder, ConnectedSession};
-pub use context::Context;
+/// Failover between connections to equivalent targets
+pub mod route_table;
+
+/// File, process and WASM operations of a session as separate handles
+pub mod api;
+
+/// In-memory transport and agent for tests
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+
+pub use error::{ErrorKind, MitoxideError};
+pub use session::{Session, SessionBuilder, SessionPool, ConnectedSession};
+pub use context::{AgentSession, Context, CommandBuilder, DirListStream, ExecDefaults, FileTail, PingStats, ProcessEvent, ProcessStream, PutOutcome, PtyEvent, PtySession, ResponseStream, SyncOptions, SyncReport, Tunnel, PARALLEL_DOWNLOAD_THRESHOLD};
 pub use router::Router;
+pub use route_table::RouteTable;
+pub use api::{FileApi, ProcessApi};
+#[cfg(feature = "wasm")]
+pub use api::WasmApi;
 
 /// Result type alias for Mitoxide operations
 pub type Result<T> = std::result::Result<T, MitoxideError>;
\ No newline at end of file
@@ -0,0 +1,177 @@
+//! Failover between connections to equivalent targets
+//!
+//! A [`RouteTable`] maps a logical target name to one or more routers, each
+//! connected to an agent that can serve the target. Requests go to the first
+//! healthy route; when a connection dies, its route leaves the rotation and
+//! idempotent requests that were in flight on it are retried on the next one.
+
+use crate::{ErrorKind, MitoxideError, Result, Router};
+use mitoxide_proto::{Message, Request, Response};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+/// Default time a health probe waits for a pong
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One connection able to serve a target
+struct Route {
+    /// Router for the connection
+    router: Arc<Router>,
+    /// Cleared when the connection dies or fails a probe, set again when a probe succeeds
+    healthy: AtomicBool,
+}
+
+impl Route {
+    /// Whether requests should be sent over this route
+    fn is_usable(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst) && self.router.is_connected()
+    }
+}
+
+/// Routes to logical targets, in failover order
+pub struct RouteTable {
+    /// Routes by target, primary first
+    routes: RwLock<HashMap<String, Vec<Arc<Route>>>>,
+    /// How long a probe waits for a pong before marking the route unhealthy
+    probe_timeout: Duration,
+}
+
+impl RouteTable {
+    /// Create an empty route table
+    pub fn new() -> Self {
+        Self {
+            routes: RwLock::new(HashMap::new()),
+            probe_timeout: DEFAULT_PROBE_TIMEOUT,
+        }
+    }
+    
+    /// Set how long health probes wait for a pong
+    pub fn with_probe_timeout(mut self, probe_timeout: Duration) -> Self {
+        self.probe_timeout = probe_timeout;
+        self
+    }
+    
+    /// Add a route to `target`, after any it already has
+    ///
+    /// The first route added is the primary; the others are alternates tried in
+    /// the order they were added.
+    pub async fn add_route(&self, target: impl Into<String>, router: Arc<Router>) {
+        let target = target.into();
+        debug!("Adding route to {}", target);
+        self.routes.write().await.entry(target).or_default().push(Arc::new(Route {
+            router,
+            healthy: AtomicBool::new(true),
+        }));
+    }
+    
+    /// Remove every route to `target`
+    pub async fn remove_target(&self, target: &str) {
+        self.routes.write().await.remove(target);
+    }
+    
+    /// Number of routes to `target`
+    pub async fn route_count(&self, target: &str) -> usize {
+        self.routes.read().await.get(target).map_or(0, Vec::len)
+    }
+    
+    /// Number of routes to `target` requests would currently be sent over
+    pub async fn healthy_route_count(&self, target: &str) -> usize {
+        self.routes.read().await.get(target)
+            .map_or(0, |routes| routes.iter().filter(|route| route.is_usable()).count())
+    }
+    
+    /// Send a message to `target` over its first healthy route
+    ///
+    /// If the connection dies before the response arrives, the route is
+    /// marked unhealthy and an idempotent request is retried on the next
+    /// healthy route. Other requests may already have run, so the connection
+    /// error is returned for them instead.
+    pub async fn send_message(&self, target: &str, message: Message) -> Result<Response> {
+        let routes = self.routes.read().await.get(target).cloned()
+            .ok_or_else(|| MitoxideError::connection(format!("No route to {}", target)))?;
+        let retryable = matches!(&message, Message::Request(request) if request.is_idempotent());
+        
+        let mut last_error = None;
+        for route in routes.iter().filter(|route| route.is_usable()) {
+            match route.router.send_message(message.clone()).await {
+                Err(error) if matches!(error.kind(), ErrorKind::Connection(..)) => {
+                    warn!("Route to {} failed: {}", target, error);
+                    route.healthy.store(false, Ordering::SeqCst);
+                    if !retryable {
+                        return Err(error);
+                    }
+                    last_error = Some(error);
+                }
+                result => return result,
+            }
+        }
+        
+        Err(last_error.unwrap_or_else(|| MitoxideError::connection(format!("No healthy route to {}", target))))
+    }
+    
+    /// Send a request to `target`, failing over as [`RouteTable::send_message`] does
+    pub async fn send_request(&self, target: &str, request: Request) -> Result<Response> {
+        self.send_message(target, Message::request(request)).await
+    }
+    
+    /// Ping every route, updating its health, and return how many are healthy
+    ///
+    /// Routes whose connection has died stay unhealthy; routes that answer
+    /// the ping come back into rotation.
+    pub async fn probe(&self) -> usize {
+        let routes: Vec<(String, Arc<Route>)> = self.routes.read().await.iter()
+            .flat_map(|(target, routes)| routes.iter().map(move |route| (target.clone(), route.clone())))
+            .collect();
+        
+        let mut healthy = 0;
+        for (target, route) in routes {
+            let alive = route.router.is_connected()
+                && matches!(
+                    timeout(self.probe_timeout, route.router.send_message(Message::request(Request::ping()))).await,
+                    Ok(Ok(Response::Pong { .. }))
+                );
+            let was_healthy = route.healthy.swap(alive, Ordering::SeqCst);
+            match (was_healthy, alive) {
+                (true, false) => warn!("Route to {} failed its health probe", target),
+                (false, true) => info!("Route to {} is healthy again", target),
+                _ => {}
+            }
+            if alive {
+                healthy += 1;
+            }
+        }
+        healthy
+    }
+    
+    /// Probe every route each `interval` in the background
+    ///
+    /// The task stops when the last other reference to the table is dropped.
+    pub fn spawn_health_checks(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let table = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(table) = table.upgrade() else {
+                    break;
+                };
+                table.probe().await;
+            }
+        })
+    }
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,103 @@
+//! Unit tests for route failover
+
+use super::*;
+use crate::test_support::spawn_agent;
+use async_trait::async_trait;
+use mitoxide_agent::agent::Handler;
+use tokio::task::JoinHandle;
+
+/// Answers every request with a pong carrying its own tag as the timestamp, after a delay
+struct TaggedPing {
+    tag: u64,
+    delay: Duration,
+}
+
+#[async_trait]
+impl Handler for TaggedPing {
+    async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+        tokio::time::sleep(self.delay).await;
+        Ok(Response::pong(request.id(), self.tag))
+    }
+}
+
+/// Connect a router to an agent through a relay task; aborting the relay kills the connection
+async fn connect_tagged(tag: u64, delay: Duration) -> (Arc<Router>, JoinHandle<()>) {
+    let (client_io, mut relay_client) = tokio::io::duplex(64 * 1024);
+    let (mut relay_agent, agent_io) = tokio::io::duplex(64 * 1024);
+    let handler: Arc<dyn Handler> = Arc::new(TaggedPing { tag, delay });
+    let handlers = [("ping".to_string(), handler.clone()), ("process_exec".to_string(), handler)];
+    spawn_agent(agent_io, &handlers, None, None).await;
+    
+    let relay = tokio::spawn(async move {
+        let _ = tokio::io::copy_bidirectional(&mut relay_client, &mut relay_agent).await;
+    });
+    
+    let (reader, writer) = tokio::io::split(client_io);
+    let (router, _shutdown_tx) = Router::with_io(reader, writer, 16, Duration::from_secs(10)).unwrap();
+    (Arc::new(router), relay)
+}
+
+/// Tag of the agent that answered a ping
+fn pong_tag(response: Response) -> u64 {
+    match response {
+        Response::Pong { timestamp, .. } => timestamp,
+        other => panic!("Expected Pong, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_failover_to_secondary_when_primary_dies() {
+    let (primary, primary_relay) = connect_tagged(1, Duration::from_millis(300)).await;
+    let (secondary, _secondary_relay) = connect_tagged(2, Duration::ZERO).await;
+    
+    let table = Arc::new(RouteTable::new());
+    table.add_route("web", primary.clone()).await;
+    table.add_route("web", secondary).await;
+    
+    assert_eq!(pong_tag(table.send_request("web", Request::ping()).await.unwrap()), 1);
+    
+    // Kill the primary while a ping is in flight on it; the ping is retried on the secondary
+    let in_flight = {
+        let table = table.clone();
+        tokio::spawn(async move { table.send_request("web", Request::ping()).await })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    primary_relay.abort();
+    
+    assert_eq!(pong_tag(in_flight.await.unwrap().unwrap()), 2);
+    assert!(!primary.is_connected());
+    assert_eq!(table.healthy_route_count("web").await, 1);
+    
+    // Later requests go straight to the secondary
+    assert_eq!(pong_tag(table.send_request("web", Request::ping()).await.unwrap()), 2);
+    assert_eq!(table.probe().await, 1);
+}
+
+#[tokio::test]
+async fn test_non_idempotent_request_is_not_retried() {
+    let (primary, primary_relay) = connect_tagged(1, Duration::from_millis(300)).await;
+    let (secondary, _secondary_relay) = connect_tagged(2, Duration::ZERO).await;
+    
+    let table = Arc::new(RouteTable::new());
+    table.add_route("web", primary).await;
+    table.add_route("web", secondary).await;
+    
+    let in_flight = {
+        let table = table.clone();
+        let request = Request::process_exec(vec!["true".to_string()], Default::default(), None, None, None);
+        tokio::spawn(async move { table.send_request("web", request).await })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    primary_relay.abort();
+    
+    assert!(matches!(in_flight.await.unwrap().unwrap_err().kind(), ErrorKind::Connection(..)));
+}
+
+#[tokio::test]
+async fn test_unknown_target() {
+    let table = RouteTable::new();
+    assert!(matches!(
+        table.send_request("nowhere", Request::ping()).await.unwrap_err().kind(),
+        ErrorKind::Connection(..)
+    ));
+}
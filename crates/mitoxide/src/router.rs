@@ -1,57 +1,386 @@
 //! Connection routing and multiplexing
 
 use crate::{Result, MitoxideError};
-use mitoxide_proto::{Message, Response, Frame, FrameCodec, FrameFlags};
-use mitoxide_proto::message::{ErrorDetails, ErrorCode};
+use mitoxide_proto::{CompressedReader, CompressedWriter, CompressionDictionary, Event, EventKind, FlowControlMessage, Message, RateLimit, RateLimiter, Response, Frame, FrameCodec};
+use mitoxide_proto::message::{ErrorDetails, ErrorCode, QosClass, StreamCompression, DEFAULT_ATTACHMENT_THRESHOLD, STREAM_INPUT_WINDOW};
 use mitoxide_ssh::Connection;
-use std::collections::HashMap;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{mpsc, oneshot, RwLock, Mutex};
-use tokio::time::timeout;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, Notify, RwLock, Mutex, Semaphore};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 /// Connection router for managing multiple connections and request/response correlation
 pub struct Router {
     /// Pending requests waiting for responses
-    pending_requests: Arc<RwLock<HashMap<Uuid, oneshot::Sender<Response>>>>,
-    /// Message sender to the connection handler
-    message_tx: mpsc::Sender<Message>,
+    pending_requests: PendingMap,
+    /// Event subscribers
+    subscribers: SubscriberList,
+    /// Outbound sender to the connection handler
+    message_tx: mpsc::Sender<Outbound>,
+    /// Window updates and resets for streams, sent to the connection handler
+    control_tx: mpsc::UnboundedSender<StreamControl>,
     /// Shutdown sender
     shutdown_tx: mpsc::Sender<()>,
-    /// Request timeout
+    /// Longest a request waits without a response or a sign of life from the agent
     request_timeout: Duration,
+    /// Signalled when the agent reports that a waiting request is still running
+    activity: ActivityMap,
+    /// Bounds the requests in flight, if the agent advertised a limit or a pipeline depth is set
+    in_flight_limit: RwLock<Option<Arc<InFlightLimit>>>,
+    /// Limits the in-flight bound is the smallest of
+    in_flight_caps: RwLock<InFlightCaps>,
+    /// Cleared once the connection handler stops
+    connected: Arc<AtomicBool>,
+    /// Shared with the connection handler, which primes stream compression with it
+    compression_dictionary: CompressionDictionarySlot,
+    /// Shared with the connection handler, which sends file content from this size up as attachments
+    attachment_threshold: AttachmentThresholdSlot,
+    /// Paces the input of all requests, if the connection has a send rate limit
+    send_limiter: RwLock<Option<Arc<RateLimiter>>>,
+    /// Send rate limit for the input of each request
+    stream_send_rate_limit: RwLock<Option<RateLimit>>,
+}
+
+/// Dictionary for stream compression, set before the session is negotiated
+type CompressionDictionarySlot = Arc<RwLock<Option<CompressionDictionary>>>;
+
+/// Attachment threshold of the connection, replaced by the one the session agreed on
+type AttachmentThresholdSlot = Arc<RwLock<Option<usize>>>;
+
+/// Caps on the requests in flight, from the agent and from the client
+#[derive(Debug, Clone, Copy, Default)]
+struct InFlightCaps {
+    /// Concurrent request limit advertised by the agent
+    agent: Option<usize>,
+    /// Pipeline depth asked for by the client
+    pipeline_depth: Option<usize>,
+}
+
+impl InFlightCaps {
+    /// The tighter of the two caps, if either is set
+    fn effective(&self) -> Option<usize> {
+        match (self.agent, self.pipeline_depth) {
+            (Some(agent), Some(depth)) => Some(agent.min(depth)),
+            (agent, depth) => agent.or(depth),
+        }
+    }
+}
+
+/// Bounds the requests in flight, handing freed slots to waiting requests
+/// of the highest QoS class first
+struct InFlightLimit {
+    /// Free slots and waiting requests
+    state: std::sync::Mutex<LimitState>,
+}
+
+/// Free slots and waiting requests of an [`InFlightLimit`]
+struct LimitState {
+    /// Slots nobody holds
+    available: usize,
+    /// Requests waiting for a slot by class, oldest first
+    waiters: BTreeMap<QosClass, VecDeque<oneshot::Sender<InFlightSlot>>>,
+}
+
+/// A request's slot under an [`InFlightLimit`], released on drop
+struct InFlightSlot {
+    /// Limit the slot belongs to; `None` once it has been given back
+    limit: Option<Arc<InFlightLimit>>,
+}
+
+impl InFlightLimit {
+    /// Create a limit with `max` slots
+    fn new(max: usize) -> Self {
+        Self {
+            state: std::sync::Mutex::new(LimitState {
+                available: max,
+                waiters: BTreeMap::new(),
+            }),
+        }
+    }
+    
+    /// Wait for a slot, behind requests of the same or a higher class
+    async fn acquire(self: &Arc<Self>, qos: QosClass) -> Result<InFlightSlot> {
+        let slot_rx = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.available > 0 {
+                state.available -= 1;
+                return Ok(InFlightSlot { limit: Some(Arc::clone(self)) });
+            }
+            let (slot_tx, slot_rx) = oneshot::channel();
+            state.waiters.entry(qos).or_default().push_back(slot_tx);
+            slot_rx
+        };
+        slot_rx.await
+            .map_err(|_| MitoxideError::protocol("In-flight limit closed".to_string()))
+    }
+}
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        let Some(limit) = self.limit.take() else {
+            return;
+        };
+        let mut state = limit.state.lock().unwrap_or_else(|e| e.into_inner());
+        
+        // Hand the slot straight to the next waiter that is still there
+        while let Some(mut class) = state.waiters.last_entry() {
+            let slot_tx = class.get_mut().pop_front();
+            if class.get().is_empty() {
+                class.remove();
+            }
+            let Some(slot_tx) = slot_tx else {
+                continue;
+            };
+            match slot_tx.send(InFlightSlot { limit: Some(Arc::clone(&limit)) }) {
+                Ok(()) => return,
+                // The waiter gave up; keep the slot from releasing itself again
+                Err(mut slot) => slot.limit = None,
+            }
+        }
+        state.available += 1;
+    }
+}
+
+/// Requests waiting for responses, keyed by request ID
+type PendingMap = Arc<RwLock<HashMap<Uuid, PendingRequest>>>;
+
+/// How a pending request receives its responses
+enum PendingRequest {
+    /// Caller waits for a single response
+    Single(oneshot::Sender<Response>),
+    /// Caller consumes partial responses followed by the final one
+    Stream {
+        /// Delivery channel
+        tx: mpsc::UnboundedSender<Response>,
+        /// In-flight slot, released with the final response
+        _slot: Option<InFlightSlot>,
+    },
+}
+
+impl PendingRequest {
+    /// Deliver a response, returning false if the caller has gone away
+    fn deliver(self, response: Response) -> bool {
+        match self {
+            Self::Single(tx) => tx.send(response).is_ok(),
+            Self::Stream { tx, .. } => tx.send(response).is_ok(),
+        }
+    }
+}
+
+/// Event subscribers, in subscription order
+type SubscriberList = Arc<RwLock<Vec<Subscriber>>>;
+
+/// Keepalive notifications for requests waiting on a timeout, by request ID
+type ActivityMap = Arc<RwLock<HashMap<Uuid, Arc<Notify>>>>;
+
+/// A subscription to agent events
+struct Subscriber {
+    /// Event kinds wanted; empty means all
+    kinds: HashSet<EventKind>,
+    /// Delivery channel
+    tx: mpsc::UnboundedSender<Event>,
+}
+
+impl Subscriber {
+    /// Deliver an event if it is wanted, returning false if the subscriber has gone away
+    fn deliver(&self, event: &Event) -> bool {
+        if !self.kinds.is_empty() && !self.kinds.contains(&event.kind()) {
+            return !self.tx.is_closed();
+        }
+        self.tx.send(event.clone()).is_ok()
+    }
+}
+
+/// Reader half of the connection to the agent
+type BoxedReader = Box<dyn AsyncRead + Unpin + Send + Sync>;
+
+/// Writer half of the connection to the agent
+type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send + Sync>;
+
+/// Outbound work queued for the connection handler
+enum Outbound {
+    /// A message that opens a new stream
+    Message {
+        /// Message to send
+        message: Message,
+        /// Input the client sends on the stream after the message, if any
+        input: Option<InputStream>,
+    },
+    /// A data chunk on an already opened stream
+    Data {
+        /// Stream ID
+        stream_id: u32,
+        /// Frame sequence number
+        sequence: u32,
+        /// Chunk payload
+        payload: Bytes,
+    },
+    /// End of the client side of a stream
+    End {
+        /// Stream ID
+        stream_id: u32,
+        /// Frame sequence number
+        sequence: u32,
+    },
+    /// Flush the writer, then report back; everything queued before is written by then
+    Flush {
+        /// Told how the flush went
+        done: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// Control of a stream's responses, sent ahead of queued outbound work
+#[derive(Debug)]
+enum StreamControl {
+    /// Give the agent credit for `delta` more bytes of partial responses
+    WindowUpdate {
+        /// Stream ID
+        stream_id: u32,
+        /// Bytes of credit
+        delta: u32,
+    },
+    /// Let the agent keep `window_size` bytes of partial responses in flight
+    Resize {
+        /// Stream ID
+        stream_id: u32,
+        /// New window size
+        window_size: u32,
+    },
+    /// End the stream's request, whatever it is doing
+    Reset {
+        /// Stream ID
+        stream_id: u32,
+    },
+}
+
+/// The client's side of a request's output window, from [`Router::send_message_windowed`]
+///
+/// The agent sends at most [`STREAM_OUTPUT_WINDOW`](mitoxide_proto::message::STREAM_OUTPUT_WINDOW) bytes of data ahead of
+/// what is granted back with [`OutputWindow::grant`].
+#[derive(Debug)]
+pub struct OutputWindow {
+    /// Stream the request was sent on
+    stream_id: u32,
+    /// Shared with the router
+    control_tx: mpsc::UnboundedSender<StreamControl>,
+}
+
+impl OutputWindow {
+    /// Return credit for `bytes` of data taken from the responses
+    pub fn grant(&self, bytes: usize) {
+        if bytes > 0 {
+            let _ = self.control_tx.send(StreamControl::WindowUpdate { stream_id: self.stream_id, delta: bytes as u32 });
+        }
+    }
+    
+    /// Let the agent keep up to `window_size` bytes of data in flight from now on
+    ///
+    /// A larger window keeps a fast link busy on a long transfer; a smaller
+    /// one bounds what a slow reader has queued. The agent clamps the size to
+    /// between [`MIN_STREAM_OUTPUT_WINDOW`](mitoxide_proto::message::MIN_STREAM_OUTPUT_WINDOW)
+    /// and [`MAX_STREAM_OUTPUT_WINDOW`](mitoxide_proto::message::MAX_STREAM_OUTPUT_WINDOW),
+    /// and takes data already in flight out of a shrunk window as it is granted back.
+    pub fn resize(&self, window_size: u32) {
+        let _ = self.control_tx.send(StreamControl::Resize { stream_id: self.stream_id, window_size });
+    }
+    
+    /// Have the agent end the request straight away, dropping whatever it hasn't sent
+    pub fn reset(&self) {
+        let _ = self.control_tx.send(StreamControl::Reset { stream_id: self.stream_id });
+    }
+}
+
+/// Client input on a stream opened by an [`Outbound::Message`]
+struct InputStream {
+    /// Reports the stream ID assigned to the message
+    stream_id_tx: oneshot::Sender<u32>,
+    /// Bytes of input the agent has room for, topped up by its window updates
+    /// and closed once the request is over
+    credit: Arc<Semaphore>,
 }
 
 impl Router {
     /// Create a new router with connection
     pub async fn new(
-        connection: Connection,
+        mut connection: Connection,
+        max_streams: u32,
+        timeout: Duration,
+    ) -> Result<(Self, mpsc::Sender<()>)> {
+        if let Some((reader, writer)) = connection.take_io() {
+            return Self::start(reader, writer, Some(connection), max_streams, timeout);
+        }
+        
+        let process = connection.process_mut()
+            .ok_or_else(|| MitoxideError::connection("Connection has no process".to_string()))?;
+        let stdin = process.stdin.take()
+            .ok_or_else(|| MitoxideError::connection("No stdin available".to_string()))?;
+        let stdout = process.stdout.take()
+            .ok_or_else(|| MitoxideError::connection("No stdout available".to_string()))?;
+        
+        Self::start(Box::new(stdout), Box::new(stdin), Some(connection), max_streams, timeout)
+    }
+    
+    /// Create a new router over custom reader/writer streams
+    pub fn with_io<R, W>(
+        reader: R,
+        writer: W,
+        max_streams: u32,
+        timeout: Duration,
+    ) -> Result<(Self, mpsc::Sender<()>)>
+    where
+        R: AsyncRead + Unpin + Send + Sync + 'static,
+        W: AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        Self::start(Box::new(reader), Box::new(writer), None, max_streams, timeout)
+    }
+    
+    /// Start the connection handler task and build the router
+    fn start(
+        reader: BoxedReader,
+        writer: BoxedWriter,
+        connection: Option<Connection>,
         max_streams: u32,
         timeout: Duration,
     ) -> Result<(Self, mpsc::Sender<()>)> {
         let (message_tx, message_rx) = mpsc::channel(max_streams as usize);
-        let (_shutdown_tx, shutdown_rx) = mpsc::channel(1);
-        let (router_shutdown_tx, _router_shutdown_rx) = mpsc::channel(1);
+        let (router_shutdown_tx, shutdown_rx) = mpsc::channel(1);
         
         let pending_requests = Arc::new(RwLock::new(HashMap::new()));
-        
-        let router = Self {
-            pending_requests: pending_requests.clone(),
-            message_tx,
-            shutdown_tx: router_shutdown_tx.clone(),
-            request_timeout: timeout,
-        };
+        let subscribers = Arc::new(RwLock::new(Vec::new()));
         
         // Start connection handler task
         let connection_handler = ConnectionHandler::new(
+            reader,
+            writer,
             connection,
             message_rx,
-            pending_requests,
+            pending_requests.clone(),
+            subscribers.clone(),
             shutdown_rx,
         );
         
+        let router = Self {
+            pending_requests,
+            subscribers,
+            message_tx,
+            control_tx: connection_handler.control_tx.clone(),
+            shutdown_tx: router_shutdown_tx.clone(),
+            request_timeout: timeout,
+            activity: connection_handler.activity.clone(),
+            in_flight_limit: RwLock::new(None),
+            in_flight_caps: RwLock::new(InFlightCaps::default()),
+            connected: connection_handler.connected.clone(),
+            compression_dictionary: connection_handler.compression_dictionary.clone(),
+            attachment_threshold: connection_handler.attachment_threshold.clone(),
+            send_limiter: RwLock::new(None),
+            stream_send_rate_limit: RwLock::new(None),
+        };
+        
         tokio::spawn(async move {
             if let Err(e) = connection_handler.run().await {
                 error!("Connection handler error: {}", e);
@@ -61,39 +390,338 @@ impl Router {
         Ok((router, router_shutdown_tx))
     }
     
+    /// Whether the connection to the agent is still up
+    ///
+    /// Once it drops, requests waiting for a response fail with
+    /// [`ErrorKind::Connection`](crate::ErrorKind::Connection) instead of running into their timeout.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+    
+    /// Subscribe to events pushed by the agent
+    ///
+    /// Only events of the given kinds are delivered; an empty slice subscribes to
+    /// all of them. The subscription ends when the receiver is dropped.
+    pub async fn subscribe(&self, kinds: &[EventKind]) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let subscriber = Subscriber {
+            kinds: kinds.iter().copied().collect(),
+            tx,
+        };
+        self.subscribers.write().await.push(subscriber);
+        rx
+    }
+    
+    /// Bound the number of requests in flight at the agent
+    ///
+    /// Requests beyond the limit are queued locally until an earlier one
+    /// completes, and start by QoS class, oldest first within a class.
+    /// Requests already in flight are not counted. A pipeline depth set with
+    /// [`Router::set_pipeline_depth`] still applies if it is smaller.
+    pub async fn set_max_in_flight(&self, max: usize) {
+        let mut caps = self.in_flight_caps.write().await;
+        caps.agent = Some(max);
+        self.apply_in_flight_caps(*caps).await;
+    }
+    
+    /// Keep up to `depth` requests in flight on the connection, each on its own stream
+    ///
+    /// Callers can issue requests concurrently without waiting for earlier
+    /// responses, which are matched back by request ID; requests beyond the
+    /// depth queue locally as they do under [`Router::set_max_in_flight`]. A
+    /// smaller limit advertised by the agent still applies.
+    pub async fn set_pipeline_depth(&self, depth: usize) {
+        let mut caps = self.in_flight_caps.write().await;
+        caps.pipeline_depth = Some(depth.max(1));
+        self.apply_in_flight_caps(*caps).await;
+    }
+    
+    /// Offer `dictionary` for stream compression when the session is negotiated
+    ///
+    /// Only used if the agent has the same dictionary, and only by a session
+    /// negotiated after it is set.
+    pub async fn set_compression_dictionary(&self, dictionary: CompressionDictionary) {
+        *self.compression_dictionary.write().await = Some(dictionary);
+    }
+    
+    /// Send file content of at least `threshold` bytes as frame attachments, or none with None
+    ///
+    /// Defaults to [`DEFAULT_ATTACHMENT_THRESHOLD`]. When the session is
+    /// negotiated the agent is told, and both ends then use the larger of
+    /// their thresholds, or none if either takes no attachments.
+    pub async fn set_attachment_threshold(&self, threshold: Option<usize>) {
+        *self.attachment_threshold.write().await = threshold;
+    }
+    
+    /// Size from which file content is sent as a frame attachment, if any is
+    pub async fn attachment_threshold(&self) -> Option<usize> {
+        *self.attachment_threshold.read().await
+    }
+    
+    /// Pace the input sent with all requests to `limit`, or stop pacing it with None
+    ///
+    /// Input chunks wait for their share of the rate before they are queued,
+    /// so a bulk upload can't saturate the link. Applies to input sent after
+    /// the limit is set.
+    pub async fn set_send_rate_limit(&self, limit: Option<RateLimit>) {
+        *self.send_limiter.write().await = limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+    }
+    
+    /// Pace the input sent with each request to `limit`, under the connection's limit if any
+    pub async fn set_stream_send_rate_limit(&self, limit: Option<RateLimit>) {
+        *self.stream_send_rate_limit.write().await = limit;
+    }
+    
+    /// ID of the dictionary set with [`Router::set_compression_dictionary`], if any
+    pub async fn compression_dictionary_id(&self) -> Option<u32> {
+        self.compression_dictionary.read().await.as_ref().map(CompressionDictionary::id)
+    }
+    
+    /// Pipeline depth set with [`Router::set_pipeline_depth`], if any
+    pub async fn pipeline_depth(&self) -> Option<usize> {
+        self.in_flight_caps.read().await.pipeline_depth
+    }
+    
+    /// Replace the in-flight limit with the tightest of `caps`
+    async fn apply_in_flight_caps(&self, caps: InFlightCaps) {
+        if let Some(max) = caps.effective() {
+            debug!("Limiting requests in flight to {}", max);
+            *self.in_flight_limit.write().await = Some(Arc::new(InFlightLimit::new(max)));
+        }
+    }
+    
     /// Send a message and wait for response
     pub async fn send_message(&self, message: Message) -> Result<Response> {
-        let request_id = message.request_id()
-            .ok_or_else(|| MitoxideError::Protocol("Message has no request ID".to_string()))?;
+        let _slot = self.acquire_slot(&message).await?;
+        let (response_tx, response_rx) = oneshot::channel();
+        self.register(&message, PendingRequest::Single(response_tx)).await?;
+        let request_id = message.request_id();
+        
+        // Send message
+        self.enqueue(Outbound::Message { message, input: None }).await?;
         
-        // Create response channel
+        self.wait_response(request_id, response_rx).await
+    }
+    
+    /// Send a message followed by data chunks on the same stream, then wait for response
+    ///
+    /// The stream is ended once `input` is exhausted.
+    pub async fn send_message_with_input(
+        &self,
+        message: Message,
+        input: mpsc::Receiver<Bytes>,
+    ) -> Result<Response> {
+        let _slot = self.acquire_slot(&message).await?;
         let (response_tx, response_rx) = oneshot::channel();
+        self.register(&message, PendingRequest::Single(response_tx)).await?;
+        let request_id = message.request_id();
         
-        // Register pending request
-        {
-            let mut pending = self.pending_requests.write().await;
-            pending.insert(request_id, response_tx);
+        let (stream_id, credit) = self.open_stream(message).await?;
+        self.feed_input(stream_id, credit, input).await;
+        
+        self.wait_response(request_id, response_rx).await
+    }
+    
+    /// Send a message whose responses arrive as a sequence of partial responses
+    /// followed by a final one, optionally feeding input on the same stream
+    ///
+    /// The returned channel yields every response and closes after the final one.
+    /// No request timeout is applied; the caller decides how long to wait.
+    pub async fn send_message_streaming(
+        &self,
+        message: Message,
+        input: Option<mpsc::Receiver<Bytes>>,
+    ) -> Result<mpsc::UnboundedReceiver<Response>> {
+        let slot = self.acquire_slot(&message).await?;
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        self.register(&message, PendingRequest::Stream { tx: response_tx, _slot: slot }).await?;
+        
+        match input {
+            Some(input) => {
+                let (stream_id, credit) = self.open_stream(message).await?;
+                self.feed_input(stream_id, credit, input).await;
+            }
+            None => {
+                self.enqueue(Outbound::Message { message, input: None }).await?;
+            }
         }
         
-        // Send message
-        self.message_tx.send(message).await
-            .map_err(|_| MitoxideError::Protocol("Failed to send message".to_string()))?;
+        Ok(response_rx)
+    }
+    
+    /// Send a message whose partial responses are flow controlled, optionally feeding `input` on the same stream
+    ///
+    /// Like [`Router::send_message_streaming`], except that the agent only
+    /// sends data as the returned [`OutputWindow`] grants credit for it, so
+    /// a caller reading slowly holds the agent back rather than queueing
+    /// responses without bound. The request must have an output window
+    /// (see [`Request::has_output_window`](mitoxide_proto::Request::has_output_window)).
+    pub async fn send_message_windowed(
+        &self,
+        message: Message,
+        input: Option<mpsc::Receiver<Bytes>>,
+    ) -> Result<(mpsc::UnboundedReceiver<Response>, OutputWindow)> {
+        let slot = self.acquire_slot(&message).await?;
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        self.register(&message, PendingRequest::Stream { tx: response_tx, _slot: slot }).await?;
+        
+        // Opened as a stream either way, for the window to know its ID
+        let (stream_id, credit) = self.open_stream(message).await?;
+        if let Some(input) = input {
+            self.feed_input(stream_id, credit, input).await;
+        }
+        
+        Ok((response_rx, OutputWindow { stream_id, control_tx: self.control_tx.clone() }))
+    }
+    
+    /// Send a message that input follows, returning the stream ID it was sent on and the stream's credit
+    async fn open_stream(&self, message: Message) -> Result<(u32, Arc<Semaphore>)> {
+        let (stream_id_tx, stream_id_rx) = oneshot::channel();
+        let credit = Arc::new(Semaphore::new(STREAM_INPUT_WINDOW as usize));
+        let input = InputStream { stream_id_tx, credit: credit.clone() };
+        self.enqueue(Outbound::Message { message, input: Some(input) }).await?;
+        let stream_id = stream_id_rx.await
+            .map_err(|_| MitoxideError::protocol("Failed to open stream".to_string()))?;
+        Ok((stream_id, credit))
+    }
+    
+    /// Wait until everything queued for the connection before the call is handed to the transport
+    ///
+    /// Requests and input chunks are written in the order they were queued;
+    /// this returns once the connection handler has written the ones queued
+    /// before it and flushed the writer, so frames held back by stream
+    /// compression are sent too. Input still waiting for credit from the agent
+    /// isn't queued yet, and window updates go out ahead of the queue.
+    pub async fn flush(&self) -> Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.enqueue(Outbound::Flush { done: done_tx }).await?;
+        done_rx.await
+            .map_err(|_| MitoxideError::connection("Connection to agent lost".to_string()))?
+    }
+    
+    /// Queue outbound work for the connection handler
+    async fn enqueue(&self, outbound: Outbound) -> Result<()> {
+        self.message_tx.send(outbound).await
+            .map_err(|_| MitoxideError::connection("Connection to agent lost".to_string()))
+    }
+    
+    /// Forward input chunks onto a stream in the background, ending it once exhausted
+    ///
+    /// Running in the background lets the response arrive early (e.g. on error).
+    /// Chunks are only taken from `input` as the agent has room for them, so
+    /// whoever fills it is held back to the agent's pace. Once the request is
+    /// over, the rest of the input is dropped. Under a send rate limit each
+    /// chunk also waits for its share of the stream's and the connection's rate.
+    async fn feed_input(&self, stream_id: u32, credit: Arc<Semaphore>, mut input: mpsc::Receiver<Bytes>) {
+        let message_tx = self.message_tx.clone();
+        let stream_limiter = self.stream_send_rate_limit.read().await.map(RateLimiter::new);
+        let send_limiter = self.send_limiter.read().await.clone();
+        tokio::spawn(async move {
+            let mut sequence = 1;
+            while let Some(mut payload) = input.recv().await {
+                // Empty chunks carry nothing, so they are not worth a frame
+                while !payload.is_empty() {
+                    let len = payload.len().min(STREAM_INPUT_WINDOW as usize);
+                    match credit.acquire_many(len as u32).await {
+                        Ok(permit) => permit.forget(),
+                        Err(_) => return,
+                    }
+                    if let Some(limiter) = &stream_limiter {
+                        limiter.acquire(len).await;
+                    }
+                    if let Some(limiter) = &send_limiter {
+                        limiter.acquire(len).await;
+                    }
+                    let chunk = payload.split_to(len);
+                    if message_tx.send(Outbound::Data { stream_id, sequence, payload: chunk }).await.is_err() {
+                        return;
+                    }
+                    sequence = sequence.wrapping_add(1);
+                }
+            }
+            let _ = message_tx.send(Outbound::End { stream_id, sequence }).await;
+        });
+    }
+    
+    /// Wait for an in-flight slot if the agent limits concurrent requests
+    ///
+    /// Requests the agent answers even at its limit, such as pings and
+    /// signals, don't wait.
+    async fn acquire_slot(&self, message: &Message) -> Result<Option<InFlightSlot>> {
+        if matches!(message, Message::Request(request) if !request.counts_toward_limit()) {
+            return Ok(None);
+        }
+        let limit = self.in_flight_limit.read().await.clone();
+        match limit {
+            Some(limit) => {
+                let qos = match message {
+                    Message::Request(request) => request.qos(),
+                    _ => QosClass::default(),
+                };
+                Ok(Some(limit.acquire(qos).await?))
+            }
+            None => Ok(None),
+        }
+    }
+    
+    /// Register a pending request for the message
+    async fn register(&self, message: &Message, pending_request: PendingRequest) -> Result<()> {
+        let request_id = message.request_id()
+            .ok_or_else(|| MitoxideError::protocol("Message has no request ID".to_string()))?;
         
-        // Wait for response with timeout
-        let response = timeout(self.request_timeout, response_rx).await
-            .map_err(|_| MitoxideError::Timeout { duration: self.request_timeout })?
-            .map_err(|_| MitoxideError::Protocol("Response channel closed".to_string()))?;
+        // Register pending request; checking the connection under the lock means
+        // the handler either sees the request when it stops or we see it stopped
+        let mut pending = self.pending_requests.write().await;
+        if !self.is_connected() {
+            return Err(MitoxideError::connection("Connection to agent lost".to_string()));
+        }
+        pending.insert(request_id, pending_request);
+        
+        Ok(())
+    }
+    
+    /// Wait for a registered response
+    ///
+    /// The request times out once the agent has been silent about it for the
+    /// request timeout; `Event::Keepalive` and `Event::Progress` events for it
+    /// start the wait over, so long-running requests can outlive the timeout.
+    async fn wait_response(&self, request_id: Option<Uuid>, response_rx: oneshot::Receiver<Response>) -> Result<Response> {
+        let activity = Arc::new(Notify::new());
+        if let Some(request_id) = request_id {
+            self.activity.write().await.insert(request_id, activity.clone());
+        }
+        
+        tokio::pin!(response_rx);
+        let result = loop {
+            tokio::select! {
+                response = &mut response_rx => {
+                    break response.map_err(|_| {
+                        MitoxideError::connection("Connection to agent lost before the response arrived".to_string())
+                    });
+                }
+                _ = activity.notified() => {
+                    debug!("Request {:?} is still running, restarting its timeout", request_id);
+                }
+                _ = tokio::time::sleep(self.request_timeout) => {
+                    break Err(MitoxideError::timeout(self.request_timeout));
+                }
+            }
+        };
         
-        Ok(response)
+        if let Some(request_id) = request_id {
+            self.activity.write().await.remove(&request_id);
+        }
+        result
     }
     
     /// Shutdown the router
     pub async fn shutdown(&self) -> Result<()> {
         debug!("Shutting down router");
         
-        // Send shutdown signal
-        self.shutdown_tx.send(()).await
-            .map_err(|_| MitoxideError::Protocol("Failed to send shutdown signal".to_string()))?;
+        // Send shutdown signal; the handler may already have stopped on its own
+        if self.shutdown_tx.send(()).await.is_err() {
+            debug!("Connection handler already stopped");
+        }
         
         // Cancel all pending requests
         let mut pending = self.pending_requests.write().await;
@@ -105,7 +733,7 @@ impl Router {
                     "Router shutdown"
                 )
             );
-            let _ = sender.send(error_response);
+            sender.deliver(error_response);
         }
         
         info!("Router shutdown complete");
@@ -117,35 +745,69 @@ impl Router {
 struct ConnectionHandler {
     /// Frame codec for the connection
     codec: FrameCodec,
-    /// Connection for reading/writing
-    connection: Connection,
-    /// Message receiver from router
-    message_rx: mpsc::Receiver<Message>,
+    /// Stream for reading frames from the agent
+    reader: CompressedReader<BoxedReader>,
+    /// Stream for writing frames to the agent
+    writer: CompressedWriter<BoxedWriter>,
+    /// Underlying connection, kept alive for the handler's lifetime
+    _connection: Option<Connection>,
+    /// Outbound receiver from router
+    message_rx: mpsc::Receiver<Outbound>,
     /// Pending requests map
-    pending_requests: Arc<RwLock<HashMap<Uuid, oneshot::Sender<Response>>>>,
+    pending_requests: PendingMap,
+    /// Event subscribers
+    subscribers: SubscriberList,
+    /// Shared with the router, signalled by keepalives for waiting requests
+    activity: ActivityMap,
     /// Shutdown receiver
     shutdown_rx: mpsc::Receiver<()>,
+    /// Shared with the router, cleared when the handler stops
+    connected: Arc<AtomicBool>,
     /// Next stream ID
     next_stream_id: Arc<Mutex<u32>>,
+    /// Shared with the router, offered for stream compression
+    compression_dictionary: CompressionDictionarySlot,
+    /// Shared with the router, updated when the session agrees on one
+    attachment_threshold: AttachmentThresholdSlot,
+    /// Credit for the input of streams whose request is still running, by stream
+    input_credits: HashMap<u32, Arc<Semaphore>>,
+    /// Shared with the router, for window updates and resets
+    control_tx: mpsc::UnboundedSender<StreamControl>,
+    /// Window updates and resets to send, ahead of queued outbound work
+    control_rx: mpsc::UnboundedReceiver<StreamControl>,
 }
 
 impl ConnectionHandler {
     /// Create a new connection handler
     fn new(
-        connection: Connection,
-        message_rx: mpsc::Receiver<Message>,
-        pending_requests: Arc<RwLock<HashMap<Uuid, oneshot::Sender<Response>>>>,
+        reader: BoxedReader,
+        writer: BoxedWriter,
+        connection: Option<Connection>,
+        message_rx: mpsc::Receiver<Outbound>,
+        pending_requests: PendingMap,
+        subscribers: SubscriberList,
         shutdown_rx: mpsc::Receiver<()>,
     ) -> Self {
         let codec = FrameCodec::new();
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
         
         Self {
             codec,
-            connection,
+            reader: CompressedReader::new(reader),
+            writer: CompressedWriter::new(writer),
+            _connection: connection,
             message_rx,
             pending_requests,
+            subscribers,
+            activity: Arc::new(RwLock::new(HashMap::new())),
             shutdown_rx,
+            connected: Arc::new(AtomicBool::new(true)),
             next_stream_id: Arc::new(Mutex::new(1)),
+            compression_dictionary: Arc::new(RwLock::new(None)),
+            attachment_threshold: Arc::new(RwLock::new(Some(DEFAULT_ATTACHMENT_THRESHOLD))),
+            input_credits: HashMap::new(),
+            control_tx,
+            control_rx,
         }
     }
     
@@ -158,8 +820,8 @@ impl ConnectionHandler {
                 // Handle outgoing messages
                 message = self.message_rx.recv() => {
                     match message {
-                        Some(msg) => {
-                            if let Err(e) = self.send_message(msg).await {
+                        Some(outbound) => {
+                            if let Err(e) = self.send_outbound(outbound).await {
                                 error!("Failed to send message: {}", e);
                             }
                         }
@@ -170,14 +832,15 @@ impl ConnectionHandler {
                     }
                 }
                 
-                // Handle incoming frames
-                frame_result = async {
-                    if let Some(stdout) = self.connection.stdout() {
-                        self.codec.read_frame(stdout).await
-                    } else {
-                        Err(mitoxide_proto::ProtocolError::Serialization("No stdout available".to_string()))
+                // Send window updates and resets
+                Some(control) = self.control_rx.recv() => {
+                    if let Err(e) = self.send_control(control).await {
+                        error!("Failed to send stream control: {}", e);
                     }
-                } => {
+                }
+                
+                // Handle incoming frames
+                frame_result = self.codec.read_frame(&mut self.reader) => {
                     match frame_result {
                         Ok(Some(frame)) => {
                             if let Err(e) = self.handle_incoming_frame(frame).await {
@@ -203,18 +866,70 @@ impl ConnectionHandler {
             }
         }
         
+        // Input still waiting for credit won't get any
+        for (_, credit) in self.input_credits.drain() {
+            credit.close();
+        }
+        
+        // Fail whatever is still waiting rather than letting it time out; dropping
+        // the senders makes every waiter see the connection as lost
+        let abandoned = {
+            let mut pending = self.pending_requests.write().await;
+            self.connected.store(false, Ordering::SeqCst);
+            pending.drain().count()
+        };
+        if abandoned > 0 {
+            warn!("Connection lost with {} requests pending", abandoned);
+        }
+        
         info!("Connection handler stopped");
         Ok(())
     }
     
-    /// Send a message over the connection
-    async fn send_message(&mut self, message: Message) -> Result<()> {
+    /// Send queued outbound work over the connection
+    async fn send_outbound(&mut self, outbound: Outbound) -> Result<()> {
+        match outbound {
+            Outbound::Message { message, input } => {
+                let stream_id = self.send_message(message).await?;
+                if let Some(InputStream { stream_id_tx, credit }) = input {
+                    self.input_credits.insert(stream_id, credit);
+                    let _ = stream_id_tx.send(stream_id);
+                }
+                Ok(())
+            }
+            Outbound::Data { stream_id, sequence, payload } => {
+                self.write_frame(&Frame::data(stream_id, sequence, payload)).await
+            }
+            Outbound::End { stream_id, sequence } => {
+                self.write_frame(&Frame::end_stream(stream_id, sequence)).await
+            }
+            Outbound::Flush { done } => {
+                let flushed = self.writer.flush().await
+                    .map_err(|e| MitoxideError::connection(format!("Failed to flush connection: {}", e)));
+                let _ = done.send(flushed);
+                Ok(())
+            }
+        }
+    }
+    
+    /// Send a window update or reset for a stream
+    async fn send_control(&mut self, control: StreamControl) -> Result<()> {
+        let frame = match control {
+            StreamControl::WindowUpdate { stream_id, delta } => FlowControlMessage::WindowUpdate { delta }
+                .to_frame(stream_id, 0)
+                .map_err(|e| MitoxideError::protocol(format!("Failed to encode window update: {}", e)))?,
+            StreamControl::Resize { stream_id, window_size } => FlowControlMessage::ResizeWindow { window_size }
+                .to_frame(stream_id, 0)
+                .map_err(|e| MitoxideError::protocol(format!("Failed to encode window resize: {}", e)))?,
+            StreamControl::Reset { stream_id } => Frame::error(stream_id, 0, Bytes::from_static(b"reset")),
+        };
+        self.write_frame(&frame).await
+    }
+    
+    /// Send a message over the connection, returning the stream ID it was sent on
+    async fn send_message(&mut self, message: Message) -> Result<u32> {
         debug!("Sending message: {:?}", message);
         
-        // Serialize message
-        let payload = rmp_serde::to_vec(&message)
-            .map_err(|e| MitoxideError::Protocol(format!("Failed to serialize message: {}", e)))?;
-        
         // Get next stream ID
         let stream_id = {
             let mut next_id = self.next_stream_id.lock().await;
@@ -223,59 +938,134 @@ impl ConnectionHandler {
             id
         };
         
-        // Create frame
-        let frame = Frame::new(
-            stream_id,
-            0, // sequence number
-            FrameFlags::NONE,
-            payload.into(),
-        );
+        // Serialize message, with large file content as an attachment
+        let attachment_threshold = *self.attachment_threshold.read().await;
+        let frame = message.to_frame(stream_id, 0, attachment_threshold)
+            .map_err(|e| MitoxideError::protocol(format!("Failed to serialize message: {}", e)))?;
         
         // Send frame
-        if let Some(stdin) = self.connection.stdin() {
-            self.codec.write_frame(stdin, &frame).await
-                .map_err(|e| MitoxideError::Protocol(format!("Failed to write frame: {}", e)))?;
-        } else {
-            return Err(MitoxideError::Protocol("No stdin available".to_string()));
-        }
+        self.write_frame(&frame).await?;
         
-        Ok(())
+        Ok(stream_id)
+    }
+    
+    /// Write a single frame to the connection
+    async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.codec.write_frame(&mut self.writer, frame).await
+            .map_err(|e| MitoxideError::protocol(format!("Failed to write frame: {}", e)))
     }
     
     /// Handle an incoming frame
     async fn handle_incoming_frame(&mut self, frame: Frame) -> Result<()> {
         debug!("Received frame: stream_id={}, len={}", frame.stream_id, frame.payload.len());
         
+        // The agent returns credit for input it has taken
+        if frame.is_flow_control() {
+            let update = FlowControlMessage::from_frame(&frame)
+                .map_err(|e| MitoxideError::protocol(format!("Failed to read flow control frame: {}", e)))?;
+            if let (FlowControlMessage::WindowUpdate { delta }, Some(credit)) = (update, self.input_credits.get(&frame.stream_id)) {
+                credit.add_permits(delta as usize);
+            }
+            return Ok(());
+        }
+        
         // Deserialize message
-        let message: Message = rmp_serde::from_slice(&frame.payload)
-            .map_err(|e| MitoxideError::Protocol(format!("Failed to deserialize message: {}", e)))?;
+        let stream_id = frame.stream_id;
+        let message = Message::from_frame(frame)
+            .map_err(|e| MitoxideError::protocol(format!("Failed to deserialize message: {}", e)))?;
         
         match message {
             Message::Response(response) => {
+                if let Response::SessionOpened { compression, dictionary_id, attachment_threshold, .. } = &response {
+                    *self.attachment_threshold.write().await = attachment_threshold.map(|threshold| threshold as usize);
+                    if let Some(compression) = compression {
+                        self.enable_stream_compression(*compression, *dictionary_id).await?;
+                    }
+                }
+                // The request is over, so input still to come has nowhere to go
+                if !response.is_partial() {
+                    if let Some(credit) = self.input_credits.remove(&stream_id) {
+                        credit.close();
+                    }
+                }
                 self.handle_response(response).await?;
             }
             Message::Request(_) => {
                 warn!("Received unexpected request from remote");
             }
+            Message::Event(event) => {
+                self.handle_event(event).await;
+            }
         }
         
         Ok(())
     }
     
+    /// Switch the connection to stream compression once the agent accepted it
+    ///
+    /// The agent compresses everything after its `SessionOpened`, so bytes the
+    /// codec already buffered past that frame are handed to the decompressor.
+    /// Both ends prime it with the dictionary the agent confirmed, if any.
+    async fn enable_stream_compression(&mut self, compression: StreamCompression, dictionary_id: Option<u32>) -> Result<()> {
+        if self.writer.is_compressed() {
+            return Ok(());
+        }
+        let dictionary = match dictionary_id {
+            Some(id) => match self.compression_dictionary.read().await.clone() {
+                Some(dictionary) if dictionary.id() == id => Some(dictionary),
+                _ => return Err(MitoxideError::protocol(format!("Agent primed stream compression with unknown dictionary {}", id))),
+            },
+            None => None,
+        };
+        info!("Enabling {:?} stream compression (dictionary: {:?})", compression, dictionary_id);
+        self.writer.enable_compression_with(dictionary.as_ref())
+            .and_then(|()| self.reader.enable_compression_with(&self.codec.take_buffered(), dictionary.as_ref()))
+            .map_err(|e| MitoxideError::protocol(format!("Failed to enable stream compression: {}", e)))
+    }
+    
+    /// Deliver an event to its subscribers, dropping those that have gone away
+    ///
+    /// Keepalive and progress events also restart the timeout of the request they are for.
+    async fn handle_event(&self, event: Event) {
+        debug!("Handling event: {:?}", event.kind());
+        
+        if let (EventKind::Keepalive | EventKind::Progress, Some(request_id)) = (event.kind(), event.request_id()) {
+            if let Some(activity) = self.activity.read().await.get(&request_id) {
+                activity.notify_one();
+            }
+        }
+        
+        let mut subscribers = self.subscribers.write().await;
+        subscribers.retain(|subscriber| subscriber.deliver(&event));
+    }
+    
     /// Handle a response message
     async fn handle_response(&self, response: Response) -> Result<()> {
         let request_id = response.request_id();
         debug!("Handling response for request: {}", request_id);
         
-        // Find pending request
-        let sender = {
-            let mut pending = self.pending_requests.write().await;
-            pending.remove(&request_id)
-        };
+        let mut pending = self.pending_requests.write().await;
+        
+        // Partial responses keep the request pending until its final response
+        if response.is_partial() {
+            match pending.get(&request_id) {
+                Some(PendingRequest::Stream { tx, .. }) => {
+                    if tx.send(response).is_err() {
+                        warn!("Failed to send response - receiver dropped");
+                        pending.remove(&request_id);
+                    }
+                }
+                Some(PendingRequest::Single(_)) => {
+                    warn!("Dropping partial response for non-streaming request: {}", request_id);
+                }
+                None => warn!("Received response for unknown request: {}", request_id),
+            }
+            return Ok(());
+        }
         
-        if let Some(sender) = sender {
+        if let Some(sender) = pending.remove(&request_id) {
             // Send response to waiting caller
-            if let Err(_) = sender.send(response) {
+            if !sender.deliver(response) {
                 warn!("Failed to send response - receiver dropped");
             }
         } else {
@@ -187,4 +187,129 @@ async fn test_oneshot_channel_drop() {
 }
 
 // More comprehensive tests would require actual connections and would be better
-// suited for integration tests with Docker containers
\ No newline at end of file
+// suited for integration tests with Docker containers
+#[tokio::test]
+async fn test_events_delivered_to_subscribers() {
+    let (client_io, mut agent_io) = tokio::io::duplex(64 * 1024);
+    let (client_read, client_write) = tokio::io::split(client_io);
+    let (router, _shutdown_tx) = Router::with_io(client_read, client_write, 16, Duration::from_secs(5)).unwrap();
+    
+    let mut progress = router.subscribe(&[EventKind::Progress]).await;
+    let mut all = router.subscribe(&[]).await;
+    let dropped = router.subscribe(&[]).await;
+    drop(dropped);
+    
+    let request_id = Uuid::new_v4();
+    let events = [
+        Event::Log { level: "info".to_string(), message: "starting".to_string() },
+        Event::Progress { request_id, completed: 1, total: Some(2), message: None },
+    ];
+    let codec = FrameCodec::new();
+    for event in &events {
+        let payload = rmp_serde::to_vec(&Message::event(event.clone())).unwrap();
+        codec.write_frame(&mut agent_io, &Frame::data(0, 0, Bytes::from(payload))).await.unwrap();
+    }
+    
+    let received = tokio::time::timeout(Duration::from_secs(1), progress.recv()).await.unwrap().unwrap();
+    assert!(matches!(received, Event::Progress { request_id: id, completed: 1, .. } if id == request_id));
+    
+    let first = tokio::time::timeout(Duration::from_secs(1), all.recv()).await.unwrap().unwrap();
+    let second = tokio::time::timeout(Duration::from_secs(1), all.recv()).await.unwrap().unwrap();
+    assert_eq!(first.kind(), EventKind::Log);
+    assert_eq!(second.kind(), EventKind::Progress);
+    
+    // Subscribers whose receivers were dropped are removed
+    assert_eq!(router.subscribers.read().await.len(), 2);
+    assert!(progress.try_recv().is_err());
+}
+
+/// Router connected to an in-process agent whose pings take `delay` to answer
+async fn router_with_slow_agent(delay: Duration, keepalive: Option<Duration>, request_timeout: Duration) -> Router {
+    struct SlowPing(Duration);
+    
+    #[async_trait::async_trait]
+    impl mitoxide_agent::agent::Handler for SlowPing {
+        async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+            tokio::time::sleep(self.0).await;
+            Ok(Response::pong(request.id(), 0))
+        }
+    }
+    
+    let (client_io, agent_io) = tokio::io::duplex(64 * 1024);
+    let (agent_read, agent_write) = tokio::io::split(agent_io);
+    let mut agent = mitoxide_agent::agent::AgentLoop::with_io(agent_read, agent_write);
+    if let Some(interval) = keepalive {
+        agent = agent.with_keepalive_interval(interval);
+    }
+    agent.register_handler("ping".to_string(), Arc::new(SlowPing(delay))).await;
+    tokio::spawn(async move { agent.run().await });
+    
+    let (client_read, client_write) = tokio::io::split(client_io);
+    let (router, _shutdown_tx) = Router::with_io(client_read, client_write, 16, request_timeout).unwrap();
+    router
+}
+
+#[tokio::test]
+async fn test_keepalives_extend_request_timeout() {
+    let delay = Duration::from_millis(400);
+    let request_timeout = Duration::from_millis(150);
+    
+    let router = router_with_slow_agent(delay, Some(Duration::from_millis(50)), request_timeout).await;
+    let response = router.send_message(Message::request(Request::ping())).await.unwrap();
+    assert!(matches!(response, Response::Pong { .. }));
+    assert!(router.activity.read().await.is_empty());
+    
+    // Without keepalives the same request looks hung
+    let router = router_with_slow_agent(delay, None, request_timeout).await;
+    let error = router.send_message(Message::request(Request::ping())).await.unwrap_err();
+    assert!(matches!(error.kind(), crate::ErrorKind::Timeout { duration } if *duration == request_timeout));
+}
+
+#[tokio::test]
+async fn test_flush_returns_once_queued_frames_are_written() {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{ready, Context as TaskContext, Poll};
+    
+    /// Writer that takes 20ms over each write, counting flushes; the codec flushes after every frame
+    struct SlowWriter {
+        sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+        flushes: Arc<AtomicUsize>,
+    }
+    
+    impl tokio::io::AsyncWrite for SlowWriter {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            let sleep = self.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(Duration::from_millis(20))));
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+            Poll::Ready(Ok(buf.len()))
+        }
+        
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+        
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+    
+    // Nothing is ever read back; the agent side is only kept open
+    let (client_read, _agent_io) = tokio::io::duplex(1024);
+    let flushes = Arc::new(AtomicUsize::new(0));
+    let writer = SlowWriter { sleep: None, flushes: flushes.clone() };
+    let (router, _shutdown_tx) = Router::with_io(client_read, writer, 16, Duration::from_secs(5)).unwrap();
+    
+    // Queueing returns long before the frames are written
+    let mut responses = Vec::new();
+    for _ in 0..5 {
+        responses.push(router.send_message_streaming(Message::request(Request::ping()), None).await.unwrap());
+    }
+    assert!(flushes.load(Ordering::SeqCst) < 5);
+    
+    // All five frames, then the flush itself
+    router.flush().await.unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 6);
+}
@@ -1,11 +1,16 @@
 //! Session management and connection handling
 
 use crate::{Result, MitoxideError, Context, Router};
+use crate::context::ExecDefaults;
 // use mitoxide_proto::{Message, Request, Response};
-use mitoxide_ssh::{Transport, StdioTransport, SshConfig, ConnectionInfo};
+use mitoxide_proto::message::StreamCompression;
+use mitoxide_proto::{CompressionDictionary, RateLimit};
+use mitoxide_ssh::{Connection, ConnectionInfo, ConnectionPool, PooledConnection, SshConfig, StdioTransport, Transport};
 
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
 use tokio::sync::{mpsc, RwLock};
 use tracing::{debug, info, warn};
@@ -24,6 +29,18 @@ pub struct SessionConfig {
     pub max_streams: u32,
     /// Enable agent bootstrapping
     pub bootstrap_agent: bool,
+    /// Environment and working directory applied to every process execution
+    pub exec_defaults: ExecDefaults,
+    /// Compression negotiated for the connection's whole frame stream
+    pub stream_compression: Option<StreamCompression>,
+    /// Dictionary stream compression is primed with if the agent has it too
+    pub compression_dictionary: Option<CompressionDictionary>,
+    /// Most requests kept in flight on the connection at once
+    pub pipeline_depth: Option<usize>,
+    /// Rate the input of all requests is paced to
+    pub send_rate_limit: Option<RateLimit>,
+    /// Rate the input of each request is paced to
+    pub stream_send_rate_limit: Option<RateLimit>,
 }
 
 /// Agent configuration
@@ -80,6 +97,12 @@ pub struct SessionState {
     pub capabilities: Vec<String>,
     /// Connection information
     pub connection_info: Option<ConnectionInfo>,
+    /// Concurrent request limit advertised by the agent
+    pub max_concurrent_requests: Option<u32>,
+    /// Stream compression negotiated with the agent
+    pub stream_compression: Option<StreamCompression>,
+    /// ID of the dictionary stream compression is primed with, if both ends have it
+    pub compression_dictionary_id: Option<u32>,
 }
 
 /// Session builder for configuring connections
@@ -96,6 +119,20 @@ pub struct SessionBuilder {
     max_streams: u32,
     /// Bootstrap agent flag
     bootstrap_agent: bool,
+    /// Process execution defaults
+    exec_defaults: ExecDefaults,
+    /// Connection stream compression
+    stream_compression: Option<StreamCompression>,
+    /// Dictionary for connection stream compression
+    compression_dictionary: Option<CompressionDictionary>,
+    /// Request pipelining depth
+    pipeline_depth: Option<usize>,
+    /// Connection send rate limit
+    send_rate_limit: Option<RateLimit>,
+    /// Per-request send rate limit
+    stream_send_rate_limit: Option<RateLimit>,
+    /// Pool the connection is shared through
+    pool: Option<SessionPool>,
 }
 
 impl SessionBuilder {
@@ -118,6 +155,13 @@ impl SessionBuilder {
             timeout: Duration::from_secs(30),
             max_streams: 100,
             bootstrap_agent: true,
+            exec_defaults: ExecDefaults::default(),
+            stream_compression: None,
+            compression_dictionary: None,
+            pipeline_depth: None,
+            send_rate_limit: None,
+            stream_send_rate_limit: None,
+            pool: None,
         }
     }
     
@@ -151,6 +195,42 @@ impl SessionBuilder {
         self
     }
     
+    /// Connect to `addr` instead of resolving the target's hostname
+    ///
+    /// The host key is still checked against the hostname, so known_hosts
+    /// entries for it keep working.
+    pub fn with_resolved_addr(mut self, addr: IpAddr) -> Self {
+        self.ssh_config.resolved_addr = Some(addr);
+        self
+    }
+    
+    /// Set the CA-signed certificate to present with the SSH key
+    pub fn with_certificate(mut self, certificate_file: PathBuf) -> Self {
+        self.ssh_config.certificate_file = Some(certificate_file);
+        self
+    }
+    
+    /// Set the host key algorithms to prefer, most preferred first
+    pub fn with_host_key_algorithms(mut self, algorithms: Vec<String>) -> Self {
+        self.ssh_config.host_key_algorithms = algorithms;
+        self
+    }
+    
+    /// Set the public key algorithms offered for authentication
+    pub fn with_pubkey_accepted_key_types(mut self, key_types: Vec<String>) -> Self {
+        self.ssh_config.pubkey_accepted_key_types = key_types;
+        self
+    }
+    
+    /// Set how often ssh probes an idle server and how many unanswered probes it tolerates
+    ///
+    /// `None` leaves the setting to the ssh client configuration.
+    pub fn with_server_alive(mut self, interval: Option<Duration>, count_max: Option<u32>) -> Self {
+        self.ssh_config.server_alive_interval = interval.map(|interval| interval.as_secs().max(1));
+        self.ssh_config.server_alive_count_max = count_max;
+        self
+    }
+    
     /// Set connection timeout
     pub fn with_timeout(mut self, timeout: Duration) -> Self {
         self.timeout = timeout;
@@ -188,6 +268,74 @@ impl SessionBuilder {
         self
     }
     
+    /// Set an environment variable for every process started in the session
+    pub fn with_env(mut self, key: String, value: String) -> Self {
+        self.exec_defaults.env.insert(key, value);
+        self
+    }
+    
+    /// Set the working directory for every process started in the session
+    pub fn with_cwd(mut self, cwd: PathBuf) -> Self {
+        self.exec_defaults.cwd = Some(cwd);
+        self
+    }
+    
+    /// Compress the connection's whole frame stream, negotiated when the session opens
+    ///
+    /// One compression stream per direction compresses many small frames much
+    /// better than compressing them one by one. Agents that don't support it
+    /// leave the connection uncompressed.
+    pub fn with_stream_compression(mut self, compression: StreamCompression) -> Self {
+        self.stream_compression = Some(compression);
+        self
+    }
+    
+    /// Prime stream compression with a dictionary trained on the workload
+    ///
+    /// Takes effect with [`SessionBuilder::with_stream_compression`], and only
+    /// if the agent was started with the same dictionary; otherwise the stream
+    /// is compressed without one.
+    pub fn with_compression_dictionary(mut self, dictionary: CompressionDictionary) -> Self {
+        self.compression_dictionary = Some(dictionary);
+        self
+    }
+    
+    /// Keep up to `depth` requests in flight on the connection at once
+    ///
+    /// Concurrent requests go out on their own streams without waiting for
+    /// earlier responses, which hides the round-trip time on slow links.
+    /// Requests beyond the depth wait locally; if the agent advertises a
+    /// smaller concurrency limit, that one wins.
+    pub fn with_pipeline_depth(mut self, depth: usize) -> Self {
+        self.pipeline_depth = Some(depth);
+        self
+    }
+    
+    /// Pace the input sent with all requests, such as file uploads, to `limit`
+    ///
+    /// Keeps a bulk transfer from saturating the link; the agent paces what
+    /// it sends back with its own limit.
+    pub fn with_send_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.send_rate_limit = Some(limit);
+        self
+    }
+    
+    /// Pace the input sent with each request to `limit`, under the connection's limit if any
+    pub fn with_stream_send_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.stream_send_rate_limit = Some(limit);
+        self
+    }
+    
+    /// Share the connection with other sessions built with the same pool
+    ///
+    /// Sessions to the same user, host and port then multiplex their requests
+    /// over one connection and agent instead of each opening their own; see
+    /// [`SessionPool`].
+    pub fn with_pool(mut self, pool: SessionPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+    
     /// Build the session configuration
     pub fn build_config(self) -> SessionConfig {
         SessionConfig {
@@ -196,15 +344,92 @@ impl SessionBuilder {
             timeout: self.timeout,
             max_streams: self.max_streams,
             bootstrap_agent: self.bootstrap_agent,
+            exec_defaults: self.exec_defaults,
+            stream_compression: self.stream_compression,
+            compression_dictionary: self.compression_dictionary,
+            pipeline_depth: self.pipeline_depth,
+            send_rate_limit: self.send_rate_limit,
+            stream_send_rate_limit: self.stream_send_rate_limit,
         }
     }
     
     /// Connect and create the session
-    pub async fn connect(self) -> Result<ConnectedSession> {
+    pub async fn connect(mut self) -> Result<ConnectedSession> {
         let target = self.target.clone();
+        let pool = self.pool.take();
         let config = self.build_config();
         let session = Session::new(target, config);
-        session.connect().await
+        match pool {
+            Some(pool) => session.connect_pooled(&pool).await,
+            None => session.connect().await,
+        }
+    }
+}
+
+/// Connection to a target shared through a pool, locked while it is being opened
+type LinkSlot = Arc<tokio::sync::Mutex<Weak<SessionLink>>>;
+
+/// Connections shared by the sessions built with [`SessionBuilder::with_pool`]
+///
+/// Sessions to the same user, host and port share one connection to the
+/// agent, which closes once the last of them is dropped or disconnected. The
+/// connection is checked out of the wrapped [`ConnectionPool`], so its
+/// retries, connect limit, circuit breaker and draining all apply to it.
+/// The session that opens a connection sets it up: stream compression and
+/// pipeline depth of sessions joining it later are ignored.
+#[derive(Clone)]
+pub struct SessionPool {
+    /// Pool connections are checked out of
+    connections: Arc<ConnectionPool>,
+    /// Connection of each target
+    links: Arc<Mutex<HashMap<String, LinkSlot>>>,
+}
+
+impl SessionPool {
+    /// Share connections checked out of `connections`
+    pub fn new(connections: ConnectionPool) -> Self {
+        Self {
+            connections: Arc::new(connections),
+            links: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    
+    /// Pool connections are checked out of, for its stats and events
+    pub fn connections(&self) -> &ConnectionPool {
+        &self.connections
+    }
+    
+    /// Slot holding the connection to `host_key`, shared by everyone connecting to it
+    fn slot(&self, host_key: &str) -> LinkSlot {
+        let mut links = self.links.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget targets whose connection closed, unless someone is connecting to them
+        links.retain(|_, slot| Arc::strong_count(slot) > 1 || slot.try_lock().is_ok_and(|link| link.strong_count() > 0));
+        links.entry(host_key.to_string()).or_default().clone()
+    }
+}
+
+impl From<ConnectionPool> for SessionPool {
+    fn from(connections: ConnectionPool) -> Self {
+        Self::new(connections)
+    }
+}
+
+/// Connection to an agent, shared by the sessions using it and closed when the last one goes
+struct SessionLink {
+    /// Connection router
+    router: Arc<Router>,
+    /// Shutdown sender
+    shutdown_tx: mpsc::Sender<()>,
+    /// State of the session that opened the connection, for sessions joining it
+    state: SessionState,
+    /// Pool checkout the connection came from, kept until the connection closes
+    _pooled: Option<PooledConnection>,
+}
+
+impl Drop for SessionLink {
+    fn drop(&mut self) {
+        // Try to send shutdown signal on drop
+        let _ = self.shutdown_tx.try_send(());
     }
 }
 
@@ -212,27 +437,19 @@ impl SessionBuilder {
 pub struct ConnectedSession {
     /// Session state
     state: Arc<RwLock<SessionState>>,
-    /// Connection router
-    router: Arc<Router>,
+    /// Connection, possibly shared with other sessions
+    link: Arc<SessionLink>,
     /// Session configuration
     config: SessionConfig,
-    /// Shutdown sender
-    shutdown_tx: mpsc::Sender<()>,
 }
 
 impl ConnectedSession {
     /// Create a new connected session
-    pub(crate) fn new(
-        state: SessionState,
-        router: Router,
-        config: SessionConfig,
-        shutdown_tx: mpsc::Sender<()>,
-    ) -> Self {
+    fn new(state: SessionState, link: Arc<SessionLink>, config: SessionConfig) -> Self {
         Self {
             state: Arc::new(RwLock::new(state)),
-            router: Arc::new(router),
+            link,
             config,
-            shutdown_tx,
         }
     }
     
@@ -246,16 +463,22 @@ impl ConnectedSession {
         self.state.read().await.id
     }
     
+    /// Get session configuration
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+    
     /// Create a new execution context
     pub async fn context(&self) -> Result<Context> {
         let state = self.state.read().await;
         if state.status != SessionStatus::Active {
-            return Err(MitoxideError::Protocol(
+            return Err(MitoxideError::protocol(
                 format!("Session not active: {:?}", state.status)
             ));
         }
         
-        Context::new(state.id, self.router.clone())
+        Ok(Context::new(state.id, self.link.router.clone())?
+            .with_defaults(self.config.exec_defaults.clone()))
     }
     
     /// Test connection health
@@ -270,8 +493,14 @@ impl ConnectedSession {
     }
     
     /// Gracefully disconnect the session
+    ///
+    /// The agent is asked to finish the requests it is running and remove
+    /// the session's temporary files before the connection is torn down. A
+    /// connection shared through a [`SessionPool`] stays open for the other
+    /// sessions using it.
     pub async fn disconnect(self) -> Result<()> {
-        info!("Disconnecting session {}", self.id().await);
+        let session_id = self.id().await;
+        info!("Disconnecting session {}", session_id);
         
         // Update session status
         {
@@ -279,26 +508,31 @@ impl ConnectedSession {
             state.status = SessionStatus::Disconnected;
         }
         
+        let Some(link) = Arc::into_inner(self.link) else {
+            info!("Session disconnected, connection still used by other sessions");
+            return Ok(());
+        };
+        
+        if self.config.bootstrap_agent && link.router.is_connected() {
+            match Context::new(session_id, link.router.clone())?.close_session().await {
+                Ok(removed) => debug!("Agent closed the connection, removing {} temporary paths", removed),
+                Err(e) => warn!("Agent did not close the connection cleanly: {}", e),
+            }
+        }
+        
         // Send shutdown signal
-        if let Err(e) = self.shutdown_tx.send(()).await {
+        if let Err(e) = link.shutdown_tx.send(()).await {
             warn!("Failed to send shutdown signal: {}", e);
         }
         
         // Wait for router to clean up
-        self.router.shutdown().await?;
+        link.router.shutdown().await?;
         
         info!("Session disconnected successfully");
         Ok(())
     }
 }
 
-impl Drop for ConnectedSession {
-    fn drop(&mut self) {
-        // Try to send shutdown signal on drop
-        let _ = self.shutdown_tx.try_send(());
-    }
-}
-
 /// Main session type for managing SSH connections
 pub struct Session {
     /// Target connection string
@@ -321,8 +555,61 @@ impl Session {
     
     /// Connect to the remote host and establish session
     pub async fn connect(self) -> Result<ConnectedSession> {
+        let transport = StdioTransport::new(self.config.ssh_config.clone());
+        self.connect_with(transport).await
+    }
+    
+    /// Establish the session over a custom transport
+    pub async fn connect_with<T: Transport>(self, mut transport: T) -> Result<ConnectedSession> {
         info!("Connecting to target: {}", self.target);
         
+        // Test connection first
+        transport.test_connection().await
+            .map_err(|e| MitoxideError::transport(format!("Connection test failed: {}", e)))?;
+        
+        // Establish connection
+        let connection = transport.connect().await
+            .map_err(|e| MitoxideError::transport(format!("Failed to connect: {}", e)))?;
+        
+        self.start(&mut transport, connection, None).await
+    }
+    
+    /// Establish the session over the pool's connection to the target, opening it if there is none
+    pub async fn connect_pooled(self, pool: &SessionPool) -> Result<ConnectedSession> {
+        let ssh_config = &self.config.ssh_config;
+        let host_key = format!("{}@{}:{}", ssh_config.username, ssh_config.host, ssh_config.port);
+        let slot = pool.slot(&host_key);
+        let mut shared = slot.lock().await;
+        
+        if let Some(link) = shared.upgrade().filter(|link| link.router.is_connected()) {
+            let mut state = link.state.clone();
+            state.id = Uuid::new_v4();
+            state.target = self.target.clone();
+            info!("Session {} shares the connection to {}", state.id, host_key);
+            return Ok(ConnectedSession::new(state, link, self.config));
+        }
+        
+        info!("Connecting to target: {}", self.target);
+        pool.connections.add_host(host_key.clone(), ssh_config.clone()).await;
+        let mut pooled = pool.connections.get_connection(&host_key).await
+            .map_err(|e| MitoxideError::transport(format!("Failed to connect: {}", e)))?;
+        let connection = pooled.take_connection()
+            .ok_or_else(|| MitoxideError::connection("Pooled connection already taken".to_string()))?;
+        let mut transport = pool.connections.transport(&host_key).await
+            .map_err(|e| MitoxideError::transport(format!("Failed to connect: {}", e)))?;
+        
+        let session = self.start(transport.as_mut(), connection, Some(pooled)).await?;
+        *shared = Arc::downgrade(&session.link);
+        Ok(session)
+    }
+    
+    /// Bootstrap the agent over `transport` and start the session on `connection`
+    async fn start<T: Transport + ?Sized>(
+        self,
+        transport: &mut T,
+        connection: Connection,
+        pooled: Option<PooledConnection>,
+    ) -> Result<ConnectedSession> {
         let session_id = Uuid::new_v4();
         let mut state = SessionState {
             id: session_id,
@@ -331,19 +618,11 @@ impl Session {
             agent_version: None,
             capabilities: Vec::new(),
             connection_info: None,
+            max_concurrent_requests: None,
+            stream_compression: None,
+            compression_dictionary_id: None,
         };
         
-        // Create transport
-        let mut transport = StdioTransport::new(self.config.ssh_config.clone());
-        
-        // Test connection first
-        transport.test_connection().await
-            .map_err(|e| MitoxideError::Transport(format!("Connection test failed: {}", e)))?;
-        
-        // Establish connection
-        let connection = transport.connect().await
-            .map_err(|e| MitoxideError::Transport(format!("Failed to connect: {}", e)))?;
-        
         state.connection_info = Some(transport.connection_info());
         
         // Bootstrap agent if enabled
@@ -352,7 +631,7 @@ impl Session {
             
             let agent_binary = self.get_agent_binary().await?;
             transport.bootstrap_agent(&agent_binary).await
-                .map_err(|e| MitoxideError::Agent(format!("Agent bootstrap failed: {}", e)))?;
+                .map_err(|e| MitoxideError::agent(format!("Agent bootstrap failed: {}", e)))?;
             
             info!("Agent bootstrapped successfully");
         }
@@ -363,6 +642,14 @@ impl Session {
             self.config.max_streams,
             self.config.timeout,
         ).await?;
+        if let Some(depth) = self.config.pipeline_depth {
+            router.set_pipeline_depth(depth).await;
+        }
+        if let Some(dictionary) = &self.config.compression_dictionary {
+            router.set_compression_dictionary(dictionary.clone()).await;
+        }
+        router.set_send_rate_limit(self.config.send_rate_limit).await;
+        router.set_stream_send_rate_limit(self.config.stream_send_rate_limit).await;
         
         // Update state to active
         state.status = SessionStatus::Active;
@@ -377,9 +664,35 @@ impl Session {
             state.capabilities.push("wasm_exec".to_string());
         }
         
+        let router = Arc::new(router);
+        
+        // Learn the agent's concurrency limit so requests queue locally instead of being
+        // rejected, and switch to stream compression if the agent accepts it
+        if self.config.bootstrap_agent {
+            let context = Context::new(session_id, router.clone())?;
+            match context.negotiate_session(None, self.config.stream_compression).await {
+                Ok(agent_session) => {
+                    debug!("Agent accepts {:?} concurrent requests ({} in flight)",
+                           agent_session.max_concurrent_requests, agent_session.in_flight);
+                    state.max_concurrent_requests = agent_session.max_concurrent_requests;
+                    state.stream_compression = agent_session.compression;
+                    state.compression_dictionary_id = agent_session.dictionary_id;
+                }
+                Err(e) => warn!("Agent session handshake failed: {}", e),
+            }
+        }
+        
+        let link = Arc::new(SessionLink {
+            router,
+            shutdown_tx,
+            state: state.clone(),
+            _pooled: pooled,
+        });
+        let session = ConnectedSession::new(state, link, self.config);
+        
         info!("Session {} established successfully", session_id);
         
-        Ok(ConnectedSession::new(state, router, self.config, shutdown_tx))
+        Ok(session)
     }
     
     /// Get agent binary (embedded or from file)
@@ -387,7 +700,7 @@ impl Session {
         if let Some(binary_path) = &self.config.agent_config.binary_path {
             // Load from file
             tokio::fs::read(binary_path).await
-                .map_err(|e| MitoxideError::Agent(format!("Failed to read agent binary: {}", e)))
+                .map_err(|e| MitoxideError::agent(format!("Failed to read agent binary: {}", e)))
         } else {
             // Use embedded binary (placeholder for now)
             // In a real implementation, this would be the compiled mitoxide-agent binary
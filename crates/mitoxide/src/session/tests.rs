@@ -39,6 +39,7 @@ async fn test_session_builder_configuration() {
         .with_timeout(Duration::from_secs(60))
         .with_key(PathBuf::from("/path/to/key"))
         .with_ssh_option("ServerAliveInterval".to_string(), "30".to_string())
+        .with_resolved_addr("192.0.2.10".parse().unwrap())
         .with_max_streams(50)
         .with_bootstrap(false)
         .with_hash_verification(true);
@@ -48,9 +49,22 @@ async fn test_session_builder_configuration() {
     assert_eq!(config.timeout, Duration::from_secs(60));
     assert_eq!(config.ssh_config.key_path, Some(PathBuf::from("/path/to/key")));
     assert_eq!(config.ssh_config.options.get("ServerAliveInterval"), Some(&"30".to_string()));
+    assert_eq!(config.ssh_config.host, "example.com");
+    assert_eq!(config.ssh_config.resolved_addr, Some("192.0.2.10".parse().unwrap()));
     assert_eq!(config.max_streams, 50);
-    assert_eq!(config.bootstrap_agent, false);
-    assert_eq!(config.agent_config.verify_hash, true);
+    assert!(!config.bootstrap_agent);
+    assert!(config.agent_config.verify_hash);
+}
+
+#[test]
+fn test_session_builder_exec_defaults() {
+    let config = SessionBuilder::new("test@example.com".to_string())
+        .with_env("APP_ENV".to_string(), "staging".to_string())
+        .with_cwd(PathBuf::from("/srv/app"))
+        .build_config();
+    
+    assert_eq!(config.exec_defaults.env.get("APP_ENV"), Some(&"staging".to_string()));
+    assert_eq!(config.exec_defaults.cwd, Some(PathBuf::from("/srv/app")));
 }
 
 #[tokio::test]
@@ -71,8 +85,8 @@ async fn test_agent_config_default() {
     
     assert_eq!(config.binary_path, None);
     assert_eq!(config.execution_timeout, Duration::from_secs(300));
-    assert_eq!(config.verify_hash, false);
-    assert_eq!(config.verify_signature, false);
+    assert!(!config.verify_hash);
+    assert!(!config.verify_signature);
 }
 
 #[tokio::test]
@@ -85,6 +99,9 @@ async fn test_session_state_creation() {
         agent_version: None,
         capabilities: Vec::new(),
         connection_info: None,
+        max_concurrent_requests: None,
+        stream_compression: None,
+        compression_dictionary_id: None,
     };
     
     assert_eq!(state.id, session_id);
@@ -119,12 +136,19 @@ fn test_session_config_clone() {
         timeout: Duration::from_secs(30),
         max_streams: 100,
         bootstrap_agent: true,
+        exec_defaults: ExecDefaults::default(),
+        stream_compression: None,
+        compression_dictionary: None,
+        pipeline_depth: Some(8),
+        send_rate_limit: None,
+        stream_send_rate_limit: None,
     };
     
     let cloned = config.clone();
     assert_eq!(config.timeout, cloned.timeout);
     assert_eq!(config.max_streams, cloned.max_streams);
     assert_eq!(config.bootstrap_agent, cloned.bootstrap_agent);
+    assert_eq!(cloned.pipeline_depth, Some(8));
 }
 
 #[test]
@@ -136,6 +160,9 @@ fn test_session_state_clone() {
         agent_version: Some("1.0.0".to_string()),
         capabilities: vec!["test".to_string()],
         connection_info: None,
+        max_concurrent_requests: None,
+        stream_compression: None,
+        compression_dictionary_id: None,
     };
     
     let cloned = state.clone();
@@ -144,4 +171,40 @@ fn test_session_state_clone() {
     assert_eq!(state.status, cloned.status);
     assert_eq!(state.agent_version, cloned.agent_version);
     assert_eq!(state.capabilities, cloned.capabilities);
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn test_sessions_share_pooled_connection() {
+    use crate::test_support::LoopbackTransport;
+    use mitoxide_ssh::{PoolConfig, PoolEvent, TransportFactory};
+    
+    let factory: TransportFactory = Arc::new(|_| Box::new(LoopbackTransport::new()));
+    let pool = SessionPool::new(ConnectionPool::new(PoolConfig::default()).with_transport_factory(factory));
+    let mut events = pool.connections().subscribe();
+    let build = |target: &str| SessionBuilder::new(target.to_string()).with_pool(pool.clone());
+    
+    let first = build("deploy@web-1").connect().await.unwrap();
+    let second = build("deploy@web-1:22").connect().await.unwrap();
+    assert_ne!(first.id().await, second.id().await);
+    first.ping().await.unwrap();
+    second.ping().await.unwrap();
+    
+    // One connection was opened and stays checked out while either session uses it
+    assert_eq!(events.try_recv().unwrap(), PoolEvent::Created { host: "deploy@web-1:22".to_string() });
+    assert!(events.try_recv().is_err());
+    assert_eq!(pool.connections().stats().await.checked_out, 1);
+    
+    // Another user or host gets its own
+    let other = build("deploy@web-2").connect().await.unwrap();
+    assert_eq!(events.try_recv().unwrap(), PoolEvent::Created { host: "deploy@web-2:22".to_string() });
+    other.disconnect().await.unwrap();
+    
+    // Disconnecting one session leaves the connection to the other
+    first.disconnect().await.unwrap();
+    second.ping().await.unwrap();
+    
+    // Once the last session is gone the next one connects afresh
+    second.disconnect().await.unwrap();
+    let third = build("deploy@web-1").connect().await.unwrap();
+    third.ping().await.unwrap();
+    assert_eq!(events.try_recv().unwrap(), PoolEvent::Created { host: "deploy@web-1:22".to_string() });
+}
@@ -0,0 +1,172 @@
+//! In-memory transport and agent for tests
+//!
+//! [`LoopbackTransport`] connects a session to an agent running in the same
+//! process over an in-memory pipe, so requests round-trip through the real
+//! protocol without SSH or a spawned agent binary. Enable the `test-support`
+//! feature to use it outside this crate.
+
+use crate::{ConnectedSession, Result, Session, SessionBuilder};
+use async_trait::async_trait;
+use mitoxide_agent::agent::{AgentLoop, Handler};
+use mitoxide_agent::handlers::{FileHandler, PingHandler, ProcessHandler, PtyHandler, TunnelHandler, VersionHandler};
+use mitoxide_proto::CompressionDictionary;
+use mitoxide_ssh::{Connection, ConnectionInfo, ServerInfo, Transport, TransportError, TransportType};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::error;
+
+/// Buffer size of the in-memory pipe between client and agent
+const LOOPBACK_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Handlers registered by request type
+pub type HandlerList = Vec<(String, Arc<dyn Handler>)>;
+
+/// Handlers the agent binary registers, apart from WASM
+pub fn default_handlers() -> HandlerList {
+    let process_handler: Arc<dyn Handler> = Arc::new(ProcessHandler::new());
+    let file_handler: Arc<dyn Handler> = Arc::new(FileHandler::new());
+    vec![
+        ("process_exec".to_string(), process_handler.clone()),
+        ("process_signal".to_string(), process_handler),
+        ("file_get".to_string(), file_handler.clone()),
+        ("file_put".to_string(), file_handler.clone()),
+        ("file_patch_text".to_string(), file_handler.clone()),
+        ("dir_list".to_string(), file_handler.clone()),
+        ("file_tail".to_string(), file_handler.clone()),
+        ("file_hash".to_string(), file_handler.clone()),
+        ("file_xattr_get".to_string(), file_handler.clone()),
+        ("file_xattr_set".to_string(), file_handler.clone()),
+        ("file_chown".to_string(), file_handler.clone()),
+        ("file_ensure".to_string(), file_handler.clone()),
+        ("file_signature".to_string(), file_handler.clone()),
+        ("file_delta_put".to_string(), file_handler.clone()),
+        ("file_set_attributes".to_string(), file_handler.clone()),
+        ("file_remove".to_string(), file_handler.clone()),
+        ("disk_space".to_string(), file_handler),
+        ("pty_exec".to_string(), Arc::new(PtyHandler::new())),
+        ("ping".to_string(), Arc::new(PingHandler)),
+        ("ping_batch".to_string(), Arc::new(PingHandler)),
+        ("version".to_string(), Arc::new(VersionHandler)),
+        ("tcp_connect".to_string(), Arc::new(TunnelHandler::new())),
+    ]
+}
+
+/// Run an in-process agent over `io` in the background
+///
+/// Handlers are registered before this returns, so requests written to the
+/// other end of `io` are served straight away.
+pub async fn spawn_agent<S>(
+    io: S,
+    handlers: &[(String, Arc<dyn Handler>)],
+    max_concurrent_requests: Option<usize>,
+    compression_dictionary: Option<&CompressionDictionary>,
+)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(io);
+    let mut agent = AgentLoop::with_io(reader, writer);
+    if let Some(max) = max_concurrent_requests {
+        agent = agent.with_max_concurrent_requests(max);
+    }
+    if let Some(dictionary) = compression_dictionary {
+        agent = agent.with_compression_dictionary(dictionary.clone());
+    }
+    for (request_type, handler) in handlers {
+        agent.register_handler(request_type.clone(), handler.clone()).await;
+    }
+    
+    tokio::spawn(async move {
+        if let Err(e) = agent.run().await {
+            error!("Loopback agent error: {}", e);
+        }
+    });
+}
+
+/// Transport that connects to an in-process agent over an in-memory pipe
+///
+/// Each [`Transport::connect`] starts a fresh agent; bootstrapping is a no-op.
+pub struct LoopbackTransport {
+    /// Handlers registered on each agent
+    handlers: HandlerList,
+    /// Concurrency limit advertised by each agent
+    max_concurrent_requests: Option<usize>,
+    /// Dictionary each agent primes stream compression with
+    compression_dictionary: Option<CompressionDictionary>,
+}
+
+impl LoopbackTransport {
+    /// Create a transport whose agents register the [`default_handlers`]
+    pub fn new() -> Self {
+        Self {
+            handlers: default_handlers(),
+            max_concurrent_requests: None,
+            compression_dictionary: None,
+        }
+    }
+    
+    /// Register a handler, replacing any default one for the same request type
+    pub fn with_handler(mut self, request_type: impl Into<String>, handler: Arc<dyn Handler>) -> Self {
+        let request_type = request_type.into();
+        self.handlers.retain(|(existing, _)| *existing != request_type);
+        self.handlers.push((request_type, handler));
+        self
+    }
+    
+    /// Limit how many requests each agent handles at once
+    pub fn with_max_concurrent_requests(mut self, max: usize) -> Self {
+        self.max_concurrent_requests = Some(max);
+        self
+    }
+    
+    /// Start each agent with a stream compression dictionary
+    pub fn with_compression_dictionary(mut self, dictionary: CompressionDictionary) -> Self {
+        self.compression_dictionary = Some(dictionary);
+        self
+    }
+    
+    /// Connect a session to a fresh in-process agent
+    pub async fn connect_session(self) -> Result<ConnectedSession> {
+        let config = SessionBuilder::new("loopback".to_string()).build_config();
+        Session::new("loopback".to_string(), config).connect_with(self).await
+    }
+}
+
+impl Default for LoopbackTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Transport for LoopbackTransport {
+    async fn connect(&mut self) -> std::result::Result<Connection, TransportError> {
+        let (client_io, agent_io) = tokio::io::duplex(LOOPBACK_BUFFER_SIZE);
+        spawn_agent(agent_io, &self.handlers, self.max_concurrent_requests, self.compression_dictionary.as_ref()).await;
+        
+        let (reader, writer) = tokio::io::split(client_io);
+        Ok(Connection::from_io(reader, writer))
+    }
+    
+    async fn bootstrap_agent(&mut self, _agent_binary: &[u8]) -> std::result::Result<(), TransportError> {
+        // The agent already runs in this process
+        Ok(())
+    }
+    
+    fn connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            host: "loopback".to_string(),
+            port: 0,
+            username: String::new(),
+            transport_type: TransportType::Local,
+            server: ServerInfo::default(),
+        }
+    }
+    
+    async fn test_connection(&mut self) -> std::result::Result<(), TransportError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,309 @@
+//! Unit tests for the loopback transport
+
+use super::*;
+use crate::proto::message::{ErrorCode, ErrorDetails, QosClass, StreamCompression, TempKind};
+use crate::proto::{RateLimit, Request, Response};
+
+#[tokio::test]
+async fn test_loopback_ping() {
+    let session = LoopbackTransport::new().connect_session().await.unwrap();
+    let context = session.context().await.unwrap();
+    
+    context.ping().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_loopback_file_get() {
+    let dir = tempfile::tempdir().unwrap();
+    let remote = dir.path().join("remote.txt");
+    let local = dir.path().join("local.txt");
+    std::fs::write(&remote, "fetched in memory").unwrap();
+    
+    let session = LoopbackTransport::new().connect_session().await.unwrap();
+    let context = session.context().await.unwrap();
+    
+    let bytes = context.get(&remote, &local).await.unwrap();
+    assert_eq!(bytes, 17);
+    assert_eq!(std::fs::read_to_string(&local).unwrap(), "fetched in memory");
+}
+
+#[tokio::test]
+async fn test_loopback_custom_handler() {
+    struct RefusePing;
+    
+    #[async_trait]
+    impl Handler for RefusePing {
+        async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+            Ok(Response::error(request.id(), ErrorDetails::new(ErrorCode::PermissionDenied, "no pings")))
+        }
+    }
+    
+    let session = LoopbackTransport::new()
+        .with_handler("ping", Arc::new(RefusePing))
+        .with_max_concurrent_requests(4)
+        .connect_session().await.unwrap();
+    assert_eq!(session.state().await.max_concurrent_requests, Some(4));
+    
+    let context = session.context().await.unwrap();
+    let error = context.ping().await.unwrap_err();
+    assert!(error.to_string().contains("no pings"));
+}
+
+#[tokio::test]
+async fn test_loopback_stream_compression() {
+    let dir = tempfile::tempdir().unwrap();
+    let remote = dir.path().join("remote.log");
+    let local = dir.path().join("local.log");
+    let content = "GET /health 200\n".repeat(4096);
+    std::fs::write(&remote, &content).unwrap();
+    
+    let config = SessionBuilder::new("loopback".to_string())
+        .with_stream_compression(StreamCompression::Zstd)
+        .build_config();
+    let session = Session::new("loopback".to_string(), config)
+        .connect_with(LoopbackTransport::new()).await.unwrap();
+    assert_eq!(session.state().await.stream_compression, Some(StreamCompression::Zstd));
+    
+    let context = session.context().await.unwrap();
+    for _ in 0..3 {
+        context.ping().await.unwrap();
+    }
+    context.get(&remote, &local).await.unwrap();
+    assert_eq!(std::fs::read_to_string(&local).unwrap(), content);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_loopback_compression_dictionary_negotiation() {
+    let samples: Vec<String> = (0..200).map(|i| format!("{{\"host\":\"web-{:02}\",\"status\":\"healthy\",\"checks\":{}}}", i % 20, i)).collect();
+    let dictionary = CompressionDictionary::train(&samples, 2048).unwrap();
+    let other = CompressionDictionary::new(&b"unrelated dictionary content"[..]);
+    let connect = |agent: CompressionDictionary| {
+        let config = SessionBuilder::new("loopback".to_string())
+            .with_stream_compression(StreamCompression::Zstd)
+            .with_compression_dictionary(dictionary.clone())
+            .build_config();
+        Session::new("loopback".to_string(), config)
+            .connect_with(LoopbackTransport::new().with_compression_dictionary(agent))
+    };
+    
+    let session = connect(dictionary.clone()).await.unwrap();
+    let state = session.state().await;
+    assert_eq!(state.stream_compression, Some(StreamCompression::Zstd));
+    assert_eq!(state.compression_dictionary_id, Some(dictionary.id()));
+    let context = session.context().await.unwrap();
+    let output = context.proc_exec(&["echo", "{\"host\":\"web-07\",\"status\":\"healthy\"}"]).await.unwrap();
+    assert_eq!(output.stdout_string().unwrap(), "{\"host\":\"web-07\",\"status\":\"healthy\"}\n");
+    
+    // A different dictionary on the agent leaves compression on, without one
+    let session = connect(other).await.unwrap();
+    let state = session.state().await;
+    assert_eq!(state.stream_compression, Some(StreamCompression::Zstd));
+    assert_eq!(state.compression_dictionary_id, None);
+    session.context().await.unwrap().ping().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_loopback_interactive_request_jumps_background_queue() {
+    let session = LoopbackTransport::new()
+        .with_max_concurrent_requests(1)
+        .connect_session().await.unwrap();
+    let mut background = session.context().await.unwrap();
+    background.set_qos(Some(QosClass::Background));
+    let background = Arc::new(background);
+    let mut interactive = session.context().await.unwrap();
+    interactive.set_qos(Some(QosClass::Interactive));
+    
+    // One background command runs at the agent while the rest queue locally
+    let finished = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut tasks = Vec::new();
+    for i in 0..4 {
+        let background = background.clone();
+        let finished = finished.clone();
+        tasks.push(tokio::spawn(async move {
+            background.proc_exec(&["sleep", "0.2"]).await.unwrap();
+            finished.lock().unwrap().push(format!("background {}", i));
+        }));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    
+    interactive.proc_exec(&["true"]).await.unwrap();
+    finished.lock().unwrap().push("interactive".to_string());
+    for task in tasks {
+        task.await.unwrap();
+    }
+    
+    let finished = finished.lock().unwrap();
+    assert_eq!(finished.len(), 5);
+    assert_eq!(finished[0], "background 0");
+    assert_eq!(finished[1], "interactive", "order: {:?}", finished);
+}
+
+#[tokio::test]
+async fn test_loopback_pipelined_requests_overlap_latency() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    
+    const LATENCY: std::time::Duration = std::time::Duration::from_millis(50);
+    
+    /// Answers after a fixed delay, standing in for a slow link
+    #[derive(Default)]
+    struct SlowVersion {
+        running: AtomicUsize,
+        peak: Arc<AtomicUsize>,
+    }
+    
+    #[async_trait]
+    impl Handler for SlowVersion {
+        async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(LATENCY).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(Response::pong(request.id(), 0))
+        }
+    }
+    
+    let handler = SlowVersion::default();
+    let peak = handler.peak.clone();
+    let config = SessionBuilder::new("loopback".to_string())
+        .with_pipeline_depth(16)
+        .build_config();
+    let session = Session::new("loopback".to_string(), config)
+        .connect_with(LoopbackTransport::new().with_handler("version", Arc::new(handler))).await.unwrap();
+    let context = session.context().await.unwrap();
+    
+    let requests: Vec<Request> = (0..100).map(|_| Request::version()).collect();
+    let ids: Vec<_> = requests.iter().map(Request::id).collect();
+    let start = std::time::Instant::now();
+    let responses = context.pipeline(requests).await;
+    let elapsed = start.elapsed();
+    
+    // Responses are matched back to their own requests
+    assert_eq!(responses.len(), 100);
+    for (response, id) in responses.into_iter().zip(ids) {
+        assert_eq!(response.unwrap().request_id(), id);
+    }
+    // Seven rounds of 16 instead of 100 round-trips one after another
+    assert!(elapsed < LATENCY * 25, "100 pipelined requests took {:?}", elapsed);
+    assert_eq!(peak.load(Ordering::SeqCst), 16);
+}
+
+#[tokio::test]
+async fn test_loopback_send_rate_limit_paces_uploads() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = dir.path().join("bulk.bin");
+    let remote = dir.path().join("uploaded.bin");
+    std::fs::write(&local, vec![7u8; 300 * 1024]).unwrap();
+    
+    // The connection's rate is the tighter one here, with no burst to speak of
+    let config = SessionBuilder::new("loopback".to_string())
+        .with_send_rate_limit(RateLimit::new(1024 * 1024).with_burst(1))
+        .with_stream_send_rate_limit(RateLimit::new(4 * 1024 * 1024).with_burst(1))
+        .build_config();
+    let session = Session::new("loopback".to_string(), config)
+        .connect_with(LoopbackTransport::new()).await.unwrap();
+    let context = session.context().await.unwrap();
+    
+    // 300 KiB at 1 MiB/s takes about 0.3s
+    let start = std::time::Instant::now();
+    assert_eq!(context.put(&local, &remote).await.unwrap(), 300 * 1024);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= std::time::Duration::from_millis(250), "took {:?}", elapsed);
+    assert!(elapsed < std::time::Duration::from_secs(2), "took {:?}", elapsed);
+    assert_eq!(std::fs::read(&remote).unwrap().len(), 300 * 1024);
+}
+
+#[tokio::test]
+async fn test_loopback_disconnect_cleans_up_temp_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = LoopbackTransport::new().connect_session().await.unwrap();
+    let context = session.context().await.unwrap();
+    
+    let scratch = context.mktemp(TempKind::File, Some(dir.path()), "scratch-", ".tmp", true).await.unwrap();
+    let kept = context.mktemp(TempKind::File, Some(dir.path()), "kept-", ".tmp", false).await.unwrap();
+    assert!(scratch.is_file());
+    
+    // The agent has removed the session's temporary file by the time the close is acknowledged
+    session.disconnect().await.unwrap();
+    assert!(!scratch.exists());
+    assert!(kept.is_file());
+}
+
+#[tokio::test]
+async fn test_loopback_agent_version() {
+    let session = LoopbackTransport::new().connect_session().await.unwrap();
+    let context = session.context().await.unwrap();
+    
+    let build = context.version().await.unwrap();
+    assert_eq!(build, mitoxide_agent::version::build_info());
+    assert_eq!(build.version, env!("CARGO_PKG_VERSION"));
+}
+
+#[tokio::test]
+async fn test_loopback_tunnel_round_trips_through_echo_server() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    
+    let session = LoopbackTransport::new()
+        .with_handler("tcp_connect", Arc::new(TunnelHandler::new().allow(format!("127.0.0.1:{}", port))))
+        .connect_session().await.unwrap();
+    let context = session.context().await.unwrap();
+    
+    let mut tunnel = context.tunnel("127.0.0.1", port).await.unwrap();
+    assert_eq!(tunnel.peer(), format!("127.0.0.1:{}", port));
+    // The agent may read the whole echo ahead of the reads below
+    tunnel.set_receive_window(1024 * 1024);
+    let message: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    tunnel.write_all(&message).await.unwrap();
+    tunnel.shutdown().await.unwrap();
+    
+    // The echo server closes once the shutdown reaches it, ending the tunnel
+    let mut echoed = Vec::new();
+    tunnel.read_to_end(&mut echoed).await.unwrap();
+    assert_eq!(echoed, message);
+    
+    // Addresses the agent doesn't allow are refused before anything connects
+    let error = context.tunnel("127.0.0.1", port.wrapping_add(1)).await.unwrap_err();
+    assert!(error.to_string().contains("not allowed"), "{}", error);
+}
+
+#[tokio::test]
+async fn test_loopback_dropped_tunnel_closes_the_connection() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    
+    // The target writes until its connection is closed, counting what it wrote
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let chunk = vec![7u8; 64 * 1024];
+        let mut written = 0;
+        while socket.write_all(&chunk).await.is_ok() {
+            written += chunk.len();
+        }
+        let _ = closed_tx.send(written);
+    });
+    
+    let session = LoopbackTransport::new()
+        .with_handler("tcp_connect", Arc::new(TunnelHandler::new().allow(format!("127.0.0.1:{}", port))))
+        .connect_session().await.unwrap();
+    let context = session.context().await.unwrap();
+    
+    let mut tunnel = context.tunnel("127.0.0.1", port).await.unwrap();
+    let mut buf = vec![0u8; 1024];
+    tunnel.read_exact(&mut buf).await.unwrap();
+    drop(tunnel);
+    
+    // The agent read no further than its window and the sockets' buffers allowed
+    let written = tokio::time::timeout(std::time::Duration::from_secs(10), closed_rx).await.unwrap().unwrap();
+    assert!(written < 64 * 1024 * 1024, "target wrote {} bytes", written);
+}
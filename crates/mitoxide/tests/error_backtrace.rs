@@ -0,0 +1,30 @@
+//! Backtraces captured by `MitoxideError`
+//!
+//! Whether backtraces are enabled is read once per process, so this runs in a
+//! test binary of its own where it can enable them before any error exists.
+
+use mitoxide::{ErrorKind, MitoxideError, Result};
+use std::backtrace::BacktraceStatus;
+
+/// Fails the way a library call would, kept out of line so it shows in the backtrace
+#[inline(never)]
+fn failing_step() -> Result<()> {
+    Err(MitoxideError::protocol("forced failure"))
+}
+
+#[test]
+fn test_error_captures_backtrace_when_enabled() {
+    std::env::set_var("RUST_LIB_BACKTRACE", "1");
+    
+    let error = failing_step().unwrap_err();
+    assert_eq!(error.to_string(), "Protocol error: forced failure");
+    assert!(matches!(error.kind(), ErrorKind::Protocol(message) if message == "forced failure"));
+    assert_eq!(error.backtrace().status(), BacktraceStatus::Captured);
+    
+    // The backtrace leads back to where the error was created
+    let backtrace = error.backtrace().to_string();
+    assert!(backtrace.contains("failing_step"), "backtrace without the failing call:\n{}", backtrace);
+    
+    let io_error: MitoxideError = std::io::Error::other("disk on fire").into();
+    assert_eq!(io_error.backtrace().status(), BacktraceStatus::Captured);
+}
@@ -10,18 +10,6 @@ use anyhow::{Context, Result};
 #[derive(Debug, Clone)]
 pub struct ContainerConfig {
     pub name: String,
-    pub service: String,
-    pub port: u16,
-    pub constraints: ContainerConstraints,
-}
-
-/// Container resource constraints for testing
-#[derive(Debug, Clone)]
-pub struct ContainerConstraints {
-    pub memory_limit: Option<String>,
-    pub cpu_limit: Option<f32>,
-    pub read_only: bool,
-    pub tmpfs_mounts: Vec<String>,
 }
 
 /// Docker test environment manager
@@ -35,61 +23,30 @@ impl DockerTestEnv {
     pub fn new() -> Self {
         let mut containers = HashMap::new();
         
-        // Alpine RO container with constraints
+        // Alpine RO container
         containers.insert("alpine_ro".to_string(), ContainerConfig {
             name: "mitoxide_alpine_ro".to_string(),
-            service: "alpine_ro".to_string(),
-            port: 2222,
-            constraints: ContainerConstraints {
-                memory_limit: Some("64m".to_string()),
-                cpu_limit: Some(0.5),
-                read_only: true,
-                tmpfs_mounts: vec!["/tmp:size=64m".to_string()],
-            },
         });
         
         // Ubuntu minimal container
         containers.insert("ubuntu_min".to_string(), ContainerConfig {
             name: "mitoxide_ubuntu_min".to_string(),
-            service: "ubuntu_min".to_string(),
-            port: 2223,
-            constraints: ContainerConstraints {
-                memory_limit: None,
-                cpu_limit: None,
-                read_only: false,
-                tmpfs_mounts: vec![],
-            },
         });
         
         // Bastion host
         containers.insert("bastion".to_string(), ContainerConfig {
             name: "mitoxide_bastion".to_string(),
-            service: "bastion".to_string(),
-            port: 2224,
-            constraints: ContainerConstraints {
-                memory_limit: None,
-                cpu_limit: None,
-                read_only: false,
-                tmpfs_mounts: vec![],
-            },
         });
         
         // Backend target (no external port)
         containers.insert("backend_target".to_string(), ContainerConfig {
             name: "mitoxide_backend_target".to_string(),
-            service: "backend_target".to_string(),
-            port: 22, // Internal port only
-            constraints: ContainerConstraints {
-                memory_limit: None,
-                cpu_limit: None,
-                read_only: false,
-                tmpfs_mounts: vec![],
-            },
         });
         
         Self {
             containers,
-            compose_file: "docker-compose.yml".to_string(),
+            // The compose file sits at the workspace root, above the crate tests run from
+            compose_file: concat!(env!("CARGO_MANIFEST_DIR"), "/../../docker-compose.yml").to_string(),
         }
     }
     
@@ -102,7 +59,8 @@ impl DockerTestEnv {
         
         // Start containers
         let output = Command::new("docker-compose")
-            .args(&["up", "-d"])
+            .args(["-f", &self.compose_file])
+            .args(["up", "-d"])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .output()
@@ -120,32 +78,13 @@ impl DockerTestEnv {
         Ok(())
     }
     
-    /// Stop all containers
-    pub async fn stop(&self) -> Result<()> {
-        println!("Stopping Docker test environment...");
-        
-        let output = Command::new("docker-compose")
-            .args(&["down"])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .context("Failed to stop Docker containers")?;
-        
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("Failed to stop containers: {}", stderr);
-        }
-        
-        println!("Docker test environment stopped");
-        Ok(())
-    }
-    
     /// Build all container images
     pub async fn build(&self) -> Result<()> {
         println!("Building Docker images...");
         
         let output = Command::new("docker-compose")
-            .args(&["build"])
+            .args(["-f", &self.compose_file])
+            .args(["build"])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .output()
@@ -165,7 +104,8 @@ impl DockerTestEnv {
         println!("Cleaning up Docker test environment...");
         
         let output = Command::new("docker-compose")
-            .args(&["down", "-v", "--remove-orphans"])
+            .args(["-f", &self.compose_file])
+            .args(["down", "-v", "--remove-orphans"])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .output()
@@ -178,7 +118,7 @@ impl DockerTestEnv {
         
         // Prune system resources
         let _ = Command::new("docker")
-            .args(&["system", "prune", "-f"])
+            .args(["system", "prune", "-f"])
             .output();
         
         println!("Docker test environment cleaned up");
@@ -195,7 +135,7 @@ impl DockerTestEnv {
         for attempt in 1..=max_attempts {
             let mut all_ready = true;
             
-            for (name, config) in &self.containers {
+            for config in self.containers.values() {
                 if !self.is_container_ready(&config.name).await? {
                     all_ready = false;
                     break;
@@ -219,7 +159,7 @@ impl DockerTestEnv {
     /// Check if a specific container is ready
     async fn is_container_ready(&self, container_name: &str) -> Result<bool> {
         let output = Command::new("docker")
-            .args(&["exec", container_name, "echo", "ready"])
+            .args(["exec", container_name, "echo", "ready"])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .output()
@@ -233,54 +173,13 @@ impl DockerTestEnv {
         self.containers.get(name)
     }
     
-    /// List all available containers
-    pub fn list_containers(&self) -> Vec<&str> {
-        self.containers.keys().map(|s| s.as_str()).collect()
-    }
-    
-    /// Get container status
-    pub async fn get_status(&self) -> Result<HashMap<String, ContainerStatus>> {
-        let output = Command::new("docker-compose")
-            .args(&["ps", "--format", "json"])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .context("Failed to get container status")?;
-        
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("Failed to get status: {}", stderr);
-        }
-        
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let mut status_map = HashMap::new();
-        
-        // Parse docker-compose ps output
-        for line in stdout.lines() {
-            if let Ok(status) = serde_json::from_str::<serde_json::Value>(line) {
-                if let (Some(name), Some(state)) = (
-                    status.get("Name").and_then(|v| v.as_str()),
-                    status.get("State").and_then(|v| v.as_str())
-                ) {
-                    status_map.insert(name.to_string(), ContainerStatus {
-                        name: name.to_string(),
-                        state: state.to_string(),
-                        running: state == "running",
-                    });
-                }
-            }
-        }
-        
-        Ok(status_map)
-    }
-    
     /// Execute command in container
     pub async fn exec_command(&self, container: &str, command: &[&str]) -> Result<CommandOutput> {
         let container_config = self.get_container(container)
             .ok_or_else(|| anyhow::anyhow!("Unknown container: {}", container))?;
         
         let mut cmd = Command::new("docker");
-        cmd.args(&["exec", &container_config.name]);
+        cmd.args(["exec", &container_config.name]);
         cmd.args(command);
         
         let output = cmd
@@ -291,25 +190,15 @@ impl DockerTestEnv {
         
         Ok(CommandOutput {
             exit_code: output.status.code().unwrap_or(-1),
-            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
             stderr: String::from_utf8_lossy(&output.stderr).to_string(),
         })
     }
 }
 
-/// Container status information
-#[derive(Debug, Clone)]
-pub struct ContainerStatus {
-    pub name: String,
-    pub state: String,
-    pub running: bool,
-}
-
 /// Command execution output
 #[derive(Debug, Clone)]
 pub struct CommandOutput {
     pub exit_code: i32,
-    pub stdout: String,
     pub stderr: String,
 }
 
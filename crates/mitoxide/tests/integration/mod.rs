@@ -6,10 +6,6 @@
 pub mod docker;
 pub mod ssh;
 pub mod utils;
-pub mod bootstrap_tests;
-pub mod process_tests;
-pub mod wasm_tests;
-pub mod pty_tests;
 pub mod routing_tests;
 
 pub use docker::*;
@@ -1,763 +0,0 @@
-//! Comprehensive integration tests for privilege escalation and PTY operations
-//! 
-//! These tests verify sudo prompt detection, PTY operations, privilege escalation
-//! failure scenarios, and credential handling security.
-
-use super::*;
-use anyhow::{Context, Result};
-use std::time::Duration;
-use tokio::time::timeout;
-
-/// Test privilege escalation and PTY operations
-pub struct PtyTests {
-    docker_env: DockerTestEnv,
-    ssh_helper: SshHelper,
-}
-
-impl PtyTests {
-    /// Create new PTY test suite
-    pub fn new() -> Self {
-        Self {
-            docker_env: DockerTestEnv::new(),
-            ssh_helper: SshHelper::new(),
-        }
-    }
-    
-    /// Setup test environment
-    pub async fn setup(&self) -> Result<()> {
-        EnvUtils::setup_test_environment()?;
-        self.docker_env.start().await?;
-        Ok(())
-    }
-    
-    /// Cleanup test environment
-    pub async fn cleanup(&self) -> Result<()> {
-        self.docker_env.stop().await?;
-        Ok(())
-    }
-    
-    /// Test sudo prompt detection and handling
-    pub async fn test_sudo_prompt_detection(&self) -> Result<()> {
-        println!("Testing sudo prompt detection and handling...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Test sudo availability and configuration
-        println!("  Testing sudo availability...");
-        let sudo_check_cmd = r#"
-echo "Checking sudo configuration..."
-
-# Check if sudo is installed
-if command -v sudo >/dev/null 2>&1; then
-    echo "sudo command available"
-else
-    echo "sudo command not available"
-    exit 1
-fi
-
-# Check sudo configuration
-sudo -n true 2>/dev/null && echo "Passwordless sudo configured" || echo "Password required for sudo"
-
-# Test sudo version
-sudo --version | head -1
-
-# Check sudoers configuration (if readable)
-if [ -r /etc/sudoers ]; then
-    echo "Sudoers file readable"
-    grep -E "^(testuser|%sudo|ALL)" /etc/sudoers 2>/dev/null || echo "No specific user rules found"
-else
-    echo "Sudoers file not readable (normal)"
-fi
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", sudo_check_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Sudo availability check")?;
-        TestAssertions::assert_output_contains(&output.stdout, "sudo command available", "Sudo availability")?;
-        
-        // Test sudo prompt patterns
-        println!("  Testing sudo prompt patterns...");
-        let prompt_test_cmd = r#"
-echo "Testing sudo prompt patterns..."
-
-# Create a script that simulates sudo prompts
-cat > /tmp/sudo_prompt_test.sh << 'EOF'
-#!/bin/sh
-# Simulate different sudo prompt patterns
-
-echo "Testing prompt pattern detection..."
-
-# Standard sudo prompt
-echo "[sudo] password for testuser:"
-
-# Alternative prompt formats
-echo "Password:"
-echo "testuser's password:"
-echo "[sudo] password for testuser@hostname:"
-echo "Sorry, try again."
-echo "[sudo] password for testuser: "
-
-# Non-sudo prompts (should not match)
-echo "Enter password:"
-echo "Database password:"
-echo "SSH password:"
-
-echo "Prompt pattern test completed"
-EOF
-
-chmod +x /tmp/sudo_prompt_test.sh
-/tmp/sudo_prompt_test.sh
-
-# Test prompt detection logic
-cat > /tmp/prompt_detector.sh << 'EOF'
-#!/bin/sh
-# Mock sudo prompt detector
-
-while IFS= read -r line; do
-    case "$line" in
-        *"[sudo] password for"*)
-            echo "DETECTED: Standard sudo prompt - $line"
-            ;;
-        *"'s password:"*)
-            echo "DETECTED: User password prompt - $line"
-            ;;
-        "Password:")
-            echo "DETECTED: Generic password prompt - $line"
-            ;;
-        "Sorry, try again.")
-            echo "DETECTED: Retry prompt - $line"
-            ;;
-        *)
-            echo "IGNORED: Non-sudo prompt - $line"
-            ;;
-    esac
-done
-EOF
-
-chmod +x /tmp/prompt_detector.sh
-/tmp/sudo_prompt_test.sh | /tmp/prompt_detector.sh
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", prompt_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Prompt pattern test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "DETECTED: Standard sudo prompt", "Standard prompt")?;
-        TestAssertions::assert_output_contains(&output.stdout, "DETECTED: User password prompt", "User prompt")?;
-        TestAssertions::assert_output_contains(&output.stdout, "IGNORED: Non-sudo prompt", "Non-sudo filtering")?;
-        
-        println!("✅ Sudo prompt detection test passed");
-        Ok(())
-    }
-    
-    /// Test PTY operations with interactive commands
-    pub async fn test_pty_interactive_operations(&self) -> Result<()> {
-        println!("Testing PTY operations with interactive commands...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Test PTY allocation and basic operations
-        println!("  Testing PTY allocation...");
-        let pty_basic_cmd = r#"
-echo "Testing PTY operations..."
-
-# Check if we're in a PTY
-if [ -t 0 ] && [ -t 1 ]; then
-    echo "Running in PTY mode"
-    tty
-else
-    echo "Not running in PTY mode"
-fi
-
-# Test terminal capabilities
-echo "Terminal type: ${TERM:-unknown}"
-echo "Terminal size: $(stty size 2>/dev/null || echo 'unknown')"
-
-# Test interactive command simulation
-cat > /tmp/interactive_test.sh << 'EOF'
-#!/bin/sh
-# Simulate interactive command
-
-echo "Interactive command started"
-echo -n "Enter your name: "
-read name
-echo "Hello, $name!"
-
-echo -n "Enter a number: "
-read number
-echo "You entered: $number"
-
-echo "Interactive test completed"
-EOF
-
-chmod +x /tmp/interactive_test.sh
-
-# Run interactive test with predefined input
-echo -e "TestUser\n42\n" | /tmp/interactive_test.sh
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", pty_basic_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "PTY basic operations")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Interactive command started", "Interactive start")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Hello, TestUser!", "Interactive input")?;
-        
-        // Test PTY with sudo simulation
-        println!("  Testing PTY with sudo simulation...");
-        let pty_sudo_cmd = r#"
-echo "Testing PTY with sudo simulation..."
-
-# Create a mock sudo interaction
-cat > /tmp/sudo_pty_test.sh << 'EOF'
-#!/bin/sh
-# Mock sudo PTY interaction
-
-echo "Simulating sudo command execution..."
-
-# Simulate sudo prompt
-echo -n "[sudo] password for testuser: "
-
-# Read password (in real scenario, this would be hidden)
-read -s password
-echo  # New line after password
-
-# Simulate password validation
-if [ "$password" = "testpass" ]; then
-    echo "Authentication successful"
-    echo "Running privileged command..."
-    echo "root ALL=(ALL:ALL) ALL" # Simulate privileged output
-    echo "Command completed successfully"
-else
-    echo "Sorry, try again."
-    exit 1
-fi
-EOF
-
-chmod +x /tmp/sudo_pty_test.sh
-
-# Test with correct password
-echo "testpass" | /tmp/sudo_pty_test.sh
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", pty_sudo_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "PTY sudo simulation")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Authentication successful", "Sudo auth")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Command completed successfully", "Sudo completion")?;
-        
-        // Test PTY signal handling
-        println!("  Testing PTY signal handling...");
-        let signal_test_cmd = r#"
-echo "Testing PTY signal handling..."
-
-# Create a signal-aware script
-cat > /tmp/signal_test.sh << 'EOF'
-#!/bin/sh
-# Signal handling test
-
-cleanup() {
-    echo "Received signal, cleaning up..."
-    exit 0
-}
-
-trap cleanup TERM INT
-
-echo "Signal test started (PID: $$)"
-echo "Waiting for signal..."
-
-# Simulate work
-for i in $(seq 1 5); do
-    echo "Working... $i"
-    sleep 1
-done
-
-echo "Signal test completed normally"
-EOF
-
-chmod +x /tmp/signal_test.sh
-
-# Run in background and send signal
-(/tmp/signal_test.sh &
-SCRIPT_PID=$!
-sleep 2
-kill -TERM $SCRIPT_PID 2>/dev/null || true
-wait $SCRIPT_PID 2>/dev/null || true
-echo "Signal test finished")
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", signal_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "PTY signal handling")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Signal test started", "Signal test start")?;
-        
-        println!("✅ PTY interactive operations test passed");
-        Ok(())
-    }
-    
-    /// Test privilege escalation failure scenarios
-    pub async fn test_privilege_escalation_failures(&self) -> Result<()> {
-        println!("Testing privilege escalation failure scenarios...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Test incorrect password handling
-        println!("  Testing incorrect password handling...");
-        let wrong_password_cmd = r#"
-echo "Testing incorrect password handling..."
-
-# Create sudo simulation with wrong password
-cat > /tmp/wrong_password_test.sh << 'EOF'
-#!/bin/sh
-# Test wrong password handling
-
-attempt=1
-max_attempts=3
-
-while [ $attempt -le $max_attempts ]; do
-    echo -n "[sudo] password for testuser: "
-    read -s password
-    echo
-    
-    if [ "$password" = "correctpass" ]; then
-        echo "Authentication successful"
-        exit 0
-    else
-        echo "Sorry, try again."
-        attempt=$((attempt + 1))
-        
-        if [ $attempt -gt $max_attempts ]; then
-            echo "sudo: $max_attempts incorrect password attempts"
-            exit 1
-        fi
-    fi
-done
-EOF
-
-chmod +x /tmp/wrong_password_test.sh
-
-# Test with wrong passwords
-(echo -e "wrongpass1\nwrongpass2\nwrongpass3\n" | /tmp/wrong_password_test.sh) || echo "Wrong password handling: PASSED"
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", wrong_password_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Wrong password test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Sorry, try again.", "Password retry")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Wrong password handling: PASSED", "Failure handling")?;
-        
-        // Test sudo timeout
-        println!("  Testing sudo timeout scenarios...");
-        let timeout_test_cmd = r#"
-echo "Testing sudo timeout scenarios..."
-
-# Create timeout simulation
-cat > /tmp/sudo_timeout_test.sh << 'EOF'
-#!/bin/sh
-# Simulate sudo timeout
-
-echo -n "[sudo] password for testuser: "
-
-# Simulate user not responding (timeout after 5 seconds)
-timeout 5s read -s password || {
-    echo
-    echo "sudo: timed out reading password"
-    exit 1
-}
-
-echo
-echo "Password received: $password"
-EOF
-
-chmod +x /tmp/sudo_timeout_test.sh
-
-# Test timeout (no input provided)
-echo "" | timeout 3s /tmp/sudo_timeout_test.sh || echo "Sudo timeout handling: PASSED"
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", timeout_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Sudo timeout test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Sudo timeout handling: PASSED", "Timeout handling")?;
-        
-        // Test permission denied scenarios
-        println!("  Testing permission denied scenarios...");
-        let permission_test_cmd = r#"
-echo "Testing permission denied scenarios..."
-
-# Create permission test
-cat > /tmp/permission_test.sh << 'EOF'
-#!/bin/sh
-# Test permission denied scenarios
-
-user="$1"
-command="$2"
-
-echo "Checking permissions for user: $user, command: $command"
-
-# Simulate sudoers check
-case "$user" in
-    "testuser")
-        case "$command" in
-            "ls"|"cat"|"echo")
-                echo "Permission granted for $command"
-                ;;
-            "rm"|"chmod"|"chown")
-                echo "$user is not in the sudoers file. This incident will be reported."
-                exit 1
-                ;;
-            *)
-                echo "Command not allowed: $command"
-                exit 1
-                ;;
-        esac
-        ;;
-    *)
-        echo "User not in sudoers: $user"
-        exit 1
-        ;;
-esac
-EOF
-
-chmod +x /tmp/permission_test.sh
-
-# Test allowed command
-/tmp/permission_test.sh testuser ls
-
-# Test denied command
-/tmp/permission_test.sh testuser rm || echo "Permission denial: PASSED"
-
-# Test unknown user
-/tmp/permission_test.sh unknownuser ls || echo "Unknown user denial: PASSED"
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", permission_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Permission test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Permission granted", "Allowed command")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Permission denial: PASSED", "Denied command")?;
-        
-        println!("✅ Privilege escalation failure scenarios test passed");
-        Ok(())
-    }
-    
-    /// Test credential handling and security
-    pub async fn test_credential_handling_security(&self) -> Result<()> {
-        println!("Testing credential handling and security...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Test secure password handling
-        println!("  Testing secure password handling...");
-        let secure_password_cmd = r#"
-echo "Testing secure password handling..."
-
-# Create secure password handler
-cat > /tmp/secure_password_test.sh << 'EOF'
-#!/bin/sh
-# Test secure password handling
-
-echo "Secure password handling test"
-
-# Test 1: Password not echoed to terminal
-echo -n "Enter password (should not echo): "
-stty -echo 2>/dev/null || true
-read password
-stty echo 2>/dev/null || true
-echo
-
-# Test 2: Password not stored in process list
-echo "Password length: ${#password}"
-
-# Test 3: Password cleared from memory (simulated)
-password="CLEARED"
-echo "Password cleared from variable"
-
-# Test 4: No password in command history
-history -c 2>/dev/null || true
-echo "Command history cleared"
-
-# Test 5: No password in environment
-env | grep -i pass || echo "No password in environment"
-
-echo "Secure password handling completed"
-EOF
-
-chmod +x /tmp/secure_password_test.sh
-
-# Run with test password
-echo "testpassword" | /tmp/secure_password_test.sh
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", secure_password_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Secure password test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Password length: 12", "Password handling")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Password cleared", "Password clearing")?;
-        TestAssertions::assert_output_contains(&output.stdout, "No password in environment", "Environment security")?;
-        
-        // Test credential caching security
-        println!("  Testing credential caching security...");
-        let caching_test_cmd = r#"
-echo "Testing credential caching security..."
-
-# Create credential cache test
-cat > /tmp/cache_test.sh << 'EOF'
-#!/bin/sh
-# Test credential caching
-
-echo "Credential caching test"
-
-# Simulate sudo timestamp check
-SUDO_TIMESTAMP="/tmp/sudo_timestamp_$$"
-CACHE_TIMEOUT=300  # 5 minutes
-
-# Check if cached credentials are valid
-if [ -f "$SUDO_TIMESTAMP" ]; then
-    timestamp=$(cat "$SUDO_TIMESTAMP")
-    current_time=$(date +%s)
-    age=$((current_time - timestamp))
-    
-    if [ $age -lt $CACHE_TIMEOUT ]; then
-        echo "Using cached credentials (age: ${age}s)"
-        echo "Cached authentication valid"
-    else
-        echo "Cached credentials expired (age: ${age}s)"
-        rm -f "$SUDO_TIMESTAMP"
-        echo "Cache cleared due to timeout"
-    fi
-else
-    echo "No cached credentials found"
-    echo "New authentication required"
-    
-    # Simulate successful authentication
-    date +%s > "$SUDO_TIMESTAMP"
-    echo "Credentials cached"
-fi
-
-# Cleanup
-rm -f "$SUDO_TIMESTAMP"
-echo "Credential cache test completed"
-EOF
-
-chmod +x /tmp/cache_test.sh
-/tmp/cache_test.sh
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", caching_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Credential caching test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Credential caching test", "Cache test start")?;
-        TestAssertions::assert_output_contains(&output.stdout, "test completed", "Cache test completion")?;
-        
-        // Test privilege boundary enforcement
-        println!("  Testing privilege boundary enforcement...");
-        let boundary_test_cmd = r#"
-echo "Testing privilege boundary enforcement..."
-
-# Create privilege boundary test
-cat > /tmp/boundary_test.sh << 'EOF'
-#!/bin/sh
-# Test privilege boundaries
-
-echo "Privilege boundary test"
-
-# Test 1: File access restrictions
-echo "Testing file access restrictions..."
-
-# Should fail: accessing sensitive files
-if [ -r /etc/shadow ]; then
-    echo "ERROR: Can read /etc/shadow without privileges"
-    exit 1
-else
-    echo "PASS: Cannot read /etc/shadow"
-fi
-
-# Should fail: writing to system directories
-if touch /etc/test_file 2>/dev/null; then
-    echo "ERROR: Can write to /etc without privileges"
-    rm -f /etc/test_file
-    exit 1
-else
-    echo "PASS: Cannot write to /etc"
-fi
-
-# Test 2: Process restrictions
-echo "Testing process restrictions..."
-
-# Should fail: changing other user's processes
-if kill -0 1 2>/dev/null; then
-    echo "PASS: Can signal init process (expected for root or same user)"
-else
-    echo "PASS: Cannot signal init process"
-fi
-
-# Test 3: Network restrictions (if applicable)
-echo "Testing network restrictions..."
-
-# Should work: basic network operations
-if ping -c 1 127.0.0.1 >/dev/null 2>&1; then
-    echo "PASS: Basic network access works"
-else
-    echo "INFO: Network access restricted"
-fi
-
-echo "Privilege boundary test completed"
-EOF
-
-chmod +x /tmp/boundary_test.sh
-/tmp/boundary_test.sh
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", boundary_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Privilege boundary test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "PASS: Cannot read /etc/shadow", "Shadow file protection")?;
-        TestAssertions::assert_output_contains(&output.stdout, "PASS: Cannot write to /etc", "System directory protection")?;
-        
-        // Cleanup
-        let cleanup_cmd = "rm -f /tmp/*_test.sh /tmp/sudo_timestamp_*";
-        let _ = self.ssh_helper.execute_command(&config, &["sh", "-c", cleanup_cmd]).await;
-        
-        println!("✅ Credential handling and security test passed");
-        Ok(())
-    }
-    
-    /// Test comprehensive PTY and privilege escalation workflow
-    pub async fn test_comprehensive_pty_workflow(&self) -> Result<()> {
-        println!("Testing comprehensive PTY and privilege escalation workflow...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Complete workflow test
-        let workflow_test_cmd = r#"
-echo "Testing comprehensive PTY workflow..."
-
-# Create comprehensive workflow test
-cat > /tmp/pty_workflow_test.sh << 'EOF'
-#!/bin/sh
-# Comprehensive PTY and privilege escalation workflow
-
-echo "=== PTY Workflow Test ==="
-
-# Step 1: Initialize PTY environment
-echo "Step 1: Initializing PTY environment"
-if [ -t 0 ]; then
-    echo "PTY detected: $(tty)"
-else
-    echo "No PTY detected, simulating..."
-fi
-
-# Step 2: User authentication simulation
-echo "Step 2: User authentication"
-echo -n "Username: "
-read username
-echo -n "Password: "
-read -s password
-echo
-
-if [ "$username" = "testuser" ] && [ "$password" = "testpass" ]; then
-    echo "Authentication successful"
-else
-    echo "Authentication failed"
-    exit 1
-fi
-
-# Step 3: Privilege escalation request
-echo "Step 3: Privilege escalation"
-echo "Requesting elevated privileges..."
-echo -n "[sudo] password for $username: "
-read -s sudo_password
-echo
-
-if [ "$sudo_password" = "testpass" ]; then
-    echo "Privilege escalation successful"
-    privileged=true
-else
-    echo "Privilege escalation failed"
-    privileged=false
-fi
-
-# Step 4: Execute privileged operations
-echo "Step 4: Executing operations"
-if [ "$privileged" = "true" ]; then
-    echo "Running privileged command: id"
-    echo "uid=0(root) gid=0(root) groups=0(root)"  # Simulated root output
-    
-    echo "Running privileged command: whoami"
-    echo "root"  # Simulated root output
-    
-    echo "Privileged operations completed"
-else
-    echo "Running unprivileged command: id"
-    echo "uid=1000(testuser) gid=1000(testuser) groups=1000(testuser)"
-    
-    echo "Unprivileged operations completed"
-fi
-
-# Step 5: Cleanup and logout
-echo "Step 5: Cleanup"
-echo "Clearing credentials..."
-echo "Logging out..."
-echo "PTY workflow completed successfully"
-EOF
-
-chmod +x /tmp/pty_workflow_test.sh
-
-# Execute workflow with predefined inputs
-echo -e "testuser\ntestpass\ntestpass\n" | /tmp/pty_workflow_test.sh
-        "#;
-        
-        let (output, duration) = PerformanceUtils::measure_async(
-            self.ssh_helper.execute_command(&config, &["sh", "-c", workflow_test_cmd])
-        ).await;
-        
-        let output = output?;
-        TestAssertions::assert_ssh_success(&output, "PTY workflow test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Authentication successful", "User auth")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Privilege escalation successful", "Privilege escalation")?;
-        TestAssertions::assert_output_contains(&output.stdout, "uid=0(root)", "Root privileges")?;
-        TestAssertions::assert_output_contains(&output.stdout, "workflow completed successfully", "Workflow completion")?;
-        
-        println!("    ✅ PTY workflow completed in {}", 
-                PerformanceUtils::format_duration(duration));
-        
-        println!("✅ Comprehensive PTY workflow test passed");
-        Ok(())
-    }
-}
-
-/// Run all PTY and privilege escalation tests
-pub async fn run_pty_tests() -> Result<()> {
-    let tests = PtyTests::new();
-    
-    tests.setup().await?;
-    
-    let mut results = Vec::new();
-    
-    // Run all PTY tests
-    results.push(("sudo_prompt_detection", tests.test_sudo_prompt_detection().await));
-    results.push(("pty_interactive_operations", tests.test_pty_interactive_operations().await));
-    results.push(("privilege_escalation_failures", tests.test_privilege_escalation_failures().await));
-    results.push(("credential_handling_security", tests.test_credential_handling_security().await));
-    results.push(("comprehensive_pty_workflow", tests.test_comprehensive_pty_workflow().await));
-    
-    tests.cleanup().await?;
-    
-    // Report results
-    let mut failed_tests = Vec::new();
-    for (test_name, result) in results {
-        match result {
-            Ok(()) => println!("✅ {} passed", test_name),
-            Err(e) => {
-                println!("❌ {} failed: {}", test_name, e);
-                failed_tests.push(test_name);
-            }
-        }
-    }
-    
-    if !failed_tests.is_empty() {
-        anyhow::bail!("PTY tests failed: {:?}", failed_tests);
-    }
-    
-    println!("🎉 All PTY and privilege escalation tests passed!");
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    
-    #[tokio::test]
-    async fn test_pty_test_creation() {
-        let tests = PtyTests::new();
-        // Should create without errors
-    }
-}
\ No newline at end of file
@@ -3,18 +3,15 @@
 //! These tests verify multi-hop SSH connections, connection routing, multiplexing,
 //! failure recovery, and load balancing across different network topologies.
 
-use super::*;
 use anyhow::{Context, Result};
-use std::collections::HashMap;
-use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::time::{timeout, sleep};
+use tokio::time::sleep;
 // Note: These imports would be used in actual implementation
 // For now, we'll simulate the behavior with mock implementations
 // use mitoxide::{Session, Context as MitoxideContext, MitoxideError};
 // use mitoxide_ssh::{ConnectionPool, PoolConfig, SshConfig as MitoxideSshConfig};
 use crate::integration::{
-    DockerTestEnv, SshHelper, SshConfig, TestAssertions, PerformanceUtils, EnvUtils
+    DockerTestEnv, SshHelper, TestAssertions, PerformanceUtils, EnvUtils
 };
 
 /// Test jump host and routing functionality
@@ -69,7 +66,7 @@ impl RoutingTests {
         println!("🧪 Testing multi-hop SSH connections through bastion...");
         
         // Test direct connection to bastion
-        let bastion_config = self.ssh_helper.config_for_container("bastion", 2224);
+        let bastion_config = self.ssh_helper.config_for_port(2224);
         let bastion_connected = self.ssh_helper.test_connectivity(&bastion_config).await?;
         TestAssertions::assert_ssh_success(
             &crate::integration::SshCommandOutput {
@@ -135,7 +132,7 @@ impl RoutingTests {
         for (container, port) in targets {
             let ssh_helper = self.ssh_helper.clone();
             let handle = tokio::spawn(async move {
-                let config = ssh_helper.config_for_container(container, port);
+                let config = ssh_helper.config_for_port(port);
                 
                 // Execute multiple concurrent commands
                 let mut command_results = Vec::new();
@@ -184,7 +181,7 @@ impl RoutingTests {
     pub async fn test_connection_failure_recovery(&self) -> Result<()> {
         println!("🧪 Testing connection failure and recovery...");
         
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
+        let config = self.ssh_helper.config_for_port(2223);
         
         // Test normal operation
         let output = self.ssh_helper.execute_command(&config, &["echo", "before_failure"]).await?;
@@ -193,8 +190,11 @@ impl RoutingTests {
         
         // Simulate network failure by stopping SSH service
         println!("Simulating network failure...");
-        let stop_output = self.docker_env.exec_command("ubuntu_min", &["pkill", "-f", "sshd"]).await;
+        let stop_output = self.docker_env.exec_command("ubuntu_min", &["pkill", "-f", "sshd"]).await?;
         // Note: This might fail if sshd is not running as expected, which is okay for the test
+        if !stop_output.success() {
+            println!("pkill exited with {}: {}", stop_output.exit_code, stop_output.stderr.trim());
+        }
         
         // Wait a moment for the failure to propagate
         sleep(Duration::from_secs(2)).await;
@@ -208,8 +208,11 @@ impl RoutingTests {
         sleep(Duration::from_secs(3)).await;
         
         // The container should automatically restart SSH, but let's ensure it's running
-        let restart_output = self.docker_env.exec_command("ubuntu_min", &["service", "ssh", "start"]).await;
+        let restart_output = self.docker_env.exec_command("ubuntu_min", &["service", "ssh", "start"]).await?;
         // This might fail if SSH is already running, which is fine
+        if !restart_output.success() {
+            println!("ssh start exited with {}: {}", restart_output.exit_code, restart_output.stderr.trim());
+        }
         
         // Wait for recovery
         sleep(Duration::from_secs(5)).await;
@@ -235,7 +238,7 @@ impl RoutingTests {
         println!("🧪 Testing load balancing and connection pooling...");
         
         // Simulate connection pooling by testing concurrent SSH connections
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
+        let config = self.ssh_helper.config_for_port(2223);
         
         // Test concurrent connection requests
         let num_concurrent = 10;
@@ -312,7 +315,7 @@ impl RoutingTests {
     pub async fn test_routing_performance(&self) -> Result<()> {
         println!("🧪 Testing routing performance under load...");
         
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
+        let config = self.ssh_helper.config_for_port(2223);
         
         // Measure latency for single operations
         let num_operations = 20; // Reduced for faster testing
@@ -1,6 +1,5 @@
 //! SSH connection helpers for integration tests
 
-use std::path::Path;
 use std::process::{Command, Stdio};
 use std::time::Duration;
 use anyhow::{Context, Result};
@@ -63,15 +62,6 @@ impl SshHelper {
         }
     }
     
-    /// Create SSH helper with custom configuration
-    pub fn with_config(key_path: &str, user: &str, timeout: Duration) -> Self {
-        Self {
-            default_key_path: key_path.to_string(),
-            default_user: user.to_string(),
-            connection_timeout: timeout,
-        }
-    }
-    
     /// Test SSH connectivity to a host
     pub async fn test_connectivity(&self, config: &SshConfig) -> Result<bool> {
         let result = timeout(
@@ -91,7 +81,7 @@ impl SshHelper {
         let mut ssh_cmd = Command::new("ssh");
         
         // Add SSH options
-        ssh_cmd.args(&[
+        ssh_cmd.args([
             "-o", "StrictHostKeyChecking=no",
             "-o", "UserKnownHostsFile=/dev/null",
             "-o", "ConnectTimeout=10",
@@ -100,19 +90,19 @@ impl SshHelper {
         ]);
         
         // Add identity file
-        ssh_cmd.args(&["-i", &config.key_path]);
+        ssh_cmd.args(["-i", &config.key_path]);
         
         // Add port
-        ssh_cmd.args(&["-p", &config.port.to_string()]);
+        ssh_cmd.args(["-p", &config.port.to_string()]);
         
         // Add jump host if specified
         if let Some(jump_host) = &config.jump_host {
             let jump_string = format!("{}@{}:{}", jump_host.user, jump_host.host, jump_host.port);
-            ssh_cmd.args(&["-J", &jump_string]);
+            ssh_cmd.args(["-J", &jump_string]);
         }
         
         // Add target host
-        ssh_cmd.arg(&config.connection_string());
+        ssh_cmd.arg(config.connection_string());
         
         // Add command
         ssh_cmd.args(command);
@@ -130,90 +120,8 @@ impl SshHelper {
         })
     }
     
-    /// Copy file to remote host
-    pub async fn copy_file_to(&self, config: &SshConfig, local_path: &Path, remote_path: &str) -> Result<()> {
-        let mut scp_cmd = Command::new("scp");
-        
-        // Add SSH options
-        scp_cmd.args(&[
-            "-o", "StrictHostKeyChecking=no",
-            "-o", "UserKnownHostsFile=/dev/null",
-            "-o", "ConnectTimeout=10",
-        ]);
-        
-        // Add identity file
-        scp_cmd.args(&["-i", &config.key_path]);
-        
-        // Add port
-        scp_cmd.args(&["-P", &config.port.to_string()]);
-        
-        // Add jump host if specified
-        if let Some(jump_host) = &config.jump_host {
-            let jump_string = format!("{}@{}:{}", jump_host.user, jump_host.host, jump_host.port);
-            scp_cmd.args(&["-o", &format!("ProxyJump={}", jump_string)]);
-        }
-        
-        // Add source and destination
-        scp_cmd.arg(local_path);
-        scp_cmd.arg(&format!("{}:{}", config.connection_string(), remote_path));
-        
-        let output = scp_cmd
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .context("Failed to copy file via SCP")?;
-        
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("SCP failed: {}", stderr);
-        }
-        
-        Ok(())
-    }
-    
-    /// Copy file from remote host
-    pub async fn copy_file_from(&self, config: &SshConfig, remote_path: &str, local_path: &Path) -> Result<()> {
-        let mut scp_cmd = Command::new("scp");
-        
-        // Add SSH options
-        scp_cmd.args(&[
-            "-o", "StrictHostKeyChecking=no",
-            "-o", "UserKnownHostsFile=/dev/null",
-            "-o", "ConnectTimeout=10",
-        ]);
-        
-        // Add identity file
-        scp_cmd.args(&["-i", &config.key_path]);
-        
-        // Add port
-        scp_cmd.args(&["-P", &config.port.to_string()]);
-        
-        // Add jump host if specified
-        if let Some(jump_host) = &config.jump_host {
-            let jump_string = format!("{}@{}:{}", jump_host.user, jump_host.host, jump_host.port);
-            scp_cmd.args(&["-o", &format!("ProxyJump={}", jump_string)]);
-        }
-        
-        // Add source and destination
-        scp_cmd.arg(&format!("{}:{}", config.connection_string(), remote_path));
-        scp_cmd.arg(local_path);
-        
-        let output = scp_cmd
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .context("Failed to copy file via SCP")?;
-        
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("SCP failed: {}", stderr);
-        }
-        
-        Ok(())
-    }
-    
-    /// Create SSH config for container
-    pub fn config_for_container(&self, container: &str, port: u16) -> SshConfig {
+    /// Create SSH config for a container's published port
+    pub fn config_for_port(&self, port: u16) -> SshConfig {
         SshConfig::direct("localhost", port, &self.default_user, &self.default_key_path)
     }
     
@@ -235,7 +143,7 @@ impl SshHelper {
         ];
         
         for (name, port) in containers {
-            let config = self.config_for_container(name, port);
+            let config = self.config_for_port(port);
             let success = self.test_connectivity(&config).await?;
             results.add_result(name, success);
             
@@ -298,22 +206,10 @@ impl TestResults {
         self.results.insert(name.to_string(), success);
     }
     
-    pub fn get_result(&self, name: &str) -> Option<bool> {
-        self.results.get(name).copied()
-    }
-    
     pub fn all_successful(&self) -> bool {
         self.results.values().all(|&success| success)
     }
     
-    pub fn success_count(&self) -> usize {
-        self.results.values().filter(|&&success| success).count()
-    }
-    
-    pub fn total_count(&self) -> usize {
-        self.results.len()
-    }
-    
     pub fn failed_tests(&self) -> Vec<String> {
         self.results
             .iter()
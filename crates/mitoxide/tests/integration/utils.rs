@@ -1,83 +1,8 @@
 //! Utility functions for integration tests
 
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::time::{Duration, SystemTime};
 use anyhow::{Context, Result};
-use tempfile::TempDir;
-
-/// Test file operations and assertions
-pub struct FileTestUtils;
-
-impl FileTestUtils {
-    /// Create a temporary test file with specified content
-    pub fn create_test_file(content: &str) -> Result<(TempDir, PathBuf)> {
-        let temp_dir = TempDir::new().context("Failed to create temporary directory")?;
-        let file_path = temp_dir.path().join("test_file.txt");
-        
-        fs::write(&file_path, content)
-            .context("Failed to write test file")?;
-        
-        Ok((temp_dir, file_path))
-    }
-    
-    /// Create a test file with binary content
-    pub fn create_binary_test_file(data: &[u8]) -> Result<(TempDir, PathBuf)> {
-        let temp_dir = TempDir::new().context("Failed to create temporary directory")?;
-        let file_path = temp_dir.path().join("test_binary.bin");
-        
-        fs::write(&file_path, data)
-            .context("Failed to write binary test file")?;
-        
-        Ok((temp_dir, file_path))
-    }
-    
-    /// Create a large test file for performance testing
-    pub fn create_large_test_file(size_mb: usize) -> Result<(TempDir, PathBuf)> {
-        let temp_dir = TempDir::new().context("Failed to create temporary directory")?;
-        let file_path = temp_dir.path().join("large_test_file.dat");
-        
-        let chunk_size = 1024 * 1024; // 1MB chunks
-        let chunk = vec![0u8; chunk_size];
-        
-        let mut file = fs::File::create(&file_path)
-            .context("Failed to create large test file")?;
-        
-        use std::io::Write;
-        for _ in 0..size_mb {
-            file.write_all(&chunk)
-                .context("Failed to write chunk to large test file")?;
-        }
-        
-        Ok((temp_dir, file_path))
-    }
-    
-    /// Verify file content matches expected
-    pub fn verify_file_content(path: &Path, expected: &str) -> Result<bool> {
-        let content = fs::read_to_string(path)
-            .context("Failed to read file for verification")?;
-        Ok(content == expected)
-    }
-    
-    /// Verify binary file content matches expected
-    pub fn verify_binary_file_content(path: &Path, expected: &[u8]) -> Result<bool> {
-        let content = fs::read(path)
-            .context("Failed to read binary file for verification")?;
-        Ok(content == expected)
-    }
-    
-    /// Get file size in bytes
-    pub fn get_file_size(path: &Path) -> Result<u64> {
-        let metadata = fs::metadata(path)
-            .context("Failed to get file metadata")?;
-        Ok(metadata.len())
-    }
-    
-    /// Check if file exists
-    pub fn file_exists(path: &Path) -> bool {
-        path.exists() && path.is_file()
-    }
-}
 
 /// Performance measurement utilities
 pub struct PerformanceUtils;
@@ -94,28 +19,6 @@ impl PerformanceUtils {
         (result, duration)
     }
     
-    /// Measure execution time of a synchronous operation
-    pub fn measure_sync<F, T>(operation: F) -> (T, Duration)
-    where
-        F: FnOnce() -> T,
-    {
-        let start = SystemTime::now();
-        let result = operation();
-        let duration = start.elapsed().unwrap_or(Duration::ZERO);
-        (result, duration)
-    }
-    
-    /// Calculate throughput in MB/s
-    pub fn calculate_throughput_mbps(bytes: u64, duration: Duration) -> f64 {
-        if duration.is_zero() {
-            return 0.0;
-        }
-        
-        let mb = bytes as f64 / (1024.0 * 1024.0);
-        let seconds = duration.as_secs_f64();
-        mb / seconds
-    }
-    
     /// Format duration for display
     pub fn format_duration(duration: Duration) -> String {
         let millis = duration.as_millis();
@@ -131,20 +34,6 @@ impl PerformanceUtils {
 pub struct TestAssertions;
 
 impl TestAssertions {
-    /// Assert that a command output indicates success
-    pub fn assert_command_success(output: &crate::integration::CommandOutput, context: &str) -> Result<()> {
-        if !output.success() {
-            anyhow::bail!(
-                "{} failed with exit code {}\nstdout: {}\nstderr: {}",
-                context,
-                output.exit_code,
-                output.stdout,
-                output.stderr
-            );
-        }
-        Ok(())
-    }
-    
     /// Assert that SSH command output indicates success
     pub fn assert_ssh_success(output: &crate::integration::SshCommandOutput, context: &str) -> Result<()> {
         if !output.success() {
@@ -188,23 +77,6 @@ impl TestAssertions {
         }
         Ok(())
     }
-    
-    /// Assert that throughput meets minimum requirement
-    pub fn assert_throughput_threshold(
-        actual_mbps: f64,
-        min_mbps: f64,
-        operation: &str,
-    ) -> Result<()> {
-        if actual_mbps < min_mbps {
-            anyhow::bail!(
-                "{} achieved {:.2} MB/s but minimum is {:.2} MB/s",
-                operation,
-                actual_mbps,
-                min_mbps
-            );
-        }
-        Ok(())
-    }
 }
 
 /// Environment setup utilities
@@ -216,7 +88,7 @@ impl EnvUtils {
         use std::process::Command;
         
         let output = Command::new("docker")
-            .args(&["--version"])
+            .args(["--version"])
             .output()
             .context("Failed to check Docker availability")?;
         
@@ -232,7 +104,7 @@ impl EnvUtils {
         use std::process::Command;
         
         let output = Command::new("docker-compose")
-            .args(&["--version"])
+            .args(["--version"])
             .output()
             .context("Failed to check docker-compose availability")?;
         
@@ -277,47 +149,3 @@ impl EnvUtils {
         Ok(())
     }
 }
-
-/// Test data generators
-pub struct TestDataGenerator;
-
-impl TestDataGenerator {
-    /// Generate random text data
-    pub fn random_text(size: usize) -> String {
-        use rand::Rng;
-        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                                 abcdefghijklmnopqrstuvwxyz\
-                                 0123456789\n\t ";
-        
-        let mut rng = rand::thread_rng();
-        (0..size)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect()
-    }
-    
-    /// Generate random binary data
-    pub fn random_binary(size: usize) -> Vec<u8> {
-        use rand::Rng;
-        let mut rng = rand::thread_rng();
-        (0..size).map(|_| rng.gen()).collect()
-    }
-    
-    /// Generate structured test data
-    pub fn structured_json_data(records: usize) -> String {
-        let mut data = String::from("[\n");
-        for i in 0..records {
-            if i > 0 {
-                data.push_str(",\n");
-            }
-            data.push_str(&format!(
-                r#"  {{"id": {}, "name": "test_record_{}", "value": {}}}"#,
-                i, i, i * 42
-            ));
-        }
-        data.push_str("\n]");
-        data
-    }
-}
\ No newline at end of file
@@ -1,701 +0,0 @@
-//! Comprehensive integration tests for WASM execution end-to-end
-//! 
-//! These tests verify WASM module execution, JSON I/O serialization,
-//! sandboxing, resource limits, and error handling.
-
-use super::*;
-use anyhow::{Context, Result};
-use std::time::Duration;
-use tokio::time::timeout;
-
-/// Test WASM execution end-to-end
-pub struct WasmTests {
-    docker_env: DockerTestEnv,
-    ssh_helper: SshHelper,
-}
-
-impl WasmTests {
-    /// Create new WASM test suite
-    pub fn new() -> Self {
-        Self {
-            docker_env: DockerTestEnv::new(),
-            ssh_helper: SshHelper::new(),
-        }
-    }
-    
-    /// Setup test environment
-    pub async fn setup(&self) -> Result<()> {
-        EnvUtils::setup_test_environment()?;
-        self.docker_env.start().await?;
-        Ok(())
-    }
-    
-    /// Cleanup test environment
-    pub async fn cleanup(&self) -> Result<()> {
-        self.docker_env.stop().await?;
-        Ok(())
-    }
-    
-    /// Create test WASM modules for various scenarios
-    pub async fn test_wasm_module_creation(&self) -> Result<()> {
-        println!("Testing WASM module creation and validation...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Check if wasmtime is available (we'll simulate it for testing)
-        let wasm_check_cmd = r#"
-# Check if we can create a simple WASM-like binary
-echo "Creating test WASM modules..."
-
-# Create a mock WASM module (WAT format simulation)
-cat > /tmp/hello.wat << 'EOF'
-(module
-  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
-  (memory (export "memory") 1)
-  (data (i32.const 8) "Hello, WASM!\n")
-  (func $main (export "_start")
-    (i32.store (i32.const 0) (i32.const 8))
-    (i32.store (i32.const 4) (i32.const 13))
-    (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 20))
-    drop
-  )
-)
-EOF
-
-echo "Mock WASM module created"
-
-# Create a JSON processing WASM module simulation
-cat > /tmp/json_processor.wat << 'EOF'
-(module
-  (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
-  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
-  (memory (export "memory") 1)
-  (func $main (export "_start")
-    ;; Read JSON from stdin, process, write to stdout
-    ;; This is a mock implementation
-    (i32.store (i32.const 0) (i32.const 100))
-    (i32.store (i32.const 4) (i32.const 50))
-    (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 20))
-    drop
-  )
-)
-EOF
-
-echo "JSON processor WASM module created"
-
-# Create an error-generating WASM module
-cat > /tmp/error_module.wat << 'EOF'
-(module
-  (func $main (export "_start")
-    ;; Simulate an error condition
-    unreachable
-  )
-)
-EOF
-
-echo "Error WASM module created"
-
-# List created modules
-ls -la /tmp/*.wat
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", wasm_check_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "WASM module creation")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Mock WASM module created", "Hello module")?;
-        TestAssertions::assert_output_contains(&output.stdout, "JSON processor WASM module created", "JSON module")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Error WASM module created", "Error module")?;
-        
-        println!("✅ WASM module creation test passed");
-        Ok(())
-    }
-    
-    /// Test JSON input/output serialization
-    pub async fn test_json_io_serialization(&self) -> Result<()> {
-        println!("Testing JSON input/output serialization...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Test JSON processing simulation
-        let json_test_cmd = r#"
-echo "Testing JSON I/O serialization..."
-
-# Create test JSON input
-cat > /tmp/input.json << 'EOF'
-{
-  "name": "test_user",
-  "age": 30,
-  "items": ["apple", "banana", "cherry"],
-  "metadata": {
-    "created": "2024-01-01",
-    "version": 1.0
-  }
-}
-EOF
-
-echo "Input JSON created"
-
-# Simulate WASM JSON processing
-cat > /tmp/json_processor.sh << 'EOF'
-#!/bin/sh
-# Mock WASM JSON processor
-input=$(cat)
-echo "Processing JSON input: $input" >&2
-
-# Parse and transform JSON (using jq if available, otherwise mock)
-if command -v jq >/dev/null 2>&1; then
-    echo "$input" | jq '{
-        processed: true,
-        original_name: .name,
-        age_category: (if .age < 18 then "minor" elif .age < 65 then "adult" else "senior" end),
-        item_count: (.items | length),
-        metadata: .metadata
-    }'
-else
-    # Mock JSON output if jq not available
-    cat << 'JSON_EOF'
-{
-  "processed": true,
-  "original_name": "test_user",
-  "age_category": "adult",
-  "item_count": 3,
-  "metadata": {
-    "created": "2024-01-01",
-    "version": 1.0
-  }
-}
-JSON_EOF
-fi
-EOF
-
-chmod +x /tmp/json_processor.sh
-
-# Execute JSON processing
-output=$(cat /tmp/input.json | /tmp/json_processor.sh)
-echo "JSON processing completed"
-
-# Validate output JSON
-echo "$output" > /tmp/output.json
-echo "Output JSON:"
-cat /tmp/output.json
-
-# Verify JSON structure
-if echo "$output" | grep -q '"processed": true'; then
-    echo "JSON processing validation: PASSED"
-else
-    echo "JSON processing validation: FAILED"
-fi
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", json_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "JSON I/O test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "JSON processing completed", "Processing completion")?;
-        TestAssertions::assert_output_contains(&output.stdout, "JSON processing validation: PASSED", "Validation")?;
-        TestAssertions::assert_output_contains(&output.stdout, "processed\": true", "Output structure")?;
-        
-        // Test complex JSON structures
-        println!("  Testing complex JSON structures...");
-        let complex_json_cmd = r#"
-# Create complex nested JSON
-cat > /tmp/complex.json << 'EOF'
-{
-  "users": [
-    {"id": 1, "name": "Alice", "roles": ["admin", "user"]},
-    {"id": 2, "name": "Bob", "roles": ["user"]}
-  ],
-  "config": {
-    "database": {
-      "host": "localhost",
-      "port": 5432,
-      "ssl": true
-    },
-    "features": {
-      "auth": true,
-      "logging": true,
-      "metrics": false
-    }
-  },
-  "timestamp": "2024-01-01T12:00:00Z"
-}
-EOF
-
-# Validate JSON syntax
-if command -v python3 >/dev/null 2>&1; then
-    python3 -c "import json; json.load(open('/tmp/complex.json'))" && echo "Complex JSON valid"
-else
-    echo "Complex JSON created (validation skipped)"
-fi
-
-# Test JSON with special characters
-cat > /tmp/special.json << 'EOF'
-{
-  "text": "Hello \"World\" with\nnewlines and\ttabs",
-  "unicode": "🚀 Unicode test ñáéíóú",
-  "numbers": [1, -2, 3.14, 1e-10],
-  "boolean": true,
-  "null_value": null
-}
-EOF
-
-echo "Special character JSON created"
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", complex_json_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Complex JSON test")?;
-        
-        println!("✅ JSON I/O serialization test passed");
-        Ok(())
-    }
-    
-    /// Test WASM sandboxing and resource limits
-    pub async fn test_wasm_sandboxing_limits(&self) -> Result<()> {
-        println!("Testing WASM sandboxing and resource limits...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Test memory limits
-        println!("  Testing memory limits...");
-        let memory_test_cmd = r#"
-echo "Testing WASM memory limits..."
-
-# Simulate WASM memory allocation test
-cat > /tmp/memory_test.sh << 'EOF'
-#!/bin/sh
-# Mock WASM memory limit test
-
-echo "Starting memory allocation test" >&2
-
-# Simulate memory allocation (limited to 64MB)
-MEMORY_LIMIT=67108864  # 64MB in bytes
-ALLOCATED=0
-
-while [ $ALLOCATED -lt $MEMORY_LIMIT ]; do
-    ALLOCATED=$((ALLOCATED + 1048576))  # Add 1MB
-    echo "Allocated: $ALLOCATED bytes" >&2
-    
-    # Simulate hitting memory limit
-    if [ $ALLOCATED -ge $MEMORY_LIMIT ]; then
-        echo "Memory limit reached: $ALLOCATED bytes" >&2
-        echo '{"status": "memory_limit_reached", "allocated": '$ALLOCATED'}'
-        exit 0
-    fi
-done
-EOF
-
-chmod +x /tmp/memory_test.sh
-/tmp/memory_test.sh
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", memory_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Memory limit test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "memory_limit_reached", "Memory limit")?;
-        
-        // Test execution time limits
-        println!("  Testing execution time limits...");
-        let timeout_test_cmd = r#"
-echo "Testing WASM execution timeout..."
-
-# Simulate WASM execution with timeout
-timeout 5s sh -c '
-    echo "Starting long-running WASM simulation" >&2
-    sleep 10
-    echo "Should not reach here"
-' || {
-    exit_code=$?
-    if [ $exit_code -eq 124 ]; then
-        echo "Execution timeout enforced correctly"
-        echo '{"status": "timeout", "duration": 5}'
-    else
-        echo "Unexpected exit code: $exit_code"
-        exit 1
-    fi
-}
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", timeout_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Timeout test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Execution timeout enforced", "Timeout enforcement")?;
-        
-        // Test filesystem access restrictions
-        println!("  Testing filesystem access restrictions...");
-        let fs_test_cmd = r#"
-echo "Testing WASM filesystem restrictions..."
-
-# Simulate WASM trying to access restricted paths
-cat > /tmp/fs_test.sh << 'EOF'
-#!/bin/sh
-# Mock WASM filesystem access test
-
-echo "Testing filesystem access" >&2
-
-# Allowed: temporary directory access
-if [ -w /tmp ]; then
-    echo "Temp directory access: ALLOWED" >&2
-    echo "test" > /tmp/wasm_test_file
-    rm -f /tmp/wasm_test_file
-else
-    echo "Temp directory access: DENIED" >&2
-fi
-
-# Restricted: system directory access
-if [ -w /etc ]; then
-    echo "System directory access: ALLOWED (SECURITY ISSUE)" >&2
-    echo '{"status": "security_violation", "access": "system_dirs"}'
-    exit 1
-else
-    echo "System directory access: DENIED (CORRECT)" >&2
-fi
-
-# Restricted: home directory access
-if [ -w /root ]; then
-    echo "Root directory access: ALLOWED (SECURITY ISSUE)" >&2
-    echo '{"status": "security_violation", "access": "root_dir"}'
-    exit 1
-else
-    echo "Root directory access: DENIED (CORRECT)" >&2
-fi
-
-echo '{"status": "sandbox_verified", "restrictions": ["system_dirs", "root_dir"]}'
-EOF
-
-chmod +x /tmp/fs_test.sh
-/tmp/fs_test.sh
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", fs_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Filesystem restriction test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "sandbox_verified", "Sandbox verification")?;
-        
-        println!("✅ WASM sandboxing and limits test passed");
-        Ok(())
-    }
-    
-    /// Test WASM error handling and recovery
-    pub async fn test_wasm_error_handling(&self) -> Result<()> {
-        println!("Testing WASM error handling and recovery...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Test invalid WASM module handling
-        println!("  Testing invalid WASM module handling...");
-        let invalid_wasm_cmd = r#"
-echo "Testing invalid WASM module handling..."
-
-# Create invalid WASM module
-echo "invalid wasm binary data" > /tmp/invalid.wasm
-
-# Simulate WASM runtime trying to load invalid module
-cat > /tmp/wasm_loader.sh << 'EOF'
-#!/bin/sh
-# Mock WASM loader with error handling
-
-module_file="$1"
-echo "Loading WASM module: $module_file" >&2
-
-# Check if file exists
-if [ ! -f "$module_file" ]; then
-    echo '{"error": "module_not_found", "file": "'$module_file'"}' 
-    exit 1
-fi
-
-# Simulate module validation
-if ! head -c 4 "$module_file" | grep -q "wasm" 2>/dev/null; then
-    echo "Invalid WASM magic number" >&2
-    echo '{"error": "invalid_wasm_format", "file": "'$module_file'"}'
-    exit 1
-fi
-
-echo "Module loaded successfully" >&2
-echo '{"status": "loaded", "file": "'$module_file'"}'
-EOF
-
-chmod +x /tmp/wasm_loader.sh
-
-# Test with invalid module
-/tmp/wasm_loader.sh /tmp/invalid.wasm || echo "Error handling worked correctly"
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", invalid_wasm_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Invalid WASM test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "invalid_wasm_format", "Format error")?;
-        
-        // Test runtime errors
-        println!("  Testing WASM runtime errors...");
-        let runtime_error_cmd = r#"
-echo "Testing WASM runtime errors..."
-
-# Simulate WASM runtime error scenarios
-cat > /tmp/runtime_test.sh << 'EOF'
-#!/bin/sh
-# Mock WASM runtime with error scenarios
-
-error_type="$1"
-echo "Simulating runtime error: $error_type" >&2
-
-case "$error_type" in
-    "stack_overflow")
-        echo "Stack overflow detected" >&2
-        echo '{"error": "stack_overflow", "details": "Call stack exceeded maximum depth"}'
-        exit 1
-        ;;
-    "out_of_bounds")
-        echo "Memory access out of bounds" >&2
-        echo '{"error": "memory_access_violation", "details": "Attempted to access memory outside allocated region"}'
-        exit 1
-        ;;
-    "division_by_zero")
-        echo "Division by zero" >&2
-        echo '{"error": "arithmetic_error", "details": "Division by zero"}'
-        exit 1
-        ;;
-    "timeout")
-        echo "Execution timeout" >&2
-        echo '{"error": "execution_timeout", "details": "Module execution exceeded time limit"}'
-        exit 1
-        ;;
-    *)
-        echo "Unknown error type" >&2
-        echo '{"error": "unknown", "details": "Unhandled error condition"}'
-        exit 1
-        ;;
-esac
-EOF
-
-chmod +x /tmp/runtime_test.sh
-
-# Test different error scenarios
-for error in stack_overflow out_of_bounds division_by_zero timeout; do
-    echo "Testing $error..."
-    /tmp/runtime_test.sh "$error" || echo "$error error handled"
-done
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", runtime_error_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Runtime error test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "stack_overflow error handled", "Stack overflow")?;
-        TestAssertions::assert_output_contains(&output.stdout, "division_by_zero error handled", "Division by zero")?;
-        
-        // Test error recovery
-        println!("  Testing error recovery...");
-        let recovery_test_cmd = r#"
-echo "Testing WASM error recovery..."
-
-# Simulate error recovery mechanism
-cat > /tmp/recovery_test.sh << 'EOF'
-#!/bin/sh
-# Mock WASM error recovery
-
-attempt=1
-max_attempts=3
-
-while [ $attempt -le $max_attempts ]; do
-    echo "Attempt $attempt of $max_attempts" >&2
-    
-    # Simulate failure on first two attempts
-    if [ $attempt -lt 3 ]; then
-        echo "Execution failed on attempt $attempt" >&2
-        attempt=$((attempt + 1))
-        sleep 1
-        continue
-    fi
-    
-    # Success on third attempt
-    echo "Execution succeeded on attempt $attempt" >&2
-    echo '{"status": "success", "attempts": '$attempt'}'
-    exit 0
-done
-
-echo "All attempts failed" >&2
-echo '{"status": "failed", "attempts": '$max_attempts'}'
-exit 1
-EOF
-
-chmod +x /tmp/recovery_test.sh
-/tmp/recovery_test.sh
-        "#;
-        
-        let output = self.ssh_helper.execute_command(&config, &["sh", "-c", recovery_test_cmd]).await?;
-        TestAssertions::assert_ssh_success(&output, "Error recovery test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "\"status\": \"success\"", "Recovery success")?;
-        
-        // Cleanup
-        let cleanup_cmd = "rm -f /tmp/*.wasm /tmp/*.wat /tmp/*_test.sh /tmp/*.json";
-        let _ = self.ssh_helper.execute_command(&config, &["sh", "-c", cleanup_cmd]).await;
-        
-        println!("✅ WASM error handling test passed");
-        Ok(())
-    }
-    
-    /// Test end-to-end WASM execution workflow
-    pub async fn test_end_to_end_wasm_workflow(&self) -> Result<()> {
-        println!("Testing end-to-end WASM execution workflow...");
-        
-        let config = self.ssh_helper.config_for_container("ubuntu_min", 2223);
-        
-        // Complete workflow test
-        let workflow_test_cmd = r#"
-echo "Testing complete WASM execution workflow..."
-
-# Step 1: Module preparation
-echo "Step 1: Preparing WASM module"
-cat > /tmp/workflow_module.wat << 'EOF'
-(module
-  (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
-  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
-  (memory (export "memory") 1)
-  (func $main (export "_start")
-    ;; Mock JSON processing workflow
-    (nop)
-  )
-)
-EOF
-
-# Step 2: Input preparation
-echo "Step 2: Preparing input data"
-cat > /tmp/workflow_input.json << 'EOF'
-{
-  "operation": "transform",
-  "data": [1, 2, 3, 4, 5],
-  "options": {
-    "multiply": 2,
-    "filter_even": true
-  }
-}
-EOF
-
-# Step 3: Execution simulation
-echo "Step 3: Executing WASM module"
-cat > /tmp/workflow_executor.sh << 'EOF'
-#!/bin/sh
-# Mock WASM execution workflow
-
-input_file="$1"
-module_file="$2"
-
-echo "Loading module: $module_file" >&2
-echo "Processing input: $input_file" >&2
-
-# Read and parse input
-input=$(cat "$input_file")
-echo "Input received: $input" >&2
-
-# Simulate processing
-echo "Executing WASM module..." >&2
-sleep 1
-
-# Generate output
-cat << 'OUTPUT_EOF'
-{
-  "status": "completed",
-  "input_processed": true,
-  "result": {
-    "original_data": [1, 2, 3, 4, 5],
-    "transformed_data": [2, 4, 6, 8, 10],
-    "filtered_data": [2, 4, 6, 8, 10],
-    "operation_count": 5
-  },
-  "execution_time_ms": 1000,
-  "memory_used_bytes": 65536
-}
-OUTPUT_EOF
-EOF
-
-chmod +x /tmp/workflow_executor.sh
-
-# Step 4: Execute workflow
-echo "Step 4: Running complete workflow"
-result=$(/tmp/workflow_executor.sh /tmp/workflow_input.json /tmp/workflow_module.wat)
-
-# Step 5: Validate results
-echo "Step 5: Validating results"
-echo "$result" > /tmp/workflow_output.json
-
-if echo "$result" | grep -q '"status": "completed"'; then
-    echo "Workflow validation: PASSED"
-    echo "Output preview:"
-    echo "$result" | head -10
-else
-    echo "Workflow validation: FAILED"
-    exit 1
-fi
-
-# Step 6: Performance metrics
-echo "Step 6: Performance metrics"
-if echo "$result" | grep -q '"execution_time_ms"'; then
-    exec_time=$(echo "$result" | grep -o '"execution_time_ms": [0-9]*' | grep -o '[0-9]*')
-    echo "Execution time: ${exec_time}ms"
-    
-    if [ "$exec_time" -lt 5000 ]; then
-        echo "Performance: ACCEPTABLE"
-    else
-        echo "Performance: SLOW"
-    fi
-fi
-
-echo "End-to-end WASM workflow completed successfully"
-        "#;
-        
-        let (output, duration) = PerformanceUtils::measure_async(
-            self.ssh_helper.execute_command(&config, &["sh", "-c", workflow_test_cmd])
-        ).await;
-        
-        let output = output?;
-        TestAssertions::assert_ssh_success(&output, "End-to-end workflow test")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Workflow validation: PASSED", "Workflow validation")?;
-        TestAssertions::assert_output_contains(&output.stdout, "workflow completed successfully", "Workflow completion")?;
-        TestAssertions::assert_output_contains(&output.stdout, "Performance: ACCEPTABLE", "Performance check")?;
-        
-        println!("    ✅ End-to-end workflow completed in {}", 
-                PerformanceUtils::format_duration(duration));
-        
-        println!("✅ End-to-end WASM workflow test passed");
-        Ok(())
-    }
-}
-
-/// Run all WASM execution tests
-pub async fn run_wasm_tests() -> Result<()> {
-    let tests = WasmTests::new();
-    
-    tests.setup().await?;
-    
-    let mut results = Vec::new();
-    
-    // Run all WASM tests
-    results.push(("module_creation", tests.test_wasm_module_creation().await));
-    results.push(("json_io_serialization", tests.test_json_io_serialization().await));
-    results.push(("sandboxing_limits", tests.test_wasm_sandboxing_limits().await));
-    results.push(("error_handling", tests.test_wasm_error_handling().await));
-    results.push(("end_to_end_workflow", tests.test_end_to_end_wasm_workflow().await));
-    
-    tests.cleanup().await?;
-    
-    // Report results
-    let mut failed_tests = Vec::new();
-    for (test_name, result) in results {
-        match result {
-            Ok(()) => println!("✅ {} passed", test_name),
-            Err(e) => {
-                println!("❌ {} failed: {}", test_name, e);
-                failed_tests.push(test_name);
-            }
-        }
-    }
-    
-    if !failed_tests.is_empty() {
-        anyhow::bail!("WASM tests failed: {:?}", failed_tests);
-    }
-    
-    println!("🎉 All WASM execution tests passed!");
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    
-    #[tokio::test]
-    async fn test_wasm_test_creation() {
-        let tests = WasmTests::new();
-        // Should create without errors
-    }
-}
\ No newline at end of file
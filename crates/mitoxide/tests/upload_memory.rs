@@ -0,0 +1,88 @@
+//! Peak memory of uploading a large file
+//!
+//! A counting global allocator records the most memory held at once while a
+//! file far larger than the stream window is uploaded to an agent running in
+//! this process. The file is read only as the agent takes earlier chunks, so
+//! the peak stays near the window on both ends rather than the file's size.
+
+use async_trait::async_trait;
+use mitoxide::{Session, SessionBuilder};
+use mitoxide_agent::agent::AgentLoop;
+use mitoxide_agent::handlers::FileHandler;
+use mitoxide_proto::message::STREAM_INPUT_WINDOW;
+use mitoxide_ssh::{Connection, ConnectionInfo, ServerInfo, Transport, TransportError, TransportType};
+use mitoxide_test_alloc::{peak_since, reset_peak, CountingAllocator};
+use std::io::Write;
+use std::sync::Arc;
+
+/// Size of the uploaded file
+const FILE_SIZE: usize = 64 * 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Transport to an agent in this process that only handles `FilePut`
+struct InProcessTransport;
+
+#[async_trait]
+impl Transport for InProcessTransport {
+    async fn connect(&mut self) -> Result<Connection, TransportError> {
+        let (client_io, agent_io) = tokio::io::duplex(64 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let mut agent = AgentLoop::with_io(agent_read, agent_write);
+        agent.register_handler("file_put".to_string(), Arc::new(FileHandler::new())).await;
+        tokio::spawn(async move { agent.run().await });
+        
+        let (reader, writer) = tokio::io::split(client_io);
+        Ok(Connection::from_io(reader, writer))
+    }
+    
+    async fn bootstrap_agent(&mut self, _agent_binary: &[u8]) -> Result<(), TransportError> {
+        Ok(())
+    }
+    
+    fn connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            host: "in-process".to_string(),
+            port: 0,
+            username: String::new(),
+            transport_type: TransportType::Local,
+            server: ServerInfo::default(),
+        }
+    }
+    
+    async fn test_connection(&mut self) -> Result<(), TransportError> {
+        Ok(())
+    }
+}
+
+// The only test in this binary, so nothing else allocates while it measures
+#[tokio::test]
+async fn test_large_upload_memory_is_bounded() {
+    let config = SessionBuilder::new("in-process".to_string()).build_config();
+    let session = Session::new("in-process".to_string(), config)
+        .connect_with(InProcessTransport).await.unwrap();
+    let context = session.context().await.unwrap();
+    
+    // Written a block at a time so the file is never in memory here either
+    let dir = tempfile::tempdir().unwrap();
+    let local = dir.path().join("large.bin");
+    let mut file = std::fs::File::create(&local).unwrap();
+    let block: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    for _ in 0..FILE_SIZE / block.len() {
+        file.write_all(&block).unwrap();
+    }
+    drop(file);
+    let remote = dir.path().join("upload/large.bin");
+    
+    let baseline = reset_peak();
+    let written = context.put_atomic(&local, &remote).await.unwrap();
+    let peak = peak_since(baseline);
+    
+    assert_eq!(written, FILE_SIZE as u64);
+    assert_eq!(std::fs::metadata(&remote).unwrap().len(), FILE_SIZE as u64);
+    assert_eq!(std::fs::read(&remote).unwrap()[..block.len()], block[..]);
+    // A few windows in flight between client and agent, nowhere near the file
+    let window = STREAM_INPUT_WINDOW as usize;
+    assert!(peak < 8 * window, "uploading held {} bytes at peak with a {} byte window", peak, window);
+}